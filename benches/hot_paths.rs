@@ -0,0 +1,120 @@
+//! Benchmarks for the hot paths of an installation, run against simulated NAND.
+//!
+//! The workloads come from `bmc_installer::fixtures`, which the operation-count canary tests in
+//! the library also use; new benchmarks should build their fixtures there too. That module is only
+//! there with the `bench` feature, which the crate's dev-dependency on itself turns on.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use bmc_installer::fixtures::{self, BENCH_LAYOUT};
+use bmc_installer::format::raw::{verify_raw_image, write_raw_image};
+use bmc_installer::nand::{NandLayout, SimNand};
+use bmc_installer::ubi::{self, ubinize::Volume, Ec, Vid, VolType};
+
+/// A NAND of a single block, for the raw image paths
+const RAW_LAYOUT: NandLayout = NandLayout {
+    blocks: 1,
+    pages_per_block: 64,
+    bytes_per_page: 2048,
+};
+
+fn bench_ubi(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ubi");
+    group.sample_size(10);
+
+    let mut dirty = fixtures::dirty_nand(BENCH_LAYOUT).unwrap();
+    group.bench_function("scan_blocks, dirty NAND", |b| {
+        b.iter(|| ubi::scan_blocks(&mut dirty).unwrap())
+    });
+
+    let ebt = ubi::scan_blocks(&mut dirty).unwrap();
+    group.bench_function("format, dirty NAND", |b| {
+        b.iter_batched_ref(
+            || (dirty.clone(), ebt.clone()),
+            |(nand, ebt)| ubi::format(nand, ebt).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    let mut formatted = dirty.clone();
+    let mut formatted_ebt = ebt.clone();
+    ubi::format(&mut formatted, &mut formatted_ebt).unwrap();
+    let data = fixtures::synthetic_data(32 << 20);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("write_volumes, 32 MiB static volume", |b| {
+        b.iter_batched_ref(
+            || (formatted.clone(), formatted_ebt.clone()),
+            |(nand, ebt)| {
+                let mut reader = &data[..];
+                let volumes: Vec<Box<dyn Volume>> =
+                    vec![Box::new(fixtures::static_volume(&mut reader))];
+                ubi::write_volumes(nand, ebt, volumes).unwrap()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+fn bench_raw(c: &mut Criterion) {
+    let mut group = c.benchmark_group("raw");
+
+    let block_size = RAW_LAYOUT.block_bytes();
+    let image = fixtures::synthetic_data(block_size);
+    let mut nand = SimNand::new(RAW_LAYOUT);
+    write_raw_image(&mut nand, &mut &image[..], false).unwrap();
+    group.throughput(Throughput::Bytes(block_size as u64));
+
+    // Rewriting an image that's already there only checks the block
+    group.bench_function("check block, matching", |b| {
+        b.iter(|| write_raw_image(&mut nand, &mut &image[..], false).unwrap())
+    });
+
+    let other = fixtures::synthetic_data(block_size + 1);
+    group.bench_function("check block, mismatching", |b| {
+        b.iter(|| verify_raw_image(&mut nand, &mut &other[1..], false).unwrap())
+    });
+
+    group.finish();
+}
+
+fn bench_headers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("headers");
+
+    let ec = Ec {
+        ec: 1234,
+        vid_hdr_offset: 2048,
+        data_offset: 4096,
+        image_seq: 0x1234_5678,
+    };
+    let vid = Vid {
+        vol_type: VolType::Static,
+        vol_id: 1,
+        lnum: 42,
+        data_size: 126976,
+        used_ebs: 100,
+        data_crc: 0xDEAD_BEEF,
+        sqnum: 99,
+        ..Default::default()
+    };
+
+    let mut buf = [0xFF; 64];
+    group.bench_function("Ec encode/decode", |b| {
+        b.iter(|| {
+            ec.encode(&mut buf).unwrap();
+            Ec::decode(&buf).unwrap()
+        })
+    });
+    group.bench_function("Vid encode/decode", |b| {
+        b.iter(|| {
+            vid.encode(&mut buf).unwrap();
+            Vid::decode(&buf).unwrap()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_ubi, bench_raw, bench_headers);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+//! Work out what src/buildinfo.rs can't see for itself: which commit the tree is at, and when it
+//! was built.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let git_dir = Path::new(&manifest_dir).join(".git");
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Only the tree's own repository says anything about it: a tarball unpacked inside another
+    // one is left without, as is a tree built without git installed
+    if git_dir.exists() {
+        for path in ["HEAD", "index", "refs"] {
+            println!("cargo:rerun-if-changed={}", git_dir.join(path).display());
+        }
+        if let Some(describe) = git_describe(&manifest_dir) {
+            println!("cargo:rustc-env=BMC_INSTALLER_GIT_DESCRIBE={describe}");
+        }
+    }
+
+    // A reproducible build gives its own time
+    let epoch = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH);
+            now.map_or(0, |x| x.as_secs())
+        });
+    println!(
+        "cargo:rustc-env=BMC_INSTALLER_BUILD_TIME={}",
+        format_utc(epoch)
+    );
+}
+
+/// `git describe` the tree, or None if git can't
+fn git_describe(dir: &str) -> Option<String> {
+    let output = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--tags"])
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    let describe = String::from_utf8(output.stdout).ok()?;
+    let describe = describe.trim();
+    (output.status.success() && !describe.is_empty()).then(|| describe.to_string())
+}
+
+/// Format seconds since the Unix epoch as e.g. `2024-03-01 12:34:56 UTC`
+fn format_utc(epoch: u64) -> String {
+    let (days, secs) = (epoch / 86400, epoch % 86400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
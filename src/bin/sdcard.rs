@@ -9,12 +9,26 @@
 //!    subprocesses to do any of the work. This binary needs to be self-contained.
 //! 4. The filesystem starts empty. Essential mountpoints like `/proc` and `/sys` need to be
 //!    established before any meaningful work can be done.
-use bmc_installer::turing_pi::{led, read_from_sdcard, setup_initramfs, upgrade_bmc, wait_forever};
+use bmc_installer::buildinfo;
+use bmc_installer::bundle::Bundle;
+use bmc_installer::error::InstallError;
+use bmc_installer::turing_pi::{
+    burn_in_flash, burn_in_from_cmdline, factory_reset, find_firmware_on_sdcard, flash_health,
+    force_requested_on_sdcard, keys, layout::UbiLayoutSpec, led, mode_from_cmdline,
+    read_from_sdcard, read_layout_from_sdcard, read_uboot_env_from_sdcard, setup_initramfs,
+    uboot_env::UbootEnv, upgrade_bmc, upgrade_from_bundle, wait_forever, FatFirmware,
+    InstallerMode, UpgradeHooks, BANNER, EC_WARN_THRESHOLD,
+};
+use bmc_installer::util::Aborted;
+
+use anyhow::Context;
 use std::{
-    io,
-    sync::{self, atomic},
+    cell::Cell,
+    fmt, fs,
+    io::{self, BufRead, Read, Seek, Write},
+    sync::{self, atomic, mpsc::RecvTimeoutError},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 const INSTRUCTIONS: &str = "\
@@ -31,28 +45,92 @@ If you wish to confirm the operation and proceed, either:
 
 If you are here in error, please remove the microSD card from the Turing Pi 2
 board and reset the BMC.
+
+Once the installation has started, it can still be aborted by holding down one
+of the buttons for 5 seconds.
 ";
 
-const KEYS_EVDEV_PATH: &str = "/dev/input/event0";
+const RESET_INSTRUCTIONS: &str = "\
+This utility will reset the Turing Pi 2 BMC to its factory settings, keeping the
+installed firmware.
+
+Note that this will ERASE ALL USER SETTINGS stored on the Turing Pi 2 BMC. Do
+NOT proceed unless you have first backed up any settings that you care about!
+
+If you wish to confirm the operation and proceed, either:
+1) Type 'CONFIRM' at the below prompt
+2) Press one of the front panel buttons (POWER or RESET), or the KEY1 button on
+   the Turing Pi 2 board itself, three times in a row
+
+If you are here in error, please remove the microSD card from the Turing Pi 2
+board and reset the BMC.
+";
+
+const BURN_IN_INSTRUCTIONS: &str = "\
+This utility has been asked to burn in the Turing Pi 2 BMC's NAND flash, as a
+factory test: every block is written and read back repeatedly, and those that
+fail are marked bad.
+
+Note that this will ERASE EVERYTHING on the NAND flash, including the installed
+firmware, which must be installed again afterwards.
+
+If you wish to confirm the operation and proceed, either:
+1) Type 'CONFIRM' at the below prompt
+2) Press one of the front panel buttons (POWER or RESET), or the KEY1 button on
+   the Turing Pi 2 board itself, three times in a row
+";
+
+/// Lines typed at the serial console, read on a thread of their own so that waiting for one can
+/// time out, and so that the menu and the confirmation prompt can take turns at reading them
+struct ConsoleInput(sync::Mutex<sync::mpsc::Receiver<String>>);
+
+impl ConsoleInput {
+    /// Read lines from `input`, from now until it ends
+    fn spawn<R: BufRead + Send + 'static>(mut input: R) -> Self {
+        let (tx, rx) = sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match input.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {
+                        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+                        if tx.send(line).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self(sync::Mutex::new(rx))
+    }
+
+    /// The next line typed, without its line ending, if one comes within `timeout`
+    fn read_line(&self, timeout: Duration) -> Result<String, RecvTimeoutError> {
+        self.0.lock().unwrap().recv_timeout(timeout)
+    }
+}
 
 /// Wait until the user confirms the installation operation, through either the serial prompt or
 /// by pressing a GPIO key multiple times.
 ///
 /// This spawns one thread each, for both methods.
-fn wait_for_confirmation() {
+fn wait_for_confirmation(console: &sync::Arc<ConsoleInput>) {
     let signals = sync::Arc::new((atomic::AtomicBool::new(false), thread::current()));
     let signals_1 = signals.clone();
     let signals_2 = signals.clone();
+    let console = console.clone();
     let mut threads = [
         Some(thread::spawn(move || {
             let (stop_flag, main_thread) = &*signals_1;
-            let ret = confirm_prompt(stop_flag);
+            let ret = confirm_prompt(&console, stop_flag);
             main_thread.unpark();
             ret
         })),
         Some(thread::spawn(move || {
             let (stop_flag, main_thread) = &*signals_2;
-            let ret = confirm_keypress(stop_flag);
+            let ret = keys::confirm_keypress(stop_flag);
             main_thread.unpark();
             ret
         })),
@@ -62,7 +140,7 @@ fn wait_for_confirmation() {
 
     loop {
         for thread in &mut threads {
-            if thread.as_ref().map_or(false, |thread| thread.is_finished()) {
+            if thread.as_ref().is_some_and(|thread| thread.is_finished()) {
                 let ret = thread
                     .take()
                     .unwrap()
@@ -79,83 +157,356 @@ fn wait_for_confirmation() {
 }
 
 /// Repeatedly nag the user to type "CONFIRM"
-fn confirm_prompt(stop_flag: &atomic::AtomicBool) -> bool {
+fn confirm_prompt(console: &ConsoleInput, stop_flag: &atomic::AtomicBool) -> bool {
     const CONFIRM_KEYWORD: &str = "CONFIRM";
 
-    let mut input = String::new();
+    // How often to check `stop_flag` while waiting for the user to type something
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    eprint!("Type \"{CONFIRM_KEYWORD}\" to continue: ");
     loop {
         if stop_flag.load(atomic::Ordering::Relaxed) {
             return false;
         }
-        eprint!("Type \"{CONFIRM_KEYWORD}\" to continue: ");
-        input.clear();
-        match io::stdin().read_line(&mut input) {
-            Ok(_) if input.trim_end() == CONFIRM_KEYWORD => return true,
-            Ok(0) => return false,
-            _ => continue,
-        };
+        match console.read_line(POLL_INTERVAL) {
+            Ok(input) if input == CONFIRM_KEYWORD => return true,
+            Ok(_) => eprint!("Type \"{CONFIRM_KEYWORD}\" to continue: "),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
     }
 }
 
-/// Monitor for a key being pressed three times
-fn confirm_keypress(stop_flag: &atomic::AtomicBool) -> bool {
-    const KEYPRESS_TIMEOUT: Duration = Duration::from_millis(500);
-    const KEYPRESS_TIMES: u8 = 3;
+/// What the user can choose to do from the menu
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum MenuChoice {
+    Install,
+    FactoryReset,
+    FlashHealth,
+    Plan,
+    Exit,
+}
 
-    let mut device = match evdev::raw_stream::RawDevice::open(KEYS_EVDEV_PATH) {
-        Ok(device) => device,
-        Err(_) => return false,
-    };
+impl MenuChoice {
+    /// Every choice, in the order the menu lists them (numbered from 1)
+    const ALL: [Self; 5] = [
+        Self::Install,
+        Self::FactoryReset,
+        Self::FlashHealth,
+        Self::Plan,
+        Self::Exit,
+    ];
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Install => "Install the firmware (erases all user data)",
+            Self::FactoryReset => "Reset to factory settings, keeping the installed firmware",
+            Self::FlashHealth => "Show the health of the NAND flash",
+            Self::Plan => "Show what would be installed, without installing it",
+            Self::Exit => "Do nothing, and wait for the microSD card to be removed",
+        }
+    }
+}
 
-    let mut last_key = None;
-    let mut last_time = None;
-    let mut times_pressed = 0;
+/// How long the menu waits for a choice before going ahead with installing, which still has to be
+/// confirmed; so a card put in without anyone at the serial console works as it always has
+const MENU_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Show the menu on `out`, and wait for a choice from `console`, asking again after anything that
+/// isn't one. If no choice is made within `timeout` (all told), or there's no more input to be had,
+/// it's [MenuChoice::Install].
+fn choose_action<W: Write>(
+    console: &ConsoleInput,
+    out: &mut W,
+    timeout: Duration,
+) -> io::Result<MenuChoice> {
+    writeln!(out, "What would you like to do?")?;
+    for (n, choice) in MenuChoice::ALL.iter().enumerate() {
+        writeln!(out, "{}) {}", n + 1, choice.description())?;
+    }
 
+    let deadline = Instant::now() + timeout;
     loop {
-        if stop_flag.load(atomic::Ordering::Relaxed) {
-            return false;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        write!(
+            out,
+            "Choose 1-{} (or wait {}s to install): ",
+            MenuChoice::ALL.len(),
+            remaining.as_secs()
+        )?;
+        out.flush()?;
+
+        let Ok(input) = console.read_line(remaining) else {
+            writeln!(out, "\nNo choice made; installing")?;
+            return Ok(MenuChoice::Install);
+        };
+        let choice = input
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| MenuChoice::ALL.get(n.checked_sub(1)?));
+        match choice {
+            Some(&choice) => return Ok(choice),
+            None => writeln!(out, "{:?} is not one of the choices", input.trim())?,
         }
+    }
+}
+
+/// Where the firmware to install comes from
+#[derive(Debug)]
+enum Source<B, R> {
+    /// Files on the FAT partition, as found there and opened
+    Fat(FatFirmware, FatSource),
+
+    /// The bootloader and rootfs at their fixed places on the card
+    Legacy { bootloader: B, rootfs: R },
+}
+
+/// The files of a [FatFirmware], opened
+#[derive(Debug)]
+enum FatSource {
+    Bundle(Bundle<fs::File>),
+    Files {
+        bootloader: fs::File,
+        rootfs: fs::File,
+    },
+}
 
-        let events = match device.fetch_events() {
-            Ok(events) => events,
-            Err(_) => return false,
+impl<B, R> Source<B, R> {
+    /// Open the firmware files on the FAT partition, if there are any, or else the partitions
+    /// given by `legacy`
+    fn open(legacy: impl FnOnce() -> anyhow::Result<(B, R)>) -> anyhow::Result<Self> {
+        let open = |path: &std::path::Path| {
+            fs::File::open(path).with_context(|| path.display().to_string())
         };
 
-        for event in events {
-            // Only follow key events
-            let key = match event.kind() {
-                evdev::InputEventKind::Key(key) => key,
-                _ => continue,
-            };
-
-            // All keypresses have to be the same key; start over if the user switched keys
-            if last_key != Some(key) {
-                last_key = Some(key);
-                times_pressed = 0;
+        let source = match find_firmware_on_sdcard()? {
+            Some(firmware) => {
+                let opened = match &firmware {
+                    FatFirmware::Bundle(path) => FatSource::Bundle(
+                        Bundle::open(open(path)?).with_context(|| path.display().to_string())?,
+                    ),
+                    FatFirmware::Files { bootloader, rootfs } => FatSource::Files {
+                        bootloader: open(bootloader)?,
+                        rootfs: open(rootfs)?,
+                    },
+                };
+                Self::Fat(firmware, opened)
+            }
+            None => {
+                let (bootloader, rootfs) = legacy()?;
+                Self::Legacy { bootloader, rootfs }
             }
+        };
+        Ok(source)
+    }
+}
 
-            // Only handle key-up events past this point
-            if event.value() != 0 {
-                continue;
+impl<B, R> fmt::Display for Source<B, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fat(firmware, _) => write!(f, "{firmware} on the microSD card"),
+            Self::Legacy { .. } => {
+                f.write_str("the bootloader and rootfs partitions of the microSD card")
             }
+        }
+    }
+}
+
+/// Tell the user about an error, and show its LED pattern: one for problems they can fix on the
+/// microSD card, and another for everything else
+fn report_error(led_tx: &sync::mpsc::Sender<led::LedCommand>, error: &anyhow::Error) {
+    let class = InstallError::of(error);
+    eprintln!("[-] ({class}, code {})", class.code());
+    let pattern = match class.user_fixable() {
+        true => {
+            eprintln!("[-] Please check the files on the microSD card, then try again.");
+            led::LED_ERROR_FIXABLE
+        }
+        false => led::LED_ERROR,
+    };
+    let _ = led_tx.send(pattern.into());
+}
 
-            // Determine how long has passed since the last key-up event (or None)
-            let timestamp = event.timestamp();
-            let time_elapsed = last_time
-                .replace(timestamp)
-                .and_then(|x| timestamp.duration_since(x).ok());
+/// Tell the user that the installer couldn't get going, then wait
+fn init_failed(led_tx: &sync::mpsc::Sender<led::LedCommand>, error: &anyhow::Error) -> ! {
+    eprintln!("[-] The installer could not initialize properly:\n{error:#}");
+    report_error(led_tx, error);
+    wait_forever();
+}
 
-            // If past the timeout (or None), start over
-            if time_elapsed.map_or(true, |x| x > KEYPRESS_TIMEOUT) {
-                times_pressed = 0;
+/// Reset the BMC to its factory settings, once the user confirms, rather than installing anything.
+///
+/// This function must never return.
+fn reset_main(
+    led_tx: &sync::mpsc::Sender<led::LedCommand>,
+    console: &sync::Arc<ConsoleInput>,
+    layout: &UbiLayoutSpec,
+) -> ! {
+    let _ = led_tx.send(led::LED_READY.into());
+    eprintln!("{RESET_INSTRUCTIONS}");
+    wait_for_confirmation(console);
+
+    match factory_reset(layout) {
+        Err(error) => {
+            eprintln!("[-] Factory reset error:\n{error:#}");
+            report_error(led_tx, &error);
+        }
+        Ok(volumes) => {
+            eprintln!("[+] Emptied volumes: {}", volumes.join(", "));
+            eprintln!("[+] DONE: Please remove the microSD card and reset the BMC.");
+            let _ = led_tx.send(led::LED_DONE.into());
+        }
+    }
+
+    wait_forever()
+}
+
+/// Burn in the NAND flash, once the user confirms, then carry on as the installer otherwise would;
+/// if it couldn't be done, wait instead, as the NAND is in no state to install onto
+fn burn_in_main(
+    led_tx: &sync::mpsc::Sender<led::LedCommand>,
+    console: &sync::Arc<ConsoleInput>,
+    passes: u32,
+) {
+    let _ = led_tx.send(led::LED_READY.into());
+    eprintln!("{BURN_IN_INSTRUCTIONS}");
+    wait_for_confirmation(console);
+
+    match burn_in_flash(passes) {
+        Err(error) => {
+            eprintln!("[-] Burn-in error:\n{error:#}");
+            report_error(led_tx, &error);
+            wait_forever();
+        }
+        Ok([boot, ubi]) => {
+            eprint!("[+] Boot partition:\n{boot}");
+            eprint!("[+] UBI partition:\n{ubi}");
+            eprintln!("[+] Burn-in done ({passes} passes); the firmware can now be installed.\n");
+        }
+    }
+}
+
+/// Find and open the firmware to install, and whether to install it even if the BMC already has it
+fn open_firmware() -> anyhow::Result<(Source<impl Read, impl Read + Seek>, bool)> {
+    let force = force_requested_on_sdcard()?;
+    Ok((Source::open(read_from_sdcard)?, force))
+}
+
+/// Install the firmware from `source`, once the user confirms, then wait.
+///
+/// This only returns if the installation failed before the user was asked to confirm it, and so
+/// before anything was touched, returning what went wrong (having told the user already).
+fn install_main<B: Read, R: Read + Seek>(
+    led_tx: &sync::mpsc::Sender<led::LedCommand>,
+    console: &sync::Arc<ConsoleInput>,
+    layout: &UbiLayoutSpec,
+    uboot_env: Option<&UbootEnv>,
+    source: Source<B, R>,
+    force: bool,
+) -> anyhow::Error {
+    // Only watch for the abort gesture once confirmed, so the confirming presses can't count
+    let abort = sync::Arc::new(atomic::AtomicBool::new(false));
+    let confirmed = Cell::new(false);
+    let description = source.to_string();
+    let pre_upgrade = || {
+        eprintln!("{INSTRUCTIONS}");
+        eprintln!("[+] Firmware to install: {description}\n");
+        wait_for_confirmation(console);
+        confirmed.set(true);
+        keys::abort_watcher_thread(abort.clone());
+    };
+
+    let mut hooks = UpgradeHooks::default()
+        .before_destructive(pre_upgrade)
+        .log_to_sdcard();
+    if force {
+        eprintln!("[+] Installing even if the BMC already has this firmware, as asked");
+        hooks = hooks.force();
+    }
+    if let Some(env) = uboot_env {
+        eprintln!("[+] Writing the U-Boot environment from the microSD card");
+        hooks = hooks.uboot_env(env.clone());
+    }
+    let result = match source {
+        Source::Fat(_, FatSource::Bundle(mut bundle)) => {
+            upgrade_from_bundle(&mut bundle, hooks, layout, led_tx.clone(), Some(&abort))
+        }
+        Source::Fat(_, FatSource::Files { bootloader, rootfs }) => upgrade_bmc(
+            rootfs,
+            bootloader,
+            hooks,
+            layout,
+            led_tx.clone(),
+            Some(&abort),
+        ),
+        Source::Legacy { bootloader, rootfs } => upgrade_bmc(
+            rootfs,
+            bootloader,
+            hooks,
+            layout,
+            led_tx.clone(),
+            Some(&abort),
+        ),
+    };
+    match result {
+        Err(error) if error.is::<Aborted>() => {
+            eprintln!("[-] Installation {error}; the BMC firmware is now incomplete.");
+            eprintln!("[-] Please remove the microSD card and reset the BMC.");
+            let _ = led_tx.send(led::LED_ERROR.into());
+        }
+        Err(error) => {
+            eprintln!("[-] Installation error:\n{error:#}");
+            report_error(led_tx, &error);
+            if !confirmed.get() {
+                return error;
             }
+        }
+        Ok(report) if report.up_to_date => {
+            eprintln!("{report}");
+            eprintln!("[+] The BMC firmware is already up to date; to reinstall it anyway, put");
+            eprintln!("[+] an empty file named `force-install` on the microSD card.");
+            eprintln!("[+] DONE: Please remove the microSD card and reset the BMC.");
+        }
+        Ok(report) => {
+            eprintln!("{report}");
+            eprintln!("[+] DONE: Please remove the microSD card and reset the BMC.");
+        }
+    }
+
+    wait_forever()
+}
 
-            times_pressed += 1;
-            if times_pressed >= KEYPRESS_TIMES {
-                return true;
+/// Tell the user how worn the NAND flash is, and how many of its blocks are bad
+fn show_flash_health() {
+    match flash_health() {
+        Ok(summary) => {
+            eprint!("[+] UBI partition:\n{summary}");
+            if summary.ec.is_some_and(|x| x.max > EC_WARN_THRESHOLD) {
+                eprintln!("[-] WARNING: the NAND is nearing the end of its life; consider");
+                eprintln!("[-] replacing the module.");
             }
         }
+        Err(error) => eprintln!("[-] Could not read the NAND flash:\n{error:#}"),
+    }
+}
+
+/// Tell the user what installing would do, without doing it
+fn show_plan<B, R>(
+    source: &Source<B, R>,
+    force: bool,
+    layout: &UbiLayoutSpec,
+    uboot_env: Option<&UbootEnv>,
+) {
+    eprintln!("[+] Firmware to install: {source}");
+    eprintln!("[+] UBI layout:\n{layout}");
+    if let Some(env) = uboot_env {
+        eprintln!("[+] U-Boot environment:\n{env}");
+    }
+    if force {
+        eprintln!("[+] It would be installed even if the BMC already has it, as asked.");
     }
+    eprintln!("[+] Nothing has been written.");
 }
 
 /// The main SD Card installation program.
@@ -165,28 +516,134 @@ fn main() -> ! {
     // Set up the LED blinking thread, in order to indicate further init errors
     let led_tx = led::led_blink_thread();
 
-    let result = setup_initramfs().and_then(|_| read_from_sdcard());
-
-    let Ok((bootloader, rootfs)) = result else {
-        eprintln!(
-            "[-] The installer could not initialize properly:\n{}",
-            result.unwrap_err()
-        );
-        let _ = led_tx.send(led::LED_ERROR);
-        wait_forever();
+    // The layout and environment files are read first, as the FAT partition stays mounted for a
+    // bundle
+    let (mode, burn_in, layout, uboot_env) = match setup_initramfs().and_then(|_| {
+        Ok((
+            mode_from_cmdline()?,
+            burn_in_from_cmdline()?,
+            read_layout_from_sdcard()?,
+            read_uboot_env_from_sdcard()?,
+        ))
+    }) {
+        Ok(x) => x,
+        Err(error) => init_failed(&led_tx, &error),
     };
+    let console = sync::Arc::new(ConsoleInput::spawn(io::BufReader::new(io::stdin())));
 
-    let pre_upgrade = || {
-        eprintln!("{INSTRUCTIONS}");
-        wait_for_confirmation();
+    // The factory's burn-in comes before anything else
+    if let Some(passes) = burn_in {
+        burn_in_main(&led_tx, &console, passes);
+    }
+
+    // A mode chosen on the kernel command line skips the menu
+    let mut choice = match mode {
+        Some(InstallerMode::Install) => Some(MenuChoice::Install),
+        Some(InstallerMode::FactoryReset) => Some(MenuChoice::FactoryReset),
+        None => {
+            eprintln!("{BANNER}");
+            eprintln!("{}", buildinfo::summary());
+            None
+        }
     };
 
-    if let Err(error) = upgrade_bmc(rootfs, bootloader, pre_upgrade, led_tx.clone()) {
-        eprintln!("[-] Installation error:\n{error}");
-        let _ = led_tx.send(led::LED_ERROR);
-    } else {
-        eprintln!("[+] DONE: Please remove the microSD card and reset the BMC.");
+    // The firmware is only opened once it's needed, then kept (or what stopped it being used)
+    let mut firmware = None;
+    loop {
+        let choice = choice.take().unwrap_or_else(|| {
+            let _ = led_tx.send(led::LED_READY.into());
+            choose_action(&console, &mut io::stderr(), MENU_TIMEOUT).unwrap_or(MenuChoice::Install)
+        });
+
+        match choice {
+            MenuChoice::Install => {
+                let error = match firmware.take().unwrap_or_else(open_firmware) {
+                    Ok((source, force)) => install_main(
+                        &led_tx,
+                        &console,
+                        &layout,
+                        uboot_env.as_ref(),
+                        source,
+                        force,
+                    ),
+                    Err(error) => {
+                        eprintln!(
+                            "[-] Cannot install the firmware on the microSD card:\n{error:#}"
+                        );
+                        report_error(&led_tx, &error);
+                        error
+                    }
+                };
+                if mode.is_some() {
+                    wait_forever();
+                }
+                firmware = Some(Err(error));
+            }
+            MenuChoice::FactoryReset => reset_main(&led_tx, &console, &layout),
+            MenuChoice::FlashHealth => show_flash_health(),
+            MenuChoice::Plan => match firmware.get_or_insert_with(open_firmware) {
+                Ok((source, force)) => show_plan(source, *force, &layout, uboot_env.as_ref()),
+                Err(error) => {
+                    eprintln!("[-] Cannot install the firmware on the microSD card:\n{error:#}")
+                }
+            },
+            MenuChoice::Exit => {
+                eprintln!(
+                    "[+] Nothing was done: Please remove the microSD card and reset the BMC."
+                );
+                wait_forever();
+            }
+        }
+        eprintln!();
+    }
+}
+
+#[test]
+fn test_choose_action() -> io::Result<()> {
+    /// Input that never comes
+    struct Stalled;
+    impl Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            loop {
+                thread::park();
+            }
+        }
+    }
+
+    // Anything that isn't a choice is asked about again
+    let console = ConsoleInput::spawn(io::Cursor::new(b"six\n0\n 3 \n".to_vec()));
+    let mut out = vec![];
+    let choice = choose_action(&console, &mut out, MENU_TIMEOUT)?;
+    assert_eq!(choice, MenuChoice::FlashHealth);
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("1) Install the firmware"));
+    assert!(out.contains("5) Do nothing"));
+    assert_eq!(out.matches("is not one of the choices").count(), 2);
+
+    // Every choice can be made, and the next line goes to whoever reads next
+    let console = ConsoleInput::spawn(io::Cursor::new(b"1\n2\n3\n4\n5\nCONFIRM\n".to_vec()));
+    for expected in MenuChoice::ALL {
+        assert_eq!(
+            choose_action(&console, &mut io::sink(), MENU_TIMEOUT)?,
+            expected
+        );
     }
+    assert_eq!(console.read_line(MENU_TIMEOUT).as_deref(), Ok("CONFIRM"));
 
-    wait_forever()
+    // With nobody there, or nothing to read at all, it installs
+    let console = ConsoleInput::spawn(io::BufReader::new(Stalled));
+    let mut out = vec![];
+    let timeout = Duration::from_millis(50);
+    assert_eq!(
+        choose_action(&console, &mut out, timeout)?,
+        MenuChoice::Install
+    );
+    assert!(String::from_utf8(out).unwrap().contains("No choice made"));
+    let console = ConsoleInput::spawn(io::empty());
+    assert_eq!(
+        choose_action(&console, &mut io::sink(), MENU_TIMEOUT)?,
+        MenuChoice::Install
+    );
+
+    Ok(())
 }
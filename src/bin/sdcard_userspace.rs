@@ -1,7 +1,38 @@
-use bmc_installer::turing_pi::{led, read_from_sdcard, upgrade_bmc};
+use bmc_installer::progress::{self, ProgressFormat};
+use bmc_installer::turing_pi::{
+    layout::UbiLayoutSpec, led, read_from_sdcard, upgrade_bmc, UpgradeHooks,
+};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+struct Cli {
+    /// How to show progress; `json` writes one JSON object per update to stderr, for a frontend
+    #[clap(long, value_enum, default_value_t)]
+    progress: ProgressFormat,
+
+    /// Install even if the NAND already has the same firmware
+    #[clap(long)]
+    force: bool,
+}
 
 fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    progress::set_format(cli.progress);
+
     let led_tx = led::led_blink_thread();
     let (bootloader, rootfs) = read_from_sdcard()?;
-    upgrade_bmc(rootfs, bootloader, || (), led_tx)
+    let mut hooks = UpgradeHooks::default();
+    if cli.force {
+        hooks = hooks.force();
+    }
+    let report = upgrade_bmc(
+        rootfs,
+        bootloader,
+        hooks,
+        &UbiLayoutSpec::default(),
+        led_tx,
+        None,
+    )?;
+    eprintln!("{report}");
+    Ok(())
 }
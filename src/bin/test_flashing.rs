@@ -7,97 +7,292 @@ use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
 
 use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Seek, Write};
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::Instant;
 
-#[cfg(target_os = "linux")]
-use bmc_installer::nand::mtd::MtdNand;
+#[cfg(unix)]
+use bmc_installer::nand::{blockdev::BlockDevNand, file::FileNand};
 use bmc_installer::{
-    format::{purge_boot0, raw::write_raw_image},
-    nand::{NandLayout, SimNand},
+    buildinfo,
+    bundle::Bundle,
+    format::{
+        clean_partition, erase_legacy_boot, purge_boot0,
+        raw::{read_raw_image, verify_raw_image, write_raw_image_sized, RawVerifyResult},
+        MMC_BOOT_OFFSETS,
+    },
+    image::{self, erofs, squashfs, ImageKind, PathOutcome},
+    lock::InstallLock,
+    nand::{
+        test::{burn_in_with, BurnInOptions},
+        trace::{read_trace_csv, replay_trace, TracingNand},
+        Nand, NandBlock, NandLayout, NandSpec, OpStats, SimNand,
+    },
     ubi::{
-        format, scan_blocks,
-        ubinize::{BasicVolume, Volume},
-        write_volumes, Ebt, VolType,
+        decode_volume_table, diff, format_with_policy, import_ubi_image, read_volume,
+        read_volume_table_copies, scan_blocks, summarize, summarize_volumes,
+        ubinize::{BasicVolume, Volume, UBI_LAYOUT_VOLUME_ID},
+        update_volume, write_volumes, BlockContent, Ebt, Ec, EcPolicy, FormatStats, Vid, VolType,
+        VolumeSelector,
     },
+    util::HexDump,
+};
+#[cfg(feature = "linux-hw")]
+use bmc_installer::{
+    lock::{default_lock_path, force_unlock},
+    nand::mtd::MtdNand,
+    turing_pi::{
+        firstboot::{Firstboot, FIRSTBOOT_VOLUME_ID},
+        install_log::{read_log_volume, INSTALL_LOG_VOLUME_NAME},
+    },
+    ubi::EC_BACKUP_SIZE,
 };
 
 #[derive(Args, Debug)]
 #[group(required = true)]
 struct NandOptions {
+    /// The NAND to use, e.g. mtd-name:ubi, mtd:/dev/mtd1, sim:nand.img?layout=1024x64x2048 or
+    /// file:nand.fnand (the other NAND options are shorthands for these)
+    #[clap(long, group = "nand-options")]
+    nand: Option<NandSpec>,
+
     /// Name of the MTD device or partition
-    #[cfg(target_os = "linux")]
+    #[cfg(feature = "linux-hw")]
     #[clap(long, group = "nand-options")]
     mtd_name: Option<String>,
 
     /// Path to a `/dev/mtdX` device
-    #[cfg(target_os = "linux")]
+    #[cfg(feature = "linux-hw")]
     #[clap(long, group = "nand-options")]
     mtd_dev: Option<PathBuf>,
 
+    /// Try to disengage the MTD device's block protection before doing anything
+    #[cfg(feature = "linux-hw")]
+    #[clap(long)]
+    unlock: bool,
+
+    /// Only trust the driver's bad block table, rather than also scanning the OOB for factory
+    /// bad-block markers (e.g. on mtdram, whose OOB means nothing)
+    #[cfg(feature = "linux-hw")]
+    #[clap(long)]
+    no_factory_scan: bool,
+
+    /// Back up each EC header written into the free bytes of its page's OOB area, so that its
+    /// erase counter can be recovered should the page become unreadable
+    #[cfg(feature = "linux-hw")]
+    #[clap(long)]
+    oob_ec_backup: bool,
+
+    /// Clear the installer lock left by another instance (which must no longer be running)
+    /// before taking it
+    #[cfg(feature = "linux-hw")]
+    #[clap(long)]
+    force_unlock: bool,
+
+    /// Path to a block device (or file) to treat as NAND with the given `--layout`
+    #[cfg(unix)]
+    #[clap(long, group = "nand-options", requires = "layout")]
+    blockdev: Option<PathBuf>,
+
+    /// Path to a NAND file, which keeps its contents and bad blocks between runs; one is created
+    /// with the given `--layout` if it doesn't exist
+    #[cfg(unix)]
+    #[clap(long, group = "nand-options")]
+    file_nand: Option<PathBuf>,
+
     /// Path to the NAND image to use
-    #[clap(long, group = "nand-options", requires = "sim_layout")]
+    #[clap(long, group = "nand-options", requires = "layout")]
     sim_path: Option<PathBuf>,
 
-    /// Layout of the NAND to simulate
-    #[clap(long)]
-    sim_layout: Option<NandLayout>,
+    /// Layout of the simulated NAND, block device or new NAND file, as BLOCKSxPAGESxBYTES (e.g.
+    /// 1024x64x2048) or TOTAL/ERASE/PAGE sizes (e.g. 128MiB/128KiB/2KiB)
+    #[clap(long, alias = "sim-layout")]
+    layout: Option<NandLayout>,
 
-    /// Write back the NAND file when done
-    #[clap(long, requires = "sim_path")]
+    /// Write back the simulated NAND's image when done
+    #[clap(long)]
     sim_write: bool,
+
+    /// Record every NAND operation (and the data programmed) to this file, as CSV
+    #[clap(long)]
+    trace: Option<PathBuf>,
 }
 
 impl NandOptions {
+    /// The NAND to use, whether given with `--nand` or one of its shorthands
+    fn spec(&self) -> Result<NandSpec> {
+        let spec = if let Some(spec) = &self.nand {
+            spec.clone()
+        } else if let Some(path) = &self.sim_path {
+            NandSpec::Sim {
+                path: Some(path.clone()),
+                layout: self.need_layout()?,
+            }
+        } else {
+            self.shorthand_spec()?
+        };
+
+        if self.sim_write {
+            anyhow::ensure!(
+                matches!(spec, NandSpec::Sim { path: Some(_), .. }),
+                "--sim-write needs a simulated NAND loaded from an image, not {spec}"
+            );
+        }
+        Ok(spec)
+    }
+
+    /// The spec given by one of the shorthands that don't exist everywhere
+    fn shorthand_spec(&self) -> Result<NandSpec> {
+        #[cfg(feature = "linux-hw")]
+        if let Some(name) = &self.mtd_name {
+            return Ok(NandSpec::MtdName(name.clone()));
+        }
+        #[cfg(feature = "linux-hw")]
+        if let Some(dev) = &self.mtd_dev {
+            return Ok(NandSpec::MtdDev(dev.clone()));
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.blockdev {
+            return Ok(NandSpec::BlockDev {
+                path: path.clone(),
+                layout: self.need_layout()?,
+            });
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.file_nand {
+            return Ok(NandSpec::File {
+                path: path.clone(),
+                layout: self.layout,
+            });
+        }
+
+        // Just a layout: a fresh simulated NAND
+        Ok(NandSpec::Sim {
+            path: None,
+            layout: self.need_layout()?,
+        })
+    }
+
+    fn need_layout(&self) -> Result<NandLayout> {
+        self.layout
+            .ok_or_else(|| anyhow::anyhow!("this NAND needs a --layout"))
+    }
+
+    /// Take the installer lock, if the NAND is the real thing; held until the lock is dropped
+    fn lock(&self) -> Result<Option<InstallLock>> {
+        #[cfg(feature = "linux-hw")]
+        if self.spec()?.is_mtd() {
+            if self.force_unlock {
+                force_unlock(&default_lock_path())?;
+            }
+            return Ok(Some(InstallLock::acquire()?));
+        }
+
+        Ok(None)
+    }
+
     fn open(&self) -> Result<NandImpl> {
-        let nandimpl = if let Some(layout) = self.sim_layout {
-            let mut sim = SimNand::new(layout);
-            if let Some(path) = &self.sim_path {
-                sim.load(&mut File::open(path)?)?;
+        let nandimpl = match self.spec()? {
+            #[cfg(unix)]
+            NandSpec::BlockDev { path, layout } => {
+                NandImpl::BlockDev(self.traced(BlockDevNand::open(path, layout)?)?)
             }
 
-            NandImpl::Sim(sim)
-        } else {
-            #[cfg(target_os = "linux")]
-            {
-                let mtd = {
-                    if let Some(name) = &self.mtd_name {
-                        MtdNand::open_named(name)?
-                    } else if let Some(dev) = &self.mtd_dev {
-                        MtdNand::open(dev)?
-                    } else {
-                        unreachable!()
+            #[cfg(unix)]
+            NandSpec::File { path, layout } => {
+                NandImpl::File(self.traced(FileNand::open_or_create(path, layout)?)?)
+            }
+
+            NandSpec::Sim { path, layout } => {
+                let mut sim = SimNand::new(layout);
+                if let Some(path) = path {
+                    sim.load(&mut File::open(path)?)?;
+                }
+
+                NandImpl::Sim(self.traced(sim)?)
+            }
+
+            #[cfg(feature = "linux-hw")]
+            spec @ (NandSpec::MtdName(_) | NandSpec::MtdDev(_)) => {
+                let mut mtd = spec.open_mtd()?;
+
+                if self.unlock {
+                    if let Some(protection) = mtd.unlock()? {
+                        println!("Still write-protected after unlocking: {protection}");
                     }
-                };
+                }
+                if !self.no_factory_scan {
+                    let found = mtd.scan_factory_bad_blocks()?;
+                    if !found.is_empty() {
+                        println!("Blocks bad only by factory marker: {found:?}");
+                    }
+                }
+                if self.oob_ec_backup {
+                    let free = mtd.enable_free_oob();
+                    if free < EC_BACKUP_SIZE {
+                        println!(
+                            "Only {free} free OOB bytes per page, not {EC_BACKUP_SIZE}; EC headers \
+                             won't be backed up"
+                        );
+                    }
+                }
 
-                NandImpl::Mtd(mtd)
+                NandImpl::Mtd(self.traced(mtd)?)
             }
 
-            #[cfg(not(target_os = "linux"))]
-            unreachable!()
+            // Anything this build can't open directly
+            #[allow(unreachable_patterns)]
+            spec => anyhow::bail!("{spec} can't be opened in this build"),
         };
 
         Ok(nandimpl)
     }
 
-    fn cleanup(&self, nand: NandImpl) -> anyhow::Result<()> {
+    /// Wrap the NAND to record its operations to the `--trace` file, if there is one, with the
+    /// data programmed so that `trace-replay` can reproduce them
+    fn traced<N: Nand>(&self, nand: N) -> Result<TracingNand<N>> {
+        let nand = TracingNand::new(nand).keep_entries(false);
+        match &self.trace {
+            Some(path) => {
+                let csv = BufWriter::new(File::create(path)?);
+                nand.keep_data(true).stream_csv(Box::new(csv))
+            }
+            None => Ok(nand),
+        }
+    }
+
+    /// Write back the NAND file, if requested; returns whether anything was saved
+    #[cfg_attr(not(feature = "linux-hw"), allow(irrefutable_let_patterns))]
+    fn cleanup(&self, nand: &mut NandImpl) -> anyhow::Result<bool> {
         if self.sim_write {
-            if let Some(path) = &self.sim_path {
-                if let NandImpl::Sim(mut sim_nand) = nand {
-                    sim_nand.save(&mut File::create(path)?)?;
+            if let NandSpec::Sim {
+                path: Some(path), ..
+            } = self.spec()?
+            {
+                if let NandImpl::Sim(sim_nand) = nand {
+                    sim_nand.inner_mut().save(&mut File::create(path)?)?;
+                    return Ok(true);
                 }
             }
         }
 
-        Ok(())
+        Ok(false)
     }
 }
 
 #[derive(Debug)]
 enum NandImpl {
-    Sim(SimNand),
+    Sim(TracingNand<SimNand>),
+
+    #[cfg(unix)]
+    BlockDev(TracingNand<BlockDevNand>),
+
+    #[cfg(unix)]
+    File(TracingNand<FileNand>),
 
-    #[cfg(target_os = "linux")]
-    Mtd(MtdNand),
+    #[cfg(feature = "linux-hw")]
+    Mtd(TracingNand<MtdNand>),
 }
 
 impl NandImpl {
@@ -105,21 +300,76 @@ impl NandImpl {
         match self {
             NandImpl::Sim(nand) => scan_blocks(nand),
 
-            #[cfg(target_os = "linux")]
+            #[cfg(unix)]
+            NandImpl::BlockDev(nand) => scan_blocks(nand),
+
+            #[cfg(unix)]
+            NandImpl::File(nand) => scan_blocks(nand),
+
+            #[cfg(feature = "linux-hw")]
             NandImpl::Mtd(nand) => scan_blocks(nand),
         }
     }
 
-    fn do_format(&mut self, ebt: &mut Ebt) -> anyhow::Result<()> {
+    fn do_format(&mut self, ebt: &mut Ebt, policy: EcPolicy) -> anyhow::Result<FormatStats> {
         match self {
-            Self::Sim(nand) => format(nand, ebt),
+            Self::Sim(nand) => format_with_policy(nand, ebt, policy, None),
+
+            #[cfg(unix)]
+            Self::BlockDev(nand) => format_with_policy(nand, ebt, policy, None),
 
-            #[cfg(target_os = "linux")]
-            Self::Mtd(nand) => format(nand, ebt),
+            #[cfg(unix)]
+            Self::File(nand) => format_with_policy(nand, ebt, policy, None),
+
+            #[cfg(feature = "linux-hw")]
+            Self::Mtd(nand) => format_with_policy(nand, ebt, policy, None),
+        }
+    }
+
+    fn stats(&self) -> OpStats {
+        match self {
+            Self::Sim(nand) => nand.stats(),
+
+            #[cfg(unix)]
+            Self::BlockDev(nand) => nand.stats(),
+
+            #[cfg(unix)]
+            Self::File(nand) => nand.stats(),
+
+            #[cfg(feature = "linux-hw")]
+            Self::Mtd(nand) => nand.stats(),
         }
     }
 }
 
+/// The state kept between commands: the open NAND, and the EBT as of the last scan
+#[derive(Debug)]
+struct Session {
+    nand: NandImpl,
+    ebt: Option<Ebt>,
+}
+
+impl Session {
+    fn new(nand: NandImpl) -> Self {
+        Self { nand, ebt: None }
+    }
+
+    /// Get the NAND along with its EBT, scanning first if there's no cached EBT
+    fn scanned(&mut self) -> anyhow::Result<(&mut NandImpl, &mut Ebt)> {
+        let ebt = match &mut self.ebt {
+            Some(ebt) => ebt,
+            ebt @ None => ebt.insert(self.nand.do_scan()?),
+        };
+
+        Ok((&mut self.nand, ebt))
+    }
+
+    /// Forget the cached EBT, e.g. because the NAND was modified behind the UBI code's back
+    fn invalidate(&mut self) {
+        self.ebt = None;
+    }
+}
+
 #[derive(Args, Debug, Clone)]
 #[group(required = true, id = "vol-type")]
 struct UbiVolume {
@@ -173,15 +423,78 @@ impl From<UbiVolume> for BasicVolume<'static> {
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    /// Print a summary of the content of each PEB; this is a read-only operation
+    /// Print a summary of the content of each PEB, and of each volume in the volume table; this is
+    /// a read-only operation
     UbiOverview,
 
+    /// Show one PEB in detail: the start of its first pages, and its EC and VID headers (and the
+    /// volume table, for the layout volume, or the first-boot record, for its volume); this is a
+    /// read-only operation
+    UbiInspect {
+        /// The block to inspect
+        block: u32,
+
+        /// How many pages to show the first bytes of
+        #[clap(long, default_value_t = 2)]
+        pages: u32,
+    },
+
     /// Perform a UBI format operation, erasing every PEB and filling in the proper EC header
-    UbiFormat,
+    UbiFormat {
+        /// How to set the erase counters
+        #[clap(long, value_enum, default_value_t)]
+        ec_policy: EcPolicy,
+    },
 
     /// Write UBI volumes
     UbiWrite(UbiVolume),
 
+    /// Write the volumes of a UBI image (as made by `ubinize`) to the NAND, keeping its erase
+    /// counters; unlike `raw-write`, which would replace them with the image's
+    UbiImport {
+        /// The path to the image
+        path: PathBuf,
+    },
+
+    /// Extract the contents of a UBI volume into a file
+    UbiRead {
+        /// The name (or numeric ID) of the volume to read
+        name: VolumeSelector,
+
+        /// The path of the file to write the volume contents to
+        out: PathBuf,
+    },
+
+    /// Replace the contents of a dynamic UBI volume with a file, in place, leaving every other
+    /// volume alone
+    UbiUpdate {
+        /// The name (or numeric ID) of the volume to update
+        name: VolumeSelector,
+
+        /// The path of the file to write into the volume
+        path: PathBuf,
+    },
+
+    /// Print the log of the last installation, from its UBI volume; this is a read-only operation
+    #[cfg(feature = "linux-hw")]
+    InstallLog,
+
+    /// Copy files out of the filesystem in a UBI volume, for inspection: EROFS, or SquashFS (as in
+    /// the rootfs of 1.x firmware)
+    RootfsExtract {
+        /// The name (or numeric ID) of the volume holding the filesystem
+        #[clap(long, default_value = "rootfs")]
+        volume: VolumeSelector,
+
+        /// A path within the filesystem to extract; may be given several times
+        #[clap(long, required = true)]
+        path: Vec<String>,
+
+        /// The directory to extract into
+        #[clap(long)]
+        out: PathBuf,
+    },
+
     /// Write a raw image to the NAND
     RawWrite {
         /// The path to the image to write to NAND
@@ -190,74 +503,796 @@ enum Command {
         /// Whether to skip over (thereby tolerating) any bad blocks encountered
         #[clap(long)]
         skip_bad: bool,
+
+        /// Also erase every block past the image, so nothing stale is left after it
+        #[clap(long)]
+        clean: bool,
+
+        /// The length of the image in bytes, checked against the room on the NAND before anything
+        /// is written; by default, the size of the file (if it's a regular one)
+        #[clap(long)]
+        length: Option<u64>,
+    },
+
+    /// Check whether a raw image is present on the NAND; this is a read-only operation
+    RawVerify {
+        /// The path to the image to compare against
+        path: PathBuf,
+
+        /// Whether to skip over any bad blocks encountered, as `raw-write --skip-bad` does
+        #[clap(long)]
+        skip_bad: bool,
+    },
+
+    /// Dump the NAND's raw contents to a file, for inspection; this is a read-only operation
+    RawRead {
+        /// The path of the file to write the dump to
+        out: PathBuf,
+
+        /// How many bytes to dump (the whole NAND by default)
+        #[clap(long)]
+        length: Option<u64>,
+
+        /// Whether to dump bad blocks as 0xFF (keeping offsets the same) rather than failing
+        #[clap(long)]
+        skip_bad: bool,
     },
 
     /// Look for Allwinner's boot0 blocks and erase them.
     PurgeBoot0,
+
+    /// Erase, program and read back every good block with test patterns, marking bad those that
+    /// keep failing; this destroys everything on the NAND
+    BurnIn {
+        /// How many times to go through the patterns
+        #[clap(long, default_value_t = 1)]
+        passes: u32,
+
+        /// How many times a block may fail before it's marked bad
+        #[clap(long, default_value_t = BurnInOptions::default().max_failures)]
+        max_failures: u32,
+
+        /// Go ahead even if the NAND holds UBI data
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Apply the erases, programs and bad-block marks recorded by `--trace` to the NAND, e.g. a
+    /// simulated one written back with `--sim-write`, to reproduce the state a traced NAND was
+    /// left in
+    TraceReplay {
+        /// The path to the trace
+        path: PathBuf,
+    },
+
+    /// Look for Allwinner's boot0 in the boot area of an SD card or eMMC, and zero it out; this
+    /// works on the given device rather than the NAND
+    PurgeBoot0Blockdev {
+        /// The path to the block device (e.g. `/dev/mmcblk0`) or image file
+        path: PathBuf,
+
+        /// An offset to look at, in bytes; may be given several times (8 KiB and 128 KiB, where
+        /// the Boot ROM looks, by default)
+        #[clap(long = "offset")]
+        offsets: Vec<u64>,
+    },
+
+    /// List the sections of an installation bundle; this works on the given file rather than the
+    /// NAND
+    BundleInfo {
+        /// The path to the bundle
+        path: PathBuf,
+
+        /// Whether to also check every section's CRC
+        #[clap(long)]
+        verify: bool,
+    },
 }
 
 impl Command {
-    fn execute(self, nand: &mut NandImpl) -> Result<()> {
+    /// Run the command, then say what it did to the NAND
+    fn execute(self, session: &mut Session) -> Result<()> {
+        let before = session.nand.stats();
+        let result = self.run(session);
+        println!("NAND operations: {}", session.nand.stats().since(&before));
+        result
+    }
+
+    fn run(self, session: &mut Session) -> Result<()> {
         match self {
             Command::UbiOverview => {
-                let ebt = nand.do_scan()?;
+                let (nand, ebt) = session.scanned()?;
 
                 for (i, content) in ebt.iter().enumerate() {
-                    println!("{i:4} => {content:?}");
+                    println!("{i:4} => {content}");
+                }
+                print!("{}", summarize(ebt));
+
+                match nand {
+                    NandImpl::Sim(nand) => volume_overview(nand, ebt),
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => volume_overview(nand, ebt),
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => volume_overview(nand, ebt),
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => volume_overview(nand, ebt),
                 }
             }
 
-            Command::UbiFormat => {
-                let mut ebt = nand.do_scan()?;
+            Command::UbiInspect { block, pages } => match &mut session.nand {
+                NandImpl::Sim(nand) => inspect_block(nand, block, pages)?,
 
-                nand.do_format(&mut ebt)?;
+                #[cfg(unix)]
+                NandImpl::BlockDev(nand) => inspect_block(nand, block, pages)?,
+
+                #[cfg(unix)]
+                NandImpl::File(nand) => inspect_block(nand, block, pages)?,
+
+                #[cfg(feature = "linux-hw")]
+                NandImpl::Mtd(nand) => inspect_block(nand, block, pages)?,
+            },
+
+            Command::UbiFormat { ec_policy } => {
+                let (nand, ebt) = session.scanned()?;
+                let before = ebt.clone();
+
+                let stats = nand.do_format(ebt, ec_policy)?;
+                println!("Formatted: {stats:?}");
+                print!("{:#}", diff(&before, ebt));
             }
 
             Command::UbiWrite(volume) => {
                 let volume: BasicVolume<'static> = volume.into();
                 let volume: Box<dyn Volume> = Box::new(volume);
 
-                let mut ebt = nand.do_scan()?;
+                let (nand, ebt) = session.scanned()?;
+                let before = ebt.clone();
+
+                nand.do_format(ebt, EcPolicy::default())?;
+
+                let stats = match nand {
+                    NandImpl::Sim(nand) => write_volumes(nand, ebt, [volume])?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => write_volumes(nand, ebt, [volume])?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => write_volumes(nand, ebt, [volume])?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => write_volumes(nand, ebt, [volume])?,
+                };
+
+                println!("Written: {stats:?}");
+                print!("{}", diff(&before, ebt));
+            }
+
+            Command::UbiImport { path } => {
+                let image = BufReader::new(File::open(path)?);
+                let (nand, ebt) = session.scanned()?;
+                let before = ebt.clone();
+
+                nand.do_format(ebt, EcPolicy::default())?;
+
+                let stats = match nand {
+                    NandImpl::Sim(nand) => import_ubi_image(nand, ebt, image)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => import_ubi_image(nand, ebt, image)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => import_ubi_image(nand, ebt, image)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => import_ubi_image(nand, ebt, image)?,
+                };
+
+                println!("Written: {stats:?}");
+                print!("{}", diff(&before, ebt));
+            }
+
+            Command::UbiRead { name, out } => {
+                let (nand, ebt) = session.scanned()?;
+                let mut out = File::create(out)?;
+
+                let len = match nand {
+                    NandImpl::Sim(nand) => read_volume(nand, ebt, &name, &mut out)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => read_volume(nand, ebt, &name, &mut out)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => read_volume(nand, ebt, &name, &mut out)?,
 
-                nand.do_format(&mut ebt)?;
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => read_volume(nand, ebt, &name, &mut out)?,
+                };
+
+                println!("Read {len} bytes from volume {name}");
+            }
+
+            Command::UbiUpdate { name, path } => {
+                let data = std::fs::read(path)?;
+                let (nand, ebt) = session.scanned()?;
+                let before = ebt.clone();
 
                 match nand {
-                    NandImpl::Sim(nand) => write_volumes(nand, &mut ebt, [volume])?,
+                    NandImpl::Sim(nand) => update_volume(nand, ebt, &name, &data)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => update_volume(nand, ebt, &name, &data)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => update_volume(nand, ebt, &name, &data)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => update_volume(nand, ebt, &name, &data)?,
+                };
+
+                println!("Wrote {} bytes to volume {name}", data.len());
+                print!("{}", diff(&before, ebt));
+            }
+
+            #[cfg(feature = "linux-hw")]
+            Command::InstallLog => {
+                let (nand, ebt) = session.scanned()?;
+
+                let log = match nand {
+                    NandImpl::Sim(nand) => read_log_volume(nand, ebt)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => read_log_volume(nand, ebt)?,
 
-                    #[cfg(target_os = "linux")]
-                    NandImpl::Mtd(nand) => write_volumes(nand, &mut ebt, [volume])?,
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => read_log_volume(nand, ebt)?,
+
+                    NandImpl::Mtd(nand) => read_log_volume(nand, ebt)?,
+                };
+
+                match log {
+                    Some(log) => print!("{log}"),
+                    None => println!("Volume {INSTALL_LOG_VOLUME_NAME:?} holds no log"),
                 }
             }
 
-            Command::RawWrite { path, skip_bad } => {
-                let mut image = File::open(path)?;
+            Command::RootfsExtract { volume, path, out } => {
+                let (nand, ebt) = session.scanned()?;
+
+                // The filesystem must be seekable, so spool the volume into a temporary file first
+                let spool_path =
+                    std::env::temp_dir().join(format!("rootfs-extract-{}.img", std::process::id()));
+                let mut spool = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&spool_path)?;
+                let _ = std::fs::remove_file(&spool_path);
 
                 match nand {
-                    NandImpl::Sim(nand) => write_raw_image(nand, &mut image, skip_bad)?,
+                    NandImpl::Sim(nand) => read_volume(nand, ebt, &volume, &mut spool)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => read_volume(nand, ebt, &volume, &mut spool)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => read_volume(nand, ebt, &volume, &mut spool)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => read_volume(nand, ebt, &volume, &mut spool)?,
+                };
+                spool.rewind()?;
+
+                let paths: Vec<&str> = path.iter().map(String::as_str).collect();
+                let report = match image::detect(&mut spool)? {
+                    ImageKind::Squashfs => squashfs::extract_paths(spool, &paths, &out)?,
+                    _ => erofs::extract_paths(spool, &paths, &out)?,
+                };
+                for (path, outcome) in &report.paths {
+                    match outcome {
+                        PathOutcome::File { bytes } => println!("{path}: {bytes} bytes"),
+                        PathOutcome::Symlink { target } => println!("{path} -> {target}"),
+                        PathOutcome::Unsupported(why) => println!("{path}: unsupported: {why}"),
+                        PathOutcome::Failed(why) => println!("{path}: failed: {why}"),
+                    }
+                }
+                anyhow::ensure!(report.all_ok(), "Not every path could be extracted");
+            }
+
+            Command::RawWrite {
+                path,
+                skip_bad,
+                clean,
+                length,
+            } => {
+                let mut image = File::open(path)?;
+                let metadata = image.metadata()?;
+                let length = length.or(metadata.is_file().then_some(metadata.len()));
+
+                session.invalidate();
+                match &mut session.nand {
+                    NandImpl::Sim(nand) => raw_write(nand, &mut image, skip_bad, clean, length)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => {
+                        raw_write(nand, &mut image, skip_bad, clean, length)?
+                    }
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => raw_write(nand, &mut image, skip_bad, clean, length)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => raw_write(nand, &mut image, skip_bad, clean, length)?,
+                };
+            }
+
+            Command::RawVerify { path, skip_bad } => {
+                let mut image = File::open(path)?;
 
-                    #[cfg(target_os = "linux")]
-                    NandImpl::Mtd(nand) => write_raw_image(nand, &mut image, skip_bad)?,
+                let result = match &mut session.nand {
+                    NandImpl::Sim(nand) => verify_raw_image(nand, &mut image, skip_bad)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => verify_raw_image(nand, &mut image, skip_bad)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => verify_raw_image(nand, &mut image, skip_bad)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => verify_raw_image(nand, &mut image, skip_bad)?,
+                };
+
+                match result {
+                    RawVerifyResult::Match { bytes } => println!("Verified: {bytes} bytes match"),
+                    RawVerifyResult::Mismatch(mismatch) => print!("Verify failed: {mismatch}"),
                 }
             }
 
+            Command::RawRead {
+                out,
+                length,
+                skip_bad,
+            } => {
+                let mut out = BufWriter::new(File::create(out)?);
+
+                let bytes = match &mut session.nand {
+                    NandImpl::Sim(nand) => read_raw_image(nand, &mut out, skip_bad, length)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => read_raw_image(nand, &mut out, skip_bad, length)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => read_raw_image(nand, &mut out, skip_bad, length)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => read_raw_image(nand, &mut out, skip_bad, length)?,
+                };
+                out.flush()?;
+
+                println!("Read: {bytes} bytes");
+            }
+
             Command::PurgeBoot0 => {
-                let purged = match nand {
+                session.invalidate();
+                let purged = match &mut session.nand {
                     NandImpl::Sim(nand) => purge_boot0(nand)?,
 
-                    #[cfg(target_os = "linux")]
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => purge_boot0(nand)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => purge_boot0(nand)?,
+
+                    #[cfg(feature = "linux-hw")]
                     NandImpl::Mtd(nand) => purge_boot0(nand)?,
                 };
 
                 println!("Purged: {purged:?}");
             }
+
+            Command::TraceReplay { path } => {
+                let entries = read_trace_csv(BufReader::new(File::open(path)?))?;
+                session.invalidate();
+                match &mut session.nand {
+                    NandImpl::Sim(nand) => replay_trace(nand, &entries)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => replay_trace(nand, &entries)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => replay_trace(nand, &entries)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => replay_trace(nand, &entries)?,
+                };
+
+                println!("Replayed: {} operations", entries.len());
+            }
+
+            Command::BurnIn {
+                passes,
+                max_failures,
+                force,
+            } => {
+                let (nand, ebt) = session.scanned()?;
+                let in_use = ebt
+                    .iter()
+                    .filter(|x| matches!(x, BlockContent::EcData(..) | BlockContent::VidOnly(_)))
+                    .count();
+                anyhow::ensure!(
+                    force || in_use == 0,
+                    "{in_use} blocks hold UBI data; use --force to burn in anyway"
+                );
+
+                let options = BurnInOptions {
+                    passes,
+                    max_failures,
+                    ..Default::default()
+                };
+                let progress = |done, total| {
+                    if done % 64 == 0 || done == total {
+                        println!("Burned in {done}/{total} blocks");
+                    }
+                };
+                let report = match nand {
+                    NandImpl::Sim(nand) => burn_in_with(nand, &options, progress)?,
+
+                    #[cfg(unix)]
+                    NandImpl::BlockDev(nand) => burn_in_with(nand, &options, progress)?,
+
+                    #[cfg(unix)]
+                    NandImpl::File(nand) => burn_in_with(nand, &options, progress)?,
+
+                    #[cfg(feature = "linux-hw")]
+                    NandImpl::Mtd(nand) => burn_in_with(nand, &options, progress)?,
+                };
+                session.invalidate();
+
+                print!("{report}");
+            }
+
+            Command::PurgeBoot0Blockdev { path, offsets } => {
+                let offsets = match &offsets[..] {
+                    [] => MMC_BOOT_OFFSETS,
+                    offsets => offsets,
+                };
+                let mut dev = File::options().read(true).write(true).open(path)?;
+
+                let cleaned = erase_legacy_boot(&mut dev, offsets)?;
+                println!("Zeroed boot0 at offsets: {cleaned:?}");
+            }
+
+            Command::BundleInfo { path, verify } => {
+                let mut bundle = Bundle::open(File::open(path)?)?;
+                for entry in bundle.sections() {
+                    println!("{entry}");
+                }
+
+                if verify {
+                    bundle.verify()?;
+                    println!("Verified: every section's CRC matches");
+                }
+            }
         };
 
         Ok(())
     }
 }
 
+/// Carry out [Command::RawWrite]
+fn raw_write<N: Nand>(
+    nand: &mut N,
+    image: &mut File,
+    skip_bad: bool,
+    clean: bool,
+    length: Option<u64>,
+) -> Result<()> {
+    let stats = write_raw_image_sized(nand, image, skip_bad, length)?;
+    println!("Written: {stats:?}");
+
+    if clean {
+        image.rewind()?;
+        let erased = clean_partition(nand, Some(image))?;
+        println!("Erased {erased} blocks past the image");
+    }
+
+    Ok(())
+}
+
+/// Print what [Command::UbiOverview] shows of the volumes, warning of any trouble with the copies
+/// of the volume table
+fn volume_overview<N: Nand>(nand: &mut N, ebt: &Ebt) {
+    let copies = read_volume_table_copies(nand, ebt);
+    if copies.len() != 2 {
+        println!(
+            "WARNING: expected 2 copies of the volume table, found {}",
+            copies.len()
+        );
+    }
+    for copy in &copies {
+        if copy.table.is_none() {
+            println!(
+                "WARNING: the volume table in LEB {} (block {}) is unreadable or corrupt",
+                copy.lnum, copy.block
+            );
+        }
+    }
+    let tables: Vec<_> = copies.iter().filter_map(|x| x.table.as_ref()).collect();
+    if tables.windows(2).any(|x| x[0] != x[1]) {
+        println!("WARNING: the copies of the volume table disagree; going by the first");
+    }
+
+    match tables.first() {
+        Some(table) => print!("{}", summarize_volumes(ebt, table)),
+        None => println!("No intact volume table, so no volumes to show"),
+    }
+}
+
+/// Print what [Command::UbiInspect] shows of a block
+fn inspect_block<N: Nand>(nand: &mut N, index: u32, pages: u32) -> Result<()> {
+    /// How much of each page to dump; enough for the EC or VID header
+    const DUMP_BYTES: usize = 64;
+
+    let layout = nand.get_layout();
+    let page_size = layout.bytes_per_page;
+    let Some(block) = nand.block(index)? else {
+        println!("Block {index} is bad");
+        return Ok(());
+    };
+
+    let mut page = vec![0; page_size];
+    for number in 0..pages.min(layout.pages_per_block) {
+        block.read(number, &mut page)?;
+        let offset = u64::from(number) * page_size as u64;
+        println!("Page {number}:");
+        print!(
+            "{}",
+            HexDump::new(&page[..DUMP_BYTES.min(page_size)]).offset(offset)
+        );
+    }
+
+    block.read(0, &mut page)?;
+    let ec = Ec::decode(&page);
+    match &ec {
+        Some(ec) => print!("EC header:\n{ec}"),
+        None => println!("No EC header"),
+    }
+
+    // Without an EC header to say where the VID header is, assume the usual place
+    let vid_page = ec.map_or(Ok(1), |ec| ec.vid_hdr_page(page_size))?;
+    block.read(vid_page, &mut page)?;
+    let vid = Vid::decode(&page);
+    match &vid {
+        Some(vid) => print!("VID header:\n{vid}"),
+        None => println!("No VID header"),
+    }
+
+    let Some((ec, vid)) = ec.zip(vid) else {
+        return Ok(());
+    };
+    let data_page = ec.data_page(page_size)?;
+    let mut data = vec![0; (layout.pages_per_block - data_page) as usize * page_size];
+    block.read(data_page, &mut data)?;
+
+    #[cfg(feature = "linux-hw")]
+    if vid.vol_id == FIRSTBOOT_VOLUME_ID {
+        match Firstboot::decode(&data) {
+            Some(firstboot) => println!("First boot record: {firstboot}"),
+            None => println!("First boot record is corrupt"),
+        }
+    }
+
+    if vid.vol_id == UBI_LAYOUT_VOLUME_ID {
+        let Some(table) = decode_volume_table(&data) else {
+            println!("Volume table is corrupt");
+            return Ok(());
+        };
+        for (id, record) in table.iter().enumerate() {
+            if let Some(record) = record {
+                print!("Volume {id}:\n{record}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a range of PEBs, given either as `start..end` or as a single PEB number
+fn parse_block_range(s: &str) -> Result<Range<usize>> {
+    Ok(match s.split_once("..") {
+        Some((start, end)) => start.parse()?..end.parse()?,
+        None => {
+            let block = s.parse()?;
+            block..block + 1
+        }
+    })
+}
+
+#[derive(Subcommand, Debug)]
+enum ShellCommand {
+    #[clap(flatten)]
+    Flash(Command),
+
+    /// Print the cached EBT, optionally limited to a range of PEBs (e.g. `100..120`)
+    Ebt {
+        #[clap(value_parser = parse_block_range)]
+        range: Option<Range<usize>>,
+    },
+
+    /// Change one of the shell's tunables
+    Set {
+        #[clap(subcommand)]
+        tunable: Tunable,
+    },
+
+    /// Run a command and report how long it took
+    Time {
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        cmd: Vec<String>,
+    },
+
+    /// Discard the cached EBT and scan the NAND again
+    #[clap(name = "!rescan")]
+    Rescan,
+
+    /// Leave the shell, writing back the NAND file if `--sim-write` was given
+    Quit {
+        /// Leave without writing back the NAND file
+        #[clap(long)]
+        no_save: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Tunable {
+    /// Report how long every command takes
+    Timing {
+        #[clap(value_parser = clap::builder::BoolishValueParser::new())]
+        value: bool,
+    },
+
+    /// Scan the NAND before every command instead of reusing the cached EBT
+    Autoscan {
+        #[clap(value_parser = clap::builder::BoolishValueParser::new())]
+        value: bool,
+    },
+}
+
+/// One line of shell input, parsed with the same definitions as the command line
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(name = "shell", no_binary_name = true)]
+struct ShellLine {
+    #[clap(subcommand)]
+    cmd: ShellCommand,
+}
+
+/// An interactive session against one NAND, for poking around without rescanning every time
+struct Shell<'a> {
+    options: &'a NandOptions,
+    session: Session,
+    timing: bool,
+    autoscan: bool,
+}
+
+impl<'a> Shell<'a> {
+    fn new(options: &'a NandOptions, nand: NandImpl) -> Self {
+        Self {
+            options,
+            session: Session::new(nand),
+            timing: false,
+            autoscan: false,
+        }
+    }
+
+    /// Read and run commands from `input` until `quit` or end of input.
+    ///
+    /// Errors are reported to `output` rather than ending the session.
+    fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> Result<()> {
+        let mut lines = input.lines();
+        loop {
+            write!(output, "> ")?;
+            output.flush()?;
+
+            let Some(line) = lines.next().transpose()? else {
+                // End of input is as good as a plain `quit`
+                writeln!(output)?;
+                return self.quit(false, &mut output);
+            };
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+
+            match self.execute(&words, &mut output) {
+                Ok(true) => return Ok(()),
+                Ok(false) => (),
+                Err(e) => writeln!(output, "Error: {e:#}")?,
+            }
+        }
+    }
+
+    /// Run one command line; returns whether the shell should exit
+    fn execute<W: Write>(&mut self, words: &[&str], output: &mut W) -> Result<bool> {
+        let cmd = match ShellLine::try_parse_from(words) {
+            Ok(line) => line.cmd,
+            Err(e) => {
+                // This covers `help` as well as genuine parse errors
+                write!(output, "{}", e.render())?;
+                return Ok(false);
+            }
+        };
+
+        if self.autoscan {
+            self.session.invalidate();
+        }
+
+        let start = Instant::now();
+        let exit = match cmd {
+            ShellCommand::Flash(cmd) => {
+                cmd.execute(&mut self.session)?;
+                false
+            }
+
+            ShellCommand::Ebt { range } => {
+                let (_, ebt) = self.session.scanned()?;
+                let range = range.unwrap_or(0..ebt.len());
+                let entries = ebt
+                    .get(range.clone())
+                    .ok_or(anyhow::anyhow!("{range:?} is out of bounds"))?;
+
+                for (i, content) in range.zip(entries) {
+                    writeln!(output, "{i:4} => {content:?}")?;
+                }
+                false
+            }
+
+            ShellCommand::Set { tunable } => {
+                match tunable {
+                    Tunable::Timing { value } => self.timing = value,
+                    Tunable::Autoscan { value } => self.autoscan = value,
+                }
+                false
+            }
+
+            ShellCommand::Time { cmd } => {
+                let words: Vec<&str> = cmd.iter().map(String::as_str).collect();
+                let timing = std::mem::replace(&mut self.timing, true);
+                let result = self.execute(&words, output);
+                self.timing = timing;
+                return result;
+            }
+
+            ShellCommand::Rescan => {
+                self.session.invalidate();
+                self.session.scanned()?;
+                false
+            }
+
+            ShellCommand::Quit { no_save } => self.quit(no_save, output).map(|_| true)?,
+        };
+
+        if self.timing {
+            writeln!(output, "Took {:.3?}", start.elapsed())?;
+        }
+
+        Ok(exit)
+    }
+
+    fn quit<W: Write>(&mut self, no_save: bool, output: &mut W) -> Result<()> {
+        if !no_save && self.options.cleanup(&mut self.session.nand)? {
+            writeln!(output, "Saved NAND image")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, long_version = buildinfo::long_version(), about)]
 struct Cli {
     /// The NAND to use
     #[clap(flatten)]
@@ -265,15 +1300,177 @@ struct Cli {
 
     /// The flashing command to run against this NAND
     #[clap(subcommand)]
-    cmd: Command,
+    cmd: CliCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    #[clap(flatten)]
+    Flash(Command),
+
+    /// Start an interactive shell that keeps the NAND open and the EBT cached between commands
+    Shell,
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
     howudoin::init(howudoin::consumers::TermLine::default());
 
-    let mut nand = args.nand.open()?;
-    args.cmd.execute(&mut nand)?;
-    args.nand.cleanup(nand)?;
+    let _lock = args.nand.lock()?;
+    let nand = args.nand.open()?;
+    match args.cmd {
+        CliCommand::Flash(cmd) => {
+            let mut session = Session::new(nand);
+            cmd.execute(&mut session)?;
+            args.nand.cleanup(&mut session.nand)?;
+        }
+
+        CliCommand::Shell => {
+            Shell::new(&args.nand, nand).run(std::io::stdin().lock(), std::io::stdout())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_shell(sim_write: bool) -> Result<(NandOptions, NandImpl)> {
+    let path = std::env::temp_dir().join(format!(
+        "test_flashing-{}-{sim_write}.bin",
+        std::process::id()
+    ));
+    SimNand::new("16x16x128".parse()?).save(&mut File::create(&path)?)?;
+
+    let mut args = vec!["test_flashing", "--sim-layout", "16x16x128", "--sim-path"];
+    args.push(path.to_str().unwrap());
+    if sim_write {
+        args.push("--sim-write");
+    }
+    args.push("shell");
+
+    let options = Cli::try_parse_from(args)?.nand;
+    let nand = options.open()?;
+    Ok((options, nand))
+}
+
+#[test]
+fn test_shell_session() -> Result<()> {
+    let (options, nand) = test_shell(false)?;
+    let mut shell = Shell::new(&options, nand);
+
+    let script =
+        "ebt 0..2\nbogus\nubi-read nonexistent /dev/null\nubi-format\n\ntime ebt 3\nquit\nebt\n";
+    let mut output = Vec::new();
+    shell.run(script.as_bytes(), &mut output)?;
+    let output = String::from_utf8(output)?;
+
+    // Errors are reported, but the session carries on
+    assert!(output.contains("unrecognized subcommand 'bogus'"));
+    assert!(output.contains("Error: no intact copy of the UBI volume table found"));
+    assert!(output.contains("   0 => Erased\n   1 => Erased\n"));
+    assert!(output.contains("   3 => EcErased("));
+    assert!(output.contains("Took "));
+
+    // The format is reflected in the cached EBT; nothing after `quit` runs, and nothing is saved
+    let ebt = shell.session.ebt.as_ref().unwrap();
+    assert!(ebt.iter().all(|x| matches!(x, BlockContent::EcErased(_))));
+    assert!(!output.contains("   15 =>"));
+    assert!(!output.contains("Saved"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "linux-hw"), allow(irrefutable_let_patterns))]
+fn test_shell_save() -> Result<()> {
+    let (options, nand) = test_shell(true)?;
+
+    let mut output = Vec::new();
+    Shell::new(&options, nand).run("ubi-format\nquit\n".as_bytes(), &mut output)?;
+    assert_eq!(String::from_utf8(output)?.matches("Saved").count(), 1);
+
+    // The formatted NAND made it to the file
+    let NandImpl::Sim(mut nand) = options.open()? else {
+        unreachable!()
+    };
+    std::fs::remove_file(options.sim_path.as_ref().unwrap())?;
+    let ebt = scan_blocks(&mut nand)?;
+    assert!(ebt
+        .iter()
+        .all(|x| !matches!(x, bmc_installer::ubi::BlockContent::Erased)));
+
+    // End of input saves too, but `quit --no-save` doesn't
+    let (options, nand) = test_shell(true)?;
+    let mut output = Vec::new();
+    Shell::new(&options, nand).run("".as_bytes(), &mut output)?;
+    assert_eq!(String::from_utf8(output)?.matches("Saved").count(), 1);
+
+    let (options, nand) = test_shell(true)?;
+    let mut output = Vec::new();
+    Shell::new(&options, nand).run("quit --no-save\n".as_bytes(), &mut output)?;
+    std::fs::remove_file(options.sim_path.as_ref().unwrap())?;
+    assert_eq!(String::from_utf8(output)?.matches("Saved").count(), 0);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_file_nand_option() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("test_flashing-{}.nand", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+    let options = |args: &[&str]| -> Result<NandOptions> {
+        let mut all = vec!["test_flashing", "--file-nand", path];
+        all.extend(args);
+        all.push("shell");
+        Ok(Cli::try_parse_from(all)?.nand)
+    };
+
+    // A new file needs a layout, and is created with it
+    assert!(options(&[])?.open().is_err());
+    let created = options(&["--layout", "16x16x128"])?;
+    let mut output = Vec::new();
+    Shell::new(&created, created.open()?).run("ubi-format\n".as_bytes(), &mut output)?;
+    assert!(!String::from_utf8(output)?.contains("Saved"));
+
+    // Reopening it needs no layout, and finds the format without any saving
+    let reopened = options(&[])?;
+    let mut output = Vec::new();
+    Shell::new(&reopened, reopened.open()?).run("ebt 3\n".as_bytes(), &mut output)?;
+    assert!(String::from_utf8(output)?.contains("   3 => EcErased("));
+
+    // ...but not with a layout other than its own
+    assert!(options(&["--layout", "8x16x128"])?.open().is_err());
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_trace_replay_command() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("test_flashing-{}.csv", std::process::id()));
+    let options = |args: &[&str]| -> Result<NandOptions> {
+        let mut all = vec!["test_flashing", "--layout", "16x16x128"];
+        all.extend(args);
+        all.push("shell");
+        Ok(Cli::try_parse_from(all)?.nand)
+    };
+
+    // A format traced on one NAND...
+    let traced = options(&["--trace", path.to_str().unwrap()])?;
+    let mut output = Vec::new();
+    Shell::new(&traced, traced.open()?).run("ubi-format\n".as_bytes(), &mut output)?;
+
+    // ...is reproduced on another from the trace file alone
+    let fresh = options(&[])?;
+    let mut shell = Shell::new(&fresh, fresh.open()?);
+    let script = format!("trace-replay {}\nebt 3\n", path.display());
+    let mut output = Vec::new();
+    shell.run(script.as_bytes(), &mut output)?;
+    std::fs::remove_file(&path)?;
+    let output = String::from_utf8(output)?;
+    assert!(output.contains("   3 => EcErased("), "{output}");
+
     Ok(())
 }
@@ -0,0 +1,64 @@
+//! Which build of the installer this is, so that what it prints and logs can be traced back to the
+//! source it was built from. The git description and build time come from the build script.
+
+use std::sync::OnceLock;
+
+/// The crate's version
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// What `git describe` said of the tree, if it was built from a git checkout (rather than, say, a
+/// tarball) with git to hand
+pub const GIT_DESCRIBE: Option<&str> = option_env!("BMC_INSTALLER_GIT_DESCRIBE");
+
+/// When the installer was built (or the time `SOURCE_DATE_EPOCH` gave), in UTC
+pub const BUILD_TIME: &str = env!("BMC_INSTALLER_BUILD_TIME");
+
+/// The crate features the installer was built with
+pub const FEATURES: &[&str] = &[
+    #[cfg(feature = "linux-hw")]
+    "linux-hw",
+];
+
+/// Everything known about this build, on one line, e.g.
+/// `bmc-installer 0.1.0 (git v0.1.0-3-gabc1234, built 2024-03-01 12:34:56 UTC, features: linux-hw)`
+pub fn summary() -> String {
+    format!("bmc-installer {}", long_version())
+}
+
+/// [summary], without the name in front, as `--version` gives it
+pub fn long_version() -> &'static str {
+    static LONG_VERSION: OnceLock<String> = OnceLock::new();
+    LONG_VERSION.get_or_init(|| format_version(VERSION, GIT_DESCRIBE, BUILD_TIME, FEATURES))
+}
+
+fn format_version(version: &str, git: Option<&str>, built: &str, features: &[&str]) -> String {
+    let features = match features {
+        [] => "none".to_string(),
+        features => features.join(", "),
+    };
+    format!(
+        "{version} (git {}, built {built}, features: {features})",
+        git.unwrap_or("unknown")
+    )
+}
+
+#[test]
+fn test_summary() {
+    let summary = summary();
+    assert!(
+        summary.starts_with(&format!("bmc-installer {VERSION} (git ")),
+        "{summary}"
+    );
+    assert!(summary.contains(BUILD_TIME), "{summary}");
+    assert!(!summary.contains('\n'), "{summary}");
+
+    // A build without git to describe it says so, rather than leaving a blank
+    assert_eq!(
+        format_version("1.2.3", None, "2024-03-01 12:34:56 UTC", &[]),
+        "1.2.3 (git unknown, built 2024-03-01 12:34:56 UTC, features: none)"
+    );
+    assert_eq!(
+        format_version("1.2.3", Some("v1.2.3-dirty"), "then", &["a", "b"]),
+        "1.2.3 (git v1.2.3-dirty, built then, features: a, b)"
+    );
+}
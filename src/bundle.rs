@@ -0,0 +1,385 @@
+//! A single-file container for everything an installation needs, so that it can be handed over as
+//! one artifact rather than as a partitioned SD card or several separate transfers.
+//!
+//! All integers are little-endian:
+//!
+//! ```text
+//! 0x00  magic "TPBUNDLE"
+//! 0x08  version (u32, currently 1)
+//! 0x0C  section count (u32)
+//! 0x10  sections, 24 bytes each: kind (u32), CRC32 (u32), offset (u64), length (u64)
+//! ....  CRC32 of everything above
+//! ....  section data, wherever the offsets say
+//! ```
+//!
+//! Sections of kinds unknown to this version are skipped over, so that later versions may add
+//! some without breaking older installers.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+const BUNDLE_MAGIC: &[u8; 8] = b"TPBUNDLE";
+const BUNDLE_VERSION: u32 = 1;
+const BUNDLE_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// The size of the fixed part of the header, before the section table
+const HEADER_SIZE: usize = 16;
+
+/// The size of each entry of the section table
+const SECTION_ENTRY_SIZE: usize = 24;
+
+/// The most sections a bundle may have; far more than will ever be needed, but it keeps a corrupt
+/// count from having a huge table read
+const MAX_SECTIONS: u32 = 64;
+
+/// What a section of a [Bundle] holds
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SectionKind {
+    /// The bootloader, to be written to the `boot` partition
+    Bootloader,
+
+    /// The rootfs image, optionally compressed
+    Rootfs,
+
+    /// The contents of the board's EEPROM
+    Eeprom,
+
+    /// A UBI layout, in the same format as the SD card's `bmc-layout.conf`
+    Layout,
+
+    /// A kind this version doesn't know about
+    Unknown(u32),
+}
+
+impl From<u32> for SectionKind {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => Self::Bootloader,
+            2 => Self::Rootfs,
+            3 => Self::Eeprom,
+            4 => Self::Layout,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+impl From<SectionKind> for u32 {
+    fn from(value: SectionKind) -> Self {
+        match value {
+            SectionKind::Bootloader => 1,
+            SectionKind::Rootfs => 2,
+            SectionKind::Eeprom => 3,
+            SectionKind::Layout => 4,
+            SectionKind::Unknown(x) => x,
+        }
+    }
+}
+
+impl fmt::Display for SectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bootloader => write!(f, "bootloader"),
+            Self::Rootfs => write!(f, "rootfs"),
+            Self::Eeprom => write!(f, "eeprom"),
+            Self::Layout => write!(f, "layout"),
+            Self::Unknown(x) => write!(f, "unknown ({x})"),
+        }
+    }
+}
+
+/// One entry of a [Bundle]'s section table
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SectionEntry {
+    pub kind: SectionKind,
+
+    /// The CRC32 of the section's data
+    pub crc: u32,
+
+    /// Where the section's data starts in the bundle
+    pub offset: u64,
+
+    /// How long the section's data is
+    pub length: u64,
+}
+
+impl fmt::Display for SectionEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} bytes at offset {:#x}, CRC32 {:08x}",
+            self.kind, self.length, self.offset, self.crc
+        )
+    }
+}
+
+/// An installation bundle, opened for reading its sections
+#[derive(Debug)]
+pub struct Bundle<R> {
+    inner: R,
+    sections: Vec<SectionEntry>,
+}
+
+impl<R: Read + Seek> Bundle<R> {
+    /// Read and check a bundle's header; the sections themselves are only checked by
+    /// [Bundle::verify]
+    pub fn open(mut inner: R) -> anyhow::Result<Self> {
+        let file_len = inner.seek(SeekFrom::End(0))?;
+        inner.rewind()?;
+
+        let mut header = vec![0; HEADER_SIZE];
+        inner.read_exact(&mut header)?;
+        anyhow::ensure!(&header[..8] == BUNDLE_MAGIC, "not an installation bundle");
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        anyhow::ensure!(
+            version == BUNDLE_VERSION,
+            "unsupported bundle version {version} (expected {BUNDLE_VERSION})"
+        );
+        let count = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        anyhow::ensure!(count <= MAX_SECTIONS, "too many sections ({count})");
+
+        header.resize(HEADER_SIZE + count as usize * SECTION_ENTRY_SIZE + 4, 0);
+        inner.read_exact(&mut header[HEADER_SIZE..])?;
+        let (table, crc) = header.split_at(header.len() - 4);
+        anyhow::ensure!(
+            BUNDLE_CRC.checksum(table) == u32::from_le_bytes(crc.try_into().unwrap()),
+            "bundle header is corrupt"
+        );
+
+        let sections = table[HEADER_SIZE..]
+            .chunks_exact(SECTION_ENTRY_SIZE)
+            .map(|raw| {
+                let entry = SectionEntry {
+                    kind: u32::from_le_bytes(raw[0..4].try_into().unwrap()).into(),
+                    crc: u32::from_le_bytes(raw[4..8].try_into().unwrap()),
+                    offset: u64::from_le_bytes(raw[8..16].try_into().unwrap()),
+                    length: u64::from_le_bytes(raw[16..24].try_into().unwrap()),
+                };
+                let in_bounds = entry
+                    .offset
+                    .checked_add(entry.length)
+                    .is_some_and(|end| end <= file_len);
+                anyhow::ensure!(in_bounds, "{} section runs past the end", entry.kind);
+                Ok(entry)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        for (i, entry) in sections.iter().enumerate() {
+            let known = !matches!(entry.kind, SectionKind::Unknown(_));
+            anyhow::ensure!(
+                !known || sections[..i].iter().all(|x| x.kind != entry.kind),
+                "duplicate {} section",
+                entry.kind
+            );
+        }
+
+        Ok(Self { inner, sections })
+    }
+
+    /// The section table, in the order it's stored
+    pub fn sections(&self) -> &[SectionEntry] {
+        &self.sections
+    }
+
+    /// Find the entry for a kind of section, if the bundle has one
+    pub fn entry(&self, kind: SectionKind) -> Option<&SectionEntry> {
+        self.sections.iter().find(|x| x.kind == kind)
+    }
+
+    /// Get a reader over a kind of section, if the bundle has one
+    pub fn section(&mut self, kind: SectionKind) -> anyhow::Result<Option<Section<'_, R>>> {
+        let Some(&entry) = self.entry(kind) else {
+            return Ok(None);
+        };
+        self.inner.seek(SeekFrom::Start(entry.offset))?;
+
+        Ok(Some(Section {
+            inner: &mut self.inner,
+            start: entry.offset,
+            len: entry.length,
+            pos: 0,
+        }))
+    }
+
+    /// Read a whole (small) section into memory
+    pub fn read_section(&mut self, kind: SectionKind) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(mut section) = self.section(kind)? else {
+            return Ok(None);
+        };
+        let mut data = vec![];
+        section.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Check every section against its CRC32
+    pub fn verify(&mut self) -> anyhow::Result<()> {
+        let mut buf = vec![0; 64 << 10];
+        for entry in self.sections.clone() {
+            self.inner.seek(SeekFrom::Start(entry.offset))?;
+            let mut digest = BUNDLE_CRC.digest();
+            let mut section = (&mut self.inner).take(entry.length);
+            loop {
+                let len = section.read(&mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                digest.update(&buf[..len]);
+            }
+            anyhow::ensure!(
+                digest.finalize() == entry.crc,
+                "{} section is corrupt",
+                entry.kind
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Give back the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// A reader over one section of a [Bundle], which can't read or seek outside of it
+#[derive(Debug)]
+pub struct Section<'a, R> {
+    inner: &'a mut R,
+    start: u64,
+    len: u64,
+
+    /// Where the underlying reader is, relative to `start`
+    pos: u64,
+}
+
+impl<R> Section<'_, R> {
+    /// How long the section is
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Is the section empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<R: Read> Read for Section<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.len.saturating_sub(self.pos);
+        let max = buf.len().min(left.try_into().unwrap_or(usize::MAX));
+        let len = self.inner.read(&mut buf[..max])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Seek> Seek for Section<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.len.checked_add_signed(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        let target = target.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "seek to a negative position",
+        ))?;
+
+        self.inner.seek(SeekFrom::Start(self.start + target))?;
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+/// Write out a bundle holding `sections`, in order
+pub fn write_bundle<W: Write>(out: &mut W, sections: &[(SectionKind, &[u8])]) -> io::Result<()> {
+    let header_len = HEADER_SIZE + sections.len() * SECTION_ENTRY_SIZE + 4;
+    let mut header = Vec::with_capacity(header_len);
+    header.extend_from_slice(BUNDLE_MAGIC);
+    header.extend_from_slice(&BUNDLE_VERSION.to_le_bytes());
+    header.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+
+    let mut offset = header_len as u64;
+    for &(kind, data) in sections {
+        header.extend_from_slice(&u32::from(kind).to_le_bytes());
+        header.extend_from_slice(&BUNDLE_CRC.checksum(data).to_le_bytes());
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        offset += data.len() as u64;
+    }
+    header.extend_from_slice(&BUNDLE_CRC.checksum(&header).to_le_bytes());
+
+    out.write_all(&header)?;
+    for (_, data) in sections {
+        out.write_all(data)?;
+    }
+    out.flush()
+}
+
+#[test]
+fn test_bundle() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+    use std::io::Cursor;
+
+    let bootloader = synthetic_data(5000);
+    let rootfs = synthetic_data(70_000);
+    let mut image = vec![];
+    write_bundle(
+        &mut image,
+        &[
+            (SectionKind::Bootloader, &bootloader),
+            (SectionKind::Unknown(99), b"from the future"),
+            (SectionKind::Rootfs, &rootfs),
+        ],
+    )?;
+
+    let mut bundle = Bundle::open(Cursor::new(&image))?;
+    bundle.verify()?;
+    let kinds: Vec<_> = bundle.sections().iter().map(|x| x.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            SectionKind::Bootloader,
+            SectionKind::Unknown(99),
+            SectionKind::Rootfs
+        ]
+    );
+    assert!(bundle.section(SectionKind::Eeprom)?.is_none());
+    assert_eq!(
+        bundle.read_section(SectionKind::Bootloader)?.unwrap(),
+        bootloader
+    );
+
+    // A section can be read and seeked around in, but not out of
+    let mut section = bundle.section(SectionKind::Rootfs)?.unwrap();
+    assert_eq!(section.len(), rootfs.len() as u64);
+    let mut buf = vec![];
+    section.seek(SeekFrom::End(-10))?;
+    section.read_to_end(&mut buf)?;
+    assert_eq!(buf, rootfs[rootfs.len() - 10..]);
+    section.seek(SeekFrom::Start(100))?;
+    let mut buf = [0; 4];
+    section.read_exact(&mut buf)?;
+    assert_eq!(buf, rootfs[100..104]);
+    assert!(section.seek(SeekFrom::Current(-200)).is_err());
+
+    // Corrupt data is only found by verifying...
+    let mut corrupt = image.clone();
+    let end = corrupt.len() - 1;
+    corrupt[end] ^= 1;
+    let mut bundle = Bundle::open(Cursor::new(&corrupt))?;
+    let error = bundle.verify().unwrap_err();
+    assert_eq!(error.to_string(), "rootfs section is corrupt");
+
+    // ...whereas a corrupt or truncated header stops the bundle from opening at all
+    let mut corrupt = image.clone();
+    corrupt[HEADER_SIZE + 8] ^= 1;
+    assert!(Bundle::open(Cursor::new(&corrupt)).is_err());
+    assert!(Bundle::open(Cursor::new(&image[..image.len() - 1])).is_err());
+    assert!(Bundle::open(Cursor::new(&image[..10])).is_err());
+    assert!(Bundle::open(Cursor::new(&bootloader)).is_err());
+
+    Ok(())
+}
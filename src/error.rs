@@ -0,0 +1,232 @@
+//! The classes of failure an installation can end in, for telling the user (or a host) more than
+//! that something went wrong.
+//!
+//! Errors are still passed around as [anyhow::Error]; those worth telling apart carry an
+//! [InstallError] that [InstallError::of] can find again, however much context is added on top.
+
+use std::fmt;
+use std::io;
+
+use crate::util::Aborted;
+
+/// What kind of failure an error is, each with a stable numeric code
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InstallError {
+    /// The image (or bundle, or layout) to install is corrupt or unusable
+    BadImage,
+
+    /// What's to be installed doesn't fit in the flash
+    FlashFull,
+
+    /// A bad block got in the way, and couldn't be worked around
+    BadBlockUnrecoverable,
+
+    /// The MTD device to install onto doesn't exist
+    MtdNotFound,
+
+    /// The MTD device to install onto isn't where it should be, so writing it could overwrite
+    /// something else, such as the bootloader
+    UnsafeTarget,
+
+    /// The board's EEPROM couldn't be found
+    EepromMissing,
+
+    /// Something took too long to become ready
+    Timeout,
+
+    /// The user called the installation off
+    Cancelled,
+
+    /// An I/O error not otherwise accounted for
+    Io,
+
+    /// Anything else
+    Other,
+}
+
+impl InstallError {
+    /// Every class, for enumerating them
+    pub const ALL: [Self; 10] = [
+        Self::BadImage,
+        Self::FlashFull,
+        Self::BadBlockUnrecoverable,
+        Self::MtdNotFound,
+        Self::UnsafeTarget,
+        Self::EepromMissing,
+        Self::Timeout,
+        Self::Cancelled,
+        Self::Io,
+        Self::Other,
+    ];
+
+    /// The code for this class; these never change, and 0 is never one of them
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Other => 1,
+            Self::BadImage => 2,
+            Self::FlashFull => 3,
+            Self::BadBlockUnrecoverable => 4,
+            Self::MtdNotFound => 5,
+            Self::EepromMissing => 6,
+            Self::Timeout => 7,
+            Self::Cancelled => 8,
+            Self::Io => 9,
+            Self::UnsafeTarget => 10,
+        }
+    }
+
+    /// The class with the given code, if there is one
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.code() == code)
+    }
+
+    /// Can the user fix this by changing what's on the microSD card (or what's sent), rather than
+    /// it being a problem with the board?
+    pub fn user_fixable(self) -> bool {
+        matches!(self, Self::BadImage | Self::FlashFull)
+    }
+
+    /// An error of this class, described by `msg`
+    pub fn msg<M>(self, msg: M) -> anyhow::Error
+    where
+        M: fmt::Display + Send + Sync + 'static,
+    {
+        anyhow::Error::new(self).context(msg)
+    }
+
+    /// Work out the class of an error: the [InstallError] it carries, if any, or else what its
+    /// cause suggests
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(&class) = error.downcast_ref::<Self>() {
+            return class;
+        }
+
+        for cause in error.chain() {
+            if cause.is::<Aborted>() {
+                return Self::Cancelled;
+            }
+            if let Some(error) = cause.downcast_ref::<io::Error>() {
+                return match error.kind() {
+                    io::ErrorKind::TimedOut => Self::Timeout,
+                    _ => Self::Io,
+                };
+            }
+        }
+
+        Self::Other
+    }
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::BadImage => "bad image",
+            Self::FlashFull => "flash full",
+            Self::BadBlockUnrecoverable => "unrecoverable bad block",
+            Self::MtdNotFound => "MTD device not found",
+            Self::UnsafeTarget => "unsafe MTD device",
+            Self::EepromMissing => "EEPROM missing",
+            Self::Timeout => "timed out",
+            Self::Cancelled => "cancelled",
+            Self::Io => "I/O error",
+            Self::Other => "installation failed",
+        })
+    }
+}
+
+impl std::error::Error for InstallError {}
+
+/// Marking the errors of a [Result] with their [InstallError]
+pub trait ResultExt<T> {
+    /// Mark any error as being of `class`, keeping its message as it is; an error that already
+    /// has a class keeps that one
+    fn class(self, class: InstallError) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> ResultExt<T> for Result<T, E> {
+    fn class(self, class: InstallError) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            let error = error.into();
+            match error.downcast_ref::<InstallError>() {
+                Some(_) => error,
+                None => class.msg(format!("{error:#}")),
+            }
+        })
+    }
+}
+
+#[test]
+fn test_install_error() {
+    use anyhow::Context;
+
+    // The codes are fixed, distinct and round-trip
+    let codes: Vec<_> = InstallError::ALL.iter().map(|x| x.code()).collect();
+    assert_eq!(codes, [2, 3, 4, 5, 10, 6, 7, 8, 9, 1]);
+    for class in InstallError::ALL {
+        assert_eq!(InstallError::from_code(class.code()), Some(class));
+    }
+    assert_eq!(InstallError::from_code(0), None);
+
+    // The class is found under any context, and the messages all survive
+    let error = InstallError::FlashFull
+        .msg("the image doesn't fit")
+        .context("Writing rootfs");
+    assert_eq!(InstallError::of(&error), InstallError::FlashFull);
+    assert_eq!(error.to_string(), "Writing rootfs");
+    assert_eq!(
+        format!("{error:#}"),
+        "Writing rootfs: the image doesn't fit: flash full"
+    );
+
+    let error: anyhow::Result<()> = Err(anyhow::anyhow!("EROFS filesystem not found"));
+    let error = error.context("rootfs").class(InstallError::BadImage);
+    let error = error.class(InstallError::Io).unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::BadImage);
+    assert_eq!(error.to_string(), "rootfs: EROFS filesystem not found");
+
+    // Without a class, the cause decides
+    let error = anyhow::Error::new(Aborted).context("Writing rootfs");
+    assert_eq!(InstallError::of(&error), InstallError::Cancelled);
+    let error = anyhow::Error::new(io::Error::from(io::ErrorKind::TimedOut));
+    assert_eq!(InstallError::of(&error), InstallError::Timeout);
+    let error = anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound)).context("/dev/mtd0");
+    assert_eq!(InstallError::of(&error), InstallError::Io);
+    assert_eq!(
+        InstallError::of(&anyhow::anyhow!("something")),
+        InstallError::Other
+    );
+}
+
+#[test]
+fn test_install_error_sources() -> anyhow::Result<()> {
+    use crate::format::raw::write_raw_image;
+    use crate::image::erofs_size;
+    use crate::nand::{Nand, NandBlock, NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 4,
+        pages_per_block: 4,
+        bytes_per_page: 256,
+    };
+
+    let error = erofs_size(&mut io::Cursor::new(vec![0; 4096])).unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::BadImage);
+    assert_eq!(error.to_string(), "EROFS filesystem not found");
+    let error = erofs_size(&mut io::Cursor::new(vec![0; 100])).unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::BadImage);
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let image = vec![0x5A; TEST_LAYOUT.total_bytes() as usize + 1];
+    let error = write_raw_image(&mut nand, &mut &image[..], true).unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::FlashFull);
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.block(1)?.unwrap().mark_bad()?;
+    let error = write_raw_image(&mut nand, &mut &image[..2048], false).unwrap_err();
+    assert_eq!(
+        InstallError::of(&error),
+        InstallError::BadBlockUnrecoverable
+    );
+
+    Ok(())
+}
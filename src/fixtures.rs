@@ -0,0 +1,82 @@
+//! Synthetic NAND workloads, shared by the benchmarks (under `benches/`) and the tests that guard
+//! how many NAND operations the hot paths perform.
+//!
+//! Everything here is deterministic, so that operation counts measured against it are too. None of
+//! it is built into the library, save for the tests, or with the `bench` feature.
+
+use crate::nand::{Nand, NandBlock, NandLayout, SimNand};
+use crate::ubi::{
+    self,
+    ubinize::{BasicVolume, Volume},
+    VolType,
+};
+
+/// The layout the benchmarks run against: as many blocks as the Turing Pi 2's NAND, but with
+/// smaller pages to keep the simulation's memory use in check
+pub const BENCH_LAYOUT: NandLayout = NandLayout {
+    blocks: 2048,
+    pages_per_block: 64,
+    bytes_per_page: 512,
+};
+
+/// A much smaller layout, for tests that only count operations
+pub const CANARY_LAYOUT: NandLayout = NandLayout {
+    blocks: 128,
+    pages_per_block: 16,
+    bytes_per_page: 512,
+};
+
+/// Generate `len` bytes of patternless-looking data
+pub fn synthetic_data(len: usize) -> Vec<u8> {
+    // xorshift32 is plenty; this only has to keep the data from being trivially repetitive
+    let mut state: u32 = 0x2545_F491;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// A static volume with the given contents
+pub fn static_volume<'a>(data: &'a mut &[u8]) -> BasicVolume<'a> {
+    BasicVolume::new(VolType::Static)
+        .name("fixture")
+        .size(data.len() as u64)
+        .image(data)
+}
+
+/// Build a NAND that has seen some use: a UBI partition with a volume taking up about a quarter of
+/// it, then scuffed up with some erased blocks, some holding garbage, and a few gone bad.
+pub fn dirty_nand(layout: NandLayout) -> anyhow::Result<SimNand> {
+    let mut nand = SimNand::new(layout);
+    let mut ebt = ubi::scan_blocks(&mut nand)?;
+    ubi::format(&mut nand, &mut ebt)?;
+
+    let block_size = layout.block_bytes();
+    let data = synthetic_data(layout.blocks as usize / 4 * block_size);
+    let mut reader = &data[..];
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+    ubi::write_volumes(&mut nand, &mut ebt, volumes)?;
+
+    let garbage = synthetic_data(layout.bytes_per_page * 2);
+    for index in 0..layout.blocks {
+        let Some(mut block) = nand.block(index)? else {
+            continue;
+        };
+
+        match index % 16 {
+            3 => block.erase()?,
+            7 => {
+                block.erase()?;
+                block.program(0, &garbage)?;
+            }
+            11 if index % 64 == 11 => block.mark_bad()?,
+            _ => (),
+        }
+    }
+
+    Ok(nand)
+}
@@ -0,0 +1,171 @@
+//! Telling apart the headers the Allwinner Boot ROM looks for at the start of a block (or of an SD
+//! card or eMMC boot area), from any buffer: the partition it was read from doesn't matter.
+//!
+//! boot0 and U-Boot SPL both start with an eGON header:
+//!
+//! | Offset | Size | Field                                                        |
+//! |--------|------|--------------------------------------------------------------|
+//! | 0x00   | 4    | A branch past the header                                     |
+//! | 0x04   | 8    | Magic: `eGON.BT0`                                            |
+//! | 0x0c   | 4    | Checksum                                                     |
+//! | 0x10   | 4    | Length of the image, header included, little-endian          |
+//! | 0x14   | 3    | U-Boot SPL only: `SPL`                                       |
+//! | 0x17   | 1    | U-Boot SPL only: header version, major in the top three bits |
+//!
+//! A TOC0 image (as used for secure boot) starts with its name and a magic number instead.
+
+/// The magic of an eGON header, at offset 0x04
+const EGON_MAGIC: &[u8] = b"eGON.BT0";
+
+/// The offset of the image length in an eGON header
+const EGON_LENGTH_OFFSET: usize = 0x10;
+
+/// The signature U-Boot puts in the eGON header of its SPL, at offset 0x14
+const SPL_SIGNATURE: &[u8] = b"SPL";
+
+/// The size of an eGON header, as far as U-Boot SPL fills it in
+pub(crate) const EGON_HEADER_SIZE: usize = 0x18;
+
+/// The longest image an eGON header is believed to describe: the whole boot0 area of the 1.0.x
+/// firmware's layout
+const EGON_MAX_LENGTH: u32 = 1 << 20;
+
+/// The (little-endian) magic number following the TOC0 name
+pub(crate) const TOC0_MAGIC: &[u8] = &0x89119800u32.to_le_bytes();
+
+/// What a buffer starts with, as far as the Boot ROM is concerned
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BootHeader {
+    /// An eGON boot0 image, `length` bytes long as its header says (0 if the header is cut off
+    /// before it says)
+    EgonBoot0 { length: u32 },
+
+    /// A U-Boot SPL, `length` bytes long as its header says, with the given header version
+    SunxiSpl { version: u8, length: u32 },
+
+    /// A TOC0 image
+    Toc0,
+
+    /// Nothing the Boot ROM would run
+    None,
+}
+
+impl BootHeader {
+    /// The length of an eGON image, if its header says something believable: at least the header
+    /// itself, a whole number of the 32-bit words its checksum covers, and no more than the boot
+    /// area could ever have held
+    pub fn plausible_length(&self) -> Option<u32> {
+        match *self {
+            Self::EgonBoot0 { length } | Self::SunxiSpl { length, .. } => {
+                Some(length).filter(|x| {
+                    (EGON_HEADER_SIZE as u32..=EGON_MAX_LENGTH).contains(x) && x.is_multiple_of(4)
+                })
+            }
+            Self::Toc0 | Self::None => None,
+        }
+    }
+}
+
+/// Classify the header at the start of `page`.
+///
+/// An eGON header cut off before it's complete can't be shown to be an SPL, so it's taken to be
+/// boot0: boot0 left behind is run by the Boot ROM, whereas an SPL wrongly taken for boot0 is only
+/// erased before being written again. An SPL whose header version isn't known is still an SPL.
+pub fn classify(page: &[u8]) -> BootHeader {
+    // TOC0 begins with its name, followed by a fixed magic number
+    if page.get(0x00..0x08) == Some(b"TOC0.GLH") && page.get(0x08..0x0c) == Some(TOC0_MAGIC) {
+        return BootHeader::Toc0;
+    }
+
+    if page.get(0x04..0x0c) != Some(EGON_MAGIC) {
+        return BootHeader::None;
+    }
+    let length = page
+        .get(EGON_LENGTH_OFFSET..EGON_LENGTH_OFFSET + 4)
+        .map_or(0, |x| u32::from_le_bytes(x.try_into().unwrap()));
+    match page.get(0x14..EGON_HEADER_SIZE) {
+        Some([signature @ .., version]) if signature == SPL_SIGNATURE => BootHeader::SunxiSpl {
+            version: *version,
+            length,
+        },
+        _ => BootHeader::EgonBoot0 { length },
+    }
+}
+
+#[test]
+fn test_classify() {
+    let mut page = vec![0u8; 256];
+
+    // Random data
+    let mut state = 0x1234567u32;
+    page.fill_with(|| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    });
+    assert_eq!(classify(&page), BootHeader::None);
+    assert_eq!(classify(&[]), BootHeader::None);
+
+    // eGON boot0, with its length
+    page[0x04..0x0c].copy_from_slice(EGON_MAGIC);
+    page[0x10..0x14].copy_from_slice(&0x6000u32.to_le_bytes());
+    let boot0 = BootHeader::EgonBoot0 { length: 0x6000 };
+    assert_eq!(classify(&page), boot0);
+    assert_eq!(boot0.plausible_length(), Some(0x6000));
+
+    // Cut short, it's still boot0, as far as there's anything to go on
+    assert_eq!(classify(&page[..0x0b]), BootHeader::None);
+    assert_eq!(classify(&page[..0x0c]), BootHeader::EgonBoot0 { length: 0 });
+    assert_eq!(classify(&page[..0x13]), BootHeader::EgonBoot0 { length: 0 });
+    for len in 0x14..=0x18 {
+        assert_eq!(classify(&page[..len]), boot0, "{len:#x} bytes");
+    }
+
+    // An implausible length is reported as it is, but not believed
+    for length in [0u32, 0x10, 0x6002, 0x1000_0000] {
+        page[0x10..0x14].copy_from_slice(&length.to_le_bytes());
+        let header = classify(&page);
+        assert_eq!(header, BootHeader::EgonBoot0 { length });
+        assert_eq!(header.plausible_length(), None, "{length:#x}");
+    }
+    assert_eq!(BootHeader::None.plausible_length(), None);
+
+    // U-Boot SPL carries the same magic, plus its own signature and version
+    page[0x10..0x14].copy_from_slice(&0x8000u32.to_le_bytes());
+    page[0x14..0x18].copy_from_slice(b"SPL\x02");
+    let spl = BootHeader::SunxiSpl {
+        version: 0x02,
+        length: 0x8000,
+    };
+    assert_eq!(classify(&page), spl);
+    assert_eq!(classify(&page[..0x18]), spl);
+    assert_eq!(spl.plausible_length(), Some(0x8000));
+
+    // ...but cut off before the version, it can't be told from boot0
+    for len in 0x15..0x18 {
+        let header = classify(&page[..len]);
+        assert_eq!(header, BootHeader::EgonBoot0 { length: 0x8000 });
+    }
+
+    // A version this doesn't know is still an SPL, never boot0
+    page[0x17] = 0xE0;
+    assert_eq!(
+        classify(&page),
+        BootHeader::SunxiSpl {
+            version: 0xE0,
+            length: 0x8000
+        }
+    );
+
+    // A signature that's almost right isn't one
+    page[0x14..0x17].copy_from_slice(b"SPM");
+    assert_eq!(classify(&page), BootHeader::EgonBoot0 { length: 0x8000 });
+
+    // TOC0, whatever follows its name
+    page[0x00..0x08].copy_from_slice(b"TOC0.GLH");
+    page[0x08..0x0c].copy_from_slice(TOC0_MAGIC);
+    assert_eq!(classify(&page), BootHeader::Toc0);
+    assert_eq!(classify(&page[..0x0c]), BootHeader::Toc0);
+    assert_eq!(classify(&page[..0x0b]), BootHeader::None);
+    page[0x08] ^= 0xFF;
+    assert_eq!(classify(&page), BootHeader::None);
+}
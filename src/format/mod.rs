@@ -5,49 +5,289 @@
 //! - 123 MiB: UBI partition, with SIMULATE_MULTIPLANE enabled
 //!
 //! This module implements:
-//! 1. If an Allwinner boot0 is detected in the `boot` partition, erase it, so that it doesn't
-//!    conflict with the U-Boot SPL.
+//! 1. If an Allwinner boot0 (or its secure-boot counterpart, TOC0) is detected in the `boot`
+//!    partition, erase it, so that it doesn't conflict with the U-Boot SPL. The same goes for the
+//!    boot area of an SD card or eMMC, which is zeroed instead.
 //! 2. General flash-writing code that can write raw images to the NAND.
+//! 3. Cleaning out whatever lies past a raw image in its partition (e.g. stale UBI headers left by
+//!    an old experiment), with [clean_partition].
 //!
 //! These steps are meant to be idempotent and no-ops on post-migrated NAND layouts, so they should
 //! always run unconditionally as part of the installation process.
 
+pub mod bootrom;
 pub mod raw;
-use crate::nand::{Nand, NandBlock};
+use crate::error::InstallError;
+use crate::nand::{Nand, NandBlock, PageUtil};
+use crate::util::ReadExt;
 
-/// Scan each block looking for an Allwinner boot0 header, and erase the found blocks.
-///
-/// Returns `true` if any blocks were erased.
-pub fn purge_boot0<N: Nand>(nand: &mut N) -> anyhow::Result<bool> {
-    let mut any_erased = false;
+use self::bootrom::BootHeader;
+#[cfg(test)]
+use self::bootrom::TOC0_MAGIC;
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// The kinds of legacy Allwinner boot code that may be found at the start of a block
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LegacyBoot {
+    /// An eGON boot0 image
+    Boot0,
+
+    /// A TOC0 image, as used on devices provisioned for secure boot
+    Toc0,
+
+    /// Anything else (including U-Boot SPL)
+    None,
+}
+
+/// What [purge_boot0] did
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PurgeStats {
+    /// How many blocks held eGON boot0 and were erased
+    pub boot0_erased: u32,
+
+    /// How many blocks held TOC0 and were erased
+    pub toc0_erased: u32,
+}
+
+impl PurgeStats {
+    /// Was any legacy boot code found (and erased)?
+    pub fn purged(&self) -> bool {
+        self.boot0_erased + self.toc0_erased > 0
+    }
+
+    /// Which kinds of legacy boot code were found (and erased)
+    pub fn kinds(&self) -> Vec<LegacyBoot> {
+        [
+            (LegacyBoot::Boot0, self.boot0_erased),
+            (LegacyBoot::Toc0, self.toc0_erased),
+        ]
+        .into_iter()
+        .filter(|&(_, count)| count > 0)
+        .map(|(kind, _)| kind)
+        .collect()
+    }
+}
+
+/// Scan each block looking for legacy Allwinner boot code (boot0 or TOC0), and erase the found
+/// blocks.
+pub fn purge_boot0<N: Nand>(nand: &mut N) -> anyhow::Result<PurgeStats> {
+    nand.ensure_writeable()?;
+
+    let mut stats = PurgeStats::default();
 
     let mut page_buf = vec![0; nand.get_layout().bytes_per_page];
     for block_index in 0..nand.get_layout().blocks {
         if let Some(mut block) = nand.block(block_index)? {
             block.read(0, &mut page_buf)?;
-            if is_boot0(&page_buf) == Some(true) {
-                block.erase()?;
-                any_erased = true;
-            }
+            let counter = match legacy_boot(&page_buf) {
+                LegacyBoot::Boot0 => &mut stats.boot0_erased,
+                LegacyBoot::Toc0 => &mut stats.toc0_erased,
+                LegacyBoot::None => continue,
+            };
+
+            // Nothing can be done about boot code that won't go away: the Boot ROM will keep
+            // running it
+            block.erase().map_err(|error| {
+                let error = format!("block {block_index} couldn't be erased: {error:#}");
+                InstallError::BadBlockUnrecoverable.msg(error)
+            })?;
+            *counter += 1;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Erase every block of the NAND past the raw image `keep_image` (as written by
+/// [write_raw_image](raw::write_raw_image), skipping bad blocks), or every block at all if there's
+/// no image to keep.
+///
+/// Blocks that are already erased are left alone. Returns how many blocks were erased.
+pub fn clean_partition<N: Nand, R: Read>(
+    nand: &mut N,
+    keep_image: Option<&mut R>,
+) -> anyhow::Result<u32> {
+    nand.ensure_writeable()?;
+
+    let layout = nand.get_layout();
+    let image_len = match keep_image {
+        Some(image) => std::io::copy(image, &mut std::io::sink())?,
+        None => 0,
+    };
+    let mut image_blocks = image_len.div_ceil(layout.block_bytes() as u64);
+
+    let mut erased = 0;
+    let mut buf = vec![0; layout.block_bytes()];
+    for block_index in 0..layout.blocks {
+        let Some(mut block) = nand.block(block_index)? else {
+            continue;
+        };
+        if image_blocks > 0 {
+            image_blocks -= 1;
+            continue;
+        }
+
+        block.read(0, &mut buf)?;
+        if !buf.is_erased() {
+            block.erase()?;
+            erased += 1;
+        }
+    }
+    if image_blocks > 0 {
+        return Err(InstallError::FlashFull.msg("the image doesn't fit in the partition"));
+    }
+
+    Ok(erased)
+}
+
+/// Where the Boot ROM looks for boot code on an SD card or eMMC: 8 KiB in, then 128 KiB in
+pub const MMC_BOOT_OFFSETS: &[u64] = &[8 << 10, 128 << 10];
+
+/// How much is read at each offset probed by [erase_legacy_boot], and zeroed if it turns out to be
+/// legacy boot code; one sector holds the whole header
+const MMC_PROBE_SIZE: usize = 512;
+
+/// Look for legacy Allwinner boot code (boot0 or TOC0) at each of `probe_offsets` into a block
+/// device, such as an eMMC, and zero out the header of any found, so that the Boot ROM passes it
+/// over.
+///
+/// Offsets past the end of `dev` are skipped. Returns the offsets that were cleaned.
+pub fn erase_legacy_boot<F: Read + Write + Seek>(
+    dev: &mut F,
+    probe_offsets: &[u64],
+) -> anyhow::Result<Vec<u64>> {
+    let mut cleaned = vec![];
+
+    let mut buf = [0; MMC_PROBE_SIZE];
+    for &offset in probe_offsets {
+        dev.seek(SeekFrom::Start(offset))?;
+        let len = dev.read_fill(&mut buf)?;
+        if legacy_boot(&buf[..len]) == LegacyBoot::None {
+            continue;
         }
+
+        dev.seek(SeekFrom::Start(offset))?;
+        dev.write_all(&[0; MMC_PROBE_SIZE][..len])?;
+        cleaned.push(offset);
     }
+    dev.flush()?;
 
-    Ok(any_erased)
+    Ok(cleaned)
 }
 
-/// Scan a buffer and determine if this is an Allwinner boot0 header.
+/// Scan a buffer and determine if this is the header of some legacy Allwinner boot code.
 ///
-/// This is careful not to detect U-Boot SPL headers, which are formatted very similarly.
-fn is_boot0(buffer: &[u8]) -> Option<bool> {
-    // Check for the BT0 magic
-    if buffer.get(0x04..0x0c)? != b"eGON.BT0" {
-        return Some(false);
+/// This is careful not to detect U-Boot SPL headers, which are formatted very similarly to boot0
+/// (see [bootrom::classify]).
+fn legacy_boot(buffer: &[u8]) -> LegacyBoot {
+    match bootrom::classify(buffer) {
+        BootHeader::EgonBoot0 { .. } => LegacyBoot::Boot0,
+        BootHeader::Toc0 => LegacyBoot::Toc0,
+        BootHeader::SunxiSpl { .. } | BootHeader::None => LegacyBoot::None,
     }
+}
+
+#[test]
+fn test_legacy_boot() {
+    let mut page = vec![0u8; 256];
+
+    // Random data
+    let mut state = 0x1234567u32;
+    page.fill_with(|| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    });
+    assert_eq!(legacy_boot(&page), LegacyBoot::None);
+
+    // eGON boot0
+    page[0x04..0x0c].copy_from_slice(b"eGON.BT0");
+    assert_eq!(legacy_boot(&page), LegacyBoot::Boot0);
+
+    // ...even cut off before it could be told from an SPL
+    assert_eq!(legacy_boot(&page[..0x15]), LegacyBoot::Boot0);
+
+    // U-Boot SPL carries the same magic, but must be left alone
+    page[0x14..0x17].copy_from_slice(b"SPL");
+    assert_eq!(legacy_boot(&page), LegacyBoot::None);
 
-    // Check if this is actually a U-Boot SPL
-    if buffer.get(0x14..0x17)? == b"SPL" {
-        return Some(false);
+    // TOC0
+    page[0x00..0x08].copy_from_slice(b"TOC0.GLH");
+    page[0x08..0x0c].copy_from_slice(TOC0_MAGIC);
+    assert_eq!(legacy_boot(&page), LegacyBoot::Toc0);
+    page[0x08] ^= 0xFF;
+    assert_eq!(legacy_boot(&page), LegacyBoot::None);
+}
+
+#[test]
+fn test_erase_legacy_boot() -> anyhow::Result<()> {
+    use std::io::Cursor;
+
+    let mut image = vec![0x5A; 256 << 10];
+
+    // boot0 at 8 KiB, an SPL at 128 KiB, and TOC0 somewhere else
+    image[(8 << 10) + 0x04..][..8].copy_from_slice(b"eGON.BT0");
+    image[(128 << 10) + 0x04..][..8].copy_from_slice(b"eGON.BT0");
+    image[(128 << 10) + 0x14..][..3].copy_from_slice(b"SPL");
+    image[(64 << 10)..][..8].copy_from_slice(b"TOC0.GLH");
+    image[(64 << 10) + 0x08..][..4].copy_from_slice(TOC0_MAGIC);
+    let original = image.clone();
+
+    let mut dev = Cursor::new(image);
+    let offsets = [8 << 10, 64 << 10, 128 << 10, 512 << 10];
+    assert_eq!(erase_legacy_boot(&mut dev, &offsets)?, [8 << 10, 64 << 10]);
+
+    // Only the legacy headers are zeroed, and nothing is written past the end
+    let image = dev.into_inner();
+    assert_eq!(image.len(), original.len());
+    for (offset, zeroed) in [(8 << 10, true), (64 << 10, true), (128 << 10, false)] {
+        let header = &image[offset..][..MMC_PROBE_SIZE];
+        assert_eq!(header.iter().all(|&x| x == 0), zeroed, "{offset}");
     }
+    assert_eq!(
+        image[(8 << 10) + MMC_PROBE_SIZE..64 << 10],
+        original[(8 << 10) + MMC_PROBE_SIZE..64 << 10]
+    );
+    assert_eq!(image[..8 << 10], original[..8 << 10]);
+
+    // Doing it again finds nothing more
+    assert_eq!(erase_legacy_boot(&mut Cursor::new(image), &offsets)?, []);
+
+    Ok(())
+}
+
+#[test]
+fn test_clean_partition() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+    use crate::ubi::Ec;
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 6,
+        pages_per_block: 4,
+        bytes_per_page: 128,
+    };
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.block(1)?.unwrap().mark_bad()?;
+
+    // Three blocks of image, around the bad block, then a stale EC header
+    let image: Vec<u8> = (0..2 * 4 * 128 + 10).map(|x| (x * 3) as u8).collect();
+    raw::write_raw_image(&mut nand, &mut &image[..], true)?;
+    let mut header = [0xFF; 128];
+    Ec::default().ec(3).encode(&mut header)?;
+    nand.block(4)?.unwrap().program(0, &header)?;
+
+    assert_eq!(clean_partition(&mut nand, Some(&mut &image[..]))?, 1);
+    assert!(matches!(
+        raw::verify_raw_image(&mut nand, &mut &image[..], true)?,
+        raw::RawVerifyResult::Match { .. }
+    ));
+    let mut page = [0; 128];
+    nand.block(4)?.unwrap().read(0, &mut page)?;
+    assert!(page.is_erased());
+
+    // Nothing more to do the second time, and without an image, everything goes
+    assert_eq!(clean_partition(&mut nand, Some(&mut &image[..]))?, 0);
+    assert_eq!(clean_partition(&mut nand, None::<&mut &[u8]>)?, 3);
 
-    Some(true)
+    Ok(())
 }
@@ -1,30 +1,48 @@
 //! This module implements logic to write raw blobs to NAND flash.
 
-use crate::nand::{Nand, NandBlock, PageUtil};
-use crate::util::ReadExt;
+use crate::error::InstallError;
+use crate::image::open_maybe_compressed;
+use crate::nand::{io_pages, Nand, NandBlock, PageUtil, WritePolicy};
+use crate::ubi::{Ec, Vid};
+use crate::util::{check_abort, ReadExt};
 
-use std::io::Read;
+use super::bootrom::{self, BootHeader, EGON_HEADER_SIZE};
+
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+
+/// Does a page start with a UBI EC or VID header? Left in the boot partition (e.g. by an old
+/// experiment), these must never survive a raw write, even in the "don't care" part of a block.
+fn is_ubi_header(page: &[u8]) -> bool {
+    Ec::decode(page).is_some() || Vid::decode(page).is_some()
+}
+
+/// How many pages are read at a time when checking a block, unless the NAND prefers otherwise. A
+/// higher number helps in high-latency situations.
+const PAGE_CHUNKS: u32 = 8;
 
 /// Scan a block to confirm that its contents match the provided slice.
 ///
 /// The provided slice should be no longer than the block contents. If it is shorter, the remaining
-/// bytes are "don't care."
+/// bytes are "don't care," except that a page there holding a UBI header counts as a mismatch.
 ///
-/// The return value is the number of pages of the block that match (and therefore the index of the
-/// page where write can start), or None if there is no partial match and the block must be erased.
-fn check_raw_block<B: NandBlock>(block: &B, mut data: &[u8]) -> Option<u32> {
-    // How many pages do we read at a time? A higher number helps in high-latency situations.
-    const PAGE_CHUNKS: u32 = 8;
-
-    let mut buf = vec![0; block.page_size() * PAGE_CHUNKS as usize];
+/// The block is read `chunk_pages` pages at a time. The return value is the number of pages of the
+/// block that match (and therefore the index of the page where write can start), or None if there
+/// is no partial match and the block must be erased.
+fn check_raw_block<B: NandBlock>(block: &B, mut data: &[u8], chunk_pages: u32) -> Option<u32> {
+    let mut buf = vec![0; block.page_size() * chunk_pages as usize];
     let mut remaining: &[u8] = &[];
 
     let mut page: u32 = 0;
     let mut data_correct_upto: Option<u32> = None;
+    let mut data_end: Option<u32> = None;
     loop {
-        if data_correct_upto.is_none() && data.is_empty() {
-            // No data mismatch found and no further data to compare
-            break Some(page);
+        if data_correct_upto.is_none() && data_end.is_none() && data.is_empty() {
+            // No data mismatch found and no further data to compare; only the rest of the block
+            // remains to be checked for UBI headers
+            data_end = Some(page);
         }
 
         if remaining.is_empty() {
@@ -32,7 +50,7 @@ fn check_raw_block<B: NandBlock>(block: &B, mut data: &[u8]) -> Option<u32> {
 
             if buf.is_empty() {
                 // Nothing more to read
-                break data_correct_upto;
+                break data_correct_upto.or(data_end);
             }
 
             if block.read(page, &mut buf).is_err() {
@@ -45,8 +63,13 @@ fn check_raw_block<B: NandBlock>(block: &B, mut data: &[u8]) -> Option<u32> {
         let (page_content, rem) = remaining.split_at(block.page_size());
         remaining = rem;
 
-        // Still comparing data?
-        if data_correct_upto.is_none() {
+        if data_end.is_some() {
+            // Past the data, anything goes but a UBI header
+            if is_ubi_header(page_content) {
+                break None;
+            }
+        } else if data_correct_upto.is_none() {
+            // Still comparing data
             let cmp_len = std::cmp::min(page_content.len(), data.len());
             if page_content[..cmp_len] == data[..cmp_len] {
                 data = &data[cmp_len..];
@@ -72,18 +95,32 @@ fn check_raw_block<B: NandBlock>(block: &B, mut data: &[u8]) -> Option<u32> {
 /// Update the specified block's contents, resuming from a partial write if possible.
 ///
 /// The provided slice should be no longer than the block contents. If it is shorter, the remaining
-/// bytes are "don't care."
-fn update_raw_block<B: NandBlock>(block: &mut B, data: &[u8]) -> anyhow::Result<()> {
-    let start_page = match check_raw_block(block, data) {
-        None => {
+/// bytes are "don't care." Under a [WritePolicy] that requires whole blocks, the block is padded
+/// out with erased pages and always programmed from the start.
+fn update_raw_block<B: NandBlock>(
+    block: &mut B,
+    data: &[u8],
+    policy: WritePolicy,
+    chunk_pages: u32,
+) -> anyhow::Result<()> {
+    let start_page = match check_raw_block(block, data, chunk_pages) {
+        Some(x) if x as usize * block.page_size() >= data.len() => {
+            // Everything already matches
+            return Ok(());
+        }
+        Some(x) if !policy.whole_blocks() => x,
+        _ => {
             block.erase()?;
             0
         }
-        Some(x) => x,
     };
 
-    // Ensure `data` is a multiple of the page size
-    let mut data_len = data.len() + block.page_size() - 1;
+    // Ensure `data` is a multiple of the page size (or the whole block)
+    let mut data_len = if policy.whole_blocks() {
+        block.page_size() * block.page_count() as usize
+    } else {
+        data.len() + block.page_size() - 1
+    };
     data_len -= data_len % block.page_size();
     let mut vec;
     let mut data = data;
@@ -95,7 +132,39 @@ fn update_raw_block<B: NandBlock>(block: &mut B, data: &[u8]) -> anyhow::Result<
         data = &vec[..];
     }
 
-    block.program(start_page, data)
+    // Only the pages past the matching ones still need programming
+    block.program(start_page, &data[start_page as usize * block.page_size()..])
+}
+
+/// Update a block with [update_raw_block], giving it 5 attempts before marking it bad.
+///
+/// Returns whether the block now holds `data`.
+fn update_raw_block_or_mark_bad<B: NandBlock>(
+    mut block: B,
+    data: &[u8],
+    policy: WritePolicy,
+    chunk_pages: u32,
+) -> anyhow::Result<bool> {
+    for _ in 0..5 {
+        if update_raw_block(&mut block, data, policy, chunk_pages).is_ok() {
+            return Ok(true);
+        }
+        block.erase()?;
+    }
+
+    // Block must have gone bad
+    block.mark_bad()?;
+    Ok(false)
+}
+
+/// What [write_raw_image] did
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RawWriteStats {
+    /// The size of the image, now present on the NAND
+    pub bytes: u64,
+
+    /// How many blocks failed to take the image and were marked bad
+    pub bad_blocks_marked: u32,
 }
 
 /// Write a raw blob to the NAND flash device.
@@ -108,40 +177,591 @@ pub fn write_raw_image<N: Nand, R: Read>(
     nand: &mut N,
     image: &mut R,
     skip_bad: bool,
-) -> anyhow::Result<()> {
-    let block_size = nand.get_layout().pages_per_block as usize * nand.get_layout().bytes_per_page;
+) -> anyhow::Result<RawWriteStats> {
+    write_raw_blocks(nand, image, skip_bad, 0, None, None)
+}
 
+/// Like [write_raw_image], for an image known to be `expected_len` bytes long (if it is): one that
+/// can't fit in the good blocks of the NAND is refused before anything is written, and one that
+/// turns out longer than that is an error
+pub fn write_raw_image_sized<N: Nand, R: Read>(
+    nand: &mut N,
+    image: &mut R,
+    skip_bad: bool,
+    expected_len: Option<u64>,
+) -> anyhow::Result<RawWriteStats> {
+    write_raw_blocks(nand, image, skip_bad, 0, expected_len, None)
+}
+
+/// Like [write_raw_image], but checks `abort` before each block, failing with
+/// [Aborted](crate::util::Aborted) once it's raised
+pub fn write_raw_image_abortable<N: Nand, R: Read>(
+    nand: &mut N,
+    image: &mut R,
+    skip_bad: bool,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<RawWriteStats> {
+    write_raw_blocks(nand, image, skip_bad, 0, None, abort)
+}
+
+/// Like [write_raw_image], but decompressing the image on the way if it's gzip- or xz-compressed
+/// (see [open_maybe_compressed]).
+///
+/// Blocks are compared against the decompressed image, so writing the same image again, whether
+/// compressed or not, still writes nothing.
+pub fn write_raw_image_auto<N: Nand, R: Read>(
+    nand: &mut N,
+    image: &mut R,
+    skip_bad: bool,
+) -> anyhow::Result<RawWriteStats> {
+    let mut image = open_maybe_compressed(image)?;
+    write_raw_blocks(nand, &mut image, skip_bad, 0, None, None)
+}
+
+/// Are there `count` good blocks from `first_block` on? Without `skip_bad`, they must be the next
+/// `count` blocks, and a bad one among them is an error.
+fn has_good_blocks<N: Nand>(
+    nand: &mut N,
+    first_block: u32,
+    count: u64,
+    skip_bad: bool,
+) -> anyhow::Result<bool> {
+    let mut found = 0;
+    for index in first_block..nand.get_layout().blocks {
+        if found >= count {
+            break;
+        }
+        match nand.block(index)? {
+            Some(_) => found += 1,
+            None if skip_bad => (),
+            None => {
+                let error = "unhandled bad block encountered";
+                return Err(InstallError::BadBlockUnrecoverable.msg(error));
+            }
+        }
+    }
+    Ok(found >= count)
+}
+
+/// [write_raw_image_sized], starting at `first_block` rather than the start of the NAND
+fn write_raw_blocks<N: Nand, R: Read>(
+    nand: &mut N,
+    image: &mut R,
+    skip_bad: bool,
+    first_block: u32,
+    expected_len: Option<u64>,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<RawWriteStats> {
+    let block_size = nand.get_layout().block_bytes();
+    let mut stats = RawWriteStats::default();
+    let policy = nand.write_policy();
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+    nand.ensure_writeable()?;
+
+    if let Some(len) = expected_len {
+        if !has_good_blocks(nand, first_block, len.div_ceil(block_size as u64), skip_bad)? {
+            return Err(InstallError::FlashFull.msg(format!(
+                "the image ({len} bytes) doesn't fit in the good blocks of the NAND"
+            )));
+        }
+    }
+
+    // The image is read a block ahead, so that one too long for the NAND is found out before the
+    // last block is written, rather than after
     let mut data = Vec::with_capacity(block_size);
-    let mut block_index: u32 = 0;
+    let mut next = Vec::with_capacity(block_size);
+    image.read_to_vec(&mut next, block_size)?;
+    let mut total = next.len() as u64;
+    let mut block_index = first_block;
     loop {
-        data.clear();
-        image.read_to_vec(&mut data, block_size)?;
+        std::mem::swap(&mut data, &mut next);
         if data.is_empty() {
             // EOF encountered means the write is complete
-            break Ok(());
+            break Ok(stats);
+        }
+        next.clear();
+        image.read_to_vec(&mut next, block_size)?;
+        total += next.len() as u64;
+        if let Some(len) = expected_len.filter(|&x| total > x) {
+            let error = format!("the image is longer than the {len} bytes expected");
+            return Err(InstallError::BadImage.msg(error));
+        }
+        check_abort(abort)?;
+        if !next.is_empty() && !has_good_blocks(nand, block_index, 2, skip_bad)? {
+            return Err(InstallError::FlashFull.msg("the image doesn't fit in the NAND"));
         }
 
-        'find_block_and_write: loop {
+        loop {
+            if block_index >= nand.get_layout().blocks {
+                return Err(InstallError::FlashFull.msg("the image doesn't fit in the NAND"));
+            }
             let block = nand.block(block_index)?;
             block_index += 1;
 
-            if let Some(mut block) = block {
-                // Give 5 attempts to update it
-                for _ in 0..5 {
-                    match update_raw_block(&mut block, &data) {
-                        Ok(()) => break 'find_block_and_write,
-                        Err(_) => block.erase()?,
-                    }
+            if let Some(block) = block {
+                if update_raw_block_or_mark_bad(block, &data, policy, chunk_pages)? {
+                    stats.bytes += data.len() as u64;
+                    break;
                 }
-
-                // Block must have gone bad
-                block.mark_bad()?;
+                stats.bad_blocks_marked += 1;
             }
 
             // Block is bad; if we can't tolerate it, bail. Otherwise, loop to find a good one.
-            anyhow::ensure!(skip_bad, "unhandled bad block encountered");
+            if !skip_bad {
+                let error = "unhandled bad block encountered";
+                return Err(InstallError::BadBlockUnrecoverable.msg(error));
+            }
+        }
+    }
+}
+
+/// Where the NAND contents first diverge from the image, as found by [verify_raw_image]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RawMismatch {
+    /// The index of the NAND block holding the mismatch
+    pub block: u32,
+
+    /// The page within that block
+    pub page: u32,
+
+    /// The offset within that page where `expected` and `found` begin
+    pub offset: usize,
+
+    /// Some bytes of the image around the mismatch
+    pub expected: Vec<u8>,
+
+    /// The same bytes as read from the NAND
+    pub found: Vec<u8>,
+}
+
+impl fmt::Display for RawMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "mismatch in block {}, page {}, at offset {:#x}:",
+            self.block, self.page, self.offset
+        )?;
+        for (label, bytes) in [("expected", &self.expected), ("found", &self.found)] {
+            write!(f, "  {label:>8}:")?;
+            for byte in bytes {
+                write!(f, " {byte:02x}")?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The outcome of [verify_raw_image]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RawVerifyResult {
+    /// The NAND holds the whole image, which is this many bytes long
+    Match { bytes: u64 },
+
+    /// The NAND contents diverge from the image
+    Mismatch(RawMismatch),
+}
+
+/// Locate the first difference between a block and the data it should hold.
+///
+/// This is the slow path of [verify_raw_image], only taken once [check_raw_block] has found that
+/// the block doesn't match.
+fn find_raw_mismatch<B: NandBlock>(
+    block: &B,
+    block_index: u32,
+    data: &[u8],
+) -> anyhow::Result<RawMismatch> {
+    // How many bytes of context to give on either side of the mismatch
+    const CONTEXT: usize = 16;
+
+    let mut buf = vec![0; block.page_size()];
+    for (page, expected) in (0..).zip(data.chunks(block.page_size())) {
+        block.read(page, &mut buf)?;
+
+        let Some(pos) = expected.iter().zip(&buf).position(|(a, b)| a != b) else {
+            continue;
+        };
+        let offset = pos - pos % CONTEXT;
+        let end = std::cmp::min(offset + CONTEXT * 2, expected.len());
+        return Ok(RawMismatch {
+            block: block_index,
+            page,
+            offset,
+            expected: expected[offset..end].to_vec(),
+            found: buf[offset..end].to_vec(),
+        });
+    }
+
+    // The data matches, so there must be a UBI header past it, which ought to have been erased
+    let data_pages = data.len().div_ceil(block.page_size()) as u32;
+    for page in data_pages..block.page_count() {
+        block.read(page, &mut buf)?;
+        if is_ubi_header(&buf) {
+            return Ok(RawMismatch {
+                block: block_index,
+                page,
+                offset: 0,
+                expected: vec![0xFF; CONTEXT * 2],
+                found: buf[..CONTEXT * 2].to_vec(),
+            });
         }
     }
+
+    anyhow::bail!("block {block_index} fails to verify, but no mismatch could be found")
+}
+
+/// Check whether a raw blob is present on the NAND flash device, without writing anything.
+///
+/// Blocks are walked the same way [write_raw_image] walks them, so `skip_bad` must be given the
+/// same value that the image was written with.
+pub fn verify_raw_image<N: Nand, R: Read>(
+    nand: &mut N,
+    image: &mut R,
+    skip_bad: bool,
+) -> anyhow::Result<RawVerifyResult> {
+    verify_raw_blocks(nand, image, skip_bad, 0)
+}
+
+/// [verify_raw_image], starting at `first_block` rather than the start of the NAND
+fn verify_raw_blocks<N: Nand, R: Read>(
+    nand: &mut N,
+    image: &mut R,
+    skip_bad: bool,
+    first_block: u32,
+) -> anyhow::Result<RawVerifyResult> {
+    let layout = nand.get_layout();
+    let block_size = layout.block_bytes();
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+
+    let mut bytes = 0;
+    let mut data = Vec::with_capacity(block_size);
+    let mut block_index = first_block;
+    loop {
+        data.clear();
+        image.read_to_vec(&mut data, block_size)?;
+        if data.is_empty() {
+            break Ok(RawVerifyResult::Match { bytes });
+        }
+
+        let block = loop {
+            let block = nand.block(block_index)?;
+            block_index += 1;
+            match block {
+                Some(block) => break block,
+                None => anyhow::ensure!(skip_bad, "unhandled bad block encountered"),
+            }
+        };
+
+        let pages = data.len().div_ceil(layout.bytes_per_page) as u32;
+        if check_raw_block(&block, &data, chunk_pages) != Some(pages) {
+            let mismatch = find_raw_mismatch(&block, block_index - 1, &data)?;
+            break Ok(RawVerifyResult::Mismatch(mismatch));
+        }
+        bytes += data.len() as u64;
+    }
+}
+
+/// Dump the NAND flash device's contents, block by block, to `out`, for inspecting offline.
+///
+/// Unlike [write_raw_image], bad blocks aren't skipped over: with `skip_bad`, each one is dumped
+/// as a block of 0xFF, so that offsets in the dump still match the device. Without it,
+/// encountering a bad block is an error.
+///
+/// The dump stops after `length` bytes if given, or else at the end of the device. Returns how
+/// many bytes were dumped.
+pub fn read_raw_image<N: Nand, W: Write>(
+    nand: &mut N,
+    out: &mut W,
+    skip_bad: bool,
+    length: Option<u64>,
+) -> anyhow::Result<u64> {
+    let layout = nand.get_layout();
+    let block_size = layout.block_bytes();
+    let length = length.unwrap_or(layout.total_bytes());
+    anyhow::ensure!(
+        length <= layout.total_bytes(),
+        "can't read {length} bytes from a {}-byte device",
+        layout.total_bytes()
+    );
+
+    let mut buf = vec![0; block_size];
+    let mut bytes = 0;
+    for block_index in 0.. {
+        let wanted = std::cmp::min(length - bytes, block_size as u64) as usize;
+        if wanted == 0 {
+            break;
+        }
+
+        // Only read as many pages as are wanted
+        let data = &mut buf[..wanted.next_multiple_of(layout.bytes_per_page)];
+        match nand.block(block_index)? {
+            Some(block) => block.read(0, data)?,
+            None if skip_bad => data.fill(0xFF),
+            None => anyhow::bail!("unhandled bad block {block_index} encountered"),
+        }
+
+        out.write_all(&data[..wanted])?;
+        bytes += wanted as u64;
+    }
+
+    Ok(bytes)
+}
+
+/// How a sunxi boot image (U-Boot SPL with an eGON header, followed by the U-Boot payload) is
+/// split up when written to the boot partition.
+///
+/// The payload offsets have to be the ones the SPL was built with (`CONFIG_SYS_NAND_U_BOOT_OFFS`
+/// and `CONFIG_SYS_NAND_U_BOOT_OFFS_REDUND`), since that's where it loads U-Boot from.
+#[derive(Debug, Copy, Clone)]
+pub struct BootImageLayout {
+    /// How many blocks at the start of the partition each hold a copy of the SPL. The Boot ROM
+    /// probes these in turn, so the copies can't move to skip a bad block.
+    pub spl_copies: u32,
+
+    /// Where the U-Boot payload starts within the image
+    pub payload_offset: u64,
+
+    /// Where in the partition the SPL loads the payload from; a block boundary past the SPL copies
+    pub payload_flash_offset: u64,
+
+    /// Where in the partition the SPL looks for a second copy of the payload, if it was built to
+    pub payload_redundant_offset: Option<u64>,
+}
+
+/// The BMC's own layout: a copy of the SPL at the start of each of the first four blocks, which
+/// the Boot ROM probes in turn, and the payload at 0x8000 in the image, loaded from 1 MiB into the
+/// boot partition (`CONFIG_SYS_NAND_U_BOOT_OFFS=0x100000`), or from 2 MiB if that copy is bad
+/// (`CONFIG_SYS_NAND_U_BOOT_OFFS_REDUND=0x200000`)
+impl Default for BootImageLayout {
+    fn default() -> Self {
+        Self {
+            spl_copies: 4,
+            payload_offset: 0x8000,
+            payload_flash_offset: 0x100000,
+            payload_redundant_offset: Some(0x200000),
+        }
+    }
+}
+
+impl BootImageLayout {
+    /// The first block of each copy of the payload, checking that they're on block boundaries
+    /// past the SPL copies, and don't overlap or run off the end of the partition
+    fn payload_blocks(&self, nand: &impl Nand, payload_len: usize) -> anyhow::Result<Vec<u32>> {
+        let layout = nand.get_layout();
+        let block_size = layout.block_bytes() as u64;
+        let blocks = (payload_len as u64).div_ceil(block_size).max(1);
+        let offsets = [
+            Some(self.payload_flash_offset),
+            self.payload_redundant_offset,
+        ];
+
+        let mut firsts: Vec<u32> = vec![];
+        for offset in offsets.into_iter().flatten() {
+            let first = offset / block_size;
+            let error = if offset % block_size != 0 {
+                "isn't on a block boundary"
+            } else if first < u64::from(self.spl_copies) {
+                "is among the SPL copies"
+            } else if first + blocks > u64::from(layout.blocks) {
+                "leaves no room for the payload in the boot partition"
+            } else if firsts
+                .iter()
+                .any(|&x| first.abs_diff(u64::from(x)) < blocks)
+            {
+                "overlaps the other copy of the payload"
+            } else {
+                firsts.push(first as u32);
+                continue;
+            };
+            let message = format!("the U-Boot payload offset {offset:#x} {error}");
+            return Err(InstallError::BadImage.msg(message));
+        }
+        Ok(firsts)
+    }
+}
+
+/// Does this look like the start of a U-Boot SPL (as opposed to boot0, or no header at all)?
+pub fn is_spl_image(header: &[u8]) -> bool {
+    matches!(bootrom::classify(header), BootHeader::SunxiSpl { .. })
+}
+
+/// Read a boot image, split into its SPL and its payload
+fn read_boot_image<R: Read + Seek>(
+    image: &mut R,
+    layout: BootImageLayout,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    image.rewind()?;
+    let mut spl = vec![0; EGON_HEADER_SIZE];
+    image.read_exact(&mut spl)?;
+    let BootHeader::SunxiSpl { length, .. } = bootrom::classify(&spl) else {
+        anyhow::bail!("boot image doesn't start with an SPL");
+    };
+    let length = length as usize;
+    anyhow::ensure!(
+        (EGON_HEADER_SIZE..=layout.payload_offset as usize).contains(&length),
+        "SPL length {length:#x} doesn't fit before the payload"
+    );
+    image.read_to_vec(&mut spl, length - EGON_HEADER_SIZE)?;
+
+    image.seek(SeekFrom::Start(layout.payload_offset))?;
+    let mut payload = vec![];
+    image.read_to_end(&mut payload)?;
+    Ok((spl, payload))
+}
+
+/// Check that a sunxi boot image can be written to the boot partition with `layout`, without
+/// writing anything: that it starts with an SPL that ends before the payload, and that each copy of
+/// the payload fits where the SPL loads it from
+pub fn check_boot_image<N: Nand, R: Read + Seek>(
+    nand: &N,
+    image: &mut R,
+    layout: BootImageLayout,
+) -> anyhow::Result<()> {
+    let (_, payload) = read_boot_image(image, layout)?;
+    layout.payload_blocks(nand, payload.len())?;
+    Ok(())
+}
+
+/// Are all of `blocks` good?
+fn all_good<N: Nand>(nand: &mut N, blocks: Range<u32>) -> anyhow::Result<bool> {
+    for index in blocks {
+        if nand.block(index)?.is_none() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Write `data` to the blocks from `first_block` on, which must all be good: unlike
+/// [write_raw_image], nothing is moved along past a bad block.
+///
+/// Returns whether `data` was written, and how many blocks failed to take it and were marked bad.
+fn write_fixed_blocks<N: Nand>(
+    nand: &mut N,
+    data: &[u8],
+    first_block: u32,
+) -> anyhow::Result<(bool, u32)> {
+    let block_size = nand.get_layout().block_bytes();
+    let policy = nand.write_policy();
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+
+    // A bad block already there means the copy can't be whole, so leave the rest alone
+    let last_block = first_block + data.len().div_ceil(block_size) as u32;
+    if !all_good(nand, first_block..last_block)? {
+        return Ok((false, 0));
+    }
+
+    for (index, chunk) in (first_block..).zip(data.chunks(block_size)) {
+        let block = nand.block(index)?.expect("block went bad on its own");
+        if !update_raw_block_or_mark_bad(block, chunk, policy, chunk_pages)? {
+            return Ok((false, 1));
+        }
+    }
+    Ok((true, 0))
+}
+
+/// Write a sunxi boot image to the boot partition: a copy of the SPL at the start of each of the
+/// first [BootImageLayout::spl_copies] blocks, and the payload at each offset the SPL loads it
+/// from.
+///
+/// A bad block in the SPL region only costs that copy; the others stay where the Boot ROM expects
+/// them. Likewise, a bad block under a copy of the payload costs that copy, rather than moving it
+/// where the SPL won't find it; so without a redundant copy, it's an error.
+pub fn write_boot_image<N: Nand, R: Read + Seek>(
+    nand: &mut N,
+    image: &mut R,
+    layout: BootImageLayout,
+) -> anyhow::Result<RawWriteStats> {
+    nand.ensure_writeable()?;
+    let policy = nand.write_policy();
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+    let (spl, payload) = read_boot_image(image, layout)?;
+    let payload_blocks = layout.payload_blocks(nand, payload.len())?;
+
+    let mut stats = RawWriteStats::default();
+    let mut copies = 0;
+    for index in 0..layout.spl_copies {
+        let Some(block) = nand.block(index)? else {
+            continue;
+        };
+        if update_raw_block_or_mark_bad(block, &spl, policy, chunk_pages)? {
+            copies += 1;
+        } else {
+            stats.bad_blocks_marked += 1;
+        }
+    }
+    if copies == 0 {
+        let error = "no copy of the SPL could be written";
+        return Err(InstallError::BadBlockUnrecoverable.msg(error));
+    }
+
+    let mut written = false;
+    for first_block in payload_blocks {
+        let (ok, marked) = write_fixed_blocks(nand, &payload, first_block)?;
+        written |= ok;
+        stats.bad_blocks_marked += marked;
+    }
+    if !written {
+        let error = "no copy of the U-Boot payload could be written where the SPL loads it from";
+        return Err(InstallError::BadBlockUnrecoverable.msg(error));
+    }
+
+    stats.bytes = (spl.len() + payload.len()) as u64;
+    Ok(stats)
+}
+
+/// Check whether a sunxi boot image, as written by [write_boot_image], is present on the NAND.
+///
+/// Every SPL copy in a good block has to match, as does every copy of the payload whose blocks
+/// are all good; there has to be at least one of those. The byte count covers one copy of each.
+pub fn verify_boot_image<N: Nand, R: Read + Seek>(
+    nand: &mut N,
+    image: &mut R,
+    layout: BootImageLayout,
+) -> anyhow::Result<RawVerifyResult> {
+    let (spl, payload) = read_boot_image(image, layout)?;
+    let payload_blocks = layout.payload_blocks(nand, payload.len())?;
+    let (block_size, page_size) = (
+        nand.get_layout().block_bytes(),
+        nand.get_layout().bytes_per_page,
+    );
+    let pages = |data: &[u8]| data.len().div_ceil(page_size) as u32;
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+
+    for index in 0..layout.spl_copies {
+        let Some(block) = nand.block(index)? else {
+            continue;
+        };
+        if check_raw_block(&block, &spl, chunk_pages) != Some(pages(&spl)) {
+            let mismatch = find_raw_mismatch(&block, index, &spl)?;
+            return Ok(RawVerifyResult::Mismatch(mismatch));
+        }
+    }
+
+    let mut copies = 0;
+    for first_block in payload_blocks {
+        let last_block = first_block + payload.len().div_ceil(block_size) as u32;
+        if !all_good(nand, first_block..last_block)? {
+            continue;
+        }
+        for (index, chunk) in (first_block..).zip(payload.chunks(block_size)) {
+            let block = nand.block(index)?.expect("block went bad on its own");
+            if check_raw_block(&block, chunk, chunk_pages) != Some(pages(chunk)) {
+                let mismatch = find_raw_mismatch(&block, index, chunk)?;
+                return Ok(RawVerifyResult::Mismatch(mismatch));
+            }
+        }
+        copies += 1;
+    }
+    anyhow::ensure!(
+        copies > 0,
+        "no copy of the U-Boot payload is in good blocks"
+    );
+
+    Ok(RawVerifyResult::Match {
+        bytes: (spl.len() + payload.len()) as u64,
+    })
 }
 
 #[test]
@@ -157,9 +777,9 @@ fn test_check_raw_block() -> anyhow::Result<()> {
     let mut nand = SimNand::new(TEST_LAYOUT);
     let mut block = nand.block(0)?.unwrap();
 
-    assert_eq!(check_raw_block(&block, &[]), Some(0));
-    assert_eq!(check_raw_block(&block, &[0xFF, 0xFF]), Some(1));
-    assert_eq!(check_raw_block(&block, &[0xFF, 0x7F]), Some(0));
+    assert_eq!(check_raw_block(&block, &[], PAGE_CHUNKS), Some(0));
+    assert_eq!(check_raw_block(&block, &[0xFF, 0xFF], PAGE_CHUNKS), Some(1));
+    assert_eq!(check_raw_block(&block, &[0xFF, 0x7F], PAGE_CHUNKS), Some(0));
 
     // Generate a bunch of test data: 10 pages of data, 20 empty, 10 more of data
     let mut test_data: [u8; 128 * 40] = std::array::from_fn(|i| match i / 128 {
@@ -171,12 +791,67 @@ fn test_check_raw_block() -> anyhow::Result<()> {
     // Program only first 35 blocks
     block.program(0, &test_data[..35 * 128])?;
 
-    assert_eq!(check_raw_block(&block, &test_data[..128 * 5]), Some(5));
-    assert_eq!(check_raw_block(&block, &test_data[..128 * 15]), Some(15));
-    assert_eq!(check_raw_block(&block, &test_data), Some(35));
+    assert_eq!(
+        check_raw_block(&block, &test_data[..128 * 5], PAGE_CHUNKS),
+        Some(5)
+    );
+    assert_eq!(
+        check_raw_block(&block, &test_data[..128 * 15], PAGE_CHUNKS),
+        Some(15)
+    );
+    assert_eq!(check_raw_block(&block, &test_data, PAGE_CHUNKS), Some(35));
 
     test_data[25 * 128] = 0x00;
-    assert_eq!(check_raw_block(&block, &test_data), None);
+    assert_eq!(check_raw_block(&block, &test_data, PAGE_CHUNKS), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_stale_ubi_header() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+    use crate::ubi::Ec;
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 2,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+
+    // The image ends partway into the second block, past which an old EC header was left
+    let image: Vec<u8> = (0..16 * 128 + 256).map(|i| (i % 241) as u8).collect();
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut stale = [0xFF; 128];
+    Ec::default().ec(7).encode(&mut stale)?;
+    nand.block(0)?.unwrap().program(0, &image[..16 * 128])?;
+    let mut block = nand.block(1)?.unwrap();
+    block.program(0, &image[16 * 128..][..256])?;
+    block.program(5, &stale)?;
+    drop(block);
+
+    // The matching pages before it aren't taken as a resumable write...
+    let block = nand.block(1)?.unwrap();
+    assert_eq!(
+        check_raw_block(&block, &image[16 * 128..], PAGE_CHUNKS),
+        None
+    );
+    drop(block);
+    let mismatch = match verify_raw_image(&mut nand, &mut &image[..], false)? {
+        RawVerifyResult::Mismatch(mismatch) => mismatch,
+        other => panic!("stale header not noticed: {other:?}"),
+    };
+    assert_eq!((mismatch.block, mismatch.page, mismatch.offset), (1, 5, 0));
+
+    // ...but erased and rewritten, leaving no trace of the header
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+    let block = nand.block(1)?.unwrap();
+    let mut page = [0; 128];
+    block.read(5, &mut page)?;
+    assert!(page.is_erased());
+    assert_eq!(
+        check_raw_block(&block, &image[16 * 128..], PAGE_CHUNKS),
+        Some(2)
+    );
 
     Ok(())
 }
@@ -194,8 +869,406 @@ fn test_update_raw_block() -> anyhow::Result<()> {
     let mut nand = SimNand::new(TEST_LAYOUT);
     let mut block = nand.block(0)?.unwrap();
 
-    update_raw_block(&mut block, &[])?;
-    update_raw_block(&mut block, &[0xAA])?;
+    update_raw_block(&mut block, &[], WritePolicy::SequentialOnly, PAGE_CHUNKS)?;
+    update_raw_block(
+        &mut block,
+        &[0xAA],
+        WritePolicy::SequentialOnly,
+        PAGE_CHUNKS,
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn test_write_raw_image_paired() -> anyhow::Result<()> {
+    use crate::nand::{test_pairing, NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 4,
+        pages_per_block: 8,
+        bytes_per_page: 128,
+    };
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.set_write_policy(WritePolicy::PairedPages {
+        pairing: test_pairing,
+    })?;
+
+    // The last block ends on page 1, whose pair would be left alone without the padding
+    let image: Vec<u8> = (0..1024 * 2 + 256).map(|x| (x * 5) as u8).collect();
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+    assert_eq!(
+        verify_raw_image(&mut nand, &mut &image[..], false)?,
+        RawVerifyResult::Match { bytes: 2304 }
+    );
+
+    // The last block was programmed to the end, so a longer image can't resume it and starts over
+    let mut longer = image.clone();
+    longer.extend([0x42; 500]);
+    write_raw_image(&mut nand, &mut &longer[..], false)?;
+    assert_eq!(
+        verify_raw_image(&mut nand, &mut &longer[..], false)?,
+        RawVerifyResult::Match { bytes: 2804 }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_write_raw_image_capacity() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 4,
+        pages_per_block: 4,
+        bytes_per_page: 128,
+    };
+    let image: Vec<u8> = (0..2049).map(|x| (x * 3) as u8).collect();
+    let (fits, over) = (&image[..2048], &image[..]);
+    let class = |result: anyhow::Result<RawWriteStats>| InstallError::of(&result.unwrap_err());
+
+    // An image that exactly fills the NAND is written, whether its length is known or not
+    for expected_len in [Some(2048), None] {
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        write_raw_image_sized(&mut nand, &mut &fits[..], false, expected_len)?;
+        assert_eq!(
+            verify_raw_image(&mut nand, &mut &fits[..], false)?,
+            RawVerifyResult::Match { bytes: 2048 }
+        );
+    }
+
+    // One byte more is refused before anything is written...
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let result = write_raw_image_sized(&mut nand, &mut &over[..], false, Some(2049));
+    assert_eq!(class(result), InstallError::FlashFull);
+    assert_eq!(nand.stats().programs + nand.stats().erases, 0);
+
+    // ...or, with the length unknown, before the last block is
+    let result = write_raw_image_sized(&mut nand, &mut &over[..], false, None);
+    assert_eq!(class(result), InstallError::FlashFull);
+    let mut last = vec![0; TEST_LAYOUT.block_bytes()];
+    nand.block(3)?.unwrap().read(0, &mut last)?;
+    assert!(last.is_erased());
+
+    // An image longer than it was said to be is an error
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let result = write_raw_image_sized(&mut nand, &mut &fits[..], false, Some(1000));
+    assert_eq!(class(result), InstallError::BadImage);
+
+    // Bad blocks take away from the room there is, or get in the way
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.block(2)?.unwrap().mark_bad()?;
+    let result = write_raw_image_sized(&mut nand, &mut &fits[..1537], true, Some(1537));
+    assert_eq!(class(result), InstallError::FlashFull);
+    let result = write_raw_image_sized(&mut nand, &mut &fits[..1025], false, Some(1025));
+    assert_eq!(class(result), InstallError::BadBlockUnrecoverable);
+    assert_eq!(nand.stats().programs + nand.stats().erases, 0);
+    write_raw_image_sized(&mut nand, &mut &fits[..1536], true, Some(1536))?;
+    assert_eq!(
+        verify_raw_image(&mut nand, &mut &fits[..1536], true)?,
+        RawVerifyResult::Match { bytes: 1536 }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_raw_image() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 8,
+        pages_per_block: 4,
+        bytes_per_page: 128,
+    };
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.block(1)?.unwrap().mark_bad()?;
+
+    // 2.5 blocks of data, landing on blocks 0, 2 and 3
+    let image: Vec<u8> = (0..1280).map(|x| (x * 7) as u8).collect();
+    let written = write_raw_image(&mut nand, &mut &image[..], true)?;
+    assert_eq!(written.bytes, 1280);
+
+    assert_eq!(
+        verify_raw_image(&mut nand, &mut &image[..], true)?,
+        RawVerifyResult::Match { bytes: 1280 }
+    );
+    assert!(verify_raw_image(&mut nand, &mut &image[..], false).is_err());
+
+    // A longer image doesn't match
+    let mut longer = image.clone();
+    longer.extend([0u8; 16]);
+    let RawVerifyResult::Mismatch(mismatch) = verify_raw_image(&mut nand, &mut &longer[..], true)?
+    else {
+        panic!("longer image should not match");
+    };
+    assert_eq!((mismatch.block, mismatch.page, mismatch.offset), (3, 2, 0));
+    assert_eq!(mismatch.found, [0xFF; 16]);
+
+    // Neither does one that differs in the middle
+    let mut altered = image.clone();
+    altered[512 + 128 + 40] ^= 0x01;
+    let RawVerifyResult::Mismatch(mismatch) = verify_raw_image(&mut nand, &mut &altered[..], true)?
+    else {
+        panic!("altered image should not match");
+    };
+    assert_eq!((mismatch.block, mismatch.page, mismatch.offset), (2, 1, 32));
+    assert_eq!(mismatch.expected, altered[512 + 128 + 32..][..32]);
+    assert_eq!(mismatch.found, image[512 + 128 + 32..][..32]);
+    assert!(mismatch.to_string().contains("block 2, page 1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_read_raw_image() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 8,
+        pages_per_block: 4,
+        bytes_per_page: 128,
+    };
+
+    // 2.5 blocks of data, ending partway through a page
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let image: Vec<u8> = (0..1300).map(|x| (x * 7) as u8).collect();
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+
+    // Exactly the image, when asked for its length
+    let mut out = Vec::new();
+    assert_eq!(
+        read_raw_image(&mut nand, &mut out, false, Some(1300))?,
+        1300
+    );
+    assert!(out == image);
+
+    // The whole device otherwise, with the erased tail after the image
+    out.clear();
+    assert_eq!(read_raw_image(&mut nand, &mut out, false, None)?, 4096);
+    assert!(out[..1300] == image[..]);
+    assert!(out[1300..].is_erased());
+
+    // Bad blocks are filled in, keeping offsets, or else refused
+    nand.block(1)?.unwrap().mark_bad()?;
+    out.clear();
+    assert_eq!(read_raw_image(&mut nand, &mut out, true, Some(1300))?, 1300);
+    assert!(out[..512] == image[..512] && out[512..1024].is_erased());
+    assert!(out[1024..] == image[1024..]);
+    assert!(read_raw_image(&mut nand, &mut Vec::new(), false, None).is_err());
+    assert!(read_raw_image(&mut nand, &mut Vec::new(), true, Some(4097)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_raw_op_counts() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+    use crate::nand::{CountingNand, NandLayout, OpCounts, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 4,
+        pages_per_block: 64,
+        bytes_per_page: 512,
+    };
+
+    let image = synthetic_data(64 * 512 * 3 + 1000);
+    let mut nand = CountingNand::new(SimNand::new(TEST_LAYOUT));
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+    assert_eq!(
+        nand.counts.take(),
+        OpCounts {
+            reads: 32,
+            programs: 4,
+            ..Default::default()
+        }
+    );
+
+    // Writing the same image again must only read, in 8-page chunks; the last block is read to
+    // the end, to make sure no UBI header lurks past the image
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+    assert_eq!(
+        nand.counts.take(),
+        OpCounts {
+            reads: 32,
+            ..Default::default()
+        }
+    );
+
+    // With a NAND preferring whole blocks, that's one read per block
+    nand.inner.set_preferred_io_size(Some(64 * 512));
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+    assert!(matches!(
+        verify_raw_image(&mut nand, &mut &image[..], false)?,
+        RawVerifyResult::Match { .. }
+    ));
+    assert_eq!(
+        nand.counts.take(),
+        OpCounts {
+            reads: 8,
+            ..Default::default()
+        }
+    );
+    nand.inner.set_preferred_io_size(None);
+
+    // A fully-mismatching block is given up on after its first chunk of pages
+    let other = synthetic_data(64 * 512 * 4);
+    let block = nand.block(0)?.unwrap();
+    assert_eq!(
+        check_raw_block(&block, &other[1..][..64 * 512], PAGE_CHUNKS),
+        None
+    );
+    drop(block);
+    assert_eq!(
+        nand.counts.take(),
+        OpCounts {
+            reads: 1,
+            ..Default::default()
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_write_raw_image_compressed() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+    use crate::nand::{CountingNand, NandLayout, SimNand};
+    use std::io::Write;
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 4,
+        pages_per_block: 16,
+        bytes_per_page: 512,
+    };
+
+    let image = synthetic_data(16 * 512 * 2 + 1000);
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+    gzip.write_all(&image)?;
+    let gzip = gzip.finish()?;
+    let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+    xz.write_all(&image)?;
+    let xz = xz.finish()?;
+
+    // Written compressed, the image lands on the NAND decompressed
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let stats = write_raw_image_auto(&mut nand, &mut &gzip[..], false)?;
+    assert_eq!(stats.bytes, image.len() as u64);
+    assert!(matches!(
+        verify_raw_image(&mut nand, &mut &image[..], false)?,
+        RawVerifyResult::Match { .. }
+    ));
+
+    // ...so writing it again, in any form, only reads
+    let mut nand = CountingNand::new(nand);
+    for data in [&image, &gzip, &xz] {
+        write_raw_image_auto(&mut nand, &mut &data[..], false)?;
+        let counts = nand.counts.take();
+        assert_eq!((counts.programs, counts.erases), (0, 0));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_write_boot_image() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+    use std::io::Cursor;
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 8,
+        pages_per_block: 4,
+        bytes_per_page: 256,
+    };
+    const LAYOUT: BootImageLayout = BootImageLayout {
+        spl_copies: 3,
+        payload_offset: 0x400,
+        payload_flash_offset: 0x1000,
+        payload_redundant_offset: Some(0x1800),
+    };
+
+    // A 0x300-byte SPL, then 1.5 blocks of payload
+    let mut image: Vec<u8> = (0..0x400 + 1536).map(|x| (x * 13) as u8).collect();
+    image[0x04..0x0c].copy_from_slice(b"eGON.BT0");
+    image[0x10..0x14].copy_from_slice(&0x300u32.to_le_bytes());
+    image[0x14..0x17].copy_from_slice(b"SPL");
+    let (spl, payload) = (&image[..0x300], &image[0x400..]);
+
+    let mut blank = SimNand::new(TEST_LAYOUT);
+    blank.block(1)?.unwrap().mark_bad()?;
+
+    // Each copy of the payload goes exactly where the SPL loads it from
+    let mut nand = blank.clone();
+    let stats = write_boot_image(&mut nand, &mut Cursor::new(&image), LAYOUT)?;
+    assert_eq!(stats.bytes, 0x300 + 1536);
+
+    // The bad block costs one SPL copy, rather than moving the next one
+    let mut buf = vec![0; 1024];
+    for index in [0, 2] {
+        nand.block(index)?.unwrap().read(0, &mut buf)?;
+        assert_eq!(buf[..0x300], *spl);
+        assert!(buf[0x300..].is_erased());
+    }
+    for first in [4, 6] {
+        nand.block(first)?.unwrap().read(0, &mut buf)?;
+        assert_eq!(buf, payload[..1024]);
+        nand.block(first + 1)?.unwrap().read(0, &mut buf)?;
+        assert_eq!(buf[..512], payload[1024..]);
+    }
+    assert_eq!(
+        verify_boot_image(&mut nand, &mut Cursor::new(&image), LAYOUT)?,
+        RawVerifyResult::Match {
+            bytes: 0x300 + 1536
+        }
+    );
+
+    // A bad block under the payload costs that copy, rather than moving it along
+    blank.block(5)?.unwrap().mark_bad()?;
+    let mut nand = blank.clone();
+    write_boot_image(&mut nand, &mut Cursor::new(&image), LAYOUT)?;
+    nand.block(4)?.unwrap().read(0, &mut buf)?;
+    assert!(buf.is_erased());
+    nand.block(6)?.unwrap().read(0, &mut buf)?;
+    assert_eq!(buf, payload[..1024]);
+    assert!(matches!(
+        verify_boot_image(&mut nand, &mut Cursor::new(&image), LAYOUT)?,
+        RawVerifyResult::Match { .. }
+    ));
+
+    // ...so without a redundant copy, it's an error
+    let single = BootImageLayout {
+        payload_redundant_offset: None,
+        ..LAYOUT
+    };
+    let mut nand = blank.clone();
+    let error = write_boot_image(&mut nand, &mut Cursor::new(&image), single).unwrap_err();
+    assert_eq!(
+        InstallError::of(&error),
+        InstallError::BadBlockUnrecoverable
+    );
+
+    // Payload offsets the SPL can't have been built with are refused
+    for (flash, redundant) in [
+        (0x1100, None),
+        (0x800, None),
+        (0x1000, Some(0x1400)),
+        (0x1c00, None),
+    ] {
+        let layout = BootImageLayout {
+            payload_flash_offset: flash,
+            payload_redundant_offset: redundant,
+            ..LAYOUT
+        };
+        let mut nand = blank.clone();
+        assert!(write_boot_image(&mut nand, &mut Cursor::new(&image), layout).is_err());
+        assert_eq!(nand.stats().programs, 0, "{flash:#x}");
+    }
+
+    // Something other than an SPL is refused
+    image[0x14] = 0;
+    assert!(write_boot_image(&mut nand, &mut Cursor::new(&image), LAYOUT).is_err());
 
     Ok(())
 }
@@ -1,11 +1,26 @@
 //! Utilities for working with images.
 //!
-//! Currently just a function to determine the meaningful size of some EROFS partition.
+//! This includes a function to determine the meaningful size of some EROFS partition, and a
+//! decompression layer so that images may be provided gzip- or xz-compressed. The [erofs] and
+//! [squashfs] submodules can additionally copy individual files out of a filesystem image, and
+//! [detect] tells what sort of image a file holds, to catch one given in place of another.
 
-use std::io::{Read, Seek, SeekFrom};
+pub mod erofs;
+mod extract;
+pub mod squashfs;
+
+pub use extract::{ExtractReport, PathOutcome};
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::mem::size_of;
 
 use crc::{Algorithm, Crc, CRC_32_ISCSI};
+
+use crate::error::{InstallError, ResultExt};
+use crate::format::bootrom::{self, BootHeader};
+use crate::util::ReadExt;
+
 const CRC_32_EROFS: Algorithm<u32> = Algorithm {
     xorout: 0,
     ..CRC_32_ISCSI
@@ -22,19 +37,112 @@ const EROFS_SUPER_POS_BLOCKS: usize = 36;
 
 const EROFS_SUPER_MAGIC_V1: u32 = 0xE0F5E1E2;
 
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+/// The magic numbers at the start of the other images [detect] knows
+const UIMAGE_MAGIC: &[u8] = &0x27051956u32.to_be_bytes();
+const FDT_MAGIC: &[u8] = &0xD00DFEEDu32.to_be_bytes();
+const UBI_EC_MAGIC: &[u8] = b"UBI#";
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
 /// Given an open EROFS image (or partition), determine its total size in bytes.
 pub fn erofs_size<F: Read + Seek>(input: &mut F) -> anyhow::Result<u64> {
     let mut superblock: [u8; EROFS_SUPER_SIZE] = [0; EROFS_SUPER_SIZE];
     input.seek(SeekFrom::Start(EROFS_SUPER_OFFSET))?;
-    input.read_exact(&mut superblock)?;
+    input
+        .read_exact(&mut superblock)
+        .class(InstallError::BadImage)?;
     input.seek(SeekFrom::Start(0))?;
 
+    parse_erofs_superblock(&mut superblock)
+}
+
+/// What [erofs_check] found out about an EROFS image
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ErofsInfo {
+    pub block_size: u32,
+    pub blocks: u32,
+
+    /// The size of the image, according to its superblock
+    pub total_bytes: u64,
+
+    /// Does the input actually hold all `total_bytes` of the image?
+    pub complete: bool,
+}
+
+/// Check an EROFS image (or partition) more thoroughly than [erofs_size] does: beyond validating
+/// the superblock, see whether the input is as long as the image should be, and if it is, make
+/// sure the root directory can be read.
+///
+/// A truncated image is not an error here; it's up to the caller what to make of
+/// [ErofsInfo::complete] being false.
+pub fn erofs_check<F: Read + Seek>(input: &mut F) -> anyhow::Result<ErofsInfo> {
+    let mut superblock: [u8; EROFS_SUPER_SIZE] = [0; EROFS_SUPER_SIZE];
+    input.seek(SeekFrom::Start(EROFS_SUPER_OFFSET))?;
+    input
+        .read_exact(&mut superblock)
+        .class(InstallError::BadImage)?;
+
+    let blkszbits = superblock[EROFS_SUPER_POS_BLKSZBITS];
+    let blocks = u32::from_le_bytes(
+        superblock[EROFS_SUPER_POS_BLOCKS..][..size_of::<u32>()]
+            .try_into()
+            .unwrap(),
+    );
+    let total_bytes = parse_erofs_superblock(&mut superblock)?;
+    let block_size = 1u32
+        .checked_shl(blkszbits.into())
+        .ok_or(InstallError::BadImage.msg("unsupported EROFS block size"))?;
+
+    let complete = input.seek(SeekFrom::End(0))? >= total_bytes;
+    if complete {
+        erofs::check_root(&mut *input).class(InstallError::BadImage)?;
+    }
+    input.seek(SeekFrom::Start(0))?;
+
+    Ok(ErofsInfo {
+        block_size,
+        blocks,
+        total_bytes,
+        complete,
+    })
+}
+
+/// Determine the size of an EROFS image that can only be read as a stream (e.g. because it is
+/// being decompressed on the fly).
+///
+/// The start of the stream is consumed to find the superblock, so a reader that replays it is
+/// returned alongside the size.
+pub fn erofs_size_streaming<'a, R: Read + 'a>(
+    mut input: R,
+) -> anyhow::Result<(u64, impl Read + 'a)> {
+    let head_len = EROFS_SUPER_OFFSET as usize + EROFS_SUPER_SIZE;
+    let mut head = Vec::with_capacity(head_len);
+    input.read_to_vec(&mut head, head_len)?;
+    if head.len() < head_len {
+        return Err(InstallError::BadImage.msg("EROFS filesystem not found"));
+    }
+
+    let mut superblock: [u8; EROFS_SUPER_SIZE] =
+        head[EROFS_SUPER_OFFSET as usize..].try_into().unwrap();
+    let size = parse_erofs_superblock(&mut superblock)?;
+
+    Ok((size, io::Cursor::new(head).chain(input)))
+}
+
+/// Validate an EROFS superblock and compute the image size from it.
+///
+/// The checksum field of `superblock` is clobbered in the process.
+fn parse_erofs_superblock(superblock: &mut [u8; EROFS_SUPER_SIZE]) -> anyhow::Result<u64> {
     let magic = u32::from_le_bytes(
         superblock[EROFS_SUPER_POS_MAGIC..][..size_of::<u32>()]
             .try_into()
             .unwrap(),
     );
-    anyhow::ensure!(magic == EROFS_SUPER_MAGIC_V1, "EROFS filesystem not found");
+    if magic != EROFS_SUPER_MAGIC_V1 {
+        return Err(InstallError::BadImage.msg("EROFS filesystem not found"));
+    }
 
     let cksum = u32::from_le_bytes(
         superblock[EROFS_SUPER_POS_CKSUM..][..size_of::<u32>()]
@@ -42,10 +150,9 @@ pub fn erofs_size<F: Read + Seek>(input: &mut F) -> anyhow::Result<u64> {
             .unwrap(),
     );
     superblock[EROFS_SUPER_POS_CKSUM..][..size_of::<u32>()].fill(0u8);
-    anyhow::ensure!(
-        cksum == EROFS_CRC.checksum(&superblock),
-        "EROFS superblock is corrupt",
-    );
+    if cksum != EROFS_CRC.checksum(&superblock[..]) {
+        return Err(InstallError::BadImage.msg("EROFS superblock is corrupt"));
+    }
 
     let blocks = u32::from_le_bytes(
         superblock[EROFS_SUPER_POS_BLOCKS..][..size_of::<u32>()]
@@ -56,5 +163,261 @@ pub fn erofs_size<F: Read + Seek>(input: &mut F) -> anyhow::Result<u64> {
 
     u64::from(blocks)
         .checked_shl(blkszbits.into())
-        .ok_or(anyhow::anyhow!("Overflow in computing EROFS image size"))
+        .ok_or(InstallError::BadImage.msg("Overflow in computing EROFS image size"))
+}
+
+/// The compression formats that images may be wrapped in
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+}
+
+impl Compression {
+    /// Identify the compression format by the magic number at the start of a stream
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(XZ_MAGIC) {
+            Self::Xz
+        } else if header.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// An image being read through a decompressor (if any); see [open_maybe_compressed]
+pub struct ImageStream<'a> {
+    compression: Compression,
+    reader: Box<dyn Read + 'a>,
+}
+
+impl ImageStream<'_> {
+    /// Which compression format was detected on the underlying stream
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
+
+impl Read for ImageStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Sniff an image for gzip/xz compression, returning a stream of the decompressed contents.
+///
+/// Uncompressed images are passed through as-is.
+pub fn open_maybe_compressed<'a, R: Read + 'a>(mut input: R) -> anyhow::Result<ImageStream<'a>> {
+    let mut header = Vec::with_capacity(XZ_MAGIC.len());
+    input.read_to_vec(&mut header, XZ_MAGIC.len())?;
+
+    let compression = Compression::detect(&header);
+    let input = io::Cursor::new(header).chain(input);
+    let reader: Box<dyn Read + 'a> = match compression {
+        Compression::None => Box::new(input),
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(input)),
+    };
+
+    Ok(ImageStream {
+        compression,
+        reader,
+    })
+}
+
+/// What sort of image a file holds, as far as [detect] can tell
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ImageKind {
+    /// An EROFS filesystem of `size` bytes, according to its superblock
+    Erofs { size: u64 },
+
+    /// An Allwinner boot image (boot0 or U-Boot SPL, with U-Boot after it), whose eGON header says
+    /// it's `size` bytes long
+    SunxiBoot { size: u64 },
+
+    /// A legacy U-Boot image (uImage)
+    UBootLegacy,
+
+    /// A FIT image, or any other flattened device tree
+    Fit,
+
+    /// A UBI image, as made by `ubinize`
+    UbiImage,
+
+    /// A SquashFS filesystem
+    Squashfs,
+
+    /// Nothing [detect] knows
+    Unknown,
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Erofs { size } => write!(f, "an EROFS filesystem ({size} bytes)"),
+            Self::SunxiBoot { size } => write!(f, "an Allwinner boot image ({size} bytes)"),
+            Self::UBootLegacy => write!(f, "a legacy U-Boot image"),
+            Self::Fit => write!(f, "a FIT image"),
+            Self::UbiImage => write!(f, "a UBI image"),
+            Self::Squashfs => write!(f, "a SquashFS filesystem"),
+            Self::Unknown => write!(f, "an unknown image"),
+        }
+    }
+}
+
+/// Tell what sort of image `input` holds from where it's positioned, by the magic numbers where
+/// each sort has them, looking through gzip or xz compression. The position is left as it was.
+pub fn detect<F: Read + Seek>(input: &mut F) -> anyhow::Result<ImageKind> {
+    let head_len = EROFS_SUPER_OFFSET as usize + EROFS_SUPER_SIZE;
+    let position = input.stream_position()?;
+    let mut head = Vec::with_capacity(head_len);
+    let read = open_maybe_compressed(&mut *input).and_then(|x| {
+        x.take(head_len as u64).read_to_end(&mut head)?;
+        Ok(())
+    });
+    input.seek(SeekFrom::Start(position))?;
+    read?;
+
+    let kind = if head.starts_with(SQUASHFS_MAGIC) {
+        ImageKind::Squashfs
+    } else if head.starts_with(UBI_EC_MAGIC) {
+        ImageKind::UbiImage
+    } else if head.starts_with(UIMAGE_MAGIC) {
+        ImageKind::UBootLegacy
+    } else if head.starts_with(FDT_MAGIC) {
+        ImageKind::Fit
+    } else {
+        match bootrom::classify(&head) {
+            BootHeader::EgonBoot0 { length } | BootHeader::SunxiSpl { length, .. } => {
+                ImageKind::SunxiBoot {
+                    size: length.into(),
+                }
+            }
+            BootHeader::Toc0 | BootHeader::None => {
+                let superblock = head.get(EROFS_SUPER_OFFSET as usize..);
+                match superblock.and_then(|x| <[u8; EROFS_SUPER_SIZE]>::try_from(x).ok()) {
+                    Some(mut superblock) => parse_erofs_superblock(&mut superblock)
+                        .map_or(ImageKind::Unknown, |size| ImageKind::Erofs { size }),
+                    None => ImageKind::Unknown,
+                }
+            }
+        }
+    };
+
+    Ok(kind)
+}
+
+#[cfg(test)]
+pub(crate) fn test_erofs_image(blocks: u32) -> Vec<u8> {
+    let mut image = vec![0u8; 4096 * blocks as usize];
+
+    let superblock = &mut image[EROFS_SUPER_OFFSET as usize..][..EROFS_SUPER_SIZE];
+    superblock[EROFS_SUPER_POS_MAGIC..][..4].copy_from_slice(&EROFS_SUPER_MAGIC_V1.to_le_bytes());
+    superblock[EROFS_SUPER_POS_BLKSZBITS] = 12;
+    superblock[EROFS_SUPER_POS_BLOCKS..][..4].copy_from_slice(&blocks.to_le_bytes());
+    let cksum = EROFS_CRC.checksum(superblock);
+    superblock[EROFS_SUPER_POS_CKSUM..][..4].copy_from_slice(&cksum.to_le_bytes());
+
+    image
+}
+
+#[test]
+fn test_erofs_size_streaming() -> anyhow::Result<()> {
+    let image = test_erofs_image(3);
+    assert_eq!(erofs_size(&mut io::Cursor::new(&image))?, 3 * 4096);
+
+    let (size, mut stream) = erofs_size_streaming(&image[..])?;
+    assert_eq!(size, 3 * 4096);
+
+    // The stream must still yield the whole image, including the part read for the superblock
+    let mut replayed = Vec::new();
+    stream.read_to_end(&mut replayed)?;
+    assert_eq!(replayed, image);
+
+    assert!(erofs_size_streaming(&image[..2048]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_open_maybe_compressed() -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let image = test_erofs_image(2);
+
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+    gzip.write_all(&image)?;
+    let gzip = gzip.finish()?;
+
+    let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+    xz.write_all(&image)?;
+    let xz = xz.finish()?;
+
+    for (input, compression) in [
+        (&image, Compression::None),
+        (&gzip, Compression::Gzip),
+        (&xz, Compression::Xz),
+    ] {
+        let stream = open_maybe_compressed(&input[..])?;
+        assert_eq!(stream.compression(), compression);
+
+        let (size, mut stream) = erofs_size_streaming(stream)?;
+        assert_eq!(size, 2 * 4096);
+
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output)?;
+        assert_eq!(output, image);
+    }
+
+    // Streams shorter than any magic number are passed through
+    let mut output = Vec::new();
+    open_maybe_compressed(&[0x1F][..])?.read_to_end(&mut output)?;
+    assert_eq!(output, [0x1F]);
+
+    Ok(())
+}
+
+#[test]
+fn test_detect() -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let with_magic = |offset: usize, magic: &[u8]| {
+        let mut image = vec![0u8; 8192];
+        image[offset..offset + magic.len()].copy_from_slice(magic);
+        image
+    };
+    let mut spl = with_magic(0x04, b"eGON.BT0");
+    spl[0x10..0x18].copy_from_slice(b"\x00\x60\x00\x00SPL\x02");
+    let mut corrupt_erofs = test_erofs_image(2);
+    corrupt_erofs[EROFS_SUPER_OFFSET as usize + EROFS_SUPER_POS_BLOCKS] ^= 1;
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+    gzip.write_all(&test_erofs_image(3))?;
+    let gzip = gzip.finish()?;
+
+    let cases = [
+        (test_erofs_image(2), ImageKind::Erofs { size: 2 * 4096 }),
+        (gzip, ImageKind::Erofs { size: 3 * 4096 }),
+        (corrupt_erofs, ImageKind::Unknown),
+        (spl, ImageKind::SunxiBoot { size: 0x6000 }),
+        (with_magic(0, UIMAGE_MAGIC), ImageKind::UBootLegacy),
+        (with_magic(0, FDT_MAGIC), ImageKind::Fit),
+        (with_magic(0, UBI_EC_MAGIC), ImageKind::UbiImage),
+        (with_magic(0, SQUASHFS_MAGIC), ImageKind::Squashfs),
+        (vec![0u8; 8192], ImageKind::Unknown),
+        (vec![0x27, 0x05], ImageKind::Unknown),
+        (vec![], ImageKind::Unknown),
+    ];
+    for (image, kind) in cases {
+        assert_eq!(detect(&mut io::Cursor::new(&image))?, kind, "{kind}");
+
+        // The image is found from where the input is, which is left as it was
+        let mut input = io::Cursor::new([&[0xAA; 100][..], &image].concat());
+        input.set_position(100);
+        assert_eq!(detect(&mut input)?, kind, "{kind}");
+        assert_eq!(input.position(), 100);
+    }
+
+    Ok(())
 }
@@ -0,0 +1,509 @@
+//! A limited EROFS reader, for pulling individual files out of an image without mounting it.
+//!
+//! Only the uncompressed ("flat") data layouts are understood, which is what our images use. Files
+//! stored any other way are reported as unsupported rather than failing the whole extraction.
+
+use super::extract::{extract_all, NodeKind, ReadOnlyFs};
+use super::{
+    parse_erofs_superblock, ExtractReport, EROFS_SUPER_OFFSET, EROFS_SUPER_POS_BLKSZBITS,
+    EROFS_SUPER_SIZE,
+};
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const EROFS_SUPER_POS_ROOT_NID: usize = 14;
+const EROFS_SUPER_POS_META_BLKADDR: usize = 40;
+
+const EROFS_INODE_SLOT_SIZE: u64 = 32;
+const EROFS_INODE_COMPACT_SIZE: u64 = 32;
+const EROFS_INODE_EXTENDED_SIZE: u64 = 64;
+const EROFS_XATTR_IBODY_HEADER_SIZE: u64 = 12;
+const EROFS_DIRENT_SIZE: usize = 12;
+
+const EROFS_INODE_FLAT_PLAIN: u16 = 0;
+const EROFS_INODE_FLAT_INLINE: u16 = 2;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+/// The parts of an on-disk inode that are needed to find its data
+#[derive(Debug, Copy, Clone)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    layout: u16,
+    raw_blkaddr: u32,
+
+    /// Where the inline tail of a `FLAT_INLINE` inode's data begins
+    tail_offset: u64,
+}
+
+impl Inode {
+    fn file_type(&self) -> u16 {
+        self.mode & S_IFMT
+    }
+}
+
+/// An open EROFS image
+struct Erofs<R> {
+    reader: R,
+    blksz: u64,
+    meta_base: u64,
+    root_nid: u64,
+}
+
+impl<R: Read + Seek> Erofs<R> {
+    fn open(mut reader: R) -> anyhow::Result<Self> {
+        let mut superblock: [u8; EROFS_SUPER_SIZE] = [0; EROFS_SUPER_SIZE];
+        reader.seek(SeekFrom::Start(EROFS_SUPER_OFFSET))?;
+        reader.read_exact(&mut superblock)?;
+
+        let blkszbits = superblock[EROFS_SUPER_POS_BLKSZBITS];
+        let root_nid = u16::from_le_bytes(
+            superblock[EROFS_SUPER_POS_ROOT_NID..][..2]
+                .try_into()
+                .unwrap(),
+        );
+        let meta_blkaddr = u32::from_le_bytes(
+            superblock[EROFS_SUPER_POS_META_BLKADDR..][..4]
+                .try_into()
+                .unwrap(),
+        );
+        parse_erofs_superblock(&mut superblock)?;
+
+        anyhow::ensure!(
+            (9..=16).contains(&blkszbits),
+            "unsupported EROFS block size"
+        );
+        let blksz = 1u64 << blkszbits;
+
+        Ok(Self {
+            reader,
+            blksz,
+            meta_base: u64::from(meta_blkaddr) * blksz,
+            root_nid: root_nid.into(),
+        })
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        Ok(self.reader.read_exact(buf)?)
+    }
+
+    /// Read an inode by its number
+    fn inode(&mut self, nid: u64) -> anyhow::Result<Inode> {
+        let offset = self.meta_base + nid * EROFS_INODE_SLOT_SIZE;
+        let mut raw = [0u8; EROFS_INODE_EXTENDED_SIZE as usize];
+        self.read_at(offset, &mut raw[..EROFS_INODE_COMPACT_SIZE as usize])?;
+
+        let u16_at =
+            |raw: &[u8], pos: usize| u16::from_le_bytes(raw[pos..][..2].try_into().unwrap());
+        let u32_at =
+            |raw: &[u8], pos: usize| u32::from_le_bytes(raw[pos..][..4].try_into().unwrap());
+
+        let format = u16_at(&raw, 0);
+        let (inode_size, size) = if format & 1 == 0 {
+            (EROFS_INODE_COMPACT_SIZE, u64::from(u32_at(&raw, 8)))
+        } else {
+            self.read_at(offset, &mut raw)?;
+            let size = u64::from_le_bytes(raw[8..][..8].try_into().unwrap());
+            (EROFS_INODE_EXTENDED_SIZE, size)
+        };
+
+        let xattr_size = match u16_at(&raw, 2) {
+            0 => 0,
+            icount => EROFS_XATTR_IBODY_HEADER_SIZE + (u64::from(icount) - 1) * 4,
+        };
+
+        Ok(Inode {
+            mode: u16_at(&raw, 4),
+            size,
+            layout: (format >> 1) & 0x7,
+            raw_blkaddr: u32_at(&raw, 16),
+            tail_offset: offset + inode_size + xattr_size,
+        })
+    }
+
+    /// Copy out the data of an inode, which must use one of the flat layouts
+    fn copy_data(&mut self, inode: &Inode, out: &mut dyn Write) -> anyhow::Result<u64> {
+        let (head_len, tail_len) = match inode.layout {
+            EROFS_INODE_FLAT_PLAIN => (inode.size, 0),
+            EROFS_INODE_FLAT_INLINE => {
+                let head_len = inode.size.saturating_sub(1) / self.blksz * self.blksz;
+                (head_len, inode.size - head_len)
+            }
+            layout => anyhow::bail!("data layout {layout} is unsupported"),
+        };
+
+        let head_offset = u64::from(inode.raw_blkaddr) * self.blksz;
+        self.reader.seek(SeekFrom::Start(head_offset))?;
+        let copied = io::copy(&mut (&mut self.reader).take(head_len), out)?;
+        anyhow::ensure!(copied == head_len, "file data is truncated");
+
+        self.reader.seek(SeekFrom::Start(inode.tail_offset))?;
+        let copied = io::copy(&mut (&mut self.reader).take(tail_len), out)?;
+        anyhow::ensure!(copied == tail_len, "file data is truncated");
+
+        Ok(inode.size)
+    }
+
+    fn read_data(&mut self, inode: &Inode) -> anyhow::Result<Vec<u8>> {
+        // The size isn't to be trusted before the data is found to be there
+        let mut data = Vec::new();
+        self.copy_data(inode, &mut data)?;
+        Ok(data)
+    }
+
+    /// Find `name` in a directory, returning its inode number
+    fn lookup_nid(&mut self, dir: &Inode, name: &str) -> anyhow::Result<Option<u64>> {
+        let data = self.read_data(dir)?;
+
+        // Each block of a directory holds an array of dirents, followed by the names they point to
+        for block in data.chunks(self.blksz as usize) {
+            let dirent = |i: usize| -> Option<(u64, usize)> {
+                let raw = block
+                    .get(i * EROFS_DIRENT_SIZE..)?
+                    .get(..EROFS_DIRENT_SIZE)?;
+                let nid = u64::from_le_bytes(raw[..8].try_into().unwrap());
+                let nameoff = u16::from_le_bytes(raw[8..10].try_into().unwrap());
+                Some((nid, nameoff.into()))
+            };
+
+            let (_, first_nameoff) = dirent(0).ok_or(anyhow::anyhow!("corrupt directory"))?;
+            let count = first_nameoff / EROFS_DIRENT_SIZE;
+            for i in 0..count {
+                let (nid, start) = dirent(i).ok_or(anyhow::anyhow!("corrupt directory"))?;
+                let end = match i + 1 < count {
+                    true => dirent(i + 1).map_or(block.len(), |(_, x)| x),
+                    false => block.len(),
+                };
+
+                let entry = block
+                    .get(start..end)
+                    .ok_or(anyhow::anyhow!("corrupt directory"))?;
+                let entry_len = entry.iter().position(|&b| b == 0).unwrap_or(entry.len());
+                if &entry[..entry_len] == name.as_bytes() {
+                    return Ok(Some(nid));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<R: Read + Seek> ReadOnlyFs for Erofs<R> {
+    type Node = Inode;
+
+    fn root(&mut self) -> anyhow::Result<Inode> {
+        self.inode(self.root_nid)
+    }
+
+    fn kind(&self, inode: &Inode) -> NodeKind {
+        match inode.file_type() {
+            S_IFDIR => NodeKind::Dir,
+            S_IFREG => NodeKind::File,
+            S_IFLNK => NodeKind::Symlink,
+            _ => NodeKind::Other,
+        }
+    }
+
+    fn lookup(&mut self, dir: &Inode, name: &str) -> anyhow::Result<Option<Inode>> {
+        match self.lookup_nid(dir, name)? {
+            Some(nid) => Ok(Some(self.inode(nid)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_link(&mut self, inode: &Inode) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.read_data(inode)?)?)
+    }
+
+    fn unsupported(&self, inode: &Inode) -> Option<String> {
+        (![EROFS_INODE_FLAT_PLAIN, EROFS_INODE_FLAT_INLINE].contains(&inode.layout)).then(|| {
+            format!(
+                "data layout {} (compressed or chunked) is unsupported",
+                inode.layout
+            )
+        })
+    }
+
+    fn copy_file(&mut self, inode: &Inode, out: &mut dyn Write) -> anyhow::Result<u64> {
+        self.copy_data(inode, out)
+    }
+}
+
+/// Copy the given paths (regular files and symlinks) out of an EROFS image into `out_dir`,
+/// preserving their directory structure.
+///
+/// Problems with individual paths are recorded in the report; only an unreadable image is an
+/// error.
+pub fn extract_paths<R: Read + Seek>(
+    reader: R,
+    paths: &[&str],
+    out_dir: &Path,
+) -> anyhow::Result<ExtractReport> {
+    let mut erofs = Erofs::open(reader)?;
+    Ok(extract_all(&mut erofs, paths, out_dir))
+}
+
+/// Make sure the root directory of an EROFS image can be read, as a sanity check of its metadata
+pub(super) fn check_root<R: Read + Seek>(reader: R) -> anyhow::Result<()> {
+    let mut erofs = Erofs::open(reader)?;
+    let root = erofs.root()?;
+    anyhow::ensure!(
+        root.file_type() == S_IFDIR,
+        "EROFS root inode is not a directory"
+    );
+    Ok(())
+}
+
+/// A node of the tree given to [build_test_erofs]
+#[cfg(test)]
+enum TestNode {
+    Dir(Vec<(&'static str, TestNode)>),
+    File(&'static [u8]),
+    Symlink(&'static str),
+    Compressed,
+}
+
+/// Generate an EROFS image of a tree, in the same flat layout `mkfs.erofs -Enoinline_data` uses
+#[cfg(test)]
+fn build_test_erofs(root: &TestNode) -> Vec<u8> {
+    use super::{EROFS_CRC, EROFS_SUPER_MAGIC_V1, EROFS_SUPER_POS_BLOCKS, EROFS_SUPER_POS_MAGIC};
+
+    const BLKSZ: usize = 4096;
+    const FIRST_NID: usize = 40; // The first inode slot after the superblock
+
+    // Flatten the tree, so that every node knows its own and its children's inode numbers
+    type Flattened<'a> = (&'a TestNode, usize, Vec<(&'a str, usize)>);
+    let mut nodes: Vec<Flattened> = vec![(root, 0, vec![])];
+    let mut i = 0;
+    while i < nodes.len() {
+        if let TestNode::Dir(children) = nodes[i].0 {
+            for (name, child) in children {
+                let index = nodes.len();
+                nodes.push((child, i, vec![]));
+                nodes[i].2.push((name, index));
+            }
+        }
+        i += 1;
+    }
+
+    let mut image = vec![0u8; BLKSZ];
+    for (index, (node, parent, children)) in nodes.iter().enumerate() {
+        let nid = |index: usize| (FIRST_NID + index) as u64;
+
+        let (mode, layout, data) = match node {
+            TestNode::Dir(_) => {
+                let mut entries = vec![(".", index, 2u8), ("..", *parent, 2)];
+                entries.extend(children.iter().map(|&(name, child)| {
+                    let file_type = match nodes[child].0 {
+                        TestNode::Dir(_) => 2,
+                        TestNode::Symlink(_) => 7,
+                        _ => 1,
+                    };
+                    (name, child, file_type)
+                }));
+                entries.sort_by_key(|&(name, _, _)| name);
+
+                let mut dirents = vec![];
+                let mut names: Vec<u8> = vec![];
+                for (name, child, file_type) in entries {
+                    let nameoff = (children.len() + 2) * EROFS_DIRENT_SIZE + names.len();
+                    dirents.extend(nid(child).to_le_bytes());
+                    dirents.extend((nameoff as u16).to_le_bytes());
+                    dirents.extend([file_type, 0]);
+                    names.extend(name.as_bytes());
+                }
+                dirents.extend(names);
+                (S_IFDIR | 0o755, EROFS_INODE_FLAT_PLAIN, dirents)
+            }
+            TestNode::File(data) => (S_IFREG | 0o644, EROFS_INODE_FLAT_PLAIN, data.to_vec()),
+            TestNode::Symlink(target) => (
+                S_IFLNK | 0o777,
+                EROFS_INODE_FLAT_PLAIN,
+                target.as_bytes().to_vec(),
+            ),
+            TestNode::Compressed => (S_IFREG | 0o644, 1, vec![0xAA; 100]),
+        };
+
+        let blkaddr = (image.len() / BLKSZ) as u32;
+        image.extend(&data);
+        image.resize(image.len().div_ceil(BLKSZ) * BLKSZ, 0);
+
+        let inode = &mut image[nid(index) as usize * EROFS_INODE_SLOT_SIZE as usize..][..32];
+        inode[0..2].copy_from_slice(&(layout << 1).to_le_bytes());
+        inode[4..6].copy_from_slice(&mode.to_le_bytes());
+        inode[6..8].copy_from_slice(&1u16.to_le_bytes());
+        inode[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        inode[16..20].copy_from_slice(&blkaddr.to_le_bytes());
+        inode[20..24].copy_from_slice(&(index as u32).to_le_bytes());
+    }
+
+    let blocks = (image.len() / BLKSZ) as u32;
+    let superblock = &mut image[EROFS_SUPER_OFFSET as usize..][..EROFS_SUPER_SIZE];
+    superblock[EROFS_SUPER_POS_MAGIC..][..4].copy_from_slice(&EROFS_SUPER_MAGIC_V1.to_le_bytes());
+    superblock[EROFS_SUPER_POS_BLKSZBITS] = 12;
+    superblock[EROFS_SUPER_POS_ROOT_NID..][..2].copy_from_slice(&(FIRST_NID as u16).to_le_bytes());
+    superblock[EROFS_SUPER_POS_BLOCKS..][..4].copy_from_slice(&blocks.to_le_bytes());
+    let cksum = EROFS_CRC.checksum(superblock);
+    superblock[4..8].copy_from_slice(&cksum.to_le_bytes());
+
+    image
+}
+
+#[test]
+fn test_extract_paths() -> anyhow::Result<()> {
+    use super::PathOutcome;
+    use std::fs;
+    use TestNode::*;
+
+    let image = build_test_erofs(&Dir(vec![
+        (
+            "etc",
+            Dir(vec![
+                ("hostname", File(b"turing-pi\n")),
+                ("os-release", Symlink("../usr/lib/os-release")),
+            ]),
+        ),
+        ("lib", Symlink("usr/lib")),
+        (
+            "usr",
+            Dir(vec![
+                (
+                    "lib",
+                    Dir(vec![
+                        ("os-release", File(b"NAME=Buildroot\n")),
+                        ("firmware.bin", Compressed),
+                    ]),
+                ),
+                ("empty", File(b"")),
+            ]),
+        ),
+    ]));
+    assert_eq!(
+        super::erofs_size(&mut io::Cursor::new(&image))?,
+        image.len() as u64
+    );
+
+    let out_dir = std::env::temp_dir().join(format!("erofs-extract-{}", std::process::id()));
+    let paths = [
+        "/etc/hostname",
+        "/etc/os-release",
+        "/lib/os-release",
+        "/etc/../usr/empty",
+        "/lib/firmware.bin",
+        "/etc/shadow",
+        "/etc/hostname/x",
+        "/usr",
+    ];
+    let report = extract_paths(io::Cursor::new(&image), &paths, &out_dir)?;
+
+    let outcomes: Vec<_> = report.paths.iter().map(|(_, x)| x.clone()).collect();
+    assert!(!report.all_ok());
+    assert_eq!(outcomes[0], PathOutcome::File { bytes: 10 });
+    assert_eq!(
+        outcomes[1],
+        PathOutcome::Symlink {
+            target: "../usr/lib/os-release".into()
+        }
+    );
+    assert_eq!(outcomes[2], PathOutcome::File { bytes: 15 });
+    assert_eq!(outcomes[3], PathOutcome::File { bytes: 0 });
+    assert!(matches!(outcomes[4], PathOutcome::Unsupported(_)));
+    assert_eq!(
+        outcomes[5],
+        PathOutcome::Failed("no such file or directory".into())
+    );
+    assert_eq!(outcomes[6], PathOutcome::Failed("not a directory".into()));
+    assert_eq!(outcomes[7], PathOutcome::Failed("is a directory".into()));
+
+    assert_eq!(fs::read(out_dir.join("etc/hostname"))?, b"turing-pi\n");
+    assert_eq!(
+        fs::read(out_dir.join("lib/os-release"))?,
+        b"NAME=Buildroot\n"
+    );
+    assert_eq!(
+        fs::read_link(out_dir.join("etc/os-release"))?,
+        Path::new("../usr/lib/os-release")
+    );
+    assert!(!out_dir.join("lib/firmware.bin").exists());
+
+    fs::remove_dir_all(&out_dir)?;
+
+    // Symlinks extracted earlier are never followed out of `out_dir`, whether to a directory...
+    let base = std::env::temp_dir().join(format!("erofs-escape-{}", std::process::id()));
+    let (out_dir, victim) = (base.join("out"), base.join("victim"));
+    fs::create_dir_all(&victim)?;
+    let image = build_test_erofs(&Dir(vec![
+        ("link", Symlink("../victim")),
+        ("victim", Dir(vec![("x", File(b"escaped"))])),
+    ]));
+    let report = extract_paths(io::Cursor::new(&image), &["/link", "/link/x"], &out_dir)?;
+    assert!(matches!(&report.paths[1].1, PathOutcome::Failed(x) if x.contains("is a symlink")));
+    assert!(!victim.join("x").exists());
+
+    // ...or to a file
+    let image = build_test_erofs(&Dir(vec![("x", Symlink("../victim/x"))]));
+    extract_paths(io::Cursor::new(&image), &["/x"], &out_dir)?;
+    let image = build_test_erofs(&Dir(vec![("x", File(b"replaced"))]));
+    let report = extract_paths(io::Cursor::new(&image), &["/x"], &out_dir)?;
+    assert!(report.all_ok());
+    assert_eq!(fs::read(out_dir.join("x"))?, b"replaced");
+    assert!(!victim.join("x").exists());
+
+    fs::remove_dir_all(&base)?;
+
+    // A directory claiming to be 4GiB is found to be truncated, without that much being allocated
+    let mut image = build_test_erofs(&Dir(vec![("x", File(b"x"))]));
+    let root = 40 * EROFS_INODE_SLOT_SIZE as usize;
+    image[root + 8..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    let superblock = &mut image[EROFS_SUPER_OFFSET as usize..][..EROFS_SUPER_SIZE];
+    superblock[4..8].fill(0);
+    let cksum = super::EROFS_CRC.checksum(superblock);
+    superblock[4..8].copy_from_slice(&cksum.to_le_bytes());
+    let report = extract_paths(io::Cursor::new(&image), &["/x"], &out_dir)?;
+    assert!(matches!(&report.paths[0].1, PathOutcome::Failed(x) if x.contains("truncated")));
+
+    Ok(())
+}
+
+#[test]
+fn test_erofs_check() -> anyhow::Result<()> {
+    use super::{erofs_check, ErofsInfo, EROFS_CRC, EROFS_SUPER_POS_CKSUM};
+
+    let image = build_test_erofs(&TestNode::Dir(vec![(
+        "hostname",
+        TestNode::File(b"turing-pi\n"),
+    )]));
+    assert_eq!(
+        erofs_check(&mut io::Cursor::new(&image))?,
+        ErofsInfo {
+            block_size: 4096,
+            blocks: (image.len() / 4096) as u32,
+            total_bytes: image.len() as u64,
+            complete: true,
+        }
+    );
+
+    // A truncated image still has a good superblock, but is found to be incomplete
+    let truncated = &image[..image.len() - 1];
+    assert!(!erofs_check(&mut io::Cursor::new(truncated))?.complete);
+    assert!(erofs_check(&mut io::Cursor::new(&image[..2048])).is_err());
+
+    // Pointing the superblock's root at the file makes for a bad root directory
+    let mut damaged = image.clone();
+    let superblock = &mut damaged[EROFS_SUPER_OFFSET as usize..][..EROFS_SUPER_SIZE];
+    superblock[EROFS_SUPER_POS_ROOT_NID..][..2].copy_from_slice(&41u16.to_le_bytes());
+    superblock[EROFS_SUPER_POS_CKSUM..][..4].fill(0);
+    let cksum = EROFS_CRC.checksum(superblock);
+    superblock[EROFS_SUPER_POS_CKSUM..][..4].copy_from_slice(&cksum.to_le_bytes());
+    let error = erofs_check(&mut io::Cursor::new(&damaged)).unwrap_err();
+    assert_eq!(error.to_string(), "EROFS root inode is not a directory");
+
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Path resolution and extraction shared by the read-only filesystem readers.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// How many symlinks may be followed while resolving one path
+const MAX_SYMLINKS: u32 = 40;
+
+/// What became of one of the paths asked to be extracted from a filesystem image
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PathOutcome {
+    /// A regular file was copied out
+    File { bytes: u64 },
+
+    /// A symlink was recreated
+    Symlink { target: String },
+
+    /// The path exists, but is stored in a way the reader doesn't understand
+    Unsupported(String),
+
+    /// The path couldn't be extracted
+    Failed(String),
+}
+
+/// The per-path results of extracting files from a filesystem image
+#[derive(Debug, Default, Clone)]
+pub struct ExtractReport {
+    pub paths: Vec<(String, PathOutcome)>,
+}
+
+impl ExtractReport {
+    /// Was every path extracted?
+    pub fn all_ok(&self) -> bool {
+        self.paths.iter().all(|(_, outcome)| {
+            matches!(
+                outcome,
+                PathOutcome::File { .. } | PathOutcome::Symlink { .. }
+            )
+        })
+    }
+}
+
+/// The types of node that the readers distinguish between
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum NodeKind {
+    Dir,
+    File,
+    Symlink,
+    Other,
+}
+
+/// A read-only filesystem, as far as looking up and copying out paths is concerned
+pub(super) trait ReadOnlyFs {
+    type Node;
+
+    fn root(&mut self) -> anyhow::Result<Self::Node>;
+
+    fn kind(&self, node: &Self::Node) -> NodeKind;
+
+    /// Find `name` in the directory `dir`
+    fn lookup(&mut self, dir: &Self::Node, name: &str) -> anyhow::Result<Option<Self::Node>>;
+
+    fn read_link(&mut self, node: &Self::Node) -> anyhow::Result<String>;
+
+    /// Explain why a regular file can't be copied out, if it can't
+    fn unsupported(&self, _node: &Self::Node) -> Option<String> {
+        None
+    }
+
+    /// Copy out the contents of a regular file, returning its size
+    fn copy_file(&mut self, node: &Self::Node, out: &mut dyn Write) -> anyhow::Result<u64>;
+}
+
+/// Walk `path` from the root directory, following symlinks everywhere except the last component
+fn resolve<F: ReadOnlyFs>(reader: &mut F, path: &str) -> anyhow::Result<F::Node> {
+    let mut stack = vec![reader.root()?];
+    let mut pending: VecDeque<String> = path.split('/').map(String::from).collect();
+    let mut symlinks = 0;
+
+    while let Some(name) = pending.pop_front() {
+        match name.as_str() {
+            "" | "." => continue,
+            ".." => {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+                continue;
+            }
+            _ => (),
+        }
+
+        let dir = stack.last().unwrap();
+        anyhow::ensure!(reader.kind(dir) == NodeKind::Dir, "not a directory");
+        let node = reader
+            .lookup(dir, &name)?
+            .ok_or(anyhow::anyhow!("no such file or directory"))?;
+
+        let is_last = pending.iter().all(|x| x.is_empty() || x == ".");
+        if reader.kind(&node) == NodeKind::Symlink && !is_last {
+            symlinks += 1;
+            anyhow::ensure!(
+                symlinks <= MAX_SYMLINKS,
+                "too many levels of symbolic links"
+            );
+
+            let target = reader.read_link(&node)?;
+            if target.starts_with('/') {
+                stack.truncate(1);
+            }
+            for component in target.split('/').rev() {
+                pending.push_front(component.to_string());
+            }
+            continue;
+        }
+
+        stack.push(node);
+    }
+
+    Ok(stack.pop().unwrap())
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, dest: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, dest)
+}
+
+#[cfg(windows)]
+fn symlink(target: &str, dest: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, dest)
+}
+
+/// Create the directories in `out_dir` leading to `relative`, without following any symlink on
+/// the way: one extracted from the image could point anywhere.
+fn create_parents(out_dir: &Path, relative: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(out_dir)?;
+
+    let mut dir = out_dir.to_path_buf();
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                anyhow::bail!("{} is a symlink, which isn't followed", dir.display())
+            }
+            Ok(meta) if meta.is_dir() => (),
+            Ok(_) => anyhow::bail!("{} is not a directory", dir.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&dir)?,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Copy a single path out of the filesystem into `out_dir`
+fn extract<F: ReadOnlyFs>(
+    reader: &mut F,
+    path: &str,
+    out_dir: &Path,
+) -> anyhow::Result<PathOutcome> {
+    let node = resolve(reader, path)?;
+
+    // Keep the destination inside `out_dir`, whatever the path looks like
+    let relative: PathBuf = Path::new(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let dest = out_dir.join(&relative);
+
+    let kind = reader.kind(&node);
+    if let (NodeKind::File, Some(why)) = (kind, reader.unsupported(&node)) {
+        return Ok(PathOutcome::Unsupported(why));
+    }
+    if let NodeKind::File | NodeKind::Symlink = kind {
+        create_parents(out_dir, &relative)?;
+
+        // A symlink already there (perhaps from an earlier path) is replaced, not written through
+        if fs::symlink_metadata(&dest).is_ok_and(|x| x.file_type().is_symlink()) {
+            fs::remove_file(&dest)?;
+        }
+    }
+
+    Ok(match kind {
+        NodeKind::File => {
+            let bytes = reader.copy_file(&node, &mut fs::File::create(&dest)?)?;
+            PathOutcome::File { bytes }
+        }
+        NodeKind::Symlink => {
+            let target = reader.read_link(&node)?;
+            let _ = fs::remove_file(&dest);
+            symlink(&target, &dest)?;
+            PathOutcome::Symlink { target }
+        }
+        NodeKind::Dir => PathOutcome::Failed("is a directory".into()),
+        NodeKind::Other => PathOutcome::Unsupported("special files are unsupported".into()),
+    })
+}
+
+/// Extract each of `paths`, recording how each one went
+pub(super) fn extract_all<F: ReadOnlyFs>(
+    reader: &mut F,
+    paths: &[&str],
+    out_dir: &Path,
+) -> ExtractReport {
+    let mut report = ExtractReport::default();
+    for &path in paths {
+        let outcome = extract(reader, path, out_dir)
+            .unwrap_or_else(|e| PathOutcome::Failed(format!("{e:#}")));
+        report.paths.push((path.to_string(), outcome));
+    }
+
+    report
+}
@@ -0,0 +1,743 @@
+//! A limited SquashFS (4.0) reader, for pulling individual files out of an image without mounting
+//! it.
+//!
+//! Only zlib ("gzip") compressed images are understood, including their uncompressed blocks. The
+//! reader is scoped to path lookup and file extraction: xattrs, the export table and directory
+//! indexes are all ignored.
+
+use super::extract::{extract_all, NodeKind, ReadOnlyFs};
+use super::ExtractReport;
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SQUASHFS_MAGIC: u32 = 0x73717368;
+const SQUASHFS_SUPER_SIZE: usize = 96;
+const SQUASHFS_COMPRESSION_ZLIB: u16 = 1;
+
+const SQUASHFS_SUPER_POS_MAGIC: usize = 0;
+const SQUASHFS_SUPER_POS_BLOCK_SIZE: usize = 12;
+const SQUASHFS_SUPER_POS_COMPRESSION: usize = 20;
+const SQUASHFS_SUPER_POS_VERSION_MAJOR: usize = 28;
+const SQUASHFS_SUPER_POS_ROOT_INODE: usize = 32;
+const SQUASHFS_SUPER_POS_INODE_TABLE: usize = 64;
+const SQUASHFS_SUPER_POS_DIRECTORY_TABLE: usize = 72;
+const SQUASHFS_SUPER_POS_FRAGMENT_TABLE: usize = 80;
+
+const SQUASHFS_METADATA_SIZE: usize = 8192;
+const SQUASHFS_METADATA_UNCOMPRESSED: u16 = 0x8000;
+const SQUASHFS_DATA_UNCOMPRESSED: u32 = 1 << 24;
+const SQUASHFS_NO_FRAGMENT: u32 = 0xFFFF_FFFF;
+const SQUASHFS_FRAGMENT_ENTRY_SIZE: u32 = 16;
+
+const SQUASHFS_INODE_HEADER_SIZE: usize = 16;
+const SQUASHFS_DIR_HEADER_SIZE: usize = 12;
+const SQUASHFS_DIR_ENTRY_SIZE: usize = 8;
+
+const SQUASHFS_BASIC_DIR: u16 = 1;
+const SQUASHFS_BASIC_FILE: u16 = 2;
+const SQUASHFS_BASIC_SYMLINK: u16 = 3;
+const SQUASHFS_EXT_DIR: u16 = 8;
+const SQUASHFS_EXT_FILE: u16 = 9;
+const SQUASHFS_EXT_SYMLINK: u16 = 10;
+
+fn le_u16(raw: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(raw[pos..][..2].try_into().unwrap())
+}
+
+fn le_u32(raw: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(raw[pos..][..4].try_into().unwrap())
+}
+
+fn le_u64(raw: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(raw[pos..][..8].try_into().unwrap())
+}
+
+/// The parts of an on-disk inode that are needed to find its contents
+#[derive(Debug, Clone)]
+enum Inode {
+    Dir {
+        block: u32,
+        offset: u16,
+        listing_len: u32,
+    },
+    File {
+        blocks_start: u64,
+        size: u64,
+        fragment: u32,
+        fragment_offset: u32,
+        block_sizes: Vec<u32>,
+    },
+    Symlink(String),
+    Other,
+}
+
+/// A position in a metadata table; reading continues into the following metadata blocks as needed
+struct MetadataCursor {
+    next_block: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+/// An open SquashFS image
+struct Squashfs<R> {
+    reader: R,
+    block_size: u32,
+    root_inode: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+}
+
+impl<R: Read + Seek> Squashfs<R> {
+    fn open(mut reader: R) -> anyhow::Result<Self> {
+        let mut superblock = [0u8; SQUASHFS_SUPER_SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut superblock)?;
+
+        anyhow::ensure!(
+            le_u32(&superblock, SQUASHFS_SUPER_POS_MAGIC) == SQUASHFS_MAGIC,
+            "SquashFS filesystem not found"
+        );
+        let version = le_u16(&superblock, SQUASHFS_SUPER_POS_VERSION_MAJOR);
+        anyhow::ensure!(version == 4, "SquashFS version {version} is unsupported");
+        let compression = le_u16(&superblock, SQUASHFS_SUPER_POS_COMPRESSION);
+        anyhow::ensure!(
+            compression == SQUASHFS_COMPRESSION_ZLIB,
+            "SquashFS compression {compression} is unsupported"
+        );
+
+        Ok(Self {
+            reader,
+            block_size: le_u32(&superblock, SQUASHFS_SUPER_POS_BLOCK_SIZE),
+            root_inode: le_u64(&superblock, SQUASHFS_SUPER_POS_ROOT_INODE),
+            inode_table: le_u64(&superblock, SQUASHFS_SUPER_POS_INODE_TABLE),
+            directory_table: le_u64(&superblock, SQUASHFS_SUPER_POS_DIRECTORY_TABLE),
+            fragment_table: le_u64(&superblock, SQUASHFS_SUPER_POS_FRAGMENT_TABLE),
+        })
+    }
+
+    fn read_raw(&mut self, pos: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut raw = vec![0u8; len];
+        self.reader.seek(SeekFrom::Start(pos))?;
+        self.reader.read_exact(&mut raw)?;
+        Ok(raw)
+    }
+
+    /// Read a (possibly zlib-compressed) block that decompresses to no more than `limit` bytes
+    fn read_block(
+        &mut self,
+        pos: u64,
+        len: usize,
+        compressed: bool,
+        limit: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let raw = self.read_raw(pos, len)?;
+        if !compressed {
+            return Ok(raw);
+        }
+
+        let mut data = Vec::new();
+        flate2::read::ZlibDecoder::new(&raw[..])
+            .take(limit as u64 + 1)
+            .read_to_end(&mut data)?;
+        anyhow::ensure!(data.len() <= limit, "SquashFS block is too large");
+        Ok(data)
+    }
+
+    fn metadata(&self, start: u64, offset: u16) -> MetadataCursor {
+        MetadataCursor {
+            next_block: start,
+            buf: Vec::new(),
+            pos: offset.into(),
+        }
+    }
+
+    fn read_metadata(
+        &mut self,
+        cursor: &mut MetadataCursor,
+        len: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        while cursor.buf.len() < cursor.pos + len {
+            let header = le_u16(&self.read_raw(cursor.next_block, 2)?, 0);
+            let size = header & !SQUASHFS_METADATA_UNCOMPRESSED;
+            let compressed = header & SQUASHFS_METADATA_UNCOMPRESSED == 0;
+
+            let block = self.read_block(
+                cursor.next_block + 2,
+                size.into(),
+                compressed,
+                SQUASHFS_METADATA_SIZE,
+            )?;
+            anyhow::ensure!(!block.is_empty(), "SquashFS metadata is corrupt");
+            cursor.buf.extend(block);
+            cursor.next_block += 2 + u64::from(size);
+        }
+
+        let data = cursor.buf[cursor.pos..][..len].to_vec();
+        cursor.pos += len;
+        Ok(data)
+    }
+
+    /// Read an inode, given a reference to it (its metadata block in the upper bits, and offset
+    /// within that block in the lower 16)
+    fn inode(&mut self, reference: u64) -> anyhow::Result<Inode> {
+        let mut cursor = self.metadata(self.inode_table + (reference >> 16), reference as u16);
+        let header = self.read_metadata(&mut cursor, SQUASHFS_INODE_HEADER_SIZE)?;
+
+        let (blocks_start, size, fragment, fragment_offset) = match le_u16(&header, 0) {
+            SQUASHFS_BASIC_DIR => {
+                let raw = self.read_metadata(&mut cursor, 16)?;
+                return Ok(Inode::Dir {
+                    block: le_u32(&raw, 0),
+                    offset: le_u16(&raw, 10),
+                    listing_len: u32::from(le_u16(&raw, 8)).saturating_sub(3),
+                });
+            }
+            SQUASHFS_EXT_DIR => {
+                let raw = self.read_metadata(&mut cursor, 24)?;
+                return Ok(Inode::Dir {
+                    block: le_u32(&raw, 8),
+                    offset: le_u16(&raw, 18),
+                    listing_len: le_u32(&raw, 4).saturating_sub(3),
+                });
+            }
+            SQUASHFS_BASIC_SYMLINK | SQUASHFS_EXT_SYMLINK => {
+                let raw = self.read_metadata(&mut cursor, 8)?;
+                let target = self.read_metadata(&mut cursor, le_u32(&raw, 4) as usize)?;
+                return Ok(Inode::Symlink(String::from_utf8(target)?));
+            }
+            SQUASHFS_BASIC_FILE => {
+                let raw = self.read_metadata(&mut cursor, 16)?;
+                let size = le_u32(&raw, 12).into();
+                (
+                    le_u32(&raw, 0).into(),
+                    size,
+                    le_u32(&raw, 4),
+                    le_u32(&raw, 8),
+                )
+            }
+            SQUASHFS_EXT_FILE => {
+                let raw = self.read_metadata(&mut cursor, 40)?;
+                (
+                    le_u64(&raw, 0),
+                    le_u64(&raw, 8),
+                    le_u32(&raw, 28),
+                    le_u32(&raw, 32),
+                )
+            }
+            _ => return Ok(Inode::Other),
+        };
+
+        // The tail end of a file is either a block of its own or kept in a fragment
+        let block_size = u64::from(self.block_size);
+        let blocks = match fragment {
+            SQUASHFS_NO_FRAGMENT => size.div_ceil(block_size),
+            _ => size / block_size,
+        };
+        let raw = self.read_metadata(&mut cursor, blocks as usize * 4)?;
+
+        Ok(Inode::File {
+            blocks_start,
+            size,
+            fragment,
+            fragment_offset,
+            block_sizes: raw.chunks(4).map(|x| le_u32(x, 0)).collect(),
+        })
+    }
+
+    /// Find `name` in a directory listing, returning a reference to its inode
+    fn lookup_ref(
+        &mut self,
+        block: u32,
+        offset: u16,
+        listing_len: u32,
+        name: &str,
+    ) -> anyhow::Result<Option<u64>> {
+        let mut cursor = self.metadata(self.directory_table + u64::from(block), offset);
+        let listing = self.read_metadata(&mut cursor, listing_len as usize)?;
+        let corrupt = || anyhow::anyhow!("SquashFS directory is corrupt");
+
+        // The listing is a run of headers, each followed by the entries whose inodes share a
+        // metadata block
+        let mut pos = 0;
+        while pos < listing.len() {
+            let header = listing
+                .get(pos..pos + SQUASHFS_DIR_HEADER_SIZE)
+                .ok_or_else(corrupt)?;
+            let count = le_u32(header, 0) + 1;
+            let start = le_u32(header, 4);
+            pos += SQUASHFS_DIR_HEADER_SIZE;
+
+            for _ in 0..count {
+                let entry = listing
+                    .get(pos..pos + SQUASHFS_DIR_ENTRY_SIZE)
+                    .ok_or_else(corrupt)?;
+                let offset = le_u16(entry, 0);
+                let name_len = usize::from(le_u16(entry, 6)) + 1;
+                pos += SQUASHFS_DIR_ENTRY_SIZE;
+
+                let entry_name = listing.get(pos..pos + name_len).ok_or_else(corrupt)?;
+                pos += name_len;
+                if entry_name == name.as_bytes() {
+                    return Ok(Some((u64::from(start) << 16) | u64::from(offset)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read and decompress the fragment block with the given index
+    fn fragment_block(&mut self, index: u32) -> anyhow::Result<Vec<u8>> {
+        let entries_per_block = SQUASHFS_METADATA_SIZE as u32 / SQUASHFS_FRAGMENT_ENTRY_SIZE;
+        let pointer = self.fragment_table + u64::from(index / entries_per_block) * 8;
+        let table_block = le_u64(&self.read_raw(pointer, 8)?, 0);
+
+        let offset = (index % entries_per_block) * SQUASHFS_FRAGMENT_ENTRY_SIZE;
+        let mut cursor = self.metadata(table_block, offset as u16);
+        let entry = self.read_metadata(&mut cursor, SQUASHFS_FRAGMENT_ENTRY_SIZE as usize)?;
+
+        let size = le_u32(&entry, 8);
+        self.read_block(
+            le_u64(&entry, 0),
+            (size & !SQUASHFS_DATA_UNCOMPRESSED) as usize,
+            size & SQUASHFS_DATA_UNCOMPRESSED == 0,
+            self.block_size as usize,
+        )
+    }
+}
+
+impl<R: Read + Seek> ReadOnlyFs for Squashfs<R> {
+    type Node = Inode;
+
+    fn root(&mut self) -> anyhow::Result<Inode> {
+        self.inode(self.root_inode)
+    }
+
+    fn kind(&self, inode: &Inode) -> NodeKind {
+        match inode {
+            Inode::Dir { .. } => NodeKind::Dir,
+            Inode::File { .. } => NodeKind::File,
+            Inode::Symlink(_) => NodeKind::Symlink,
+            Inode::Other => NodeKind::Other,
+        }
+    }
+
+    fn lookup(&mut self, dir: &Inode, name: &str) -> anyhow::Result<Option<Inode>> {
+        let &Inode::Dir {
+            block,
+            offset,
+            listing_len,
+        } = dir
+        else {
+            anyhow::bail!("not a directory");
+        };
+
+        match self.lookup_ref(block, offset, listing_len, name)? {
+            Some(reference) => Ok(Some(self.inode(reference)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_link(&mut self, inode: &Inode) -> anyhow::Result<String> {
+        match inode {
+            Inode::Symlink(target) => Ok(target.clone()),
+            _ => anyhow::bail!("not a symlink"),
+        }
+    }
+
+    fn copy_file(&mut self, inode: &Inode, out: &mut dyn Write) -> anyhow::Result<u64> {
+        let Inode::File {
+            blocks_start,
+            size,
+            fragment,
+            fragment_offset,
+            block_sizes,
+        } = inode
+        else {
+            anyhow::bail!("not a regular file");
+        };
+
+        let mut pos = *blocks_start;
+        let mut remaining = *size;
+        for &block_size in block_sizes {
+            let len = remaining.min(self.block_size.into()) as usize;
+
+            // A block size of zero marks a sparse block
+            let data = match block_size {
+                0 => vec![0u8; len],
+                _ => {
+                    let on_disk = block_size & !SQUASHFS_DATA_UNCOMPRESSED;
+                    let compressed = block_size & SQUASHFS_DATA_UNCOMPRESSED == 0;
+                    let data = self.read_block(
+                        pos,
+                        on_disk as usize,
+                        compressed,
+                        self.block_size as usize,
+                    )?;
+                    pos += u64::from(on_disk);
+                    data
+                }
+            };
+
+            anyhow::ensure!(data.len() >= len, "file data is truncated");
+            out.write_all(&data[..len])?;
+            remaining -= len as u64;
+        }
+
+        if *fragment != SQUASHFS_NO_FRAGMENT {
+            let block = self.fragment_block(*fragment)?;
+            let tail = block
+                .get(*fragment_offset as usize..)
+                .and_then(|x| x.get(..remaining as usize))
+                .ok_or(anyhow::anyhow!("file data is truncated"))?;
+            out.write_all(tail)?;
+            remaining = 0;
+        }
+
+        anyhow::ensure!(remaining == 0, "file data is truncated");
+        Ok(*size)
+    }
+}
+
+/// Copy the given paths (regular files and symlinks) out of a SquashFS image into `out_dir`,
+/// preserving their directory structure.
+///
+/// Problems with individual paths are recorded in the report; only an unreadable image is an
+/// error.
+pub fn extract_paths<R: Read + Seek>(
+    reader: R,
+    paths: &[&str],
+    out_dir: &Path,
+) -> anyhow::Result<ExtractReport> {
+    let mut squashfs = Squashfs::open(reader)?;
+    Ok(extract_all(&mut squashfs, paths, out_dir))
+}
+
+/// A node of the tree given to [build_test_squashfs]
+#[cfg(test)]
+enum TestNode {
+    Dir(Vec<(&'static str, TestNode)>),
+
+    /// A file stored entirely in data blocks
+    File(Vec<u8>),
+
+    /// A file stored entirely in the fragment block
+    Fragment(&'static [u8]),
+
+    Symlink(&'static str),
+}
+
+/// Generate a SquashFS image of a tree, laid out the way `mksquashfs` does it
+///
+/// With `compress`, every block that zlib manages to shrink is stored compressed.
+#[cfg(test)]
+fn build_test_squashfs(root: &TestNode, compress: bool) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 4096;
+
+    let pack = |data: &[u8]| -> (Vec<u8>, bool) {
+        if compress {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            let packed = encoder.finish().unwrap();
+            if packed.len() < data.len() {
+                return (packed, true);
+            }
+        }
+        (data.to_vec(), false)
+    };
+    let metadata_block = |data: &[u8]| -> Vec<u8> {
+        assert!(data.len() <= SQUASHFS_METADATA_SIZE);
+        let (packed, compressed) = pack(data);
+        let mut header = packed.len() as u16;
+        if !compressed {
+            header |= SQUASHFS_METADATA_UNCOMPRESSED;
+        }
+        [&header.to_le_bytes()[..], &packed].concat()
+    };
+
+    // Flatten the tree, so that every node knows its own and its children's inode numbers
+    type Flattened<'a> = (&'a TestNode, usize, Vec<(&'a str, usize)>);
+    let mut nodes: Vec<Flattened> = vec![(root, 0, vec![])];
+    let mut i = 0;
+    while i < nodes.len() {
+        if let TestNode::Dir(children) = nodes[i].0 {
+            for (name, child) in children {
+                let index = nodes.len();
+                nodes.push((child, i, vec![]));
+                nodes[i].2.push((name, index));
+            }
+        }
+        i += 1;
+    }
+    let ino = |index: usize| index as u32 + 1;
+
+    // File data, followed by the one fragment block
+    let mut image = vec![0u8; SQUASHFS_SUPER_SIZE];
+    let mut fragments = vec![];
+    let mut file_layouts = vec![];
+    for (node, _, _) in &nodes {
+        file_layouts.push(match node {
+            TestNode::File(data) => {
+                let start = image.len() as u32;
+                let mut block_sizes = vec![];
+                for chunk in data.chunks(BLOCK_SIZE) {
+                    let (packed, compressed) = pack(chunk);
+                    image.extend(&packed);
+                    block_sizes.push(match compressed {
+                        true => packed.len() as u32,
+                        false => packed.len() as u32 | SQUASHFS_DATA_UNCOMPRESSED,
+                    });
+                }
+                (start, SQUASHFS_NO_FRAGMENT, 0, block_sizes)
+            }
+            TestNode::Fragment(data) => {
+                let offset = fragments.len() as u32;
+                fragments.extend(*data);
+                (0, 0, offset, vec![])
+            }
+            _ => (0, SQUASHFS_NO_FRAGMENT, 0, vec![]),
+        });
+    }
+    assert!(fragments.len() <= BLOCK_SIZE);
+    let fragment_start = image.len() as u64;
+    let (packed, compressed) = pack(&fragments);
+    image.extend(&packed);
+    let mut fragment_size = packed.len() as u32;
+    if !compressed {
+        fragment_size |= SQUASHFS_DATA_UNCOMPRESSED;
+    }
+
+    // Inode sizes are known up front, so that directory entries can point at them
+    let inode_sizes = nodes
+        .iter()
+        .zip(&file_layouts)
+        .map(|((node, _, _), layout)| {
+            SQUASHFS_INODE_HEADER_SIZE
+                + match node {
+                    TestNode::Symlink(target) => 8 + target.len(),
+                    _ => 16 + layout.3.len() * 4,
+                }
+        });
+    let inode_offsets: Vec<usize> = inode_sizes
+        .scan(0, |offset, size| {
+            *offset += size;
+            Some(*offset - size)
+        })
+        .collect();
+
+    // One listing per directory, each with a single header
+    let mut directory_table = vec![];
+    let mut listings = vec![(0, 0); nodes.len()];
+    for (index, (_, _, children)) in nodes.iter().enumerate() {
+        if !matches!(nodes[index].0, TestNode::Dir(_)) {
+            continue;
+        }
+
+        let start = directory_table.len();
+        let mut children = children.clone();
+        children.sort_by_key(|&(name, _)| name);
+        if let Some(base) = children.iter().map(|&(_, child)| ino(child)).min() {
+            directory_table.extend((children.len() as u32 - 1).to_le_bytes());
+            directory_table.extend(0u32.to_le_bytes());
+            directory_table.extend(base.to_le_bytes());
+            for (name, child) in children {
+                let inode_type = match nodes[child].0 {
+                    TestNode::Dir(_) => SQUASHFS_BASIC_DIR,
+                    TestNode::Symlink(_) => SQUASHFS_BASIC_SYMLINK,
+                    _ => SQUASHFS_BASIC_FILE,
+                };
+                directory_table.extend((inode_offsets[child] as u16).to_le_bytes());
+                directory_table.extend(((ino(child) - base) as i16).to_le_bytes());
+                directory_table.extend(inode_type.to_le_bytes());
+                directory_table.extend((name.len() as u16 - 1).to_le_bytes());
+                directory_table.extend(name.as_bytes());
+            }
+        }
+        listings[index] = (start, directory_table.len() - start);
+    }
+
+    let mut inode_table = vec![];
+    for (index, (node, parent, _)) in nodes.iter().enumerate() {
+        assert_eq!(inode_table.len(), inode_offsets[index]);
+
+        let (inode_type, mode) = match node {
+            TestNode::Dir(_) => (SQUASHFS_BASIC_DIR, 0o755u16),
+            TestNode::Symlink(_) => (SQUASHFS_BASIC_SYMLINK, 0o777),
+            _ => (SQUASHFS_BASIC_FILE, 0o644),
+        };
+        inode_table.extend(inode_type.to_le_bytes());
+        inode_table.extend(mode.to_le_bytes());
+        inode_table.extend([0u8; 8]); // uid, gid, mtime
+        inode_table.extend(ino(index).to_le_bytes());
+
+        match node {
+            TestNode::Dir(_) => {
+                let (start, len) = listings[index];
+                let parent = if index == 0 { nodes.len() + 1 } else { *parent };
+                inode_table.extend(0u32.to_le_bytes());
+                inode_table.extend(2u32.to_le_bytes());
+                inode_table.extend((len as u16 + 3).to_le_bytes());
+                inode_table.extend((start as u16).to_le_bytes());
+                inode_table.extend(ino(parent).to_le_bytes());
+            }
+            TestNode::Symlink(target) => {
+                inode_table.extend(1u32.to_le_bytes());
+                inode_table.extend((target.len() as u32).to_le_bytes());
+                inode_table.extend(target.as_bytes());
+            }
+            TestNode::File(_) | TestNode::Fragment(_) => {
+                let len = match node {
+                    TestNode::File(data) => data.len(),
+                    TestNode::Fragment(data) => data.len(),
+                    _ => unreachable!(),
+                };
+                let (start, fragment, offset, block_sizes) = &file_layouts[index];
+                inode_table.extend(start.to_le_bytes());
+                inode_table.extend(fragment.to_le_bytes());
+                inode_table.extend(offset.to_le_bytes());
+                inode_table.extend((len as u32).to_le_bytes());
+                for size in block_sizes {
+                    inode_table.extend(size.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    let inode_table_start = image.len() as u64;
+    image.extend(metadata_block(&inode_table));
+    let directory_table_start = image.len() as u64;
+    image.extend(metadata_block(&directory_table));
+
+    // The fragment and ID tables are each a metadata block, followed by a pointer to that block
+    let fragment_entries = image.len() as u64;
+    let fragment_entry = [
+        &fragment_start.to_le_bytes()[..],
+        &fragment_size.to_le_bytes(),
+        &[0u8; 4],
+    ]
+    .concat();
+    image.extend(metadata_block(&fragment_entry));
+    let fragment_table_start = image.len() as u64;
+    image.extend(fragment_entries.to_le_bytes());
+
+    let ids = image.len() as u64;
+    image.extend(metadata_block(&0u32.to_le_bytes()));
+    let id_table_start = image.len() as u64;
+    image.extend(ids.to_le_bytes());
+
+    let bytes_used = image.len() as u64;
+    let superblock = [
+        &SQUASHFS_MAGIC.to_le_bytes()[..],
+        &(nodes.len() as u32).to_le_bytes(),
+        &0u32.to_le_bytes(), // mtime
+        &(BLOCK_SIZE as u32).to_le_bytes(),
+        &1u32.to_le_bytes(), // fragments
+        &SQUASHFS_COMPRESSION_ZLIB.to_le_bytes(),
+        &(BLOCK_SIZE.trailing_zeros() as u16).to_le_bytes(),
+        &0u16.to_le_bytes(), // flags
+        &1u16.to_le_bytes(), // IDs
+        &4u16.to_le_bytes(),
+        &0u16.to_le_bytes(),
+        &(inode_offsets[0] as u64).to_le_bytes(),
+        &bytes_used.to_le_bytes(),
+        &id_table_start.to_le_bytes(),
+        &u64::MAX.to_le_bytes(), // xattrs
+        &inode_table_start.to_le_bytes(),
+        &directory_table_start.to_le_bytes(),
+        &fragment_table_start.to_le_bytes(),
+        &u64::MAX.to_le_bytes(), // export table
+    ]
+    .concat();
+    image[..SQUASHFS_SUPER_SIZE].copy_from_slice(&superblock);
+
+    // mksquashfs pads the image out to a multiple of 4 KiB
+    image.resize(image.len().div_ceil(4096) * 4096, 0);
+    image
+}
+
+#[test]
+fn test_extract_paths() -> anyhow::Result<()> {
+    use super::PathOutcome;
+    use std::{fs, io};
+    use TestNode::*;
+
+    let big: Vec<u8> = (0..10000u32).map(|x| (x % 251) as u8).collect();
+    let tree = Dir(vec![
+        (
+            "etc",
+            Dir(vec![
+                ("hostname", Fragment(b"turing-pi\n")),
+                (
+                    "network",
+                    Dir(vec![("interfaces", File(b"auto eth0\n".to_vec()))]),
+                ),
+                ("ssh", Symlink("../data/ssh")),
+                ("localtime", Symlink("/usr/share/zoneinfo/UTC")),
+                ("empty", Dir(vec![])),
+            ]),
+        ),
+        (
+            "data",
+            Dir(vec![
+                (
+                    "ssh",
+                    Dir(vec![("ssh_host_ed25519_key", Fragment(b"PRIVATE\n"))]),
+                ),
+                ("big", File(big.clone())),
+            ]),
+        ),
+    ]);
+
+    for compress in [false, true] {
+        let image = build_test_squashfs(&tree, compress);
+        let out_dir = std::env::temp_dir().join(format!(
+            "squashfs-extract-{}-{compress}",
+            std::process::id()
+        ));
+
+        let paths = [
+            "/etc/hostname",
+            "/etc/network/interfaces",
+            "/etc/ssh/ssh_host_ed25519_key",
+            "/etc/localtime",
+            "/data/big",
+            "/data/missing",
+            "/etc/empty",
+        ];
+        let report = extract_paths(io::Cursor::new(&image), &paths, &out_dir)?;
+
+        let outcomes: Vec<_> = report.paths.iter().map(|(_, x)| x.clone()).collect();
+        assert_eq!(
+            outcomes,
+            [
+                PathOutcome::File { bytes: 10 },
+                PathOutcome::File { bytes: 10 },
+                PathOutcome::File { bytes: 8 },
+                PathOutcome::Symlink {
+                    target: "/usr/share/zoneinfo/UTC".into()
+                },
+                PathOutcome::File { bytes: 10000 },
+                PathOutcome::Failed("no such file or directory".into()),
+                PathOutcome::Failed("is a directory".into()),
+            ]
+        );
+
+        assert_eq!(fs::read(out_dir.join("etc/hostname"))?, b"turing-pi\n");
+        assert_eq!(
+            fs::read(out_dir.join("etc/network/interfaces"))?,
+            b"auto eth0\n"
+        );
+        assert_eq!(fs::read(out_dir.join("data/big"))?, big);
+
+        fs::remove_dir_all(&out_dir)?;
+    }
+
+    // Anything that isn't SquashFS is rejected outright
+    let image = build_test_squashfs(&tree, false);
+    assert!(extract_paths(io::Cursor::new(&image[1..]), &[], Path::new("/")).is_err());
+
+    Ok(())
+}
@@ -1,6 +1,14 @@
+pub mod buildinfo;
+pub mod bundle;
+pub mod error;
+#[cfg(any(test, feature = "bench"))]
+pub mod fixtures;
 pub mod format;
 pub mod image;
+pub mod lock;
 pub mod nand;
+pub mod progress;
+#[cfg(feature = "linux-hw")]
 pub mod turing_pi;
 pub mod ubi;
 pub mod util;
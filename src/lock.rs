@@ -0,0 +1,127 @@
+//! Keeping more than one installer from touching the NAND at once.
+//!
+//! Each MTD device is `flock`ed while open (see [MtdNand::open](crate::nand::mtd::MtdNand::open)),
+//! but that alone can't catch one instance using the `boot`/`ubi` partitions while another uses the
+//! whole device. So anything about to write to the NAND also takes an [InstallLock] first.
+
+use std::fs::{self, File, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The lock file's name, in `/run` (or the temporary directory, where there's no `/run`)
+const LOCK_FILE_NAME: &str = "bmc-installer.lock";
+
+/// The default lock file
+pub fn default_lock_path() -> PathBuf {
+    let run = Path::new("/run");
+    match run.is_dir() {
+        true => run.join(LOCK_FILE_NAME),
+        false => std::env::temp_dir().join(LOCK_FILE_NAME),
+    }
+}
+
+/// Exclusive use of the NAND by this installer instance; released when dropped
+#[derive(Debug)]
+pub struct InstallLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl InstallLock {
+    /// Take the lock at [default_lock_path]
+    pub fn acquire() -> anyhow::Result<Self> {
+        Self::acquire_at(default_lock_path())
+    }
+
+    /// Take the lock at `path`, failing at once if another instance holds it
+    pub fn acquire_at(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+                let holder = match holder.trim() {
+                    "" => String::new(),
+                    pid => format!(" (process {pid})"),
+                };
+                anyhow::bail!(
+                    "another installer instance is running{holder}; it holds {}",
+                    path.display()
+                );
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        // Note who holds the lock, for the error above
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(Self { _file: file, path })
+    }
+
+    /// The lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Remove the lock file at `path`, so that the next [InstallLock::acquire_at] succeeds even if
+/// another instance (e.g. a hung one) still holds the lock.
+///
+/// A lock is released when the process holding it exits, crashed or not, so this is only needed
+/// when that process is still around. It keeps its lock on the removed file, and nothing stops it
+/// from writing to the NAND: stop it first.
+pub fn force_unlock(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_install_lock() -> anyhow::Result<()> {
+    use std::sync::{mpsc, Arc, Barrier};
+    use std::thread;
+
+    let path = std::env::temp_dir().join(format!("test-install-lock-{}", std::process::id()));
+    force_unlock(&path)?;
+
+    // One thread holds the lock while another tries for it
+    let barrier = Arc::new(Barrier::new(2));
+    let (tx, rx) = mpsc::channel();
+    let holder = {
+        let (path, barrier) = (path.clone(), barrier.clone());
+        thread::spawn(move || -> anyhow::Result<()> {
+            let _lock = InstallLock::acquire_at(&path)?;
+            barrier.wait();
+            rx.recv()?;
+            Ok(())
+        })
+    };
+    barrier.wait();
+    let error = InstallLock::acquire_at(&path).unwrap_err().to_string();
+    assert!(error.contains("another installer instance is running"));
+    assert!(error.contains(&format!("process {}", std::process::id())));
+
+    // Dropping the lock releases it
+    tx.send(())?;
+    holder.join().unwrap()?;
+    let lock = InstallLock::acquire_at(&path)?;
+    assert_eq!(lock.path(), path);
+
+    // Forcing it open leaves the old holder locking a file nobody else looks at
+    force_unlock(&path)?;
+    let forced = InstallLock::acquire_at(&path)?;
+    assert!(InstallLock::acquire_at(&path).is_err());
+    drop((lock, forced));
+    force_unlock(&path)?;
+
+    Ok(())
+}
@@ -0,0 +1,184 @@
+//! NAND abstraction layer implementation over a block device (e.g. eMMC) or a regular file
+//!
+//! Block devices have no erase blocks or bad-block marking of their own, so this imposes a
+//! [NandLayout] on the device and emulates NAND semantics: erasing fills a block with 0xFF, and
+//! programming refuses to write over anything but erased pages, just like [SimNand](super::SimNand)
+//! does. That way, the compare-before-write logic shared with real NAND behaves the same here.
+
+use super::{Nand, NandBlock, NandLayout, OpCounters, OpStats, PageUtil, SharedNand};
+
+use anyhow::{bail, ensure};
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A block device (or file) standing in for NAND flash
+#[derive(Debug)]
+pub struct BlockDevNand {
+    file: File,
+    layout: NandLayout,
+
+    /// The operations carried out through this handle and the others of the same device
+    stats: Arc<OpCounters>,
+}
+
+impl BlockDevNand {
+    /// Open a block device (or file) by path, treating it as having the given layout
+    pub fn open<P: AsRef<Path>>(path: P, layout: NandLayout) -> anyhow::Result<Self> {
+        let mut file = File::options().read(true).write(true).open(path)?;
+
+        // Block devices report a length of 0 in their metadata, but can be seeked to the end
+        let len = file.seek(SeekFrom::End(0))?;
+        ensure!(
+            len >= layout.total_bytes(),
+            "device holds {len} bytes, but the layout {layout} needs {}",
+            layout.total_bytes()
+        );
+
+        Ok(Self {
+            file,
+            layout,
+            stats: Default::default(),
+        })
+    }
+}
+
+impl Nand for BlockDevNand {
+    type Block<'a> = BlockDevBlock<'a>;
+
+    fn block(&mut self, index: u32) -> anyhow::Result<Option<BlockDevBlock<'_>>> {
+        ensure!(index < self.layout.blocks, "block {index} out of range");
+
+        // There's no such thing as a bad block here
+        Ok(Some(BlockDevBlock { nand: self, index }))
+    }
+
+    fn get_layout(&self) -> NandLayout {
+        self.layout
+    }
+
+    fn stats(&self) -> OpStats {
+        self.stats.get()
+    }
+}
+
+impl SharedNand for BlockDevNand {
+    fn clone_handle(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            layout: self.layout,
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+pub struct BlockDevBlock<'a> {
+    nand: &'a BlockDevNand,
+    index: u32,
+}
+
+impl BlockDevBlock<'_> {
+    /// Ensure that the byte count and starting page range is valid, and compute the device offset
+    /// for the page
+    fn offset_for(&self, start_page: u32, bytes: usize) -> anyhow::Result<u64> {
+        ensure!(
+            bytes.is_multiple_of(self.page_size()),
+            "buffer not multiple of page size"
+        );
+
+        let end_page = start_page as usize + bytes / self.page_size();
+        ensure!(
+            end_page <= self.page_count() as usize,
+            "block {0}, page range {start_page}..{end_page} out of bounds",
+            self.index
+        );
+
+        let block_base = self.nand.layout.block_bytes() as u64 * u64::from(self.index);
+        Ok(block_base + (self.page_size() * start_page as usize) as u64)
+    }
+}
+
+impl NandBlock for BlockDevBlock<'_> {
+    fn page_count(&self) -> u32 {
+        self.nand.layout.pages_per_block
+    }
+    fn page_size(&self) -> usize {
+        self.nand.layout.bytes_per_page
+    }
+    fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
+        let offset = self.offset_for(start_page, content.len())?;
+        self.nand.stats.read(content.len());
+        Ok(self.nand.file.read_exact_at(content, offset)?)
+    }
+    fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
+        let offset = self.offset_for(start_page, content.len())?;
+        self.nand.stats.program(content.len());
+
+        // Keep to NAND's rules: everything from `start_page` on must still be erased
+        let mut rest = vec![0; self.page_size() * (self.page_count() - start_page) as usize];
+        let rest_offset = self.offset_for(start_page, rest.len())?;
+        self.nand.file.read_exact_at(&mut rest, rest_offset)?;
+        ensure!(rest.is_erased(), "write in already-written area");
+
+        Ok(self.nand.file.write_all_at(content, offset)?)
+    }
+    fn erase(&mut self) -> anyhow::Result<()> {
+        self.nand.stats.erase(1);
+
+        // BLKDISCARD would be quicker, but discarded sectors may read back as zeroes rather than
+        // as erased
+        let erased = vec![0xFF; self.nand.layout.block_bytes()];
+        let offset = self.offset_for(0, erased.len())?;
+        Ok(self.nand.file.write_all_at(&erased, offset)?)
+    }
+    fn mark_bad(self) -> anyhow::Result<()> {
+        self.nand.stats.mark_bad();
+        bail!("block {} can't be marked bad on a block device", self.index)
+    }
+}
+
+#[test]
+fn test_blockdev_raw_image() -> anyhow::Result<()> {
+    use crate::format::raw::{verify_raw_image, write_raw_image, RawVerifyResult};
+    use crate::nand::CountingNand;
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 8,
+        pages_per_block: 4,
+        bytes_per_page: 512,
+    };
+
+    let path = std::env::temp_dir().join(format!("blockdev-nand-{}.img", std::process::id()));
+    let file = File::create(&path)?;
+    file.set_len(TEST_LAYOUT.total_bytes())?;
+    let nand = BlockDevNand::open(&path, TEST_LAYOUT);
+    std::fs::remove_file(&path)?;
+
+    // Start from an erased device
+    let mut nand = CountingNand::new(nand?);
+    for index in 0..TEST_LAYOUT.blocks {
+        nand.block(index)?.unwrap().erase()?;
+    }
+
+    let image: Vec<u8> = (0..5000).map(|x| (x * 7) as u8).collect();
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+    assert_eq!(
+        verify_raw_image(&mut nand, &mut &image[..], false)?,
+        RawVerifyResult::Match { bytes: 5000 }
+    );
+
+    // Writing the same image again changes nothing
+    nand.counts.take();
+    write_raw_image(&mut nand, &mut &image[..], false)?;
+    assert_eq!(nand.counts.get().mutations(), 0);
+
+    // Programming over written pages is refused, as on NAND
+    let page = vec![0u8; TEST_LAYOUT.bytes_per_page];
+    assert!(nand.block(0)?.unwrap().program(0, &page).is_err());
+    assert!(nand.block(0)?.unwrap().mark_bad().is_err());
+
+    Ok(())
+}
@@ -0,0 +1,365 @@
+//! NAND abstraction layer implementation over a file of its own format, for simulating NAND
+//! flash of any size that persists between runs (like Linux's mtdram, but in a file).
+//!
+//! Unlike [SimNand](super::SimNand), nothing is held in memory, and bad blocks are remembered. The
+//! file holds:
+//!
+//! - A header, at offset 0:
+//!   - the magic bytes `BMCNAND1`;
+//!   - the [NandLayout], as the number of blocks, pages per block and bytes per page, each a
+//!     little-endian `u32`;
+//!   - 4 reserved bytes, always 0;
+//!   - the bad-block bitmap, one bit per block (the LSB of byte 0 being block 0);
+//!   - the erased bitmap, likewise. A block whose bit is set is erased, whatever the file holds
+//!     for it.
+//! - The data of each block, in order, starting at the first multiple of [DATA_ALIGN] past the
+//!   header.
+//!
+//! Erasing a block only sets its bit in the erased bitmap; its data is filled with 0xFF when it's
+//! next programmed. A new file starts out with every block erased, and no data written at all,
+//! so it takes up next to no space until used.
+
+use super::{Nand, NandBlock, NandLayout, OpCounters, OpStats, PageUtil, SharedNand};
+
+use anyhow::{bail, ensure};
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"BMCNAND1";
+
+/// The size of the header, less its bitmaps
+const HEADER_SIZE: u64 = MAGIC.len() as u64 + 4 * 4;
+
+/// What the offset of the block data is a multiple of
+pub const DATA_ALIGN: u64 = 4096;
+
+/// One of the bitmaps in the header
+#[derive(Debug, Copy, Clone)]
+enum Bitmap {
+    Bad,
+    Erased,
+}
+
+/// NAND flash simulated in a file
+#[derive(Debug)]
+pub struct FileNand {
+    file: File,
+    layout: NandLayout,
+
+    /// The operations carried out through this handle and the others of the same file
+    stats: Arc<OpCounters>,
+}
+
+impl FileNand {
+    /// Create a new file (replacing any existing one) for a NAND with the given layout, with
+    /// every block erased and none bad
+    pub fn create<P: AsRef<Path>>(path: P, layout: NandLayout) -> anyhow::Result<Self> {
+        ensure!(
+            layout.blocks > 0 && layout.block_bytes() > 0,
+            "layout {layout} has no room for anything"
+        );
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let nand = Self {
+            file,
+            layout,
+            stats: Default::default(),
+        };
+
+        let mut header = MAGIC.to_vec();
+        for x in [
+            layout.blocks,
+            layout.pages_per_block,
+            layout.bytes_per_page.try_into()?,
+            0,
+        ] {
+            header.extend(x.to_le_bytes());
+        }
+        header.resize(nand.bitmap_offset(Bitmap::Erased) as usize, 0);
+        header.resize(
+            (nand.bitmap_offset(Bitmap::Erased) + nand.bitmap_bytes()) as usize,
+            0xFF,
+        );
+        header.resize(nand.data_offset() as usize, 0);
+        nand.file.write_all_at(&header, 0)?;
+        nand.file
+            .set_len(nand.data_offset() + layout.total_bytes())?;
+
+        Ok(nand)
+    }
+
+    /// Open an existing file, as made by [FileNand::create]
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let file = File::options().read(true).write(true).open(path)?;
+
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, 0)?;
+        let (magic, fields) = header.split_at(MAGIC.len());
+        ensure!(magic == MAGIC, "not a NAND file (bad magic)");
+        let mut fields = fields
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()));
+        let layout = NandLayout {
+            blocks: fields.next().unwrap(),
+            pages_per_block: fields.next().unwrap(),
+            bytes_per_page: fields.next().unwrap() as usize,
+        };
+
+        let nand = Self {
+            file,
+            layout,
+            stats: Default::default(),
+        };
+        let len = nand.file.metadata()?.len();
+        ensure!(
+            len >= nand.data_offset() + layout.total_bytes(),
+            "NAND file is truncated: it holds {len} bytes, but the layout {layout} needs {}",
+            nand.data_offset() + layout.total_bytes()
+        );
+
+        Ok(nand)
+    }
+
+    /// Open an existing file, which must have the given layout if there is one, or else create
+    /// one with it
+    pub fn open_or_create<P: AsRef<Path>>(
+        path: P,
+        layout: Option<NandLayout>,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        match (path.exists(), layout) {
+            (true, layout) => {
+                let nand = Self::open(path)?;
+                if let Some(layout) = layout {
+                    ensure!(
+                        layout == nand.layout,
+                        "{} has layout {}, not {layout}",
+                        path.display(),
+                        nand.layout
+                    );
+                }
+                Ok(nand)
+            }
+            (false, Some(layout)) => Self::create(path, layout),
+            (false, None) => bail!(
+                "{} doesn't exist, and has no layout to create it with",
+                path.display()
+            ),
+        }
+    }
+
+    /// How many bytes each bitmap takes up
+    fn bitmap_bytes(&self) -> u64 {
+        u64::from(self.layout.blocks).div_ceil(8)
+    }
+
+    fn bitmap_offset(&self, bitmap: Bitmap) -> u64 {
+        match bitmap {
+            Bitmap::Bad => HEADER_SIZE,
+            Bitmap::Erased => HEADER_SIZE + self.bitmap_bytes(),
+        }
+    }
+
+    /// Where the data of block 0 begins
+    fn data_offset(&self) -> u64 {
+        (HEADER_SIZE + 2 * self.bitmap_bytes()).next_multiple_of(DATA_ALIGN)
+    }
+
+    /// Get a block's bit in one of the bitmaps
+    fn flag(&self, bitmap: Bitmap, index: u32) -> anyhow::Result<bool> {
+        ensure!(index < self.layout.blocks, "block {index} out of range");
+        let mut byte = [0];
+        let offset = self.bitmap_offset(bitmap) + u64::from(index / 8);
+        self.file.read_exact_at(&mut byte, offset)?;
+        Ok(byte[0] & (1 << (index % 8)) != 0)
+    }
+
+    /// Set or clear a block's bit in one of the bitmaps
+    fn set_flag(&self, bitmap: Bitmap, index: u32, value: bool) -> anyhow::Result<()> {
+        ensure!(index < self.layout.blocks, "block {index} out of range");
+        let mut byte = [0];
+        let offset = self.bitmap_offset(bitmap) + u64::from(index / 8);
+        self.file.read_exact_at(&mut byte, offset)?;
+        match value {
+            true => byte[0] |= 1 << (index % 8),
+            false => byte[0] &= !(1 << (index % 8)),
+        }
+        Ok(self.file.write_all_at(&byte, offset)?)
+    }
+}
+
+impl Nand for FileNand {
+    type Block<'a> = FileBlock<'a>;
+
+    fn block(&mut self, index: u32) -> anyhow::Result<Option<FileBlock<'_>>> {
+        match self.flag(Bitmap::Bad, index)? {
+            true => Ok(None),
+            false => Ok(Some(FileBlock { nand: self, index })),
+        }
+    }
+
+    fn get_layout(&self) -> NandLayout {
+        self.layout
+    }
+
+    fn stats(&self) -> OpStats {
+        self.stats.get()
+    }
+}
+
+impl SharedNand for FileNand {
+    fn clone_handle(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            layout: self.layout,
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+pub struct FileBlock<'a> {
+    nand: &'a FileNand,
+    index: u32,
+}
+
+impl FileBlock<'_> {
+    /// Ensure that the byte count and starting page range is valid, and compute the file offset
+    /// for the page
+    fn offset_for(&self, start_page: u32, bytes: usize) -> anyhow::Result<u64> {
+        ensure!(
+            bytes.is_multiple_of(self.page_size()),
+            "buffer not multiple of page size"
+        );
+
+        let end_page = start_page as usize + bytes / self.page_size();
+        ensure!(
+            end_page <= self.page_count() as usize,
+            "block {0}, page range {start_page}..{end_page} out of bounds",
+            self.index
+        );
+
+        let block_base = self.nand.layout.block_bytes() as u64 * u64::from(self.index);
+        Ok(self.nand.data_offset() + block_base + (self.page_size() * start_page as usize) as u64)
+    }
+
+    /// Read pages, as [NandBlock::read] does, without counting it
+    fn read_pages(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
+        let offset = self.offset_for(start_page, content.len())?;
+        match self.nand.flag(Bitmap::Erased, self.index)? {
+            true => content.fill(0xFF),
+            false => self.nand.file.read_exact_at(content, offset)?,
+        }
+        Ok(())
+    }
+}
+
+impl NandBlock for FileBlock<'_> {
+    fn page_count(&self) -> u32 {
+        self.nand.layout.pages_per_block
+    }
+    fn page_size(&self) -> usize {
+        self.nand.layout.bytes_per_page
+    }
+    fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
+        self.nand.stats.read(content.len());
+        self.read_pages(start_page, content)
+    }
+    fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
+        let offset = self.offset_for(start_page, content.len())?;
+        self.nand.stats.program(content.len());
+
+        // The first program since an erase writes out the whole block, erased pages and all
+        if self.nand.flag(Bitmap::Erased, self.index)? {
+            let mut block = vec![0xFF; self.nand.layout.block_bytes()];
+            let start = self.page_size() * start_page as usize;
+            block[start..start + content.len()].copy_from_slice(content);
+            self.nand
+                .file
+                .write_all_at(&block, self.offset_for(0, block.len())?)?;
+            return self.nand.set_flag(Bitmap::Erased, self.index, false);
+        }
+
+        // Keep to NAND's rules: everything from `start_page` on must still be erased
+        let mut rest = vec![0; self.page_size() * (self.page_count() - start_page) as usize];
+        self.read_pages(start_page, &mut rest)?;
+        ensure!(rest.is_erased(), "write in already-written area");
+
+        Ok(self.nand.file.write_all_at(content, offset)?)
+    }
+    fn erase(&mut self) -> anyhow::Result<()> {
+        self.nand.stats.erase(1);
+        self.nand.set_flag(Bitmap::Erased, self.index, true)
+    }
+    fn mark_bad(self) -> anyhow::Result<()> {
+        self.nand.stats.mark_bad();
+        self.nand.set_flag(Bitmap::Bad, self.index, true)
+    }
+}
+
+#[test]
+fn test_file_nand() -> anyhow::Result<()> {
+    use crate::ubi::{self, BlockContent};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+
+    let path = std::env::temp_dir().join(format!("file-nand-{}.img", std::process::id()));
+    let mut nand = FileNand::create(&path, TEST_LAYOUT)?;
+    assert_eq!(
+        std::fs::metadata(&path)?.len(),
+        DATA_ALIGN + TEST_LAYOUT.total_bytes()
+    );
+
+    // A new file is all erased
+    let mut ebt = ubi::scan_blocks(&mut nand)?;
+    assert!(ebt.iter().all(|&x| x == BlockContent::Erased));
+    ubi::format(&mut nand, &mut ebt)?;
+    nand.block(5)?.unwrap().mark_bad()?;
+
+    // Blocks follow NAND's rules, including after an erase
+    let page = vec![0x5A; TEST_LAYOUT.bytes_per_page];
+    let mut block = nand.block(6)?.unwrap();
+    assert!(block.program(0, &page).is_err());
+    block.erase()?;
+    block.program(1, &page)?;
+    assert!(block.program(0, &page).is_err());
+    block.program(2, &page)?;
+    let mut data = vec![0; TEST_LAYOUT.block_bytes()];
+    block.read(0, &mut data)?;
+    let pages: Vec<_> = data.chunks(TEST_LAYOUT.bytes_per_page).collect();
+    assert!(pages[0].is_erased() && pages[1] == page && pages[2] == page);
+    assert!(pages[3..].iter().all(|x| x.is_erased()));
+    block.erase()?;
+    drop(nand);
+
+    // Reopening the file brings back the layout, the erase counters and the bad block
+    let mut nand = FileNand::open(&path)?;
+    assert_eq!(nand.get_layout(), TEST_LAYOUT);
+    assert!(nand.block(5)?.is_none());
+    let reopened = ubi::scan_blocks(&mut nand)?;
+    assert_eq!(reopened[5], BlockContent::Bad);
+    assert_eq!(reopened[6], BlockContent::Erased);
+    for (block, (before, after)) in ebt.iter().zip(reopened.iter()).enumerate() {
+        if ![5, 6].contains(&block) {
+            assert_eq!(before, after, "block {block}");
+        }
+    }
+
+    // Anything else isn't opened
+    nand.file.write_all_at(b"X", 0)?;
+    assert!(FileNand::open(&path).is_err());
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
@@ -1,12 +1,27 @@
 //! Abstractions and code to access NAND flash
 
+use std::cell::{Cell, RefCell};
+use std::fmt;
 use std::io::{Read, Write};
+use std::ops::Add;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use anyhow::ensure;
 
-#[cfg(target_os = "linux")]
+#[cfg(unix)]
+pub mod blockdev;
+#[cfg(unix)]
+pub mod file;
+#[cfg(feature = "linux-hw")]
 pub mod mtd;
+pub mod partition;
+pub mod spec;
+pub mod test;
+pub mod trace;
+
+pub use spec::NandSpec;
 
 /// Convenience methods for operating on `[u8]`s that represent page contents
 pub trait PageUtil {
@@ -21,18 +36,74 @@ impl PageUtil for [u8] {
 }
 
 /// A pub-fields struct describing the data layout of a NAND flash device
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct NandLayout {
     pub blocks: u32,
     pub pages_per_block: u32,
     pub bytes_per_page: usize,
 }
 
-/// Parse strings like "BLOCKSxPAGESxBYTES"
+impl NandLayout {
+    /// How many bytes in each block
+    pub fn block_bytes(&self) -> usize {
+        self.pages_per_block as usize * self.bytes_per_page
+    }
+
+    /// How many bytes in the whole NAND
+    pub fn total_bytes(&self) -> u64 {
+        u64::from(self.blocks) * self.block_bytes() as u64
+    }
+}
+
+/// The binary units understood by [parse_size], largest first
+const SIZE_UNITS: [(&str, u64); 4] = [
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+    ("B", 1),
+];
+
+/// Parse a size like "128MiB" or "2048"
+pub(crate) fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let multiplier = match unit {
+        "" => 1,
+        unit => {
+            SIZE_UNITS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+                .ok_or(anyhow::anyhow!(
+                    "unknown unit {unit:?} (expected GiB, MiB, KiB or B)"
+                ))?
+                .1
+        }
+    };
+
+    number
+        .parse::<u64>()?
+        .checked_mul(multiplier)
+        .ok_or(anyhow::anyhow!("{s} is too large"))
+}
+
+/// Format a size in the largest unit that represents it exactly
+pub(crate) fn format_size(bytes: u64) -> String {
+    let (name, multiplier) = SIZE_UNITS
+        .into_iter()
+        .find(|&(_, multiplier)| bytes.is_multiple_of(multiplier))
+        .unwrap();
+
+    format!("{}{name}", bytes / multiplier)
+}
+
+/// Parse strings like "BLOCKSxPAGESxBYTES", or "TOTAL/ERASE/PAGE" sizes like "128MiB/128KiB/2KiB"
 impl FromStr for NandLayout {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.contains('/') {
+            return layout_from_sizes(s);
+        }
+
         let [blocks, pages_per_block, bytes_per_page]: [&str; 3] = s
             .split('x')
             .collect::<Vec<_>>()
@@ -50,6 +121,106 @@ impl FromStr for NandLayout {
     }
 }
 
+/// Parse the "TOTAL/ERASE/PAGE" form of [NandLayout::from_str]
+fn layout_from_sizes(s: &str) -> anyhow::Result<NandLayout> {
+    let [total, erase, page]: [u64; 3] = s
+        .split('/')
+        .map(parse_size)
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected TOTAL/ERASE/PAGE"))?;
+
+    ensure!(page > 0, "page size must be nonzero");
+    ensure!(
+        erase > 0 && erase % page == 0,
+        "erase size {} isn't a multiple of the page size {}",
+        format_size(erase),
+        format_size(page)
+    );
+    ensure!(
+        total > 0 && total % erase == 0,
+        "total size {} isn't a multiple of the erase size {}",
+        format_size(total),
+        format_size(erase)
+    );
+
+    Ok(NandLayout {
+        blocks: (total / erase).try_into()?,
+        pages_per_block: (erase / page).try_into()?,
+        bytes_per_page: page.try_into()?,
+    })
+}
+
+/// Show as "BLOCKSxPAGESxBYTES", or with `{:#}`, as "TOTAL/ERASE/PAGE" sizes; either parses back
+impl fmt::Display for NandLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(
+                f,
+                "{}/{}/{}",
+                format_size(self.total_bytes()),
+                format_size(self.block_bytes() as u64),
+                format_size(self.bytes_per_page as u64)
+            )
+        } else {
+            write!(
+                f,
+                "{}x{}x{}",
+                self.blocks, self.pages_per_block, self.bytes_per_page
+            )
+        }
+    }
+}
+
+/// The reasons a NAND flash device might refuse to be written
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WriteProtection {
+    /// The partition is read-only, usually because of a `read-only;` property in the device tree
+    ReadOnly,
+
+    /// The chip's block protection is engaged, e.g. because its WP pin is strapped
+    Locked,
+}
+
+impl fmt::Display for WriteProtection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadOnly => write!(
+                f,
+                "the NAND partition is read-only (check for a `read-only` device tree property)"
+            ),
+            Self::Locked => write!(
+                f,
+                "the NAND chip is write-protected (check the WP pin strapping, or try unlocking it)"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WriteProtection {}
+
+/// The rules a NAND imposes on the order in which a block's pages may be programmed
+#[derive(Debug, Copy, Clone)]
+pub enum WritePolicy {
+    /// Pages must be programmed in ascending order, but a block may be left partly programmed
+    SequentialOnly,
+
+    /// As with [WritePolicy::SequentialOnly], but pages also come in pairs sharing the same cells
+    /// (as in MLC NAND), and a lower page must never be left programmed without its upper page.
+    ///
+    /// `pairing` maps a page to the other page of its pair; an unpaired page maps to itself.
+    /// Writers comply by always programming blocks to the end. UBI writes its EC header page on
+    /// its own, so page 0 must be unpaired for a UBI partition.
+    PairedPages { pairing: fn(u32) -> u32 },
+}
+
+impl WritePolicy {
+    /// Must every program() run to the end of the block?
+    pub fn whole_blocks(&self) -> bool {
+        matches!(self, Self::PairedPages { .. })
+    }
+}
+
 /// Represents a NAND flash device
 pub trait Nand {
     type Block<'a>: NandBlock + 'a
@@ -63,6 +234,179 @@ pub trait Nand {
 
     /// Get the layout of the NAND
     fn get_layout(&self) -> NandLayout;
+
+    /// Determine whether (and why) the NAND can't be written
+    fn write_protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        Ok(None)
+    }
+
+    /// Get the rules for the order in which pages may be programmed
+    fn write_policy(&self) -> WritePolicy {
+        WritePolicy::SequentialOnly
+    }
+
+    /// How many bytes the NAND would rather be read at a time, if it has a preference.
+    ///
+    /// Code that reads blocks in chunks sizes them with [io_pages], so that a NAND with a high
+    /// cost per read (e.g. a syscall) can ask for fewer, larger ones.
+    fn preferred_io_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Erase the `count` blocks starting at `first_block`, skipping any marked bad.
+    ///
+    /// A NAND that can erase several blocks at once (e.g. with one syscall) overrides this; by
+    /// default, the blocks are erased one at a time. An error doesn't say which block failed, nor
+    /// how many were erased before it, so a caller that has to mark the failing block bad erases
+    /// the blocks one at a time to find it.
+    fn erase_range(&mut self, first_block: u32, count: u32) -> anyhow::Result<()> {
+        for index in first_block..first_block.saturating_add(count) {
+            if let Some(mut block) = self.block(index)? {
+                block.erase()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail with the [WriteProtection] as the error if the NAND can't be written.
+    ///
+    /// Destructive operations call this before touching anything, so that a write-protected NAND
+    /// isn't mistaken for a failing one (and its blocks marked bad).
+    fn ensure_writeable(&self) -> anyhow::Result<()> {
+        match self.write_protection()? {
+            Some(protection) => Err(protection.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// How many operations have been carried out on the NAND so far; all zeroes for a NAND that
+    /// doesn't count them
+    fn stats(&self) -> OpStats {
+        OpStats::default()
+    }
+}
+
+/// How many of each operation a NAND has carried out, as counted by [Nand::stats].
+///
+/// Every attempt counts, whether it succeeded or not, as a failed erase still wears the block.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct OpStats {
+    pub reads: u64,
+    pub programs: u64,
+    pub erases: u64,
+    pub bytes_read: u64,
+    pub bytes_programmed: u64,
+    pub mark_bads: u64,
+}
+
+impl OpStats {
+    /// What was counted since `earlier`, an earlier reading of the same counters
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            reads: self.reads.saturating_sub(earlier.reads),
+            programs: self.programs.saturating_sub(earlier.programs),
+            erases: self.erases.saturating_sub(earlier.erases),
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            bytes_programmed: self
+                .bytes_programmed
+                .saturating_sub(earlier.bytes_programmed),
+            mark_bads: self.mark_bads.saturating_sub(earlier.mark_bads),
+        }
+    }
+
+    /// Count an operation in `stats`
+    fn count(stats: &Cell<Self>, op: impl FnOnce(&mut Self)) {
+        let mut counted = stats.get();
+        op(&mut counted);
+        stats.set(counted);
+    }
+}
+
+impl Add for OpStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            reads: self.reads + other.reads,
+            programs: self.programs + other.programs,
+            erases: self.erases + other.erases,
+            bytes_read: self.bytes_read + other.bytes_read,
+            bytes_programmed: self.bytes_programmed + other.bytes_programmed,
+            mark_bads: self.mark_bads + other.mark_bads,
+        }
+    }
+}
+
+impl fmt::Display for OpStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} reads ({} bytes), {} programs ({} bytes), {} erases, {} marked bad",
+            self.reads,
+            self.bytes_read,
+            self.programs,
+            self.bytes_programmed,
+            self.erases,
+            self.mark_bads
+        )
+    }
+}
+
+/// Counters for [OpStats], shared between the handles of a device (see [SharedNand]), which may
+/// be on different threads
+#[derive(Debug, Default)]
+pub(crate) struct OpCounters {
+    reads: AtomicU64,
+    programs: AtomicU64,
+    erases: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_programmed: AtomicU64,
+    mark_bads: AtomicU64,
+}
+
+impl OpCounters {
+    pub fn read(&self, bytes: usize) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn program(&self, bytes: usize) {
+        self.programs.fetch_add(1, Ordering::Relaxed);
+        self.bytes_programmed
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn erase(&self, blocks: u32) {
+        self.erases.fetch_add(u64::from(blocks), Ordering::Relaxed);
+    }
+
+    pub fn mark_bad(&self) {
+        self.mark_bads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> OpStats {
+        OpStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            programs: self.programs.load(Ordering::Relaxed),
+            erases: self.erases.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_programmed: self.bytes_programmed.load(Ordering::Relaxed),
+            mark_bads: self.mark_bads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How many pages of `nand` to read at a time: its [Nand::preferred_io_size] in pages (at least
+/// one, and at most a block), or `default` if it has no preference
+pub fn io_pages<N: Nand>(nand: &N, default: u32) -> u32 {
+    let layout = nand.get_layout();
+    nand.preferred_io_size()
+        .map_or(default, |size| {
+            (size / layout.bytes_per_page)
+                .try_into()
+                .unwrap_or(u32::MAX)
+        })
+        .clamp(1, layout.pages_per_block.max(1))
 }
 
 /// Represents a block of a NAND flash device
@@ -91,13 +435,282 @@ pub trait NandBlock {
     /// This should be called if an erase() results in error, or if a (properly in-order) program()
     /// results in error and we have already tried erase() and reprogramming it.
     fn mark_bad(self) -> anyhow::Result<()>;
+
+    /// How many bytes of each page's OOB area are free to be used, past those taken up by ECC and
+    /// the bad block marker. 0 (the default) unless the NAND has been asked to let them be used,
+    /// and gets at them with [NandBlock::read_oob] and [NandBlock::program_oob].
+    fn oob_free(&self) -> usize {
+        0
+    }
+
+    /// Read the free bytes of a page's OOB area (see [NandBlock::oob_free]), from the first
+    fn read_oob(&self, _page: u32, _oob: &mut [u8]) -> anyhow::Result<()> {
+        anyhow::bail!("no access to the OOB area")
+    }
+
+    /// Program one page with `content`, and the start of its OOB area's free bytes with `oob`,
+    /// together: most NANDs can't have a page's OOB area programmed apart from its data
+    fn program_oob(&mut self, _page: u32, _content: &[u8], _oob: &[u8]) -> anyhow::Result<()> {
+        anyhow::bail!("no access to the OOB area")
+    }
+}
+
+/// A NAND flash device that can be opened more than once, so that it may be accessed from several
+/// threads at once
+pub trait SharedNand: Nand + Send + Sized {
+    /// Get another handle to the same device
+    fn clone_handle(&self) -> anyhow::Result<Self>;
+}
+
+/// A [Nand] that can be used as a trait object (`Box<dyn AnyNand>`), which a [Nand] can't, as the
+/// type of its blocks can't be named. Its blocks are reached by index instead.
+///
+/// Every [Nand] is an [AnyNand], and a `Box<dyn AnyNand>` is a [Nand] again, so whatever works with
+/// one NAND or another (e.g. as opened by [open_nand]) works with it.
+pub trait AnyNand {
+    /// See [Nand::get_layout]
+    fn layout(&self) -> NandLayout;
+
+    /// Is block `index` marked bad?
+    fn is_bad(&mut self, index: u32) -> anyhow::Result<bool>;
+
+    /// See [NandBlock::read]
+    fn read_pages(&mut self, index: u32, start_page: u32, content: &mut [u8])
+        -> anyhow::Result<()>;
+
+    /// See [NandBlock::program]
+    fn program_pages(&mut self, index: u32, start_page: u32, content: &[u8]) -> anyhow::Result<()>;
+
+    /// See [NandBlock::erase]
+    fn erase_block(&mut self, index: u32) -> anyhow::Result<()>;
+
+    /// See [NandBlock::mark_bad]
+    fn mark_block_bad(&mut self, index: u32) -> anyhow::Result<()>;
+
+    /// See [NandBlock::oob_free]; 0 for a bad block
+    fn free_oob(&mut self, index: u32) -> usize;
+
+    /// See [NandBlock::read_oob]
+    fn read_page_oob(&mut self, index: u32, page: u32, oob: &mut [u8]) -> anyhow::Result<()>;
+
+    /// See [NandBlock::program_oob]
+    fn program_page_oob(
+        &mut self,
+        index: u32,
+        page: u32,
+        content: &[u8],
+        oob: &[u8],
+    ) -> anyhow::Result<()>;
+
+    /// See [Nand::erase_range]
+    fn erase_blocks(&mut self, first_block: u32, count: u32) -> anyhow::Result<()>;
+
+    /// See [Nand::write_protection]
+    fn protection(&self) -> anyhow::Result<Option<WriteProtection>>;
+
+    /// See [Nand::write_policy]
+    fn policy(&self) -> WritePolicy;
+
+    /// See [Nand::preferred_io_size]
+    fn io_size(&self) -> Option<usize>;
+
+    /// See [Nand::stats]
+    fn op_stats(&self) -> OpStats;
+}
+
+impl<N: Nand> AnyNand for N {
+    fn layout(&self) -> NandLayout {
+        self.get_layout()
+    }
+
+    fn is_bad(&mut self, index: u32) -> anyhow::Result<bool> {
+        Ok(self.block(index)?.is_none())
+    }
+
+    fn read_pages(
+        &mut self,
+        index: u32,
+        start_page: u32,
+        content: &mut [u8],
+    ) -> anyhow::Result<()> {
+        good_block(self, index)?.read(start_page, content)
+    }
+
+    fn program_pages(&mut self, index: u32, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
+        good_block(self, index)?.program(start_page, content)
+    }
+
+    fn erase_block(&mut self, index: u32) -> anyhow::Result<()> {
+        good_block(self, index)?.erase()
+    }
+
+    fn mark_block_bad(&mut self, index: u32) -> anyhow::Result<()> {
+        good_block(self, index)?.mark_bad()
+    }
+
+    fn free_oob(&mut self, index: u32) -> usize {
+        self.block(index).ok().flatten().map_or(0, |x| x.oob_free())
+    }
+
+    fn read_page_oob(&mut self, index: u32, page: u32, oob: &mut [u8]) -> anyhow::Result<()> {
+        good_block(self, index)?.read_oob(page, oob)
+    }
+
+    fn program_page_oob(
+        &mut self,
+        index: u32,
+        page: u32,
+        content: &[u8],
+        oob: &[u8],
+    ) -> anyhow::Result<()> {
+        good_block(self, index)?.program_oob(page, content, oob)
+    }
+
+    fn erase_blocks(&mut self, first_block: u32, count: u32) -> anyhow::Result<()> {
+        self.erase_range(first_block, count)
+    }
+
+    fn protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        self.write_protection()
+    }
+
+    fn policy(&self) -> WritePolicy {
+        self.write_policy()
+    }
+
+    fn io_size(&self) -> Option<usize> {
+        self.preferred_io_size()
+    }
+
+    fn op_stats(&self) -> OpStats {
+        self.stats()
+    }
+}
+
+/// Get a block that mustn't be marked bad
+fn good_block<N: Nand>(nand: &mut N, index: u32) -> anyhow::Result<N::Block<'_>> {
+    nand.block(index)?
+        .ok_or(anyhow::anyhow!("block {index} is marked bad"))
+}
+
+// Each method goes through `**self`: the box is itself an [AnyNand], by way of this impl, and would
+// otherwise call back into it forever
+impl<'n> Nand for Box<dyn AnyNand + 'n> {
+    type Block<'a>
+        = AnyBlock<'a>
+    where
+        Self: 'a;
+
+    fn block(&mut self, index: u32) -> anyhow::Result<Option<AnyBlock<'_>>> {
+        if (**self).is_bad(index)? {
+            return Ok(None);
+        }
+
+        let layout = (**self).layout();
+        Ok(Some(AnyBlock {
+            nand: RefCell::new(&mut **self),
+            index,
+            layout,
+        }))
+    }
+
+    fn get_layout(&self) -> NandLayout {
+        (**self).layout()
+    }
+
+    fn write_protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        (**self).protection()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        (**self).policy()
+    }
+
+    fn preferred_io_size(&self) -> Option<usize> {
+        (**self).io_size()
+    }
+
+    fn erase_range(&mut self, first_block: u32, count: u32) -> anyhow::Result<()> {
+        (**self).erase_blocks(first_block, count)
+    }
+
+    fn stats(&self) -> OpStats {
+        (**self).op_stats()
+    }
+}
+
+/// A block of a `Box<dyn AnyNand>`
+pub struct AnyBlock<'a> {
+    // Reading a block takes `&self`, but reading an [AnyNand] takes `&mut`
+    nand: RefCell<&'a mut (dyn AnyNand + 'a)>,
+    index: u32,
+    layout: NandLayout,
+}
+
+impl NandBlock for AnyBlock<'_> {
+    fn page_count(&self) -> u32 {
+        self.layout.pages_per_block
+    }
+
+    fn page_size(&self) -> usize {
+        self.layout.bytes_per_page
+    }
+
+    fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
+        self.nand
+            .borrow_mut()
+            .read_pages(self.index, start_page, content)
+    }
+
+    fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
+        self.nand
+            .get_mut()
+            .program_pages(self.index, start_page, content)
+    }
+
+    fn erase(&mut self) -> anyhow::Result<()> {
+        self.nand.get_mut().erase_block(self.index)
+    }
+
+    fn mark_bad(self) -> anyhow::Result<()> {
+        self.nand.into_inner().mark_block_bad(self.index)
+    }
+
+    fn oob_free(&self) -> usize {
+        self.nand.borrow_mut().free_oob(self.index)
+    }
+
+    fn read_oob(&self, page: u32, oob: &mut [u8]) -> anyhow::Result<()> {
+        self.nand.borrow_mut().read_page_oob(self.index, page, oob)
+    }
+
+    fn program_oob(&mut self, page: u32, content: &[u8], oob: &[u8]) -> anyhow::Result<()> {
+        self.nand
+            .get_mut()
+            .program_page_oob(self.index, page, content, oob)
+    }
+}
+
+/// Open the NAND that `spec` describes (see [NandSpec]), e.g. `mtd-name:ubi` or
+/// `sim:?layout=1024x64x2048`
+pub fn open_nand(spec: &str) -> anyhow::Result<Box<dyn AnyNand>> {
+    spec.parse::<NandSpec>()?.open()
 }
 
 /// A simulated in-memory NAND flash, for testing purposes
-#[derive(Debug, Clone)]
+///
+/// The blocks are shared between all handles returned by [SharedNand::clone_handle], whereas
+/// `clone()` makes an independent copy. So are the [OpStats], which each block counts for itself.
+#[derive(Debug)]
 pub struct SimNand {
-    blocks: Box<[SimBlock]>,
+    blocks: Arc<[Mutex<SimBlock>]>,
     layout: NandLayout,
+    write_protection: Option<WriteProtection>,
+    write_policy: WritePolicy,
+    preferred_io_size: Option<usize>,
+
+    /// Every call to [Nand::erase_range], as the first block and the count
+    erase_ranges: Arc<Mutex<Vec<(u32, u32)>>>,
 }
 
 /// A block of SimNand
@@ -114,24 +727,143 @@ pub struct SimBlock {
 
     /// Is this block marked bad?
     marked_bad: bool,
+
+    /// Does erasing this block fail?
+    failing_erase: bool,
+
+    /// How many more programs of this block fail, [u32::MAX] being every one
+    failing_programs: u32,
+
+    /// How many pages a failing program writes before it fails
+    torn_pages: u32,
+
+    /// How many more reads of this block fail
+    failing_reads: Cell<u32>,
+
+    /// The free bytes of every page's OOB area, `oob_size` per page, or nothing for a NAND without
+    oob: Vec<u8>,
+    oob_size: usize,
+
+    /// The page pairing to enforce, if the NAND is simulating [WritePolicy::PairedPages]
+    pairing: Option<fn(u32) -> u32>,
+
+    /// How many pages have been programmed since the last erase, counting erased content (only
+    /// tracked when `pairing` is set)
+    programmed: u32,
+
+    /// The operations carried out on this block
+    stats: Cell<OpStats>,
 }
 
 impl SimNand {
     /// Create an empty SimNand with the specified layout
     pub fn new(layout: NandLayout) -> Self {
-        let blocks = vec![SimBlock::new(layout); layout.blocks as usize];
-        let blocks = blocks.into_boxed_slice();
+        let blocks = (0..layout.blocks)
+            .map(|_| Mutex::new(SimBlock::new(layout)))
+            .collect();
+
+        Self {
+            blocks,
+            layout,
+            write_protection: None,
+            write_policy: WritePolicy::SequentialOnly,
+            preferred_io_size: None,
+            erase_ranges: Default::default(),
+        }
+    }
+
+    /// Make the simulated NAND report itself as write-protected (or not)
+    pub fn set_write_protection(&mut self, protection: Option<WriteProtection>) {
+        self.write_protection = protection;
+    }
+
+    /// Make the simulated NAND report (and enforce) the given write policy
+    ///
+    /// Under [WritePolicy::PairedPages], a program() that leaves a lower page without its upper
+    /// page fails, as does programming a page again without erasing it, even with erased content.
+    pub fn set_write_policy(&mut self, policy: WritePolicy) -> anyhow::Result<()> {
+        let pairing = match policy {
+            WritePolicy::SequentialOnly => None,
+            WritePolicy::PairedPages { pairing } => Some(pairing),
+        };
+        for block in 0..self.layout.blocks {
+            self.lock_block(block)?.pairing = pairing;
+        }
+
+        self.write_policy = policy;
+        Ok(())
+    }
+
+    /// Make the simulated NAND report a [Nand::preferred_io_size]
+    pub fn set_preferred_io_size(&mut self, size: Option<usize>) {
+        self.preferred_io_size = size;
+    }
+
+    /// Make erasing a block fail (or not), as a block going bad would
+    pub fn set_erase_failure(&mut self, index: u32, failing: bool) -> anyhow::Result<()> {
+        self.lock_block(index)?.failing_erase = failing;
+        Ok(())
+    }
+
+    /// Make programming a block fail (or not), as a block going bad would
+    pub fn set_program_failure(&mut self, index: u32, failing: bool) -> anyhow::Result<()> {
+        self.set_program_failures(index, if failing { u32::MAX } else { 0 })
+    }
 
-        Self { blocks, layout }
+    /// Make the next `count` programs of a block fail, as a controller timing out would; with
+    /// [u32::MAX], every program does
+    pub fn set_program_failures(&mut self, index: u32, count: u32) -> anyhow::Result<()> {
+        self.lock_block(index)?.failing_programs = count;
+        Ok(())
+    }
+
+    /// Make the failing programs of a block write their first `pages` pages before failing, as a
+    /// program cut short would (by default, a failing program writes nothing)
+    pub fn set_torn_programs(&mut self, index: u32, pages: u32) -> anyhow::Result<()> {
+        self.lock_block(index)?.torn_pages = pages;
+        Ok(())
+    }
+
+    /// Make the next `count` reads of a block fail, as a flaky block would; with [u32::MAX], every
+    /// read does
+    pub fn set_read_failures(&mut self, index: u32, count: u32) -> anyhow::Result<()> {
+        self.lock_block(index)?.failing_reads.set(count);
+        Ok(())
+    }
+
+    /// Give every page `size` free bytes of OOB area (see [NandBlock::oob_free]), all erased; 0
+    /// (as a new SimNand has) takes them away again
+    pub fn set_oob_size(&mut self, size: usize) -> anyhow::Result<()> {
+        for block in 0..self.layout.blocks {
+            let mut block = self.lock_block(block)?;
+            block.oob = vec![0xFF; size * block.page_count as usize];
+            block.oob_size = size;
+        }
+        Ok(())
+    }
+
+    /// The ranges passed to [Nand::erase_range] so far, through this handle or any other, as the
+    /// first block and the count, forgetting them once taken
+    pub fn take_erase_ranges(&self) -> Vec<(u32, u32)> {
+        std::mem::take(&mut self.erase_ranges.lock().expect("SimNand poisoned"))
+    }
+
+    /// Lock one of the blocks, regardless of whether it's marked bad
+    fn lock_block(&self, index: u32) -> anyhow::Result<MutexGuard<'_, SimBlock>> {
+        self.blocks
+            .get(index as usize)
+            .ok_or(anyhow::anyhow!("block {index} out of range"))?
+            .lock()
+            .map_err(|_| anyhow::anyhow!("block {index} poisoned"))
     }
 
     /// Initialize the NAND contents with content read from a type implementing `Read`.
     pub fn load<R: Read>(&mut self, read: &mut R) -> anyhow::Result<()> {
-        let size = self.layout.bytes_per_page * self.layout.pages_per_block as usize;
+        let size = self.layout.block_bytes();
         let mut buf = vec![0; size];
 
         for block in 0..self.layout.blocks {
-            let mut block = &mut self.blocks[block as usize];
+            let mut block = self.lock_block(block)?;
             block.marked_bad = false;
             read.read_exact(&mut buf)?;
             block.program(0, &buf)?;
@@ -142,7 +874,7 @@ impl SimNand {
 
     /// Write the contents of this simulated NAND block out to a writable stream (such as a File)
     pub fn save<W: Write>(&mut self, write: &mut W) -> anyhow::Result<()> {
-        let size = self.layout.bytes_per_page * self.layout.pages_per_block as usize;
+        let size = self.layout.block_bytes();
         let mut buf = vec![0; size];
 
         for block in 0..self.layout.blocks {
@@ -166,6 +898,15 @@ impl SimBlock {
             page_count: layout.pages_per_block,
             page_size: layout.bytes_per_page,
             marked_bad: false,
+            failing_erase: false,
+            failing_programs: 0,
+            torn_pages: 0,
+            failing_reads: Cell::new(0),
+            oob: Vec::new(),
+            oob_size: 0,
+            pairing: None,
+            programmed: 0,
+            stats: Default::default(),
         }
     }
 
@@ -176,6 +917,10 @@ impl SimBlock {
         let begin = index as usize * self.page_size;
 
         ensure!(begin >= self.data.len(), "write in already-written area");
+        if self.pairing.is_some() {
+            ensure!(index >= self.programmed, "write in already-written area");
+            self.programmed = index + 1;
+        }
 
         // Writing fully-erased content is a no-op.
         if !content.is_erased() {
@@ -201,24 +946,108 @@ impl SimBlock {
 
         Ok(())
     }
+
+    /// Check that no lower page was left programmed without its upper page
+    fn check_pairing(&self) -> anyhow::Result<()> {
+        let Some(pairing) = self.pairing else {
+            return Ok(());
+        };
+
+        for page in 0..self.programmed {
+            let pair = pairing(page);
+            ensure!(
+                pair <= page || pair < self.programmed,
+                "lower page {page} programmed without its pair {pair}"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Clone for SimNand {
+    fn clone(&self) -> Self {
+        let blocks = (0..self.layout.blocks)
+            .map(|i| {
+                let block = self.lock_block(i).expect("SimNand poisoned").clone();
+                Mutex::new(SimBlock {
+                    stats: Default::default(),
+                    ..block
+                })
+            })
+            .collect();
+
+        Self {
+            blocks,
+            layout: self.layout,
+            write_protection: self.write_protection,
+            write_policy: self.write_policy,
+            preferred_io_size: self.preferred_io_size,
+            erase_ranges: Default::default(),
+        }
+    }
 }
 
 impl Nand for SimNand {
-    type Block<'a> = &'a mut SimBlock;
+    type Block<'a> = MutexGuard<'a, SimBlock>;
 
     fn block(&mut self, index: u32) -> anyhow::Result<Option<Self::Block<'_>>> {
-        self.blocks
-            .get_mut(index as usize)
-            .ok_or(anyhow::anyhow!("block {index} out of range"))
+        self.lock_block(index)
             .map(|x| Some(x).filter(|y| !y.marked_bad))
     }
 
     fn get_layout(&self) -> NandLayout {
         self.layout
     }
+
+    fn write_protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        Ok(self.write_protection)
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.write_policy
+    }
+
+    fn preferred_io_size(&self) -> Option<usize> {
+        self.preferred_io_size
+    }
+
+    fn erase_range(&mut self, first_block: u32, count: u32) -> anyhow::Result<()> {
+        self.erase_ranges
+            .lock()
+            .map_err(|_| anyhow::anyhow!("SimNand poisoned"))?
+            .push((first_block, count));
+
+        for index in first_block..first_block.saturating_add(count) {
+            if let Some(mut block) = self.block(index)? {
+                block.erase()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> OpStats {
+        self.blocks
+            .iter()
+            .map(|x| x.lock().expect("SimNand poisoned").stats.get())
+            .fold(OpStats::default(), Add::add)
+    }
 }
 
-impl NandBlock for &mut SimBlock {
+impl SharedNand for SimNand {
+    fn clone_handle(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            blocks: self.blocks.clone(),
+            layout: self.layout,
+            write_protection: self.write_protection,
+            write_policy: self.write_policy,
+            preferred_io_size: self.preferred_io_size,
+            erase_ranges: self.erase_ranges.clone(),
+        })
+    }
+}
+
+impl NandBlock for MutexGuard<'_, SimBlock> {
     fn page_count(&self) -> u32 {
         self.page_count
     }
@@ -227,34 +1056,235 @@ impl NandBlock for &mut SimBlock {
     }
 
     fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
-        let mut page = start_page;
-        for chunk in content.chunks_mut(self.page_size()) {
+        OpStats::count(&self.stats, |x| {
+            x.reads += 1;
+            x.bytes_read += content.len() as u64;
+        });
+        let failing = self.failing_reads.get();
+        if failing > 0 {
+            if failing != u32::MAX {
+                self.failing_reads.set(failing - 1);
+            }
+            anyhow::bail!("simulated read failure");
+        }
+
+        for (page, chunk) in (start_page..).zip(content.chunks_mut(self.page_size())) {
             self.read_page(page, chunk)?;
-            page += 1;
         }
         Ok(())
     }
 
     fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
-        let mut page = start_page;
-        for chunk in content.chunks(self.page_size()) {
+        OpStats::count(&self.stats, |x| {
+            x.programs += 1;
+            x.bytes_programmed += content.len() as u64;
+        });
+        let failing = self.failing_programs;
+        if failing > 0 {
+            if failing != u32::MAX {
+                self.failing_programs = failing - 1;
+            }
+            let chunks = content
+                .chunks(self.page_size())
+                .take(self.torn_pages as usize);
+            for (page, chunk) in (start_page..).zip(chunks) {
+                self.write_page(page, chunk)?;
+            }
+            anyhow::bail!("simulated program failure");
+        }
+
+        for (page, chunk) in (start_page..).zip(content.chunks(self.page_size())) {
             self.write_page(page, chunk)?;
-            page += 1;
         }
-        Ok(())
+        self.check_pairing()
     }
 
     fn erase(&mut self) -> anyhow::Result<()> {
+        OpStats::count(&self.stats, |x| x.erases += 1);
+        ensure!(!self.failing_erase, "simulated erase failure");
         self.data.clear();
+        self.oob.fill(0xFF);
+        self.programmed = 0;
 
         Ok(())
     }
 
     fn mark_bad(mut self) -> anyhow::Result<()> {
-        self.erase()?;
+        OpStats::count(&self.stats, |x| x.mark_bads += 1);
+        self.data.clear();
+        self.oob.fill(0xFF);
+        self.programmed = 0;
         self.marked_bad = true;
         Ok(())
     }
+
+    fn oob_free(&self) -> usize {
+        self.oob_size
+    }
+
+    fn read_oob(&self, page: u32, oob: &mut [u8]) -> anyhow::Result<()> {
+        ensure!(oob.len() <= self.oob_size, "OOB read past the free bytes");
+        ensure!(page < self.page_count, "page index out of bounds");
+        OpStats::count(&self.stats, |x| x.reads += 1);
+
+        let begin = page as usize * self.oob_size;
+        oob.copy_from_slice(&self.oob[begin..][..oob.len()]);
+        Ok(())
+    }
+
+    fn program_oob(&mut self, page: u32, content: &[u8], oob: &[u8]) -> anyhow::Result<()> {
+        ensure!(
+            oob.len() <= self.oob_size,
+            "OOB program past the free bytes"
+        );
+        ensure!(content.len() == self.page_size, "content not page-sized");
+        self.program(page, content)?;
+
+        let begin = page as usize * self.oob_size;
+        self.oob[begin..][..oob.len()].copy_from_slice(oob);
+        Ok(())
+    }
+}
+
+/// How many of each operation a [CountingNand] has seen
+#[cfg(test)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct OpCounts {
+    pub reads: u32,
+    pub programs: u32,
+    pub erases: u32,
+    pub bad_marks: u32,
+}
+
+#[cfg(test)]
+impl OpCounts {
+    /// How many attempts there were to modify the NAND
+    pub fn mutations(&self) -> u32 {
+        self.programs + self.erases + self.bad_marks
+    }
+}
+
+/// A wrapper for tests that counts every operation on the NAND
+#[cfg(test)]
+pub(crate) struct CountingNand<N> {
+    pub inner: N,
+    pub counts: std::cell::Cell<OpCounts>,
+
+    /// Fail to get any block once this many mutations have been counted
+    pub fail_after_mutations: Option<u32>,
+}
+
+#[cfg(test)]
+pub(crate) struct CountingBlock<'a, B> {
+    inner: B,
+    counts: &'a std::cell::Cell<OpCounts>,
+}
+
+#[cfg(test)]
+impl<N: Nand> CountingNand<N> {
+    pub fn new(inner: N) -> Self {
+        Self {
+            inner,
+            counts: Default::default(),
+            fail_after_mutations: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl<B> CountingBlock<'_, B> {
+    fn count(&self, op: impl FnOnce(&mut OpCounts)) {
+        let mut counts = self.counts.get();
+        op(&mut counts);
+        self.counts.set(counts);
+    }
+}
+
+#[cfg(test)]
+impl<N: Nand> Nand for CountingNand<N> {
+    type Block<'a>
+        = CountingBlock<'a, N::Block<'a>>
+    where
+        Self: 'a;
+
+    fn block(&mut self, index: u32) -> anyhow::Result<Option<Self::Block<'_>>> {
+        if let Some(limit) = self.fail_after_mutations {
+            ensure!(
+                self.counts.get().mutations() < limit,
+                "simulated failure getting block {index}"
+            );
+        }
+
+        let counts = &self.counts;
+        Ok(self
+            .inner
+            .block(index)?
+            .map(|inner| CountingBlock { inner, counts }))
+    }
+
+    fn get_layout(&self) -> NandLayout {
+        self.inner.get_layout()
+    }
+
+    fn write_protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        self.inner.write_protection()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.inner.write_policy()
+    }
+
+    fn preferred_io_size(&self) -> Option<usize> {
+        self.inner.preferred_io_size()
+    }
+
+    fn stats(&self) -> OpStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+impl<B: NandBlock> NandBlock for CountingBlock<'_, B> {
+    fn page_count(&self) -> u32 {
+        self.inner.page_count()
+    }
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
+        self.count(|x| x.reads += 1);
+        self.inner.read(start_page, content)
+    }
+
+    fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
+        self.count(|x| x.programs += 1);
+        self.inner.program(start_page, content)
+    }
+
+    fn erase(&mut self) -> anyhow::Result<()> {
+        self.count(|x| x.erases += 1);
+        self.inner.erase()
+    }
+
+    fn mark_bad(self) -> anyhow::Result<()> {
+        self.count(|x| x.bad_marks += 1);
+        self.inner.mark_bad()
+    }
+
+    fn oob_free(&self) -> usize {
+        self.inner.oob_free()
+    }
+
+    fn read_oob(&self, page: u32, oob: &mut [u8]) -> anyhow::Result<()> {
+        self.count(|x| x.reads += 1);
+        self.inner.read_oob(page, oob)
+    }
+
+    fn program_oob(&mut self, page: u32, content: &[u8], oob: &[u8]) -> anyhow::Result<()> {
+        self.count(|x| x.programs += 1);
+        self.inner.program_oob(page, content, oob)
+    }
 }
 
 #[cfg(test)]
@@ -264,6 +1294,50 @@ const TEST_LAYOUT: NandLayout = NandLayout {
     bytes_per_page: 256,
 };
 
+#[test]
+fn test_layout_from_str() {
+    let parse = |s: &str| s.parse::<NandLayout>().map(|x| x.to_string());
+
+    assert_eq!(parse("1024x64x2048").unwrap(), "1024x64x2048");
+    assert_eq!(parse("128MiB/128KiB/2KiB").unwrap(), "1024x64x2048");
+    assert_eq!(parse("128mib/131072/2048B").unwrap(), "1024x64x2048");
+    assert_eq!(parse("4GiB/256KiB/4KiB").unwrap(), "16384x64x4096");
+
+    for bad in [
+        "1024x64",
+        "128MiB/128KiB",
+        "128MiB/128KiB/3KiB",
+        "100KiB/128KiB/2KiB",
+        "128MiB/128KiB/0",
+        "128MB/128KiB/2KiB",
+        "-1/128KiB/2KiB",
+    ] {
+        assert!(bad.parse::<NandLayout>().is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn test_layout_display() {
+    let layout: NandLayout = "1024x64x2048".parse().unwrap();
+    assert_eq!(layout.block_bytes(), 128 << 10);
+    assert_eq!(layout.total_bytes(), 128 << 20);
+    assert_eq!(format!("{layout:#}"), "128MiB/128KiB/2KiB");
+
+    let odd = NandLayout {
+        blocks: 3,
+        pages_per_block: 5,
+        bytes_per_page: 100,
+    };
+    assert_eq!(format!("{odd:#}"), "1500B/500B/100B");
+    assert_eq!(
+        format!("{odd:#}")
+            .parse::<NandLayout>()
+            .unwrap()
+            .to_string(),
+        "3x5x100"
+    );
+}
+
 #[test]
 fn test_sim_block() {
     let mut nand = SimNand::new(TEST_LAYOUT);
@@ -301,16 +1375,154 @@ fn test_sim_read_write() {
     assert!(data_out.is_erased());
 }
 
+/// A pairing for tests: pages 4n+1 and 4n+3 share cells, and even pages are unpaired
+#[cfg(test)]
+pub(crate) fn test_pairing(page: u32) -> u32 {
+    match page % 4 {
+        1 => page + 2,
+        3 => page - 2,
+        _ => page,
+    }
+}
+
+#[test]
+fn test_sim_paired_pages() {
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.set_write_policy(WritePolicy::PairedPages {
+        pairing: test_pairing,
+    })
+    .unwrap();
+
+    let page = vec![0xA5u8; TEST_LAYOUT.bytes_per_page];
+    let mut block = nand.block(0).unwrap().unwrap();
+
+    // Page 0 is unpaired, but page 1 can't be left without page 3
+    block.program(0, &page).unwrap();
+    assert!(block.program(1, &page).is_err());
+
+    // Programming the pair together is fine, even if the upper page is erased
+    block.erase().unwrap();
+    let mut pair = page.repeat(3);
+    pair[2 * TEST_LAYOUT.bytes_per_page..].fill(0xFF);
+    block.program(1, &pair).unwrap();
+
+    // ...but the erased page can't be programmed again
+    assert!(block.program(3, &page).is_err());
+}
+
 #[test]
 fn test_sim_load() {
     let mut nand = SimNand::new(TEST_LAYOUT);
     nand.load(&mut std::io::repeat(0x55u8)).unwrap();
 
-    let mut buf =
-        vec![0u8; nand.get_layout().bytes_per_page * nand.get_layout().pages_per_block as usize];
+    let mut buf = vec![0u8; nand.get_layout().block_bytes()];
 
     let block = nand.block(0).unwrap().unwrap();
     block.read(0, &mut buf).unwrap();
 
     assert!(buf.iter().all(|&x| x == 0x55u8));
 }
+
+#[test]
+fn test_sim_clone_handle() {
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut handle = nand.clone_handle().unwrap();
+    let mut copy = nand.clone();
+
+    // Changes through one handle are visible through the other, but not in a clone
+    handle.block(0).unwrap().unwrap().mark_bad().unwrap();
+    assert!(nand.block(0).unwrap().is_none());
+    assert!(copy.block(0).unwrap().is_some());
+}
+
+#[test]
+fn test_sim_stats() -> anyhow::Result<()> {
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let page = vec![0xA5u8; TEST_LAYOUT.bytes_per_page];
+    let mut buf = vec![0u8; 2 * TEST_LAYOUT.bytes_per_page];
+
+    // Each call counts once, however many pages it covers; a failed one still counts
+    let mut block = nand.block(1)?.unwrap();
+    block.program(0, &page)?;
+    block.program(1, &page.repeat(2))?;
+    assert!(block.program(0, &page).is_err());
+    block.read(0, &mut buf)?;
+    block.erase()?;
+    drop(block);
+    nand.block(2)?.unwrap().mark_bad()?;
+    nand.set_erase_failure(3, true)?;
+    assert!(nand.erase_range(2, 3).is_err());
+
+    let expected = OpStats {
+        reads: 1,
+        programs: 3,
+        erases: 2,
+        bytes_read: 512,
+        bytes_programmed: 1024,
+        mark_bads: 1,
+    };
+    assert_eq!(nand.stats(), expected);
+    assert_eq!(
+        expected.to_string(),
+        "1 reads (512 bytes), 3 programs (1024 bytes), 2 erases, 1 marked bad"
+    );
+
+    // The counts are shared between handles, but a clone starts afresh
+    let mut handle = nand.clone_handle()?;
+    handle.block(4)?.unwrap().erase()?;
+    let stats = nand.stats();
+    assert_eq!(
+        stats.since(&expected),
+        OpStats {
+            erases: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(expected + stats.since(&expected), stats);
+    assert_eq!(nand.clone().stats(), OpStats::default());
+
+    Ok(())
+}
+
+#[test]
+fn test_any_nand() -> anyhow::Result<()> {
+    use crate::ubi::{self, VolumeSelector};
+
+    let layout: NandLayout = "64x16x256".parse()?;
+    let mut sim = SimNand::new(layout);
+    sim.set_preferred_io_size(Some(1024));
+    sim.block(7)?.unwrap().mark_bad()?;
+    let mut nand: Box<dyn AnyNand> = Box::new(sim);
+    assert_eq!(nand.get_layout(), layout);
+    assert_eq!(nand.preferred_io_size(), Some(1024));
+
+    // Bad blocks stay bad, and the rest behave as the NAND underneath does
+    assert!(nand.block(7)?.is_none());
+    let page = vec![0x5A; 256];
+    let mut block = nand.block(8)?.unwrap();
+    block.erase()?;
+    block.program(1, &page)?;
+    assert!(block.program(0, &page).is_err());
+    let mut data = vec![0; 512];
+    block.read(0, &mut data)?;
+    assert!(data[..256].is_erased() && data[256..] == page);
+    block.mark_bad()?;
+    assert!(nand.block(8)?.is_none());
+    assert_eq!(nand.stats().mark_bads, 2);
+
+    // Good enough for UBI
+    let mut ebt = ubi::scan_blocks(&mut nand)?;
+    ubi::format(&mut nand, &mut ebt)?;
+    let volume: Box<dyn ubi::ubinize::Volume> = Box::new(
+        ubi::ubinize::BasicVolume::new(ubi::VolType::Dynamic)
+            .name("data")
+            .size(8 << 10),
+    );
+    ubi::write_volumes(&mut nand, &mut ebt, vec![volume])?;
+    let selector = VolumeSelector::Name("data".into());
+    ubi::write_leb(&mut nand, &mut ebt, &selector, 0, b"hello")?;
+    let leb = ubi::read_volume_leb(&mut nand, &ebt, &selector, 0)?.unwrap();
+    assert_eq!(&leb[..5], b"hello");
+
+    Ok(())
+}
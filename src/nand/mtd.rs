@@ -1,34 +1,149 @@
 //! NAND abstraction layer implementation over the Linux MTD subsystem
 
-use super::{Nand, NandBlock, NandLayout};
+use super::{
+    Nand, NandBlock, NandLayout, OpCounters, OpStats, SharedNand, WritePolicy, WriteProtection,
+};
+use crate::error::InstallError;
 
 use anyhow::{bail, ensure};
+use nix::errno::Errno;
 
-use std::fs::File;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{File, TryLockError};
 use std::io::{BufRead, BufReader};
 use std::mem::MaybeUninit;
 use std::os::{fd::AsRawFd, unix::fs::FileExt};
 use std::path::Path;
+use std::sync::Arc;
 
 /// NAND flash that wraps an open /dev/mtdX file
 #[derive(Debug)]
 pub struct MtdNand {
     file: File,
     layout: NandLayout,
+
+    /// The `MTD_*` flags of the device, from MEMGETINFO and (where available) sysfs
+    flags: u32,
+
+    /// The number of OOB bytes per page; 0 for devices without any (e.g. mtdram)
+    oob_size: u32,
+
+    /// The number of those bytes left free by ECC, as sysfs says (0 if it doesn't)
+    oob_avail: u32,
+
+    /// Have the free OOB bytes been let out for use (see [MtdNand::enable_free_oob])?
+    free_oob: bool,
+
+    /// Blocks carrying a factory bad-block marker, as found by [MtdNand::scan_factory_bad_blocks]
+    factory_bad: BTreeSet<u32>,
+
+    /// Where the device is, as sysfs tells it
+    geometry: MtdGeometry,
+
+    /// The operations carried out through this handle and the others of the same device
+    stats: Arc<OpCounters>,
+}
+
+/// Where an MTD partition lies on its chip, as sysfs tells it; anything it doesn't say (as older
+/// kernels don't say where a partition starts) is None
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MtdGeometry {
+    /// The name the device tree gives the partition, e.g. `ubi`
+    pub name: Option<String>,
+
+    /// Where the partition starts, in bytes from the start of the chip
+    pub offset: Option<u64>,
+
+    /// How big the partition is, in bytes
+    pub size: Option<u64>,
+}
+
+impl MtdGeometry {
+    /// Where the partition ends (exclusive), if sysfs says both where it starts and how big it is
+    pub fn end(&self) -> Option<u64> {
+        Some(self.offset? + self.size?)
+    }
+}
+
+/// E.g. `"ubi" at 0x400000, 0x7c00000 bytes`, with `?` for anything unknown
+impl fmt::Display for MtdGeometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name:?}")?,
+            None => write!(f, "?")?,
+        }
+        match self.offset {
+            Some(offset) => write!(f, " at {offset:#x}")?,
+            None => write!(f, " at ?")?,
+        }
+        match self.size {
+            Some(size) => write!(f, ", {size:#x} bytes"),
+            None => write!(f, ", ? bytes"),
+        }
+    }
 }
 
 impl MtdNand {
-    /// Open an `mtd` device, by path (e.g. "/dev/mtd0")
+    /// Open an `mtd` device, by path (e.g. "/dev/mtd0").
+    ///
+    /// The device is `flock`ed for as long as it's open (exclusively, unless it could only be
+    /// opened read-only), so this fails if another installer instance has it open.
     pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let file = File::options().read(true).write(true).open(path)?;
-        let layout = unsafe {
+        let path = path.as_ref();
+
+        // A read-only partition can't be opened for writing, but we still want to be able to
+        // explain why it's read-only
+        let (file, writeable) = match File::options().read(true).write(true).open(path) {
+            Ok(file) => (file, true),
+            Err(_) => (File::open(path)?, false),
+        };
+        let locked = match writeable {
+            true => file.try_lock(),
+            false => file.try_lock_shared(),
+        };
+        match locked {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => bail!(
+                "another installer instance is running: {} is in use",
+                path.display()
+            ),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        let info = unsafe {
             let mut info = MaybeUninit::<ioctl::mtd_info_user>::uninit();
             ioctl::memgetinfo(file.as_raw_fd(), info.as_mut_ptr())?;
             info.assume_init()
+        };
+
+        // The sysfs attribute is only consulted to confirm that the device is writeable
+        let mut flags = info.flags;
+        if let Some(sysfs_flags) = path.file_name().and_then(sysfs_flags) {
+            flags &= sysfs_flags | !ioctl::MTD_WRITEABLE;
         }
-        .try_into()?;
+        let oob_avail = path.file_name().and_then(sysfs_oob_avail).unwrap_or(0);
+        let geometry = path.file_name().map(sysfs_geometry).unwrap_or_default();
+
+        let oob_size = info.oobsize;
+        let layout = info.try_into()?;
+
+        Ok(Self {
+            file,
+            layout,
+            flags,
+            oob_size,
+            oob_avail,
+            free_oob: false,
+            factory_bad: BTreeSet::new(),
+            geometry,
+            stats: Default::default(),
+        })
+    }
 
-        Ok(Self { file, layout })
+    /// Where the device lies on its chip, as sysfs tells it
+    pub fn geometry(&self) -> &MtdGeometry {
+        &self.geometry
     }
 
     /// Open an `mtd` device by its name, by searching `/proc/mtd`
@@ -46,7 +161,166 @@ impl MtdNand {
             }
         }
 
-        bail!("MTD device {name} could not be found");
+        Err(InstallError::MtdNotFound.msg(format!("MTD device {name} could not be found")))
+    }
+
+    /// Does the MTD subsystem allow writing to this device?
+    pub fn is_writeable(&self) -> bool {
+        self.flags & ioctl::MTD_WRITEABLE != 0
+    }
+
+    /// Try to disengage the chip's block protection, then check whether it worked
+    pub fn unlock(&mut self) -> anyhow::Result<Option<WriteProtection>> {
+        let erase_info = self.whole_device();
+        unsafe {
+            ioctl::memunlock(self.file.as_raw_fd(), &erase_info)?;
+        }
+
+        self.write_protection()
+    }
+
+    /// Let the free bytes of each page's OOB area be used (see [NandBlock::oob_free]), returning
+    /// how many there are: none if ECC takes up the whole area, or if the kernel doesn't say (as
+    /// it does in sysfs from Linux 4.16). Reading them back takes Linux 6.1's `MEMREAD`.
+    pub fn enable_free_oob(&mut self) -> usize {
+        self.free_oob = true;
+        self.oob_avail as usize
+    }
+
+    /// Look for factory bad-block markers in the OOB area of every block, so that blocks the
+    /// driver's bad block table doesn't know about yet are treated as bad all the same. Returns
+    /// the blocks that only the markers say are bad.
+    ///
+    /// Devices without OOB (like mtdram) have no markers to find; skip this when testing on one
+    /// whose OOB is emulated with garbage.
+    pub fn scan_factory_bad_blocks(&mut self) -> anyhow::Result<Vec<u32>> {
+        if self.oob_size == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut oob = vec![0u8; self.oob_size as usize];
+        let mut found = vec![];
+        for index in 0..self.layout.blocks {
+            let block_base = self.layout.block_bytes() as u64 * u64::from(index);
+            let mut marked = false;
+            for page in 0..FACTORY_MARKER_PAGES.min(self.layout.pages_per_block) {
+                let start = block_base + (self.layout.bytes_per_page as u64) * u64::from(page);
+                self.read_oob(start, &mut oob)?;
+                marked |= has_factory_bad_marker(&oob, self.layout.bytes_per_page);
+            }
+            if !marked {
+                continue;
+            }
+
+            self.factory_bad.insert(index);
+            if unsafe { ioctl::memgetbadblock(self.file.as_raw_fd(), &block_base)? } == 0 {
+                found.push(index);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Read the OOB area of the page at byte offset `start`
+    fn read_oob(&self, start: u64, oob: &mut [u8]) -> anyhow::Result<()> {
+        let mut request = ioctl::mtd_oob_buf64 {
+            start,
+            pad: 0,
+            length: oob.len() as u32,
+            usr_ptr: oob.as_mut_ptr() as u64,
+        };
+        unsafe {
+            ioctl::memreadoob64(self.file.as_raw_fd(), &mut request)?;
+        }
+        ensure!(
+            request.length as usize == oob.len(),
+            "short OOB read at offset {start:#x}"
+        );
+        Ok(())
+    }
+
+    /// Describe the whole device, for ioctls that take a range
+    fn whole_device(&self) -> ioctl::erase_info_user {
+        ioctl::erase_info_user {
+            start: 0,
+            length: self.layout.total_bytes() as u32,
+        }
+    }
+}
+
+/// How many bytes an [MtdNand] would rather be read at a time. Every read is a syscall, which costs
+/// more than reading a few pages past what a scan turns out to need; a scan still reads no more
+/// than the first few pages of a block that starts with a UBI header, though.
+const MTD_PREFERRED_IO_SIZE: usize = 64 << 10;
+
+/// How many pages at the start of each block the manufacturer may have marked bad
+const FACTORY_MARKER_PAGES: u32 = 2;
+
+/// Does a page's OOB area carry a factory bad-block marker? The marker is any value but 0xFF in
+/// the first OOB byte of a large-page chip, or the sixth of a small-page (512-byte) one.
+fn has_factory_bad_marker(oob: &[u8], page_size: usize) -> bool {
+    let position = if page_size > 512 { 0 } else { 5 };
+    oob.get(position).is_some_and(|&x| x != 0xFF)
+}
+
+/// Read an attribute of a device (e.g. "mtd3") from sysfs
+fn sysfs_attribute(dev_name: &OsStr, attribute: &str) -> Option<String> {
+    let path = Path::new("/sys/class/mtd").join(dev_name).join(attribute);
+    std::fs::read_to_string(path).ok()
+}
+
+/// Parse a numeric sysfs attribute: decimal, or hexadecimal with a `0x` prefix (as the MTD flags
+/// are), with the trailing newline
+fn parse_sysfs_number(text: &str) -> Option<u64> {
+    let text = text.trim();
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Read the `MTD_*` flags of a device from sysfs
+fn sysfs_flags(dev_name: &OsStr) -> Option<u32> {
+    parse_sysfs_number(&sysfs_attribute(dev_name, "flags")?)?
+        .try_into()
+        .ok()
+}
+
+/// Read how many OOB bytes per page of a device are free of ECC from sysfs
+fn sysfs_oob_avail(dev_name: &OsStr) -> Option<u32> {
+    parse_sysfs_number(&sysfs_attribute(dev_name, "oobavail")?)?
+        .try_into()
+        .ok()
+}
+
+/// Read the name, offset (from Linux 4.19) and size of a device from sysfs
+fn sysfs_geometry(dev_name: &OsStr) -> MtdGeometry {
+    let number = |attribute| parse_sysfs_number(&sysfs_attribute(dev_name, attribute)?);
+    MtdGeometry {
+        name: sysfs_attribute(dev_name, "name").map(|x| x.trim_end().to_string()),
+        offset: number("offset"),
+        size: number("size"),
+    }
+}
+
+/// Interpret what MEMISLOCKED returned. Not every driver implements locking; those that don't
+/// can't be locked, but any other error means the answer is unknown, not that it's unlocked.
+fn is_locked(result: nix::Result<i32>) -> anyhow::Result<bool> {
+    match result {
+        Ok(locked) => Ok(locked > 0),
+        Err(Errno::EOPNOTSUPP) => Ok(false),
+        Err(e) => Err(anyhow::Error::new(e).context("couldn't tell whether the device is locked")),
+    }
+}
+
+/// Work out why a device can't be written, from its flags and whether any part of it is locked
+fn write_protection(flags: u32, locked: bool) -> Option<WriteProtection> {
+    if flags & ioctl::MTD_WRITEABLE == 0 {
+        Some(WriteProtection::ReadOnly)
+    } else if locked {
+        Some(WriteProtection::Locked)
+    } else {
+        None
     }
 }
 
@@ -56,8 +330,11 @@ impl Nand for MtdNand {
     fn block(&mut self, index: u32) -> anyhow::Result<Option<MtdBlock<'_>>> {
         ensure!(index < self.layout.blocks, "block {index} out of range");
 
-        let block_size = self.layout.pages_per_block * self.layout.bytes_per_page as u32;
-        let block_base: u64 = (block_size * index) as u64;
+        if self.factory_bad.contains(&index) {
+            return Ok(None);
+        }
+
+        let block_base = self.layout.block_bytes() as u64 * u64::from(index);
         let bad = unsafe { ioctl::memgetbadblock(self.file.as_raw_fd(), &block_base)? };
         if bad == 0 {
             Ok(Some(MtdBlock { nand: self, index }))
@@ -69,6 +346,70 @@ impl Nand for MtdNand {
     fn get_layout(&self) -> NandLayout {
         self.layout
     }
+
+    fn write_protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        let mut erase_info = self.whole_device();
+        let locked = unsafe { ioctl::memislocked(self.file.as_raw_fd(), &mut erase_info) };
+        Ok(write_protection(self.flags, is_locked(locked)?))
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        // MEMGETINFO can say a device is MLC (`MTD_MLCNANDFLASH`), but not how its pages are
+        // paired, so every type is treated as sequential-only for now
+        WritePolicy::SequentialOnly
+    }
+
+    fn preferred_io_size(&self) -> Option<usize> {
+        Some(MTD_PREFERRED_IO_SIZE)
+    }
+
+    fn erase_range(&mut self, first_block: u32, count: u32) -> anyhow::Result<()> {
+        let end = first_block.saturating_add(count);
+        ensure!(end <= self.layout.blocks, "block {end} out of range");
+
+        // MEMERASE refuses a range holding a bad block, so erase the runs of good blocks between
+        // them, each with one ioctl
+        let block_bytes = self.layout.block_bytes() as u32;
+        let mut start = first_block;
+        for index in first_block..=end {
+            if index < end && self.block(index)?.is_some() {
+                continue;
+            }
+            if start < index {
+                let erase_info = ioctl::erase_info_user {
+                    start: block_bytes * start,
+                    length: block_bytes * (index - start),
+                };
+                self.stats.erase(index - start);
+                unsafe {
+                    ioctl::memerase(self.file.as_raw_fd(), &erase_info)?;
+                }
+            }
+            start = index + 1;
+        }
+
+        Ok(())
+    }
+
+    fn stats(&self) -> OpStats {
+        self.stats.get()
+    }
+}
+
+impl SharedNand for MtdNand {
+    fn clone_handle(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            layout: self.layout,
+            flags: self.flags,
+            oob_size: self.oob_size,
+            oob_avail: self.oob_avail,
+            free_oob: self.free_oob,
+            factory_bad: self.factory_bad.clone(),
+            geometry: self.geometry.clone(),
+            stats: self.stats.clone(),
+        })
+    }
 }
 
 pub struct MtdBlock<'a> {
@@ -79,7 +420,7 @@ pub struct MtdBlock<'a> {
 impl MtdBlock<'_> {
     /// Compute the number of bytes in this block
     fn size(&self) -> u32 {
-        self.nand.layout.pages_per_block * self.nand.layout.bytes_per_page as u32
+        self.nand.layout.block_bytes() as u32
     }
 
     /// Compute the offset of the first byte of this block
@@ -91,7 +432,7 @@ impl MtdBlock<'_> {
     /// offset for the page
     fn offset_for(&self, start_page: u32, bytes: usize) -> anyhow::Result<u64> {
         ensure!(
-            bytes % self.page_size() == 0,
+            bytes.is_multiple_of(self.page_size()),
             "buffer not multiple of page size"
         );
 
@@ -115,13 +456,16 @@ impl NandBlock for MtdBlock<'_> {
     }
     fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
         let offset = self.offset_for(start_page, content.len())?;
+        self.nand.stats.read(content.len());
         Ok(self.nand.file.read_exact_at(content, offset)?)
     }
     fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
         let offset = self.offset_for(start_page, content.len())?;
+        self.nand.stats.program(content.len());
         Ok(self.nand.file.write_all_at(content, offset)?)
     }
     fn erase(&mut self) -> anyhow::Result<()> {
+        self.nand.stats.erase(1);
         let erase_info = ioctl::erase_info_user {
             start: self.base(),
             length: self.size(),
@@ -132,12 +476,60 @@ impl NandBlock for MtdBlock<'_> {
         Ok(())
     }
     fn mark_bad(self) -> anyhow::Result<()> {
+        self.nand.stats.mark_bad();
         let block_base: u64 = self.base() as u64;
         unsafe {
             ioctl::memsetbadblock(self.nand.file.as_raw_fd(), &block_base)?;
         }
         Ok(())
     }
+    fn oob_free(&self) -> usize {
+        match self.nand.free_oob {
+            true => self.nand.oob_avail as usize,
+            false => 0,
+        }
+    }
+    fn read_oob(&self, page: u32, oob: &mut [u8]) -> anyhow::Result<()> {
+        ensure!(oob.len() <= self.oob_free(), "OOB read past the free bytes");
+        let offset = self.offset_for(page, self.page_size())?;
+        self.nand.stats.read(oob.len());
+        let mut request = ioctl::mtd_read_req {
+            start: offset,
+            len: 0,
+            ooblen: oob.len() as u64,
+            usr_data: 0,
+            usr_oob: oob.as_mut_ptr() as u64,
+            mode: ioctl::MTD_OPS_AUTO_OOB,
+            padding: [0; 7],
+            ecc_stats: Default::default(),
+        };
+        unsafe {
+            ioctl::memread(self.nand.file.as_raw_fd(), &mut request)?;
+        }
+        Ok(())
+    }
+    fn program_oob(&mut self, page: u32, content: &[u8], oob: &[u8]) -> anyhow::Result<()> {
+        ensure!(
+            oob.len() <= self.oob_free(),
+            "OOB program past the free bytes"
+        );
+        ensure!(content.len() == self.page_size(), "content not page-sized");
+        let offset = self.offset_for(page, content.len())?;
+        self.nand.stats.program(content.len());
+        let mut request = ioctl::mtd_write_req {
+            start: offset,
+            len: content.len() as u64,
+            ooblen: oob.len() as u64,
+            usr_data: content.as_ptr() as u64,
+            usr_oob: oob.as_ptr() as u64,
+            mode: ioctl::MTD_OPS_AUTO_OOB,
+            padding: [0; 7],
+        };
+        unsafe {
+            ioctl::memwrite(self.nand.file.as_raw_fd(), &mut request)?;
+        }
+        Ok(())
+    }
 }
 
 mod ioctl {
@@ -146,10 +538,13 @@ mod ioctl {
     use super::NandLayout;
 
     use anyhow::ensure;
-    use nix::{ioctl_read, ioctl_write_ptr};
+    use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
 
     const MTD_IOC_MAGIC: u8 = b'M';
 
+    /// The `flags` bit indicating that the device may be written
+    pub const MTD_WRITEABLE: u32 = 0x400;
+
     #[repr(C)]
     pub struct mtd_info_user {
         pub r#type: u8,
@@ -172,11 +567,11 @@ mod ioctl {
             }
 
             ensure!(
-                self.size % self.erasesize == 0,
+                self.size.is_multiple_of(self.erasesize),
                 "MTD size not multiple of erasesize"
             );
             ensure!(
-                self.erasesize % self.writesize == 0,
+                self.erasesize.is_multiple_of(self.writesize),
                 "MTD erasesize not multiple of writesize"
             );
 
@@ -198,7 +593,181 @@ mod ioctl {
         pub length: u32,
     }
     ioctl_write_ptr!(memerase, MTD_IOC_MAGIC, 2, erase_info_user);
+    ioctl_write_ptr!(memunlock, MTD_IOC_MAGIC, 6, erase_info_user);
+    // The kernel declares this one as `_IOR`, even though it reads the range it's given
+    ioctl_read!(memislocked, MTD_IOC_MAGIC, 23, erase_info_user);
 
     ioctl_write_ptr!(memgetbadblock, MTD_IOC_MAGIC, 11, u64);
     ioctl_write_ptr!(memsetbadblock, MTD_IOC_MAGIC, 12, u64);
+
+    #[repr(C)]
+    pub struct mtd_oob_buf64 {
+        pub start: u64,
+        pub pad: u32,
+        pub length: u32,
+        pub usr_ptr: u64,
+    }
+    ioctl_readwrite!(memreadoob64, MTD_IOC_MAGIC, 22, mtd_oob_buf64);
+
+    /// The `mode` of a read or write request that puts OOB data in the bytes ECC leaves free
+    pub const MTD_OPS_AUTO_OOB: u8 = 1;
+
+    #[repr(C)]
+    pub struct mtd_write_req {
+        pub start: u64,
+        pub len: u64,
+        pub ooblen: u64,
+        pub usr_data: u64,
+        pub usr_oob: u64,
+        pub mode: u8,
+        pub padding: [u8; 7],
+    }
+    ioctl_readwrite!(memwrite, MTD_IOC_MAGIC, 24, mtd_write_req);
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct mtd_read_req_ecc_stats {
+        pub uncorrectable_errors: u32,
+        pub corrected_bitflips: u32,
+        pub max_bitflips: u32,
+    }
+
+    #[repr(C)]
+    pub struct mtd_read_req {
+        pub start: u64,
+        pub len: u64,
+        pub ooblen: u64,
+        pub usr_data: u64,
+        pub usr_oob: u64,
+        pub mode: u8,
+        pub padding: [u8; 7],
+        pub ecc_stats: mtd_read_req_ecc_stats,
+    }
+    ioctl_readwrite!(memread, MTD_IOC_MAGIC, 26, mtd_read_req);
+}
+
+#[test]
+fn test_write_protection() {
+    const MTD_CAP_NANDFLASH: u32 = ioctl::MTD_WRITEABLE;
+
+    assert_eq!(write_protection(MTD_CAP_NANDFLASH, false), None);
+    assert_eq!(
+        write_protection(MTD_CAP_NANDFLASH, true),
+        Some(WriteProtection::Locked)
+    );
+
+    // A read-only partition is reported as such, whether or not the chip is also locked
+    for locked in [false, true] {
+        assert_eq!(write_protection(0, locked), Some(WriteProtection::ReadOnly));
+    }
+}
+
+#[test]
+fn test_is_locked() {
+    assert!(!is_locked(Ok(0)).unwrap());
+    assert!(is_locked(Ok(1)).unwrap());
+    assert!(!is_locked(Err(Errno::EOPNOTSUPP)).unwrap());
+
+    // A failure to ask isn't an answer
+    for errno in [Errno::ENOTTY, Errno::EINVAL, Errno::EIO] {
+        assert!(is_locked(Err(errno)).is_err(), "{errno}");
+    }
+}
+
+#[test]
+fn test_parse_sysfs_number() {
+    for (text, number) in [
+        ("4194304\n", Some(4194304)),
+        ("0\n", Some(0)),
+        ("0x800\n", Some(0x800)),
+        ("0x400c00\n", Some(0x400c00)),
+        ("  12  ", Some(12)),
+        ("\n", None),
+        ("-1\n", None),
+        ("0x\n", None),
+        ("0xZZ\n", None),
+        ("ubi\n", None),
+    ] {
+        assert_eq!(parse_sysfs_number(text), number, "{text:?}");
+    }
+
+    let geometry = MtdGeometry {
+        name: Some("ubi".into()),
+        offset: Some(0x400000),
+        size: Some(0x7c00000),
+    };
+    assert_eq!(geometry.end(), Some(0x8000000));
+    assert_eq!(geometry.to_string(), "\"ubi\" at 0x400000, 0x7c00000 bytes");
+    let unknown = MtdGeometry::default();
+    assert_eq!(unknown.end(), None);
+    assert_eq!(unknown.to_string(), "? at ?, ? bytes");
+}
+
+#[test]
+fn test_factory_bad_marker() {
+    let mut oob = [0xFFu8; 64];
+    assert!(!has_factory_bad_marker(&oob, 2048));
+    assert!(!has_factory_bad_marker(&oob, 512));
+
+    // Large-page chips are marked in the first byte, small-page ones in the sixth
+    oob[0] = 0x00;
+    assert!(has_factory_bad_marker(&oob, 2048));
+    assert!(!has_factory_bad_marker(&oob, 512));
+    oob[0] = 0xFF;
+    oob[5] = 0xF0;
+    assert!(!has_factory_bad_marker(&oob, 4096));
+    assert!(has_factory_bad_marker(&oob, 512));
+
+    // No OOB, no marker
+    assert!(!has_factory_bad_marker(&[], 2048));
+}
+
+/// Check chunked reads against an mtdram device (e.g. after `modprobe mtdram total_size=1024
+/// erase_size=128`), given as `BMC_TEST_MTD=/dev/mtdX`. The device's first block is overwritten.
+#[test]
+#[ignore]
+fn test_mtdram_chunked_read() -> anyhow::Result<()> {
+    let mut nand = MtdNand::open(std::env::var("BMC_TEST_MTD")?)?;
+    let layout = nand.get_layout();
+    let chunk_pages = super::io_pages(&nand, 1);
+    assert!(chunk_pages > 1 || layout.pages_per_block == 1);
+
+    let data: Vec<u8> = (0..layout.block_bytes()).map(|x| (x % 251) as u8).collect();
+    let mut block = nand.block(0)?.ok_or(anyhow::anyhow!("block 0 is bad"))?;
+    block.erase()?;
+    block.program(0, &data)?;
+
+    // Reading in chunks of the preferred size gives the same bytes as reading page by page
+    let mut chunked = vec![0; data.len()];
+    for (start_page, chunk) in (0..)
+        .step_by(chunk_pages as usize)
+        .zip(chunked.chunks_mut(chunk_pages as usize * layout.bytes_per_page))
+    {
+        block.read(start_page, chunk)?;
+    }
+    let mut paged = vec![0; data.len()];
+    for (page, buf) in (0..).zip(paged.chunks_mut(layout.bytes_per_page)) {
+        block.read(page, buf)?;
+    }
+    assert!(chunked == data && paged == data);
+
+    Ok(())
+}
+
+/// Check that an mtdram device (see [test_mtdram_chunked_read]) can't be opened twice at once, and
+/// can be once the first is closed
+#[test]
+#[ignore]
+fn test_mtdram_lock() -> anyhow::Result<()> {
+    let path = std::env::var("BMC_TEST_MTD")?;
+    let nand = MtdNand::open(&path)?;
+    let error = MtdNand::open(&path).unwrap_err();
+    assert!(error.to_string().contains("another installer instance"));
+
+    // Handles cloned from the one open device share its lock
+    let clone = nand.clone_handle()?;
+    drop((nand, clone));
+    MtdNand::open(&path)?;
+
+    Ok(())
 }
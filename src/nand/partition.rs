@@ -0,0 +1,233 @@
+//! A NAND adapter that restricts access to a range of blocks, for devices that aren't partitioned
+//! by the kernel
+
+use super::{
+    Nand, NandBlock, NandLayout, OpCounters, OpStats, SharedNand, WritePolicy, WriteProtection,
+};
+
+use anyhow::ensure;
+
+use std::sync::Arc;
+
+/// A view of a contiguous range of blocks of another NAND, addressed from 0.
+///
+/// Its [Nand::stats] count what's done through the view (and its other handles) alone, rather
+/// than the whole of the other NAND, which may have other partitions.
+#[derive(Debug)]
+pub struct PartitionNand<N> {
+    inner: N,
+    first_block: u32,
+    layout: NandLayout,
+    stats: Arc<OpCounters>,
+}
+
+/// A block of a [PartitionNand]
+pub struct PartitionBlock<'a, B> {
+    inner: B,
+    stats: &'a OpCounters,
+}
+
+impl<N: Nand> PartitionNand<N> {
+    /// Create a view of `block_count` blocks of `inner`, starting at `first_block`
+    pub fn new(inner: N, first_block: u32, block_count: u32) -> anyhow::Result<Self> {
+        let layout = inner.get_layout();
+        ensure!(
+            first_block
+                .checked_add(block_count)
+                .is_some_and(|end| end <= layout.blocks),
+            "partition of blocks {first_block}+{block_count} doesn't fit in {} blocks",
+            layout.blocks
+        );
+
+        Ok(Self {
+            inner,
+            first_block,
+            layout: NandLayout {
+                blocks: block_count,
+                ..layout
+            },
+            stats: Default::default(),
+        })
+    }
+
+    /// Create a view of the whole of `inner`
+    pub fn whole(inner: N) -> Self {
+        let layout = inner.get_layout();
+        Self {
+            inner,
+            first_block: 0,
+            layout,
+            stats: Default::default(),
+        }
+    }
+
+    /// Give back the underlying NAND
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+}
+
+impl<N: Nand> Nand for PartitionNand<N> {
+    type Block<'a>
+        = PartitionBlock<'a, N::Block<'a>>
+    where
+        Self: 'a;
+
+    fn block(&mut self, index: u32) -> anyhow::Result<Option<Self::Block<'_>>> {
+        ensure!(index < self.layout.blocks, "block {index} out of range");
+        let stats = &*self.stats;
+        Ok(self
+            .inner
+            .block(self.first_block + index)?
+            .map(|inner| PartitionBlock { inner, stats }))
+    }
+
+    fn get_layout(&self) -> NandLayout {
+        self.layout
+    }
+
+    fn write_protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        self.inner.write_protection()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.inner.write_policy()
+    }
+
+    fn preferred_io_size(&self) -> Option<usize> {
+        self.inner.preferred_io_size()
+    }
+
+    fn erase_range(&mut self, first_block: u32, count: u32) -> anyhow::Result<()> {
+        ensure!(
+            first_block
+                .checked_add(count)
+                .is_some_and(|end| end <= self.layout.blocks),
+            "blocks {first_block}+{count} out of range"
+        );
+
+        // Only the blocks that aren't bad are erased, and counted
+        let first_block = self.first_block + first_block;
+        let mut good = 0;
+        for index in first_block..first_block + count {
+            if self.inner.block(index)?.is_some() {
+                good += 1;
+            }
+        }
+        self.stats.erase(good);
+
+        self.inner.erase_range(first_block, count)
+    }
+
+    fn stats(&self) -> OpStats {
+        self.stats.get()
+    }
+}
+
+impl<B: NandBlock> NandBlock for PartitionBlock<'_, B> {
+    fn page_count(&self) -> u32 {
+        self.inner.page_count()
+    }
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
+        self.stats.read(content.len());
+        self.inner.read(start_page, content)
+    }
+
+    fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
+        self.stats.program(content.len());
+        self.inner.program(start_page, content)
+    }
+
+    fn erase(&mut self) -> anyhow::Result<()> {
+        self.stats.erase(1);
+        self.inner.erase()
+    }
+
+    fn mark_bad(self) -> anyhow::Result<()> {
+        self.stats.mark_bad();
+        self.inner.mark_bad()
+    }
+
+    fn oob_free(&self) -> usize {
+        self.inner.oob_free()
+    }
+
+    fn read_oob(&self, page: u32, oob: &mut [u8]) -> anyhow::Result<()> {
+        self.stats.read(oob.len());
+        self.inner.read_oob(page, oob)
+    }
+
+    fn program_oob(&mut self, page: u32, content: &[u8], oob: &[u8]) -> anyhow::Result<()> {
+        self.stats.program(content.len());
+        self.inner.program_oob(page, content, oob)
+    }
+}
+
+impl<N: SharedNand> SharedNand for PartitionNand<N> {
+    fn clone_handle(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner: self.inner.clone_handle()?,
+            first_block: self.first_block,
+            layout: self.layout,
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+#[test]
+fn test_partition() -> anyhow::Result<()> {
+    use super::{NandBlock, PageUtil, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 4,
+        bytes_per_page: 128,
+    };
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    assert!(PartitionNand::new(nand.clone_handle()?, 10, 7).is_err());
+    assert!(PartitionNand::new(nand.clone_handle()?, 1, u32::MAX).is_err());
+
+    let mut part = PartitionNand::new(nand.clone_handle()?, 10, 4)?;
+    assert_eq!(part.get_layout().blocks, 4);
+    assert!(part.block(4).is_err());
+
+    // Writes and bad-block marks land at the offset blocks of the underlying NAND
+    part.block(0)?.unwrap().program(0, &[0x5A; 128])?;
+    part.block(3)?.unwrap().mark_bad()?;
+    assert!(part.block(3)?.is_none());
+
+    // So do erased ranges
+    part.block(1)?.unwrap().program(0, &[0x5A; 128])?;
+    assert!(part.erase_range(2, 3).is_err());
+    part.erase_range(1, 3)?;
+    assert_eq!(nand.take_erase_ranges(), [(11, 3)]);
+
+    // The partition counts only what was done through it, leaving out the bad block it skipped
+    let other = PartitionNand::new(nand.clone_handle()?, 0, 10)?;
+    let stats = part.stats();
+    assert_eq!((stats.programs, stats.erases, stats.mark_bads), (2, 2, 1));
+    assert_eq!(part.clone_handle()?.stats(), stats);
+    assert_eq!(other.stats(), OpStats::default());
+
+    let mut page = [0; 128];
+    for block in 0..TEST_LAYOUT.blocks {
+        match block {
+            10 => {
+                nand.block(block)?.unwrap().read(0, &mut page)?;
+                assert_eq!(page, [0x5A; 128]);
+            }
+            13 => assert!(nand.block(block)?.is_none()),
+            _ => {
+                nand.block(block)?.unwrap().read(0, &mut page)?;
+                assert!(page.is_erased());
+            }
+        }
+    }
+
+    Ok(())
+}
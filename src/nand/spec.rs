@@ -0,0 +1,304 @@
+//! Strings naming a NAND to open, so that every binary can be pointed at the same kinds of NAND the
+//! same way. A spec is a scheme, a colon, a path or name, and perhaps a `?layout=` query:
+//!
+//! - `mtd-name:ubi`: the MTD device or partition with that name;
+//! - `mtd:/dev/mtd1`: an `mtd` device, by path;
+//! - `sim:/path.img?layout=1024x64x2048`: a [SimNand](super::SimNand) loaded from an image, or an
+//!   erased one if there's no path (`sim:?layout=...`);
+//! - `file:/path.fnand`: a [FileNand](super::file::FileNand), created with the given layout if it
+//!   doesn't exist yet;
+//! - `blockdev:/dev/mmcblk0?layout=...`: a [BlockDevNand](super::blockdev::BlockDevNand).
+//!
+//! Layouts are written as [NandLayout] parses them.
+
+use super::{AnyNand, NandLayout};
+
+use anyhow::{anyhow, bail};
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The schemes a spec may start with, for error messages
+const SCHEMES: &str = "mtd-name, mtd, sim, file or blockdev";
+
+/// Which NAND to open, as parsed from a spec (see the [module documentation](self))
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NandSpec {
+    /// An MTD device or partition, by name
+    MtdName(String),
+
+    /// An `mtd` device, by path
+    MtdDev(PathBuf),
+
+    /// A simulated NAND, loaded from an image if there's a path
+    Sim {
+        path: Option<PathBuf>,
+        layout: NandLayout,
+    },
+
+    /// A NAND file, which needs a layout only if it's to be created
+    File {
+        path: PathBuf,
+        layout: Option<NandLayout>,
+    },
+
+    /// A block device (or file) treated as NAND
+    BlockDev { path: PathBuf, layout: NandLayout },
+}
+
+impl NandSpec {
+    /// Is this the real thing, rather than a stand-in for it?
+    pub fn is_mtd(&self) -> bool {
+        matches!(self, Self::MtdName(_) | Self::MtdDev(_))
+    }
+
+    /// Open the NAND, whatever it is
+    pub fn open(&self) -> anyhow::Result<Box<dyn AnyNand>> {
+        match self {
+            #[cfg(feature = "linux-hw")]
+            Self::MtdName(_) | Self::MtdDev(_) => Ok(Box::new(self.open_mtd()?)),
+
+            #[cfg(not(feature = "linux-hw"))]
+            Self::MtdName(_) | Self::MtdDev(_) => {
+                bail!("{self}: MTD devices aren't supported in this build")
+            }
+
+            Self::Sim { path, layout } => {
+                let mut sim = super::SimNand::new(*layout);
+                if let Some(path) = path {
+                    sim.load(&mut std::fs::File::open(path)?)?;
+                }
+                Ok(Box::new(sim))
+            }
+
+            #[cfg(unix)]
+            Self::File { path, layout } => Ok(Box::new(super::file::FileNand::open_or_create(
+                path, *layout,
+            )?)),
+
+            #[cfg(unix)]
+            Self::BlockDev { path, layout } => Ok(Box::new(super::blockdev::BlockDevNand::open(
+                path, *layout,
+            )?)),
+
+            #[cfg(not(unix))]
+            Self::File { .. } | Self::BlockDev { .. } => {
+                bail!("{self}: not supported on this platform")
+            }
+        }
+    }
+
+    /// Open the MTD device this names, failing if it names something else
+    #[cfg(feature = "linux-hw")]
+    pub fn open_mtd(&self) -> anyhow::Result<super::mtd::MtdNand> {
+        use super::mtd::MtdNand;
+
+        match self {
+            Self::MtdName(name) => MtdNand::open_named(name),
+            Self::MtdDev(path) => MtdNand::open(path),
+            _ => bail!("{self} isn't an MTD device"),
+        }
+    }
+}
+
+impl FromStr for NandSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((scheme, rest)) = s.split_once(':') else {
+            bail!("NAND spec {s:?} has no scheme (expected {SCHEMES}, e.g. mtd-name:ubi)");
+        };
+        let (target, query) = match rest.split_once('?') {
+            Some((target, query)) => (target, Some(query)),
+            None => (rest, None),
+        };
+
+        let mut layout = None;
+        for param in query.into_iter().flat_map(|x| x.split('&')) {
+            match param.split_once('=') {
+                Some(("layout", value)) => {
+                    let parsed = value
+                        .parse::<NandLayout>()
+                        .map_err(|e| e.context(format!("NAND spec {s:?}: bad layout")))?;
+                    layout = Some(parsed);
+                }
+                _ => bail!("NAND spec {s:?}: unknown parameter {param:?} (expected layout=...)"),
+            }
+        }
+
+        let need_target = |what: &str| match target {
+            "" => Err(anyhow!("NAND spec {s:?} is missing {what}")),
+            _ => Ok(target),
+        };
+        let need_layout = || {
+            layout.ok_or(anyhow!(
+                "NAND spec {s:?} needs a layout, e.g. {s}?layout=1024x64x2048"
+            ))
+        };
+        let no_layout = || match layout {
+            Some(_) => Err(anyhow!("NAND spec {s:?}: an MTD device has its own layout")),
+            None => Ok(()),
+        };
+
+        match scheme {
+            "mtd-name" => {
+                no_layout()?;
+                Ok(Self::MtdName(need_target("a name")?.into()))
+            }
+            "mtd" => {
+                no_layout()?;
+                Ok(Self::MtdDev(need_target("a path")?.into()))
+            }
+            "sim" => Ok(Self::Sim {
+                path: (!target.is_empty()).then(|| target.into()),
+                layout: need_layout()?,
+            }),
+            "file" => Ok(Self::File {
+                path: need_target("a path")?.into(),
+                layout,
+            }),
+            "blockdev" => Ok(Self::BlockDev {
+                path: need_target("a path")?.into(),
+                layout: need_layout()?,
+            }),
+            other => bail!("unknown NAND spec scheme {other:?} in {s:?} (expected {SCHEMES})"),
+        }
+    }
+}
+
+impl fmt::Display for NandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MtdName(name) => write!(f, "mtd-name:{name}"),
+            Self::MtdDev(path) => write!(f, "mtd:{}", path.display()),
+            Self::Sim { path, layout } => {
+                let path = path.as_ref().map(|x| x.display().to_string());
+                write!(f, "sim:{}?layout={layout}", path.unwrap_or_default())
+            }
+            Self::File { path, layout } => {
+                write!(f, "file:{}", path.display())?;
+                match layout {
+                    Some(layout) => write!(f, "?layout={layout}"),
+                    None => Ok(()),
+                }
+            }
+            Self::BlockDev { path, layout } => {
+                write!(f, "blockdev:{}?layout={layout}", path.display())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_nand_spec() -> anyhow::Result<()> {
+    let layout = NandLayout {
+        blocks: 1024,
+        pages_per_block: 64,
+        bytes_per_page: 2048,
+    };
+
+    for (text, spec) in [
+        ("mtd-name:ubi", NandSpec::MtdName("ubi".into())),
+        ("mtd:/dev/mtd1", NandSpec::MtdDev("/dev/mtd1".into())),
+        (
+            "sim:/tmp/nand.img?layout=1024x64x2048",
+            NandSpec::Sim {
+                path: Some("/tmp/nand.img".into()),
+                layout,
+            },
+        ),
+        (
+            "sim:?layout=1024x64x2048",
+            NandSpec::Sim { path: None, layout },
+        ),
+        (
+            "file:/tmp/nand.fnand",
+            NandSpec::File {
+                path: "/tmp/nand.fnand".into(),
+                layout: None,
+            },
+        ),
+        (
+            "file:/tmp/nand.fnand?layout=1024x64x2048",
+            NandSpec::File {
+                path: "/tmp/nand.fnand".into(),
+                layout: Some(layout),
+            },
+        ),
+        (
+            "blockdev:/dev/mmcblk0?layout=1024x64x2048",
+            NandSpec::BlockDev {
+                path: "/dev/mmcblk0".into(),
+                layout,
+            },
+        ),
+    ] {
+        assert_eq!(text.parse::<NandSpec>()?, spec, "{text}");
+        assert_eq!(spec.to_string().parse::<NandSpec>()?, spec, "{text}");
+    }
+    assert!(NandSpec::MtdName("boot".into()).is_mtd());
+    assert!(!NandSpec::Sim { path: None, layout }.is_mtd());
+
+    // The layout can be given in sizes, too
+    let spec: NandSpec = "sim:?layout=128MiB/128KiB/2KiB".parse()?;
+    assert_eq!(
+        spec,
+        NandSpec::Sim {
+            path: None,
+            layout: "1024x64x2048".parse()?
+        }
+    );
+
+    // Bad specs say what's wrong with them
+    for (text, error) in [
+        ("/dev/mtd1", "has no scheme"),
+        ("nand:/dev/mtd1", "unknown NAND spec scheme \"nand\""),
+        ("mtd-name:", "missing a name"),
+        ("mtd:", "missing a path"),
+        ("file:?layout=1024x64x2048", "missing a path"),
+        ("sim:/tmp/nand.img", "needs a layout"),
+        ("blockdev:/dev/mmcblk0", "needs a layout"),
+        ("sim:?layout=lots", "bad layout"),
+        (
+            "sim:?layout=1024x64x2048&ecc=4",
+            "unknown parameter \"ecc=4\"",
+        ),
+        ("mtd:/dev/mtd1?layout=1024x64x2048", "has its own layout"),
+    ] {
+        let message = format!("{:#}", text.parse::<NandSpec>().unwrap_err());
+        assert!(message.contains(error), "{text}: {message}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_open_nand() -> anyhow::Result<()> {
+    use super::{open_nand, Nand, NandBlock};
+
+    let mut nand = open_nand("sim:?layout=16x16x128")?;
+    assert_eq!(nand.get_layout(), "16x16x128".parse()?);
+    assert!(nand.block(3)?.unwrap().read(0, &mut [0; 128]).is_ok());
+
+    #[cfg(unix)]
+    {
+        let path = std::env::temp_dir().join(format!("nand-spec-{}.fnand", std::process::id()));
+        let spec = format!("file:{}", path.display());
+
+        // A new NAND file needs a layout, which it keeps when reopened
+        assert!(open_nand(&spec).is_err());
+        let mut nand = open_nand(&format!("{spec}?layout=16x16x128"))?;
+        nand.block(2)?.unwrap().mark_bad()?;
+        drop(nand);
+        let mut nand = open_nand(&spec)?;
+        assert!(nand.block(2)?.is_none());
+        assert!(open_nand(&format!("{spec}?layout=32x16x128")).is_err());
+        std::fs::remove_file(&path)?;
+    }
+
+    #[cfg(not(feature = "linux-hw"))]
+    assert!(open_nand("mtd-name:ubi").is_err());
+
+    Ok(())
+}
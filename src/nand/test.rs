@@ -0,0 +1,362 @@
+//! A destructive test of a NAND, for burning it in before its first installation: every good block
+//! is erased, programmed with known patterns and read back, over and over, to find the blocks that
+//! ought to be marked bad beyond those the factory bad block table already has.
+//!
+//! Blocks that fail [BurnInOptions::max_failures] times are marked bad. Blocks that only erase or
+//! program much more slowly than the rest are reported as suspects, but left alone. Every block
+//! that survives is left erased.
+
+use super::{Nand, NandBlock};
+
+use anyhow::{bail, Context};
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The patterns [burn_in] writes by default: alternating bits both ways, then every bit
+/// programmed. (Programming 0xFF would leave a page erased, which tests nothing.)
+pub const DEFAULT_PATTERNS: &[u8] = &[0x55, 0xAA, 0x00];
+
+/// Erase or program times this short are never taken as slow, however they compare with the
+/// median: at this scale, the difference is down to scheduling rather than the NAND
+const SLOW_FLOOR: Duration = Duration::from_millis(1);
+
+/// How to burn in a NAND
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BurnInOptions {
+    /// How many times to go through the patterns
+    pub passes: u32,
+
+    /// The byte each page is filled with, for each erase-program-read cycle in a pass
+    pub patterns: Vec<u8>,
+
+    /// How many cycles a block may fail before it's marked bad
+    pub max_failures: u32,
+
+    /// How many times the median erase (or program) time a block may take before it's suspect
+    pub slow_factor: u32,
+}
+
+impl Default for BurnInOptions {
+    fn default() -> Self {
+        Self {
+            passes: 1,
+            patterns: DEFAULT_PATTERNS.to_vec(),
+            max_failures: 2,
+            slow_factor: 4,
+        }
+    }
+}
+
+/// How one good block fared
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BlockResult {
+    pub index: u32,
+
+    /// How many cycles failed
+    pub failures: u32,
+
+    /// What went wrong the last time a cycle failed
+    pub last_error: Option<String>,
+
+    /// The longest an erase took
+    pub erase_time: Duration,
+
+    /// The longest programming the whole block took
+    pub program_time: Duration,
+
+    /// Was the block marked bad for failing too often?
+    pub marked_bad: bool,
+
+    /// Did the block take much longer than the others to erase or program?
+    pub slow: bool,
+}
+
+/// What [burn_in] found
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BurnInReport {
+    /// The blocks already marked bad, which weren't tested
+    pub already_bad: Vec<u32>,
+
+    /// Every other block, in order
+    pub blocks: Vec<BlockResult>,
+
+    /// The median of the blocks' [BlockResult::erase_time]
+    pub median_erase_time: Duration,
+
+    /// The median of the blocks' [BlockResult::program_time]
+    pub median_program_time: Duration,
+
+    /// How long the whole test took
+    pub duration: Duration,
+}
+
+impl BurnInReport {
+    /// The blocks marked bad by the test
+    pub fn marked_bad(&self) -> Vec<u32> {
+        self.blocks_where(|x| x.marked_bad)
+    }
+
+    /// The blocks that failed at least once, but not often enough to be marked bad
+    pub fn failing(&self) -> Vec<u32> {
+        self.blocks_where(|x| x.failures > 0 && !x.marked_bad)
+    }
+
+    /// The blocks that were slow, but otherwise passed
+    pub fn suspects(&self) -> Vec<u32> {
+        self.blocks_where(|x| x.slow && !x.marked_bad)
+    }
+
+    fn blocks_where(&self, f: impl Fn(&BlockResult) -> bool) -> Vec<u32> {
+        self.blocks
+            .iter()
+            .filter(|x| f(x))
+            .map(|x| x.index)
+            .collect()
+    }
+}
+
+impl fmt::Display for BurnInReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Tested {} blocks in {:.1}s ({} already bad)",
+            self.blocks.len(),
+            self.duration.as_secs_f32(),
+            self.already_bad.len()
+        )?;
+        writeln!(
+            f,
+            "Median times: erase {:?}, program {:?}",
+            self.median_erase_time, self.median_program_time
+        )?;
+        writeln!(f, "Marked bad: {:?}", self.marked_bad())?;
+        writeln!(f, "Failed, but not marked bad: {:?}", self.failing())?;
+        writeln!(f, "Slow (suspect): {:?}", self.suspects())?;
+        for block in self.blocks.iter().filter(|x| x.failures > 0) {
+            let error = block.last_error.as_deref().unwrap_or_default();
+            writeln!(
+                f,
+                "Block {}: {} failures, last: {error}",
+                block.index, block.failures
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Burn in `nand`, going through `patterns` `passes` times (see the [module documentation](self)),
+/// calling `progress` after each block with the number done so far and the number in all
+pub fn burn_in<N: Nand>(
+    nand: &mut N,
+    passes: u32,
+    patterns: &[u8],
+    progress: impl FnMut(u32, u32),
+) -> anyhow::Result<BurnInReport> {
+    let options = BurnInOptions {
+        passes,
+        patterns: patterns.to_vec(),
+        ..Default::default()
+    };
+    burn_in_with(nand, &options, progress)
+}
+
+/// Like [burn_in], with every option given
+pub fn burn_in_with<N: Nand>(
+    nand: &mut N,
+    options: &BurnInOptions,
+    mut progress: impl FnMut(u32, u32),
+) -> anyhow::Result<BurnInReport> {
+    if options.patterns.is_empty() {
+        bail!("no patterns to burn in with");
+    }
+    nand.ensure_writeable()?;
+
+    let started = Instant::now();
+    let layout = nand.get_layout();
+    let mut data = vec![0; layout.block_bytes()];
+    let mut readback = vec![0; layout.block_bytes()];
+    let mut report = BurnInReport::default();
+    for index in 0..layout.blocks {
+        let Some(mut block) = nand.block(index)? else {
+            report.already_bad.push(index);
+            progress(index + 1, layout.blocks);
+            continue;
+        };
+
+        let mut result = BlockResult {
+            index,
+            ..Default::default()
+        };
+        let cycles = (0..options.passes).flat_map(|_| &options.patterns);
+        for &pattern in cycles {
+            data.fill(pattern);
+            if let Err(error) = cycle(&mut block, &data, &mut readback, &mut result) {
+                result.failures += 1;
+                result.last_error = Some(format!("{error:#}"));
+                if result.failures >= options.max_failures {
+                    break;
+                }
+            }
+        }
+
+        // A block that can't be left erased is no use to anyone either
+        result.marked_bad = result.failures >= options.max_failures
+            || block.erase().is_err_and(|error| {
+                result.failures += 1;
+                result.last_error = Some(format!("final erase: {error:#}"));
+                true
+            });
+        if result.marked_bad {
+            block.mark_bad()?;
+        }
+
+        report.blocks.push(result);
+        progress(index + 1, layout.blocks);
+    }
+
+    flag_slow(&mut report, options.slow_factor);
+    report.duration = started.elapsed();
+    Ok(report)
+}
+
+/// Erase `block`, program it with `data` and read it back, keeping track of the times taken
+fn cycle<B: NandBlock>(
+    block: &mut B,
+    data: &[u8],
+    readback: &mut [u8],
+    result: &mut BlockResult,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    block.erase().context("erase")?;
+    result.erase_time = result.erase_time.max(start.elapsed());
+
+    // All at once, which programs every page in order
+    let start = Instant::now();
+    block.program(0, data).context("program")?;
+    result.program_time = result.program_time.max(start.elapsed());
+
+    block.read(0, readback).context("read")?;
+    let page_size = block.page_size();
+    let wrong = readback
+        .chunks(page_size)
+        .zip(data.chunks(page_size))
+        .position(|(x, y)| x != y);
+    if let Some(page) = wrong {
+        bail!("page {page} read back wrong");
+    }
+    Ok(())
+}
+
+/// Work out the median times, and flag the blocks taking more than `factor` times as long
+fn flag_slow(report: &mut BurnInReport, factor: u32) {
+    let median = |time: fn(&BlockResult) -> Duration| {
+        let mut times: Vec<Duration> = report.blocks.iter().map(time).collect();
+        times.sort_unstable();
+        times.get(times.len() / 2).copied().unwrap_or_default()
+    };
+    report.median_erase_time = median(|x| x.erase_time);
+    report.median_program_time = median(|x| x.program_time);
+
+    let limit = |median: Duration| (median * factor).max(SLOW_FLOOR);
+    let (erase_limit, program_limit) = (
+        limit(report.median_erase_time),
+        limit(report.median_program_time),
+    );
+    for block in &mut report.blocks {
+        block.slow = block.erase_time > erase_limit || block.program_time > program_limit;
+    }
+}
+
+#[test]
+fn test_burn_in() -> anyhow::Result<()> {
+    use super::{NandLayout, SimNand};
+
+    let layout: NandLayout = "16x8x256".parse()?;
+    let mut nand = SimNand::new(layout);
+    nand.block(2)?.unwrap().mark_bad()?;
+    nand.set_erase_failure(5, true)?;
+    nand.set_program_failure(9, true)?;
+
+    // One read failing isn't enough to lose the block; failing every time is
+    nand.set_read_failures(11, 1)?;
+    nand.set_read_failures(12, u32::MAX)?;
+
+    let mut calls = vec![];
+    let report = burn_in(&mut nand, 2, DEFAULT_PATTERNS, |done, total| {
+        calls.push((done, total))
+    })?;
+    assert_eq!(calls.len(), 16);
+    assert_eq!(calls.last(), Some(&(16, 16)));
+
+    assert_eq!(report.already_bad, [2]);
+    assert_eq!(report.blocks.len(), 15);
+    assert_eq!(report.marked_bad(), [5, 9, 12]);
+    assert_eq!(report.failing(), [11]);
+    let block = |index| report.blocks.iter().find(|x| x.index == index).unwrap();
+    assert_eq!(block(5).failures, 2);
+    assert!(block(5).last_error.as_ref().unwrap().contains("erase"));
+    assert!(block(9).last_error.as_ref().unwrap().contains("program"));
+    assert!(block(12).last_error.as_ref().unwrap().contains("read"));
+    assert_eq!(block(11).failures, 1);
+    assert_eq!(block(0).failures, 0);
+
+    // The blocks marked bad are bad now; the rest are erased, having been through every pattern
+    for index in 0..16 {
+        match nand.block(index)? {
+            None => assert!([2, 5, 9, 12].contains(&index), "block {index}"),
+            Some(block) => {
+                let mut data = vec![0; layout.block_bytes()];
+                block.read(0, &mut data)?;
+                assert!(data.iter().all(|&x| x == 0xFF), "block {index}");
+            }
+        }
+    }
+    let stats = nand.stats();
+    assert_eq!(stats.mark_bads, 4);
+    assert!(stats.programs >= 11 * 2 * 3);
+
+    // The report says as much
+    let text = report.to_string();
+    assert!(text.contains("Marked bad: [5, 9, 12]"), "{text}");
+    assert!(text.contains("Block 11: 1 failures"), "{text}");
+
+    // Nothing to test with is an error, as is a write-protected NAND
+    assert!(burn_in(&mut nand, 1, &[], |_, _| ()).is_err());
+    nand.set_write_protection(Some(super::WriteProtection::ReadOnly));
+    assert!(burn_in(&mut nand, 1, DEFAULT_PATTERNS, |_, _| ()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_flag_slow() {
+    let ms = Duration::from_millis;
+    let mut report = BurnInReport {
+        blocks: [(2, 10), (3, 11), (2, 50), (2, 10), (9, 10), (2, 12)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (erase, program))| BlockResult {
+                index: i as u32,
+                erase_time: ms(erase),
+                program_time: ms(program),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    flag_slow(&mut report, 4);
+    assert_eq!(
+        (report.median_erase_time, report.median_program_time),
+        (ms(2), ms(11))
+    );
+    assert_eq!(report.suspects(), [2, 4]);
+
+    // Times that are all tiny aren't told apart
+    for block in &mut report.blocks {
+        block.erase_time /= 100;
+        block.program_time /= 100;
+    }
+    flag_slow(&mut report, 4);
+    assert_eq!(report.suspects(), Vec::<u32>::new());
+}
@@ -0,0 +1,514 @@
+//! A NAND adapter that records every operation, for reconstructing what happened in a failed
+//! installation.
+//!
+//! Traces can be kept in memory, streamed out as CSV, or both. Reads are recorded without their
+//! data, and programs with a CRC32 of theirs; a trace that also kept the programmed data (in
+//! memory, or as hex in the CSV, read back with [read_trace_csv]) can be replayed onto another
+//! NAND (e.g. a [SimNand](super::SimNand)) with [replay_trace].
+
+use super::{Nand, NandBlock, NandLayout, OpStats, WritePolicy, WriteProtection};
+
+use anyhow::{ensure, Context};
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use std::cell::RefCell;
+use std::fmt::{self, Write as _};
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+const TRACE_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// The header line of a CSV trace
+pub const TRACE_CSV_HEADER: &str = "op,block,start_page,len,crc32,data,error";
+
+/// A kind of NAND operation
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TraceOp {
+    Read,
+    Program,
+    Erase,
+    MarkBad,
+}
+
+impl fmt::Display for TraceOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TraceOp::Read => "read",
+            TraceOp::Program => "program",
+            TraceOp::Erase => "erase",
+            TraceOp::MarkBad => "mark_bad",
+        })
+    }
+}
+
+impl FromStr for TraceOp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "read" => TraceOp::Read,
+            "program" => TraceOp::Program,
+            "erase" => TraceOp::Erase,
+            "mark_bad" => TraceOp::MarkBad,
+            _ => anyhow::bail!("unknown operation {s:?}"),
+        })
+    }
+}
+
+/// One operation recorded by a [TracingNand]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TraceEntry {
+    pub op: TraceOp,
+    pub block: u32,
+
+    /// The first page read or programmed; 0 for erases and bad-block marks
+    pub start_page: u32,
+
+    /// How many bytes were read or programmed; 0 for erases and bad-block marks
+    pub len: usize,
+
+    /// The CRC32 of the programmed data
+    pub crc: Option<u32>,
+
+    /// The programmed data itself, if the trace keeps it
+    pub data: Option<Vec<u8>>,
+
+    /// What the operation failed with, if it did
+    pub error: Option<String>,
+}
+
+impl TraceEntry {
+    /// Format the entry as a line of CSV, in the columns of [TRACE_CSV_HEADER], with any data in
+    /// hex
+    pub fn to_csv(&self) -> String {
+        let crc = self.crc.map_or(String::new(), |x| format!("{x:08x}"));
+        let mut data = String::with_capacity(self.data.as_ref().map_or(0, |x| x.len() * 2));
+        for byte in self.data.iter().flatten() {
+            let _ = write!(data, "{byte:02x}");
+        }
+        let error = self.error.as_deref().map_or(String::new(), |x| {
+            // Keep the message to one field
+            format!("\"{}\"", x.replace('"', "\"\"").replace('\n', " "))
+        });
+
+        format!(
+            "{},{},{},{},{crc},{data},{error}",
+            self.op, self.block, self.start_page, self.len
+        )
+    }
+
+    /// Parse a line of CSV written by [TraceEntry::to_csv]
+    pub fn from_csv(line: &str) -> anyhow::Result<Self> {
+        let mut fields = line.splitn(7, ',');
+        let mut field = |name| fields.next().context(format!("no {name} field"));
+        let op = field("op")?.parse()?;
+        let block = field("block")?.parse().context("block")?;
+        let start_page = field("start_page")?.parse().context("start_page")?;
+        let len = field("len")?.parse().context("len")?;
+        let crc = match field("crc32")? {
+            "" => None,
+            x => Some(u32::from_str_radix(x, 16).context("crc32")?),
+        };
+        let data = match field("data")? {
+            "" => None,
+            x => Some(parse_hex(x).context("data")?),
+        };
+        let error = match field("error")? {
+            "" => None,
+            x => {
+                let x = x.strip_prefix('"').and_then(|x| x.strip_suffix('"'));
+                Some(x.context("error field isn't quoted")?.replace("\"\"", "\""))
+            }
+        };
+
+        Ok(Self {
+            op,
+            block,
+            start_page,
+            len,
+            crc,
+            data,
+            error,
+        })
+    }
+}
+
+/// The bytes written in hex as `hex`, two digits to a byte
+fn parse_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digits = std::str::from_utf8(pair).ok().filter(|_| pair.len() == 2);
+            Ok(u8::from_str_radix(
+                digits.context("odd number of hex digits")?,
+                16,
+            )?)
+        })
+        .collect()
+}
+
+/// Read back a trace written as CSV (see [TracingNand::stream_csv]), e.g. to give to
+/// [replay_trace]
+pub fn read_trace_csv(csv: impl BufRead) -> anyhow::Result<Vec<TraceEntry>> {
+    let mut lines = csv.lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    ensure!(
+        header == TRACE_CSV_HEADER,
+        "not a NAND trace (the header is {header:?}, not {TRACE_CSV_HEADER:?})"
+    );
+
+    lines
+        .enumerate()
+        .map(|(i, line)| TraceEntry::from_csv(&line?).context(format!("line {}", i + 2)))
+        .collect()
+}
+
+/// Where a [TracingNand] puts its entries
+struct TraceLog {
+    entries: Vec<TraceEntry>,
+    keep_entries: bool,
+    keep_data: bool,
+    csv: Option<Box<dyn Write>>,
+}
+
+impl TraceLog {
+    /// Is anything being recorded at all?
+    fn enabled(&self) -> bool {
+        self.keep_entries || self.csv.is_some()
+    }
+
+    fn record<T>(
+        &mut self,
+        op: TraceOp,
+        block: u32,
+        (start_page, len): (u32, usize),
+        data: &[u8],
+        result: &anyhow::Result<T>,
+    ) {
+        if !self.enabled() {
+            return;
+        }
+
+        let programmed = op == TraceOp::Program;
+        let entry = TraceEntry {
+            op,
+            block,
+            start_page,
+            len,
+            crc: programmed.then(|| TRACE_CRC.checksum(data)),
+            data: (programmed && self.keep_data).then(|| data.to_vec()),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+        };
+
+        if let Some(csv) = &mut self.csv {
+            // A trace is a debugging aid; failing to write it mustn't fail the operation
+            let _ = writeln!(csv, "{}", entry.to_csv());
+        }
+        if self.keep_entries {
+            self.entries.push(entry);
+        }
+    }
+}
+
+/// A wrapper that records every operation on the NAND
+pub struct TracingNand<N> {
+    inner: N,
+    log: RefCell<TraceLog>,
+}
+
+pub struct TracingBlock<'a, B> {
+    inner: B,
+    index: u32,
+    log: &'a RefCell<TraceLog>,
+}
+
+impl<N: Nand> TracingNand<N> {
+    /// Start tracing `inner`, keeping the entries in memory (but not the data programmed)
+    pub fn new(inner: N) -> Self {
+        Self {
+            inner,
+            log: RefCell::new(TraceLog {
+                entries: vec![],
+                keep_entries: true,
+                keep_data: false,
+                csv: None,
+            }),
+        }
+    }
+
+    /// Change whether entries are kept in memory; with this off and no CSV output, nothing is
+    /// recorded at all
+    pub fn keep_entries(self, keep: bool) -> Self {
+        self.log.borrow_mut().keep_entries = keep;
+        self
+    }
+
+    /// Record the data of each program too, in memory and in the CSV, as [replay_trace] needs
+    pub fn keep_data(self, keep: bool) -> Self {
+        self.log.borrow_mut().keep_data = keep;
+        self
+    }
+
+    /// Also write each entry out as a line of CSV, starting with a header line
+    pub fn stream_csv(self, mut csv: Box<dyn Write>) -> anyhow::Result<Self> {
+        writeln!(csv, "{TRACE_CSV_HEADER}")?;
+        self.log.borrow_mut().csv = Some(csv);
+        Ok(self)
+    }
+
+    /// Take the entries recorded in memory so far
+    pub fn take_entries(&self) -> Vec<TraceEntry> {
+        std::mem::take(&mut self.log.borrow_mut().entries)
+    }
+
+    /// Get at the underlying NAND, without tracing
+    pub fn inner_mut(&mut self) -> &mut N {
+        &mut self.inner
+    }
+
+    /// Give back the underlying NAND
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+}
+
+impl<N: fmt::Debug> fmt::Debug for TracingNand<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TracingNand")
+            .field("inner", &self.inner)
+            .field("entries", &self.log.borrow().entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<N: Nand> Nand for TracingNand<N> {
+    type Block<'a>
+        = TracingBlock<'a, N::Block<'a>>
+    where
+        Self: 'a;
+
+    fn block(&mut self, index: u32) -> anyhow::Result<Option<Self::Block<'_>>> {
+        let log = &self.log;
+        Ok(self
+            .inner
+            .block(index)?
+            .map(|inner| TracingBlock { inner, index, log }))
+    }
+
+    fn get_layout(&self) -> NandLayout {
+        self.inner.get_layout()
+    }
+
+    fn write_protection(&self) -> anyhow::Result<Option<WriteProtection>> {
+        self.inner.write_protection()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.inner.write_policy()
+    }
+
+    fn preferred_io_size(&self) -> Option<usize> {
+        self.inner.preferred_io_size()
+    }
+
+    fn stats(&self) -> OpStats {
+        self.inner.stats()
+    }
+}
+
+impl<B: NandBlock> NandBlock for TracingBlock<'_, B> {
+    fn page_count(&self) -> u32 {
+        self.inner.page_count()
+    }
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read(&self, start_page: u32, content: &mut [u8]) -> anyhow::Result<()> {
+        let result = self.inner.read(start_page, content);
+        let range = (start_page, content.len());
+        self.log
+            .borrow_mut()
+            .record(TraceOp::Read, self.index, range, &[], &result);
+        result
+    }
+
+    fn program(&mut self, start_page: u32, content: &[u8]) -> anyhow::Result<()> {
+        let result = self.inner.program(start_page, content);
+        let range = (start_page, content.len());
+        self.log
+            .borrow_mut()
+            .record(TraceOp::Program, self.index, range, content, &result);
+        result
+    }
+
+    fn erase(&mut self) -> anyhow::Result<()> {
+        let result = self.inner.erase();
+        self.log
+            .borrow_mut()
+            .record(TraceOp::Erase, self.index, (0, 0), &[], &result);
+        result
+    }
+
+    fn mark_bad(self) -> anyhow::Result<()> {
+        let result = self.inner.mark_bad();
+        self.log
+            .borrow_mut()
+            .record(TraceOp::MarkBad, self.index, (0, 0), &[], &result);
+        result
+    }
+
+    fn oob_free(&self) -> usize {
+        self.inner.oob_free()
+    }
+
+    fn read_oob(&self, page: u32, oob: &mut [u8]) -> anyhow::Result<()> {
+        self.inner.read_oob(page, oob)
+    }
+
+    // Traced as a program of the page alone; the OOB bytes aren't replayed
+    fn program_oob(&mut self, page: u32, content: &[u8], oob: &[u8]) -> anyhow::Result<()> {
+        let result = self.inner.program_oob(page, content, oob);
+        let range = (page, content.len());
+        self.log
+            .borrow_mut()
+            .record(TraceOp::Program, self.index, range, content, &result);
+        result
+    }
+}
+
+/// Apply the erases, programs and bad-block marks of a trace to `nand`, for reproducing offline
+/// the state a traced NAND was left in.
+///
+/// The trace must have kept its programmed data (see [TracingNand::keep_data]), which is checked
+/// against the recorded CRCs. Operations that failed when traced are skipped, as are reads.
+pub fn replay_trace<N: Nand>(nand: &mut N, entries: &[TraceEntry]) -> anyhow::Result<()> {
+    for (number, entry) in entries.iter().enumerate() {
+        if entry.error.is_some() || entry.op == TraceOp::Read {
+            continue;
+        }
+
+        let Some(mut block) = nand.block(entry.block)? else {
+            anyhow::bail!("entry {number}: block {} is bad", entry.block);
+        };
+        match entry.op {
+            TraceOp::Program => {
+                let Some(data) = &entry.data else {
+                    anyhow::bail!("entry {number}: the trace doesn't have the programmed data");
+                };
+                ensure!(
+                    entry.crc == Some(TRACE_CRC.checksum(data)),
+                    "entry {number}: programmed data doesn't match its CRC"
+                );
+                block.program(entry.start_page, data)?;
+            }
+            TraceOp::Erase => block.erase()?,
+            TraceOp::MarkBad => block.mark_bad()?,
+            TraceOp::Read => unreachable!(),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_trace_replay() -> anyhow::Result<()> {
+    use super::SimNand;
+    use crate::ubi::{self, ubinize::BasicVolume, ubinize::Volume, VolType};
+
+    use std::rc::Rc;
+
+    /// A CSV sink that can still be looked at once the trace owns it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+
+    // A partition with a block of garbage on it, so that the format has something to erase
+    let mut start = SimNand::new(LAYOUT);
+    start.block(3)?.unwrap().program(0, &[0x5A; 128])?;
+    let csv = SharedBuf::default();
+    let mut nand = TracingNand::new(start.clone())
+        .keep_data(true)
+        .stream_csv(Box::new(csv.clone()))?;
+
+    let mut ebt = ubi::scan_blocks(&mut nand)?;
+    ubi::format(&mut nand, &mut ebt)?;
+    let data = vec![0x33; 3 * 14 * 128];
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(data.len() as u64)
+            .image(&data[..]),
+    )];
+    ubi::write_volumes(&mut nand, &mut ebt, volumes)?;
+
+    // Everything is recorded, in memory and as CSV alike
+    let entries = nand.take_entries();
+    for op in [TraceOp::Read, TraceOp::Program, TraceOp::Erase] {
+        assert!(entries.iter().any(|x| x.op == op), "no {op} recorded");
+    }
+    assert!(entries
+        .iter()
+        .all(|x| x.error.is_none() && (x.op == TraceOp::Program) == x.data.is_some()));
+    let csv = String::from_utf8(csv.0.borrow().clone())?;
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some(TRACE_CSV_HEADER));
+    assert_eq!(lines.count(), entries.len());
+    assert!(csv.contains("\nerase,"));
+    assert_eq!(read_trace_csv(csv.as_bytes())?, entries);
+
+    // Replaying onto the NAND as it was brings it to the same state
+    let mut replayed = start;
+    replay_trace(&mut replayed, &entries)?;
+    let (mut image, mut expected) = (vec![], vec![]);
+    replayed.save(&mut image)?;
+    nand.into_inner().save(&mut expected)?;
+    assert!(image == expected);
+
+    // ...but not without the data, or with the wrong data
+    let mut entries = entries;
+    let program = entries
+        .iter_mut()
+        .find(|x| x.op == TraceOp::Program)
+        .unwrap();
+    program.data.as_mut().unwrap()[0] ^= 1;
+    let error = replay_trace(&mut SimNand::new(LAYOUT), &entries).unwrap_err();
+    assert!(error.to_string().contains("doesn't match its CRC"));
+
+    // An error survives the trip through CSV, commas, quotes and all
+    let entry = TraceEntry {
+        op: TraceOp::MarkBad,
+        block: 7,
+        start_page: 0,
+        len: 0,
+        crc: None,
+        data: None,
+        error: Some("mark_bad, \"block 7\": I/O error".into()),
+    };
+    assert_eq!(TraceEntry::from_csv(&entry.to_csv())?, entry);
+    let error = read_trace_csv("op,block\n".as_bytes()).unwrap_err();
+    assert!(error.to_string().contains("not a NAND trace"));
+    let csv = format!("{TRACE_CSV_HEADER}\nprogram,1,0,2,,0g,\n");
+    let error = read_trace_csv(csv.as_bytes()).unwrap_err();
+    assert_eq!(
+        format!("{error:#}"),
+        "line 2: data: invalid digit found in string"
+    );
+
+    Ok(())
+}
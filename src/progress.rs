@@ -0,0 +1,357 @@
+//! How installation progress is shown: on the terminal (the default), or as JSON lines for a
+//! frontend driving the installer to follow.
+//!
+//! Each JSON line is one update to a progress report, e.g.:
+//!
+//! ```text
+//! {"id":1,"parent":null,"label":"Installing BMC firmware","desc":"Scanning NAND","state":"in_progress","pos":2,"len":5,"messages":[]}
+//! {"id":2,"parent":1,"label":"Formatting","desc":"","state":"completed","pos":null,"len":null,"messages":[{"severity":"warn","msg":"3 bad blocks"}]}
+//! {"id":2,"closed":true}
+//! ```
+//!
+//! A report's messages are only sent in the first update they appear in.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use howudoin::report::{Report, Severity, State};
+use howudoin::{Consume, Controller, Id};
+
+/// Where progress goes, as picked with [set_format]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// A line on the terminal
+    #[default]
+    Term,
+
+    /// JSON lines on stderr
+    Json,
+}
+
+static JSON_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Pick where progress goes once [init] is called
+pub fn set_format(format: ProgressFormat) {
+    JSON_PROGRESS.store(format == ProgressFormat::Json, Ordering::Relaxed);
+}
+
+/// Start showing progress, in the format picked with [set_format]
+pub fn init() {
+    if JSON_PROGRESS.load(Ordering::Relaxed) {
+        howudoin::init(JsonLines::new(io::stderr()));
+    } else {
+        howudoin::init(howudoin::consumers::TermLine::default());
+    }
+}
+
+/// Like [init], but also passing `tee` each new description and message of a report, as a line
+/// of text (e.g. to keep a log of the installation)
+pub fn init_with_tee(tee: impl FnMut(&str) + Send + 'static) {
+    if JSON_PROGRESS.load(Ordering::Relaxed) {
+        howudoin::init(Tee::new(JsonLines::new(io::stderr()), tee));
+    } else {
+        howudoin::init(Tee::new(howudoin::consumers::TermLine::default(), tee));
+    }
+}
+
+/// Where a progress report is at
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProgressState {
+    InProgress { pos: u64, len: Option<u64> },
+    Completed,
+    Cancelled,
+}
+
+/// One update to a progress report
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProgressEvent<'a> {
+    pub id: Id,
+    pub parent: Option<Id>,
+    pub label: &'a str,
+    pub desc: &'a str,
+    pub state: ProgressState,
+
+    /// Every message added to the report so far, as (severity, message)
+    pub messages: Vec<(&'static str, &'a str)>,
+}
+
+impl<'a> ProgressEvent<'a> {
+    fn from_report(report: &'a Report, id: Id, parent: Option<Id>) -> Self {
+        let state = match report.state {
+            State::InProgress { pos, len, .. } => ProgressState::InProgress { pos, len },
+            State::Completed { .. } => ProgressState::Completed,
+            State::Cancelled => ProgressState::Cancelled,
+        };
+        let messages = report
+            .accums
+            .iter()
+            .map(|x| {
+                let severity = match x.severity {
+                    Severity::Error => "error",
+                    Severity::Warn => "warn",
+                    Severity::Info => "info",
+                };
+                (severity, x.msg.as_str())
+            })
+            .collect();
+
+        Self {
+            id,
+            parent,
+            label: &report.label,
+            desc: &report.desc,
+            state,
+            messages,
+        }
+    }
+
+    /// Format the update as one line of JSON, including only the messages after the first `skip`
+    fn to_json(&self, skip: usize) -> String {
+        let (state, pos, len) = match self.state {
+            ProgressState::InProgress { pos, len } => ("in_progress", Some(pos), len),
+            ProgressState::Completed => ("completed", None, None),
+            ProgressState::Cancelled => ("cancelled", None, None),
+        };
+        let number = |x: Option<u64>| x.map_or("null".into(), |x| x.to_string());
+        let messages: Vec<String> = self
+            .messages
+            .iter()
+            .skip(skip)
+            .map(|(severity, msg)| {
+                format!(r#"{{"severity":"{severity}","msg":{}}}"#, json_string(msg))
+            })
+            .collect();
+
+        format!(
+            r#"{{"id":{},"parent":{},"label":{},"desc":{},"state":"{state}","pos":{},"len":{},"messages":[{}]}}"#,
+            self.id,
+            number(self.parent.map(|x| x as u64)),
+            json_string(self.label),
+            json_string(self.desc),
+            number(pos),
+            number(len),
+            messages.join(",")
+        )
+    }
+}
+
+/// Quote and escape a string for JSON
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A howudoin consumer writing each progress update out as a line of JSON
+pub struct JsonLines<W> {
+    out: W,
+
+    /// How many of each open report's messages have been written already
+    sent: HashMap<Id, usize>,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Write out one update
+    pub fn event(&mut self, event: &ProgressEvent) {
+        let sent = self.sent.entry(event.id).or_default();
+        let line = event.to_json(*sent);
+        *sent = event.messages.len();
+
+        // Progress is only informational; failing to show it mustn't fail the installation
+        let _ = writeln!(self.out, "{line}").and_then(|_| self.out.flush());
+    }
+
+    /// Write out that a report has gone away
+    pub fn close(&mut self, id: Id) {
+        self.sent.remove(&id);
+        let _ = writeln!(self.out, r#"{{"id":{id},"closed":true}}"#).and_then(|_| self.out.flush());
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write + Send + 'static> Consume for JsonLines<W> {
+    fn rpt(&mut self, report: &Report, id: Id, parent: Option<Id>, _controller: &Controller) {
+        self.event(&ProgressEvent::from_report(report, id, parent));
+    }
+
+    fn closed(&mut self, id: Id) {
+        self.close(id);
+    }
+}
+
+/// A howudoin consumer passing reports on to another, and their news to a function as text
+struct Tee<C, F> {
+    inner: C,
+    tee: F,
+
+    /// The last description of each open report, and how many of its messages have been passed on
+    seen: HashMap<Id, (String, usize)>,
+}
+
+impl<C, F: FnMut(&str)> Tee<C, F> {
+    fn new(inner: C, tee: F) -> Self {
+        Self {
+            inner,
+            tee,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Pass on the news in one update: a new description, and any messages not passed on before
+    fn event(&mut self, event: &ProgressEvent) {
+        let (desc, sent) = self.seen.entry(event.id).or_default();
+        if desc != event.desc && !event.desc.is_empty() {
+            (self.tee)(&format!("{}: {}", event.label, event.desc));
+            *desc = event.desc.into();
+        }
+        for (severity, msg) in event.messages.iter().skip(*sent) {
+            let severity = match *severity {
+                "error" => "error: ",
+                "warn" => "warning: ",
+                _ => "",
+            };
+            (self.tee)(&format!("{}: {severity}{msg}", event.label));
+        }
+        *sent = event.messages.len();
+    }
+
+    /// Forget a report that has gone away
+    fn close(&mut self, id: Id) {
+        self.seen.remove(&id);
+    }
+}
+
+impl<C: Consume, F: FnMut(&str) + Send + 'static> Consume for Tee<C, F> {
+    fn debounce(&self) -> Duration {
+        self.inner.debounce()
+    }
+
+    fn rpt(&mut self, report: &Report, id: Id, parent: Option<Id>, controller: &Controller) {
+        self.event(&ProgressEvent::from_report(report, id, parent));
+        self.inner.rpt(report, id, parent, controller);
+    }
+
+    fn closed(&mut self, id: Id) {
+        self.close(id);
+        self.inner.closed(id);
+    }
+}
+
+#[test]
+fn test_json_lines() {
+    let mut json = JsonLines::new(vec![]);
+    let mut event = ProgressEvent {
+        id: 1,
+        parent: None,
+        label: "Installing BMC firmware",
+        desc: "Scanning NAND",
+        state: ProgressState::InProgress {
+            pos: 1,
+            len: Some(5),
+        },
+        messages: vec![],
+    };
+    json.event(&event);
+
+    // Messages are only written the first time they're seen
+    event.messages.push(("warn", "3 bad blocks"));
+    event.state = ProgressState::InProgress { pos: 2, len: None };
+    json.event(&event);
+    event.messages.push(("info", "block \"7\"\n\tworn"));
+    json.event(&event);
+
+    let child = ProgressEvent {
+        id: 2,
+        parent: Some(1),
+        label: "Formatting",
+        desc: "",
+        state: ProgressState::Completed,
+        messages: vec![("error", "oh no")],
+    };
+    json.event(&child);
+    json.close(2);
+    json.event(&child);
+    event.state = ProgressState::Cancelled;
+    json.event(&event);
+
+    let output = String::from_utf8(json.into_inner()).unwrap();
+    let expected = [
+        r#"{"id":1,"parent":null,"label":"Installing BMC firmware","desc":"Scanning NAND","state":"in_progress","pos":1,"len":5,"messages":[]}"#,
+        r#"{"id":1,"parent":null,"label":"Installing BMC firmware","desc":"Scanning NAND","state":"in_progress","pos":2,"len":null,"messages":[{"severity":"warn","msg":"3 bad blocks"}]}"#,
+        r#"{"id":1,"parent":null,"label":"Installing BMC firmware","desc":"Scanning NAND","state":"in_progress","pos":2,"len":null,"messages":[{"severity":"info","msg":"block \"7\"\n\tworn"}]}"#,
+        r#"{"id":2,"parent":1,"label":"Formatting","desc":"","state":"completed","pos":null,"len":null,"messages":[{"severity":"error","msg":"oh no"}]}"#,
+        r#"{"id":2,"closed":true}"#,
+        // A report that closed and came back starts over
+        r#"{"id":2,"parent":1,"label":"Formatting","desc":"","state":"completed","pos":null,"len":null,"messages":[{"severity":"error","msg":"oh no"}]}"#,
+        r#"{"id":1,"parent":null,"label":"Installing BMC firmware","desc":"Scanning NAND","state":"cancelled","pos":null,"len":null,"messages":[]}"#,
+    ];
+    assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn test_tee() {
+    let mut lines = vec![];
+    let mut tee = Tee::new((), |x: &str| lines.push(x.to_string()));
+    let mut event = ProgressEvent {
+        id: 1,
+        parent: None,
+        label: "Writing rootfs",
+        desc: "",
+        state: ProgressState::InProgress { pos: 0, len: None },
+        messages: vec![],
+    };
+    tee.event(&event);
+
+    // Each description is passed on once, as is each message
+    event.desc = "Volume rootfs";
+    tee.event(&event);
+    tee.event(&event);
+    event.messages.push(("warn", "block 7 went bad"));
+    event.messages.push(("info", "retrying"));
+    tee.event(&event);
+    event.messages.push(("error", "flash is full"));
+    tee.event(&event);
+
+    // ...until the report goes away, after which one with the same ID starts over
+    tee.close(1);
+    event.messages.truncate(0);
+    tee.event(&event);
+
+    assert_eq!(
+        lines,
+        [
+            "Writing rootfs: Volume rootfs",
+            "Writing rootfs: warning: block 7 went bad",
+            "Writing rootfs: retrying",
+            "Writing rootfs: error: flash is full",
+            "Writing rootfs: Volume rootfs",
+        ]
+    );
+}
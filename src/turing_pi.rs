@@ -1,28 +1,81 @@
+pub mod firstboot;
+pub mod install_log;
+pub mod keys;
+pub mod kmsg;
+pub mod layout;
 pub mod led;
+pub mod stamp;
+pub mod uboot_env;
 
 use anyhow::Context;
 use nix::errno::Errno;
-use nix::mount::{mount, MsFlags};
+use nix::mount::{mount, umount, MsFlags};
 use retry::{delay::Fixed, retry};
-use std::fmt::Debug;
-use std::io::{self, Read, Seek};
-use std::sync::mpsc;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::io::{self, Read, Seek, Write};
+use std::sync::{atomic::AtomicBool, mpsc};
 use std::thread;
-use std::time::Duration;
-use std::{fs, path::Path};
+use std::time::{Duration, Instant};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use crate::{
-    format, image,
-    nand::{mtd::MtdNand, Nand},
+    buildinfo,
+    bundle::{Bundle, SectionKind},
+    error::{InstallError, ResultExt},
+    format::{
+        self,
+        raw::{self, RawVerifyResult},
+    },
+    image::{self, ImageKind},
+    lock::InstallLock,
+    nand::{
+        format_size,
+        mtd::{MtdGeometry, MtdNand},
+        partition::PartitionNand,
+        test::{self as nand_test, BurnInReport},
+        Nand, NandBlock, NandLayout, NandSpec, OpStats, SharedNand,
+    },
+    progress,
     ubi::{
         self,
-        ubinize::{BasicVolume, Volume},
-        VolType,
+        ubinize::{ReopenImage, UBI_MAX_VOLUMES},
+        EbtDiff, EbtError, EbtSummary, EcStats, VolumeSelector,
     },
+    util::{check_abort, Aborted, ReadExt, SharedReader},
 };
 
-use self::led::LedState;
-const BANNER: &str = r"
+use self::firstboot::{firstboot_volume, Firstboot, FIRSTBOOT_VOLUME_ID, FIRSTBOOT_VOLUME_NAME};
+use self::install_log::{
+    has_log_volume, install_log_volume, write_log_volume, InstallLog, LogSink,
+    INSTALL_LOG_FILE_NAME, INSTALL_LOG_MAX_BYTES, INSTALL_LOG_VOLUME_NAME,
+};
+use self::kmsg::{KernelLog, KmsgCursor, KmsgFilter};
+use self::layout::{get_ubi_volumes_with, UbiLayoutSpec, VolumeSize};
+use self::led::LedCommand;
+use self::stamp::{DigestReader, InstallStamp, STAMP_VOLUME_NAME};
+use self::uboot_env::{UbootEnv, ENV_FLAG_ACTIVE, UBOOT_ENV_SIZE, UBOOT_ENV_VOLUME_NAME};
+
+/// How many threads scan the UBI partition at once; enough to keep the SPI NAND busy
+const SCAN_THREADS: usize = 4;
+
+/// The drivers for the Turing Pi 2's NAND controller, whose kernel messages are worth keeping
+const KMSG_DRIVERS: &[&str] = &["sun6i-spi"];
+
+/// The most kernel messages to keep for showing to the user
+const KMSG_MAX_LINES: usize = 40;
+
+/// How many LEBs of the installed rootfs are read back before deciding it needn't be rewritten
+const STAMP_SPOT_CHECKS: usize = 8;
+
+/// An erase counter past which the NAND is getting near the end of its life
+pub const EC_WARN_THRESHOLD: u64 = 60_000;
+
+/// The banner shown when the installer starts
+pub const BANNER: &str = r"
  _____ _   _ ____  ___ _   _  ____
 |_   _| | | |  _ \|_ _| \ | |/ ___|
   | | | | | | |_) || ||  \| | |  _
@@ -69,89 +122,877 @@ pub fn wait_forever() -> ! {
     }
 }
 
+/// A summary of what [upgrade_bmc] did, for showing to the user
+#[derive(Debug, Default, Clone)]
+pub struct InstallReport {
+    /// Did the NAND already have this firmware, so that nothing was written?
+    pub up_to_date: bool,
+
+    /// Was legacy Allwinner boot0 code found (and erased)?
+    pub boot0_purged: bool,
+
+    /// Was the UBI partition migrated away from SIMULATE_MULTIPLANE?
+    pub migrated: bool,
+
+    /// Was a stale UBI fastmap found (and erased)?
+    pub fastmap_invalidated: bool,
+
+    /// How many blocks were already bad
+    pub bad_blocks_found: u32,
+
+    /// How many blocks went bad during the installation and were marked as such
+    pub bad_blocks_marked: u32,
+
+    /// The blocks of the UBI partition that couldn't be read when it was scanned
+    pub scan_issues: ubi::ScanIssues,
+
+    /// How many PEBs were written for each UBI volume, by volume ID
+    pub pebs_written: BTreeMap<u32, u32>,
+
+    /// How many PEBs an interrupted installation had already written, and were kept, for each UBI
+    /// volume, by volume ID
+    pub pebs_kept: BTreeMap<u32, u32>,
+
+    /// The spread of erase counters across the UBI partition, as found before formatting it
+    pub ec_stats: Option<EcStats>,
+
+    /// How many bytes of bootloader were written to the boot partition
+    pub bootloader_bytes: u64,
+
+    /// The state of the UBI partition once the rootfs was written
+    pub ubi_summary: Option<EbtSummary>,
+
+    /// Which blocks of the UBI partition changed, from scanning it to writing the rootfs
+    pub ubi_changes: EbtDiff,
+
+    /// The record left for the first boot to find
+    pub firstboot: Option<Firstboot>,
+
+    /// How long each task took
+    pub task_durations: Vec<(&'static str, Duration)>,
+
+    /// The operations each task carried out on the NAND, both partitions together
+    pub task_ops: Vec<(&'static str, OpStats)>,
+
+    /// The kernel's messages about the NAND during the installation
+    pub kernel_log: KernelLog,
+}
+
+impl InstallReport {
+    /// A warning about the NAND wearing out, if its erase counters are past [EC_WARN_THRESHOLD]
+    pub fn wear_warning(&self) -> Option<String> {
+        let max = self.ec_stats.as_ref()?.max;
+        (max > EC_WARN_THRESHOLD).then(|| {
+            format!(
+                "WARNING: the NAND is nearing the end of its life \
+                 (blocks erased up to {max} times); consider replacing the module"
+            )
+        })
+    }
+}
+
+impl fmt::Display for InstallReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(warning) = self.wear_warning() {
+            writeln!(f, "{warning}")?;
+        }
+        if self.up_to_date {
+            writeln!(f, "The NAND already has this firmware; nothing was written")?;
+        }
+        if self.boot0_purged {
+            writeln!(f, "Legacy Allwinner boot code was erased")?;
+        }
+        if self.migrated {
+            writeln!(f, "UBI partition was migrated from the v1.x layout")?;
+        }
+        if self.fastmap_invalidated {
+            writeln!(f, "A stale UBI fastmap was erased")?;
+        }
+        writeln!(
+            f,
+            "Bad blocks: {} found, {} newly marked",
+            self.bad_blocks_found, self.bad_blocks_marked
+        )?;
+        if !self.scan_issues.is_empty() {
+            writeln!(f, "Unreadable blocks: {}", self.scan_issues)?;
+        }
+        for (vol_id, pebs) in &self.pebs_written {
+            writeln!(f, "UBI volume {vol_id:#x}: {pebs} PEBs written")?;
+        }
+        for (vol_id, pebs) in &self.pebs_kept {
+            writeln!(
+                f,
+                "UBI volume {vol_id:#x}: {pebs} PEBs kept from an interrupted install"
+            )?;
+        }
+        if let Some(stats) = &self.ec_stats {
+            writeln!(f, "Erase counters: {stats}")?;
+        }
+        writeln!(f, "Bootloader: {} bytes written", self.bootloader_bytes)?;
+        if let Some(firstboot) = &self.firstboot {
+            writeln!(f, "First boot record: {firstboot}")?;
+        }
+        if let Some(summary) = &self.ubi_summary {
+            write!(f, "UBI partition:\n{summary}")?;
+            write!(f, "UBI blocks changed:\n{}", self.ubi_changes)?;
+        }
+        for (desc, duration) in &self.task_durations {
+            write!(f, "{desc}: {:.1}s", duration.as_secs_f32())?;
+            if let Some((_, ops)) = self.task_ops.iter().find(|x| x.0 == *desc) {
+                write!(f, " ({ops})")?;
+            }
+            writeln!(f)?;
+        }
+        if !self.kernel_log.is_empty() {
+            write!(f, "Kernel messages:\n{}", self.kernel_log)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The kernel command line parameter naming the NAND to install the bootloader to, as a
+/// [NandSpec] (e.g. `bmc_installer.boot_nand=mtd:/dev/mtd2`)
+const BOOT_NAND_PARAMETER: &str = "bmc_installer.boot_nand";
+
+/// The kernel command line parameter naming the NAND holding the UBI partition, likewise
+const UBI_NAND_PARAMETER: &str = "bmc_installer.ubi_nand";
+
+/// The NANDs chosen on a kernel command line with [BOOT_NAND_PARAMETER] and [UBI_NAND_PARAMETER],
+/// in that order, if it chooses them; they must be MTD devices
+pub fn nands_on_cmdline(cmdline: &str) -> anyhow::Result<(Option<NandSpec>, Option<NandSpec>)> {
+    let parse = |name| {
+        cmdline_parameter(cmdline, name)
+            .map(|value| {
+                let spec: NandSpec = value.parse().context(name)?;
+                anyhow::ensure!(spec.is_mtd(), "{name}: {spec} isn't an MTD device");
+                Ok(spec)
+            })
+            .transpose()
+    };
+    Ok((parse(BOOT_NAND_PARAMETER)?, parse(UBI_NAND_PARAMETER)?))
+}
+
+/// The value of the kernel command line parameter `name`, the last one counting
+fn cmdline_parameter<'a>(cmdline: &'a str, name: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|x| x.strip_prefix(name)?.strip_prefix('='))
+        .next_back()
+}
+
+/// Open the `boot` and `ubi` partitions of the NAND flash, or whichever NANDs the kernel command
+/// line chooses instead (see [nands_on_cmdline]).
+///
+/// If the device tree doesn't partition the NAND (neither partition exists), and the command line
+/// doesn't choose, fall back on carving the partitions out of the whole device, using the same
+/// layout that the firmware's device tree would. Either way, the partitions must pass
+/// [check_partition_geometry].
+fn open_mtd_partitions() -> anyhow::Result<(PartitionNand<MtdNand>, PartitionNand<MtdNand>)> {
+    const WHOLE_NAND_PATH: &str = "/dev/mtd0";
+    const BOOT_PARTITION_SIZE: u32 = 4 << 20;
+
+    let (boot_spec, ubi_spec) = nands_on_cmdline(&fs::read_to_string("/proc/cmdline")?)?;
+    let chosen = boot_spec.is_some() || ubi_spec.is_some();
+    let boot_spec = boot_spec.unwrap_or(NandSpec::MtdName("boot".into()));
+    let ubi_spec = ubi_spec.unwrap_or(NandSpec::MtdName("ubi".into()));
+
+    if chosen {
+        let opened = boot_spec
+            .open_mtd()
+            .and_then(|boot| Ok((boot, ubi_spec.open_mtd()?)));
+        let (boot, ubi) = opened
+            .context(format!("opening {boot_spec} and {ubi_spec}"))
+            .class(InstallError::MtdNotFound)?;
+        return Ok((
+            PartitionNand::whole(with_factory_bad_blocks(boot)?),
+            PartitionNand::whole(with_factory_bad_blocks(ubi)?),
+        ));
+    }
+
+    // Only carve up the whole device if the device tree doesn't partition it at all; a partition
+    // that's there but can't be opened is an error, not a reason to write past it
+    match (boot_spec.open_mtd(), ubi_spec.open_mtd()) {
+        (Ok(boot), Ok(ubi)) => {
+            // The device tree says where these are, and a damaged one has been known to put the
+            // `ubi` partition over the bootloader
+            check_partition_geometry(
+                (boot.geometry(), boot.get_layout()),
+                (ubi.geometry(), ubi.get_layout()),
+            )?;
+            return Ok((
+                PartitionNand::whole(with_factory_bad_blocks(boot)?),
+                PartitionNand::whole(with_factory_bad_blocks(ubi)?),
+            ));
+        }
+        (Err(boot), Err(ubi)) if mtd_missing(&boot) && mtd_missing(&ubi) => (),
+        (Err(error), _) => return Err(error.context(boot_spec.to_string())),
+        (_, Err(error)) => return Err(error.context(ubi_spec.to_string())),
+    }
+
+    let nand = MtdNand::open(WHOLE_NAND_PATH)
+        .context(WHOLE_NAND_PATH)
+        .class(InstallError::MtdNotFound)?;
+    let nand = with_factory_bad_blocks(nand)?;
+    let whole = nand.geometry().clone();
+    let layout = nand.get_layout();
+    let boot_blocks = BOOT_PARTITION_SIZE / layout.block_bytes() as u32;
+    let ubi_blocks = layout.blocks.saturating_sub(boot_blocks);
+    let boot = PartitionNand::new(nand.clone_handle()?, 0, boot_blocks)?;
+    let ubi = PartitionNand::new(nand, boot_blocks, ubi_blocks)?;
+
+    // Held to the same standard as the device tree's partitions, as if it had made these
+    let (boot_geometry, ubi_geometry) = carved_geometry(&whole, boot.get_layout());
+    check_partition_geometry(
+        (&boot_geometry, boot.get_layout()),
+        (&ubi_geometry, ubi.get_layout()),
+    )?;
+    Ok((boot, ubi))
+}
+
+/// Is `error`, from opening an MTD device, because there's no such device?
+fn mtd_missing(error: &anyhow::Error) -> bool {
+    InstallError::of(error) == InstallError::MtdNotFound
+        || error.chain().any(|cause| {
+            cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|x| x.kind() == io::ErrorKind::NotFound)
+        })
+}
+
+/// Where `boot` and `ubi` partitions carved out of the device `whole`, `boot` being `boot_layout`
+/// and `ubi` the rest, lie on the chip
+fn carved_geometry(whole: &MtdGeometry, boot_layout: NandLayout) -> (MtdGeometry, MtdGeometry) {
+    let boot_bytes = boot_layout.total_bytes();
+    let boot = MtdGeometry {
+        name: Some("boot".into()),
+        offset: whole.offset,
+        size: Some(boot_bytes),
+    };
+    let ubi = MtdGeometry {
+        name: Some("ubi".into()),
+        offset: whole.offset.map(|x| x + boot_bytes),
+        size: whole.size.map(|x| x.saturating_sub(boot_bytes)),
+    };
+    (boot, ubi)
+}
+
+/// Have the NAND treat blocks with factory bad-block markers as bad, even where the driver hasn't
+/// registered them in its bad block table
+fn with_factory_bad_blocks(mut nand: MtdNand) -> anyhow::Result<MtdNand> {
+    let found = nand.scan_factory_bad_blocks()?;
+    if !found.is_empty() {
+        eprintln!("Blocks with factory bad-block markers, skipped: {found:?}");
+    }
+    Ok(nand)
+}
+
+/// The smallest and largest `boot` partition [check_partition_geometry] will believe in
+const BOOT_PARTITION_BOUNDS: (u64, u64) = (1 << 20, 16 << 20);
+
+/// Make sure the `boot` and `ubi` partitions are where they can be: `boot` at the start of the chip
+/// and of a believable size, `ubi` past its end, and each as big as its device says it is. Anything
+/// sysfs doesn't say goes unchecked.
+fn check_partition_geometry(
+    (boot, boot_layout): (&MtdGeometry, NandLayout),
+    (ubi, ubi_layout): (&MtdGeometry, NandLayout),
+) -> anyhow::Result<()> {
+    let wrong = |what: String| {
+        Err(InstallError::UnsafeTarget.msg(format!(
+            "the MTD partitions look wrong ({what}): boot is {boot}, ubi is {ubi}"
+        )))
+    };
+
+    if let Some(offset) = boot.offset.filter(|&x| x != 0) {
+        return wrong(format!("boot starts at {offset:#x}, rather than 0"));
+    }
+    let (min, max) = BOOT_PARTITION_BOUNDS;
+    if let Some(size) = boot.size.filter(|x| !(min..=max).contains(x)) {
+        return wrong(format!(
+            "boot is {}, outside {} to {}",
+            format_size(size),
+            format_size(min),
+            format_size(max)
+        ));
+    }
+    if let (Some(boot_end), Some(ubi_offset)) = (boot.end(), ubi.offset) {
+        if ubi_offset < boot_end {
+            return wrong("they overlap".into());
+        }
+    }
+    for (name, geometry, layout) in [("boot", boot, boot_layout), ("ubi", ubi, ubi_layout)] {
+        if let Some(size) = geometry.size.filter(|&x| x != layout.total_bytes()) {
+            return wrong(format!(
+                "{name} is {size:#x} bytes, but its device holds {:#x}",
+                layout.total_bytes()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Make sure the first good block of `nand` doesn't start with anything the Boot ROM would run, as
+/// it would if the `ubi` partition were really over the bootloader
+fn check_not_boot_code<N: Nand>(nand: &mut N) -> anyhow::Result<()> {
+    let layout = nand.get_layout();
+    let mut page = vec![0; layout.bytes_per_page];
+    for index in 0..layout.blocks {
+        let Some(block) = nand.block(index)? else {
+            continue;
+        };
+        block.read(0, &mut page)?;
+        return match format::bootrom::classify(&page) {
+            format::bootrom::BootHeader::None => Ok(()),
+            header => Err(InstallError::UnsafeTarget.msg(format!(
+                "the ubi partition starts with boot code ({header:?}) in block {index}; it's \
+                 probably over the bootloader, so nothing has been written"
+            ))),
+        };
+    }
+    Ok(())
+}
+
+/// Where [upgrade_bmc_with] gets the NAND partitions to install onto
+pub trait NandProvider {
+    type Nand: SharedNand;
+
+    /// Open the `boot` and `ubi` partitions, in that order
+    fn open_partitions(&self) -> anyhow::Result<(Self::Nand, Self::Nand)>;
+}
+
+/// The Turing Pi 2's own NAND flash, through MTD
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MtdPartitions;
+
+impl NandProvider for MtdPartitions {
+    type Nand = PartitionNand<MtdNand>;
+
+    fn open_partitions(&self) -> anyhow::Result<(Self::Nand, Self::Nand)> {
+        open_mtd_partitions()
+    }
+}
+
+/// What the [UpgradeHooks] get to see of the installation after each task
+#[derive(Debug, Copy, Clone)]
+pub struct TaskView<'a> {
+    /// The erase block table of the UBI partition, once it has been scanned
+    pub ebt: Option<&'a ubi::Ebt>,
+
+    /// The report of the installation so far
+    pub report: &'a InstallReport,
+}
+
+type BeforeTaskFn<'a> = Box<dyn FnMut(&str) + 'a>;
+type AfterTaskFn<'a> = Box<dyn FnMut(&str, &anyhow::Result<()>, &TaskView) + 'a>;
+
+/// Code for [upgrade_bmc] to run as the installation goes along; by default, there's none
+#[derive(Default)]
+pub struct UpgradeHooks<'a> {
+    before_destructive: Option<Box<dyn FnOnce() + 'a>>,
+    before_task: Option<BeforeTaskFn<'a>>,
+    after_task: Option<AfterTaskFn<'a>>,
+    force: bool,
+    uboot_env: Option<UbootEnv>,
+    log_to_sdcard: bool,
+    boot_image_layout: raw::BootImageLayout,
+}
+
+impl<'a> UpgradeHooks<'a> {
+    /// Run `hook` once everything has been checked, just before anything is written to the NAND
+    /// (e.g. to have the user confirm)
+    pub fn before_destructive(mut self, hook: impl FnOnce() + 'a) -> Self {
+        self.before_destructive = Some(Box::new(hook));
+        self
+    }
+
+    /// Run `hook` before each task, with its description
+    pub fn before_task(mut self, hook: impl FnMut(&str) + 'a) -> Self {
+        self.before_task = Some(Box::new(hook));
+        self
+    }
+
+    /// Run `hook` after each task, with its description and result, even if it failed
+    pub fn after_task(
+        mut self,
+        hook: impl FnMut(&str, &anyhow::Result<()>, &TaskView) + 'a,
+    ) -> Self {
+        self.after_task = Some(Box::new(hook));
+        self
+    }
+
+    /// Install even if the NAND already has the same firmware, rather than leaving it be
+    pub fn force(mut self) -> Self {
+        self.force = true;
+        self
+    }
+
+    /// Fill the `uboot-env` volume with `env`, rather than leaving it empty for U-Boot to use the
+    /// environment built into it. The install stamp doesn't cover the environment, so this always
+    /// installs, as [UpgradeHooks::force] does.
+    pub fn uboot_env(mut self, env: UbootEnv) -> Self {
+        self.uboot_env = Some(env);
+        self
+    }
+
+    /// Save the log of the installation to the SD card's FAT partition (see [install_log]), rather
+    /// than only to the UBI partition
+    pub fn log_to_sdcard(mut self) -> Self {
+        self.log_to_sdcard = true;
+        self
+    }
+
+    /// Lay out a bootloader that starts with an SPL as `layout` says (see [raw::write_boot_image]),
+    /// rather than as the BMC's own is ([raw::BootImageLayout::default])
+    pub fn boot_image_layout(mut self, layout: raw::BootImageLayout) -> Self {
+        self.boot_image_layout = layout;
+        self
+    }
+}
+
+impl Debug for UpgradeHooks<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpgradeHooks")
+            .field("before_destructive", &self.before_destructive.is_some())
+            .field("before_task", &self.before_task.is_some())
+            .field("after_task", &self.after_task.is_some())
+            .field("force", &self.force)
+            .field("uboot_env", &self.uboot_env)
+            .field("log_to_sdcard", &self.log_to_sdcard)
+            .field("boot_image_layout", &self.boot_image_layout)
+            .finish()
+    }
+}
+
 /// This is the core function of the installer. Several tasks are executed to
 /// upgrade from v1.x firmware or to install onto new flash.
+///
+/// If the install stamp shows that the NAND already has this firmware (see [stamp]), and a sample
+/// of it reads back intact, nothing is formatted or written, unless [UpgradeHooks::force] says so.
+///
+/// Raising `abort` stops the installation before the next task, or the next block of a format or
+/// rootfs write, with an [Aborted] error. The bootloader is never left half-written, though.
+///
+/// An installation cut short partway through writing the rootfs (by an abort or a power loss) is
+/// picked up again by the next one, which keeps the blocks already written that still hold what it
+/// would write (see [ubi::format_for_resume]).
+///
+/// Everything the installation prints, and the error it fails with, is kept in an [InstallLog],
+/// which is saved once it's over, however it ended (see [install_log]).
+///
+/// Nothing is done while another installer instance holds the [InstallLock].
 pub fn upgrade_bmc(
-    mut rootfs: impl Read + Seek,
+    rootfs: impl Read + Seek,
     bootloader: impl Read,
-    pre_upgrade: impl FnOnce(),
-    led_tx: mpsc::Sender<&'static [LedState]>,
-) -> anyhow::Result<()> {
-    eprintln!("{}", BANNER);
+    hooks: UpgradeHooks,
+    layout: &UbiLayoutSpec,
+    led_tx: mpsc::Sender<LedCommand>,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<InstallReport> {
+    let _lock = InstallLock::acquire()?;
+    upgrade_bmc_with(
+        &MtdPartitions,
+        rootfs,
+        bootloader,
+        hooks,
+        layout,
+        led_tx,
+        abort,
+    )
+}
+
+/// Like [upgrade_bmc], but installing onto the NAND partitions from `provider`
+pub fn upgrade_bmc_with<P: NandProvider>(
+    provider: &P,
+    rootfs: impl Read + Seek,
+    bootloader: impl Read,
+    hooks: UpgradeHooks,
+    layout: &UbiLayoutSpec,
+    led_tx: mpsc::Sender<LedCommand>,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<InstallReport> {
+    let log = InstallLog::new();
+    let log_to_sdcard = hooks.log_to_sdcard;
+    let mut log_ubi = None;
+    let result = upgrade_bmc_logged(
+        provider,
+        rootfs,
+        bootloader,
+        hooks,
+        layout,
+        led_tx,
+        abort,
+        &log,
+        &mut log_ubi,
+    );
+    if let Err(error) = &result {
+        log.record(&format!("Installation failed: {error:#}"));
+    }
+    save_install_log(&log, log_to_sdcard, log_ubi);
+
+    result
+}
+
+/// Do the work of [upgrade_bmc_with], keeping `log`. Once the UBI partition has been scanned,
+/// `log_ubi` is left with it and its EBT (as far as they got), for the log to be written into,
+/// unless the installation found nothing to do.
+#[allow(clippy::too_many_arguments)]
+fn upgrade_bmc_logged<P: NandProvider>(
+    provider: &P,
+    mut rootfs: impl Read + Seek,
+    mut bootloader: impl Read,
+    mut hooks: UpgradeHooks,
+    layout: &UbiLayoutSpec,
+    led_tx: mpsc::Sender<LedCommand>,
+    abort: Option<&AtomicBool>,
+    log: &InstallLog,
+    log_ubi: &mut Option<(P::Nand, ubi::Ebt)>,
+) -> anyhow::Result<InstallReport> {
+    log.print(BANNER);
+    log.print(buildinfo::summary());
 
     // Open the NAND flash partitions
-    let nand_boot = MtdNand::open_named("boot")?;
-    let nand_ubi = MtdNand::open_named("ubi")?;
+    let (nand_boot, mut nand_ubi) = provider.open_partitions()?;
+
+    // Refuse to go any further if either partition can't be written; otherwise, the first failed
+    // erase would have us marking perfectly good blocks bad
+    for nand in [&nand_boot, &nand_ubi] {
+        nand.ensure_writeable()?;
+    }
+
+    // Nor if the UBI partition is where the bootloader should be, however it came to be opened
+    check_not_boot_code(&mut nand_ubi)?;
+
+    // A file given in the wrong place is better called out as that than left to fail as a damaged
+    // image of the right sort
+    match image::detect(&mut rootfs)? {
+        ImageKind::Erofs { .. } | ImageKind::Unknown => (),
+        kind => {
+            return Err(InstallError::BadImage.msg(format!(
+                "the rootfs image looks like {kind}, rather than an EROFS filesystem"
+            )))
+        }
+    }
+
+    // An uncompressed rootfs can be checked for damage before anything is erased; a compressed one
+    // relies on the decompressor noticing instead
+    let mut header = Vec::new();
+    rootfs.read_to_vec(&mut header, 8)?;
+    rootfs.rewind()?;
+    if image::Compression::detect(&header) == image::Compression::None {
+        let info = image::erofs_check(&mut rootfs).context("rootfs image is unusable")?;
+        if !info.complete {
+            return Err(InstallError::BadImage.msg(format!(
+                "rootfs image is truncated: it should be {} bytes long",
+                info.total_bytes
+            )));
+        }
+    }
 
-    // Locate the rootfs and bootloader to be written
-    let rootfs_size = image::erofs_size(&mut rootfs)?;
+    // The rootfs may be compressed, so it can only be read as a stream; make sure there's a
+    // filesystem in it before going any further
+    open_rootfs(&mut rootfs)?;
 
     // Define the UBI image
-    let ubi_volumes: Vec<Box<dyn Volume + '_>> = vec![
-        Box::new(
-            BasicVolume::new(VolType::Dynamic)
-                .id(0)
-                .name("uboot-env")
-                .size(65536),
-        ),
-        Box::new(
-            BasicVolume::new(VolType::Static)
-                .name("rootfs")
-                .skipcheck() // Opening the volume at boot takes ~10sec. longer without this flag
-                .size(rootfs_size)
-                .image(&mut rootfs),
-        ),
-    ];
+    layout.validate().class(InstallError::BadImage)?;
+    for name in [
+        STAMP_VOLUME_NAME,
+        FIRSTBOOT_VOLUME_NAME,
+        INSTALL_LOG_VOLUME_NAME,
+    ] {
+        anyhow::ensure!(
+            layout.volumes.iter().all(|x| x.name != name),
+            "volume name {name:?} is reserved for the installer"
+        );
+    }
+    anyhow::ensure!(
+        layout
+            .volumes
+            .iter()
+            .all(|x| x.id != Some(FIRSTBOOT_VOLUME_ID)),
+        "volume ID {FIRSTBOOT_VOLUME_ID} is reserved for {FIRSTBOOT_VOLUME_NAME:?}"
+    );
+    anyhow::ensure!(
+        layout.volumes.len() + 3 <= UBI_MAX_VOLUMES,
+        "too many volumes to leave room for {STAMP_VOLUME_NAME:?}, {FIRSTBOOT_VOLUME_NAME:?} and \
+         {INSTALL_LOG_VOLUME_NAME:?}"
+    );
+    if *layout != UbiLayoutSpec::default() {
+        log.print(format_args!("Using a custom UBI layout:\n{layout}"));
+    }
+
+    // The U-Boot environment, if there's one to write, needs a volume it fits in
+    let uboot_env = match hooks.uboot_env.take() {
+        Some(env) => {
+            let env = env.encode(UBOOT_ENV_SIZE, Some(ENV_FLAG_ACTIVE))?;
+            let volume = layout
+                .volumes
+                .iter()
+                .find(|x| x.name == UBOOT_ENV_VOLUME_NAME);
+            match volume.map(|x| x.size) {
+                Some(VolumeSize::Bytes(size)) if size >= env.len() as u64 => Some(env),
+                _ => {
+                    return Err(InstallError::BadImage.msg(format!(
+                        "the layout has no {UBOOT_ENV_VOLUME_NAME:?} volume of at least {} for \
+                         the U-Boot environment",
+                        format_size(env.len() as u64)
+                    )))
+                }
+            }
+        }
+        None => None,
+    };
+
+    // The bootloader is small, and needs reading several times: to compare with what's installed,
+    // to write and to verify. It may come compressed (e.g. in a bundle), but is always handled
+    // decompressed, so installing the same one again still writes nothing.
+    let mut bootloader_data = Vec::new();
+    image::open_maybe_compressed(&mut bootloader)?.read_to_end(&mut bootloader_data)?;
+    match image::detect(&mut io::Cursor::new(&bootloader_data))? {
+        ImageKind::SunxiBoot { .. } | ImageKind::Unknown => (),
+        kind => {
+            return Err(InstallError::BadImage.msg(format!(
+                "the bootloader image looks like {kind}, rather than an Allwinner boot image"
+            )))
+        }
+    }
+    let boot_bytes = nand_boot.get_layout().total_bytes();
+    if bootloader_data.len() as u64 > boot_bytes {
+        return Err(InstallError::FlashFull.msg(format!(
+            "the bootloader ({} bytes) doesn't fit in the boot partition ({boot_bytes} bytes)",
+            bootloader_data.len()
+        )));
+    }
+
+    // An SPL is written redundantly, with the payload where the SPL loads it from; make sure that
+    // all fits before the UBI partition is touched, rather than after
+    let boot_layout = hooks.boot_image_layout;
+    if raw::is_spl_image(&bootloader_data) {
+        let mut image = io::Cursor::new(&bootloader_data);
+        raw::check_boot_image(&nand_boot, &mut image, boot_layout).class(InstallError::BadImage)?;
+    }
 
     // These are the tasks to be run once the user confirms the operation:
-    struct TaskCtx<'a, N: Nand, R: Read> {
+    struct TaskCtx<'a, N: SharedNand, R: Read + Seek> {
         rpt: howudoin::Tx,
         nand_boot: N,
         nand_ubi: N,
         ebt: Option<ubi::Ebt>,
-        ubi_volumes: Vec<Box<dyn Volume + 'a>>,
-        bootloader: R,
+
+        /// The EBT as first scanned, for seeing what the installation changed
+        scanned_ebt: Option<ubi::Ebt>,
+
+        layout: &'a UbiLayoutSpec,
+
+        /// The rootfs image as given, which may be compressed
+        rootfs: R,
+
+        /// The digest of the rootfs as written, for the install stamp
+        rootfs_digest: Option<stamp::Digest>,
+
+        bootloader: Vec<u8>,
+
+        /// How to lay out the bootloader, if it's an SPL
+        boot_layout: raw::BootImageLayout,
+
+        force: bool,
+
+        /// The U-Boot environment to write, encoded
+        uboot_env: Option<Vec<u8>>,
+
+        report: InstallReport,
+        led_tx: mpsc::Sender<LedCommand>,
+        abort: Option<&'a AtomicBool>,
+
+        /// Which task is running, out of how many
+        task: (usize, usize),
     }
     type TaskFn<Ctx> = fn(&mut Ctx) -> anyhow::Result<()>;
-    let tasks: [(&str, TaskFn<TaskCtx<'_, _, _>>); 5] = [
+    let tasks: [(&str, TaskFn<TaskCtx<'_, _, _>>); 7] = [
         ("Purging boot0 code", |ctx| {
-            let purged = format::purge_boot0(&mut ctx.nand_boot)?;
-            if purged {
-                ctx.rpt
-                    .add_info("Legacy Allwinner boot code has been found and erased");
+            let stats = format::purge_boot0(&mut ctx.nand_boot)?;
+            if stats.purged() {
+                ctx.rpt.add_info(format!(
+                    "Legacy Allwinner boot code ({:?}) has been found and erased",
+                    stats.kinds()
+                ));
             }
+            ctx.report.boot0_purged = stats.purged();
             Ok(())
         }),
         ("Analyzing UBI partition", |ctx| {
-            let ebt = ubi::scan_blocks(&mut ctx.nand_ubi)?;
+            let (ebt, issues) =
+                ubi::scan_blocks_parallel_with_issues(&mut ctx.nand_ubi, SCAN_THREADS)?;
+            ctx.report.scan_issues = issues;
+            ctx.scanned_ebt = Some(ebt.clone());
             ctx.ebt = Some(ebt);
             Ok(())
         }),
+        ("Checking installed firmware", |ctx| {
+            let ebt = ctx.ebt.as_ref().unwrap();
+            if ctx.force || ctx.uboot_env.is_some() {
+                return Ok(());
+            }
+            let Some(installed) = stamp::read_stamp(&mut ctx.nand_ubi, ebt) else {
+                return Ok(());
+            };
+
+            // The rootfs is only worth reading through if everything else matches
+            let expected = InstallStamp::new(installed.rootfs, &ctx.bootloader, ctx.layout);
+            if installed != expected {
+                return Ok(());
+            }
+            let (_, image) = open_rootfs(&mut ctx.rootfs)?;
+            let digest = stamp::digest_image(image, leb_size(&ctx.nand_ubi))?;
+            if digest.digest != installed.rootfs {
+                return Ok(());
+            }
+
+            // The stamp says what was written, not that it's still intact
+            let intact = ctx.layout.image_volumes().all(|x| {
+                let volume = VolumeSelector::Name(x.name.clone());
+                stamp::spot_check(&mut ctx.nand_ubi, ebt, &volume, &digest, STAMP_SPOT_CHECKS)
+            }) && matches!(
+                verify_bootloader(&mut ctx.nand_boot, &ctx.bootloader, ctx.boot_layout)?,
+                RawVerifyResult::Match { .. }
+            );
+            if intact {
+                ctx.rpt
+                    .add_info("The NAND already has this firmware; nothing needs writing");
+                ctx.report.up_to_date = true;
+            } else {
+                ctx.rpt
+                    .add_info("The installed firmware has been damaged; reinstalling it");
+            }
+            Ok(())
+        }),
         ("Formatting UBI partition", |ctx| {
-            ubi::format(&mut ctx.nand_ubi, ctx.ebt.as_mut().unwrap())?;
+            let ebt = ctx.ebt.as_mut().unwrap();
+            let stats = match ubi::format_for_resume(&mut ctx.nand_ubi, ebt, ctx.abort) {
+                Ok(stats) => stats,
+                Err(error) => {
+                    // Only the blocks being worked on are in doubt after a NAND error; rescan
+                    // just those and give the format another go from where it stopped
+                    let Some(blocks) = error.downcast_ref().map(EbtError::blocks) else {
+                        return Err(error);
+                    };
+                    ctx.rpt.add_info(format!(
+                        "Retrying format after an error at block {}",
+                        blocks.start
+                    ));
+                    ubi::rescan_range(&mut ctx.nand_ubi, ebt, blocks)?;
+                    ubi::format_for_resume(&mut ctx.nand_ubi, ebt, ctx.abort)?
+                }
+            };
+            if stats.blocks_kept > 0 {
+                ctx.rpt.add_info(format!(
+                    "Resuming an interrupted installation: {} blocks may not need writing again",
+                    stats.blocks_kept
+                ));
+            }
+            ctx.report.migrated = stats.migrated;
+            ctx.report.fastmap_invalidated = stats.fastmap_invalidated;
+            ctx.report.ec_stats = stats.ec_stats;
+            if let Some(warning) = ctx.report.wear_warning() {
+                ctx.rpt.add_info(warning);
+            }
+            ctx.report.bad_blocks_found += stats.bad_blocks_found;
+            ctx.report.bad_blocks_marked += stats.bad_blocks_marked;
             Ok(())
         }),
         ("Writing rootfs", |ctx| {
-            ubi::write_volumes(
+            // Any volume filled from the rootfs after the first opens it again, rather than it
+            // being spilled into a temporary file that may not have room for it
+            let source = SharedReader::new(&mut ctx.rootfs);
+            let (rootfs_size, rootfs) = open_rootfs(source.clone())?;
+            let mut rootfs = DigestReader::new(rootfs);
+            let reopen: ReopenImage = Box::new(move || {
+                let (_, image) = open_rootfs(source.clone()).map_err(io::Error::other)?;
+                Ok(Box::new(image))
+            });
+            let uboot_env = ctx.uboot_env.as_deref();
+            let mut volumes =
+                get_ubi_volumes_with(ctx.layout, &mut rootfs, rootfs_size, Some(reopen), |name| {
+                    uboot_env.filter(|_| name == UBOOT_ENV_VOLUME_NAME)
+                });
+            // The first-boot record goes first, so that no volume given an ID automatically takes
+            // the one it needs
+            volumes.insert(0, firstboot_volume());
+            volumes.push(stamp::stamp_volume());
+            volumes.push(install_log_volume(leb_size(&ctx.nand_ubi)));
+
+            // This is the longest task by far, so the LEDs follow its progress too
+            let mut shown = None;
+            let stats = ubi::write_volumes_with_progress(
                 &mut ctx.nand_ubi,
                 ctx.ebt.as_mut().unwrap(),
-                ctx.ubi_volumes.split_off(0),
+                volumes,
+                // Spread the rootfs across the EC distribution, so UBI has no reason to move it
+                ubi::StripedPicker::default(),
+                |done, total| {
+                    let percent = install_progress(ctx.task, done, total);
+                    if shown.replace(percent) != Some(percent) {
+                        let _ = ctx.led_tx.send(LedCommand::Progress(percent));
+                    }
+                },
+                ctx.abort,
             )?;
+            ctx.rootfs_digest = Some(rootfs.digest());
+            ctx.report.bad_blocks_marked += stats.bad_blocks_marked;
+            ctx.report.pebs_written = stats.pebs_written;
+            ctx.report.pebs_kept = stats.pebs_kept;
+
+            let (scanned, ebt) = (ctx.scanned_ebt.take().unwrap(), ctx.ebt.as_ref().unwrap());
+            ctx.report.ubi_summary = Some(ubi::summarize(ebt));
+            ctx.report.ubi_changes = ubi::diff(&scanned, ebt);
             Ok(())
         }),
         ("Updating bootloader", |ctx| {
-            format::raw::write_raw_image(&mut ctx.nand_boot, &mut ctx.bootloader, false)?;
-            Ok(())
+            // An SPL can be written redundantly, so a bad block under it doesn't brick the board
+            let (nand, bootloader) = (&mut ctx.nand_boot, &ctx.bootloader);
+            let stats = if raw::is_spl_image(bootloader) {
+                raw::write_boot_image(nand, &mut io::Cursor::new(bootloader), ctx.boot_layout)?
+            } else {
+                let len = Some(bootloader.len() as u64);
+                raw::write_raw_image_sized(nand, &mut &bootloader[..], false, len)?
+            };
+            ctx.report.bad_blocks_marked += stats.bad_blocks_marked;
+            ctx.report.bootloader_bytes = stats.bytes;
+
+            // A corrupt boot partition bricks the board, so make sure before reporting success
+            match verify_bootloader(nand, bootloader, ctx.boot_layout)? {
+                RawVerifyResult::Match { .. } => Ok(()),
+                RawVerifyResult::Mismatch(mismatch) => {
+                    anyhow::bail!("Bootloader failed to verify after writing: {mismatch}")
+                }
+            }
+        }),
+        ("Writing install stamp", |ctx| {
+            let (rootfs, ebt) = (ctx.rootfs_digest.unwrap(), ctx.ebt.as_mut().unwrap());
+            let firstboot = Firstboot::new(rootfs);
+            firstboot.write(&mut ctx.nand_ubi, ebt)?;
+            ctx.report.firstboot = Some(firstboot);
+
+            let stamp = InstallStamp::new(rootfs, &ctx.bootloader, ctx.layout);
+            stamp::write_stamp(&mut ctx.nand_ubi, ebt, &stamp)
         }),
     ];
 
     // Ready...
-    let _ = led_tx.send(led::LED_READY);
+    let _ = led_tx.send(led::LED_READY.into());
 
-    pre_upgrade();
+    if let Some(hook) = hooks.before_destructive.take() {
+        hook();
+    }
 
     // ...go!
-    howudoin::init(howudoin::consumers::TermLine::default());
+    let kmsg = KmsgCursor::now();
+    let kernel_log = || {
+        kmsg.as_ref()
+            .map(|x| x.collect(&KmsgFilter::new(KMSG_DRIVERS), KMSG_MAX_LINES))
+            .unwrap_or_default()
+    };
+    let tee = log.clone();
+    progress::init_with_tee(move |line| tee.record(line));
     let rpt = howudoin::new()
         .label("Installing BMC firmware")
         .set_len(u64::try_from(tasks.len()).ok());
@@ -160,27 +1001,541 @@ pub fn upgrade_bmc(
         nand_boot,
         nand_ubi,
         ebt: None,
-        ubi_volumes,
-        bootloader,
+        scanned_ebt: None,
+        layout,
+        rootfs,
+        rootfs_digest: None,
+        bootloader: bootloader_data,
+        boot_layout,
+        force: hooks.force,
+        uboot_env,
+        report: InstallReport::default(),
+        led_tx: led_tx.clone(),
+        abort,
+        task: (0, tasks.len()),
     };
-    let _ = led_tx.send(led::LED_BUSY);
-    for (desc, task) in tasks {
+    for (index, (desc, task)) in tasks.into_iter().enumerate() {
         ctx.rpt.desc(desc);
         ctx.rpt.inc();
+        ctx.task.0 = index;
+        let _ = led_tx.send(LedCommand::Progress(install_progress(ctx.task, 0, 1)));
 
-        if let Err(error) = task(&mut ctx) {
+        if let Some(hook) = &mut hooks.before_task {
+            hook(desc);
+        }
+        let start = Instant::now();
+        let ops = ctx.nand_boot.stats() + ctx.nand_ubi.stats();
+        let result = match check_abort(ctx.abort) {
+            Ok(()) => task(&mut ctx),
+            Err(aborted) => Err(aborted.into()),
+        };
+        if let Some(hook) = &mut hooks.after_task {
+            let view = TaskView {
+                ebt: ctx.ebt.as_ref(),
+                report: &ctx.report,
+            };
+            hook(desc, &result, &view);
+        }
+        if let Err(error) = result {
             howudoin::disable();
             thread::sleep(Duration::from_millis(10)); // Give howudoin time to shut down
+
+            // The kernel log often says more about a NAND failure than the error itself does
+            let kernel_log = kernel_log();
+            if !kernel_log.is_empty() && !error.is::<Aborted>() {
+                log.print(format!("Kernel messages:\n{kernel_log}").trim_end());
+            }
+            *log_ubi = ctx.ebt.take().map(|ebt| (ctx.nand_ubi, ebt));
             return Err(error);
         }
+        ctx.report.task_durations.push((desc, start.elapsed()));
+        let ops = (ctx.nand_boot.stats() + ctx.nand_ubi.stats()).since(&ops);
+        ctx.report.task_ops.push((desc, ops));
+
+        if ctx.report.up_to_date {
+            break;
+        }
     }
 
     ctx.rpt.finish();
     howudoin::disable();
     thread::sleep(Duration::from_millis(10)); // Give howudoin time to shut down
-    let _ = led_tx.send(led::LED_DONE);
+    let done = match ctx.report.wear_warning() {
+        _ if ctx.report.up_to_date => led::LED_DONE_UP_TO_DATE,
+        Some(_) => led::LED_DONE_WARNING,
+        None => led::LED_DONE,
+    };
+    let _ = led_tx.send(done.into());
 
-    Ok(())
+    ctx.report.kernel_log = kernel_log();
+    if !ctx.report.kernel_log.is_empty() {
+        log.record(&format!("Kernel messages:\n{}", ctx.report.kernel_log));
+    }
+
+    // Writing the log would spoil the NAND being left untouched
+    if !ctx.report.up_to_date {
+        *log_ubi = ctx.ebt.take().map(|ebt| (ctx.nand_ubi, ebt));
+    }
+    Ok(ctx.report)
+}
+
+/// Save `log` to the best [LogSink] there is, trying the other if that fails, and say where it
+/// went. The SD card is only tried if `sdcard` is set, and the UBI partition only if `ubi` has the
+/// log volume.
+fn save_install_log<N: Nand>(log: &InstallLog, sdcard: bool, ubi: Option<(N, ubi::Ebt)>) {
+    let log = log.snapshot();
+    let mut sdcard = sdcard;
+    let mut ubi =
+        ubi.and_then(|(mut nand, ebt)| has_log_volume(&mut nand, &ebt).then_some((nand, ebt)));
+
+    while let Some(sink) = LogSink::choose(sdcard, ubi.is_some()) {
+        let result = match sink {
+            LogSink::SdCard => {
+                sdcard = false;
+                save_log_to_sdcard(&log.to_text(INSTALL_LOG_MAX_BYTES))
+            }
+            LogSink::UbiVolume => {
+                let (mut nand, mut ebt) = ubi.take().unwrap();
+                write_log_volume(&mut nand, &mut ebt, &log)
+            }
+        };
+        match result {
+            Ok(()) => {
+                eprintln!("The installation log has been saved to {sink}");
+                return;
+            }
+            Err(error) => eprintln!("Couldn't save the installation log to {sink}: {error:#}"),
+        }
+    }
+}
+
+/// Like [upgrade_bmc], but installing the bootloader and rootfs of a [Bundle]. The bundle is
+/// verified before anything else is done.
+///
+/// A layout section in the bundle takes the place of `layout`.
+pub fn upgrade_from_bundle<R: Read + Seek>(
+    bundle: &mut Bundle<R>,
+    hooks: UpgradeHooks,
+    layout: &UbiLayoutSpec,
+    led_tx: mpsc::Sender<LedCommand>,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<InstallReport> {
+    let _lock = InstallLock::acquire()?;
+    upgrade_from_bundle_with(&MtdPartitions, bundle, hooks, layout, led_tx, abort)
+}
+
+/// Like [upgrade_from_bundle], but installing onto the NAND partitions from `provider`
+pub fn upgrade_from_bundle_with<P: NandProvider, R: Read + Seek>(
+    provider: &P,
+    bundle: &mut Bundle<R>,
+    hooks: UpgradeHooks,
+    layout: &UbiLayoutSpec,
+    led_tx: mpsc::Sender<LedCommand>,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<InstallReport> {
+    bundle.verify().class(InstallError::BadImage)?;
+
+    let bundle_layout = match bundle.read_section(SectionKind::Layout)? {
+        Some(contents) => Some(
+            String::from_utf8(contents)?
+                .parse::<UbiLayoutSpec>()
+                .context("bundle layout")?,
+        ),
+        None => None,
+    };
+    if bundle.entry(SectionKind::Eeprom).is_some() {
+        eprintln!("The bundle's EEPROM contents are ignored; this installer doesn't write them");
+    }
+    let bootloader = bundle
+        .read_section(SectionKind::Bootloader)?
+        .ok_or(anyhow::anyhow!("bundle has no bootloader"))?;
+    let rootfs = bundle
+        .section(SectionKind::Rootfs)?
+        .ok_or(anyhow::anyhow!("bundle has no rootfs"))?;
+
+    upgrade_bmc_with(
+        provider,
+        rootfs,
+        &bootloader[..],
+        hooks,
+        bundle_layout.as_ref().unwrap_or(layout),
+        led_tx,
+        abort,
+    )
+}
+
+/// Reset the BMC to its factory settings without reinstalling it: the dynamic volumes of `layout`
+/// (such as `uboot-env`) are emptied, and the rootfs and everything else is left as it is.
+///
+/// Nothing is done while another installer instance holds the [InstallLock].
+pub fn factory_reset(layout: &UbiLayoutSpec) -> anyhow::Result<Vec<String>> {
+    let _lock = InstallLock::acquire()?;
+    factory_reset_with(&MtdPartitions, layout)
+}
+
+/// Like [factory_reset], but on the NAND partitions from `provider`. Returns the names of the
+/// volumes emptied.
+pub fn factory_reset_with<P: NandProvider>(
+    provider: &P,
+    layout: &UbiLayoutSpec,
+) -> anyhow::Result<Vec<String>> {
+    layout.validate().class(InstallError::BadImage)?;
+    let names: Vec<&str> = layout
+        .volumes
+        .iter()
+        .filter(|x| x.vol_type == ubi::VolType::Dynamic)
+        .map(|x| x.name.as_str())
+        .collect();
+
+    let (_, mut nand) = provider.open_partitions()?;
+    nand.ensure_writeable()?;
+    let mut ebt = ubi::scan_blocks_parallel(&mut nand, SCAN_THREADS)?;
+    ubi::reset_volumes(&mut nand, &mut ebt, &names)?;
+
+    Ok(names.into_iter().map(String::from).collect())
+}
+
+/// Scan the UBI partition, changing nothing, for an overview of the flash's health: how many of
+/// its blocks are bad, and how worn the rest are (see also [EC_WARN_THRESHOLD])
+pub fn flash_health() -> anyhow::Result<EbtSummary> {
+    flash_health_with(&MtdPartitions)
+}
+
+/// Like [flash_health], but on the NAND partitions from `provider`
+pub fn flash_health_with<P: NandProvider>(provider: &P) -> anyhow::Result<EbtSummary> {
+    let (_, mut nand) = provider.open_partitions()?;
+    let ebt = ubi::scan_blocks_parallel(&mut nand, SCAN_THREADS)?;
+    Ok(ubi::summarize(&ebt))
+}
+
+/// Burn in both NAND partitions (see [crate::nand::test]), going through the default patterns
+/// `passes` times; this destroys everything on them. Returns the reports for the `boot` and `ubi`
+/// partitions, in that order.
+pub fn burn_in_flash(passes: u32) -> anyhow::Result<[BurnInReport; 2]> {
+    let _lock = InstallLock::acquire()?;
+    burn_in_flash_with(&MtdPartitions, passes)
+}
+
+/// Like [burn_in_flash], but on the NAND partitions from `provider`
+pub fn burn_in_flash_with<P: NandProvider>(
+    provider: &P,
+    passes: u32,
+) -> anyhow::Result<[BurnInReport; 2]> {
+    let (mut boot, mut ubi) = provider.open_partitions()?;
+    let burn = |label: &str, nand: &mut P::Nand| {
+        let rpt = howudoin::new().label(format!("Burning in {label} partition"));
+        let report =
+            nand_test::burn_in(nand, passes, nand_test::DEFAULT_PATTERNS, |done, total| {
+                rpt.set_len(u64::from(total)).set_pos(u64::from(done));
+            });
+        rpt.close();
+        report.context(format!("burning in the {label} partition"))
+    };
+    Ok([burn("boot", &mut boot)?, burn("ubi", &mut ubi)?])
+}
+
+/// The kernel command line parameter asking for the NAND to be burned in (see [burn_in_flash])
+/// before anything else, as `bmc_installer.burn_in=PASSES`
+const BURN_IN_PARAMETER: &str = "bmc_installer.burn_in";
+
+/// How many passes of burning in a kernel command line asks for, if it asks for any
+pub fn burn_in_on_cmdline(cmdline: &str) -> anyhow::Result<Option<u32>> {
+    cmdline_parameter(cmdline, BURN_IN_PARAMETER)
+        .map(|passes| match passes.parse() {
+            Ok(0) | Err(_) => Err(anyhow::anyhow!(
+                "{BURN_IN_PARAMETER}: expected a number of passes, not {passes:?}"
+            )),
+            Ok(passes) => Ok(passes),
+        })
+        .transpose()
+}
+
+/// How many passes of burning in this kernel's command line asks for, if it asks for any
+pub fn burn_in_from_cmdline() -> anyhow::Result<Option<u32>> {
+    burn_in_on_cmdline(&fs::read_to_string("/proc/cmdline")?)
+}
+
+/// Open the rootfs image from the start, through a decompressor if need be, returning its size and
+/// a stream of exactly that much
+fn open_rootfs<'a>(mut rootfs: impl Read + Seek + 'a) -> anyhow::Result<(u64, impl Read + 'a)> {
+    rootfs.rewind()?;
+    let (size, image) = image::erofs_size_streaming(image::open_maybe_compressed(rootfs)?)?;
+    Ok((size, image.take(size)))
+}
+
+/// The LEB size that [ubi::write_volumes] lays the UBI partition out with
+fn leb_size(nand: &impl Nand) -> usize {
+    let layout = nand.get_layout();
+    layout.bytes_per_page * (layout.pages_per_block as usize - 2)
+}
+
+/// Check the boot partition against `bootloader`, which is laid out as `layout` says if it's an
+/// SPL, or else as a raw image
+fn verify_bootloader<N: Nand>(
+    nand: &mut N,
+    bootloader: &[u8],
+    layout: raw::BootImageLayout,
+) -> anyhow::Result<RawVerifyResult> {
+    if raw::is_spl_image(bootloader) {
+        raw::verify_boot_image(nand, &mut io::Cursor::new(bootloader), layout)
+    } else {
+        raw::verify_raw_image(nand, &mut &bootloader[..], false)
+    }
+}
+
+/// Work out how far through the installation we are, as a percentage, from which task is running
+/// (out of how many) and how far through its `total` steps that task is
+fn install_progress((task, tasks): (usize, usize), done: u32, total: u32) -> u8 {
+    let done = u64::from(done.min(total));
+    let total = u64::from(total.max(1));
+    let percent = (task as u64 * total + done) * 100 / (tasks.max(1) as u64 * total);
+
+    percent.min(100) as u8
+}
+
+/// The SD card's FAT partition, which may hold a layout file or firmware files
+const SDCARD_FAT_PATH: &str = "/dev/mmcblk0p1";
+
+/// Where [mount_sdcard_fat] mounts the FAT partition
+const SDCARD_MOUNT_PATH: &str = "/sdcard";
+
+/// Mount the SD card's FAT partition (read-only) at [SDCARD_MOUNT_PATH], returning whether there
+/// was one to mount
+fn mount_sdcard_fat() -> anyhow::Result<bool> {
+    let mount_path = Path::new(SDCARD_MOUNT_PATH);
+    if !mount_path.is_dir() {
+        fs::create_dir(mount_path)?;
+    }
+    let mounted = mount(
+        Some(SDCARD_FAT_PATH),
+        mount_path,
+        Some("vfat"),
+        MsFlags::MS_RDONLY,
+        None::<&str>,
+    );
+
+    Ok(mounted.is_ok())
+}
+
+/// Write `text` to [INSTALL_LOG_FILE_NAME] on the SD card's FAT partition, which is made writable
+/// for as long as that takes: remounted if it's mounted already (e.g. for firmware files to be read
+/// from it), or else mounted
+fn save_log_to_sdcard(text: &str) -> anyhow::Result<()> {
+    let mount_path = Path::new(SDCARD_MOUNT_PATH);
+    let remounted = mount(
+        None::<&str>,
+        mount_path,
+        None::<&str>,
+        MsFlags::MS_REMOUNT,
+        None::<&str>,
+    )
+    .is_ok();
+    if !remounted {
+        if !mount_path.is_dir() {
+            fs::create_dir(mount_path)?;
+        }
+        mount(
+            Some(SDCARD_FAT_PATH),
+            mount_path,
+            Some("vfat"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .context(SDCARD_FAT_PATH)?;
+    }
+
+    let written = fs::File::create(mount_path.join(INSTALL_LOG_FILE_NAME)).and_then(|mut file| {
+        file.write_all(text.as_bytes())?;
+        file.sync_all()
+    });
+    if remounted {
+        let flags = MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+        let _ = mount(None::<&str>, mount_path, None::<&str>, flags, None::<&str>);
+    } else {
+        let _ = umount(mount_path);
+    }
+    written.context(INSTALL_LOG_FILE_NAME)
+}
+
+/// Read the UBI layout from `/bmc-layout.conf` on the SD card's FAT partition, falling back on the
+/// default layout if there's no such partition or file
+pub fn read_layout_from_sdcard() -> anyhow::Result<UbiLayoutSpec> {
+    const LAYOUT_FILE: &str = "bmc-layout.conf";
+
+    if !mount_sdcard_fat()? {
+        return Ok(UbiLayoutSpec::default());
+    }
+
+    let mount_path = Path::new(SDCARD_MOUNT_PATH);
+    let contents = fs::read_to_string(mount_path.join(LAYOUT_FILE));
+    let _ = umount(mount_path);
+    match contents {
+        Ok(contents) => contents.parse().context(LAYOUT_FILE),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(UbiLayoutSpec::default()),
+        Err(e) => Err(e).context(LAYOUT_FILE),
+    }
+}
+
+/// Read the U-Boot environment from `/uboot-env.txt` on the SD card's FAT partition (see
+/// [uboot_env]), if there's such a file
+pub fn read_uboot_env_from_sdcard() -> anyhow::Result<Option<UbootEnv>> {
+    const UBOOT_ENV_FILE: &str = "uboot-env.txt";
+
+    if !mount_sdcard_fat()? {
+        return Ok(None);
+    }
+
+    let mount_path = Path::new(SDCARD_MOUNT_PATH);
+    let contents = fs::read_to_string(mount_path.join(UBOOT_ENV_FILE));
+    let _ = umount(mount_path);
+    match contents {
+        Ok(contents) => contents
+            .parse()
+            .map(Some)
+            .context(UBOOT_ENV_FILE)
+            .class(InstallError::BadImage),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context(UBOOT_ENV_FILE),
+    }
+}
+
+/// Is there a `/force-install` file on the SD card's FAT partition, asking for the firmware to be
+/// installed even if the NAND already has it?
+pub fn force_requested_on_sdcard() -> anyhow::Result<bool> {
+    const FORCE_FILE: &str = "force-install";
+
+    if !mount_sdcard_fat()? {
+        return Ok(false);
+    }
+
+    let mount_path = Path::new(SDCARD_MOUNT_PATH);
+    let force = mount_path.join(FORCE_FILE).exists();
+    let _ = umount(mount_path);
+    Ok(force)
+}
+
+/// What the SD card installer has been asked to do
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum InstallerMode {
+    /// Install the firmware
+    #[default]
+    Install,
+
+    /// Empty the volumes holding the BMC's settings, keeping the installed firmware (see
+    /// [factory_reset])
+    FactoryReset,
+}
+
+/// The kernel command line parameter choosing the [InstallerMode]
+const MODE_PARAMETER: &str = "bmc_installer.mode";
+
+impl std::str::FromStr for InstallerMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "install" => Ok(Self::Install),
+            "reset" => Ok(Self::FactoryReset),
+            other => anyhow::bail!("unknown installer mode {other:?} (expected install or reset)"),
+        }
+    }
+}
+
+impl InstallerMode {
+    /// The mode chosen on a kernel command line with `bmc_installer.mode=MODE`, the last one
+    /// counting; installing, if there's none
+    pub fn from_cmdline(cmdline: &str) -> anyhow::Result<Self> {
+        Ok(Self::chosen_on_cmdline(cmdline)?.unwrap_or_default())
+    }
+
+    /// Like [InstallerMode::from_cmdline], but None if the command line doesn't choose a mode
+    pub fn chosen_on_cmdline(cmdline: &str) -> anyhow::Result<Option<Self>> {
+        cmdline_parameter(cmdline, MODE_PARAMETER)
+            .map(|mode| mode.parse().context(MODE_PARAMETER))
+            .transpose()
+    }
+}
+
+/// The [InstallerMode] chosen on this kernel's command line, if it chooses one
+pub fn mode_from_cmdline() -> anyhow::Result<Option<InstallerMode>> {
+    InstallerMode::chosen_on_cmdline(&fs::read_to_string("/proc/cmdline")?)
+}
+
+/// A bundle on the SD card's FAT partition, holding everything to install
+pub const SDCARD_BUNDLE_FILE: &str = "install.tpbundle";
+
+/// A rootfs image on the SD card's FAT partition, to install in place of the rootfs partition's
+pub const SDCARD_ROOTFS_FILE: &str = "tp2-rootfs.erofs";
+
+/// A bootloader image on the SD card's FAT partition, to install in place of the one at the
+/// start of the card
+pub const SDCARD_BOOTLOADER_FILE: &str = "tp2-bootloader.bin";
+
+/// Firmware found as files on the SD card's FAT partition
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FatFirmware {
+    /// A bundle ([SDCARD_BUNDLE_FILE])
+    Bundle(PathBuf),
+
+    /// Separate images ([SDCARD_BOOTLOADER_FILE] and [SDCARD_ROOTFS_FILE])
+    Files {
+        bootloader: PathBuf,
+        rootfs: PathBuf,
+    },
+}
+
+impl FatFirmware {
+    /// Look for firmware in `dir`, the FAT partition's mountpoint: a bundle, which comes first, or
+    /// else both separate images. None means there's neither, and the card's raw partitions are
+    /// to be installed instead; just one of the separate images is an error.
+    pub fn find(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let bundle = dir.join(SDCARD_BUNDLE_FILE);
+        if bundle.is_file() {
+            return Ok(Some(Self::Bundle(bundle)));
+        }
+
+        let bootloader = dir.join(SDCARD_BOOTLOADER_FILE);
+        let rootfs = dir.join(SDCARD_ROOTFS_FILE);
+        match (bootloader.is_file(), rootfs.is_file()) {
+            (true, true) => Ok(Some(Self::Files { bootloader, rootfs })),
+            (false, false) => Ok(None),
+            (true, false) => Err(InstallError::BadImage.msg(format!(
+                "found {SDCARD_BOOTLOADER_FILE}, but not {SDCARD_ROOTFS_FILE} to go with it"
+            ))),
+            (false, true) => Err(InstallError::BadImage.msg(format!(
+                "found {SDCARD_ROOTFS_FILE}, but not {SDCARD_BOOTLOADER_FILE} to go with it"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for FatFirmware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bundle(path) => write!(f, "the bundle {}", path.display()),
+            Self::Files { bootloader, rootfs } => write!(
+                f,
+                "the bootloader {} and the rootfs {}",
+                bootloader.display(),
+                rootfs.display()
+            ),
+        }
+    }
+}
+
+/// Look for firmware files on the SD card's FAT partition (see [FatFirmware::find]). The partition
+/// stays mounted if any are found, for them to be opened.
+pub fn find_firmware_on_sdcard() -> anyhow::Result<Option<FatFirmware>> {
+    if !mount_sdcard_fat()? {
+        return Ok(None);
+    }
+
+    let mount_path = Path::new(SDCARD_MOUNT_PATH);
+    let firmware = FatFirmware::find(mount_path);
+    if !matches!(firmware, Ok(Some(_))) {
+        let _ = umount(mount_path);
+    }
+    firmware
 }
 
 /// Locate the rootfs and bootloader to be written from a fixed partitioned SDcard layout
@@ -194,17 +1549,941 @@ pub fn read_from_sdcard() -> anyhow::Result<(impl Read + Debug, impl Read + Seek
     const BOOTLOADER_SIZE: u64 = 6 * 64 * 2048;
     const BOOTLOADER_OFFSET: u64 = 8192; // Boot ROM expects this offset, so it will never change
 
+    // The card may take a moment to show up after boot
     let rootfs = retry(Fixed::from_millis(100).take(10), || {
         fs::File::open(ROOTFS_PATH)
     })
-    .context(ROOTFS_PATH)?;
+    .context(ROOTFS_PATH)
+    .class(InstallError::Timeout)?;
 
     let mut bootloader = retry(Fixed::from_millis(100).take(10), || {
         fs::File::open(BOOTLOADER_PATH)
     })
-    .context(BOOTLOADER_PATH)?;
+    .context(BOOTLOADER_PATH)
+    .class(InstallError::Timeout)?;
 
     bootloader.seek(io::SeekFrom::Start(BOOTLOADER_OFFSET))?;
     let bootloader = bootloader.take(BOOTLOADER_SIZE);
     Ok((bootloader, rootfs))
 }
+
+/// Fresh NAND, standing in for the `boot` and `ubi` MTD partitions
+#[cfg(test)]
+struct SimPartitions {
+    boot: crate::nand::SimNand,
+    ubi: crate::nand::SimNand,
+}
+
+#[cfg(test)]
+impl SimPartitions {
+    fn new() -> Self {
+        let layout = crate::nand::NandLayout {
+            blocks: 64,
+            pages_per_block: 16,
+            bytes_per_page: 512,
+        };
+        Self::with_layouts(layout, layout)
+    }
+
+    fn with_layouts(boot: crate::nand::NandLayout, ubi: crate::nand::NandLayout) -> Self {
+        Self {
+            boot: crate::nand::SimNand::new(boot),
+            ubi: crate::nand::SimNand::new(ubi),
+        }
+    }
+}
+
+#[cfg(test)]
+impl NandProvider for SimPartitions {
+    type Nand = crate::nand::SimNand;
+
+    fn open_partitions(&self) -> anyhow::Result<(Self::Nand, Self::Nand)> {
+        Ok((self.boot.clone_handle()?, self.ubi.clone_handle()?))
+    }
+}
+
+/// A compressed rootfs, which skips the checks that need a real filesystem in the image
+#[cfg(test)]
+fn test_rootfs() -> anyhow::Result<Vec<u8>> {
+    test_erofs_rootfs(3)
+}
+
+/// Like [test_rootfs], but of `blocks` EROFS blocks
+#[cfg(test)]
+fn test_erofs_rootfs(blocks: u32) -> anyhow::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut rootfs = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+    rootfs.write_all(&image::test_erofs_image(blocks))?;
+    Ok(rootfs.finish()?)
+}
+
+#[test]
+fn test_upgrade_hooks() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+
+    use std::cell::RefCell;
+    use std::sync::atomic::Ordering;
+
+    let rootfs = test_rootfs()?;
+    let bootloader = synthetic_data(8192);
+
+    let run = |abort_after: Option<&str>| {
+        let log = RefCell::new(vec![]);
+        let abort = AtomicBool::new(false);
+        let hooks = UpgradeHooks::default()
+            .before_destructive(|| log.borrow_mut().push("before destructive".to_string()))
+            .before_task(|desc| log.borrow_mut().push(format!("before {desc}")))
+            .after_task(|desc, result, view| {
+                let ebt = if view.ebt.is_some() { "EBT" } else { "no EBT" };
+                let ok = if result.is_ok() { "ok" } else { "failed" };
+                log.borrow_mut().push(format!("after {desc}: {ok}, {ebt}"));
+                if abort_after == Some(desc) {
+                    abort.store(true, Ordering::Relaxed);
+                }
+            });
+        let result = upgrade_bmc_with(
+            &SimPartitions::new(),
+            io::Cursor::new(&rootfs),
+            &bootloader[..],
+            hooks,
+            &UbiLayoutSpec::default(),
+            mpsc::channel().0,
+            Some(&abort),
+        );
+        (result, log.into_inner())
+    };
+
+    let (result, log) = run(None);
+    let report = result?;
+    assert_eq!(report.bootloader_bytes, bootloader.len() as u64);
+    assert_eq!(
+        log,
+        [
+            "before destructive",
+            "before Purging boot0 code",
+            "after Purging boot0 code: ok, no EBT",
+            "before Analyzing UBI partition",
+            "after Analyzing UBI partition: ok, EBT",
+            "before Checking installed firmware",
+            "after Checking installed firmware: ok, EBT",
+            "before Formatting UBI partition",
+            "after Formatting UBI partition: ok, EBT",
+            "before Writing rootfs",
+            "after Writing rootfs: ok, EBT",
+            "before Updating bootloader",
+            "after Updating bootloader: ok, EBT",
+            "before Writing install stamp",
+            "after Writing install stamp: ok, EBT",
+        ]
+    );
+
+    // A failed task is still followed by its hook, and ends the installation
+    let (result, log) = run(Some("Analyzing UBI partition"));
+    assert!(result.unwrap_err().is::<Aborted>());
+    assert_eq!(
+        log[3..],
+        [
+            "before Analyzing UBI partition",
+            "after Analyzing UBI partition: ok, EBT",
+            "before Checking installed firmware",
+            "after Checking installed firmware: failed, EBT",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_upgrade_end_to_end() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+
+    let boot_layout = NandLayout {
+        blocks: 16,
+        pages_per_block: 32,
+        bytes_per_page: 1024,
+    };
+    let ubi_layout = NandLayout {
+        blocks: 96,
+        ..boot_layout
+    };
+    let parts = SimPartitions::with_layouts(boot_layout, ubi_layout);
+    let erofs = image::test_erofs_image(40);
+    let rootfs = test_erofs_rootfs(40)?;
+    let bootloader = synthetic_data(20_000);
+
+    let (led_tx, led_rx) = mpsc::channel();
+    let report = upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(&rootfs),
+        &bootloader[..],
+        UpgradeHooks::default(),
+        &UbiLayoutSpec::default(),
+        led_tx,
+        None,
+    )?;
+
+    // The LEDs go from ready, through progress that only ever goes up, to done
+    let leds: Vec<_> = led_rx.try_iter().collect();
+    assert_eq!(leds.first(), Some(&LedCommand::from(led::LED_READY)));
+    assert_eq!(leds.last(), Some(&LedCommand::from(led::LED_DONE)));
+    let progress: Vec<_> = leds
+        .iter()
+        .filter_map(|x| match x {
+            LedCommand::Progress(percent) => Some(*percent),
+            _ => None,
+        })
+        .collect();
+    assert!(progress.windows(2).all(|x| x[0] <= x[1]));
+
+    let leb_size = 30 * 1024;
+    assert_eq!(report.task_durations.len(), 7);
+    assert_eq!(report.bootloader_bytes, bootloader.len() as u64);
+    assert_eq!(
+        report.pebs_written[&1],
+        erofs.len().div_ceil(leb_size) as u32
+    );
+    assert_eq!((report.bad_blocks_found, report.bad_blocks_marked), (0, 0));
+
+    // Every operation on the NAND is put down to one task or another, but for saving the log
+    // afterwards, which programs one LEB; analyzing only reads
+    let (mut boot, mut nand_ubi) = parts.open_partitions()?;
+    let ops = report
+        .task_ops
+        .iter()
+        .fold(OpStats::default(), |x, y| x + y.1);
+    let saving_log = (boot.stats() + nand_ubi.stats()).since(&ops);
+    assert_eq!(saving_log.erases + saving_log.mark_bads, 0);
+    assert!(saving_log.bytes_programmed <= nand_ubi.get_layout().block_bytes() as u64);
+    let (_, analyzing) = report.task_ops[1];
+    assert!(analyzing.reads >= u64::from(nand_ubi.get_layout().blocks));
+    assert_eq!(analyzing.programs + analyzing.erases, 0);
+
+    // The UBI partition has the rootfs, decompressed, and a stamp saying so
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    let table = ubi::read_volume_table(&mut nand_ubi, &ebt)?;
+    let names: Vec<_> = table.iter().flatten().map(|x| x.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "uboot-env",
+            "rootfs",
+            layout::DATA_VOLUME_NAME,
+            STAMP_VOLUME_NAME,
+            INSTALL_LOG_VOLUME_NAME,
+            FIRSTBOOT_VOLUME_NAME
+        ]
+    );
+    let mut out = vec![];
+    let volume = VolumeSelector::Name("rootfs".into());
+    ubi::read_volume(&mut nand_ubi, &ebt, &volume, &mut out)?;
+    assert!(out == erofs);
+    let expected = InstallStamp::new(
+        stamp::Digest::of(&erofs),
+        &bootloader,
+        &UbiLayoutSpec::default(),
+    );
+    assert_eq!(stamp::read_stamp(&mut nand_ubi, &ebt), Some(expected));
+
+    // ...and a record of the install, pending the first boot, with the ID U-Boot expects
+    let record = table[FIRSTBOOT_VOLUME_ID as usize].as_ref().unwrap();
+    assert_eq!(record.name, FIRSTBOOT_VOLUME_NAME);
+    let firstboot = Firstboot::read(&mut nand_ubi, &ebt).unwrap();
+    assert!(firstboot.pending);
+    assert_eq!(firstboot.rootfs, stamp::Digest::of(&erofs));
+    assert_eq!(firstboot.installer_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(report.firstboot, Some(firstboot));
+
+    // ...and the log of the installation
+    let log = install_log::read_log_volume(&mut nand_ubi, &ebt)?.unwrap();
+    assert!(BANNER.lines().all(|x| log.contains(x)), "{log}");
+    assert!(log.contains(&crate::buildinfo::summary()), "{log}");
+
+    // ...and the boot partition has the bootloader
+    assert!(matches!(
+        verify_bootloader(&mut boot, &bootloader, Default::default())?,
+        RawVerifyResult::Match { .. }
+    ));
+
+    // Images given in each other's place are called out as such, before anything is written
+    let mut spl = bootloader.clone();
+    spl[0x04..0x18].copy_from_slice(b"eGON.BT0\0\0\0\0\x20\x4e\0\0SPL\x02");
+    for (rootfs, bootloader, slot) in [
+        (&spl, &bootloader, "rootfs"),
+        (&rootfs, &rootfs, "bootloader"),
+    ] {
+        let parts = SimPartitions::new();
+        let error = upgrade_bmc_with(
+            &parts,
+            io::Cursor::new(rootfs),
+            &bootloader[..],
+            UpgradeHooks::default(),
+            &UbiLayoutSpec::default(),
+            mpsc::channel().0,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(InstallError::of(&error), InstallError::BadImage);
+        assert!(
+            error
+                .to_string()
+                .starts_with(&format!("the {slot} image looks like")),
+            "{error}"
+        );
+        let (boot, nand_ubi) = parts.open_partitions()?;
+        let ops = boot.stats() + nand_ubi.stats();
+        assert_eq!(ops.programs + ops.erases, 0);
+    }
+
+    // Blocks that fail to read don't stop the installation, but are reported
+    let mut parts = SimPartitions::with_layouts(boot_layout, ubi_layout);
+    parts.ubi.set_read_failures(5, 1)?;
+    parts.ubi.set_read_failures(7, u32::MAX)?;
+    let report = upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(&rootfs),
+        &bootloader[..],
+        UpgradeHooks::default(),
+        &UbiLayoutSpec::default(),
+        mpsc::channel().0,
+        None,
+    )?;
+    assert_eq!(report.scan_issues.flaky, [5]);
+    assert_eq!(report.scan_issues.unreadable, [7]);
+    assert!(report.to_string().contains("Unreadable blocks: 1 block(s)"));
+
+    // The unreadable block erases, so it was never bad
+    assert_eq!(report.bad_blocks_found, 0);
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    assert!(nand_ubi.block(7)?.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_upgrade_spl_bootloader() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+    use crate::nand::PageUtil;
+
+    // The BMC's boot partition: 4 MiB, in blocks of 128 KiB
+    let boot_layout = NandLayout {
+        blocks: 32,
+        pages_per_block: 64,
+        bytes_per_page: 2048,
+    };
+    let ubi_layout = SimPartitions::new().ubi.get_layout();
+    let mut parts = SimPartitions::with_layouts(boot_layout, ubi_layout);
+    let block_size = boot_layout.block_bytes();
+
+    // A 0x6000-byte SPL, and the payload at 0x8000 in the image
+    let mut bootloader = synthetic_data(0x8000 + 300_000);
+    bootloader[0x04..0x18].copy_from_slice(b"eGON.BT0\0\0\0\0\0\x60\0\0SPL\x02");
+    let (spl, payload) = (&bootloader[..0x6000], &bootloader[0x8000..]);
+
+    // A bad block under the second SPL copy, and under the first copy of the payload
+    parts.boot.block(1)?.unwrap().mark_bad()?;
+    parts
+        .boot
+        .block(0x100000 / block_size as u32 + 1)?
+        .unwrap()
+        .mark_bad()?;
+
+    let report = upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(test_rootfs()?),
+        &bootloader[..],
+        UpgradeHooks::default(),
+        &UbiLayoutSpec::default(),
+        mpsc::channel().0,
+        None,
+    )?;
+    assert_eq!(report.bootloader_bytes, (spl.len() + payload.len()) as u64);
+
+    // The SPL copies stayed where the Boot ROM probes for them, and the payload is where the SPL
+    // loads it from: at the redundant offset, since the first copy couldn't be written
+    let (mut boot, _) = parts.open_partitions()?;
+    let mut flash = vec![];
+    raw::read_raw_image(&mut boot, &mut flash, true, None)?;
+    for copy in [0, 2, 3] {
+        let start = copy * block_size;
+        assert!(flash[start..start + spl.len()] == *spl, "SPL copy {copy}");
+    }
+    assert!(flash[0x100000..0x100000 + block_size].is_erased());
+    assert!(flash[0x200000..0x200000 + payload.len()] == *payload);
+
+    // A payload that doesn't fit where the SPL loads it from is refused before anything is written
+    let parts = SimPartitions::with_layouts(
+        NandLayout {
+            blocks: 18,
+            ..boot_layout
+        },
+        ubi_layout,
+    );
+    let error = upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(test_rootfs()?),
+        &bootloader[..],
+        UpgradeHooks::default(),
+        &UbiLayoutSpec::default(),
+        mpsc::channel().0,
+        None,
+    )
+    .unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::BadImage);
+    let (boot, nand_ubi) = parts.open_partitions()?;
+    let ops = boot.stats() + nand_ubi.stats();
+    assert_eq!(ops.programs + ops.erases, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_upgrade_from_bundle() -> anyhow::Result<()> {
+    use crate::bundle::write_bundle;
+    use crate::fixtures::synthetic_data;
+
+    let rootfs = test_rootfs()?;
+    let bootloader = synthetic_data(8192);
+    let layout = "uboot-env dynamic 64KiB 0\nrootfs static image -\ndata dynamic 32KiB -\n";
+    let mut image = vec![];
+    write_bundle(
+        &mut image,
+        &[
+            (SectionKind::Bootloader, &bootloader),
+            (SectionKind::Rootfs, &rootfs),
+            (SectionKind::Layout, layout.as_bytes()),
+        ],
+    )?;
+
+    let parts = SimPartitions::new();
+    let mut bundle = Bundle::open(io::Cursor::new(&image))?;
+    let report = upgrade_from_bundle_with(
+        &parts,
+        &mut bundle,
+        UpgradeHooks::default(),
+        &UbiLayoutSpec::default(),
+        mpsc::channel().0,
+        None,
+    )?;
+    assert_eq!(report.bootloader_bytes, bootloader.len() as u64);
+
+    // The bundle's layout was used over the one passed in
+    let (mut boot, mut nand_ubi) = parts.open_partitions()?;
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    let table = ubi::read_volume_table(&mut nand_ubi, &ebt)?;
+    let names: Vec<_> = table.iter().flatten().map(|x| x.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "uboot-env",
+            "rootfs",
+            "data",
+            STAMP_VOLUME_NAME,
+            INSTALL_LOG_VOLUME_NAME,
+            FIRSTBOOT_VOLUME_NAME
+        ]
+    );
+    assert!(matches!(
+        raw::verify_raw_image(&mut boot, &mut &bootloader[..], false)?,
+        RawVerifyResult::Match { .. }
+    ));
+
+    // A corrupt bundle is refused before anything is written
+    let mut corrupt = image.clone();
+    let end = corrupt.len() - 1;
+    corrupt[end] ^= 1;
+    let parts = SimPartitions::new();
+    let error = upgrade_from_bundle_with(
+        &parts,
+        &mut Bundle::open(io::Cursor::new(&corrupt))?,
+        UpgradeHooks::default(),
+        &UbiLayoutSpec::default(),
+        mpsc::channel().0,
+        None,
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("corrupt"));
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    assert!(ebt.iter().all(|x| *x == ubi::BlockContent::Erased));
+
+    Ok(())
+}
+
+#[test]
+fn test_upgrade_up_to_date() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+
+    let rootfs = test_rootfs()?;
+    let bootloader = synthetic_data(8192);
+    let parts = SimPartitions::new();
+    let install = |rootfs: &[u8], hooks: UpgradeHooks| {
+        upgrade_bmc_with(
+            &parts,
+            io::Cursor::new(rootfs),
+            &bootloader[..],
+            hooks,
+            &UbiLayoutSpec::default(),
+            mpsc::channel().0,
+            None,
+        )
+    };
+    let snapshot = || -> anyhow::Result<Vec<u8>> {
+        let (mut boot, mut ubi) = parts.open_partitions()?;
+        let mut image = vec![];
+        boot.save(&mut image)?;
+        ubi.save(&mut image)?;
+        Ok(image)
+    };
+
+    let report = install(&rootfs, UpgradeHooks::default())?;
+    assert!(!report.up_to_date);
+    let installed = snapshot()?;
+
+    // Installing the same firmware again stops short of writing anything
+    let report = install(&rootfs, UpgradeHooks::default())?;
+    assert!(report.up_to_date);
+    assert_eq!(report.bootloader_bytes, 0);
+    let tasks: Vec<_> = report.task_durations.iter().map(|x| x.0).collect();
+    assert_eq!(tasks.last(), Some(&"Checking installed firmware"));
+    assert!(snapshot()? == installed);
+
+    // ...unless forced to
+    let report = install(&rootfs, UpgradeHooks::default().force())?;
+    assert!(!report.up_to_date);
+    assert_eq!(report.bootloader_bytes, bootloader.len() as u64);
+    assert!(snapshot()? != installed);
+    assert!(install(&rootfs, UpgradeHooks::default())?.up_to_date);
+
+    // Damage to the installed rootfs is found, and repaired
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    let rootfs_leb = ebt
+        .iter()
+        .position(|x| matches!(x, ubi::BlockContent::EcData(_, Some(vid)) if vid.vol_id == 1))
+        .unwrap() as u32;
+    nand_ubi.block(rootfs_leb)?.unwrap().erase()?;
+    assert!(!install(&rootfs, UpgradeHooks::default())?.up_to_date);
+    assert!(install(&rootfs, UpgradeHooks::default())?.up_to_date);
+
+    // Different firmware is installed as usual
+    let other = test_erofs_rootfs(4)?;
+    assert!(!install(&other, UpgradeHooks::default())?.up_to_date);
+    assert!(install(&other, UpgradeHooks::default())?.up_to_date);
+
+    // A U-Boot environment is always written, as the stamp can't say whether it already is
+    let env = uboot_env::UbootEnv::new().var("bootdelay", "0")?;
+    let report = install(&other, UpgradeHooks::default().uboot_env(env.clone()))?;
+    assert!(!report.up_to_date);
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    let mut volume = vec![];
+    let selector = VolumeSelector::Name(UBOOT_ENV_VOLUME_NAME.into());
+    ubi::read_volume(&mut nand_ubi, &ebt, &selector, &mut volume)?;
+    // A dynamic volume reads back in whole LEBs
+    volume.truncate(UBOOT_ENV_SIZE);
+    assert_eq!(
+        UbootEnv::decode(&volume, true),
+        Some((env.clone(), Some(ENV_FLAG_ACTIVE)))
+    );
+
+    // ...so long as the layout has room for it
+    let layout: UbiLayoutSpec =
+        "uboot-env dynamic 4KiB 0\nrootfs static image - skipcheck".parse()?;
+    let error = upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(&other),
+        &bootloader[..],
+        UpgradeHooks::default().uboot_env(env),
+        &layout,
+        mpsc::channel().0,
+        None,
+    )
+    .unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::BadImage);
+
+    Ok(())
+}
+
+#[test]
+fn test_upgrade_ab_rootfs() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+
+    // Both copies of a compressed rootfs are installed whole, the second decompressed again
+    let parts = SimPartitions::new();
+    let layout: UbiLayoutSpec = "
+        uboot-env  dynamic  64KiB  0
+        rootfs_a   static   image  -   skipcheck
+        rootfs_b   static   image  -   skipcheck
+    "
+    .parse()?;
+    upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(test_rootfs()?),
+        &synthetic_data(8192)[..],
+        UpgradeHooks::default(),
+        &layout,
+        mpsc::channel().0,
+        None,
+    )?;
+
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    for name in ["rootfs_a", "rootfs_b"] {
+        let mut out = vec![];
+        ubi::read_volume(&mut nand_ubi, &ebt, &name.parse()?, &mut out)?;
+        assert!(out == image::test_erofs_image(3), "{name}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_factory_reset() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+
+    let parts = SimPartitions::new();
+    let rootfs = test_rootfs()?;
+    let layout = UbiLayoutSpec::default();
+    upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(&rootfs),
+        &synthetic_data(8192)[..],
+        UpgradeHooks::default(),
+        &layout,
+        mpsc::channel().0,
+        None,
+    )?;
+
+    // The BMC saves some settings...
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    let mut ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    let env = VolumeSelector::Name("uboot-env".into());
+    ubi::write_leb(&mut nand_ubi, &mut ebt, &env, 0, b"bootdelay=0")?;
+    let mut installed_rootfs = vec![];
+    ubi::read_volume(
+        &mut nand_ubi,
+        &ebt,
+        &"rootfs".parse()?,
+        &mut installed_rootfs,
+    )?;
+
+    // ...which a factory reset throws away, keeping the firmware and the records of its install
+    assert_eq!(
+        factory_reset_with(&parts, &layout)?,
+        ["uboot-env", layout::DATA_VOLUME_NAME]
+    );
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    assert_eq!(ubi::read_volume_leb(&mut nand_ubi, &ebt, &env, 0)?, None);
+    let mut out = vec![];
+    ubi::read_volume(&mut nand_ubi, &ebt, &"rootfs".parse()?, &mut out)?;
+    assert!(out == installed_rootfs);
+    assert!(stamp::read_stamp(&mut nand_ubi, &ebt).is_some());
+    assert!(Firstboot::read(&mut nand_ubi, &ebt).is_some());
+
+    // Looking over the flash's health changes nothing
+    let health = flash_health_with(&parts)?;
+    assert_eq!(ubi::scan_blocks(&mut nand_ubi)?, ebt);
+    assert_eq!(health, ubi::summarize(&ebt));
+    assert!(health.ec.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_installer_mode() -> anyhow::Result<()> {
+    let cmdline = "console=ttyS0,115200 root=/dev/ram0";
+    assert_eq!(
+        InstallerMode::from_cmdline(cmdline)?,
+        InstallerMode::Install
+    );
+    let cmdline = "console=ttyS0 bmc_installer.mode=reset quiet";
+    assert_eq!(
+        InstallerMode::from_cmdline(cmdline)?,
+        InstallerMode::FactoryReset
+    );
+    let cmdline = "bmc_installer.mode=reset bmc_installer.mode=install";
+    assert_eq!(
+        InstallerMode::from_cmdline(cmdline)?,
+        InstallerMode::Install
+    );
+    assert!(InstallerMode::from_cmdline("bmc_installer.mode=wipe").is_err());
+
+    // Whether a mode was chosen at all can be told apart
+    assert_eq!(
+        InstallerMode::chosen_on_cmdline("console=ttyS0 root=/dev/ram0")?,
+        None
+    );
+    assert_eq!(
+        InstallerMode::chosen_on_cmdline("bmc_installer.mode=install")?,
+        Some(InstallerMode::Install)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_burn_in_flash() -> anyhow::Result<()> {
+    let parts = SimPartitions::new();
+    parts.ubi.clone_handle()?.set_erase_failure(9, true)?;
+    let [boot, ubi] = burn_in_flash_with(&parts, 1)?;
+    assert_eq!((boot.blocks.len(), ubi.blocks.len()), (64, 64));
+    assert!(boot.marked_bad().is_empty());
+    assert_eq!(ubi.marked_bad(), [9]);
+
+    // Which leaves the NAND ready to install onto
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    let ebt = ubi::scan_blocks(&mut nand_ubi)?;
+    assert_eq!(ebt[9], ubi::BlockContent::Bad);
+    assert_eq!(
+        ebt.iter()
+            .filter(|x| **x == ubi::BlockContent::Erased)
+            .count(),
+        63
+    );
+
+    assert_eq!(burn_in_on_cmdline("console=ttyS0 quiet")?, None);
+    assert_eq!(burn_in_on_cmdline("bmc_installer.burn_in=3")?, Some(3));
+    for cmdline in ["bmc_installer.burn_in=0", "bmc_installer.burn_in=yes"] {
+        assert!(burn_in_on_cmdline(cmdline).is_err(), "{cmdline}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_nands_on_cmdline() -> anyhow::Result<()> {
+    assert_eq!(
+        nands_on_cmdline("console=ttyS0 root=/dev/ram0")?,
+        (None, None)
+    );
+
+    let cmdline =
+        "bmc_installer.ubi_nand=mtd-name:rootfs quiet bmc_installer.boot_nand=mtd:/dev/mtd4";
+    assert_eq!(
+        nands_on_cmdline(cmdline)?,
+        (
+            Some(NandSpec::MtdDev("/dev/mtd4".into())),
+            Some(NandSpec::MtdName("rootfs".into()))
+        )
+    );
+
+    // The last one counts, and a parameter only matches by its whole name
+    let cmdline = "bmc_installer.ubi_nand=mtd:/dev/mtd1 bmc_installer.ubi_nand=mtd:/dev/mtd2 \
+                   bmc_installer.ubi_nand_x=mtd:/dev/mtd3";
+    assert_eq!(
+        nands_on_cmdline(cmdline)?.1,
+        Some(NandSpec::MtdDev("/dev/mtd2".into()))
+    );
+
+    // Only MTD devices are installed onto
+    for cmdline in [
+        "bmc_installer.boot_nand=sim:?layout=32x64x2048",
+        "bmc_installer.ubi_nand=ubi",
+    ] {
+        let error = nands_on_cmdline(cmdline).unwrap_err();
+        assert!(format!("{error:#}").contains("_nand"), "{error:#}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_partition_geometry() {
+    let layout = |bytes: u64| NandLayout {
+        blocks: (bytes >> 17) as u32,
+        pages_per_block: 64,
+        bytes_per_page: 2048,
+    };
+    let geometry = |name: &str, offset, size| MtdGeometry {
+        name: Some(name.into()),
+        offset,
+        size,
+    };
+    let check = |boot: &MtdGeometry, ubi: &MtdGeometry| {
+        check_partition_geometry(
+            (boot, layout(boot.size.unwrap_or(4 << 20))),
+            (ubi, layout(ubi.size.unwrap_or(124 << 20))),
+        )
+    };
+    let boot = geometry("boot", Some(0), Some(4 << 20));
+    let ubi = geometry("ubi", Some(4 << 20), Some(124 << 20));
+    check(&boot, &ubi).unwrap();
+
+    // Older kernels don't say where partitions start, which leaves less to check
+    let unplaced = |x: &MtdGeometry| MtdGeometry {
+        offset: None,
+        ..x.clone()
+    };
+    check(&unplaced(&boot), &unplaced(&ubi)).unwrap();
+    check(&MtdGeometry::default(), &MtdGeometry::default()).unwrap();
+
+    let wrong = |boot: &MtdGeometry, ubi: &MtdGeometry, what: &str| {
+        let error = check(boot, ubi).unwrap_err();
+        assert_eq!(InstallError::of(&error), InstallError::UnsafeTarget);
+        let message = format!("{error:#}");
+        assert!(message.contains(what), "{message}");
+        assert!(message.contains(r#"ubi is "ubi" at"#), "{message}");
+    };
+    wrong(
+        &geometry("boot", Some(0x20000), Some(4 << 20)),
+        &ubi,
+        "boot starts at 0x20000",
+    );
+    wrong(
+        &geometry("boot", Some(0), Some(512 << 10)),
+        &ubi,
+        "boot is 512KiB",
+    );
+    wrong(
+        &geometry("boot", Some(0), Some(32 << 20)),
+        &ubi,
+        "outside 1MiB to 16MiB",
+    );
+    wrong(
+        &boot,
+        &geometry("ubi", Some(0), Some(128 << 20)),
+        "they overlap",
+    );
+    wrong(
+        &boot,
+        &geometry("ubi", Some(2 << 20), Some(124 << 20)),
+        "they overlap",
+    );
+
+    // The device opened must be the size sysfs says the partition is
+    let error = check_partition_geometry((&boot, layout(4 << 20)), (&ubi, layout(128 << 20)));
+    let message = format!("{:#}", error.unwrap_err());
+    assert!(
+        message.contains("ubi is 0x7c00000 bytes, but its device holds 0x8000000"),
+        "{message}"
+    );
+}
+
+#[test]
+fn test_carved_partitions() {
+    let boot_layout = NandLayout {
+        blocks: 32,
+        pages_per_block: 64,
+        bytes_per_page: 2048,
+    };
+    let whole = MtdGeometry {
+        name: Some("spi0.0".into()),
+        offset: Some(0),
+        size: Some(128 << 20),
+    };
+    let (boot, ubi) = carved_geometry(&whole, boot_layout);
+    assert_eq!(boot.to_string(), r#""boot" at 0x0, 0x400000 bytes"#);
+    assert_eq!(ubi.to_string(), r#""ubi" at 0x400000, 0x7c00000 bytes"#);
+
+    // A "whole" device that's really a partition further along the chip isn't carved up
+    let whole = MtdGeometry {
+        offset: Some(4 << 20),
+        ..whole
+    };
+    let (boot, ubi) = carved_geometry(&whole, boot_layout);
+    let ubi_layout = NandLayout {
+        blocks: 992,
+        ..boot_layout
+    };
+    let error = check_partition_geometry((&boot, boot_layout), (&ubi, ubi_layout)).unwrap_err();
+    assert!(
+        format!("{error:#}").contains("boot starts at 0x400000"),
+        "{error:#}"
+    );
+
+    // Only partitions that aren't there at all are a reason to carve
+    assert!(mtd_missing(
+        &InstallError::MtdNotFound.msg("MTD device \"ubi\" could not be found")
+    ));
+    let error = anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound)).context("/dev/mtd3");
+    assert!(mtd_missing(&error));
+    let error = anyhow::Error::new(io::Error::from(io::ErrorKind::PermissionDenied));
+    assert!(!mtd_missing(&error.context("/dev/mtd3")));
+    assert!(!mtd_missing(&anyhow::anyhow!(
+        "another installer instance is running: /dev/mtd3 is in use"
+    )));
+}
+
+#[test]
+fn test_upgrade_over_boot_code() -> anyhow::Result<()> {
+    use crate::fixtures::synthetic_data;
+
+    // The `ubi` partition is over the bootloader, as with a damaged device tree
+    let parts = SimPartitions::new();
+    let mut page = synthetic_data(512);
+    page[0x04..0x14].copy_from_slice(b"eGON.BT0\0\0\0\0\x20\x4e\0\0");
+    page[0x14..0x18].copy_from_slice(b"SPL\x02");
+    let (_, mut nand_ubi) = parts.open_partitions()?;
+    nand_ubi.block(0)?.unwrap().program(0, &page)?;
+    let ops = parts.boot.stats() + nand_ubi.stats();
+
+    let error = upgrade_bmc_with(
+        &parts,
+        io::Cursor::new(test_rootfs()?),
+        &synthetic_data(8192)[..],
+        UpgradeHooks::default(),
+        &UbiLayoutSpec::default(),
+        mpsc::channel().0,
+        None,
+    )
+    .unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::UnsafeTarget);
+    assert!(
+        format!("{error:#}").contains("starts with boot code"),
+        "{error:#}"
+    );
+
+    // Nothing was touched
+    let ops = (parts.boot.stats() + nand_ubi.stats()).since(&ops);
+    assert_eq!((ops.erases, ops.programs), (0, 0));
+
+    // A bad first block is passed over for the next
+    let mut parts = SimPartitions::new();
+    parts.ubi.block(0)?.unwrap().mark_bad()?;
+    parts.ubi.block(1)?.unwrap().program(0, &page)?;
+    let mut nand_ubi = parts.ubi.clone_handle()?;
+    assert!(check_not_boot_code(&mut nand_ubi).is_err());
+    parts.ubi.block(1)?.unwrap().erase()?;
+    check_not_boot_code(&mut nand_ubi)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_fat_firmware() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("fat-firmware-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let found = || FatFirmware::find(&dir);
+
+    // Nothing there leaves the raw partitions to be installed
+    assert_eq!(found()?, None);
+
+    // One image alone is a mistake worth telling the user about
+    fs::write(dir.join(SDCARD_ROOTFS_FILE), b"rootfs")?;
+    let error = found().unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::BadImage);
+    assert!(error.to_string().contains(SDCARD_BOOTLOADER_FILE));
+
+    // Both are used together
+    fs::write(dir.join(SDCARD_BOOTLOADER_FILE), b"bootloader")?;
+    let files = FatFirmware::Files {
+        bootloader: dir.join(SDCARD_BOOTLOADER_FILE),
+        rootfs: dir.join(SDCARD_ROOTFS_FILE),
+    };
+    assert_eq!(found()?, Some(files.clone()));
+    assert!(files.to_string().contains(SDCARD_ROOTFS_FILE));
+
+    // A bundle wins over them, but only as a file
+    fs::create_dir(dir.join(SDCARD_BUNDLE_FILE))?;
+    assert_eq!(found()?, Some(files));
+    fs::remove_dir(dir.join(SDCARD_BUNDLE_FILE))?;
+    fs::write(dir.join(SDCARD_BUNDLE_FILE), b"bundle")?;
+    assert_eq!(
+        found()?,
+        Some(FatFirmware::Bundle(dir.join(SDCARD_BUNDLE_FILE)))
+    );
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
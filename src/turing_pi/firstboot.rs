@@ -0,0 +1,215 @@
+//! A record of a fresh installation, kept in a small UBI volume of its own, so that the boot flow
+//! (U-Boot, then the BMC firmware) can tell the first boot after an installation from any other.
+//!
+//! The record says which installer wrote it, when (if the clock could be trusted), and what rootfs
+//! was installed, along with a flag saying the first boot is still pending. Whatever carries out
+//! the one-time actions of the first boot is expected to clear the flag, by rewriting the record.
+//!
+//! The record is a fixed-size structure, in little-endian order:
+//!
+//! | Offset | Size | Field                                                       |
+//! |--------|------|-------------------------------------------------------------|
+//! | 0      | 8    | Magic: `TPFIRSTB`                                           |
+//! | 8      | 4    | Format version: 1                                           |
+//! | 12     | 4    | Flags: bit 0 is set while the first boot is pending         |
+//! | 16     | 8    | Install time, in seconds since the Unix epoch; 0 if unknown |
+//! | 24     | 8    | Length of the rootfs image, decompressed                    |
+//! | 32     | 4    | CRC32 of the rootfs image, decompressed                     |
+//! | 36     | 1    | Length of the installer version                             |
+//! | 37     | 31   | Installer version, padded with zeroes                       |
+//! | 68     | 4    | CRC32 of everything before it                               |
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::nand::Nand;
+use crate::ubi::{
+    read_volume_leb,
+    ubinize::{BasicVolume, Volume},
+    write_leb, Ebt, VolType, VolumeSelector,
+};
+
+use super::stamp::Digest;
+
+/// The name of the UBI volume holding the record
+pub const FIRSTBOOT_VOLUME_NAME: &str = "firstboot";
+
+/// The ID of the UBI volume holding the record, which is always the same so that U-Boot can find
+/// it without looking the name up
+pub const FIRSTBOOT_VOLUME_ID: u32 = 7;
+
+const FIRSTBOOT_MAGIC: &[u8; 8] = b"TPFIRSTB";
+const FIRSTBOOT_FORMAT_VERSION: u32 = 1;
+const FIRSTBOOT_FLAG_PENDING: u32 = 1 << 0;
+const FIRSTBOOT_VERSION_SIZE: usize = 31;
+const FIRSTBOOT_SIZE: usize = 37 + FIRSTBOOT_VERSION_SIZE + 4;
+const FIRSTBOOT_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// The earliest install time believed; a clock reading before this means the clock was never set
+/// (from the RTC or otherwise)
+const EARLIEST_TRUSTED_TIME: Duration = Duration::from_secs(1_704_067_200); // 2024-01-01
+
+/// What an installation leaves for the first boot to find
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Firstboot {
+    /// The version of the installer that wrote the record
+    pub installer_version: String,
+
+    /// When the installation was done, in seconds since the Unix epoch, if the clock was set
+    pub installed_at: Option<u64>,
+
+    /// The rootfs image, decompressed
+    pub rootfs: Digest,
+
+    /// Is the first boot after the installation still to come?
+    pub pending: bool,
+}
+
+impl Firstboot {
+    /// The record of an installation of `rootfs` by this installer, now
+    pub fn new(rootfs: Digest) -> Self {
+        let installed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .filter(|&x| x >= EARLIEST_TRUSTED_TIME)
+            .map(|x| x.as_secs());
+
+        Self {
+            installer_version: env!("CARGO_PKG_VERSION").into(),
+            installed_at,
+            rootfs,
+            pending: true,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let version = self.installer_version.as_bytes();
+        let version = &version[..version.len().min(FIRSTBOOT_VERSION_SIZE)];
+        let flags = match self.pending {
+            true => FIRSTBOOT_FLAG_PENDING,
+            false => 0,
+        };
+
+        let mut out = FIRSTBOOT_MAGIC.to_vec();
+        out.extend(FIRSTBOOT_FORMAT_VERSION.to_le_bytes());
+        out.extend(flags.to_le_bytes());
+        out.extend(self.installed_at.unwrap_or(0).to_le_bytes());
+        out.extend(self.rootfs.len.to_le_bytes());
+        out.extend(self.rootfs.crc.to_le_bytes());
+        out.push(version.len() as u8);
+        out.extend(version);
+        out.resize(FIRSTBOOT_SIZE - 4, 0);
+        out.extend(FIRSTBOOT_CRC.checksum(&out).to_le_bytes());
+        out
+    }
+
+    /// Decode a record from the start of `bytes`, if there's an intact one there in a format
+    /// version this understands
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..FIRSTBOOT_SIZE)?;
+        let (body, crc) = bytes.split_at(FIRSTBOOT_SIZE - 4);
+        if !body.starts_with(FIRSTBOOT_MAGIC) || FIRSTBOOT_CRC.checksum(body).to_le_bytes() != crc {
+            return None;
+        }
+
+        let u32_at = |x: usize| u32::from_le_bytes(body[x..x + 4].try_into().unwrap());
+        let u64_at = |x: usize| u64::from_le_bytes(body[x..x + 8].try_into().unwrap());
+        if u32_at(8) != FIRSTBOOT_FORMAT_VERSION {
+            return None;
+        }
+        let version = body.get(37..37 + body[36] as usize)?;
+
+        Some(Self {
+            installer_version: String::from_utf8_lossy(version).into(),
+            installed_at: Some(u64_at(16)).filter(|&x| x != 0),
+            rootfs: Digest {
+                len: u64_at(24),
+                crc: u32_at(32),
+            },
+            pending: u32_at(12) & FIRSTBOOT_FLAG_PENDING != 0,
+        })
+    }
+
+    /// Read the record off the UBI partition, if there's an intact one
+    pub fn read<N: Nand>(nand: &mut N, ebt: &Ebt) -> Option<Self> {
+        let volume = VolumeSelector::Id(FIRSTBOOT_VOLUME_ID);
+        let leb = read_volume_leb(nand, ebt, &volume, 0).ok()??;
+        Self::decode(&leb)
+    }
+
+    /// Write the record into its volume, which must have been installed with [firstboot_volume]
+    pub fn write<N: Nand>(&self, nand: &mut N, ebt: &mut Ebt) -> anyhow::Result<()> {
+        let volume = VolumeSelector::Id(FIRSTBOOT_VOLUME_ID);
+        write_leb(nand, ebt, &volume, 0, &self.encode())
+    }
+}
+
+impl fmt::Display for Firstboot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "installed by {} at {}, rootfs {} bytes (CRC {:08x}), first boot {}",
+            self.installer_version,
+            self.installed_at
+                .map_or("an unknown time".into(), |x| format!("{x}s past the epoch")),
+            self.rootfs.len,
+            self.rootfs.crc,
+            match self.pending {
+                true => "pending",
+                false => "done",
+            }
+        )
+    }
+}
+
+/// The (empty) volume for the record to be written into, to install along with the others
+pub fn firstboot_volume<'a>() -> Box<dyn Volume + 'a> {
+    Box::new(
+        BasicVolume::new(VolType::Dynamic)
+            .id(FIRSTBOOT_VOLUME_ID)
+            .name(FIRSTBOOT_VOLUME_NAME)
+            .size(FIRSTBOOT_SIZE as u64),
+    )
+}
+
+#[test]
+fn test_firstboot_encoding() {
+    let record = Firstboot {
+        installer_version: "1.2.3".into(),
+        installed_at: Some(1_750_000_000),
+        rootfs: Digest::of(b"rootfs"),
+        pending: true,
+    };
+    let mut bytes = record.encode();
+    assert_eq!(bytes.len(), FIRSTBOOT_SIZE);
+
+    // Whatever pads out the rest of the LEB is ignored, but damage to the record isn't
+    bytes.resize(512, 0xFF);
+    assert_eq!(Firstboot::decode(&bytes), Some(record.clone()));
+    bytes[20] ^= 1;
+    assert_eq!(Firstboot::decode(&bytes), None);
+    assert_eq!(Firstboot::decode(&[0xFF; 512]), None);
+
+    // Nor is a format version this doesn't know
+    let mut bytes = record.encode();
+    bytes[8] = 2;
+    let crc = FIRSTBOOT_CRC.checksum(&bytes[..FIRSTBOOT_SIZE - 4]);
+    bytes[FIRSTBOOT_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    assert_eq!(Firstboot::decode(&bytes), None);
+
+    // An unknown time and an overlong version survive, as far as they can
+    let record = Firstboot {
+        installer_version: "x".repeat(40),
+        installed_at: None,
+        pending: false,
+        ..record
+    };
+    let decoded = Firstboot::decode(&record.encode()).unwrap();
+    assert_eq!(
+        decoded.installer_version,
+        "x".repeat(FIRSTBOOT_VERSION_SIZE)
+    );
+    assert_eq!((decoded.installed_at, decoded.pending), (None, false));
+}
@@ -0,0 +1,323 @@
+//! A log of each installation, kept for a post-mortem: by the time anyone asks why an installation
+//! failed in the field, whatever it said on the serial console is usually long gone.
+//!
+//! Everything [upgrade_bmc](super::upgrade_bmc) prints, every progress message and the error it
+//! fails with (if it does) go into an [InstallLog], a line at a time, stamped with the time since
+//! boot:
+//!
+//! ```text
+//! [   12.345678] Installing BMC firmware: Formatting UBI partition
+//! ```
+//!
+//! Only the newest [INSTALL_LOG_MAX_BYTES] are kept. Once the installation is over, however it
+//! ended, the log is saved to the best [LogSink] there is: a file on the SD card's FAT partition,
+//! or else a dynamic UBI volume of one LEB, [INSTALL_LOG_VOLUME_NAME].
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::nand::Nand;
+use crate::ubi::{
+    read_volume_leb, read_volume_table,
+    ubinize::{BasicVolume, Volume},
+    write_leb, Ebt, VolType, VolumeSelector,
+};
+
+/// The name of the UBI volume holding the log of the last installation
+pub const INSTALL_LOG_VOLUME_NAME: &str = "install-log";
+
+/// The name of the file holding the log on the SD card's FAT partition
+pub const INSTALL_LOG_FILE_NAME: &str = "install-log.txt";
+
+/// The most of the log kept in memory; what's saved is cut down further to fit where it goes
+pub const INSTALL_LOG_MAX_BYTES: usize = 64 << 10;
+
+/// The newest lines of a log, up to a limit in bytes
+#[derive(Debug, Clone)]
+pub struct LogBuffer {
+    lines: VecDeque<String>,
+
+    /// The bytes the lines take up, newlines included
+    bytes: usize,
+    max_bytes: usize,
+
+    /// How many lines have been dropped to make room
+    dropped: u64,
+}
+
+impl LogBuffer {
+    /// An empty log of at most `max_bytes`
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            dropped: 0,
+        }
+    }
+
+    /// Add a line (without its newline), dropping the oldest ones to make room for it; a line too
+    /// long to ever fit is cut short
+    pub fn push(&mut self, line: impl Into<String>) {
+        let mut line = line.into();
+        if line.len() >= self.max_bytes {
+            let mut end = self.max_bytes.saturating_sub(1);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+
+        while self.bytes + line.len() + 1 > self.max_bytes {
+            let Some(oldest) = self.lines.pop_front() else {
+                return;
+            };
+            self.bytes -= oldest.len() + 1;
+            self.dropped += 1;
+        }
+        self.bytes += line.len() + 1;
+        self.lines.push_back(line);
+    }
+
+    /// The lines held, oldest first
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// How many lines have been dropped to make room for newer ones
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The log as text, keeping only as many of the newest lines as fit in `max_bytes`, after a
+    /// note of how many came before them
+    pub fn to_text(&self, max_bytes: usize) -> String {
+        let note = |dropped: u64| format!("[{dropped} earlier lines dropped]\n");
+        let mut bytes = 0;
+        let mut kept = 0;
+        for line in self.lines.iter().rev() {
+            let dropped = self.dropped + (self.lines.len() - kept - 1) as u64;
+            let note_len = match dropped {
+                0 => 0,
+                _ => note(dropped).len(),
+            };
+            if bytes + line.len() + 1 + note_len > max_bytes {
+                break;
+            }
+            bytes += line.len() + 1;
+            kept += 1;
+        }
+
+        let dropped = self.dropped + (self.lines.len() - kept) as u64;
+        let mut text = match dropped {
+            0 => String::new(),
+            _ => note(dropped),
+        };
+        for line in self.lines.iter().skip(self.lines.len() - kept) {
+            text.push_str(line);
+            text.push('\n');
+        }
+
+        // There may not even be room for the note
+        if text.len() > max_bytes {
+            text.clear();
+        }
+        text
+    }
+}
+
+/// The log of an installation in progress, which can be shared (e.g. with the thread showing
+/// progress)
+#[derive(Debug, Clone)]
+pub struct InstallLog {
+    buffer: Arc<Mutex<LogBuffer>>,
+
+    /// When the log was started, for stamping lines if the time since boot isn't known
+    started: Instant,
+}
+
+impl InstallLog {
+    pub fn new() -> Self {
+        Self {
+            buffer: Arc::new(Mutex::new(LogBuffer::new(INSTALL_LOG_MAX_BYTES))),
+            started: Instant::now(),
+        }
+    }
+
+    /// Print `text` to stderr, as `eprintln!` does, and add it to the log
+    pub fn print(&self, text: impl fmt::Display) {
+        let text = text.to_string();
+        eprintln!("{text}");
+        self.record(&text);
+    }
+
+    /// Add `text` to the log, a line at a time, without printing it
+    pub fn record(&self, text: &str) {
+        let time = since_boot().unwrap_or_else(|| self.started.elapsed());
+        let mut buffer = self.buffer.lock().unwrap_or_else(PoisonError::into_inner);
+        for line in text.lines() {
+            buffer.push(format!(
+                "[{:5}.{:06}] {line}",
+                time.as_secs(),
+                time.subsec_micros()
+            ));
+        }
+    }
+
+    /// What the log holds so far
+    pub fn snapshot(&self) -> LogBuffer {
+        self.buffer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Default for InstallLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How long it's been since boot, if the kernel says
+fn since_boot() -> Option<Duration> {
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    let seconds: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Somewhere to save the log
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LogSink {
+    /// [INSTALL_LOG_FILE_NAME] on the SD card's FAT partition
+    SdCard,
+
+    /// The [INSTALL_LOG_VOLUME_NAME] volume of the UBI partition
+    UbiVolume,
+}
+
+impl LogSink {
+    /// Where the log would best be saved, given whether the SD card can be written to and whether
+    /// the UBI partition has a log volume that can be; the SD card is easier to get at
+    pub fn choose(sdcard: bool, ubi_volume: bool) -> Option<Self> {
+        match (sdcard, ubi_volume) {
+            (true, _) => Some(Self::SdCard),
+            (false, true) => Some(Self::UbiVolume),
+            (false, false) => None,
+        }
+    }
+}
+
+impl fmt::Display for LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SdCard => write!(f, "{INSTALL_LOG_FILE_NAME} on the microSD card"),
+            Self::UbiVolume => write!(f, "the {INSTALL_LOG_VOLUME_NAME:?} UBI volume"),
+        }
+    }
+}
+
+/// The (empty) volume for the log to be written into, one LEB of `leb_size` bytes, to install along
+/// with the others
+pub fn install_log_volume<'a>(leb_size: usize) -> Box<dyn Volume + 'a> {
+    Box::new(
+        BasicVolume::new(VolType::Dynamic)
+            .name(INSTALL_LOG_VOLUME_NAME)
+            .size(leb_size as u64),
+    )
+}
+
+/// Does the UBI partition have the volume for the log, going by its volume table?
+pub fn has_log_volume<N: Nand>(nand: &mut N, ebt: &Ebt) -> bool {
+    read_volume_table(nand, ebt).is_ok_and(|table| {
+        table
+            .iter()
+            .flatten()
+            .any(|x| x.name == INSTALL_LOG_VOLUME_NAME)
+    })
+}
+
+/// Write `log` into its volume, which must have been installed with [install_log_volume], keeping
+/// as much of its end as fits
+pub fn write_log_volume<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    log: &LogBuffer,
+) -> anyhow::Result<()> {
+    let text = log.to_text(super::leb_size(nand));
+    let volume = VolumeSelector::Name(INSTALL_LOG_VOLUME_NAME.into());
+    write_leb(nand, ebt, &volume, 0, text.as_bytes())
+}
+
+/// Read the log of the last installation back from its volume, if there's one
+pub fn read_log_volume<N: Nand>(nand: &mut N, ebt: &Ebt) -> anyhow::Result<Option<String>> {
+    let volume = VolumeSelector::Name(INSTALL_LOG_VOLUME_NAME.into());
+    let Some(mut data) = read_volume_leb(nand, ebt, &volume, 0)? else {
+        return Ok(None);
+    };
+
+    // The text is padded out with erased bytes, which never turn up in UTF-8
+    let len = data.iter().rposition(|&x| x != 0xFF).map_or(0, |x| x + 1);
+    data.truncate(len);
+    Ok(Some(String::from_utf8_lossy(&data).into()))
+}
+
+#[test]
+fn test_log_buffer() {
+    let mut log = LogBuffer::new(32);
+    for line in ["one", "two", "three"] {
+        log.push(line);
+    }
+    assert_eq!(log.to_text(100), "one\ntwo\nthree\n");
+
+    // The oldest lines make room for the new ones
+    log.push("x".repeat(25));
+    assert_eq!(log.lines().collect::<Vec<_>>(), ["three", &"x".repeat(25)]);
+    assert_eq!(log.dropped(), 2);
+    assert!(log.lines().map(|x| x.len() + 1).sum::<usize>() <= 32);
+
+    // A line too long for the whole buffer is cut short, at a character boundary
+    log.push(format!("{}é", "y".repeat(30)));
+    assert_eq!(log.lines().collect::<Vec<_>>(), ["y".repeat(30)]);
+    assert_eq!(log.dropped(), 4);
+
+    // Text cut down further says how much was left out
+    let mut log = LogBuffer::new(1000);
+    for i in 0..10 {
+        log.push(format!("line {i}"));
+    }
+    assert_eq!(log.to_text(1000).lines().count(), 10);
+    let text = log.to_text(50);
+    assert!(text.len() <= 50, "{text:?}");
+    assert_eq!(text, "[7 earlier lines dropped]\nline 7\nline 8\nline 9\n");
+    assert_eq!(log.to_text(10), "");
+    assert_eq!(LogBuffer::new(10).to_text(10), "");
+}
+
+#[test]
+fn test_install_log() {
+    let log = InstallLog::new();
+    let shared = log.clone();
+    shared.record("first\nsecond");
+    log.record("third");
+
+    // Each line is stamped with the time
+    let lines: Vec<_> = log.snapshot().lines().map(String::from).collect();
+    assert_eq!(lines.len(), 3);
+    for (line, text) in lines.iter().zip(["first", "second", "third"]) {
+        let (stamp, rest) = line.split_once("] ").unwrap();
+        assert_eq!(rest, text);
+        assert!(stamp.starts_with('[') && stamp.contains('.'), "{line:?}");
+    }
+}
+
+#[test]
+fn test_log_sink() {
+    assert_eq!(LogSink::choose(true, true), Some(LogSink::SdCard));
+    assert_eq!(LogSink::choose(true, false), Some(LogSink::SdCard));
+    assert_eq!(LogSink::choose(false, true), Some(LogSink::UbiVolume));
+    assert_eq!(LogSink::choose(false, false), None);
+}
@@ -0,0 +1,178 @@
+//! Monitoring of the Turing Pi 2's keys: the front panel's POWER and RESET buttons, and KEY1 on the
+//! board itself, all of which report through the same evdev device.
+
+use std::os::unix::io::AsRawFd;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use evdev::{raw_stream::RawDevice, InputEventKind, Key};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+
+const KEYS_EVDEV_PATH: &str = "/dev/input/event0";
+
+/// How long a key has to be held down to abort an installation
+pub const ABORT_HOLD_TIME: Duration = Duration::from_secs(5);
+
+/// Monitor for a key being pressed three times
+pub fn confirm_keypress(stop_flag: &AtomicBool) -> bool {
+    const KEYPRESS_TIMEOUT: Duration = Duration::from_millis(500);
+    const KEYPRESS_TIMES: u8 = 3;
+
+    let mut device = match RawDevice::open(KEYS_EVDEV_PATH) {
+        Ok(device) => device,
+        Err(_) => return false,
+    };
+
+    let mut last_key = None;
+    let mut last_time = None;
+    let mut times_pressed = 0;
+
+    loop {
+        if stop_flag.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let events = match device.fetch_events() {
+            Ok(events) => events,
+            Err(_) => return false,
+        };
+
+        for event in events {
+            // Only follow key events
+            let key = match event.kind() {
+                InputEventKind::Key(key) => key,
+                _ => continue,
+            };
+
+            // All keypresses have to be the same key; start over if the user switched keys
+            if last_key != Some(key) {
+                last_key = Some(key);
+                times_pressed = 0;
+            }
+
+            // Only handle key-up events past this point
+            if event.value() != 0 {
+                continue;
+            }
+
+            // Determine how long has passed since the last key-up event (or None)
+            let timestamp = event.timestamp();
+            let time_elapsed = last_time
+                .replace(timestamp)
+                .and_then(|x| timestamp.duration_since(x).ok());
+
+            // If past the timeout (or None), start over
+            if time_elapsed.is_none_or(|x| x > KEYPRESS_TIMEOUT) {
+                times_pressed = 0;
+            }
+
+            times_pressed += 1;
+            if times_pressed >= KEYPRESS_TIMES {
+                return true;
+            }
+        }
+    }
+}
+
+/// Keeps track of which key, if any, is being held down
+#[derive(Debug, Default)]
+struct HoldTracker {
+    /// The key being held, and when it went down
+    held: Option<(Key, SystemTime)>,
+}
+
+impl HoldTracker {
+    /// Follow a key event, whose `value` is 1 for key-down, 0 for key-up, or 2 for autorepeat
+    fn key_event(&mut self, key: Key, value: i32, timestamp: SystemTime) {
+        match value {
+            1 => self.held = Some((key, timestamp)),
+            0 if self.held.is_some_and(|(held, _)| held == key) => self.held = None,
+            _ => (),
+        }
+    }
+
+    /// How much longer the held key needs holding, as of `now`; None if no key is held
+    fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        let (_, since) = self.held?;
+        let elapsed = now.duration_since(since).unwrap_or_default();
+        Some(ABORT_HOLD_TIME.saturating_sub(elapsed))
+    }
+}
+
+/// Wait for any key to be held down for [ABORT_HOLD_TIME]; returns false if the keys can't be
+/// monitored
+fn wait_for_hold() -> bool {
+    let mut device = match RawDevice::open(KEYS_EVDEV_PATH) {
+        Ok(device) => device,
+        Err(_) => return false,
+    };
+    let mut tracker = HoldTracker::default();
+
+    loop {
+        // A held key doesn't send anything more, so wake up once it would have been held long
+        // enough
+        let timeout = match tracker.remaining(SystemTime::now()) {
+            Some(remaining) if remaining.is_zero() => return true,
+            Some(remaining) => remaining.as_millis() as i32 + 1,
+            None => -1,
+        };
+
+        let mut fds = [PollFd::new(device.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => (),
+            Err(_) => return false,
+        }
+
+        let events = match device.fetch_events() {
+            Ok(events) => events,
+            Err(_) => return false,
+        };
+        for event in events {
+            if let InputEventKind::Key(key) = event.kind() {
+                tracker.key_event(key, event.value(), event.timestamp());
+            }
+        }
+    }
+}
+
+/// Watch the keys in the background, raising `abort` once any of them is held down for
+/// [ABORT_HOLD_TIME].
+///
+/// If the keys can't be monitored, the watcher gives up quietly and `abort` is never raised.
+pub fn abort_watcher_thread(abort: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        if wait_for_hold() {
+            abort.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+#[test]
+fn test_hold_tracker() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    let at = |ms| start + Duration::from_millis(ms);
+
+    let mut tracker = HoldTracker::default();
+    assert_eq!(tracker.remaining(at(0)), None);
+
+    // Pressing and releasing a key starts and stops the clock
+    tracker.key_event(Key::KEY_POWER, 1, at(0));
+    assert_eq!(tracker.remaining(at(2000)), Some(Duration::from_secs(3)));
+    tracker.key_event(Key::KEY_POWER, 0, at(2500));
+    assert_eq!(tracker.remaining(at(6000)), None);
+
+    // Autorepeat and releasing some other key don't count, but pressing another key starts over
+    tracker.key_event(Key::KEY_RESTART, 1, at(7000));
+    tracker.key_event(Key::KEY_RESTART, 2, at(8000));
+    tracker.key_event(Key::KEY_POWER, 0, at(8000));
+    assert_eq!(tracker.remaining(at(9000)), Some(Duration::from_secs(3)));
+    tracker.key_event(Key::KEY_POWER, 1, at(9000));
+    assert_eq!(tracker.remaining(at(12000)), Some(Duration::from_secs(2)));
+    assert_eq!(tracker.remaining(at(20000)), Some(Duration::ZERO));
+}
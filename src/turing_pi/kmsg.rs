@@ -0,0 +1,337 @@
+//! Best-effort capture of the kernel messages logged during an installation.
+//!
+//! NAND failures usually reach us as a bare EIO; the detail (ECC errors, SPI timeouts) is only in
+//! the kernel log. None of this is essential, so a missing or unreadable `/dev/kmsg` just means
+//! there are no kernel messages to show.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use nix::fcntl::OFlag;
+
+const KMSG_PATH: &str = "/dev/kmsg";
+
+/// The message prefixes of the subsystems involved in an installation
+const SUBSYSTEM_PREFIXES: &[&str] = &["mtd", "ubi", "nand", "spi"];
+
+/// A single record from `/dev/kmsg`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KmsgRecord {
+    /// The syslog facility (0 for the kernel itself)
+    pub facility: u8,
+
+    /// The syslog level, from 0 (emergency) to 7 (debug)
+    pub level: u8,
+
+    /// The kernel's sequence number for this record
+    pub seq: u64,
+
+    /// Microseconds since boot
+    pub timestamp_us: u64,
+
+    /// The message text, with the kernel's `\xNN` escapes left as they are
+    pub message: String,
+
+    /// The `KEY=value` continuation lines (e.g. `SUBSYSTEM=spi`)
+    pub properties: Vec<String>,
+}
+
+impl KmsgRecord {
+    /// Parse a record in the `/dev/kmsg` format: `PRIO,SEQ,TIMESTAMP,FLAGS[,...];MESSAGE`,
+    /// followed by any number of continuation lines, each starting with a space
+    pub fn parse(record: &str) -> Option<Self> {
+        let mut lines = record.trim_end_matches('\n').split('\n');
+        let (prefix, message) = lines.next()?.split_once(';')?;
+
+        let mut fields = prefix.split(',');
+        let prio: u32 = fields.next()?.parse().ok()?;
+        let seq = fields.next()?.parse().ok()?;
+        let timestamp_us = fields.next()?.parse().ok()?;
+
+        let properties = lines
+            .map(|line| line.strip_prefix(' ').map(String::from))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            facility: (prio >> 3).try_into().ok()?,
+            level: (prio & 7) as u8,
+            seq,
+            timestamp_us,
+            message: message.to_string(),
+            properties,
+        })
+    }
+}
+
+impl fmt::Display for KmsgRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] {}",
+            self.timestamp_us / 1_000_000,
+            self.timestamp_us % 1_000_000,
+            self.message
+        )
+    }
+}
+
+/// Decides which kernel messages are relevant to an installation
+#[derive(Debug, Clone)]
+pub struct KmsgFilter {
+    prefixes: Vec<String>,
+}
+
+impl KmsgFilter {
+    /// Match the MTD/UBI/SPI/NAND subsystems, plus the given controller driver names (e.g.
+    /// "sun6i-spi"), which log under their own name
+    pub fn new(drivers: &[&str]) -> Self {
+        let prefixes = SUBSYSTEM_PREFIXES
+            .iter()
+            .chain(drivers)
+            .map(|x| x.to_string())
+            .collect();
+
+        Self { prefixes }
+    }
+
+    /// Is this record from the kernel, and from one of the subsystems or drivers of interest?
+    ///
+    /// Kernel messages start with the name of whoever logged them (e.g. "ubi0: ...",
+    /// "spi-nand spi0.0: ..."), so only that first word is considered.
+    pub fn matches(&self, record: &KmsgRecord) -> bool {
+        let source = record
+            .message
+            .split([' ', ':'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        record.facility == 0 && self.prefixes.iter().any(|x| source.starts_with(x.as_str()))
+    }
+}
+
+/// Kernel messages, bounded to the most recent ones
+#[derive(Debug, Default, Clone)]
+pub struct KernelLog {
+    pub lines: Vec<String>,
+
+    /// How many earlier lines were dropped to keep within the bound
+    pub omitted: usize,
+}
+
+impl KernelLog {
+    /// Keep the last `max_lines` of `records`
+    pub fn bounded(records: impl IntoIterator<Item = KmsgRecord>, max_lines: usize) -> Self {
+        let mut lines: Vec<String> = records.into_iter().map(|x| x.to_string()).collect();
+        let omitted = lines.len().saturating_sub(max_lines);
+        lines.drain(..omitted);
+
+        Self { lines, omitted }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+impl fmt::Display for KernelLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.omitted > 0 {
+            writeln!(f, "({} earlier lines omitted)", self.omitted)?;
+        }
+        for line in &self.lines {
+            writeln!(f, "{line}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read every record available without blocking, skipping any that can't be parsed
+///
+/// Each read() of `/dev/kmsg` returns exactly one record. It fails with EPIPE when records were
+/// overwritten before they could be read, which only means some are missing, and with EAGAIN
+/// once there are no more.
+fn read_records<R: Read>(reader: &mut R) -> Vec<KmsgRecord> {
+    // The kernel limits records to 8KiB, continuation lines included
+    let mut buf = vec![0; 8192];
+    let mut records = vec![];
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                let record = String::from_utf8_lossy(&buf[..len]);
+                records.extend(KmsgRecord::parse(&record));
+            }
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+    }
+
+    records
+}
+
+/// Open a kmsg device so that reads don't wait for new messages
+fn open_nonblocking(path: &Path) -> Option<File> {
+    File::options()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)
+        .ok()
+}
+
+/// A position in the kernel log, from which later messages can be collected
+#[derive(Debug, Clone)]
+pub struct KmsgCursor {
+    path: &'static Path,
+
+    /// The sequence number of the last record logged before the cursor, if any
+    last_seq: Option<u64>,
+}
+
+impl KmsgCursor {
+    /// Mark the current end of the kernel log, or give up (returning None) if it can't be read
+    pub fn now() -> Option<Self> {
+        Self::open(Path::new(KMSG_PATH))
+    }
+
+    fn open(path: &'static Path) -> Option<Self> {
+        let mut file = open_nonblocking(path)?;
+        let last_seq = read_records(&mut file).last().map(|x| x.seq);
+
+        Some(Self { path, last_seq })
+    }
+
+    /// Collect the relevant messages logged since the cursor was made, keeping the last
+    /// `max_lines`; an unreadable log yields none
+    pub fn collect(&self, filter: &KmsgFilter, max_lines: usize) -> KernelLog {
+        let records = open_nonblocking(self.path)
+            .map(|mut file| read_records(&mut file))
+            .unwrap_or_default();
+
+        KernelLog::bounded(
+            records
+                .into_iter()
+                .filter(|x| self.last_seq.is_none_or(|seq| x.seq > seq))
+                .filter(|x| filter.matches(x)),
+            max_lines,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A kmsg device that returns one record per read, then EAGAIN
+    struct FakeKmsg(Vec<io::Result<&'static str>>);
+
+    impl Read for FakeKmsg {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let record = self.0.remove(0)?;
+            buf[..record.len()].copy_from_slice(record.as_bytes());
+            Ok(record.len())
+        }
+    }
+
+    const RECORDS: &[&str] = &[
+        "6,100,5000000,-;Linux version 6.1.0\n",
+        "3,101,12345678,-;nand: timeout while waiting for chip ready\n",
+        "4,102,12400000,-,caller=T1;spi-nand spi0.0: ECC error at page 0x1f40\n SUBSYSTEM=spi\n DEVICE=+spi:spi0.0\n",
+        "5,103,12500000,-;ubi0: attaching mtd1\n",
+        "3,104,12600000,-;sun6i-spi 4026000.spi: timeout transferring 2048 bytes\n",
+        "14,105,12700000,-;mtd: written by a user process\n",
+    ];
+
+    #[test]
+    fn test_parse() {
+        let record = KmsgRecord::parse(RECORDS[2]).unwrap();
+        assert_eq!(record.facility, 0);
+        assert_eq!(record.level, 4);
+        assert_eq!(record.seq, 102);
+        assert_eq!(record.timestamp_us, 12_400_000);
+        assert_eq!(record.message, "spi-nand spi0.0: ECC error at page 0x1f40");
+        assert_eq!(record.properties, ["SUBSYSTEM=spi", "DEVICE=+spi:spi0.0"]);
+        assert_eq!(
+            record.to_string(),
+            "[   12.400000] spi-nand spi0.0: ECC error at page 0x1f40"
+        );
+
+        let user = KmsgRecord::parse(RECORDS[5]).unwrap();
+        assert_eq!((user.facility, user.level), (1, 6));
+
+        for garbage in [
+            "",
+            "no prefix",
+            "x,1,2,-;msg",
+            "6,1;short prefix",
+            "6,1,2,-;ok\nnot indented",
+        ] {
+            assert_eq!(KmsgRecord::parse(garbage), None, "{garbage:?}");
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let matched = |filter: &KmsgFilter| -> Vec<u64> {
+            RECORDS
+                .iter()
+                .filter_map(|x| KmsgRecord::parse(x))
+                .filter(|x| filter.matches(x))
+                .map(|x| x.seq)
+                .collect()
+        };
+
+        // The controller driver only matches when named, and userspace messages never do
+        assert_eq!(matched(&KmsgFilter::new(&[])), [101, 102, 103]);
+        assert_eq!(
+            matched(&KmsgFilter::new(&["sun6i-spi"])),
+            [101, 102, 103, 104]
+        );
+    }
+
+    #[test]
+    fn test_read_records() {
+        let mut kmsg = FakeKmsg(vec![
+            Ok(RECORDS[0]),
+            Err(io::ErrorKind::BrokenPipe.into()),
+            Ok("garbage"),
+            Ok(RECORDS[1]),
+        ]);
+        let seqs: Vec<u64> = read_records(&mut kmsg).iter().map(|x| x.seq).collect();
+        assert_eq!(seqs, [100, 101]);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let records = || RECORDS.iter().filter_map(|x| KmsgRecord::parse(x));
+
+        let log = KernelLog::bounded(records(), 2);
+        assert_eq!(log.omitted, 4);
+        assert_eq!(log.lines.len(), 2);
+        assert!(log.lines[1].ends_with("mtd: written by a user process"));
+        assert!(log.to_string().starts_with("(4 earlier lines omitted)\n"));
+
+        let log = KernelLog::bounded(records(), 10);
+        assert_eq!((log.omitted, log.lines.len()), (0, 6));
+    }
+
+    #[test]
+    fn test_missing_kmsg() {
+        assert!(KmsgCursor::open(Path::new("/nonexistent/kmsg")).is_none());
+
+        let cursor = KmsgCursor {
+            path: Path::new("/nonexistent/kmsg"),
+            last_seq: None,
+        };
+        assert!(cursor.collect(&KmsgFilter::new(&[]), 10).is_empty());
+    }
+}
@@ -0,0 +1,534 @@
+//! The set of UBI volumes to install, which downstream images may extend (e.g. with a `data` or
+//! `swap` volume) through a layout file, without changing the installer.
+//!
+//! The layout file has one volume per line, as whitespace-separated fields:
+//!
+//! ```text
+//! # name     type     size   id  flags
+//! uboot-env  dynamic  64KiB  0
+//! rootfs     static   image  -   skipcheck
+//! data       dynamic  16MiB  -   autoresize
+//! ```
+//!
+//! The size is in bytes (with an optional KiB/MiB/GiB unit), or `image` for a volume to be filled
+//! from the rootfs image. There may be more than one of those, e.g. for A/B updates:
+//!
+//! ```text
+//! rootfs_a   static   image  -   skipcheck
+//! rootfs_b   static   image  -   skipcheck
+//! ```
+//!
+//! in which case each gets a whole copy of the image. The ID is `-` to have one assigned, and the
+//! flags are optional and comma-separated; besides `skipcheck` and `autoresize`, `align=SIZE`
+//! makes every LEB of the volume a multiple of SIZE. Blank lines and anything after a `#` are
+//! ignored.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+use crate::nand::{format_size, parse_size};
+use crate::ubi::{
+    ubinize::{BasicVolume, DuplicateImage, ReopenImage, Volume, UBI_MAX_VOLUMES},
+    VolType,
+};
+
+/// The name of the volume the BMC firmware keeps its data in, which UBI grows to fill the NAND
+pub const DATA_VOLUME_NAME: &str = "data";
+
+/// The ID [UbiLayoutSpec::default] gives the `data` volume
+pub const DATA_VOLUME_ID: u32 = 2;
+
+/// How big a volume in a [UbiLayoutSpec] is
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VolumeSize {
+    /// A fixed size, in bytes; the volume starts out empty
+    Bytes(u64),
+
+    /// The size of the image being installed, which the volume is filled from
+    Image,
+}
+
+/// One volume of a [UbiLayoutSpec]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VolumeSpec {
+    pub name: String,
+    pub vol_type: VolType,
+    pub size: VolumeSize,
+
+    /// The volume ID, or None to have one assigned
+    pub id: Option<u32>,
+
+    /// Set UBI's "skip CRC check" flag
+    pub skipcheck: bool,
+
+    /// Set UBI's "autoresize" flag
+    pub autoresize: bool,
+
+    /// What the size of each LEB must be a multiple of, in bytes; 1 for any size
+    pub align: NonZeroU32,
+}
+
+/// The UBI volumes to install, at least one of which is filled from the image
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UbiLayoutSpec {
+    pub volumes: Vec<VolumeSpec>,
+}
+
+impl Default for UbiLayoutSpec {
+    /// The layout the BMC firmware expects: the U-Boot environment, the rootfs, then an empty
+    /// `data` volume for UBI to grow into the rest of the NAND on first attach (which the firmware
+    /// would otherwise have to create while booting)
+    fn default() -> Self {
+        Self {
+            volumes: vec![
+                VolumeSpec {
+                    name: "uboot-env".into(),
+                    vol_type: VolType::Dynamic,
+                    size: VolumeSize::Bytes(65536),
+                    id: Some(0),
+                    skipcheck: false,
+                    autoresize: false,
+                    align: NonZeroU32::MIN,
+                },
+                VolumeSpec {
+                    name: "rootfs".into(),
+                    vol_type: VolType::Static,
+                    size: VolumeSize::Image,
+                    id: None,
+                    // Opening the volume at boot takes ~10sec. longer without this flag
+                    skipcheck: true,
+                    autoresize: false,
+                    align: NonZeroU32::MIN,
+                },
+                VolumeSpec {
+                    name: DATA_VOLUME_NAME.into(),
+                    vol_type: VolType::Dynamic,
+                    size: VolumeSize::Bytes(0),
+                    id: Some(DATA_VOLUME_ID),
+                    skipcheck: false,
+                    autoresize: true,
+                    align: NonZeroU32::MIN,
+                },
+            ],
+        }
+    }
+}
+
+impl UbiLayoutSpec {
+    /// Make sure UBI can take this layout: there must be at most [UBI_MAX_VOLUMES] volumes, with
+    /// unique names and IDs, at most one of them set to autoresize, and at least one of them
+    /// filled from the image
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.volumes.len() <= UBI_MAX_VOLUMES,
+            "too many volumes ({}; UBI allows at most {UBI_MAX_VOLUMES})",
+            self.volumes.len()
+        );
+
+        let mut names = HashSet::new();
+        let mut ids = HashSet::new();
+        for volume in &self.volumes {
+            anyhow::ensure!(!volume.name.is_empty(), "volume name is empty");
+            anyhow::ensure!(
+                names.insert(&volume.name),
+                "duplicate volume name {:?}",
+                volume.name
+            );
+            if let Some(id) = volume.id {
+                anyhow::ensure!(
+                    (id as usize) < UBI_MAX_VOLUMES,
+                    "volume ID {id} out of range (must be below {UBI_MAX_VOLUMES})"
+                );
+                anyhow::ensure!(ids.insert(id), "duplicate volume ID {id}");
+            }
+        }
+
+        let autoresize: Vec<_> = self
+            .volumes
+            .iter()
+            .filter(|x| x.autoresize)
+            .map(|x| x.name.as_str())
+            .collect();
+        anyhow::ensure!(
+            autoresize.len() <= 1,
+            "only one volume may have the `autoresize` flag, not {autoresize:?}"
+        );
+
+        anyhow::ensure!(
+            self.image_volumes().next().is_some(),
+            "at least one volume must have size `image`"
+        );
+
+        Ok(())
+    }
+
+    /// The volumes filled from the image, in order
+    pub fn image_volumes(&self) -> impl Iterator<Item = &VolumeSpec> {
+        self.volumes.iter().filter(|x| x.size == VolumeSize::Image)
+    }
+}
+
+impl FromStr for VolumeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [name, vol_type, size, id, flags @ ..] = &fields[..] else {
+            anyhow::bail!("expected NAME TYPE SIZE ID [FLAGS]");
+        };
+
+        let vol_type = match *vol_type {
+            "static" => VolType::Static,
+            "dynamic" => VolType::Dynamic,
+            other => anyhow::bail!("unknown volume type {other:?} (expected static or dynamic)"),
+        };
+        let size = match *size {
+            "image" => VolumeSize::Image,
+            size => VolumeSize::Bytes(parse_size(size)?),
+        };
+        let id = match *id {
+            "-" => None,
+            id => Some(id.parse()?),
+        };
+
+        let mut volume = Self {
+            name: name.to_string(),
+            vol_type,
+            size,
+            id,
+            skipcheck: false,
+            autoresize: false,
+            align: NonZeroU32::MIN,
+        };
+        match flags {
+            [] => (),
+            [flags] => {
+                for flag in flags.split(',') {
+                    match flag.split_once('=') {
+                        Some(("align", size)) => {
+                            volume.align = u32::try_from(parse_size(size)?)
+                                .ok()
+                                .and_then(NonZeroU32::new)
+                                .ok_or(anyhow::anyhow!("invalid alignment {size:?}"))?;
+                        }
+                        None if flag == "skipcheck" => volume.skipcheck = true,
+                        None if flag == "autoresize" => volume.autoresize = true,
+                        _ => anyhow::bail!("unknown volume flag {flag:?}"),
+                    }
+                }
+            }
+            _ => anyhow::bail!("flags must be comma-separated, without spaces"),
+        }
+
+        Ok(volume)
+    }
+}
+
+/// Parse (and validate) a layout file
+impl FromStr for UbiLayoutSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut volumes = vec![];
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                let volume = line
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("line {}: {e}", number + 1))?;
+                volumes.push(volume);
+            }
+        }
+
+        let spec = Self { volumes };
+        spec.validate()?;
+        Ok(spec)
+    }
+}
+
+/// Write out a layout in the format it's parsed from
+impl fmt::Display for UbiLayoutSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for volume in &self.volumes {
+            let vol_type = match volume.vol_type {
+                VolType::Static => "static",
+                VolType::Dynamic => "dynamic",
+            };
+            let size = match volume.size {
+                VolumeSize::Bytes(bytes) => format_size(bytes),
+                VolumeSize::Image => "image".into(),
+            };
+            let id = volume.id.map_or("-".into(), |x| x.to_string());
+            let align = format!("align={}", format_size(volume.align.get().into()));
+            let flags: Vec<&str> = [
+                (volume.skipcheck, "skipcheck"),
+                (volume.autoresize, "autoresize"),
+                (volume.align != NonZeroU32::MIN, &align),
+            ]
+            .into_iter()
+            .filter_map(|(set, flag)| set.then_some(flag))
+            .collect();
+
+            write!(f, "{} {vol_type} {size} {id}", volume.name)?;
+            if !flags.is_empty() {
+                write!(f, " {}", flags.join(","))?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Build the volumes of a (valid) layout, filling each `image` volume from `image`, which holds
+/// `image_size` bytes.
+///
+/// `image` is only read through once. Where more than one volume is filled from it, it's spilled
+/// into a temporary file as the first is written, as with [DuplicateImage::new].
+pub fn get_ubi_volumes<'a>(
+    spec: &UbiLayoutSpec,
+    image: &'a mut dyn Read,
+    image_size: u64,
+) -> Vec<Box<dyn Volume + 'a>> {
+    get_ubi_volumes_with(spec, image, image_size, None, |_| None)
+}
+
+/// Like [get_ubi_volumes], but filling any fixed-size volume that `contents` has something for
+/// (by its name) with that, rather than leaving it empty.
+///
+/// With `reopen`, which opens `image` again from the start, the volumes filled from it after the
+/// first each read it afresh through that, as with [DuplicateImage::reopened], rather than it
+/// being spilled.
+pub fn get_ubi_volumes_with<'a>(
+    spec: &UbiLayoutSpec,
+    image: &'a mut dyn Read,
+    image_size: u64,
+    reopen: Option<ReopenImage<'a>>,
+    mut contents: impl FnMut(&str) -> Option<&'a [u8]>,
+) -> Vec<Box<dyn Volume + 'a>> {
+    let (mut image, duplicate) = match (spec.image_volumes().count(), reopen) {
+        (0 | 1, _) => (Some(image), None),
+        (_, Some(reopen)) => (None, Some(DuplicateImage::reopened(image, reopen))),
+        (_, None) => (None, Some(DuplicateImage::new(image))),
+    };
+    spec.volumes
+        .iter()
+        .map(|spec| {
+            let mut volume = BasicVolume::new(spec.vol_type).name(spec.name.clone());
+            if let Some(id) = spec.id {
+                volume = volume.id(id);
+            }
+            if spec.skipcheck {
+                volume = volume.skipcheck();
+            }
+            if spec.autoresize {
+                volume = volume.autoresize();
+            }
+            volume = volume.align(spec.align);
+            volume = match spec.size {
+                VolumeSize::Bytes(bytes) => match contents(&spec.name) {
+                    Some(data) => volume.size(bytes).image(data),
+                    None => volume.size(bytes),
+                },
+                VolumeSize::Image => match (image.take(), &duplicate) {
+                    (Some(image), _) => volume.size(image_size).image(image),
+                    (None, Some(duplicate)) => volume.size(image_size).image(duplicate.copy()),
+                    (None, None) => volume.size(image_size),
+                },
+            };
+
+            Box::new(volume) as Box<dyn Volume + 'a>
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> anyhow::Result<()> {
+        let spec: UbiLayoutSpec = "
+            # The stock layout, plus some room for user data
+            uboot-env  dynamic  64KiB  0
+            rootfs     static   image  -   skipcheck
+            data       dynamic  16MiB  5   autoresize,skipcheck  # grows to fill the NAND
+            cache      dynamic  1MiB   -   align=4KiB
+        "
+        .parse()?;
+        assert_eq!(spec.volumes.len(), 4);
+        assert_eq!(spec.volumes[..2], UbiLayoutSpec::default().volumes[..2]);
+        assert_eq!(
+            spec.volumes[2],
+            VolumeSpec {
+                name: "data".into(),
+                vol_type: VolType::Dynamic,
+                size: VolumeSize::Bytes(16 << 20),
+                id: Some(5),
+                skipcheck: true,
+                autoresize: true,
+                align: NonZeroU32::MIN,
+            }
+        );
+        assert_eq!(spec.volumes[3].align.get(), 4096);
+
+        // Writing a layout out gives back something that parses to the same thing
+        assert_eq!(spec.to_string().parse::<UbiLayoutSpec>()?, spec);
+
+        for (bad, error) in [
+            (
+                "rootfs static image",
+                "line 1: expected NAME TYPE SIZE ID [FLAGS]",
+            ),
+            ("rootfs ro image -", "line 1: unknown volume type \"ro\""),
+            (
+                "rootfs static image - compressed",
+                "line 1: unknown volume flag",
+            ),
+            (
+                "a dynamic 1KiB -",
+                "at least one volume must have size `image`",
+            ),
+            (
+                "a static image 1\nb dynamic 1KiB 1",
+                "duplicate volume ID 1",
+            ),
+            (
+                "a static image -\na dynamic 1KiB -",
+                "duplicate volume name \"a\"",
+            ),
+            ("a static image 128", "volume ID 128 out of range"),
+            (
+                "a static image - autoresize\nb dynamic 1KiB - autoresize",
+                "only one volume may have the `autoresize` flag, not [\"a\", \"b\"]",
+            ),
+            (
+                "a static image - align=0",
+                "line 1: invalid alignment \"0\"",
+            ),
+            ("a static image - align=8GiB", "line 1: invalid alignment"),
+        ] {
+            let message = bad.parse::<UbiLayoutSpec>().unwrap_err().to_string();
+            assert!(message.starts_with(error), "{bad:?}: {message}");
+        }
+
+        let too_many: String = (0..=UBI_MAX_VOLUMES)
+            .map(|i| format!("v{i} static image -\n"))
+            .collect();
+        let message = too_many.parse::<UbiLayoutSpec>().unwrap_err().to_string();
+        assert!(message.starts_with("too many volumes (129"), "{message}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_volumes() -> anyhow::Result<()> {
+        use crate::fixtures::synthetic_data;
+        use crate::nand::{NandLayout, SimNand};
+        use crate::ubi;
+
+        const TEST_LAYOUT: NandLayout = NandLayout {
+            blocks: 64,
+            pages_per_block: 16,
+            bytes_per_page: 512,
+        };
+
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = ubi::scan_blocks(&mut nand)?;
+        ubi::format(&mut nand, &mut ebt)?;
+        let rootfs = synthetic_data(20000);
+
+        // The volumes the installer has always written
+        let (mut expected, mut expected_ebt) = (nand.clone(), ebt.clone());
+        let mut reader = &rootfs[..];
+        let volumes: Vec<Box<dyn Volume>> = vec![
+            Box::new(
+                BasicVolume::new(VolType::Dynamic)
+                    .id(0)
+                    .name("uboot-env")
+                    .size(65536),
+            ),
+            Box::new(
+                BasicVolume::new(VolType::Static)
+                    .name("rootfs")
+                    .skipcheck()
+                    .size(rootfs.len() as u64)
+                    .image(&mut reader),
+            ),
+            Box::new(
+                BasicVolume::new(VolType::Dynamic)
+                    .id(2)
+                    .name("data")
+                    .size(0)
+                    .autoresize(),
+            ),
+        ];
+        ubi::write_volumes(&mut expected, &mut expected_ebt, volumes)?;
+
+        let mut reader = &rootfs[..];
+        let volumes = get_ubi_volumes(&UbiLayoutSpec::default(), &mut reader, rootfs.len() as u64);
+        ubi::write_volumes(&mut nand, &mut ebt, volumes)?;
+
+        let (mut image, mut expected_image) = (vec![], vec![]);
+        nand.save(&mut image)?;
+        expected.save(&mut expected_image)?;
+        assert!(image == expected_image);
+
+        // The `data` volume takes no blocks yet, but reserves one, as UBI won't take a volume that
+        // reserves none; autoresize grows it from there
+        let ebt = ubi::scan_blocks(&mut nand)?;
+        let table = ubi::read_volume_table(&mut nand, &ebt)?;
+        let data = table[DATA_VOLUME_ID as usize].as_ref().unwrap();
+        assert_eq!(data.name, DATA_VOLUME_NAME);
+        assert_eq!(data.flags, ubi::UBI_VTBL_AUTORESIZE_FLG);
+        assert_eq!(data.reserved_pebs, 1);
+        assert!(!ebt.iter().any(
+            |x| matches!(x, ubi::BlockContent::EcData(_, Some(vid)) if vid.vol_id == DATA_VOLUME_ID)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ab_volumes() -> anyhow::Result<()> {
+        use crate::fixtures::synthetic_data;
+        use crate::nand::{NandLayout, SimNand};
+        use crate::ubi;
+
+        const TEST_LAYOUT: NandLayout = NandLayout {
+            blocks: 64,
+            pages_per_block: 16,
+            bytes_per_page: 512,
+        };
+
+        let spec: UbiLayoutSpec = "
+            uboot-env  dynamic  64KiB  0
+            rootfs_a   static   image  -   skipcheck
+            rootfs_b   static   image  -   skipcheck
+        "
+        .parse()?;
+        let names: Vec<_> = spec.image_volumes().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["rootfs_a", "rootfs_b"]);
+
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = ubi::scan_blocks(&mut nand)?;
+        ubi::format(&mut nand, &mut ebt)?;
+        let rootfs = synthetic_data(20000);
+
+        // A stream, which can only be read once, fills both
+        let mut reader = &rootfs[..];
+        let volumes = get_ubi_volumes(&spec, &mut reader, rootfs.len() as u64);
+        ubi::write_volumes(&mut nand, &mut ebt, volumes)?;
+
+        for name in names {
+            let mut out = Vec::new();
+            ubi::read_volume(&mut nand, &ebt, &name.parse()?, &mut out)?;
+            assert!(out == rootfs, "{name}");
+        }
+
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    borrow::Cow,
     fs,
     sync::mpsc::{self, channel},
     thread,
@@ -38,6 +39,45 @@ impl LedState {
     }
 }
 
+/// What the blink thread should show
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LedCommand {
+    /// Repeat a blink pattern
+    Pattern(&'static [LedState]),
+
+    /// Show a percentage as a bar on the swport LEDs (each lit port is 25%), with the FP LED
+    /// keeping a heartbeat
+    Progress(u8),
+}
+
+impl From<&'static [LedState]> for LedCommand {
+    fn from(pattern: &'static [LedState]) -> Self {
+        Self::Pattern(pattern)
+    }
+}
+
+impl LedCommand {
+    /// The blink pattern to repeat for this command
+    fn pattern(self) -> Cow<'static, [LedState]> {
+        match self {
+            Self::Pattern(x) => x.into(),
+            Self::Progress(percent) => progress_pattern(percent).to_vec().into(),
+        }
+    }
+}
+
+/// Render a progress percentage: N of the 4 swports lit solid for every full N*25%, under a
+/// heartbeat on the FP LED
+fn progress_pattern(percent: u8) -> [LedState; 2] {
+    let lit = usize::from(percent.min(100)) * 4 / 100;
+    let swports = std::array::from_fn(|i| i < lit);
+
+    [
+        Custom(Duration::from_millis(200), true, swports),
+        Custom(Duration::from_millis(800), false, swports),
+    ]
+}
+
 pub const LED_READY: &[LedState] = &[
     On(Duration::from_millis(1500)),
     Off(Duration::from_millis(1500)),
@@ -85,6 +125,28 @@ pub const LED_DONE: &[LedState] = &[
     Off(Duration::from_millis(2600)),
 ];
 
+/// Like [LED_DONE], but with a long blink after the two short ones, to say that the installation
+/// succeeded but something needs the user's attention
+pub const LED_DONE_WARNING: &[LedState] = &[
+    On(Duration::from_millis(100)),
+    Off(Duration::from_millis(200)),
+    On(Duration::from_millis(100)),
+    Off(Duration::from_millis(200)),
+    On(Duration::from_millis(900)),
+    Off(Duration::from_millis(2000)),
+];
+
+/// Like [LED_DONE], but with three short blinks, to say that the firmware was already installed and
+/// nothing needed doing
+pub const LED_DONE_UP_TO_DATE: &[LedState] = &[
+    On(Duration::from_millis(100)),
+    Off(Duration::from_millis(200)),
+    On(Duration::from_millis(100)),
+    Off(Duration::from_millis(200)),
+    On(Duration::from_millis(100)),
+    Off(Duration::from_millis(2300)),
+];
+
 const DIT: Duration = Duration::from_millis(150);
 const DAH: Duration = Duration::from_millis(450);
 const GAP: Duration = Duration::from_millis(1050);
@@ -112,6 +174,16 @@ pub const LED_ERROR: &[LedState] = &[
     Off(GAP),
 ];
 
+/// Like [LED_ERROR], but blinking "M" (for "media") rather than SOS, to say that the problem is
+/// with what's on the microSD card (e.g. a corrupt image), which the user can fix and try again
+pub const LED_ERROR_FIXABLE: &[LedState] = &[
+    // M --
+    On(DAH),
+    Off(DIT),
+    On(DAH),
+    Off(GAP),
+];
+
 /// This runs in a thread that communicates with the RTL8370MB to control its LEDs. These LEDs are
 /// useful in case the user doesn't have other indicators connected (like front panel LEDs).
 ///
@@ -185,12 +257,15 @@ pub fn rtl8370mb_led_thread(rx: mpsc::Receiver<[bool; 4]>) {
 
 /// This runs in a thread and manages the LED blinking.
 ///
-/// Send new blink patterns through the MPSC channel to change the active pattern.
-pub fn led_blink_thread() -> mpsc::Sender<&'static [LedState]> {
+/// Send new [LedCommand]s through the MPSC channel to change what's shown; sending the command
+/// that's already showing changes nothing.
+pub fn led_blink_thread() -> mpsc::Sender<LedCommand> {
     let (tx, rx) = channel();
     thread::spawn(move || {
-        let mut pattern = &[LedState::Off(Duration::from_secs(3600))][..];
-        let mut remaining = &[][..];
+        const IDLE: &[LedState] = &[LedState::Off(Duration::from_secs(3600))];
+        let mut command = LedCommand::Pattern(IDLE);
+        let mut pattern = command.pattern();
+        let mut position = 0;
 
         let (rtl_tx, rtl_rx) = mpsc::channel();
         thread::spawn(move || rtl8370mb_led_thread(rtl_rx));
@@ -210,16 +285,11 @@ pub fn led_blink_thread() -> mpsc::Sender<&'static [LedState]> {
         };
 
         loop {
-            let cmd = match remaining.split_first() {
-                Some((first, elements)) => {
-                    remaining = elements;
-                    *first
-                }
-                None => {
-                    remaining = pattern;
-                    continue;
-                }
+            let Some(&cmd) = pattern.get(position) else {
+                position = 0;
+                continue;
             };
+            position += 1;
 
             let (fw, swports) = cmd.get_leds();
             set_leds(fw, swports);
@@ -229,9 +299,11 @@ pub fn led_blink_thread() -> mpsc::Sender<&'static [LedState]> {
                     mpsc::RecvTimeoutError::Timeout => (),
                     mpsc::RecvTimeoutError::Disconnected => break,
                 },
-                Ok(new_pattern) => {
-                    pattern = new_pattern;
-                    remaining = &[];
+                Ok(new_command) if new_command == command => (),
+                Ok(new_command) => {
+                    command = new_command;
+                    pattern = command.pattern();
+                    position = 0;
                 }
             }
         }
@@ -240,3 +312,30 @@ pub fn led_blink_thread() -> mpsc::Sender<&'static [LedState]> {
     });
     tx
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_progress_pattern() {
+        let swports = |percent| progress_pattern(percent).map(|x| x.get_leds().1);
+
+        assert_eq!(swports(0), [[false; 4]; 2]);
+        assert_eq!(swports(24), [[false; 4]; 2]);
+        assert_eq!(swports(25), [[true, false, false, false]; 2]);
+        assert_eq!(swports(74), [[true, true, false, false]; 2]);
+        assert_eq!(swports(100), [[true; 4]; 2]);
+        assert_eq!(swports(255), [[true; 4]; 2]);
+
+        // The FP LED beats regardless
+        let fp = progress_pattern(50).map(|x| x.get_leds().0);
+        assert_eq!(fp, [true, false]);
+    }
+
+    #[test]
+    fn test_command_pattern() {
+        assert_eq!(*LedCommand::from(LED_DONE).pattern(), *LED_DONE);
+        assert_eq!(*LedCommand::Progress(50).pattern(), progress_pattern(50));
+    }
+}
@@ -0,0 +1,219 @@
+//! A record of the firmware last installed, kept in a small UBI volume of its own, so that
+//! installing the same firmware again can be skipped.
+//!
+//! The stamp holds the length and CRC32 of the rootfs image, the bootloader and the UBI layout. It
+//! is written last, once everything else has been installed and verified, so an interrupted
+//! installation leaves no stamp behind.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use std::io::{self, Read};
+
+use crate::nand::Nand;
+use crate::ubi::{
+    read_volume_leb,
+    ubinize::{BasicVolume, Volume},
+    write_leb, Ebt, VolType, VolumeSelector,
+};
+use crate::util::ReadExt;
+
+use super::layout::UbiLayoutSpec;
+
+/// The name of the UBI volume holding the stamp
+pub const STAMP_VOLUME_NAME: &str = "install-stamp";
+
+const STAMP_MAGIC: &[u8; 8] = b"TPSTAMP1";
+const STAMP_SIZE: usize = STAMP_MAGIC.len() + 3 * 12 + 4;
+const STAMP_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// The length and CRC32 of some data
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Digest {
+    pub len: u64,
+    pub crc: u32,
+}
+
+impl Digest {
+    pub fn of(data: &[u8]) -> Self {
+        Self {
+            len: data.len() as u64,
+            crc: STAMP_CRC.checksum(data),
+        }
+    }
+}
+
+/// A reader that digests everything read through it
+pub struct DigestReader<R> {
+    inner: R,
+    digest: crc::Digest<'static, u32>,
+    len: u64,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            digest: STAMP_CRC.digest(),
+            len: 0,
+        }
+    }
+
+    /// The digest of everything read so far
+    pub fn digest(&self) -> Digest {
+        Digest {
+            len: self.len,
+            crc: self.digest.clone().finalize(),
+        }
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.digest.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+/// What an installation wrote
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InstallStamp {
+    /// The rootfs image, decompressed
+    pub rootfs: Digest,
+    pub bootloader: Digest,
+
+    /// The UBI layout, as written out by its `Display` impl
+    pub layout: Digest,
+}
+
+impl InstallStamp {
+    pub fn new(rootfs: Digest, bootloader: &[u8], layout: &UbiLayoutSpec) -> Self {
+        Self {
+            rootfs,
+            bootloader: Digest::of(bootloader),
+            layout: Digest::of(layout.to_string().as_bytes()),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = STAMP_MAGIC.to_vec();
+        for digest in [self.rootfs, self.bootloader, self.layout] {
+            out.extend(digest.len.to_le_bytes());
+            out.extend(digest.crc.to_le_bytes());
+        }
+        out.extend(STAMP_CRC.checksum(&out).to_le_bytes());
+        out
+    }
+
+    /// Decode a stamp from the start of `bytes`, if there's an intact one there
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..STAMP_SIZE)?;
+        let (body, crc) = bytes.split_at(STAMP_SIZE - 4);
+        if !body.starts_with(STAMP_MAGIC) || STAMP_CRC.checksum(body).to_le_bytes() != crc {
+            return None;
+        }
+
+        let mut digests = body[STAMP_MAGIC.len()..].chunks_exact(12).map(|x| Digest {
+            len: u64::from_le_bytes(x[..8].try_into().unwrap()),
+            crc: u32::from_le_bytes(x[8..].try_into().unwrap()),
+        });
+        Some(Self {
+            rootfs: digests.next()?,
+            bootloader: digests.next()?,
+            layout: digests.next()?,
+        })
+    }
+}
+
+/// The (empty) volume for the stamp to be written into, to install along with the others
+pub fn stamp_volume<'a>() -> Box<dyn Volume + 'a> {
+    Box::new(
+        BasicVolume::new(VolType::Dynamic)
+            .name(STAMP_VOLUME_NAME)
+            .size(STAMP_SIZE as u64),
+    )
+}
+
+/// Read the stamp off the UBI partition, if there's an intact one
+pub fn read_stamp<N: Nand>(nand: &mut N, ebt: &Ebt) -> Option<InstallStamp> {
+    let volume = VolumeSelector::Name(STAMP_VOLUME_NAME.into());
+    let leb = read_volume_leb(nand, ebt, &volume, 0).ok()??;
+    InstallStamp::decode(&leb)
+}
+
+/// Write the stamp into its volume, which must have been installed with [stamp_volume]
+pub fn write_stamp<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    stamp: &InstallStamp,
+) -> anyhow::Result<()> {
+    let volume = VolumeSelector::Name(STAMP_VOLUME_NAME.into());
+    write_leb(nand, ebt, &volume, 0, &stamp.encode())
+}
+
+/// The digest of an image, along with the CRC32 of each LEB-sized chunk of it, for spot-checking
+/// the installed copy with [spot_check]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ImageDigest {
+    pub digest: Digest,
+    pub chunk_crcs: Vec<u32>,
+}
+
+/// Digest all of `image`, in chunks of `chunk_size` (the LEB size)
+pub fn digest_image(image: impl Read, chunk_size: usize) -> anyhow::Result<ImageDigest> {
+    let mut image = DigestReader::new(image);
+    let mut chunk_crcs = vec![];
+    let mut buf = vec![0; chunk_size];
+    loop {
+        let filled = image.read_fill(&mut buf)?;
+        if filled == 0 {
+            break;
+        }
+        chunk_crcs.push(STAMP_CRC.checksum(&buf[..filled]));
+    }
+
+    Ok(ImageDigest {
+        digest: image.digest(),
+        chunk_crcs,
+    })
+}
+
+/// Read up to `samples` LEBs of `volume`, spread evenly from first to last, and check that they
+/// hold the chunks of the image that `digest` was taken of
+pub fn spot_check<N: Nand>(
+    nand: &mut N,
+    ebt: &Ebt,
+    volume: &VolumeSelector,
+    digest: &ImageDigest,
+    samples: usize,
+) -> bool {
+    let chunks = digest.chunk_crcs.len();
+    let mut lnums: Vec<usize> = (0..samples)
+        .map(|i| i * chunks.saturating_sub(1) / samples.saturating_sub(1).max(1))
+        .collect();
+    lnums.dedup();
+
+    lnums.into_iter().all(|lnum| {
+        let leb = read_volume_leb(nand, ebt, volume, lnum as u32);
+        matches!(leb, Ok(Some(data)) if Some(&STAMP_CRC.checksum(&data)) == digest.chunk_crcs.get(lnum))
+    })
+}
+
+#[test]
+fn test_stamp_encoding() {
+    let stamp = InstallStamp {
+        rootfs: Digest::of(b"rootfs"),
+        bootloader: Digest::of(b"bootloader"),
+        layout: Digest::of(b"layout"),
+    };
+    let mut bytes = stamp.encode();
+    assert_eq!(bytes.len(), STAMP_SIZE);
+
+    // Whatever pads out the rest of the LEB is ignored, but damage to the stamp isn't
+    bytes.resize(512, 0xFF);
+    assert_eq!(InstallStamp::decode(&bytes), Some(stamp));
+    bytes[10] ^= 1;
+    assert_eq!(InstallStamp::decode(&bytes), None);
+    assert_eq!(InstallStamp::decode(&[0xFF; 512]), None);
+}
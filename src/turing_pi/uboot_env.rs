@@ -0,0 +1,251 @@
+//! The U-Boot environment, for filling the `uboot-env` volume with something better than nothing.
+//!
+//! Left empty, the volume has U-Boot fall back on the environment built into it. Given a set of
+//! variables (e.g. from a `uboot-env.txt` file, as `name=value` lines), the installer writes them
+//! in the form U-Boot reads with `CONFIG_ENV_IS_IN_UBI`, as `mkenvimage` would:
+//!
+//! | Offset | Size         | Field                                                          |
+//! |--------|--------------|----------------------------------------------------------------|
+//! | 0      | 4            | CRC32 of the data, little-endian                               |
+//! | 4      | 1            | Flags, only in the redundant layout: [ENV_FLAG_ACTIVE] or not  |
+//! | 4 or 5 | the rest     | Data: `name=value` entries, each ended by a 0x00, then another |
+//!
+//! The data is padded out with 0x00 to the size of the environment, and the CRC covers all of it.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure};
+
+use crate::error::InstallError;
+use crate::nand::format_size;
+
+/// The name of the UBI volume holding the environment
+pub const UBOOT_ENV_VOLUME_NAME: &str = "uboot-env";
+
+/// The size of the environment U-Boot expects (`CONFIG_ENV_SIZE`), which fills the volume
+pub const UBOOT_ENV_SIZE: usize = 64 << 10;
+
+/// The flags of the copy of a redundant environment that's in use
+pub const ENV_FLAG_ACTIVE: u8 = 1;
+
+/// The flags of the copy of a redundant environment that's been superseded
+pub const ENV_FLAG_OBSOLETE: u8 = 0;
+
+const ENV_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// A U-Boot environment: its variables, in the order they're written
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct UbootEnv {
+    vars: Vec<(String, String)>,
+}
+
+impl UbootEnv {
+    /// An environment with no variables
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the variable `name`, which must not be set already
+    pub fn var(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> anyhow::Result<Self> {
+        let (name, value) = (name.into(), value.into());
+        ensure!(
+            !name.is_empty() && !name.contains(['=', '\0']) && !name.contains(char::is_whitespace),
+            "bad variable name {name:?}"
+        );
+        ensure!(
+            !value.contains('\0'),
+            "the value of {name:?} contains a NUL"
+        );
+        ensure!(self.get(&name).is_none(), "variable {name:?} is set twice");
+        self.vars.push((name, value));
+        Ok(self)
+    }
+
+    /// The value of the variable `name`, if it's set
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every variable, as (name, value)
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(x, y)| (x.as_str(), y.as_str()))
+    }
+
+    /// How big the header is, for the redundant layout or not
+    fn header_size(redundant: bool) -> usize {
+        match redundant {
+            true => 5,
+            false => 4,
+        }
+    }
+
+    /// Encode the environment in `size` bytes, in the redundant layout with the given flags if
+    /// there are any, or otherwise in the single one
+    pub fn encode(&self, size: usize, flags: Option<u8>) -> anyhow::Result<Vec<u8>> {
+        let header_size = Self::header_size(flags.is_some());
+        let mut data = Vec::new();
+        for (name, value) in &self.vars {
+            data.extend(name.as_bytes());
+            data.push(b'=');
+            data.extend(value.as_bytes());
+            data.push(0);
+        }
+        // An empty environment still needs ending
+        if data.is_empty() {
+            data.push(0);
+        }
+        data.push(0);
+
+        if header_size + data.len() > size {
+            return Err(InstallError::BadImage.msg(format!(
+                "the U-Boot environment takes {}, more than the {} it has room for",
+                format_size((header_size + data.len()) as u64),
+                format_size((size - header_size.min(size)) as u64)
+            )));
+        }
+        data.resize(size - header_size, 0);
+
+        let mut out = ENV_CRC.checksum(&data).to_le_bytes().to_vec();
+        out.extend(flags);
+        out.extend(data);
+        Ok(out)
+    }
+
+    /// Decode an environment filling `bytes`, in the redundant layout or the single one, if its
+    /// CRC is intact; the flags come with it, for the redundant layout
+    pub fn decode(bytes: &[u8], redundant: bool) -> Option<(Self, Option<u8>)> {
+        let header_size = Self::header_size(redundant);
+        let data = bytes.get(header_size..)?;
+        if ENV_CRC.checksum(data).to_le_bytes() != bytes[..4] {
+            return None;
+        }
+        let flags = redundant.then(|| bytes[4]);
+
+        let mut env = Self::new();
+        for entry in data.split(|&x| x == 0).take_while(|x| !x.is_empty()) {
+            let entry = String::from_utf8_lossy(entry);
+            let (name, value) = entry.split_once('=')?;
+            env = env.var(name, value).ok()?;
+        }
+        Some((env, flags))
+    }
+}
+
+/// Parse `name=value` lines; blank lines and those starting with a `#` are ignored
+impl FromStr for UbootEnv {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut env = Self::new();
+        for (line_no, line) in s.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                bail!("line {}: expected name=value", line_no + 1);
+            };
+            env = env
+                .var(name.trim(), value)
+                .map_err(|e| e.context(format!("line {}", line_no + 1)))?;
+        }
+        Ok(env)
+    }
+}
+
+impl fmt::Display for UbootEnv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.vars {
+            writeln!(f, "{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_uboot_env() -> anyhow::Result<()> {
+    // Laid out as `mkenvimage -r -s 256` lays out the three variables, padding with 0xFF
+    const DATA: &[u8] = b"baudrate=115200\0bootcmd=run distro_bootcmd\0bootdelay=3\0\0";
+    let mut fixture = 0x549dfc93u32.to_le_bytes().to_vec();
+    fixture.push(ENV_FLAG_ACTIVE);
+    fixture.extend(DATA);
+    fixture.resize(256, 0xFF);
+
+    let env: UbootEnv = "
+        # Comments and blank lines are skipped
+        baudrate=115200
+        bootcmd=run distro_bootcmd
+        bootdelay=3
+    "
+    .parse()?;
+    assert_eq!(env.get("bootcmd"), Some("run distro_bootcmd"));
+    assert_eq!(
+        UbootEnv::decode(&fixture, true),
+        Some((env.clone(), Some(ENV_FLAG_ACTIVE)))
+    );
+
+    // This pads with 0x00 instead, as U-Boot does, and round-trips
+    let bytes = env.encode(256, Some(ENV_FLAG_ACTIVE))?;
+    assert_eq!(bytes.len(), 256);
+    assert_eq!(bytes[..4], 0x4d286ae5u32.to_le_bytes());
+    assert_eq!(bytes[4..5 + DATA.len()], fixture[4..5 + DATA.len()]);
+    assert_eq!(
+        UbootEnv::decode(&bytes, true),
+        Some((env.clone(), Some(ENV_FLAG_ACTIVE)))
+    );
+    let bytes = env.encode(256, Some(ENV_FLAG_OBSOLETE))?;
+    assert_eq!(
+        UbootEnv::decode(&bytes, true),
+        Some((env.clone(), Some(ENV_FLAG_OBSOLETE)))
+    );
+
+    // The single layout has no flags, and doesn't decode as the redundant one
+    let bytes = env.encode(256, None)?;
+    assert_eq!(bytes[4..8], *b"baud");
+    assert_eq!(UbootEnv::decode(&bytes, false), Some((env.clone(), None)));
+    assert_eq!(UbootEnv::decode(&bytes, true), None);
+
+    // Damage is noticed
+    let mut bytes = env.encode(UBOOT_ENV_SIZE, Some(ENV_FLAG_ACTIVE))?;
+    assert_eq!(bytes.len(), UBOOT_ENV_SIZE);
+    bytes[UBOOT_ENV_SIZE - 1] = 1;
+    assert_eq!(UbootEnv::decode(&bytes, true), None);
+
+    // An empty environment is still a valid one
+    let bytes = UbootEnv::new().encode(256, Some(ENV_FLAG_ACTIVE))?;
+    assert_eq!(bytes[5..7], [0, 0]);
+    assert_eq!(
+        UbootEnv::decode(&bytes, true),
+        Some((UbootEnv::new(), Some(ENV_FLAG_ACTIVE)))
+    );
+    assert_eq!("\n# nothing\n".parse::<UbootEnv>()?, UbootEnv::new());
+
+    // An environment too big for its size isn't
+    let big = UbootEnv::new().var("bootargs", "x".repeat(250))?;
+    let error = big.encode(256, Some(ENV_FLAG_ACTIVE)).unwrap_err();
+    assert_eq!(InstallError::of(&error), InstallError::BadImage);
+    assert!(big.encode(512, Some(ENV_FLAG_ACTIVE)).is_ok());
+
+    // Nor are malformed variables
+    for text in ["bootdelay", "=3", "a b=c", "bootdelay=3\nbootdelay=4"] {
+        assert!(text.parse::<UbootEnv>().is_err(), "{text:?}");
+    }
+    assert_eq!(
+        "bootargs=console=ttyS0 quiet\n"
+            .parse::<UbootEnv>()?
+            .to_string(),
+        "bootargs=console=ttyS0 quiet\n"
+    );
+
+    Ok(())
+}
@@ -1,17 +1,72 @@
 //! This module implements the reformatting/erasing logic.
 
-use super::headers::Ec;
-use super::scan::{BlockContent, Ebt};
-use super::ubinize::{Ubinizer, Volume};
-
+use super::headers::{Ec, Vid, VolType, EC_BACKUP_SIZE, UBI_CRC};
+use super::read::{read_leb, Leb};
+use super::scan::{BlockContent, Ebt, EbtError, EcStats};
+use super::ubinize::{
+    PlannedVolume, Ubinizer, Volume, UBI_LAYOUT_VOLUME_EBS, UBI_LAYOUT_VOLUME_ID,
+};
+
+use crate::error::InstallError;
 use crate::nand::{Nand, NandBlock, NandLayout, PageUtil};
+use crate::util::check_abort;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::num::NonZeroU32;
+use std::ops::{Bound, Range};
+use std::sync::{atomic::AtomicBool, mpsc};
+use std::thread;
+
+/// What [format] did
+#[derive(Debug, Default, Clone)]
+pub struct FormatStats {
+    /// Was the NAND migrated away from AWNAND's SIMULATE_MULTIPLANE layout?
+    pub migrated: bool,
+
+    /// Was that migration resuming one that an earlier format left unfinished (e.g. because of
+    /// a power loss)?
+    pub resumed_migration: bool,
+
+    /// How many blocks were already bad
+    pub bad_blocks_found: u32,
+
+    /// How many blocks failed to erase or program and were marked bad
+    pub bad_blocks_marked: u32,
+
+    /// Was a UBI fastmap found (and erased)?
+    pub fastmap_invalidated: bool,
+
+    /// The spread of erase counters before the format, or None if no block had an EC header
+    pub ec_stats: Option<EcStats>,
 
-use std::collections::{BTreeMap, HashMap, VecDeque};
+    /// How many blocks of an interrupted [write_volumes] were left alone by [format_for_resume],
+    /// for the next write to keep
+    pub blocks_kept: u32,
+}
+
+/// What [write_volumes] did
+#[derive(Debug, Default, Clone)]
+pub struct WriteStats {
+    /// How many PEBs were written for each volume, by volume ID
+    pub pebs_written: BTreeMap<u32, u32>,
+
+    /// How many PEBs an interrupted write had already left as they should be, and so were kept
+    /// rather than written again, for each volume, by volume ID
+    pub pebs_kept: BTreeMap<u32, u32>,
+
+    /// How many blocks failed to program and were marked bad (or were found to have gone bad)
+    pub bad_blocks_marked: u32,
+}
+
+/// Count the blocks in the EBT that are known to be bad
+fn count_bad(ebt: &Ebt) -> u32 {
+    ebt.iter().filter(|&&x| x == BlockContent::Bad).count() as u32
+}
 
 /// These are the actions that may be taken on each block to migrate away from SIMULATE_MULTIPLANE;
 /// this type implements the "command pattern"
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
-enum FormatAction {
+pub(super) enum FormatAction {
     /// Do nothing
     Ignore,
 
@@ -24,7 +79,11 @@ enum FormatAction {
 
 impl FormatAction {
     /// Run the action on the specified NAND block
-    fn execute<B: NandBlock>(self, mut block: B, content: &mut BlockContent) -> anyhow::Result<()> {
+    pub(super) fn execute<B: NandBlock>(
+        self,
+        mut block: B,
+        content: &mut BlockContent,
+    ) -> anyhow::Result<()> {
         let (erase, ec) = match self {
             Self::Ignore => return Ok(()),
             Self::Write(x) => (false, x),
@@ -40,11 +99,26 @@ impl FormatAction {
             }
         }
 
+        Self::write_ec(block, content, ec, erase)
+    }
+
+    /// Write the EC header into a block, which has just been erased if `erased` is set. Where the
+    /// NAND lets the OOB area be used, and there's room, a backup of the header goes along with it
+    /// (see [Ec::encode_backup]).
+    fn write_ec<B: NandBlock>(
+        mut block: B,
+        content: &mut BlockContent,
+        ec: Ec,
+        erased: bool,
+    ) -> anyhow::Result<()> {
         let mut hdr_bytes = vec![0; block.page_size()];
         ec.encode(&mut hdr_bytes)?;
 
-        let program_result = block.program(0, &hdr_bytes);
-        match (program_result, erase) {
+        let program_result = match block.oob_free() >= EC_BACKUP_SIZE {
+            true => block.program_oob(0, &hdr_bytes, &ec.encode_backup()),
+            false => block.program(0, &hdr_bytes),
+        };
+        match (program_result, erased) {
             // An error when we weren't trying to erase is probably from the block being in an
             // unclean state; promote this to an `Erase` and try again:
             (Err(_), false) => Self::Erase(ec.inc_ec()).execute(block, content),
@@ -64,27 +138,71 @@ impl FormatAction {
     }
 }
 
+/// How [format_with_policy] sets the erase counters of the blocks it formats
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum EcPolicy {
+    /// Keep each block's erase counter, counting the erase, with the mean for blocks that have
+    /// none; in a SIMULATE_MULTIPLANE migration, odd blocks take their superblock's
+    #[default]
+    Preserve,
+
+    /// Start every erase counter at 0, throwing away what UBI knows of the wear; only for NAND
+    /// known to be new (e.g. in factory provisioning)
+    Reset,
+
+    /// Like `Preserve`, but in a SIMULATE_MULTIPLANE migration, both blocks of a superblock take
+    /// the higher of their erase counters, so as never to understate the wear
+    PreserveMaxPair,
+}
+
+impl EcPolicy {
+    /// The EC header for a block about to be erased `erases` more times, which goes by the erase
+    /// counter `known` (if there is one to go by)
+    fn ec(self, proto: Ec, known: Option<u64>, erases: u64) -> Ec {
+        match (self, known) {
+            (Self::Reset, _) | (_, None) => proto,
+            (_, Some(ec)) => proto.ec(ec + erases),
+        }
+    }
+}
+
 /// Determine what formatting action needs to be taken to erase a block in a given state
-fn erase_action(content: BlockContent, ec_proto: Ec) -> FormatAction {
+fn erase_action(content: BlockContent, ec_proto: Ec, policy: EcPolicy) -> FormatAction {
+    let known = content.ec().map(|x| x.ec);
+    block_action(content, known, ec_proto, policy)
+}
+
+/// Like [erase_action], but with the block going by the erase counter `known`, which needn't be
+/// its own
+fn block_action(
+    content: BlockContent,
+    known: Option<u64>,
+    ec_proto: Ec,
+    policy: EcPolicy,
+) -> FormatAction {
     use BlockContent::*;
     use FormatAction::*;
 
+    let ec = |erases| policy.ec(ec_proto, known, erases);
     match content {
         // Bad blocks can't have anything done with them
         Bad => Ignore,
 
-        // We can ignore any empty blocks with ECs that already match the prototype's layout fields
-        EcErased(x) if x == ec_proto.ec(x.ec) => Ignore,
+        // A fastmap left behind would describe volumes that no longer exist, and a kernel that
+        // trusts it would corrupt the new ones, so it has to go whatever the other rules say
+        EcData(..) if content.is_fastmap() => Erase(ec(1)),
+
+        // We can ignore any empty blocks whose EC headers already say what they should
+        EcErased(x) if x == ec(0) => Ignore,
 
         // Otherwise, we have to do something.
 
-        // Fully-erased blocks should have the prototypical EC written in, no erase needed first
-        Erased => Write(ec_proto),
+        // Fully-erased blocks should have their EC written in, no erase needed first
+        Erased => Write(ec(0)),
 
-        // If we know the EC, erase and use that. Otherwise, just use the prototypical EC, which
-        // holds the mean erase count.
-        EcData(x, _) | EcErased(x) => Erase(ec_proto.ec(x.ec + 1)),
-        RawVid(_) | Garbage => Erase(ec_proto),
+        // Anything else is erased first. Blocks without an erase counter to go by (including those
+        // whose EC header was lost) get the prototypical EC, which holds the mean erase count.
+        EcData(..) | EcErased(_) | RawVid(_) | VidOnly(_) | Garbage => Erase(ec(1)),
     }
 }
 
@@ -96,61 +214,65 @@ fn migrate_superblock_action(
     even: BlockContent,
     odd: BlockContent,
     ec_proto: Ec,
+    policy: EcPolicy,
 ) -> [FormatAction; 2] {
-    use BlockContent::*;
-    use FormatAction::*;
+    let (even_ec, odd_ec) = (even.ec().map(|x| x.ec), odd.ec().map(|x| x.ec));
 
-    // Determine the action to take on the even block; this is a standard UBI erase:
-    let even_action = erase_action(even, ec_proto);
-
-    // Determine the action to take on the odd block, depending on the even block:
-    let odd_action = match (even, odd) {
-        // Bad blocks can't have anything done with them
-        (_, Bad) => Ignore,
-
-        // If there's already an EC in the odd block, no special even-block analysis is required
-        (_, EcErased(x)) if x == ec_proto.ec(x.ec) => Ignore,
-        (_, EcErased(x) | EcData(x, _)) => Erase(ec_proto.ec(x.ec + 1)),
-
-        // Copy superblock EC (from even physical block) to odd block
-        (EcErased(x) | EcData(x, _), Erased) => Write(ec_proto.ec(x.ec)),
-        (EcErased(x) | EcData(x, _), RawVid(_) | Garbage) => Erase(ec_proto.ec(x.ec + 1)),
-
-        // When the superblock EC cannot be copied, just use the prototypical EC header:
-        (_, Erased) => Write(ec_proto),
-        (_, RawVid(_) | Garbage) => Erase(ec_proto),
+    // The even block goes by its own EC, and the odd block by its own if it has one, or else the
+    // superblock's (which is in the even block), unless the policy says otherwise
+    let (even_known, odd_known) = match policy {
+        EcPolicy::PreserveMaxPair => (even_ec.max(odd_ec), even_ec.max(odd_ec)),
+        EcPolicy::Preserve | EcPolicy::Reset => (even_ec, odd_ec.or(even_ec)),
     };
 
-    [even_action, odd_action]
+    [
+        block_action(even, even_known, ec_proto, policy),
+        block_action(odd, odd_known, ec_proto, policy),
+    ]
+}
+
+/// Is an EC header laid out the way [compute_prototype] lays them out for this page size? Those
+/// that aren't were written before a SIMULATE_MULTIPLANE migration, for a doubled page size.
+fn has_current_layout(ec: &Ec, page_size: u32) -> bool {
+    ec.vid_hdr_offset == page_size && ec.data_offset == page_size * 2
 }
 
 /// Figure out the "prototype" EC header. That is, the header that should be written to every PEB
 /// in the UBI partition.
-fn compute_prototype(
+///
+/// The image_seq is the most common one among the EC headers already laid out for the current
+/// page size, if there are any. Those are only found alongside headers with another layout when a
+/// migration was interrupted, and the image_seq it settled on has to be kept. The mean erase
+/// counter still takes every header into account.
+///
+/// The spread of the erase counters it's worked out from comes back alongside it, or None if no
+/// block has an EC header.
+pub(super) fn compute_prototype(
     layout: NandLayout,
     blocks: impl Iterator<Item = BlockContent>,
-) -> anyhow::Result<Ec> {
+) -> anyhow::Result<(Ec, Option<EcStats>)> {
     let page_size: u32 = layout.bytes_per_page.try_into()?;
 
+    let echdrs: Vec<Ec> = blocks.filter_map(|content| content.ec()).collect();
+    let any_current = echdrs.iter().any(|x| has_current_layout(x, page_size));
+
     // Find the mode of image_seq so that we can reuse most of the EC headers without erasing.
     let mut image_seq_ctrs = HashMap::new();
 
-    // Find the mean EC value
+    // Find the mean EC value, keeping every EC for the stats
     let mut ec_sum = 0;
     let mut ec_count = 0;
+    let mut ecs = vec![];
 
-    for content in blocks {
-        let echdr = match content {
-            BlockContent::EcErased(x) => x,
-            BlockContent::EcData(x, _) => x,
-            _ => continue,
-        };
-
+    for echdr in echdrs {
         // Add a tally to the number of times `echdr.image_seq` is seen
-        *image_seq_ctrs.entry(echdr.image_seq).or_insert(0) += 1;
+        if !any_current || has_current_layout(&echdr, page_size) {
+            *image_seq_ctrs.entry(echdr.image_seq).or_insert(0) += 1;
+        }
 
         ec_sum += echdr.ec;
         ec_count += 1;
+        ecs.push(echdr.ec);
     }
 
     // Determine the mode of `echdr.image_seq`
@@ -162,33 +284,132 @@ fn compute_prototype(
     // Compute mean EC value, rounded to nearest integer, or 1 if ec_count == 0
     let ec = (ec_sum + ec_count / 2).checked_div(ec_count).unwrap_or(1);
 
-    Ok(Ec {
+    let proto = Ec {
         vid_hdr_offset: page_size,
         data_offset: page_size * 2,
 
         ec,
         image_seq,
-    })
+    };
+
+    Ok((proto, EcStats::from_ecs(ecs)))
 }
 
 /// Reformat the UBI partition, performing AWNAND `SIMULATE_MULTIPLANE` migration (if deemed
 /// necessary), otherwise do regular UBI erase.
 ///
 /// This does not write the layout volume, so it is not sufficient for UBI to accept the partition.
-pub fn format<N: Nand>(nand: &mut N, ebt: &mut Ebt) -> anyhow::Result<()> {
+///
+/// A NAND error partway through is returned as an [EbtError], saying which entries of `ebt` can't
+/// be trusted.
+pub fn format<N: Nand>(nand: &mut N, ebt: &mut Ebt) -> anyhow::Result<FormatStats> {
+    format_abortable(nand, ebt, None)
+}
+
+/// Like [format], but checks `abort` before each block, failing with
+/// [Aborted](crate::util::Aborted) once it's raised. Every block handled up to that point is
+/// reflected in `ebt`, so the format can be picked up again later.
+pub fn format_abortable<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<FormatStats> {
+    format_with_policy(nand, ebt, EcPolicy::Preserve, abort)
+}
+
+/// Like [format_abortable], but setting the erase counters as `policy` says
+pub fn format_with_policy<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    policy: EcPolicy,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<FormatStats> {
+    format_inner(nand, ebt, policy, false, abort)
+}
+
+/// Like [format_abortable], but if the partition holds the static volumes of an interrupted
+/// [write_volumes] (and so has no layout volume yet, that being written last), their blocks are
+/// left alone, for the next [write_volumes] to keep whichever of them it would write the same.
+///
+/// A partition with a layout volume, or in need of a SIMULATE_MULTIPLANE migration, is formatted
+/// in full.
+pub fn format_for_resume<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<FormatStats> {
+    format_inner(nand, ebt, EcPolicy::Preserve, true, abort)
+}
+
+/// Could this block, with the given prototypical EC header, be kept for [write_volumes] to reuse?
+/// Only the static volumes' blocks can be: a dynamic volume's LEBs hold no CRC of their data.
+fn is_resumable(content: &BlockContent, proto: Ec) -> bool {
+    match content {
+        BlockContent::EcData(ec, Some(vid)) => {
+            vid.vol_type == VolType::Static
+                && vid.vol_id < UBI_LAYOUT_VOLUME_ID
+                && has_current_layout(ec, proto.vid_hdr_offset)
+                && ec.image_seq == proto.image_seq
+        }
+        _ => false,
+    }
+}
+
+fn format_inner<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    policy: EcPolicy,
+    resume: bool,
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<FormatStats> {
+    nand.ensure_writeable()?;
+    nand.get_layout().validate_for_ubi()?;
+
     let rpt = howudoin::new().label("Erasing blocks");
+    let bad_blocks_found = count_bad(ebt);
+    let fastmap_invalidated = ebt.iter().any(BlockContent::is_fastmap);
 
-    let proto = compute_prototype(nand.get_layout(), ebt.iter().copied())?;
+    let (mut proto, ec_stats) = compute_prototype(nand.get_layout(), ebt.iter().copied())?;
+    if policy == EcPolicy::Reset {
+        proto = proto.ec(0);
+    }
 
     let needs_migration = ebt.iter().any(|x| matches!(x, BlockContent::RawVid(_)));
+
+    // Blocks already carrying the new layout's EC headers alongside the old layout's VID-first
+    // blocks mean an earlier migration was cut short
+    let resumed_migration = needs_migration
+        && ebt.iter().any(|x| match x {
+            BlockContent::EcErased(ec) | BlockContent::EcData(ec, _) => {
+                has_current_layout(ec, proto.vid_hdr_offset)
+            }
+            _ => false,
+        });
+
+    // A layout volume means the last write finished, so there's nothing to resume
+    let keep_resumable = resume
+        && !needs_migration
+        && !ebt.iter().any(
+            |x| matches!(x, BlockContent::EcData(_, Some(vid)) if vid.vol_id == UBI_LAYOUT_VOLUME_ID),
+        );
+    let blocks_kept = if keep_resumable {
+        ebt.iter().filter(|x| is_resumable(x, proto)).count() as u32
+    } else {
+        0
+    };
+
     let work: VecDeque<(u32, FormatAction)> = if needs_migration {
-        rpt.add_info("AWNAND SIMULATE_MULTIPLANE layout detected, performing migration");
+        if resumed_migration {
+            rpt.add_info("Interrupted SIMULATE_MULTIPLANE migration detected, resuming it");
+        } else {
+            rpt.add_info("AWNAND SIMULATE_MULTIPLANE layout detected, performing migration");
+        }
 
         let mut work = VecDeque::new();
         for (i, action) in ebt
             .chunks_exact(2)
             .map(|x| TryInto::<[BlockContent; 2]>::try_into(x).unwrap())
-            .flat_map(|[even, odd]| migrate_superblock_action(even, odd, proto))
+            .flat_map(|[even, odd]| migrate_superblock_action(even, odd, proto, policy))
             .enumerate()
             .filter(|&(_, action)| action != FormatAction::Ignore)
         {
@@ -208,130 +429,559 @@ pub fn format<N: Nand>(nand: &mut N, ebt: &mut Ebt) -> anyhow::Result<()> {
 
         work
     } else {
-        // Migration not needed, just do a regular erase
+        // Migration not needed, just do a regular erase, keeping what's worth resuming
         ebt.iter()
             .enumerate()
-            .map(|(i, s)| (i as u32, erase_action(*s, proto)))
+            .map(|(i, s)| {
+                if keep_resumable && is_resumable(s, proto) {
+                    (i as u32, FormatAction::Ignore)
+                } else {
+                    (i as u32, erase_action(*s, proto, policy))
+                }
+            })
             .filter(|&(_, action)| action != FormatAction::Ignore)
             .collect()
     };
 
     rpt.set_len(u64::try_from(work.len()).ok());
-    if !work.is_empty() {
-        for (block, action) in work.into_iter() {
+    let mut work = work.into_iter().peekable();
+    let mut processed = 0;
+    while let Some((block, action)) = work.next() {
+        check_abort(abort)?;
+
+        // Consecutive blocks being erased to the same EC header (as those with no EC of their own
+        // are) are erased together
+        let mut count = 1;
+        if let FormatAction::Erase(ec) = action {
+            while work
+                .next_if(|&(next, x)| next == block + count && x == action)
+                .is_some()
+            {
+                count += 1;
+            }
+            if count > 1 {
+                erase_run(nand, ebt, block..block + count, ec, processed)?;
+            }
+        }
+        if count == 1 {
             let content = &mut ebt[block as usize];
-
-            action.execute(
-                nand.block(block)?
-                    .ok_or(anyhow::anyhow!("Block unexpectedly marked bad"))?,
-                content,
-            )?;
-            rpt.inc();
+            usable_block(nand, block)
+                .and_then(|x| action.execute(x, content))
+                .map_err(|error| EbtError::new(block, processed, error))?;
         }
+
+        processed += count;
+        rpt.inc_by(count);
     }
 
     rpt.close();
 
+    Ok(FormatStats {
+        migrated: needs_migration,
+        resumed_migration,
+        bad_blocks_found,
+        bad_blocks_marked: count_bad(ebt) - bad_blocks_found,
+        fastmap_invalidated,
+        ec_stats,
+        blocks_kept,
+    })
+}
+
+/// Get a block that the [Ebt] says isn't bad
+fn usable_block<N: Nand>(nand: &mut N, block: u32) -> anyhow::Result<N::Block<'_>> {
+    nand.block(block)?
+        .ok_or(anyhow::anyhow!("Block unexpectedly marked bad"))
+}
+
+/// Carry out [FormatAction::Erase] with `ec` on each of `blocks`, erasing them all with one
+/// [Nand::erase_range]. Should that fail, they're erased again one at a time, so that only the
+/// block at fault is marked bad.
+///
+/// The EC headers are still programmed one at a time: each is a single page, alone in its block,
+/// so there's nothing contiguous to gather them into.
+fn erase_run<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    blocks: Range<u32>,
+    ec: Ec,
+    processed: u32,
+) -> Result<(), EbtError> {
+    let erased = nand.erase_range(blocks.start, blocks.len() as u32).is_ok();
+
+    for (i, block) in (0..).zip(blocks.clone()) {
+        let content = &mut ebt[block as usize];
+        usable_block(nand, block)
+            .and_then(|x| match erased {
+                true => FormatAction::write_ec(x, content, ec, true),
+                false => FormatAction::Erase(ec).execute(x, content),
+            })
+            // Any of the blocks not yet seen to may have been erased already
+            .map_err(|error| EbtError::range(block, blocks.end - block, processed + i, error))?;
+    }
+
     Ok(())
 }
 
+/// Decides which of the erased blocks each LEB goes into, as [write_volumes_with_progress] writes
+/// them
+pub trait BlockPicker {
+    /// Take the next block out of `blocks_by_ec`, which maps each erase count to the usable blocks
+    /// having it, in ascending order; None once there are none left
+    fn pick(&mut self, blocks_by_ec: &mut BTreeMap<u64, Vec<u32>>) -> Option<u32>;
+}
+
+/// Picks blocks from a percentile of the EC distribution, using up every block of that EC before
+/// working out the percentile again.
+///
+/// The reason we use a percentile is so that there's still decent wear-leveling, but we don't
+/// crowd lots of (probably static) blocks onto low EC blocks where they're likely to get moved by
+/// UBI's own wear-leveling algorithm anyway. This is what [write_volumes] uses.
+#[derive(Debug, Clone)]
+pub struct PercentilePicker {
+    percentile: usize,
+
+    /// The blocks left over from the EC last picked
+    current: std::vec::IntoIter<u32>,
+}
+
+impl PercentilePicker {
+    pub fn new(percentile: usize) -> Self {
+        Self {
+            percentile,
+            current: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Default for PercentilePicker {
+    fn default() -> Self {
+        Self::new(25)
+    }
+}
+
+impl BlockPicker for PercentilePicker {
+    fn pick(&mut self, blocks_by_ec: &mut BTreeMap<u64, Vec<u32>>) -> Option<u32> {
+        loop {
+            if let Some(block) = self.current.next() {
+                return Some(block);
+            }
+
+            let sum: usize = blocks_by_ec.values().map(|x| x.len()).sum();
+            let mut threshold = sum * self.percentile / 100;
+            let percentile_ec = blocks_by_ec.iter().find_map(|(&k, v)| {
+                if v.len() >= threshold {
+                    Some(k)
+                } else {
+                    threshold -= v.len();
+                    None
+                }
+            })?;
+            self.current = blocks_by_ec.remove(&percentile_ec)?.into_iter();
+        }
+    }
+}
+
+/// Splits the EC distribution into stripes holding equal numbers of blocks, then picks a block
+/// from each stripe in turn, lowest EC first.
+///
+/// Consecutive LEBs thus land on blocks of varied EC, and a large static volume ends up spread
+/// across the whole EC distribution, rather than clustered where UBI's wear-leveling would want
+/// to move it off again.
+#[derive(Debug, Clone)]
+pub struct StripedPicker {
+    stripes: usize,
+
+    /// The lowest EC of each stripe, worked out on the first pick
+    starts: Vec<u64>,
+
+    /// Which stripe to pick from next
+    next: usize,
+}
+
+impl StripedPicker {
+    pub fn new(stripes: usize) -> Self {
+        Self {
+            stripes: stripes.max(1),
+            starts: vec![],
+            next: 0,
+        }
+    }
+}
+
+impl Default for StripedPicker {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl BlockPicker for StripedPicker {
+    fn pick(&mut self, blocks_by_ec: &mut BTreeMap<u64, Vec<u32>>) -> Option<u32> {
+        if self.starts.is_empty() {
+            let ecs: Vec<u64> = blocks_by_ec
+                .iter()
+                .flat_map(|(&ec, blocks)| blocks.iter().map(move |_| ec))
+                .collect();
+            if ecs.is_empty() {
+                return None;
+            }
+            self.starts = (0..self.stripes)
+                .map(|i| ecs[i * ecs.len() / self.stripes])
+                .collect();
+            self.starts.dedup();
+        }
+
+        // Stripes run out at different times, so skip over any that already have
+        for _ in 0..self.starts.len() {
+            let stripe = self.next % self.starts.len();
+            self.next += 1;
+
+            let end = self
+                .starts
+                .get(stripe + 1)
+                .map_or(Bound::Unbounded, |&x| Bound::Excluded(x));
+            let Some((&ec, _)) = blocks_by_ec
+                .range((Bound::Included(self.starts[stripe]), end))
+                .next()
+            else {
+                continue;
+            };
+
+            let blocks = blocks_by_ec.get_mut(&ec)?;
+            let block = blocks.remove(0);
+            if blocks.is_empty() {
+                blocks_by_ec.remove(&ec);
+            }
+            return Some(block);
+        }
+
+        None
+    }
+}
+
 /// Use the `ubinize` module to write UBI volumes to the flash device.
-pub fn write_volumes<'a, N, V>(nand: &mut N, ebt: &mut Ebt, volumes: V) -> anyhow::Result<()>
+///
+/// Any blocks of static volumes still in `ebt` (as [format_for_resume] leaves them after an
+/// interrupted write) are kept where they hold just what would be written, and their data's CRC
+/// checks out; the image is still read through, to be sure of that. The rest are erased before the
+/// layout volume is written, so a partition that UBI will accept never has stale LEBs in it.
+///
+/// As with [format], a NAND error partway through is returned as an [EbtError].
+pub fn write_volumes<'a, N, V>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    volumes: V,
+) -> anyhow::Result<WriteStats>
 where
     N: Nand,
     V: IntoIterator<Item = Box<dyn Volume + 'a>>,
     for<'x> &'x V: IntoIterator<Item = &'x V::Item>,
 {
-    // Compute the EB size. This is the full block size, minus the first 2 pages (for EC and VID).
+    write_volumes_with_progress(
+        nand,
+        ebt,
+        volumes,
+        PercentilePicker::default(),
+        |_, _| (),
+        None,
+    )
+}
+
+/// Like [write_volumes], but with the choice of physical blocks up to `picker`, and calling
+/// `progress` after each LEB is written, with the number written so far and the number expected in
+/// all. That number is revised as each volume turns out bigger or smaller than expected; while a
+/// volume's size can't be known in advance, it counts just the LEBs read of it so far.
+///
+/// If given, `abort` is checked before each LEB, as in [format_abortable]. The volumes are left
+/// incomplete, so UBI won't accept the partition until they're written again.
+pub fn write_volumes_with_progress<'a, N, V>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    volumes: V,
+    picker: impl BlockPicker,
+    progress: impl FnMut(u32, u32),
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<WriteStats>
+where
+    N: Nand,
+    V: IntoIterator<Item = Box<dyn Volume + 'a>>,
+{
+    let eb_size = eb_size_for_writing(nand)?;
+
+    // Plan out the volumes, with the blocks each is expected to need
+    let mut ubinizer = Ubinizer::new(volumes, eb_size)?;
+    let plan = ubinizer.plan();
+    write_lebs(
+        nand,
+        ebt,
+        plan,
+        |data| ubinizer.next_block(data),
+        picker,
+        progress,
+        abort,
+    )
+}
+
+/// How many LEBs [write_volumes_pipelined] lets the reading of the volumes get ahead of the writing
+const PIPELINE_DEPTH: usize = 2;
+
+/// What the reading side of [write_volumes_pipelined] hands over to the writing side
+enum Piped {
+    /// The volumes, with how many blocks they're expected to take up
+    Plan(Vec<PlannedVolume>),
+
+    /// A LEB, with its data at the start of the buffer
+    Leb(Vid, Vec<u8>, usize),
+
+    /// There are no more LEBs
+    End,
+}
+
+/// Like [write_volumes_with_progress], but reading the volumes on a thread of its own, up to
+/// [PIPELINE_DEPTH] LEBs ahead of the NAND being written, so that a slow image source and a slow
+/// NAND are kept busy at the same time.
+///
+/// The volumes are made by `make_volumes`, on that thread, so they needn't be [Send] themselves;
+/// only what they're made from has to be. An error on either side stops the other: the reading
+/// within a LEB of it, the writing before the next LEB. The NAND ends up just as
+/// [write_volumes_with_progress] would leave it.
+pub fn write_volumes_pipelined<'a, N, V, F>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    make_volumes: F,
+    picker: impl BlockPicker,
+    progress: impl FnMut(u32, u32),
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<WriteStats>
+where
+    N: Nand,
+    F: FnOnce() -> V + Send,
+    V: IntoIterator<Item = Box<dyn Volume + 'a>>,
+{
+    let eb_size = eb_size_for_writing(nand)?;
+
+    thread::scope(|s| {
+        let (tx, rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let (recycle_tx, recycle_rx) = mpsc::channel();
+        s.spawn(move || {
+            let result = read_volumes(make_volumes, eb_size, &tx, recycle_rx);
+            if let Err(error) = result {
+                let _ = tx.send(Err(error));
+            }
+        });
+
+        // Should the reading side stop without saying why, it panicked, and the scope says so
+        let stopped = || anyhow::anyhow!("the volumes stopped being read");
+        let plan = match rx.recv().map_err(|_| stopped())?? {
+            Piped::Plan(plan) => plan,
+            _ => unreachable!("the plan comes first"),
+        };
+        let next_leb = |data: &mut [u8]| match rx.recv().map_err(|_| stopped())?? {
+            Piped::Leb(vid, buf, filled) => {
+                data[..filled].copy_from_slice(&buf[..filled]);
+                let _ = recycle_tx.send(buf);
+                Ok(Some((vid, filled)))
+            }
+            Piped::End => Ok(None),
+            Piped::Plan(_) => unreachable!("the plan comes once"),
+        };
+
+        // Returning drops `rx`, which stops the reading side at its next LEB
+        write_lebs(nand, ebt, plan, next_leb, picker, progress, abort)
+    })
+}
+
+/// The reading side of [write_volumes_pipelined]: make the volumes, and send their plan, then their
+/// LEBs, to `tx`, in buffers taken back from `recycle` where there are any. Stops early, without
+/// error, once the writing side stops listening.
+fn read_volumes<'a, V>(
+    make_volumes: impl FnOnce() -> V,
+    eb_size: NonZeroU32,
+    tx: &mpsc::SyncSender<anyhow::Result<Piped>>,
+    recycle: mpsc::Receiver<Vec<u8>>,
+) -> anyhow::Result<()>
+where
+    V: IntoIterator<Item = Box<dyn Volume + 'a>>,
+{
+    let mut ubinizer = Ubinizer::new(make_volumes(), eb_size)?;
+    if tx.send(Ok(Piped::Plan(ubinizer.plan()))).is_err() {
+        return Ok(());
+    }
+
+    loop {
+        let mut buf = recycle
+            .try_recv()
+            .unwrap_or_else(|_| vec![0; u32::from(eb_size) as usize]);
+        let piped = match ubinizer.next_block(&mut buf)? {
+            Some((vid, filled)) => Piped::Leb(vid, buf, filled),
+            None => Piped::End,
+        };
+        let end = matches!(piped, Piped::End);
+        if tx.send(Ok(piped)).is_err() || end {
+            return Ok(());
+        }
+    }
+}
+
+/// Check that volumes can be written to `nand`, and work out its EB size: the full block size,
+/// minus the first 2 pages (for EC and VID)
+pub(super) fn eb_size_for_writing<N: Nand>(nand: &N) -> anyhow::Result<NonZeroU32> {
+    nand.ensure_writeable()?;
     let layout = nand.get_layout();
+    layout.validate_for_ubi()?;
+
     let eb_size = layout.bytes_per_page as u32 * (layout.pages_per_block - 2);
-    let eb_size = eb_size.try_into().expect("LEB size must be nonzero");
+    Ok(eb_size.try_into().expect("LEB size must be nonzero"))
+}
 
-    // Estimate the needed blocks to complete the flashing operation.
-    let blocks = Ubinizer::estimate_blocks((&volumes).into_iter().map(|x| &**x), eb_size);
+/// Write the LEBs that `next_leb` yields (as [Ubinizer::next_block] does), of the volumes in
+/// `plan`, for [write_volumes_with_progress] and [write_volumes_pipelined]
+fn write_lebs<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    plan: Vec<PlannedVolume>,
+    mut next_leb: impl FnMut(&mut [u8]) -> anyhow::Result<Option<(Vid, usize)>>,
+    mut picker: impl BlockPicker,
+    mut progress: impl FnMut(u32, u32),
+    abort: Option<&AtomicBool>,
+) -> anyhow::Result<WriteStats> {
+    let layout = nand.get_layout();
+    let eb_size = layout.bytes_per_page * (layout.pages_per_block as usize - 2);
+    let whole_blocks = nand.write_policy().whole_blocks();
 
-    // Scan the ebt for only EcErased blocks (ignore all others) and sort them by a percentile of
-    // the EC value. The reason we use a percentile is so that there's still decent wear-leveling,
-    // but we don't crowd lots of (probably static) blocks onto low EC blocks where they're likely
-    // to get moved by UBI's own wear-leveling algorithm anyway.
-    const PERCENTILE: usize = 25;
-    let mut blocks_by_ec: BTreeMap<u64, Vec<u32>> = BTreeMap::new();
+    // UBI reads the layout volume on every attach, so it goes on the least-worn blocks, rather
+    // than whatever `picker` has left by the time it comes (last)
+    let vid_size = layout.bytes_per_page;
+    let mut reserved = lowest_ec_blocks(ebt, vid_size, UBI_LAYOUT_VOLUME_EBS as usize);
 
-    ebt.iter()
+    // The rest are grouped by EC, for `picker` to choose from
+    let mut blocks_by_ec = blocks_by_ec(ebt, vid_size, &reserved.iter().copied().collect());
+
+    // Static LEBs left by an interrupted write, by block, which are kept if they turn out to hold
+    // just what's about to be written
+    let mut leftovers: BTreeMap<u32, Leb> = ebt
+        .iter()
         .enumerate()
         .filter_map(|(i, content)| match content {
-            BlockContent::EcErased(ec) => Some((i as u32, ec.ec)),
+            BlockContent::EcData(ec, Some(vid))
+                if vid.vol_type == VolType::Static
+                    && vid.vol_id < UBI_LAYOUT_VOLUME_ID
+                    && ec.vid_hdr_offset as usize == vid_size
+                    && ec.data_offset as usize == vid_size * 2 =>
+            {
+                let block = i as u32;
+                Some((
+                    block,
+                    Leb {
+                        block,
+                        ec: *ec,
+                        vid: *vid,
+                    },
+                ))
+            }
             _ => None,
         })
-        .for_each(|(block, ec)| {
-            blocks_by_ec.entry(ec).or_default().push(block);
-        });
+        .collect();
+
+    let bad_blocks_before = count_bad(ebt);
+    let mut pebs_written = BTreeMap::new();
+    let mut pebs_kept = BTreeMap::new();
+    let mut processed = 0;
+
+    // Take in the LEBs, into one buffer reused for every block: the VID header's page, then the
+    // LEB itself. Each write programs some prefix of it.
+    let mut buf = vec![0u8; vid_size + eb_size];
+
+    // Iterate over all logical blocks provided, with the bar's length following the volumes as
+    // they turn out, and indeterminate while any volume's size is unknown
+    let mut plan = PlanProgress::new(plan);
+    let mut blocks = plan.expected();
+    let rpt = howudoin::new().label("Programming blocks");
+    rpt.set_len(blocks.1.then_some(u64::from(blocks.0)));
+    while let Some((vid, filled)) = next_leb(&mut buf[vid_size..])? {
+        check_abort(abort)?;
+        plan.leb(vid.vol_id);
+        if plan.expected() != blocks {
+            blocks = plan.expected();
+            rpt.set_len(blocks.1.then_some(u64::from(blocks.0)));
+        }
+        rpt.desc(plan.describe());
+        anyhow::ensure!(
+            vid.vol_type == VolType::Dynamic || vid.data_size as usize == filled,
+            "LEB {} of volume {} has {filled} bytes of data, but its VID header says {}",
+            vid.lnum,
+            vid.vol_id,
+            vid.data_size
+        );
+
+        if let Some(block) = find_kept(nand, &leftovers, vid) {
+            leftovers.remove(&block);
+            *pebs_kept.entry(vid.vol_id).or_default() += 1;
 
-    let mut block_ordering = std::iter::from_fn(move || {
-        let sum: usize = blocks_by_ec.values().map(|x| x.len()).sum();
-        let mut threshold = sum * PERCENTILE / 100;
-        blocks_by_ec
-            .iter()
-            .find_map(|(&k, v)| {
-                if v.len() >= threshold {
-                    Some(k)
-                } else {
-                    threshold -= v.len();
-                    None
-                }
-            })
-            .and_then(|percentile_ec| blocks_by_ec.remove(&percentile_ec))
-    })
-    .flatten();
+            rpt.inc();
+            processed += 1;
+            progress(processed, blocks.0);
+            continue;
+        }
 
-    // Begin ubinizing volumes
-    let mut ubinizer = Ubinizer::new(volumes, eb_size);
-    let vid_size = layout.bytes_per_page;
-    let mut data = Vec::with_capacity(u32::from(eb_size) as usize + vid_size);
-    data.resize(vid_size, 0u8);
-
-    // Iterate over all logical blocks provided by the Ubinizer
-    let rpt = howudoin::new()
-        .label("Programming blocks")
-        .set_len(u64::from(blocks));
-    while let Some(vid) = ubinizer.next_block(&mut data)? {
-        // Prepare the `data` buffer: first, pad it to a multiple of the page size
-        let mut size = data.len() + layout.bytes_per_page - 1;
-        size -= size % layout.bytes_per_page;
-        data.resize(size, 0xFFu8);
+        // Once the layout volume is written, UBI takes every LEB it finds as part of a volume
+        if vid.vol_id == UBI_LAYOUT_VOLUME_ID {
+            erase_leftovers(nand, ebt, &mut leftovers, &mut blocks_by_ec, processed)?;
+        }
 
-        // Writing an "erased" (all-0xFF) page is (theoretically, at least) a no-op. So, as a
-        // simple optimization, strip off any erased page(s) from the end.
-        loop {
-            if data.is_empty() {
-                break;
-            }
-            let minus_last_page = data.len() - layout.bytes_per_page;
-            if data[minus_last_page..].is_erased() {
-                data.truncate(minus_last_page);
-            } else {
-                break;
+        // Pad the data out to a whole page, leaving whatever's past it alone
+        let mut size = vid_size + filled + layout.bytes_per_page - 1;
+        size -= size % layout.bytes_per_page;
+        buf[vid_size + filled..size].fill(0xFF);
+        if whole_blocks {
+            // Fill the rest of the block, so no lower page is left without its pair
+            buf[size..].fill(0xFF);
+            size = buf.len();
+        } else {
+            // Writing an "erased" (all-0xFF) page is (theoretically, at least) a no-op. So, as a
+            // simple optimization, strip off any erased page(s) from the end of the data. Not from
+            // a static volume's, though: every byte of that is covered by the data CRC, and UBI
+            // checks it against whatever a page never programmed happens to read back as.
+            let keep = match vid.vol_type {
+                VolType::Static => size,
+                VolType::Dynamic => vid_size,
+            };
+            while size > keep && buf[size - layout.bytes_per_page..size].is_erased() {
+                size -= layout.bytes_per_page;
             }
         }
 
         // Prepare the VID header to be written out.
-        vid.encode(&mut data[..vid_size])?;
+        vid.encode(&mut buf[..vid_size])?;
+        let data = &buf[..size];
 
         // Loop until the logical block is successfully written. This is a loop because the
         // physical block may end up getting marked bad, and new physical blocks will have to be
         // selected until the logical block can be written.
         'write_loop: loop {
-            // Select physical block to write into
-            let (block_id, ebt_entry, ec) = loop {
-                let block_id = block_ordering
-                    .next()
-                    .ok_or(anyhow::anyhow!("Flash is full"))?;
+            // Select physical block to write into: a reserved one for the layout volume, for as
+            // long as there are any that haven't gone bad
+            let (block_id, ebt_entry, mut ec) = loop {
+                let picked = match vid.vol_id {
+                    UBI_LAYOUT_VOLUME_ID if !reserved.is_empty() => Some(reserved.remove(0)),
+                    _ => picker.pick(&mut blocks_by_ec),
+                };
+                let Some(block_id) = picked else {
+                    // The leftovers aren't worth running out of space for
+                    if leftovers.is_empty() {
+                        return Err(InstallError::FlashFull.msg("Flash is full"));
+                    }
+                    erase_leftovers(nand, ebt, &mut leftovers, &mut blocks_by_ec, processed)?;
+                    continue;
+                };
                 let ebt_entry = &mut ebt[block_id as usize];
                 let ec = match *ebt_entry {
                     BlockContent::EcErased(ec) => ec,
                     _ => unreachable!(),
                 };
-                match nand.block(block_id)? {
+                match nand
+                    .block(block_id)
+                    .map_err(|error| EbtError::new(block_id, processed, error))?
+                {
                     Some(_) => break (block_id, ebt_entry, ec),
                     None => {
                         // Guess it went bad? Try again...
@@ -341,37 +991,224 @@ where
                 };
             };
 
-            // Try to write the block; if that fails, erase it and try again; if that still fails,
-            // mark the block bad.
+            // Try to write the block; if that fails without having written anything, try again
+            // as it is; failing that, erase it and try again; if that still fails, mark the block
+            // bad.
+            let stale = |error| EbtError::new(block_id, processed, error);
+            let mut tried_again = false;
             let mut tried_erase = false;
             loop {
-                let mut block = nand.block(block_id)?.expect("block went bad on its own");
-                if block.program(1, &data).is_ok() {
+                let mut block = nand
+                    .block(block_id)
+                    .map_err(stale)?
+                    .expect("block went bad on its own");
+                if block.program(1, data).is_ok() {
                     *ebt_entry = BlockContent::EcData(ec, Some(vid));
+                    *pebs_written.entry(vid.vol_id).or_default() += 1;
 
                     // Success! Move on to the next logical block.
                     break 'write_loop;
                 }
 
-                if tried_erase {
+                if !tried_again && !tried_erase && is_unwritten(&block, 1, data.len()) {
+                    // The data never made it to the NAND (say, the controller timed out), so the
+                    // block is as good as it was; an erase would only wear it.
+                    tried_again = true;
+                } else if tried_erase {
                     // Block just doesn't want to be written; it's bad.
-                    block.mark_bad()?;
+                    block.mark_bad().map_err(stale)?;
                     *ebt_entry = BlockContent::Bad;
                     break;
                 } else {
                     // Erase the block before trying again.
-                    FormatAction::Erase(ec.inc_ec()).execute(block, ebt_entry)?;
+                    FormatAction::Erase(ec.inc_ec())
+                        .execute(block, ebt_entry)
+                        .map_err(stale)?;
+                    match *ebt_entry {
+                        BlockContent::EcErased(erased) => ec = erased,
+
+                        // It failed to erase, or to take its EC header, and was marked bad
+                        _ => break,
+                    }
                     tried_erase = true;
                 }
             }
         }
 
         rpt.inc();
-        data.truncate(vid_size);
+        processed += 1;
+        progress(processed, blocks.0);
     }
 
     rpt.close();
 
+    Ok(WriteStats {
+        pebs_written,
+        pebs_kept,
+        bad_blocks_marked: count_bad(ebt) - bad_blocks_before,
+    })
+}
+
+/// Are the `len` bytes from `start_page` of a block still erased, as a program that failed before
+/// writing anything leaves them? A block that can't be read is taken to have been written.
+fn is_unwritten<B: NandBlock>(block: &B, start_page: u32, len: usize) -> bool {
+    let mut buf = vec![0; len];
+    block.read(start_page, &mut buf).is_ok() && buf.is_erased()
+}
+
+/// Is `content` an erased block whose EC header puts the VID header and data where
+/// [write_volumes] writes them? Only those are written to; `format` makes sure that's true of
+/// every block. Returns the EC.
+fn usable_ec(content: &BlockContent, vid_size: usize) -> Option<u64> {
+    match content {
+        BlockContent::EcErased(ec)
+            if ec.vid_hdr_offset as usize == vid_size
+                && ec.data_offset as usize == vid_size * 2 =>
+        {
+            Some(ec.ec)
+        }
+        _ => None,
+    }
+}
+
+/// The usable blocks (see [usable_ec]), but for those in `exclude`, grouped by EC
+fn blocks_by_ec(ebt: &Ebt, vid_size: usize, exclude: &BTreeSet<u32>) -> BTreeMap<u64, Vec<u32>> {
+    let mut blocks_by_ec: BTreeMap<u64, Vec<u32>> = BTreeMap::new();
+    for (i, content) in ebt.iter().enumerate() {
+        let block = i as u32;
+        if let Some(ec) = usable_ec(content, vid_size).filter(|_| !exclude.contains(&block)) {
+            blocks_by_ec.entry(ec).or_default().push(block);
+        }
+    }
+    blocks_by_ec
+}
+
+/// The `count` usable blocks (see [usable_ec]) with the lowest ECs, lowest first
+fn lowest_ec_blocks(ebt: &Ebt, vid_size: usize, count: usize) -> Vec<u32> {
+    let mut blocks: Vec<(u64, u32)> = (ebt.iter().enumerate())
+        .filter_map(|(i, content)| Some((usable_ec(content, vid_size)?, i as u32)))
+        .collect();
+    blocks.sort_unstable();
+    blocks.into_iter().take(count).map(|(_, x)| x).collect()
+}
+
+/// Find the block among `leftovers` that holds just the static LEB described by `vid` (other than
+/// its sqnum), with data matching its CRC
+fn find_kept<N: Nand>(nand: &mut N, leftovers: &BTreeMap<u32, Leb>, vid: Vid) -> Option<u32> {
+    if vid.vol_type != VolType::Static {
+        return None;
+    }
+
+    leftovers
+        .values()
+        .filter(|leb| leb.vid.sqnum(vid.sqnum) == vid)
+        .find(|leb| {
+            // A block that can't be read back is as good as a corrupt one
+            read_leb(nand, leb, vid.data_size as usize)
+                .is_ok_and(|data| UBI_CRC.checksum(&data) == vid.data_crc)
+        })
+        .map(|leb| leb.block)
+}
+
+/// How far [write_lebs] has got through the volumes of a [Ubinizer::plan], which come one after
+/// another, each LEB of a volume before any of the next
+struct PlanProgress {
+    plan: Vec<PlannedVolume>,
+
+    /// How many LEBs of each volume in `plan` have come so far
+    done: Vec<u32>,
+
+    /// The volume in `plan` the last LEB was of; those before it are finished
+    current: Option<usize>,
+}
+
+impl PlanProgress {
+    fn new(plan: Vec<PlannedVolume>) -> Self {
+        let done = vec![0; plan.len()];
+        Self {
+            plan,
+            done,
+            current: None,
+        }
+    }
+
+    /// Count a LEB of the volume with the given ID
+    fn leb(&mut self, vol_id: u32) {
+        let start = self.current.unwrap_or(0);
+        let found = self.plan[start..].iter().position(|x| x.vol_id == vol_id);
+        if let Some(index) = found.map(|x| start + x) {
+            self.current = Some(index);
+            self.done[index] += 1;
+        }
+    }
+
+    /// How many LEBs there'll be in all, as far as can be told yet, and whether that's as many as
+    /// expected (rather than just as many as have come of a volume whose size is unknown): the
+    /// finished volumes as they turned out, and the rest as planned, unless more have come
+    fn expected(&self) -> (u32, bool) {
+        let mut known = true;
+        let total = (self.plan.iter().zip(&self.done).enumerate())
+            .map(
+                |(index, (volume, &done))| match (self.current, volume.blocks) {
+                    (Some(current), _) if index < current => done,
+                    (_, Some(blocks)) => blocks.max(done),
+                    (_, None) => {
+                        known = false;
+                        done
+                    }
+                },
+            )
+            .sum();
+        (total, known)
+    }
+
+    /// Say how far the current volume has got, e.g. `rootfs: 12/34 blocks`
+    fn describe(&self) -> String {
+        let Some(index) = self.current else {
+            return String::new();
+        };
+        let volume = &self.plan[index];
+        let name = match (&volume.name, volume.vol_id) {
+            (_, UBI_LAYOUT_VOLUME_ID) => "volume table".into(),
+            (Some(name), _) => name.clone(),
+            (None, vol_id) => format!("volume {vol_id}"),
+        };
+        match volume.blocks {
+            Some(blocks) => format!(
+                "{name}: {}/{} blocks",
+                self.done[index],
+                blocks.max(self.done[index])
+            ),
+            None => format!("{name}: {} blocks", self.done[index]),
+        }
+    }
+}
+
+/// Erase every block in `leftovers`, making them available for [write_volumes_with_progress] to
+/// write into
+fn erase_leftovers<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    leftovers: &mut BTreeMap<u32, Leb>,
+    blocks_by_ec: &mut BTreeMap<u64, Vec<u32>>,
+    processed: u32,
+) -> anyhow::Result<()> {
+    for (block_id, leb) in std::mem::take(leftovers) {
+        let stale = |error| EbtError::new(block_id, processed, error);
+        let ebt_entry = &mut ebt[block_id as usize];
+        let Some(block) = nand.block(block_id).map_err(stale)? else {
+            *ebt_entry = BlockContent::Bad;
+            continue;
+        };
+
+        FormatAction::Erase(leb.ec.inc_ec())
+            .execute(block, ebt_entry)
+            .map_err(stale)?;
+        if let BlockContent::EcErased(ec) = *ebt_entry {
+            blocks_by_ec.entry(ec.ec).or_default().push(block_id);
+        }
+    }
+
     Ok(())
 }
 
@@ -388,17 +1225,1191 @@ mod test {
         bytes_per_page: 128,
     };
 
+    #[test]
+    fn test_compute_prototype() -> anyhow::Result<()> {
+        use super::super::Vid;
+
+        let ec = |ec| Ec {
+            ec,
+            image_seq: 7,
+            ..Default::default()
+        };
+        let blocks = [
+            BlockContent::EcErased(ec(10)),
+            BlockContent::Bad,
+            BlockContent::EcData(ec(30), Some(Vid::default())),
+            BlockContent::EcErased(Ec {
+                image_seq: 3,
+                ..ec(10)
+            }),
+            BlockContent::Garbage,
+            BlockContent::EcErased(ec(1000)),
+            BlockContent::Erased,
+        ];
+
+        let (proto, stats) = compute_prototype(TEST_LAYOUT, blocks.into_iter())?;
+        assert_eq!((proto.ec, proto.image_seq), (263, 7));
+        assert_eq!((proto.vid_hdr_offset, proto.data_offset), (128, 256));
+
+        let stats = stats.unwrap();
+        assert_eq!(
+            (stats.count, stats.min, stats.median, stats.max),
+            (4, 10, 10, 1000)
+        );
+        assert_eq!(stats.mean, 262.5);
+        assert_eq!(stats.bucket_width, 124);
+        assert_eq!(stats.histogram, [3, 0, 0, 0, 0, 0, 0, 1]);
+
+        // Without any EC headers to go on, the prototype falls back on an EC of 1
+        for blocks in [&[][..], &[BlockContent::Bad; 4], &[BlockContent::Erased]] {
+            let (proto, stats) = compute_prototype(TEST_LAYOUT, blocks.iter().copied())?;
+            assert_eq!((proto.ec, proto.image_seq), (1, 0));
+            assert_eq!(stats, None);
+        }
+
+        // One EC, or many equal ones, make for a single, narrow bucket
+        let blocks = [BlockContent::EcErased(ec(60_000)); 3];
+        let stats = compute_prototype(TEST_LAYOUT, blocks.into_iter())?
+            .1
+            .unwrap();
+        assert_eq!(
+            (stats.min, stats.median, stats.max),
+            (60_000, 60_000, 60_000)
+        );
+        assert_eq!((stats.histogram[0], stats.bucket_width), (3, 1));
+
+        // Once some headers are laid out for this page size, only theirs decide the image_seq
+        let migrated = Ec {
+            vid_hdr_offset: 128,
+            data_offset: 256,
+            image_seq: 3,
+            ..ec(20)
+        };
+        let blocks = [
+            BlockContent::EcErased(ec(10)),
+            BlockContent::EcData(ec(10), None),
+            BlockContent::EcErased(migrated),
+        ];
+        let (proto, _) = compute_prototype(TEST_LAYOUT, blocks.into_iter())?;
+        assert_eq!((proto.ec, proto.image_seq), (13, 3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_erase_action() {
+        use super::super::{ubinize::UBI_FM_SB_VOLUME_ID, Vid};
+        use BlockContent::*;
+        use EcPolicy::*;
+        use FormatAction::*;
+
+        let proto = Ec {
+            ec: 5,
+            vid_hdr_offset: 128,
+            data_offset: 256,
+            image_seq: 7,
+        };
+        let ec = |ec| proto.ec(ec);
+        let old = |ec| Ec {
+            vid_hdr_offset: 256,
+            data_offset: 512,
+            ..proto.ec(ec)
+        };
+        let fastmap = Vid {
+            vol_id: UBI_FM_SB_VOLUME_ID,
+            ..Default::default()
+        };
+
+        // Under Reset, format hands out a prototype with an EC of 0
+        for (content, policy, expected) in [
+            (Bad, Preserve, Ignore),
+            (Erased, Preserve, Write(proto)),
+            (EcErased(ec(9)), Preserve, Ignore),
+            (EcErased(old(9)), Preserve, Erase(ec(10))),
+            (EcData(ec(9), None), Preserve, Erase(ec(10))),
+            (EcData(ec(9), Some(fastmap)), Preserve, Erase(ec(10))),
+            (Garbage, Preserve, Erase(proto)),
+            (RawVid(Vid::default()), Preserve, Erase(proto)),
+            (VidOnly(Vid::default()), Preserve, Erase(proto)),
+            (VidOnly(Vid::default()), PreserveMaxPair, Erase(proto)),
+            (EcData(ec(9), None), PreserveMaxPair, Erase(ec(10))),
+            (Bad, Reset, Ignore),
+            (Erased, Reset, Write(ec(0))),
+            (EcErased(ec(0)), Reset, Ignore),
+            (EcErased(ec(9)), Reset, Erase(ec(0))),
+            (EcData(ec(9), Some(fastmap)), Reset, Erase(ec(0))),
+            (Garbage, Reset, Erase(ec(0))),
+            (VidOnly(Vid::default()), Reset, Erase(ec(0))),
+        ] {
+            let proto = if policy == Reset { ec(0) } else { proto };
+            let action = erase_action(content, proto, policy);
+            assert_eq!(action, expected, "{content:?} under {policy:?}");
+        }
+
+        for (even, odd, policy, expected) in [
+            // An untouched superblock: the EC header is in the even block, and the VID header
+            // (if it's in use) at the start of the odd block
+            (
+                EcData(old(10), None),
+                RawVid(Vid::default()),
+                Preserve,
+                [Erase(ec(11)); 2],
+            ),
+            (
+                EcErased(old(10)),
+                Erased,
+                Preserve,
+                [Erase(ec(11)), Write(ec(10))],
+            ),
+            (
+                EcData(old(10), None),
+                Bad,
+                Preserve,
+                [Erase(ec(11)), Ignore],
+            ),
+            (Garbage, Erased, Preserve, [Erase(proto), Write(proto)]),
+            (Bad, Garbage, Preserve, [Ignore, Erase(proto)]),
+            // An odd block that lost its EC header goes by its superblock's, like any other
+            (
+                EcData(old(10), None),
+                VidOnly(Vid::default()),
+                Preserve,
+                [Erase(ec(11)); 2],
+            ),
+            // A superblock that an interrupted migration got to, with ECs of its own in each block
+            (EcErased(ec(10)), EcErased(ec(3)), Preserve, [Ignore; 2]),
+            (Garbage, EcErased(ec(3)), Preserve, [Erase(proto), Ignore]),
+            // Taking the higher EC of the pair only matters once both blocks have one
+            (
+                EcData(old(10), None),
+                RawVid(Vid::default()),
+                PreserveMaxPair,
+                [Erase(ec(11)); 2],
+            ),
+            (
+                EcErased(old(10)),
+                Erased,
+                PreserveMaxPair,
+                [Erase(ec(11)), Write(ec(10))],
+            ),
+            (
+                EcErased(ec(10)),
+                EcErased(ec(3)),
+                PreserveMaxPair,
+                [Ignore, Erase(ec(11))],
+            ),
+            (
+                Garbage,
+                EcErased(ec(3)),
+                PreserveMaxPair,
+                [Erase(ec(4)), Ignore],
+            ),
+            (
+                Erased,
+                EcData(ec(20), None),
+                PreserveMaxPair,
+                [Write(ec(20)), Erase(ec(21))],
+            ),
+            (Bad, EcErased(ec(3)), PreserveMaxPair, [Ignore; 2]),
+            // Resetting ignores every EC found
+            (
+                EcData(old(10), None),
+                RawVid(Vid::default()),
+                Reset,
+                [Erase(ec(0)); 2],
+            ),
+            (EcErased(ec(0)), Erased, Reset, [Ignore, Write(ec(0))]),
+            (EcErased(ec(10)), Bad, Reset, [Erase(ec(0)), Ignore]),
+        ] {
+            let proto = if policy == Reset { ec(0) } else { proto };
+            let actions = migrate_superblock_action(even, odd, proto, policy);
+            assert_eq!(actions, expected, "{even:?}, {odd:?} under {policy:?}");
+        }
+    }
+
+    /// A NAND as AWNAND's SIMULATE_MULTIPLANE leaves it, with every other superblock holding a
+    /// LEB. The EC headers are split evenly between two image_seqs, so that neither is the obvious
+    /// one to keep.
+    fn multiplane_nand() -> anyhow::Result<SimNand> {
+        use super::super::multiplane::{MultiplaneNand, Superblock};
+
+        let layout = NandLayout {
+            blocks: 32,
+            ..TEST_LAYOUT
+        };
+        let mut builder = MultiplaneNand::new(layout);
+        for superblock in 0..layout.blocks / 2 {
+            builder = builder
+                .ec(superblock, 10 + u64::from(superblock))
+                .image_seq(superblock, [0x1111, 0x2222][superblock as usize % 2]);
+            if superblock % 2 == 0 {
+                let leb = Superblock::Leb {
+                    vol_id: 1,
+                    lnum: superblock,
+                };
+                builder = builder.superblock(superblock, leb);
+            }
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_format_migration() -> anyhow::Result<()> {
+        let mut nand = multiplane_nand()?;
+        let mut ebt = scan_blocks(&mut nand)?;
+        assert_eq!(
+            ebt.iter()
+                .filter(|x| matches!(x, BlockContent::RawVid(_)))
+                .count(),
+            8
+        );
+
+        let stats = format(&mut nand, &mut ebt)?;
+        assert!(stats.migrated && !stats.resumed_migration);
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        // Every block now has the same EC header layout and image_seq
+        let ecs: Vec<Ec> = ebt
+            .iter()
+            .map(|x| match x {
+                BlockContent::EcErased(ec) => *ec,
+                other => panic!("{other:?} left after the migration"),
+            })
+            .collect();
+        assert!(ecs
+            .iter()
+            .all(|ec| has_current_layout(ec, 128) && ec.image_seq == ecs[0].image_seq));
+
+        // The odd blocks of superblocks take their even block's EC (counting an erase, if they
+        // needed one), rather than the mean
+        assert_eq!((ecs[0].ec, ecs[1].ec), (11, 11));
+        assert_eq!((ecs[2].ec, ecs[3].ec), (12, 11));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_migration_interrupted() -> anyhow::Result<()> {
+        use crate::nand::CountingNand;
+        use crate::ubi::rescan_range;
+
+        // Cut the migration short once it's well into the VID superblocks, which go last
+        let mut nand = CountingNand::new(multiplane_nand()?);
+        let mut ebt = scan_blocks(&mut nand)?;
+        nand.fail_after_mutations = Some(30);
+        let error = format(&mut nand, &mut ebt).unwrap_err();
+        let error = error.downcast_ref::<EbtError>().expect("not an EbtError");
+        nand.fail_after_mutations = None;
+        rescan_range(&mut nand, &mut ebt, error.block..error.block + 1)?;
+        let image_seq = ebt
+            .iter()
+            .find_map(|x| match x {
+                BlockContent::EcErased(ec) if has_current_layout(ec, 128) => Some(ec.image_seq),
+                _ => None,
+            })
+            .unwrap();
+
+        // Whatever image_seq the first attempt settled on, picking up from a fresh scan keeps it
+        let mut ebt = scan_blocks(&mut nand)?;
+        assert!(ebt.iter().any(|x| matches!(x, BlockContent::RawVid(_))));
+        let stats = format(&mut nand, &mut ebt)?;
+        assert!(stats.migrated && stats.resumed_migration);
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+        assert!(ebt.iter().all(|x| matches!(
+            x,
+            BlockContent::EcErased(ec) if has_current_layout(ec, 128) && ec.image_seq == image_seq
+        )));
+
+        // ...and with the migration finished, there's nothing left to resume
+        let stats = format(&mut nand, &mut ebt)?;
+        assert!(!stats.migrated && !stats.resumed_migration);
+
+        Ok(())
+    }
+
     #[test]
     fn test_format_blank() -> anyhow::Result<()> {
         let mut nand = SimNand::new(TEST_LAYOUT);
 
         let mut ebt = scan_blocks(&mut nand)?;
-        format(&mut nand, &mut ebt)?;
+        let stats = format(&mut nand, &mut ebt)?;
+        assert!(!stats.migrated);
+        assert_eq!(stats.bad_blocks_found, 0);
+        assert_eq!(stats.bad_blocks_marked, 0);
 
         // Make sure `format` updated `ebt`:
         let ebt2 = scan_blocks(&mut nand)?;
         assert_eq!(ebt, ebt2);
 
+        // Resetting the erase counters takes them from 1 down to 0, and formatting again as usual
+        // keeps them there
+        assert!(ebt.iter().all(|x| x.ec().unwrap().ec == 1));
+        format_with_policy(&mut nand, &mut ebt, EcPolicy::Reset, None)?;
+        format(&mut nand, &mut ebt)?;
+        assert!(ebt.iter().all(|x| x.ec().unwrap().ec == 0));
+        assert_eq!(ebt, scan_blocks(&mut nand)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_erase_ranges() -> anyhow::Result<()> {
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let garbage = vec![0x5A; TEST_LAYOUT.bytes_per_page];
+        for block in 0..TEST_LAYOUT.blocks {
+            nand.block(block)?.unwrap().program(0, &garbage)?;
+        }
+        nand.block(5)?.unwrap().mark_bad()?;
+        nand.set_erase_failure(9, true)?;
+
+        // Every block gets the prototype, so they're erased in runs split only by the bad block;
+        // the second run fails, and is redone a block at a time to find the one to mark bad
+        let mut ebt = scan_blocks(&mut nand)?;
+        let stats = format(&mut nand, &mut ebt)?;
+        assert_eq!(nand.take_erase_ranges(), [(0, 5), (6, 10)]);
+        assert_eq!((stats.bad_blocks_found, stats.bad_blocks_marked), (1, 1));
+        assert_eq!(ebt[9], BlockContent::Bad);
+        assert!(ebt
+            .iter()
+            .filter(|&&x| x != BlockContent::Bad)
+            .all(|x| x.ec().unwrap().ec == 1));
+        assert_eq!(ebt, scan_blocks(&mut nand)?);
+
+        // Resetting the erase counters erases every good block, in runs between the bad ones;
+        // once reset, there's nothing left to erase
+        format_with_policy(&mut nand, &mut ebt, EcPolicy::Reset, None)?;
+        assert_eq!(nand.take_erase_ranges(), [(0, 5), (6, 3), (10, 6)]);
+        format_with_policy(&mut nand, &mut ebt, EcPolicy::Reset, None)?;
+        assert!(nand.take_erase_ranges().is_empty());
+        assert_eq!(ebt, scan_blocks(&mut nand)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_protected() -> anyhow::Result<()> {
+        use crate::format::{purge_boot0, raw::write_raw_image};
+        use crate::nand::{CountingNand, WriteProtection};
+
+        // Start from a NAND with something worth erasing on it
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+
+        nand.set_write_protection(Some(WriteProtection::ReadOnly));
+        let mut nand = CountingNand::new(nand);
+
+        let errors = [
+            format(&mut nand, &mut ebt).err(),
+            write_volumes(&mut nand, &mut ebt, Vec::<Box<dyn Volume>>::new()).err(),
+            purge_boot0(&mut nand).err(),
+            write_raw_image(&mut nand, &mut &[0u8; 128][..], false).err(),
+        ];
+        for error in errors {
+            let error = error.expect("write-protected NAND was written");
+            assert_eq!(
+                error.downcast_ref::<WriteProtection>(),
+                Some(&WriteProtection::ReadOnly)
+            );
+        }
+        assert_eq!(nand.counts.get().mutations(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_op_counts() -> anyhow::Result<()> {
+        use crate::fixtures::{dirty_nand, static_volume, synthetic_data, CANARY_LAYOUT};
+        use crate::nand::{CountingNand, OpCounts};
+
+        let mut nand = dirty_nand(CANARY_LAYOUT)?;
+        let mut ebt = scan_blocks(&mut nand)?;
+        let mut nand = CountingNand::new(nand);
+
+        // `format` works from the EBT alone, so it mustn't read anything
+        format(&mut nand, &mut ebt)?;
+        assert_eq!(
+            nand.counts.take(),
+            OpCounts {
+                programs: 49,
+                erases: 41,
+                ..Default::default()
+            }
+        );
+
+        let data = synthetic_data(16 * 14 * 512);
+        let mut reader = &data[..];
+        let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+        write_volumes(&mut nand, &mut ebt, volumes)?;
+
+        // One program per PEB: 16 LEBs of data, plus both copies of the layout volume
+        assert_eq!(
+            nand.counts.take(),
+            OpCounts {
+                programs: 18,
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_volumes_large() -> anyhow::Result<()> {
+        use super::super::{read_volume, ubinize::BasicVolume, VolType, VolumeSelector};
+        use crate::fixtures::synthetic_data;
+
+        const LARGE_LAYOUT: NandLayout = NandLayout {
+            blocks: 128,
+            pages_per_block: 64,
+            bytes_per_page: 2048,
+        };
+
+        let mut nand = SimNand::new(LARGE_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+
+        // A few MiB that don't end on a LEB boundary, with erased-looking pages inside and at the
+        // end, which mustn't get lost along with the padding
+        let mut rootfs = synthetic_data(5 << 20);
+        rootfs[1 << 20..(1 << 20) + 8192].fill(0xFF);
+        rootfs.extend([0xFF; 3000]);
+        let env = synthetic_data(100_000);
+
+        let (mut rootfs_reader, mut env_reader) = (&rootfs[..], &env[..]);
+        let volumes: Vec<Box<dyn Volume>> = vec![
+            Box::new(
+                BasicVolume::new(VolType::Dynamic)
+                    .name("env")
+                    .size(env.len() as u64)
+                    .image(&mut env_reader),
+            ),
+            Box::new(
+                BasicVolume::new(VolType::Static)
+                    .name("rootfs")
+                    .size(rootfs.len() as u64)
+                    .image(&mut rootfs_reader),
+            ),
+        ];
+        let stats = write_volumes(&mut nand, &mut ebt, volumes)?;
+        let leb_size = 62 * 2048;
+        assert_eq!(
+            stats.pebs_written[&1],
+            rootfs.len().div_ceil(leb_size) as u32
+        );
+
+        let mut out = Vec::new();
+        read_volume(
+            &mut nand,
+            &ebt,
+            &VolumeSelector::Name("rootfs".into()),
+            &mut out,
+        )?;
+        assert!(out == rootfs);
+
+        // A dynamic volume reads back whole LEBs
+        out.clear();
+        read_volume(
+            &mut nand,
+            &ebt,
+            &VolumeSelector::Name("env".into()),
+            &mut out,
+        )?;
+        assert!(out[..env.len()] == env[..]);
+        assert!(out[env.len()..].is_erased());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_volumes_static_tail() -> anyhow::Result<()> {
+        use super::super::{read_volume, VolumeSelector};
+        use crate::fixtures::{static_volume, synthetic_data};
+
+        let page = TEST_LAYOUT.bytes_per_page;
+        let leb_size = page * (TEST_LAYOUT.pages_per_block as usize - 2);
+
+        // What writing a static volume of `data` programs, beyond the layout volume
+        let write = |data: &[u8]| -> anyhow::Result<u64> {
+            let mut nand = SimNand::new(TEST_LAYOUT);
+            let mut ebt = scan_blocks(&mut nand)?;
+            format(&mut nand, &mut ebt)?;
+            let before = nand.stats();
+
+            let mut reader = data;
+            let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+            write_volumes(&mut nand, &mut ebt, volumes)?;
+
+            let mut out = Vec::new();
+            let selector = VolumeSelector::Name("fixture".into());
+            read_volume(&mut nand, &ebt, &selector, &mut out)?;
+            assert!(out == data, "{} bytes", data.len());
+            Ok(nand.stats().since(&before).bytes_programmed)
+        };
+        // A one-byte volume takes a page for its VID header and one for its data
+        let layout_volume = write(&[0x5A])? - 2 * page as u64;
+
+        // Images ending in erased-looking pages, on and around page and LEB boundaries: every page
+        // of data is programmed, however it ends
+        for len in [
+            leb_size,
+            leb_size * 2,
+            leb_size - page,
+            leb_size - 1,
+            leb_size + 1,
+            page * 5,
+            page * 5 - 1,
+            page * 5 + 1,
+        ] {
+            let mut data = synthetic_data(len);
+            let tail = len.min(300);
+            data[len - tail..].fill(0xFF);
+
+            let programmed: usize = data
+                .chunks(leb_size)
+                .map(|leb| page + leb.len().next_multiple_of(page))
+                .sum();
+            assert_eq!(write(&data)? - layout_volume, programmed as u64, "{len}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_volumes_pipelined() -> anyhow::Result<()> {
+        use super::super::{ubinize::BasicVolume, VolType};
+        use crate::fixtures::{static_volume, synthetic_data};
+        use std::io::{self, Read};
+
+        const LAYOUT: NandLayout = NandLayout {
+            blocks: 64,
+            ..TEST_LAYOUT
+        };
+        const LEB_SIZE: usize = 14 * 128;
+
+        let data = synthetic_data(20 * LEB_SIZE + 300);
+        let env = synthetic_data(5000);
+        fn fixture_volumes<'a>(
+            data: &'a mut &[u8],
+            env: &'a mut &[u8],
+        ) -> Vec<Box<dyn Volume + 'a>> {
+            vec![
+                Box::new(static_volume(data)),
+                Box::new(
+                    BasicVolume::new(VolType::Dynamic)
+                        .name("env")
+                        .size(env.len() as u64)
+                        .image(env),
+                ),
+            ]
+        }
+
+        // Written from another thread or not, the volumes end up just the same
+        let mut serial = SimNand::new(LAYOUT);
+        let mut serial_ebt = scan_blocks(&mut serial)?;
+        format(&mut serial, &mut serial_ebt)?;
+        let mut pipelined = serial.clone();
+        let mut pipelined_ebt = serial_ebt.clone();
+
+        let (mut data_reader, mut env_reader) = (&data[..], &env[..]);
+        let volumes = fixture_volumes(&mut data_reader, &mut env_reader);
+        let serial_stats = write_volumes(&mut serial, &mut serial_ebt, volumes)?;
+
+        let (mut data_reader, mut env_reader) = (&data[..], &env[..]);
+        let (data_reader, env_reader) = (&mut data_reader, &mut env_reader);
+        let mut progress = vec![];
+        let pipelined_stats = write_volumes_pipelined(
+            &mut pipelined,
+            &mut pipelined_ebt,
+            move || fixture_volumes(data_reader, env_reader),
+            PercentilePicker::default(),
+            |done, total| progress.push((done, total)),
+            None,
+        )?;
+        assert_eq!(pipelined_stats.pebs_written, serial_stats.pebs_written);
+        assert_eq!(pipelined_ebt, serial_ebt);
+        assert_eq!(scan_blocks(&mut pipelined)?, pipelined_ebt);
+        let (mut serial_image, mut pipelined_image) = (vec![], vec![]);
+        serial.save(&mut serial_image)?;
+        pipelined.save(&mut pipelined_image)?;
+        assert!(serial_image == pipelined_image);
+        assert_eq!(progress.len(), 26);
+        assert_eq!(progress.last(), Some(&(26, 26)));
+
+        // An error reading the volumes reaches the writing side...
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("source went away"))
+            }
+        }
+        let mut nand = SimNand::new(LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+        let make_volumes = || -> Vec<Box<dyn Volume>> {
+            let image = io::Cursor::new(synthetic_data(3 * LEB_SIZE)).chain(Failing);
+            vec![Box::new(
+                BasicVolume::new(VolType::Static)
+                    .name("broken")
+                    .size(10 * LEB_SIZE as u64)
+                    .image(image),
+            )]
+        };
+        let error = write_volumes_pipelined(
+            &mut nand,
+            &mut ebt,
+            make_volumes,
+            PercentilePicker::default(),
+            |_, _| (),
+            None,
+        )
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("source went away"));
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        // ...and one writing them stops the reading, however much more there was to read
+        let make_volumes = || -> Vec<Box<dyn Volume>> {
+            vec![Box::new(
+                BasicVolume::new(VolType::Static)
+                    .name("endless")
+                    .size(1 << 40)
+                    .image(io::repeat(0x5A)),
+            )]
+        };
+        let error = write_volumes_pipelined(
+            &mut nand,
+            &mut ebt,
+            make_volumes,
+            PercentilePicker::default(),
+            |_, _| (),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(InstallError::of(&error), InstallError::FlashFull);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_volumes_paired() -> anyhow::Result<()> {
+        use crate::fixtures::{static_volume, synthetic_data};
+        use crate::nand::{test_pairing, WritePolicy};
+
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        nand.set_write_policy(WritePolicy::PairedPages {
+            pairing: test_pairing,
+        })?;
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+
+        // The layout volume's LEBs and the last data LEB only partly fill their blocks, leaving
+        // lower pages unpaired unless padded out
+        let data = synthetic_data(3 * 14 * 128 + 100);
+        let mut reader = &data[..];
+        let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+        let stats = write_volumes(&mut nand, &mut ebt, volumes)?;
+        assert_eq!(stats.bad_blocks_marked, 0);
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_volumes_program_retry() -> anyhow::Result<()> {
+        use super::super::{read_volume, VolumeSelector};
+        use crate::fixtures::{static_volume, synthetic_data};
+        use crate::nand::OpStats;
+
+        let data = synthetic_data(3 * 14 * 128 + 100);
+
+        // Every block's first `failures` programs fail, having written `torn` pages first
+        let write = |failures: u32, torn: u32| -> anyhow::Result<(OpStats, Ebt, Ebt, WriteStats)> {
+            let mut nand = SimNand::new(TEST_LAYOUT);
+            let mut ebt = scan_blocks(&mut nand)?;
+            format(&mut nand, &mut ebt)?;
+            let formatted = ebt.clone();
+            let before = nand.stats();
+            for index in 0..TEST_LAYOUT.blocks {
+                nand.set_program_failures(index, failures)?;
+                nand.set_torn_programs(index, torn)?;
+            }
+
+            let mut reader = &data[..];
+            let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+            let stats = write_volumes(&mut nand, &mut ebt, volumes)?;
+            assert_eq!(stats.bad_blocks_marked, 0);
+            assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+            let mut out = Vec::new();
+            let selector = VolumeSelector::Name("fixture".into());
+            read_volume(&mut nand, &ebt, &selector, &mut out)?;
+            assert!(out == data);
+            Ok((nand.stats().since(&before), formatted, ebt, stats))
+        };
+
+        // How much each written block's EC went up by
+        let wear = |formatted: &Ebt, ebt: &Ebt| -> Vec<u64> {
+            let written = formatted.iter().zip(ebt.iter()).filter_map(|x| match x {
+                (BlockContent::EcErased(before), BlockContent::EcData(after, Some(_))) => {
+                    Some(after.ec - before.ec)
+                }
+                _ => None,
+            });
+            written.collect()
+        };
+
+        // A program that wrote nothing is tried again as it is, without an erase
+        let (ops, formatted, ebt, stats) = write(1, 0)?;
+        let written = wear(&formatted, &ebt);
+        assert_eq!(written.len() as u32, stats.pebs_written.values().sum());
+        assert!(written.iter().all(|&x| x == 0), "{written:?}");
+        assert_eq!(ops.erases, 0);
+
+        // One that wrote part of the data leaves the block to be erased first, as does a second
+        // failure
+        for (failures, torn) in [(1, 1), (2, 0)] {
+            let (_, formatted, ebt, _) = write(failures, torn)?;
+            let written = wear(&formatted, &ebt);
+            assert!(!written.is_empty());
+            assert!(
+                written.iter().all(|&x| x == 1),
+                "{failures} {torn}: {written:?}"
+            );
+        }
+
+        // A block that can't be written even after an erase is still marked bad, after three tries
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+        for index in 0..TEST_LAYOUT.blocks {
+            nand.set_program_failure(index, true)?;
+        }
+        let before = nand.stats();
+        let mut reader = &data[..];
+        let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+        assert!(write_volumes(&mut nand, &mut ebt, volumes).is_err());
+        let stats = nand.stats().since(&before);
+        assert!(stats.mark_bads > 0);
+        assert_eq!(stats.programs, 3 * stats.mark_bads);
+        assert_eq!(stats.erases, stats.mark_bads);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_progress() -> anyhow::Result<()> {
+        use super::super::{ubinize::BasicVolume, VolType};
+        use crate::fixtures::{static_volume, synthetic_data};
+
+        // The total follows the volumes as they turn out: a volume of unknown size counts as what
+        // has come of it so far
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+        let (data, stream) = (synthetic_data(2 * 14 * 128), synthetic_data(5000));
+        let (mut data, mut stream) = (&data[..], &stream[..]);
+        let volumes: Vec<Box<dyn Volume>> = vec![
+            Box::new(static_volume(&mut data)),
+            Box::new(BasicVolume::new(VolType::Dynamic).image(&mut stream)),
+        ];
+        let mut progress = vec![];
+        write_volumes_with_progress(
+            &mut nand,
+            &mut ebt,
+            volumes,
+            PercentilePicker::default(),
+            |done, total| progress.push((done, total)),
+            None,
+        )?;
+        assert_eq!(
+            progress,
+            [(1, 4), (2, 4), (3, 5), (4, 6), (5, 7), (6, 7), (7, 7)]
+        );
+
+        // A volume coming up short is done with once the next begins; one running over stretches
+        // the total as it goes
+        let planned = |vol_id, name: Option<&str>, blocks| PlannedVolume {
+            vol_id,
+            name: name.map(String::from),
+            blocks,
+        };
+        let mut plan = PlanProgress::new(vec![
+            planned(0, Some("rootfs"), Some(3)),
+            planned(1, None, Some(2)),
+            planned(UBI_LAYOUT_VOLUME_ID, None, Some(2)),
+        ]);
+        assert_eq!((plan.expected(), plan.describe()), ((7, true), "".into()));
+        plan.leb(0);
+        plan.leb(0);
+        assert_eq!(plan.expected(), (7, true));
+        assert_eq!(plan.describe(), "rootfs: 2/3 blocks");
+        plan.leb(1);
+        assert_eq!(plan.expected(), (6, true));
+        assert_eq!(plan.describe(), "volume 1: 1/2 blocks");
+        plan.leb(1);
+        plan.leb(1);
+        assert_eq!(plan.expected(), (7, true));
+        assert_eq!(plan.describe(), "volume 1: 3/3 blocks");
+        plan.leb(UBI_LAYOUT_VOLUME_ID);
+        assert_eq!(plan.expected(), (7, true));
+        assert_eq!(plan.describe(), "volume table: 1/2 blocks");
+
+        // Nor does one of unknown size leave the total unknown once it's done with
+        let mut plan = PlanProgress::new(vec![
+            planned(0, Some("stream"), None),
+            planned(UBI_LAYOUT_VOLUME_ID, None, Some(2)),
+        ]);
+        assert_eq!(plan.expected(), (2, false));
+        plan.leb(0);
+        assert_eq!(
+            (plan.expected(), plan.describe()),
+            ((3, false), "stream: 1 blocks".into())
+        );
+        plan.leb(UBI_LAYOUT_VOLUME_ID);
+        assert_eq!(plan.expected(), (3, true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort() -> anyhow::Result<()> {
+        use crate::fixtures::{static_volume, synthetic_data};
+        use crate::util::Aborted;
+        use std::sync::atomic::Ordering;
+
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        let abort = AtomicBool::new(true);
+
+        // Nothing is touched once the flag is raised
+        let error = format_abortable(&mut nand, &mut ebt, Some(&abort)).unwrap_err();
+        assert!(error.is::<Aborted>());
+        assert!(ebt.iter().all(|x| *x == BlockContent::Erased));
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        abort.store(false, Ordering::Relaxed);
+        format_abortable(&mut nand, &mut ebt, Some(&abort))?;
+
+        // Raising it partway through leaves the EBT accounting for every LEB written so far
+        let data = synthetic_data(3 * 14 * 128);
+        let mut reader = &data[..];
+        let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+        let progress = |done, _| {
+            if done == 2 {
+                abort.store(true, Ordering::Relaxed);
+            }
+        };
+        let error = write_volumes_with_progress(
+            &mut nand,
+            &mut ebt,
+            volumes,
+            PercentilePicker::default(),
+            progress,
+            Some(&abort),
+        )
+        .unwrap_err();
+        assert!(error.is::<Aborted>());
+        let written = ebt
+            .iter()
+            .filter(|x| matches!(x, BlockContent::EcData(..)))
+            .count();
+        assert_eq!(written, 2);
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_volumes_resume() -> anyhow::Result<()> {
+        use super::super::{read_volume, VolumeSelector};
+        use crate::fixtures::{static_volume, synthetic_data};
+        use crate::nand::CountingNand;
+        use std::sync::atomic::Ordering;
+
+        const LEB_SIZE: usize = 14 * 128;
+
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+
+        // Cut the write short after 4 of the 6 LEBs, as a power loss would
+        let data = synthetic_data(6 * LEB_SIZE);
+        let abort = AtomicBool::new(false);
+        let mut reader = &data[..];
+        let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+        let progress = |done, _| {
+            if done == 4 {
+                abort.store(true, Ordering::Relaxed);
+            }
+        };
+        let picker = PercentilePicker::default();
+        write_volumes_with_progress(&mut nand, &mut ebt, volumes, picker, progress, Some(&abort))
+            .unwrap_err();
+
+        // Leave LEB 1 half-programmed, with an intact VID header but not all of its data
+        {
+            let lebs = super::super::read::find_lebs(&ebt, 0);
+            let mut block = nand.block(lebs[&1].block)?.unwrap();
+            let mut pages = vec![0; 3 * 128];
+            block.read(0, &mut pages)?;
+            block.erase()?;
+            block.program(0, &pages)?;
+        }
+
+        // A fresh scan, as after rebooting, and the format keeps what was written...
+        let mut ebt = scan_blocks(&mut nand)?;
+        let stats = format_for_resume(&mut nand, &mut ebt, None)?;
+        assert_eq!(stats.blocks_kept, 4);
+
+        // ...and the write keeps what's still intact and matches the image, where LEB 3 doesn't
+        let mut image = data.clone();
+        image[3 * LEB_SIZE] ^= 1;
+        let mut nand = CountingNand::new(nand);
+        let mut reader = &image[..];
+        let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+        let stats = write_volumes(&mut nand, &mut ebt, volumes)?;
+        assert_eq!(stats.pebs_kept, BTreeMap::from([(0, 2)]));
+        assert_eq!(stats.pebs_written[&0], 4);
+
+        // Only the LEBs that had to be were written, besides the layout volume; the blocks left
+        // over were erased and had their EC headers written back
+        let counts = nand.counts.take();
+        assert_eq!((counts.programs, counts.erases), (4 + 2 + 2, 2));
+        let mut nand = nand.inner;
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+        let mut out = vec![];
+        read_volume(&mut nand, &ebt, &VolumeSelector::Id(0), &mut out)?;
+        assert!(out == image);
+
+        // A finished write leaves nothing to resume
+        let stats = format_for_resume(&mut nand, &mut ebt, None)?;
+        assert_eq!(stats.blocks_kept, 0);
+        assert!(ebt.iter().all(|x| matches!(x, BlockContent::EcErased(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_striped_picker() -> anyhow::Result<()> {
+        use crate::fixtures::{static_volume, synthetic_data};
+        use crate::ubi::{read_volume, VolumeSelector};
+
+        const LAYOUT: NandLayout = NandLayout {
+            blocks: 128,
+            pages_per_block: 16,
+            bytes_per_page: 128,
+        };
+        const LEB_SIZE: usize = 14 * 128;
+
+        /// The mean and variance of the ECs of the blocks in `ebt` holding data
+        fn data_ec_stats(ebt: &Ebt) -> (f64, f64) {
+            let ecs: Vec<f64> = ebt
+                .iter()
+                .filter_map(|x| match x {
+                    BlockContent::EcData(ec, Some(_)) => Some(ec.ec as f64),
+                    _ => None,
+                })
+                .collect();
+            let mean = ecs.iter().sum::<f64>() / ecs.len() as f64;
+            let variance = ecs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / ecs.len() as f64;
+            (mean, variance)
+        }
+
+        // A NAND that's seen uneven wear: ECs from 0 to 990, with some repeats
+        let mut nand = SimNand::new(LAYOUT);
+        let mut page = vec![0xFF; LAYOUT.bytes_per_page];
+        for index in 0..LAYOUT.blocks {
+            let ec = Ec {
+                ec: u64::from(index * 37 % 100) * 10,
+                vid_hdr_offset: 128,
+                data_offset: 256,
+                image_seq: 1,
+            };
+            ec.encode(&mut page)?;
+            nand.block(index)?.unwrap().program(0, &page)?;
+        }
+        let ebt = scan_blocks(&mut nand)?;
+
+        // Write a quarter of the NAND's worth of data both ways
+        let data = synthetic_data(30 * LEB_SIZE);
+        let mut results = vec![];
+        for striped in [false, true] {
+            let mut nand = nand.clone();
+            let mut ebt = ebt.clone();
+            let mut reader = &data[..];
+            let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+            if striped {
+                write_volumes_with_progress(
+                    &mut nand,
+                    &mut ebt,
+                    volumes,
+                    StripedPicker::default(),
+                    |_, _| (),
+                    None,
+                )?;
+            } else {
+                write_volumes(&mut nand, &mut ebt, volumes)?;
+            }
+
+            // Whichever blocks were picked, the data must read back intact
+            assert_eq!(scan_blocks(&mut nand)?, ebt);
+            let mut out = Vec::new();
+            let selector = VolumeSelector::Name("fixture".into());
+            read_volume(&mut nand, &ebt, &selector, &mut out)?;
+            assert!(out == data);
+
+            results.push(data_ec_stats(&ebt));
+        }
+
+        // The whole NAND has a mean EC of about 495, and a variance of about 83000. The percentile
+        // picker clusters the data in the lower part of that; striping samples all of it.
+        let [(percentile_mean, percentile_variance), (striped_mean, striped_variance)] =
+            results[..].try_into().unwrap();
+        assert!(percentile_mean < 350.0, "{percentile_mean}");
+        assert!(percentile_variance < 10000.0, "{percentile_variance}");
+        assert!((striped_mean - 495.0).abs() < 100.0, "{striped_mean}");
+        assert!(
+            (60000.0..100000.0).contains(&striped_variance),
+            "{striped_variance}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_volume_placement() -> anyhow::Result<()> {
+        use crate::fixtures::{static_volume, synthetic_data};
+        use crate::ubi::read_volume_table;
+
+        const LAYOUT: NandLayout = NandLayout {
+            blocks: 32,
+            pages_per_block: 16,
+            bytes_per_page: 128,
+        };
+
+        // A worn NAND, but for a few blocks scattered about
+        let mut nand = SimNand::new(LAYOUT);
+        let mut page = vec![0xFF; LAYOUT.bytes_per_page];
+        for index in 0..LAYOUT.blocks {
+            let ec = Ec {
+                ec: match index {
+                    21 => 2,
+                    13 => 3,
+                    6 => 4,
+                    _ => 5000 + u64::from(index * 7 % 32),
+                },
+                vid_hdr_offset: 128,
+                data_offset: 256,
+                image_seq: 1,
+            };
+            ec.encode(&mut page)?;
+            nand.block(index)?.unwrap().program(0, &page)?;
+        }
+        let ebt = scan_blocks(&mut nand)?;
+
+        let layout_blocks = |ebt: &Ebt| -> Vec<usize> {
+            (ebt.iter().enumerate())
+                .filter(|(_, x)| {
+                    matches!(x, BlockContent::EcData(_, Some(vid)) if vid.vol_id == UBI_LAYOUT_VOLUME_ID)
+                })
+                .map(|(i, _)| i)
+                .collect()
+        };
+        let data = synthetic_data(10 * 14 * 128);
+        let write = |nand: &mut SimNand, ebt: &mut Ebt| {
+            let mut reader = &data[..];
+            let volumes: Vec<Box<dyn Volume>> = vec![Box::new(static_volume(&mut reader))];
+            write_volumes(nand, ebt, volumes)
+        };
+
+        // Both copies of the layout volume go on the two least-worn blocks, whichever come first
+        let mut written = nand.clone();
+        let mut ebt_written = ebt.clone();
+        write(&mut written, &mut ebt_written)?;
+        assert_eq!(layout_blocks(&ebt_written), [13, 21]);
+        assert_eq!(scan_blocks(&mut written)?, ebt_written);
+        let table = read_volume_table(&mut written, &ebt_written)?;
+        assert_eq!(table.iter().flatten().count(), 1);
+
+        // One of them having gone bad since the scan, the other copy goes wherever the rest would
+        let mut written = nand.clone();
+        let mut ebt_written = ebt.clone();
+        written.block(21)?.unwrap().mark_bad()?;
+        write(&mut written, &mut ebt_written)?;
+        let placed = layout_blocks(&ebt_written);
+        assert_eq!(placed.len(), 2);
+        assert!(placed.contains(&13) && !placed.contains(&21), "{placed:?}");
+        assert_eq!(ebt_written[21], BlockContent::Bad);
+        assert_eq!(scan_blocks(&mut written)?, ebt_written);
+        assert!(read_volume_table(&mut written, &ebt_written).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_fastmap() -> anyhow::Result<()> {
+        use crate::ubi::{ubinize::UBI_FM_SB_VOLUME_ID, Vid};
+
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+
+        // Plant a fastmap anchor in one block, and an ordinary volume's LEB in another
+        let mut buf = vec![0xFF; TEST_LAYOUT.bytes_per_page];
+        for (block, vol_id) in [(3, UBI_FM_SB_VOLUME_ID), (5, 0)] {
+            let vid = Vid {
+                vol_id,
+                ..Default::default()
+            };
+            vid.encode(&mut buf)?;
+            nand.block(block)?.unwrap().program(1, &buf)?;
+        }
+
+        let mut ebt = scan_blocks(&mut nand)?;
+        assert!(ebt[3].is_fastmap());
+        assert!(!ebt[5].is_fastmap());
+
+        let stats = format(&mut nand, &mut ebt)?;
+        assert!(stats.fastmap_invalidated);
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+        assert!(matches!(ebt[3], BlockContent::EcErased(_)));
+        assert!(matches!(ebt[5], BlockContent::EcErased(_)));
+
+        // With the fastmap gone, formatting again finds nothing to invalidate
+        assert!(!format(&mut nand, &mut ebt)?.fastmap_invalidated);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_interrupted() -> anyhow::Result<()> {
+        use crate::nand::CountingNand;
+        use crate::ubi::rescan_range;
+
+        // Blocks 4 to 6 hold garbage, so they're erased together; the rest only need EC headers
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let garbage = vec![0x5A; TEST_LAYOUT.bytes_per_page];
+        for block in 4..7 {
+            nand.block(block)?.unwrap().program(0, &garbage)?;
+        }
+        let mut ebt = scan_blocks(&mut nand)?;
+        let mut nand = CountingNand::new(nand);
+
+        // Interrupt the format after a few blocks, partway through erasing that run
+        nand.fail_after_mutations = Some(6);
+        let error = format(&mut nand, &mut ebt).unwrap_err();
+        let error = error.downcast_ref::<EbtError>().expect("not an EbtError");
+        assert_eq!((error.blocks(), error.processed), (4..7, 4));
+
+        // Rescanning the untrusted blocks is enough to bring the EBT in line with the NAND
+        nand.fail_after_mutations = None;
+        rescan_range(&mut nand, &mut ebt, error.blocks())?;
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        // ...and for the format to pick up where it left off
+        format(&mut nand, &mut ebt)?;
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
         Ok(())
     }
 }
@@ -1,13 +1,67 @@
 //! This module contains the code necessary to read, write, and manipulate EC/VID headers, with
 //! CRC verification/computation.
 
+use super::ubinize::{UBI_FM_DATA_VOLUME_ID, UBI_FM_SB_VOLUME_ID, UBI_LAYOUT_VOLUME_ID};
+
 use crc::{Crc, CRC_32_JAMCRC};
 pub use deku::{DekuContainerRead, DekuContainerWrite};
 use income::{EcHdr, VidHdr, VtblRecord, UBI_EC_HDR_MAGIC, UBI_VID_HDR_MAGIC};
 
+use std::fmt;
+
+/// The CRC used for every UBI header and for LEB data alike.
+///
+/// The kernel computes these as `crc32(UBI_CRC32_INIT, ...)`, i.e. a reflected CRC-32 seeded with
+/// 0xFFFFFFFF and not inverted at the end, which is exactly JAMCRC (the bitwise inverse of the
+/// common CRC-32). The tests at the bottom of this file check this against kernel-computed values.
 pub const UBI_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_JAMCRC);
 const UBI_VERSION: u8 = 1;
 
+/// The volume table record flag asking UBI to grow the volume to fill the partition
+pub const UBI_VTBL_AUTORESIZE_FLG: u8 = 0x01;
+
+/// The volume table record flag telling UBI not to check a static volume's CRCs when opening it
+pub const UBI_VTBL_SKIP_CRC_CHECK_FLG: u8 = 0x02;
+
+/// The standard CRC "check" input, and what the kernel's UBI CRC makes of it
+const CRC_CHECK_INPUT: &[u8] = b"123456789";
+const CRC_CHECK_VALUE: u32 = 0x340BC6D9;
+
+/// An EC header as the kernel would write it (ec=0x12345, vid_hdr_offset=2048, data_offset=4096,
+/// image_seq=0xDEADBEEF), for checking our encoding and CRC end-to-end
+#[rustfmt::skip]
+const GOLDEN_EC_HDR: [u8; 64] = [
+    0x55, 0x42, 0x49, 0x23, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x23, 0x45,
+    0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x10, 0x00, 0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xF6, 0x3C, 0x81, 0x92,
+];
+const GOLDEN_EC: Ec = Ec {
+    ec: 0x12345,
+    vid_hdr_offset: 2048,
+    data_offset: 4096,
+    image_seq: 0xDEADBEEF,
+};
+
+/// Recompute known CRC values at runtime, to prove that this build's CRC tables (and header
+/// encoding) still agree with the kernel.
+pub fn crc_self_check() -> anyhow::Result<()> {
+    let crc = UBI_CRC.checksum(CRC_CHECK_INPUT);
+    anyhow::ensure!(
+        crc == CRC_CHECK_VALUE,
+        "UBI CRC self-check failed: got {crc:#010x}, expected {CRC_CHECK_VALUE:#010x}"
+    );
+
+    let mut hdr = [0u8; GOLDEN_EC_HDR.len()];
+    GOLDEN_EC.encode(&mut hdr)?;
+    anyhow::ensure!(
+        hdr == GOLDEN_EC_HDR,
+        "UBI CRC self-check failed: EC header does not match the kernel's"
+    );
+
+    Ok(())
+}
+
 /// A trait missing from the `income` crate: implements parsing UBI headers from byteslices, with
 /// magic and CRC verification.
 pub trait ParseHeader<'a>: Sized + DekuContainerRead<'a> + ComputeCrc {
@@ -99,6 +153,12 @@ impl ComputeCrc for VtblRecord {
     }
 }
 
+/// How many bytes [Ec::encode_backup] takes
+pub const EC_BACKUP_SIZE: usize = 28;
+
+/// The magic number starting a backup EC header, unlike UBI's own "UBI#"
+const EC_BACKUP_MAGIC: &[u8] = b"UBI%";
+
 /// This represents the specific fields we care about in an EC header
 ///
 /// This is meant to be more ergonomic to work with than EcHdr, which represents the raw data
@@ -123,6 +183,16 @@ impl Ec {
         self
     }
 
+    /// Translate `vid_hdr_offset` into the index of the page holding the VID header
+    pub fn vid_hdr_page(&self, page_size: usize) -> anyhow::Result<u32> {
+        offset_to_page(self.vid_hdr_offset, page_size)
+    }
+
+    /// Translate `data_offset` into the index of the page where LEB data begins
+    pub fn data_page(&self, page_size: usize) -> anyhow::Result<u32> {
+        offset_to_page(self.data_offset, page_size)
+    }
+
     /// Convert from a byte slice
     pub fn decode(bytes: &[u8]) -> Option<Self> {
         EcHdr::parse(bytes).map(|x| x.into())
@@ -137,6 +207,81 @@ impl Ec {
         out_bytes.copy_from_slice(&bytes);
         Ok(())
     }
+
+    /// A copy of this header, small enough for the free bytes of the OOB area of the page holding
+    /// it, to fall back on should the page become unreadable: a magic number, the fields in the
+    /// header's own order (big-endian, like UBI's), and a UBI CRC of those
+    pub fn encode_backup(&self) -> [u8; EC_BACKUP_SIZE] {
+        let mut out = [0; EC_BACKUP_SIZE];
+        out[0..4].copy_from_slice(EC_BACKUP_MAGIC);
+        out[4..12].copy_from_slice(&self.ec.to_be_bytes());
+        out[12..16].copy_from_slice(&self.vid_hdr_offset.to_be_bytes());
+        out[16..20].copy_from_slice(&self.data_offset.to_be_bytes());
+        out[20..24].copy_from_slice(&self.image_seq.to_be_bytes());
+        let crc = UBI_CRC.checksum(&out[..24]);
+        out[24..28].copy_from_slice(&crc.to_be_bytes());
+        out
+    }
+
+    /// Convert back from [Ec::encode_backup]'s bytes, if they're intact
+    pub fn decode_backup(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..EC_BACKUP_SIZE)?;
+        let word = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        if &bytes[0..4] != EC_BACKUP_MAGIC || word(24) != UBI_CRC.checksum(&bytes[..24]) {
+            return None;
+        }
+
+        Some(Self {
+            ec: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+            vid_hdr_offset: word(12),
+            data_offset: word(16),
+            image_seq: word(20),
+        })
+    }
+}
+
+/// Write one labeled line of a header's Display output
+fn field(f: &mut fmt::Formatter<'_>, label: &str, value: impl fmt::Display) -> fmt::Result {
+    writeln!(f, "{label:<16}{value}")
+}
+
+/// Format a count with thousands separators (e.g. "74,565"), for reading erase counters at a glance
+fn group_thousands(count: u64) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::with_capacity(digits.len() * 4 / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+impl fmt::Display for Ec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        field(f, "Erase counter", group_thousands(self.ec))?;
+        field(
+            f,
+            "VID header at",
+            format_args!("{:#x}", self.vid_hdr_offset),
+        )?;
+        field(f, "Data at", format_args!("{:#x}", self.data_offset))?;
+        field(
+            f,
+            "Image sequence",
+            format_args!("{:#010x}", self.image_seq),
+        )
+    }
+}
+
+/// Convert a byte offset within a PEB to a page index, as long as it's page-aligned
+fn offset_to_page(offset: u32, page_size: usize) -> anyhow::Result<u32> {
+    anyhow::ensure!(
+        (offset as usize).is_multiple_of(page_size),
+        "UBI offset {offset} is not aligned to the {page_size}-byte page size"
+    );
+    Ok(offset / page_size as u32)
 }
 
 impl From<EcHdr> for Ec {
@@ -206,6 +351,15 @@ impl From<VolType> for u8 {
     }
 }
 
+impl fmt::Display for VolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dynamic => "dynamic",
+            Self::Static => "static",
+        })
+    }
+}
+
 impl TryFrom<u8> for VolType {
     type Error = ();
 
@@ -358,6 +512,39 @@ impl From<Vid> for VidHdr {
     }
 }
 
+impl fmt::Display for Vid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let internal = match self.vol_id {
+            UBI_LAYOUT_VOLUME_ID => " (layout volume)",
+            UBI_FM_SB_VOLUME_ID => " (fastmap anchor)",
+            UBI_FM_DATA_VOLUME_ID => " (fastmap data)",
+            _ => "",
+        };
+        let compat = match self.compat {
+            0 => "",
+            1 => " (delete)",
+            2 => " (read-only)",
+            4 => " (preserve)",
+            5 => " (reject)",
+            _ => " (unknown)",
+        };
+
+        field(f, "Volume ID", format_args!("{:#x}{internal}", self.vol_id))?;
+        field(f, "Volume type", self.vol_type)?;
+        field(f, "LEB number", self.lnum)?;
+        field(f, "Sequence number", self.sqnum)?;
+        field(
+            f,
+            "Data size",
+            format_args!("{} bytes (CRC {:#010x})", self.data_size, self.data_crc),
+        )?;
+        field(f, "Used EBs", self.used_ebs)?;
+        field(f, "Data pad", self.data_pad)?;
+        field(f, "Copy flag", if self.copy_flag { "yes" } else { "no" })?;
+        field(f, "Compat", format_args!("{}{compat}", self.compat))
+    }
+}
+
 /// This represents the specific fields we care about in a volume table record
 #[derive(Debug, Default, Eq, PartialEq, Clone)]
 pub struct VolTableRecord {
@@ -419,6 +606,34 @@ impl VolTableRecord {
     }
 }
 
+impl fmt::Display for VolTableRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags: Vec<&str> = [
+            (UBI_VTBL_AUTORESIZE_FLG, "autoresize"),
+            (UBI_VTBL_SKIP_CRC_CHECK_FLG, "skipcheck"),
+        ]
+        .into_iter()
+        .filter_map(|(flag, name)| (self.flags & flag != 0).then_some(name))
+        .collect();
+        let flags = match flags[..] {
+            [] => format!("{:#04x}", self.flags),
+            _ => format!("{:#04x} ({})", self.flags, flags.join(", ")),
+        };
+
+        field(f, "Name", format_args!("{:?}", self.name))?;
+        field(f, "Volume type", self.vol_type)?;
+        field(f, "Reserved PEBs", self.reserved_pebs)?;
+        field(f, "Alignment", self.alignment)?;
+        field(f, "Data pad", self.data_pad)?;
+        field(
+            f,
+            "Update marker",
+            if self.upd_marker { "set" } else { "clear" },
+        )?;
+        field(f, "Flags", flags)
+    }
+}
+
 pub trait OptionIntoBytes {
     fn into_bytes(self) -> Vec<u8>;
 }
@@ -528,3 +743,171 @@ fn test_encode() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// The expected values below were produced with the kernel's algorithm, `crc32(UBI_CRC32_INIT, buf,
+// len)` from drivers/mtd/ubi, which is equivalent to Python's `zlib.crc32(buf) ^ 0xFFFFFFFF`. The
+// header bytes were packed from the `struct ubi_*_hdr`/`ubi_vtbl_record` layouts in
+// include/uapi/mtd/ubi-media.h.
+
+#[test]
+fn test_data_crc_golden() {
+    let pattern: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+
+    for (data, expected) in [
+        (&[][..], 0xFFFFFFFF),
+        (CRC_CHECK_INPUT, CRC_CHECK_VALUE),
+        (&pattern[..], 0x8DE8B959),
+    ] {
+        assert_eq!(UBI_CRC.checksum(data), expected);
+    }
+
+    crc_self_check().unwrap();
+}
+
+#[test]
+fn test_hdr_crc_golden() -> anyhow::Result<()> {
+    let mut buf = [0u8; 64];
+    GOLDEN_EC.encode(&mut buf)?;
+    assert_eq!(buf, GOLDEN_EC_HDR);
+    assert_eq!(Ec::decode(&GOLDEN_EC_HDR), Some(GOLDEN_EC));
+
+    // A static volume's LEB 3 of 4, holding the 1000-byte pattern from `test_data_crc_golden`
+    #[rustfmt::skip]
+    let golden_vid_hdr: [u8; 64] = [
+        0x55, 0x42, 0x49, 0x21, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xE8, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00,
+        0x8D, 0xE8, 0xB9, 0x59, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2A,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x6B, 0x7A, 0xCB, 0xD6,
+    ];
+    let vid = Vid {
+        vol_type: VolType::Static,
+        vol_id: 1,
+        lnum: 3,
+        data_size: 1000,
+        used_ebs: 4,
+        data_crc: 0x8DE8B959,
+        sqnum: 42,
+        ..Default::default()
+    };
+    vid.encode(&mut buf)?;
+    assert_eq!(buf, golden_vid_hdr);
+    assert_eq!(Vid::decode(&golden_vid_hdr), Some(vid));
+
+    Ok(())
+}
+
+#[test]
+fn test_vtbl_crc_golden() {
+    let record = VolTableRecord {
+        reserved_pebs: 16,
+        alignment: 1,
+        vol_type: VolType::Static,
+        name: "rootfs".to_string(),
+        ..Default::default()
+    };
+
+    let mut golden = vec![0u8; 172];
+    golden[..12].copy_from_slice(&[0, 0, 0, 16, 0, 0, 0, 1, 0, 0, 0, 0]);
+    golden[12] = 2; // vol_type
+    golden[15] = 6; // name_len
+    golden[16..22].copy_from_slice(b"rootfs");
+    golden[168..].copy_from_slice(&0x0A9004E7u32.to_be_bytes());
+
+    assert_eq!(record.clone().into_bytes(), golden);
+    assert_eq!(VolTableRecord::decode(&golden), Some(record));
+}
+
+#[test]
+fn test_ec_backup() {
+    let backup = GOLDEN_EC.encode_backup();
+    assert_eq!(&backup[..12], b"UBI%\0\0\0\0\0\x01\x23\x45");
+    assert_eq!(Ec::decode_backup(&backup), Some(GOLDEN_EC));
+
+    // Whatever follows it in the OOB area doesn't matter
+    let mut oob = [0xFF; 40];
+    oob[..EC_BACKUP_SIZE].copy_from_slice(&backup);
+    assert_eq!(Ec::decode_backup(&oob), Some(GOLDEN_EC));
+
+    // Erased, cut short, or with any bit flipped, it's no backup at all
+    assert_eq!(Ec::decode_backup(&[0xFF; EC_BACKUP_SIZE]), None);
+    assert_eq!(Ec::decode_backup(&backup[..EC_BACKUP_SIZE - 1]), None);
+    for byte in 0..EC_BACKUP_SIZE {
+        let mut flipped = backup;
+        flipped[byte] ^= 0x04;
+        assert_eq!(Ec::decode_backup(&flipped), None, "byte {byte}");
+    }
+}
+
+#[test]
+fn test_display() {
+    assert_eq!(
+        GOLDEN_EC.to_string(),
+        "\
+Erase counter   74,565
+VID header at   0x800
+Data at         0x1000
+Image sequence  0xdeadbeef
+"
+    );
+
+    let vid = Vid {
+        vol_type: VolType::Static,
+        vol_id: 1,
+        lnum: 3,
+        data_size: 1000,
+        used_ebs: 4,
+        data_crc: 0x8DE8B959,
+        sqnum: 42,
+        ..Default::default()
+    };
+    assert_eq!(
+        vid.to_string(),
+        "\
+Volume ID       0x1
+Volume type     static
+LEB number      3
+Sequence number 42
+Data size       1000 bytes (CRC 0x8de8b959)
+Used EBs        4
+Data pad        0
+Copy flag       no
+Compat          0
+"
+    );
+    let layout = Vid {
+        vol_id: UBI_LAYOUT_VOLUME_ID,
+        compat: 5,
+        ..vid
+    };
+    assert!(layout
+        .to_string()
+        .starts_with("Volume ID       0x7fffefff (layout volume)\n"));
+    assert!(layout.to_string().ends_with("Compat          5 (reject)\n"));
+
+    let record = VolTableRecord {
+        reserved_pebs: 16,
+        alignment: 1,
+        vol_type: VolType::Static,
+        upd_marker: true,
+        name: "rootfs".to_string(),
+        flags: UBI_VTBL_SKIP_CRC_CHECK_FLG,
+        ..Default::default()
+    };
+    assert_eq!(
+        record.to_string(),
+        "\
+Name            \"rootfs\"
+Volume type     static
+Reserved PEBs   16
+Alignment       1
+Data pad        0
+Update marker   set
+Flags           0x02 (skipcheck)
+"
+    );
+
+    assert_eq!(group_thousands(0), "0");
+    assert_eq!(group_thousands(999), "999");
+    assert_eq!(group_thousands(1000), "1,000");
+    assert_eq!(group_thousands(1234567), "1,234,567");
+}
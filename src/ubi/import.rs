@@ -0,0 +1,505 @@
+//! This module implements importing a UBI image, as made by `ubinize`, onto the flash device.
+//!
+//! Such an image can't be written raw: its EC headers are the ones it was made with rather than
+//! this NAND's erase counters, and it puts every LEB in a fixed PEB, whatever state that block is
+//! in. Instead, the image is read PEB by PEB for its VID headers and data (its EC headers only say
+//! where those are), and each volume's LEBs are written as [write_volumes] writes any other. The
+//! image's layout volume is dropped, and a new one made from the volume table in it.
+
+use super::format::{eb_size_for_writing, write_volumes, WriteStats};
+use super::headers::{Ec, Vid, VolTableRecord, VolType, UBI_CRC};
+use super::read::decode_volume_table;
+use super::scan::Ebt;
+use super::ubinize::{
+    vtbl_record_count, Volume, VolumeData, UBI_LAYOUT_VOLUME_ID, UBI_MAX_VOLUMES,
+    UBI_VTBL_RECORD_SIZE,
+};
+use crate::nand::{Nand, PageUtil};
+use crate::util::ReadExt;
+
+use anyhow::{anyhow, bail, ensure};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Read;
+use std::num::NonZeroU32;
+use std::rc::Rc;
+
+/// Write the volumes of a UBI image to `nand`, which must have been formatted first, as for
+/// [write_volumes] (see the [module documentation](self)).
+///
+/// The image must have been made for this NAND's PEB and LEB sizes. Its volumes are written in
+/// order of ID; each one's LEBs are expected to be together in the image, as `ubinize` puts them,
+/// but those of a volume that comes before its turn are held in memory until then.
+pub fn import_ubi_image<N: Nand, R: Read>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    image: R,
+) -> anyhow::Result<WriteStats> {
+    let eb_size = eb_size_for_writing(nand)?;
+    let peb_size = nand.get_layout().block_bytes();
+    let source = Rc::new(RefCell::new(ImageSource::open(image, peb_size, eb_size)?));
+
+    let table = source.borrow().table.clone().unwrap_or_default();
+    let volumes: Vec<Box<dyn Volume + '_>> = (0..)
+        .zip(table)
+        .filter_map(|(id, record)| {
+            let volume = ImageVolume {
+                source: source.clone(),
+                id,
+                record: record?,
+            };
+            Some(Box::new(volume) as Box<dyn Volume + '_>)
+        })
+        .collect();
+    let stats = write_volumes(nand, ebt, volumes)?;
+
+    // Anything left over belongs to a volume that was already written
+    source.borrow_mut().finish()?;
+
+    Ok(stats)
+}
+
+/// The LEBs of a UBI image, read PEB by PEB and handed out volume by volume
+struct ImageSource<'a> {
+    image: Box<dyn Read + 'a>,
+    peb_size: usize,
+    eb_size: NonZeroU32,
+
+    /// How many PEBs have been read so far
+    pebs: u32,
+
+    /// The image's volume table, once a copy of it has been found
+    table: Option<Vec<Option<VolTableRecord>>>,
+
+    /// LEBs read before their volume's turn, by volume ID
+    pending: BTreeMap<u32, VecDeque<(Vid, Vec<u8>)>>,
+
+    /// The volumes whose LEBs have all been handed out
+    finished: BTreeSet<u32>,
+}
+
+impl<'a> ImageSource<'a> {
+    /// Start reading an image, as far as its volume table
+    fn open<R: Read + 'a>(image: R, peb_size: usize, eb_size: NonZeroU32) -> anyhow::Result<Self> {
+        let mut source = Self {
+            image: Box::new(image),
+            peb_size,
+            eb_size,
+            pebs: 0,
+            table: None,
+            pending: BTreeMap::new(),
+            finished: BTreeSet::new(),
+        };
+
+        while source.table.is_none() {
+            let Some((vid, data)) = source.next_peb()? else {
+                bail!(
+                    "the UBI image has no intact volume table (was it made for {} byte PEBs?)",
+                    source.peb_size
+                );
+            };
+            source.hold(vid, data)?;
+        }
+
+        // The LEBs that came before the table have to belong to volumes in it
+        for &vol_id in source.pending.keys() {
+            source.check_volume(vol_id)?;
+        }
+
+        Ok(source)
+    }
+
+    /// Read PEBs up to the next one holding a LEB, returning its VID header and data, or None at
+    /// the end of the image
+    fn next_peb(&mut self) -> anyhow::Result<Option<(Vid, Vec<u8>)>> {
+        let mut peb = vec![0; self.peb_size];
+        loop {
+            let filled = self.image.read_fill(&mut peb)?;
+            if filled == 0 {
+                return Ok(None);
+            }
+            let index = self.pebs;
+            self.pebs += 1;
+            ensure!(
+                filled == peb.len(),
+                "the UBI image ends partway through PEB {index}"
+            );
+            if peb.is_erased() {
+                continue;
+            }
+
+            let ec = Ec::decode(&peb).ok_or(anyhow!(
+                "PEB {index} of the UBI image has no EC header (was it made for {} byte PEBs?)",
+                self.peb_size
+            ))?;
+            let (vid_offset, data_offset) = (ec.vid_hdr_offset as usize, ec.data_offset as usize);
+            ensure!(
+                vid_offset < data_offset && data_offset < peb.len(),
+                "PEB {index} of the UBI image has headers out of place"
+            );
+            let eb_size = u32::from(self.eb_size) as usize;
+            ensure!(
+                peb.len() - data_offset == eb_size,
+                "PEB {index} of the UBI image holds {} bytes of data, but this NAND's LEBs hold \
+                 {eb_size}",
+                peb.len() - data_offset
+            );
+
+            // A PEB with only an EC header is free
+            let vid_bytes = &peb[vid_offset..data_offset];
+            if vid_bytes.is_erased() {
+                continue;
+            }
+            let vid = Vid::decode(vid_bytes).ok_or(anyhow!(
+                "PEB {index} of the UBI image has a corrupt VID header"
+            ))?;
+
+            return Ok(Some((vid, peb.split_off(data_offset))));
+        }
+    }
+
+    /// Hold on to a LEB read before its volume's turn, taking the volume table from the layout
+    /// volume and dropping UBI's other internal volumes
+    fn hold(&mut self, vid: Vid, data: Vec<u8>) -> anyhow::Result<()> {
+        if vid.vol_id == UBI_LAYOUT_VOLUME_ID {
+            if self.table.is_none() {
+                let len = vtbl_record_count(self.eb_size) * UBI_VTBL_RECORD_SIZE;
+                self.table = decode_volume_table(&data[..len]);
+            }
+            return Ok(());
+        }
+        if vid.vol_id as usize >= UBI_MAX_VOLUMES {
+            return Ok(());
+        }
+
+        if self.table.is_some() {
+            self.check_volume(vid.vol_id)?;
+        }
+        ensure!(
+            !self.finished.contains(&vid.vol_id),
+            "LEB {} of volume {} comes after other volumes' LEBs in the UBI image",
+            vid.lnum,
+            vid.vol_id
+        );
+        self.pending
+            .entry(vid.vol_id)
+            .or_default()
+            .push_back((vid, data));
+        Ok(())
+    }
+
+    /// Fail unless the image's volume table has the volume `vol_id`
+    fn check_volume(&self, vol_id: u32) -> anyhow::Result<()> {
+        let table = self.table.as_deref().unwrap_or_default();
+        ensure!(
+            matches!(table.get(vol_id as usize), Some(Some(_))),
+            "the UBI image has LEBs of volume {vol_id}, which isn't in its volume table"
+        );
+        Ok(())
+    }
+
+    /// The next LEB of volume `vol_id`, or None once its LEBs have run out. Having `started` on
+    /// the volume, that's as soon as a LEB of another volume turns up.
+    fn next_leb(&mut self, vol_id: u32, started: bool) -> anyhow::Result<Option<(Vid, Vec<u8>)>> {
+        if let Some(leb) = self.pending.get_mut(&vol_id).and_then(VecDeque::pop_front) {
+            return Ok(Some(leb));
+        }
+
+        while let Some((vid, data)) = self.next_peb()? {
+            if vid.vol_id == vol_id {
+                return Ok(Some((vid, data)));
+            }
+            let other = vid.vol_id < UBI_MAX_VOLUMES as u32;
+            self.hold(vid, data)?;
+            if started && other {
+                break;
+            }
+        }
+
+        self.finished.insert(vol_id);
+        Ok(None)
+    }
+
+    /// Read the rest of the image, checking that there's nothing in it that wasn't written
+    fn finish(&mut self) -> anyhow::Result<()> {
+        while let Some((vid, data)) = self.next_peb()? {
+            self.hold(vid, data)?;
+        }
+        if let Some((vol_id, lebs)) = self.pending.iter().find(|(_, x)| !x.is_empty()) {
+            bail!(
+                "{} LEB(s) of volume {vol_id} in the UBI image weren't written",
+                lebs.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A volume of a UBI image, with the record the image's volume table has for it
+struct ImageVolume<'s> {
+    source: Rc<RefCell<ImageSource<'s>>>,
+    id: u32,
+    record: VolTableRecord,
+}
+
+impl<'s> Volume for ImageVolume<'s> {
+    fn into_data<'a>(self: Box<Self>, eb_size: NonZeroU32, vol_id: u32) -> Box<dyn VolumeData + 'a>
+    where
+        Self: 'a,
+    {
+        Box::new(ImageVolumeData {
+            source: self.source,
+            image_id: self.id,
+            vol_id,
+            eb_size,
+            record: self.record,
+            last_lnum: None,
+        })
+    }
+
+    fn get_vol_id(&self) -> Option<u32> {
+        Some(self.id)
+    }
+
+    fn get_name(&self) -> Option<&str> {
+        Some(self.record.name.as_str()).filter(|x| !x.is_empty())
+    }
+
+    fn get_flags(&self) -> u8 {
+        self.record.flags
+    }
+
+    fn estimate_blocks(&self, _: NonZeroU32) -> u32 {
+        self.record.reserved_pebs
+    }
+}
+
+struct ImageVolumeData<'s> {
+    source: Rc<RefCell<ImageSource<'s>>>,
+
+    /// The volume's ID in the image, which it keeps unless it's been given another
+    image_id: u32,
+    vol_id: u32,
+    eb_size: NonZeroU32,
+    record: VolTableRecord,
+    last_lnum: Option<u32>,
+}
+
+impl VolumeData for ImageVolumeData<'_> {
+    fn next_block(&mut self, data: &mut [u8]) -> anyhow::Result<Option<(Vid, usize)>> {
+        let next = self
+            .source
+            .borrow_mut()
+            .next_leb(self.image_id, self.last_lnum.is_some())?;
+        let Some((vid, leb)) = next else {
+            return Ok(None);
+        };
+
+        let lnum = vid.lnum;
+        ensure!(
+            !matches!(self.last_lnum, Some(last) if lnum <= last),
+            "LEB {lnum} of volume {} is out of order in the UBI image",
+            self.image_id
+        );
+        self.last_lnum = Some(lnum);
+
+        // A static LEB says how much data it has; a dynamic one is as long as its last non-erased
+        // byte, the rest being left erased anyway
+        let room = u32::from(self.eb_size).saturating_sub(vid.data_pad) as usize;
+        let len = match vid.vol_type {
+            VolType::Static => vid.data_size as usize,
+            VolType::Dynamic => leb.iter().rposition(|&x| x != 0xFF).map_or(0, |x| x + 1),
+        };
+        ensure!(
+            len <= room,
+            "LEB {lnum} of volume {} holds more data than fits in it",
+            self.image_id
+        );
+        if vid.vol_type == VolType::Static {
+            ensure!(
+                UBI_CRC.checksum(&leb[..len]) == vid.data_crc,
+                "LEB {lnum} of volume {} is corrupt in the UBI image",
+                self.image_id
+            );
+        }
+
+        data[..len].copy_from_slice(&leb[..len]);
+        let vid = Vid {
+            vol_id: self.vol_id,
+            copy_flag: false,
+            sqnum: 0,
+            ..vid
+        };
+        Ok(Some((vid, len)))
+    }
+
+    fn into_vtbl_record(self: Box<Self>) -> VolTableRecord {
+        // The volume has to reserve room for every LEB it was written with
+        let mut record = self.record;
+        let used = self.last_lnum.map_or(0, |x| x + 1);
+        record.reserved_pebs = record.reserved_pebs.max(used);
+        record
+    }
+}
+
+#[test]
+fn test_import_ubi_image() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Ubinizer};
+    use super::{format, read_volume_table, scan_blocks};
+    use crate::fixtures::synthetic_data;
+    use crate::nand::{NandBlock, NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 32,
+        pages_per_block: 8,
+        bytes_per_page: 256,
+    };
+    let page = TEST_LAYOUT.bytes_per_page;
+    let eb_size = page * (TEST_LAYOUT.pages_per_block as usize - 2);
+
+    let rootfs = synthetic_data(eb_size * 5 + 100);
+    let env = vec![0x5A; eb_size + 17];
+    let volumes = || -> Vec<Box<dyn Volume + '_>> {
+        vec![
+            Box::new(
+                BasicVolume::new(VolType::Static)
+                    .id(0)
+                    .name("rootfs")
+                    .size(rootfs.len() as u64)
+                    .image(&rootfs[..]),
+            ),
+            Box::new(
+                BasicVolume::new(VolType::Dynamic)
+                    .id(1)
+                    .name("env")
+                    .size(eb_size as u64 * 3)
+                    .image(&env[..]),
+            ),
+            Box::new(
+                BasicVolume::new(VolType::Dynamic)
+                    .id(3)
+                    .name("data")
+                    .autoresize(),
+            ),
+        ]
+    };
+
+    // An image as `ubinize` makes one, from the PEBs the Ubinizer yields; its EC headers are
+    // nothing like the NAND's. There's a free PEB (with just an EC header) and an erased one in it,
+    // too, and the layout volume comes first, unless `layout_last`.
+    let ubinize = |layout_last: bool| -> anyhow::Result<Vec<u8>> {
+        let ec = Ec {
+            ec: 1234,
+            vid_hdr_offset: page as u32,
+            data_offset: 2 * page as u32,
+            image_seq: 0xC0FFEE,
+        };
+        let mut ubinizer = Ubinizer::new(volumes(), (eb_size as u32).try_into()?)?;
+        let mut pebs = vec![];
+        loop {
+            let mut peb = vec![0xFF; TEST_LAYOUT.block_bytes()];
+            let Some((vid, _)) = ubinizer.next_block(&mut peb[2 * page..])? else {
+                break;
+            };
+            ec.encode(&mut peb[..page])?;
+            vid.encode(&mut peb[page..2 * page])?;
+            pebs.push((vid.vol_id, peb));
+        }
+        if !layout_last {
+            pebs.sort_by_key(|&(vol_id, _)| vol_id != UBI_LAYOUT_VOLUME_ID);
+        }
+        let mut free = vec![0xFF; TEST_LAYOUT.block_bytes()];
+        ec.encode(&mut free[..page])?;
+        pebs.insert(3, (0, free));
+        pebs.push((0, vec![0xFF; TEST_LAYOUT.block_bytes()]));
+
+        Ok(pebs.into_iter().flat_map(|(_, x)| x).collect())
+    };
+
+    let mut blank = SimNand::new(TEST_LAYOUT);
+    blank.block(5)?.unwrap().mark_bad()?;
+    let save = |nand: &mut SimNand| -> anyhow::Result<Vec<u8>> {
+        let mut image = vec![];
+        nand.save(&mut image)?;
+        Ok(image)
+    };
+
+    // Writing the volumes directly...
+    let mut direct = blank.clone();
+    let mut ebt = scan_blocks(&mut direct)?;
+    format(&mut direct, &mut ebt)?;
+    let direct_stats = write_volumes(&mut direct, &mut ebt, volumes())?;
+    let expected = save(&mut direct)?;
+
+    // ...leaves the NAND just as importing an image of them does, whichever end its layout volume
+    // is at
+    for layout_last in [false, true] {
+        let mut nand = blank.clone();
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+        let stats = import_ubi_image(&mut nand, &mut ebt, &ubinize(layout_last)?[..])?;
+        assert_eq!(stats.pebs_written, direct_stats.pebs_written);
+        assert!(save(&mut nand)? == expected, "layout_last: {layout_last}");
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        let table = read_volume_table(&mut nand, &ebt)?;
+        let record = table[3].as_ref().unwrap();
+        assert_eq!((record.name.as_str(), record.reserved_pebs), ("data", 1));
+    }
+
+    // Images that don't fit the NAND, or are damaged, are refused
+    let good = ubinize(false)?;
+    let mut corrupt = good.clone();
+    corrupt[TEST_LAYOUT.block_bytes() * 4 + page + 8] ^= 1;
+    let mut unlisted = good.clone();
+    let mut vid = Vid::decode(&unlisted[TEST_LAYOUT.block_bytes() * 4 + page..]).unwrap();
+    vid.vol_id = 2;
+    vid.encode(&mut unlisted[TEST_LAYOUT.block_bytes() * 4 + page..][..page])?;
+    for (image, error) in [
+        (&good[..good.len() - 1], "ends partway through PEB"),
+        (
+            &good[TEST_LAYOUT.block_bytes() * 2..],
+            "no intact volume table",
+        ),
+        (&corrupt[..], "corrupt VID header"),
+        (&unlisted[..], "volume 2, which isn't in its volume table"),
+    ] {
+        let mut nand = blank.clone();
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+        let message = format!(
+            "{:#}",
+            import_ubi_image(&mut nand, &mut ebt, image).unwrap_err()
+        );
+        assert!(message.contains(error), "{message}");
+    }
+    // ...as are images made for other PEB or LEB sizes
+    for (pages_per_block, bytes_per_page, error) in [
+        (
+            16,
+            256,
+            "no intact volume table (was it made for 4096 byte PEBs?)",
+        ),
+        (
+            16,
+            128,
+            "holds 1536 bytes of data, but this NAND's LEBs hold 1792",
+        ),
+    ] {
+        let mut nand = SimNand::new(NandLayout {
+            blocks: 32,
+            pages_per_block,
+            bytes_per_page,
+        });
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+        let message = format!(
+            "{:#}",
+            import_ubi_image(&mut nand, &mut ebt, &good[..]).unwrap_err()
+        );
+        assert!(message.contains(error), "{message}");
+    }
+
+    Ok(())
+}
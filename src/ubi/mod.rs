@@ -34,9 +34,35 @@
 
 mod format;
 mod headers;
+mod import;
+#[cfg(test)]
+mod multiplane;
+mod read;
 mod scan;
 pub mod ubinize;
+mod update;
 
-pub use format::{format, write_volumes};
-pub use headers::VolType;
-pub use scan::{scan_blocks, Ebt};
+pub use format::{
+    format, format_abortable, format_for_resume, format_with_policy, write_volumes,
+    write_volumes_pipelined, write_volumes_with_progress, BlockPicker, EcPolicy, FormatStats,
+    PercentilePicker, StripedPicker, WriteStats,
+};
+pub use headers::{
+    crc_self_check, Ec, Vid, VolTableRecord, VolType, EC_BACKUP_SIZE, UBI_VTBL_AUTORESIZE_FLG,
+    UBI_VTBL_SKIP_CRC_CHECK_FLG,
+};
+pub use import::import_ubi_image;
+pub use read::{
+    decode_volume_table, read_volume, read_volume_leb, read_volume_table, read_volume_table_copies,
+    VolumeSelector, VolumeTableCopy,
+};
+pub use scan::{
+    diff, rescan_range, scan_blocks, scan_blocks_cached, scan_blocks_parallel,
+    scan_blocks_parallel_with_issues, scan_blocks_with_issues, summarize, summarize_volumes,
+    verify_ebt, BlockChange, BlockContent, Ebt, EbtDiff, EbtError, EbtSummary, EcStats,
+    HeaderCache, ScanIssues, Transition, VolumeUsage, VolumesSummary, EC_HISTOGRAM_BUCKETS,
+};
+pub use update::{
+    begin_volume_update, finish_volume_update, migrate_volume_table, reset_volumes,
+    reset_volumes_batched, rewrite_layout, update_volume, write_leb, ResetStats, RESET_BATCH_BYTES,
+};
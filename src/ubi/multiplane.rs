@@ -0,0 +1,393 @@
+//! Test support: NANDs as UBI on AWNAND's `SIMULATE_MULTIPLANE` leaves them (see the
+//! [module documentation](super)), for the migration away from it to be tested against.
+//!
+//! Each superblock (pair of blocks) is set up as one PEB of the doubled geometry would be:
+//! - the EC header in page 0 of the even block, laid out for the doubled page size (the VID header
+//!   2 pages in, the data 4 pages in);
+//! - for a PEB holding a LEB, the VID header in page 0 of the odd block, where AWNAND puts it, and
+//!   the LEB's data from page 2 on, each doubled page split between the even block's page and the
+//!   odd block's.
+
+use super::headers::{Ec, Vid, VolType};
+use super::ubinize::{BasicVolume, Volume};
+use super::{
+    format, format_with_policy, read_volume, read_volume_table_copies, scan_blocks, write_volumes,
+    BlockContent, Ebt, EcPolicy,
+};
+use crate::nand::{Nand, NandBlock, NandLayout, SimNand};
+
+use std::collections::BTreeSet;
+
+/// How [MultiplaneNand] leaves a superblock
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum Superblock {
+    /// Both blocks erased, as if UBI never got to it
+    Erased,
+
+    /// A free PEB: just the EC header
+    Free,
+
+    /// A PEB holding LEB `lnum` of volume `vol_id`
+    Leb { vol_id: u32, lnum: u32 },
+
+    /// Already migrated, as by a migration that was cut short: each block has an EC header laid
+    /// out for the real page size, and nothing else
+    Migrated,
+}
+
+/// A builder for a [SimNand] as UBI on `SIMULATE_MULTIPLANE` leaves it
+#[derive(Debug, Clone)]
+pub(super) struct MultiplaneNand {
+    layout: NandLayout,
+    superblocks: Vec<Superblock>,
+    ecs: Vec<u64>,
+    image_seqs: Vec<u32>,
+    bad: BTreeSet<u32>,
+}
+
+impl MultiplaneNand {
+    /// Start with every superblock [Superblock::Free], with an erase counter of 10 and an
+    /// image_seq of 0x1111
+    pub(super) fn new(layout: NandLayout) -> Self {
+        assert_eq!(
+            layout.blocks % 2,
+            0,
+            "superblocks need an even number of blocks"
+        );
+        assert!(
+            layout.pages_per_block >= 3,
+            "superblocks need room for their data"
+        );
+        let superblocks = (layout.blocks / 2) as usize;
+        Self {
+            layout,
+            superblocks: vec![Superblock::Free; superblocks],
+            ecs: vec![10; superblocks],
+            image_seqs: vec![0x1111; superblocks],
+            bad: BTreeSet::new(),
+        }
+    }
+
+    /// Leave superblock `index` as `content`
+    pub(super) fn superblock(mut self, index: u32, content: Superblock) -> Self {
+        self.superblocks[index as usize] = content;
+        self
+    }
+
+    /// Give superblock `index` the erase counter `ec`
+    pub(super) fn ec(mut self, index: u32, ec: u64) -> Self {
+        self.ecs[index as usize] = ec;
+        self
+    }
+
+    /// Give superblock `index` the image_seq `image_seq`
+    pub(super) fn image_seq(mut self, index: u32, image_seq: u32) -> Self {
+        self.image_seqs[index as usize] = image_seq;
+        self
+    }
+
+    /// Mark (physical) block `block` bad, once whatever its superblock holds is written
+    pub(super) fn bad(mut self, block: u32) -> Self {
+        self.bad.insert(block);
+        self
+    }
+
+    /// The EC header superblock `index` is given; `migrated` has it laid out for the real page
+    /// size, rather than the doubled one
+    fn ec_header(&self, index: u32, migrated: bool) -> Ec {
+        let page_size = self.layout.bytes_per_page as u32;
+        let scale = if migrated { 1 } else { 2 };
+        Ec {
+            ec: self.ecs[index as usize],
+            vid_hdr_offset: page_size * scale,
+            data_offset: page_size * scale * 2,
+            image_seq: self.image_seqs[index as usize],
+        }
+    }
+
+    /// Write the superblocks to a fresh [SimNand]
+    pub(super) fn build(&self) -> anyhow::Result<SimNand> {
+        let mut nand = SimNand::new(self.layout);
+        for index in 0..self.superblocks.len() as u32 {
+            self.write_superblock(&mut nand, index)?;
+        }
+        for &block in &self.bad {
+            nand.block(block)?.unwrap().mark_bad()?;
+        }
+        Ok(nand)
+    }
+
+    fn write_superblock(&self, nand: &mut SimNand, index: u32) -> anyhow::Result<()> {
+        let page_size = self.layout.bytes_per_page;
+        let (even, odd) = (index * 2, index * 2 + 1);
+        let mut page = vec![0xFF; page_size];
+        match self.superblocks[index as usize] {
+            Superblock::Erased => (),
+            Superblock::Free => {
+                self.ec_header(index, false).encode(&mut page)?;
+                nand.block(even)?.unwrap().program(0, &page)?;
+            }
+            Superblock::Leb { vol_id, lnum } => {
+                self.ec_header(index, false).encode(&mut page)?;
+                nand.block(even)?.unwrap().program(0, &page)?;
+
+                page.fill(0xFF);
+                let vid = Vid {
+                    vol_id,
+                    lnum,
+                    sqnum: u64::from(index) + 1,
+                    ..Default::default()
+                };
+                vid.encode(&mut page)?;
+                nand.block(odd)?.unwrap().program(0, &page)?;
+
+                // Each doubled page of data is its first half in the even block, and its second
+                // half in the odd one
+                let pages = self.layout.pages_per_block as usize - 2;
+                let data = leb_data(vol_id, lnum, pages * page_size * 2);
+                let mut halves = data.chunks(page_size);
+                let mut even_data = Vec::with_capacity(pages * page_size);
+                let mut odd_data = Vec::with_capacity(pages * page_size);
+                while let (Some(first), Some(second)) = (halves.next(), halves.next()) {
+                    even_data.extend_from_slice(first);
+                    odd_data.extend_from_slice(second);
+                }
+                nand.block(even)?.unwrap().program(2, &even_data)?;
+                nand.block(odd)?.unwrap().program(2, &odd_data)?;
+            }
+            Superblock::Migrated => {
+                self.ec_header(index, true).encode(&mut page)?;
+                nand.block(even)?.unwrap().program(0, &page)?;
+                nand.block(odd)?.unwrap().program(0, &page)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The data [MultiplaneNand] fills LEB `lnum` of volume `vol_id` with: `len` bytes, different for
+/// each LEB, and never erased-looking
+fn leb_data(vol_id: u32, lnum: u32, len: usize) -> Vec<u8> {
+    let seed = (vol_id.wrapping_mul(31) ^ lnum) as usize;
+    (0..len).map(|i| ((seed + i) % 251) as u8).collect()
+}
+
+/// The layout the tests below build their NANDs with
+const LAYOUT: NandLayout = NandLayout {
+    blocks: 32,
+    pages_per_block: 16,
+    bytes_per_page: 128,
+};
+
+/// The EC header of every block of `ebt` that has one, by block
+fn ecs(ebt: &Ebt) -> Vec<Option<Ec>> {
+    ebt.iter().map(|x| x.ec()).collect()
+}
+
+/// Check that `ebt` is as a finished migration leaves it: every good block erased with an EC
+/// header for the real page size, all with `image_seq`
+fn assert_migrated(ebt: &Ebt, image_seq: u32) {
+    for (block, content) in ebt.iter().enumerate() {
+        match content {
+            BlockContent::Bad => (),
+            BlockContent::EcErased(ec) => {
+                assert_eq!(
+                    (ec.vid_hdr_offset, ec.data_offset, ec.image_seq),
+                    (128, 256, image_seq),
+                    "block {block}"
+                );
+            }
+            other => panic!("block {block} is {other:?} after the migration"),
+        }
+    }
+}
+
+/// Write a volume over the migrated NAND, and check it reads back through a fresh scan, along
+/// with both copies of the volume table
+fn assert_usable(nand: &mut SimNand, ebt: &mut Ebt) -> anyhow::Result<()> {
+    let data = leb_data(7, 7, 3 * 14 * 128 + 50);
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(data.len() as u64)
+            .image(&data[..]),
+    )];
+    write_volumes(nand, ebt, volumes)?;
+
+    let ebt = scan_blocks(nand)?;
+    let copies = read_volume_table_copies(nand, &ebt);
+    assert_eq!(copies.len(), 2);
+    assert!(copies.iter().all(|x| x.table.is_some()));
+    let mut out = vec![];
+    read_volume(nand, &ebt, &"rootfs".parse()?, &mut out)?;
+    assert!(out == data);
+    assert!(ebt.iter().all(|x| !matches!(x, BlockContent::RawVid(_))));
+    Ok(())
+}
+
+#[test]
+fn test_multiplane_layout() -> anyhow::Result<()> {
+    let mut nand = MultiplaneNand::new(LAYOUT)
+        .superblock(0, Superblock::Leb { vol_id: 3, lnum: 1 })
+        .superblock(1, Superblock::Erased)
+        .superblock(2, Superblock::Migrated)
+        .build()?;
+    let ebt = scan_blocks(&mut nand)?;
+
+    // The VID header is found where AWNAND puts it, and the EC header laid out for the doubled
+    // page size
+    let legacy = Ec {
+        ec: 10,
+        vid_hdr_offset: 256,
+        data_offset: 512,
+        image_seq: 0x1111,
+    };
+    assert!(matches!(ebt[0], BlockContent::EcData(ec, _) if ec == legacy));
+    assert!(matches!(ebt[1], BlockContent::RawVid(vid) if (vid.vol_id, vid.lnum) == (3, 1)));
+    assert_eq!(ebt[2..4], [BlockContent::Erased, BlockContent::Erased]);
+    let migrated = Ec {
+        vid_hdr_offset: 128,
+        data_offset: 256,
+        ..legacy
+    };
+    assert_eq!(ebt[4..6], [BlockContent::EcErased(migrated); 2]);
+    assert_eq!(
+        ebt[6..8],
+        [BlockContent::EcErased(legacy), BlockContent::Erased]
+    );
+
+    // The data is split page by page between the blocks
+    let data = leb_data(3, 1, 14 * 128 * 2);
+    let (mut even, mut odd) = (vec![0; 128], vec![0; 128]);
+    nand.block(0)?.unwrap().read(5, &mut even)?;
+    nand.block(1)?.unwrap().read(5, &mut odd)?;
+    assert!([even, odd].concat() == data[3 * 256..4 * 256]);
+
+    Ok(())
+}
+
+#[test]
+fn test_migrate_clean() -> anyhow::Result<()> {
+    // Every other superblock in use, with erase counters all different
+    let mut builder = MultiplaneNand::new(LAYOUT);
+    for index in 0..LAYOUT.blocks / 2 {
+        builder = builder.ec(index, 20 + u64::from(index) * 3);
+        if index % 2 == 0 {
+            let leb = Superblock::Leb {
+                vol_id: 0,
+                lnum: index / 2,
+            };
+            builder = builder.superblock(index, leb);
+        }
+    }
+    let mut nand = builder.build()?;
+    let mut ebt = scan_blocks(&mut nand)?;
+
+    let stats = format(&mut nand, &mut ebt)?;
+    assert!(stats.migrated && !stats.resumed_migration);
+    assert_eq!(scan_blocks(&mut nand)?, ebt);
+    assert_migrated(&ebt, 0x1111);
+
+    // Every block was erased but the free superblocks' odd ones, which only took an EC header
+    // after their even block's
+    for (index, pair) in ecs(&ebt).chunks(2).enumerate() {
+        let ec = 20 + index as u64 * 3;
+        let odd = if index % 2 == 0 { ec + 1 } else { ec };
+        assert_eq!((pair[0].unwrap().ec, pair[1].unwrap().ec), (ec + 1, odd));
+    }
+
+    assert_usable(&mut nand, &mut ebt)
+}
+
+#[test]
+fn test_migrate_bad_odd_blocks() -> anyhow::Result<()> {
+    let mut builder = MultiplaneNand::new(LAYOUT);
+    for index in 0..4 {
+        let leb = Superblock::Leb {
+            vol_id: 0,
+            lnum: index,
+        };
+        builder = builder.superblock(index, leb).ec(index, 50);
+    }
+    let mut nand = builder.bad(1).bad(7).bad(13).build()?;
+    let mut ebt = scan_blocks(&mut nand)?;
+    assert_eq!(
+        ebt.iter()
+            .filter(|x| matches!(x, BlockContent::RawVid(_)))
+            .count(),
+        2
+    );
+
+    let stats = format(&mut nand, &mut ebt)?;
+    assert!(stats.migrated);
+    assert_eq!(stats.bad_blocks_found, 3);
+    assert_migrated(&ebt, 0x1111);
+    for block in [1, 7, 13] {
+        assert_eq!(ebt[block], BlockContent::Bad);
+    }
+
+    // An even block whose odd one is bad is still its own PEB now
+    assert_eq!(ebt[0].ec().unwrap().ec, 51);
+    assert_eq!(ebt[12].ec().unwrap().ec, 11);
+
+    assert_usable(&mut nand, &mut ebt)
+}
+
+#[test]
+fn test_migrate_image_seqs() -> anyhow::Result<()> {
+    // The most common image_seq is kept, however the EC headers with it are spread about
+    let mut builder = MultiplaneNand::new(LAYOUT);
+    for index in 0..LAYOUT.blocks / 2 {
+        let image_seq = if index % 3 == 0 { 0xAAAA } else { 0xBBBB };
+        builder = builder.image_seq(index, image_seq);
+    }
+    let builder = builder.superblock(4, Superblock::Leb { vol_id: 0, lnum: 0 });
+    let mut nand = builder.build()?;
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+    assert_migrated(&ebt, 0xBBBB);
+    assert_usable(&mut nand, &mut ebt)?;
+
+    // ...and the policy decides what the pairs' erase counters become
+    let builder = builder.ec(4, 30).ec(5, 70);
+    for (policy, expected) in [
+        (EcPolicy::Preserve, [31, 31, 71, 70]),
+        (EcPolicy::Reset, [0, 0, 0, 0]),
+    ] {
+        let mut nand = builder.build()?;
+        let mut ebt = scan_blocks(&mut nand)?;
+        format_with_policy(&mut nand, &mut ebt, policy, None)?;
+        let ecs: Vec<_> = ecs(&ebt)[8..12].iter().map(|x| x.unwrap().ec).collect();
+        assert_eq!(ecs, expected, "{policy:?}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_migrate_partial() -> anyhow::Result<()> {
+    // A migration cut short has already settled on an image_seq, which isn't the most common
+    // among the headers left to migrate, and is kept
+    let mut builder = MultiplaneNand::new(LAYOUT);
+    for index in 0..3 {
+        builder = builder
+            .superblock(index, Superblock::Migrated)
+            .image_seq(index, 0x2222);
+    }
+    let builder = builder
+        .superblock(8, Superblock::Leb { vol_id: 0, lnum: 0 })
+        .superblock(9, Superblock::Erased);
+    let mut nand = builder.build()?;
+    let mut ebt = scan_blocks(&mut nand)?;
+
+    let stats = format(&mut nand, &mut ebt)?;
+    assert!(stats.migrated && stats.resumed_migration);
+    assert_migrated(&ebt, 0x2222);
+
+    // The blocks already migrated, and the erased superblock's odd block, aren't erased again
+    let ecs: Vec<_> = ecs(&ebt).iter().map(|x| x.unwrap().ec).collect();
+    assert_eq!(ecs[..6], [10; 6]);
+    assert_eq!(ecs[16..20], [11, 11, 10, 10]);
+
+    assert_usable(&mut nand, &mut ebt)
+}
@@ -0,0 +1,806 @@
+//! This module implements reading UBI volumes back out of the flash device.
+
+use super::format::compute_prototype;
+use super::headers::{Ec, Vid, VolTableRecord, VolType, UBI_CRC};
+use super::scan::{BlockContent, Ebt};
+use super::ubinize::{
+    vtbl_record_count, UBI_LAYOUT_VOLUME_ID, UBI_MAX_VOLUMES, UBI_VTBL_RECORD_SIZE,
+};
+
+use crate::nand::{Nand, NandBlock};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Identifies a volume, either by its ID or by its name
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum VolumeSelector {
+    Id(u32),
+    Name(String),
+}
+
+/// Parse a volume ID if the string is numeric, otherwise treat it as a volume name
+impl FromStr for VolumeSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.parse() {
+            Ok(id) => Self::Id(id),
+            Err(_) => Self::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for VolumeSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "#{id}"),
+            Self::Name(name) => write!(f, "\"{name}\""),
+        }
+    }
+}
+
+impl VolumeSelector {
+    /// Does this selector match the volume table entry at index `vol_id`?
+    fn matches(&self, vol_id: u32, record: &VolTableRecord) -> bool {
+        match self {
+            Self::Id(id) => *id == vol_id,
+            Self::Name(name) => record.name == *name,
+        }
+    }
+}
+
+/// A PEB holding one LEB of some volume
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Leb {
+    pub block: u32,
+    pub ec: Ec,
+    pub vid: Vid,
+}
+
+/// Find the PEBs holding the LEBs of a volume, keyed by `lnum`.
+///
+/// Where several PEBs claim the same LEB, the one with the highest `sqnum` wins.
+pub(super) fn find_lebs(ebt: &Ebt, vol_id: u32) -> BTreeMap<u32, Leb> {
+    let mut lebs: BTreeMap<u32, Leb> = BTreeMap::new();
+
+    for (block, content) in ebt.iter().enumerate() {
+        let (ec, vid) = match content {
+            BlockContent::EcData(ec, Some(vid)) if vid.vol_id == vol_id => (*ec, *vid),
+            _ => continue,
+        };
+        insert_newest(
+            &mut lebs,
+            Leb {
+                block: block as u32,
+                ec,
+                vid,
+            },
+        );
+    }
+
+    lebs
+}
+
+/// Like [find_lebs], but also finding the LEBs in blocks whose EC header was lost
+/// ([BlockContent::VidOnly]), for reading them back. Those blocks are given the EC header that
+/// [format](super::format) would give them, so their [Leb]s mustn't be used to write anything.
+pub(super) fn find_live_lebs<N: Nand>(
+    nand: &N,
+    ebt: &Ebt,
+    vol_id: u32,
+) -> anyhow::Result<BTreeMap<u32, Leb>> {
+    let mut lebs = find_lebs(ebt, vol_id);
+    if !ebt
+        .iter()
+        .any(|x| matches!(x, BlockContent::VidOnly(vid) if vid.vol_id == vol_id))
+    {
+        return Ok(lebs);
+    }
+
+    let (ec, _) = compute_prototype(nand.get_layout(), ebt.iter().copied())?;
+    for (block, content) in ebt.iter().enumerate() {
+        if let BlockContent::VidOnly(vid) = *content {
+            if vid.vol_id == vol_id {
+                insert_newest(
+                    &mut lebs,
+                    Leb {
+                        block: block as u32,
+                        ec,
+                        vid,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(lebs)
+}
+
+/// Add a PEB to the LEBs found so far, unless a newer copy of its LEB is already there
+fn insert_newest(lebs: &mut BTreeMap<u32, Leb>, leb: Leb) {
+    match lebs.get(&leb.vid.lnum) {
+        Some(newer) if newer.vid.sqnum > leb.vid.sqnum => (),
+        _ => {
+            lebs.insert(leb.vid.lnum, leb);
+        }
+    }
+}
+
+/// Compute the EB size (i.e. PEB size minus EC/VID header space) of a PEB, according to the offsets
+/// in its own EC header
+pub(super) fn eb_size<N: Nand>(nand: &N, ec: &Ec) -> anyhow::Result<u32> {
+    let layout = nand.get_layout();
+    (layout.block_bytes() as u32)
+        .checked_sub(ec.data_offset)
+        .filter(|&size| size > 0)
+        .ok_or(anyhow::anyhow!(
+            "UBI data offset {} leaves no room for data",
+            ec.data_offset
+        ))
+}
+
+/// The size of the LEB held by a PEB: its EB size, less the padding its VID header says the
+/// volume's alignment leaves at the end
+fn leb_size<N: Nand>(nand: &N, leb: &Leb) -> anyhow::Result<u32> {
+    eb_size(nand, &leb.ec)?
+        .checked_sub(leb.vid.data_pad)
+        .ok_or(anyhow::anyhow!(
+            "block {} has data_pad {} larger than its EB size",
+            leb.block,
+            leb.vid.data_pad
+        ))
+}
+
+/// Read a static volume's LEB out of a PEB, checking it against the size and CRC in its VID header
+fn read_static_leb<N: Nand>(
+    nand: &mut N,
+    leb: &Leb,
+    volume: &VolumeSelector,
+) -> anyhow::Result<Vec<u8>> {
+    let lnum = leb.vid.lnum;
+    anyhow::ensure!(
+        leb.vid.data_size <= leb_size(nand, leb)?,
+        "LEB {lnum} of volume {volume} claims more data than fits in it"
+    );
+    let data = read_leb(nand, leb, leb.vid.data_size as usize)?;
+    anyhow::ensure!(
+        UBI_CRC.checksum(&data) == leb.vid.data_crc,
+        "LEB {lnum} of volume {volume} is corrupt"
+    );
+
+    Ok(data)
+}
+
+/// Read the first `len` bytes of LEB data out of a PEB, starting wherever its EC header says
+pub(super) fn read_leb<N: Nand>(nand: &mut N, leb: &Leb, len: usize) -> anyhow::Result<Vec<u8>> {
+    let page_size = nand.get_layout().bytes_per_page;
+    anyhow::ensure!(
+        len <= eb_size(nand, &leb.ec)? as usize,
+        "block {} is too small to hold {len} bytes of data",
+        leb.block
+    );
+
+    let data_page = leb.ec.data_page(page_size)?;
+    let block = nand.block(leb.block)?.ok_or(anyhow::anyhow!(
+        "block {} unexpectedly marked bad",
+        leb.block
+    ))?;
+
+    // Reads must be an integral number of pages, so round up and then cut the excess.
+    let mut data = vec![0; len.div_ceil(page_size) * page_size];
+    block.read(data_page, &mut data)?;
+    data.truncate(len);
+
+    Ok(data)
+}
+
+/// Decode a volume table from the data of a layout volume LEB.
+///
+/// The table is indexed by volume ID, with `None` for unused entries; if any record is corrupt,
+/// there is no table at all.
+pub fn decode_volume_table(data: &[u8]) -> Option<Vec<Option<VolTableRecord>>> {
+    let empty_record = VolTableRecord::none_into_bytes();
+
+    data.chunks_exact(UBI_VTBL_RECORD_SIZE)
+        .take(UBI_MAX_VOLUMES)
+        .map(|bytes| match VolTableRecord::decode(bytes) {
+            Some(record) => Some(Some(record)),
+            None if bytes == empty_record => Some(None),
+            None => None, // Corrupt record
+        })
+        .collect()
+}
+
+/// Read the volume table out of the layout volume, returning it with the EB size of the PEB it
+/// came from.
+pub(super) fn read_table<N: Nand>(
+    nand: &mut N,
+    ebt: &Ebt,
+) -> anyhow::Result<(Vec<Option<VolTableRecord>>, u32)> {
+    for leb in find_lebs(ebt, UBI_LAYOUT_VOLUME_ID).into_values() {
+        let eb_size = eb_size(nand, &leb.ec)?;
+        let record_count = vtbl_record_count(eb_size.try_into()?);
+        let data = match read_leb(nand, &leb, record_count * UBI_VTBL_RECORD_SIZE) {
+            Ok(x) => x,
+            Err(_) => continue, // Try the other copy
+        };
+
+        if let Some(table) = decode_volume_table(&data) {
+            return Ok((table, eb_size));
+        }
+    }
+
+    anyhow::bail!("no intact copy of the UBI volume table found");
+}
+
+/// Read and decode the volume table out of the layout volume.
+///
+/// The returned table is indexed by volume ID, with `None` for unused entries. Either copy of the
+/// layout volume may be used, as long as every record in it is intact.
+pub fn read_volume_table<N: Nand>(
+    nand: &mut N,
+    ebt: &Ebt,
+) -> anyhow::Result<Vec<Option<VolTableRecord>>> {
+    read_table(nand, ebt).map(|(table, _)| table)
+}
+
+/// One copy of the volume table, as found by [read_volume_table_copies]
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeTableCopy {
+    /// Which LEB of the layout volume this copy is in
+    pub lnum: u32,
+
+    /// The PEB holding it
+    pub block: u32,
+
+    /// The table, or None if it couldn't be read or a record is corrupt
+    pub table: Option<Vec<Option<VolTableRecord>>>,
+}
+
+/// Read every copy of the volume table out of the layout volume, intact or not, for checking them
+/// against each other
+pub fn read_volume_table_copies<N: Nand>(nand: &mut N, ebt: &Ebt) -> Vec<VolumeTableCopy> {
+    find_lebs(ebt, UBI_LAYOUT_VOLUME_ID)
+        .into_values()
+        .map(|leb| {
+            let table = eb_size(nand, &leb.ec)
+                .and_then(|size| Ok(vtbl_record_count(size.try_into()?)))
+                .and_then(|count| read_leb(nand, &leb, count * UBI_VTBL_RECORD_SIZE))
+                .ok()
+                .and_then(|data| decode_volume_table(&data));
+            VolumeTableCopy {
+                lnum: leb.vid.lnum,
+                block: leb.block,
+                table,
+            }
+        })
+        .collect()
+}
+
+/// Look a volume up in a volume table, returning its ID and record
+pub(super) fn find_volume(
+    table: Vec<Option<VolTableRecord>>,
+    volume: &VolumeSelector,
+) -> anyhow::Result<(u32, VolTableRecord)> {
+    table
+        .into_iter()
+        .enumerate()
+        .filter_map(|(id, record)| Some((id as u32, record?)))
+        .find(|(id, record)| volume.matches(*id, record))
+        .ok_or(anyhow::anyhow!("volume {volume} not found"))
+}
+
+/// Extract the contents of a volume from the flash device, writing them to `out`.
+///
+/// Static volumes yield exactly the data that was written to them; a missing or corrupt LEB is an
+/// error, as are LEBs disagreeing on how many there are, or none left at all (an empty static
+/// volume looks just like a lost one). Dynamic volumes yield their whole reserved size, with
+/// unmapped LEBs read as 0xFF.
+///
+/// Returns the number of bytes written to `out`.
+pub fn read_volume<N: Nand, W: Write>(
+    nand: &mut N,
+    ebt: &Ebt,
+    volume: &VolumeSelector,
+    out: &mut W,
+) -> anyhow::Result<u64> {
+    let (table, layout_eb_size) = read_table(nand, ebt)?;
+    let (vol_id, record) = find_volume(table, volume)?;
+
+    let lebs = find_live_lebs(nand, ebt, vol_id)?;
+
+    let mut written = 0;
+    match record.vol_type {
+        VolType::Static => {
+            // Every LEB says how many the volume has, so they must all agree; with none found at
+            // all, the volume was lost rather than left empty
+            let Some((&first, leb)) = lebs.iter().next() else {
+                anyhow::ensure!(
+                    record.reserved_pebs == 0,
+                    "volume {volume} has no LEBs left"
+                );
+                return Ok(0);
+            };
+            let used_ebs = leb.vid.used_ebs;
+            if let Some((lnum, leb)) = lebs.iter().find(|(_, x)| x.vid.used_ebs != used_ebs) {
+                anyhow::bail!(
+                    "LEBs {first} and {lnum} of volume {volume} disagree on its size ({used_ebs} \
+                     and {} LEBs)",
+                    leb.vid.used_ebs
+                );
+            }
+
+            for lnum in 0..used_ebs {
+                let leb = lebs
+                    .get(&lnum)
+                    .ok_or(anyhow::anyhow!("LEB {lnum} of volume {volume} is missing"))?;
+
+                let data = read_static_leb(nand, leb, volume)?;
+                out.write_all(&data)?;
+                written += data.len() as u64;
+            }
+        }
+
+        VolType::Dynamic => {
+            // Every LEB of the volume is the same size; use the EC header of a mapped LEB to find
+            // it, or the layout volume's if no LEB is mapped.
+            let eb_size = match lebs.values().next() {
+                Some(leb) => eb_size(nand, &leb.ec)?,
+                None => layout_eb_size,
+            };
+            let leb_size = eb_size
+                .checked_sub(record.data_pad)
+                .ok_or(anyhow::anyhow!("volume {volume} has invalid data_pad"))?;
+
+            // A mapped LEB goes by its own VID header's padding, which ought to agree anyway
+            for lnum in 0..record.reserved_pebs {
+                let data = match lebs.get(&lnum) {
+                    Some(leb) => read_leb(nand, leb, self::leb_size(nand, leb)? as usize)?,
+                    None => vec![0xFF; leb_size as usize],
+                };
+
+                out.write_all(&data)?;
+                written += data.len() as u64;
+            }
+        }
+    }
+
+    Ok(written)
+}
+
+/// Read one LEB of a volume out of the flash device, or None if it isn't mapped.
+///
+/// As with [read_volume], a static volume's LEB is the data written to it, which must match its
+/// CRC, and a dynamic volume's is the whole LEB.
+pub fn read_volume_leb<N: Nand>(
+    nand: &mut N,
+    ebt: &Ebt,
+    volume: &VolumeSelector,
+    lnum: u32,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let (table, _) = read_table(nand, ebt)?;
+    let (vol_id, record) = find_volume(table, volume)?;
+    let Some(leb) = find_live_lebs(nand, ebt, vol_id)?.remove(&lnum) else {
+        return Ok(None);
+    };
+
+    let data = match record.vol_type {
+        VolType::Static => read_static_leb(nand, &leb, volume)?,
+        VolType::Dynamic => read_leb(nand, &leb, leb_size(nand, &leb)? as usize)?,
+    };
+
+    Ok(Some(data))
+}
+
+#[test]
+fn test_read_volume() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Volume};
+    use super::{format, scan_blocks, write_volumes};
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+    const LEB_SIZE: usize = 128 * 14;
+
+    let static_data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    let dynamic_data: Vec<u8> = (0..2000).map(|i| (i % 13) as u8).collect();
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+
+    let mut static_image = &static_data[..];
+    let mut dynamic_image = &dynamic_data[..];
+    let volumes: Vec<Box<dyn Volume>> = vec![
+        Box::new(
+            BasicVolume::new(VolType::Static)
+                .name("rootfs")
+                .size(static_data.len() as u64)
+                .image(&mut static_image),
+        ),
+        Box::new(
+            BasicVolume::new(VolType::Dynamic)
+                .name("env")
+                .size(4000)
+                .image(&mut dynamic_image),
+        ),
+    ];
+    let stats = write_volumes(&mut nand, &mut ebt, volumes)?;
+    assert_eq!(stats.pebs_written[&0], 3);
+    assert_eq!(stats.pebs_written[&UBI_LAYOUT_VOLUME_ID], 2);
+
+    let ebt = scan_blocks(&mut nand)?;
+
+    let mut out = Vec::new();
+    let len = read_volume(
+        &mut nand,
+        &ebt,
+        &VolumeSelector::Name("rootfs".into()),
+        &mut out,
+    )?;
+    assert_eq!(len, static_data.len() as u64);
+    assert_eq!(out, static_data);
+
+    let mut out = Vec::new();
+    read_volume(&mut nand, &ebt, &"1".parse()?, &mut out)?;
+    assert_eq!(out.len(), LEB_SIZE * 3);
+    assert_eq!(out[..dynamic_data.len()], dynamic_data);
+    assert!(out[dynamic_data.len()..].iter().all(|&x| x == 0xFF));
+
+    assert!(read_volume(&mut nand, &ebt, &"nope".parse()?, &mut Vec::new()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_read_volume_foreign_offsets() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Volume};
+    use super::{format, scan_blocks, write_volumes};
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+    const LEB_SIZE: usize = 128 * 14;
+
+    // Two LEBs; the second is short enough to survive relocation to a later data offset
+    let data: Vec<u8> = (0..LEB_SIZE + 1000).map(|i| (i % 241) as u8).collect();
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+
+    let mut image = &data[..];
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(data.len() as u64)
+            .image(&mut image),
+    )];
+    write_volumes(&mut nand, &mut ebt, volumes)?;
+
+    // Rewrite LEB 1 the way a tool using different header offsets would
+    let leb = find_lebs(&ebt, 0)[&1];
+    let foreign_ec = Ec {
+        vid_hdr_offset: 128 * 2,
+        data_offset: 128 * 4,
+        ..leb.ec
+    };
+    let leb_data = read_leb(&mut nand, &leb, leb.vid.data_size as usize)?;
+
+    let mut block = nand.block(leb.block)?.unwrap();
+    block.erase()?;
+    let mut page = vec![0xFF; 128];
+    foreign_ec.encode(&mut page)?;
+    block.program(0, &page)?;
+    leb.vid.encode(&mut page)?;
+    block.program(2, &page)?;
+    let mut padded = leb_data.clone();
+    padded.resize(leb_data.len().div_ceil(128) * 128, 0xFF);
+    block.program(4, &padded)?;
+    drop(block);
+
+    let ebt = scan_blocks(&mut nand)?;
+    assert_eq!(
+        ebt[leb.block as usize],
+        BlockContent::EcData(foreign_ec, Some(leb.vid))
+    );
+
+    let mut out = Vec::new();
+    read_volume(&mut nand, &ebt, &"rootfs".parse()?, &mut out)?;
+    assert_eq!(out, data);
+
+    Ok(())
+}
+
+#[test]
+fn test_read_volume_salvaged() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Volume};
+    use super::{format, scan_blocks, write_volumes};
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+    const LEB_SIZE: usize = 128 * 14;
+
+    let data: Vec<u8> = (0..LEB_SIZE * 2 + 500).map(|i| (i % 239) as u8).collect();
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+
+    let mut image = &data[..];
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(data.len() as u64)
+            .image(&mut image),
+    )];
+    write_volumes(&mut nand, &mut ebt, volumes)?;
+
+    // Rewrite LEB 1 with its EC header damaged, but everything after intact
+    let leb = find_lebs(&ebt, 0)[&1];
+    let block_bytes = TEST_LAYOUT.block_bytes();
+    let mut contents = vec![0; block_bytes];
+    let mut block = nand.block(leb.block)?.unwrap();
+    block.read(0, &mut contents)?;
+    contents[10] ^= 0x01;
+    block.erase()?;
+    block.program(0, &contents)?;
+    drop(block);
+
+    let mut ebt = scan_blocks(&mut nand)?;
+    assert_eq!(ebt[leb.block as usize], BlockContent::VidOnly(leb.vid));
+
+    // Its data is still read back...
+    let mut out = Vec::new();
+    read_volume(&mut nand, &ebt, &"rootfs".parse()?, &mut out)?;
+    assert_eq!(out, data);
+    let volume = VolumeSelector::Id(0);
+    let leb_data = read_volume_leb(&mut nand, &ebt, &volume, 1)?.unwrap();
+    assert_eq!(leb_data, data[LEB_SIZE..LEB_SIZE * 2]);
+
+    // ...and a format doesn't take it for a SIMULATE_MULTIPLANE block, only giving it the mean EC
+    let (proto, _) = compute_prototype(TEST_LAYOUT, ebt.iter().copied())?;
+    let stats = format(&mut nand, &mut ebt)?;
+    assert!(!stats.migrated);
+    assert_eq!(ebt[leb.block as usize], BlockContent::EcErased(proto));
+
+    Ok(())
+}
+
+#[test]
+fn test_read_volume_gaps() -> anyhow::Result<()> {
+    use super::ubinize::{LebStreamVolume, Volume};
+    use super::{format, scan_blocks, write_volumes};
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+    const LEB_SIZE: usize = 128 * 14;
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+
+    // LEBs 1 and 3 of the dynamic volume are left unmapped
+    let dynamic_lebs = vec![(0, vec![0x11; LEB_SIZE]), (2, vec![0x22; 300])];
+    let static_lebs = vec![(0, vec![0x33; LEB_SIZE]), (1, vec![0x44; 10])];
+    let volumes: Vec<Box<dyn Volume>> = vec![
+        Box::new(
+            LebStreamVolume::new(VolType::Dynamic, dynamic_lebs)
+                .name("data")
+                .reserve(4),
+        ),
+        Box::new(LebStreamVolume::new(VolType::Static, static_lebs).name("backup")),
+    ];
+    let stats = write_volumes(&mut nand, &mut ebt, volumes)?;
+    assert_eq!(stats.pebs_written[&0], 2);
+
+    let ebt = scan_blocks(&mut nand)?;
+
+    let mut expected = vec![0x11; LEB_SIZE];
+    expected.resize(LEB_SIZE * 2, 0xFF);
+    expected.resize(LEB_SIZE * 2 + 300, 0x22);
+    expected.resize(LEB_SIZE * 4, 0xFF);
+    let mut out = Vec::new();
+    read_volume(&mut nand, &ebt, &"data".parse()?, &mut out)?;
+    assert_eq!(out, expected);
+
+    let mut expected = vec![0x33; LEB_SIZE];
+    expected.resize(LEB_SIZE + 10, 0x44);
+    let mut out = Vec::new();
+    read_volume(&mut nand, &ebt, &"backup".parse()?, &mut out)?;
+    assert_eq!(out, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_read_volume_static_lost() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Volume};
+    use super::{format, scan_blocks, write_volumes};
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+    const LEB_SIZE: usize = 128 * 14;
+
+    let data = vec![0x5A; LEB_SIZE + 100];
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(data.len() as u64)
+            .image(&data[..]),
+    )];
+    write_volumes(&mut nand, &mut ebt, volumes)?;
+
+    // With every LEB erased, the volume is gone, not empty
+    for leb in find_lebs(&ebt, 0).into_values() {
+        nand.block(leb.block)?.unwrap().erase()?;
+    }
+    let ebt = scan_blocks(&mut nand)?;
+    let error = read_volume(&mut nand, &ebt, &"rootfs".parse()?, &mut Vec::new()).unwrap_err();
+    assert!(error.to_string().contains("no LEBs left"), "{error}");
+
+    Ok(())
+}
+
+#[test]
+fn test_read_volume_static_used_ebs() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Volume};
+    use super::{format, scan_blocks, write_volumes};
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+    const LEB_SIZE: usize = 128 * 14;
+
+    let data = vec![0x5A; LEB_SIZE + 100];
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(data.len() as u64)
+            .image(&data[..]),
+    )];
+    write_volumes(&mut nand, &mut ebt, volumes)?;
+
+    // Rewrite the last LEB, intact, but claiming the volume is one LEB longer than the first does
+    let leb = find_lebs(&ebt, 0)[&1];
+    let leb_data = read_leb(&mut nand, &leb, leb.vid.data_size as usize)?;
+    let vid = Vid {
+        used_ebs: 3,
+        ..leb.vid
+    };
+
+    let mut block = nand.block(leb.block)?.unwrap();
+    block.erase()?;
+    let mut page = vec![0xFF; 128];
+    leb.ec.encode(&mut page)?;
+    block.program(0, &page)?;
+    vid.encode(&mut page)?;
+    block.program(1, &page)?;
+    let mut padded = leb_data;
+    padded.resize(256, 0xFF);
+    block.program(2, &padded)?;
+    drop(block);
+
+    let ebt = scan_blocks(&mut nand)?;
+    assert_eq!(
+        ebt[leb.block as usize],
+        BlockContent::EcData(leb.ec, Some(vid))
+    );
+    let error = read_volume(&mut nand, &ebt, &"rootfs".parse()?, &mut Vec::new()).unwrap_err();
+    assert!(error.to_string().contains("disagree"), "{error}");
+
+    Ok(())
+}
+
+#[test]
+fn test_read_volume_aligned() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Volume};
+    use super::{format, scan_blocks, write_volumes};
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 1024,
+    };
+    // 4KiB alignment leaves 2KiB of the 14KiB EB unused
+    const LEB_SIZE: usize = 12 * 1024;
+
+    let static_data: Vec<u8> = (0..LEB_SIZE * 2).map(|i| (i % 251) as u8).collect();
+    let dynamic_data: Vec<u8> = (0..1000).map(|i| (i % 13) as u8).collect();
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+
+    let mut static_image = &static_data[..];
+    let mut dynamic_image = &dynamic_data[..];
+    let volumes: Vec<Box<dyn Volume>> = vec![
+        Box::new(
+            BasicVolume::new(VolType::Static)
+                .name("rootfs")
+                .size(static_data.len() as u64)
+                .align(4096.try_into()?)
+                .image(&mut static_image),
+        ),
+        Box::new(
+            BasicVolume::new(VolType::Dynamic)
+                .name("env")
+                .size(LEB_SIZE as u64 * 2)
+                .align(4096.try_into()?)
+                .image(&mut dynamic_image),
+        ),
+    ];
+    let stats = write_volumes(&mut nand, &mut ebt, volumes)?;
+    // An exact multiple of the LEB size takes no more LEBs than it needs
+    assert_eq!(stats.pebs_written[&0], 2);
+
+    let ebt = scan_blocks(&mut nand)?;
+    let table = read_volume_table(&mut nand, &ebt)?;
+    for vol_id in [0, 1] {
+        let record = table[vol_id].as_ref().unwrap();
+        assert_eq!((record.alignment, record.data_pad), (4096, 2048));
+        let lebs = find_lebs(&ebt, vol_id as u32);
+        assert!(lebs.values().all(|leb| leb.vid.data_pad == 2048));
+    }
+    assert_eq!(table[1].as_ref().unwrap().reserved_pebs, 2);
+
+    let mut out = Vec::new();
+    read_volume(&mut nand, &ebt, &"rootfs".parse()?, &mut out)?;
+    assert_eq!(out, static_data);
+
+    let mut out = Vec::new();
+    read_volume(&mut nand, &ebt, &"env".parse()?, &mut out)?;
+    assert_eq!(out.len(), LEB_SIZE * 2);
+    assert_eq!(out[..dynamic_data.len()], dynamic_data);
+    assert!(out[dynamic_data.len()..].iter().all(|&x| x == 0xFF));
+
+    // An alignment bigger than the EB leaves no room for anything
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+    let mut image = &static_data[..100];
+    let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(100)
+            .align((32 * 1024).try_into()?)
+            .image(&mut image),
+    )];
+    assert!(write_volumes(&mut nand, &mut ebt, volumes).is_err());
+
+    Ok(())
+}
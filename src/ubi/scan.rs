@@ -1,7 +1,15 @@
 //! This module contains code to scan NAND blocks and determine their contents (per UBI).
 
 use super::headers::*;
-use crate::nand::{Nand, NandBlock, PageUtil};
+use super::ubinize::{UBI_FM_DATA_VOLUME_ID, UBI_FM_SB_VOLUME_ID, UBI_LAYOUT_VOLUME_ID};
+use crate::nand::{io_pages, Nand, NandBlock, PageUtil, SharedNand};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
 /// These are the states that a given block may be detected in
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -24,30 +32,66 @@ pub enum BlockContent {
     /// identically to Garbage
     RawVid(Vid),
 
+    /// The block's EC header is unreadable, but its VID header (in the page after, where UBI puts
+    /// it) is intact, so the data it holds is still live. Its erase counter is lost; as UBI does,
+    /// it goes by the mean when erased.
+    VidOnly(Vid),
+
     /// The block is in some other (invalid, per UBI) state, and needs to be erased
     Garbage,
 }
 
 impl BlockContent {
-    /// Read a NAND block and characterize its content
-    fn scan_block<B: NandBlock>(block: &B) -> anyhow::Result<Self> {
-        // How many pages do we read at a time? A higher number helps in high-latency situations.
-        const PAGE_CHUNKS: u32 = 4;
+    /// Does this block belong to a UBI fastmap (either its anchor or its data)?
+    pub fn is_fastmap(&self) -> bool {
+        matches!(
+            self,
+            Self::EcData(_, Some(vid))
+                if [UBI_FM_SB_VOLUME_ID, UBI_FM_DATA_VOLUME_ID].contains(&vid.vol_id)
+        )
+    }
 
-        let mut buf = vec![0; block.page_size() * PAGE_CHUNKS as usize];
+    /// The block's EC header, if it has one
+    pub fn ec(&self) -> Option<Ec> {
+        match self {
+            Self::EcErased(ec) | Self::EcData(ec, _) => Some(*ec),
+            _ => None,
+        }
+    }
 
+    /// Read a NAND block and characterize its content, reading `chunk_pages` pages at a time into
+    /// `buf` (which must hold that many).
+    ///
+    /// The first chunk is no bigger than [PAGE_CHUNKS], since that's as far as a block starting
+    /// with a UBI header (as most do) is read; only a block read through, to see that it's erased,
+    /// gets the bigger chunks. If the block turns out to start with a UBI header, `buf` is left
+    /// holding its first chunk.
+    fn scan_block<B: NandBlock>(
+        block: &B,
+        chunk_pages: u32,
+        buf: &mut [u8],
+    ) -> anyhow::Result<Self> {
         let mut echdr: Option<Ec> = None;
-        for start_page in (0..block.page_count()).step_by(PAGE_CHUNKS as usize) {
+        let mut in_use = false;
+        let mut corrupt_first_page = false;
+        let mut chunk_end = 0;
+        let mut start_page = 0;
+        'scan: while start_page < block.page_count() {
+            let pages = match start_page {
+                0 => chunk_pages.min(PAGE_CHUNKS),
+                _ => chunk_pages,
+            };
             if echdr.is_some() {
                 // Optimization: If we have found an EC header, but we're still looping, it means
                 // the first few pages were [EC, erased, ...], so we can probably just assume the
-                // rest of the pages are erased.
+                // rest of the pages are erased (aside from the VID header, checked below).
                 break;
             }
 
             // Clip the buffer down to the size of the page(s) read on this iteration
-            let end_page = std::cmp::min(block.page_count(), start_page + PAGE_CHUNKS);
+            let end_page = std::cmp::min(block.page_count(), start_page + pages);
             let buf = &mut buf[..block.page_size() * (end_page - start_page) as usize];
+            chunk_end = end_page;
 
             // Read pages `start_page..end_page`
             block.read(start_page, buf)?;
@@ -67,47 +111,930 @@ impl BlockContent {
                 // Not first page, or first page doesn't contain a UBI header, so this loop is now
                 // finding out if the block is fully-erased.
                 if !page_bytes.is_erased() {
-                    let vid = match page {
-                        1 => Vid::decode(page_bytes),
-                        _ => None,
-                    };
-
                     // Non-erased page found means this block is in use
-                    return Ok(echdr.map_or(Self::Garbage, |x| Self::EcData(x, vid)));
+                    in_use = true;
+                    corrupt_first_page = page == 0;
+                    break 'scan;
                 }
             }
+            start_page = end_page;
         }
 
-        // If we got out of the loop, we didn't encounter any data pages, so it's erased
-        Ok(echdr.map_or(Self::Erased, Self::EcErased))
+        // A backup of the EC header in the OOB area (see [Ec::encode_backup]) saves the erase
+        // counter of a block whose first page is corrupt; the page still has to be rewritten, so
+        // the block is taken to hold data, whether or not its VID header survived
+        if echdr.is_none() && corrupt_first_page {
+            echdr = ec_backup(block);
+        }
+
+        let ec = match (echdr, in_use) {
+            (None, false) => return Ok(Self::Erased),
+            (None, true) if corrupt_first_page && block.page_count() > 1 => {
+                // Only the EC header may have been lost, if the VID header after it is intact.
+                // The loop above stopped in the first chunk, so that's what `buf` holds.
+                let mut vid_buf = vec![];
+                let vid_bytes: &[u8] = if chunk_end > 1 {
+                    &buf[block.page_size()..][..block.page_size()]
+                } else {
+                    vid_buf.resize(block.page_size(), 0);
+                    block.read(1, &mut vid_buf)?;
+                    &vid_buf
+                };
+                return Ok(Vid::decode(vid_bytes).map_or(Self::Garbage, Self::VidOnly));
+            }
+            (None, true) => return Ok(Self::Garbage),
+            (Some(ec), _) => ec,
+        };
+
+        // The EC header says where the VID header lives; only look for it there. If the EC header
+        // was found, the loop above stopped after the first chunk, so that's what `buf` holds.
+        let vid_page = match ec.vid_hdr_page(block.page_size()) {
+            Ok(page) if page > 0 && page < block.page_count() => page,
+            _ => {
+                return Ok(if in_use {
+                    Self::EcData(ec, None)
+                } else {
+                    Self::EcErased(ec)
+                })
+            }
+        };
+        let mut vid_buf = vec![];
+        let vid_bytes: &[u8] = if vid_page < chunk_end {
+            &buf[vid_page as usize * block.page_size()..][..block.page_size()]
+        } else {
+            vid_buf.resize(block.page_size(), 0);
+            block.read(vid_page, &mut vid_buf)?;
+            &vid_buf
+        };
+
+        if in_use || !vid_bytes.is_erased() {
+            Ok(Self::EcData(ec, Vid::decode(vid_bytes)))
+        } else {
+            Ok(Self::EcErased(ec))
+        }
+    }
+}
+
+/// The backup of a block's EC header in the OOB area of its first page, if it has one
+fn ec_backup<B: NandBlock>(block: &B) -> Option<Ec> {
+    if block.oob_free() < EC_BACKUP_SIZE {
+        return None;
     }
+    let mut oob = [0; EC_BACKUP_SIZE];
+    block.read_oob(0, &mut oob).ok()?;
+    Ec::decode_backup(&oob)
 }
 
+/// How many pages [BlockContent::scan_block] reads at a time, unless the NAND prefers otherwise. A
+/// higher number helps in high-latency situations.
+const PAGE_CHUNKS: u32 = 4;
+
 /// The (E)rase(b)lock (t)able. A map of the current state of the NAND flash as determined by
 /// [scan_blocks], which should be kept up-to-date as other operations are performed on flash.
 pub type Ebt = Box<[BlockContent]>;
 
+/// An error partway through an operation that keeps the [Ebt] up-to-date as it goes (such as
+/// [format](super::format)).
+///
+/// Every entry is still accurate except those of the `count` blocks from `block` on, which should
+/// be rescanned (see [rescan_range]) before the [Ebt] is used again.
+#[derive(Debug)]
+pub struct EbtError {
+    /// The block being worked on when the error happened
+    pub block: u32,
+
+    /// How many blocks, from `block` on, can't be trusted: 1, unless the error cut short the
+    /// handling of several at once
+    pub count: u32,
+
+    /// How many blocks had already been dealt with
+    pub processed: u32,
+
+    error: anyhow::Error,
+}
+
+impl EbtError {
+    pub(super) fn new(block: u32, processed: u32, error: anyhow::Error) -> Self {
+        Self::range(block, 1, processed, error)
+    }
+
+    pub(super) fn range(block: u32, count: u32, processed: u32, error: anyhow::Error) -> Self {
+        Self {
+            block,
+            count,
+            processed,
+            error,
+        }
+    }
+
+    /// The blocks whose entries can't be trusted
+    pub fn blocks(&self) -> Range<u32> {
+        self.block..self.block + self.count
+    }
+}
+
+impl fmt::Display for EbtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {}: {:#}", self.block, self.error)
+    }
+}
+
+impl std::error::Error for EbtError {}
+
+/// How many times a block is read before it's given up on as unreadable
+const SCAN_ATTEMPTS: u32 = 2;
+
+/// The blocks a scan couldn't read cleanly. Rather than fail the scan, it takes a block whose reads
+/// fail to be garbage, for erasing to decide its fate. That goes for one whose reads keep failing,
+/// too: pages left half-programmed by a power cut fail ECC however often they're read, but erase
+/// cleanly, and a block that really is bad fails to erase.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ScanIssues {
+    /// Blocks that failed to read, but read on trying again; taken to be [BlockContent::Garbage]
+    pub flaky: Vec<u32>,
+
+    /// Blocks that failed to read every time; also taken to be [BlockContent::Garbage]
+    pub unreadable: Vec<u32>,
+}
+
+impl ScanIssues {
+    pub fn is_empty(&self) -> bool {
+        self.flaky.is_empty() && self.unreadable.is_empty()
+    }
+
+    /// How many blocks failed to read at all
+    pub fn count(&self) -> usize {
+        self.flaky.len() + self.unreadable.len()
+    }
+
+    fn add(&mut self, block: u32, issue: Option<ScanIssue>) {
+        match issue {
+            Some(ScanIssue::Flaky) => self.flaky.push(block),
+            Some(ScanIssue::Unreadable) => self.unreadable.push(block),
+            None => (),
+        }
+    }
+}
+
+impl fmt::Display for ScanIssues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} block(s) failed to read and will be erased {:?}, {} could not be read at all and \
+             will be erased, or marked bad if that fails {:?}",
+            self.flaky.len(),
+            self.flaky,
+            self.unreadable.len(),
+            self.unreadable
+        )
+    }
+}
+
+/// Why a block was classified without being read
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ScanIssue {
+    Flaky,
+    Unreadable,
+}
+
+/// Scan block `n`, if it's not bad, retrying if reading it fails (see [ScanIssues]). `buf` is
+/// used as [BlockContent::scan_block] uses it.
+fn scan_or_classify<N: Nand>(
+    nand: &mut N,
+    n: u32,
+    chunk_pages: u32,
+    buf: &mut [u8],
+) -> anyhow::Result<(BlockContent, Option<ScanIssue>)> {
+    let Some(block) = nand.block(n)? else {
+        return Ok((BlockContent::Bad, None));
+    };
+    if let Ok(content) = BlockContent::scan_block(&block, chunk_pages, buf) {
+        return Ok((content, None));
+    }
+
+    // Whatever a retry reads can't be trusted, but it says whether the block is readable at all
+    let readable =
+        (1..SCAN_ATTEMPTS).any(|_| BlockContent::scan_block(&block, chunk_pages, buf).is_ok());
+    let issue = match readable {
+        true => ScanIssue::Flaky,
+        false => ScanIssue::Unreadable,
+    };
+    Ok((BlockContent::Garbage, Some(issue)))
+}
+
 /// Read all blocks of the NAND (only as much as necessary to determine content), return the [Ebt]
+///
+/// Blocks that can't be read are reported, and classified as [ScanIssues] describes.
 pub fn scan_blocks<N: Nand>(nand: &mut N) -> anyhow::Result<Ebt> {
+    Ok(scan_blocks_with_issues(nand)?.0)
+}
+
+/// Like [scan_blocks], but also returns the blocks that couldn't be read
+pub fn scan_blocks_with_issues<N: Nand>(nand: &mut N) -> anyhow::Result<(Ebt, ScanIssues)> {
+    scan_inner(nand, None)
+}
+
+/// Like [scan_blocks_with_issues], but keeping the header pages of the blocks that start with a
+/// UBI header in `cache`, for [verify_ebt] to use later
+pub fn scan_blocks_cached<N: Nand>(
+    nand: &mut N,
+    cache: &mut HeaderCache,
+) -> anyhow::Result<(Ebt, ScanIssues)> {
+    scan_inner(nand, Some(cache))
+}
+
+fn scan_inner<N: Nand>(
+    nand: &mut N,
+    mut cache: Option<&mut HeaderCache>,
+) -> anyhow::Result<(Ebt, ScanIssues)> {
+    nand.get_layout().validate_for_ubi()?;
     let block_count = nand.get_layout().blocks;
     let rpt = howudoin::new()
         .label("Scanning blocks")
         .set_len(u64::from(block_count));
 
-    // Grr, try_collect() isn't stable yet, so:
+    // The cache needs the header pages to come in the first chunk
+    let mut chunk_pages = io_pages(nand, PAGE_CHUNKS);
+    if cache.is_some() {
+        chunk_pages = chunk_pages.max(HEADER_PAGES);
+    }
+    let page_size = nand.get_layout().bytes_per_page;
+    let mut buf = vec![0; page_size * chunk_pages as usize];
+
     let mut ebt = Vec::with_capacity(block_count as usize);
-    for block_result in (0..block_count).map(|n| {
-        nand.block(n)?
-            .as_ref()
-            .map_or(Ok(BlockContent::Bad), BlockContent::scan_block)
-    }) {
+    let mut issues = ScanIssues::default();
+    for n in 0..block_count {
+        let (content, issue) = scan_or_classify(nand, n, chunk_pages, &mut buf)?;
+        if let Some(cache) = cache.as_deref_mut() {
+            if HeaderCache::keeps(&content) {
+                cache.insert(n, content, &buf[..page_size * HEADER_PAGES as usize]);
+            }
+        }
         rpt.inc();
-        ebt.push(block_result?);
+        ebt.push(content);
+        issues.add(n, issue);
     }
 
+    report_misplaced(&rpt, &ebt);
+    report_issues(&rpt, &issues);
     rpt.close();
 
-    Ok(ebt.into())
+    Ok((ebt.into(), issues))
+}
+
+/// Scan the blocks in `range` again, updating their entries in the [Ebt]
+pub fn rescan_range<N: Nand>(nand: &mut N, ebt: &mut Ebt, range: Range<u32>) -> anyhow::Result<()> {
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+    let mut buf = vec![0; nand.get_layout().bytes_per_page * chunk_pages as usize];
+    for n in range {
+        ebt[n as usize] = scan_or_classify(nand, n, chunk_pages, &mut buf)?.0;
+    }
+
+    Ok(())
+}
+
+/// Read every block back, returning those whose content isn't what the [Ebt] says, e.g. after
+/// writing to them.
+///
+/// A block whose entry is still what it was when `cache` took its headers hasn't been touched
+/// since, so it's taken to be as scanned rather than read again.
+pub fn verify_ebt<N: Nand>(
+    nand: &mut N,
+    ebt: &Ebt,
+    mut cache: Option<&mut HeaderCache>,
+) -> anyhow::Result<Vec<u32>> {
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+    let mut buf = vec![0; nand.get_layout().bytes_per_page * chunk_pages as usize];
+    let mut mismatched = vec![];
+    for (n, expected) in (0..).zip(ebt.iter()) {
+        if let Some(cache) = cache.as_deref_mut() {
+            if cache.get(n, expected).is_some() {
+                continue;
+            }
+        }
+        if scan_or_classify(nand, n, chunk_pages, &mut buf)?.0 != *expected {
+            mismatched.push(n);
+        }
+    }
+
+    Ok(mismatched)
+}
+
+/// How many pages at the start of a block a [HeaderCache] keeps: the EC header's and the VID
+/// header's
+const HEADER_PAGES: u32 = 2;
+
+/// The raw header pages of blocks that a scan found to start with a UBI header
+/// ([BlockContent::EcErased], [BlockContent::EcData] or [BlockContent::RawVid]), so that later
+/// passes over the same blocks needn't read them again.
+///
+/// An entry only stands for as long as the block's [Ebt] entry is what it was when scanned: any
+/// erase or write changes the entry (if only the erase counter), so looking a block up with its
+/// current entry says whether the cached pages are still what's on flash. At most `capacity` blocks
+/// are kept, the least recently used going first; each takes two pages of memory.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderCache {
+    capacity: usize,
+    entries: BTreeMap<u32, CachedHeaders>,
+
+    /// The blocks in [HeaderCache::entries], by when they were last used
+    lru: BTreeMap<u64, u32>,
+    clock: u64,
+    hits: u64,
+}
+
+#[derive(Debug, Clone)]
+struct CachedHeaders {
+    content: BlockContent,
+    pages: Box<[u8]>,
+    used: u64,
+}
+
+impl HeaderCache {
+    /// An empty cache, holding the headers of up to `capacity` blocks
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How many lookups have found what they were after
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The header pages of `block`, if they're cached and its content is still `content`. An entry
+    /// for the block with other content is out of date, and dropped.
+    pub fn get(&mut self, block: u32, content: &BlockContent) -> Option<&[u8]> {
+        if self.entries.get(&block)?.content != *content {
+            let stale = self.entries.remove(&block)?;
+            self.lru.remove(&stale.used);
+            return None;
+        }
+
+        let entry = self.entries.get_mut(&block)?;
+        self.lru.remove(&entry.used);
+        self.clock += 1;
+        entry.used = self.clock;
+        self.lru.insert(entry.used, block);
+        self.hits += 1;
+        Some(&entry.pages)
+    }
+
+    /// Is a block with this content one whose headers are kept?
+    fn keeps(content: &BlockContent) -> bool {
+        matches!(
+            content,
+            BlockContent::EcErased(_) | BlockContent::EcData(..) | BlockContent::RawVid(_)
+        )
+    }
+
+    fn insert(&mut self, block: u32, content: BlockContent, pages: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(old) = self.entries.remove(&block) {
+            self.lru.remove(&old.used);
+        }
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+
+        self.clock += 1;
+        let entry = CachedHeaders {
+            content,
+            pages: pages.into(),
+            used: self.clock,
+        };
+        self.lru.insert(entry.used, block);
+        self.entries.insert(block, entry);
+    }
+}
+
+/// Like [scan_blocks], but shares the blocks out among `threads` workers, each with its own handle
+/// to the NAND, so that the latency of one block's reads overlaps with the others'.
+pub fn scan_blocks_parallel<N: SharedNand>(nand: &mut N, threads: usize) -> anyhow::Result<Ebt> {
+    Ok(scan_blocks_parallel_with_issues(nand, threads)?.0)
+}
+
+/// Like [scan_blocks_parallel], but also returns the blocks that couldn't be read
+pub fn scan_blocks_parallel_with_issues<N: SharedNand>(
+    nand: &mut N,
+    threads: usize,
+) -> anyhow::Result<(Ebt, ScanIssues)> {
+    nand.get_layout().validate_for_ubi()?;
+    let block_count = nand.get_layout().blocks;
+    let rpt = howudoin::new()
+        .label("Scanning blocks")
+        .set_len(u64::from(block_count));
+
+    let chunk_pages = io_pages(nand, PAGE_CHUNKS);
+    let next_block = AtomicU32::new(0);
+    let mut ebt = vec![None; block_count as usize];
+    let mut issues = ScanIssues::default();
+    thread::scope(|s| -> anyhow::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..threads.max(1) {
+            let mut nand = nand.clone_handle()?;
+            let tx = tx.clone();
+            let next_block = &next_block;
+
+            s.spawn(move || {
+                let page_size = nand.get_layout().bytes_per_page;
+                let mut buf = vec![0; page_size * chunk_pages as usize];
+                loop {
+                    let n = next_block.fetch_add(1, Ordering::Relaxed);
+                    if n >= block_count {
+                        break;
+                    }
+
+                    let result = scan_or_classify(&mut nand, n, chunk_pages, &mut buf);
+
+                    // A failed send means the receiver gave up on an error; stop too.
+                    if tx.send((n, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        // Results arrive out of order, but only this thread reports progress, so it still counts up
+        for (n, result) in rx {
+            rpt.inc();
+            let (content, issue) = result?;
+            ebt[n as usize] = Some(content);
+            issues.add(n, issue);
+        }
+
+        Ok(())
+    })?;
+
+    // The workers only stop early on error, so every block has been scanned
+    let ebt: Vec<_> = ebt.into_iter().map(Option::unwrap).collect();
+
+    // ...but not in order
+    issues.flaky.sort_unstable();
+    issues.unreadable.sort_unstable();
+
+    report_misplaced(&rpt, &ebt);
+    report_issues(&rpt, &issues);
+    rpt.close();
+
+    Ok((ebt.into(), issues))
+}
+
+/// Blocks in use without a VID header where the EC header claims are not usable as LEBs; this
+/// usually means some other tool wrote them using different offsets.
+fn report_misplaced(rpt: &howudoin::Tx, ebt: &[BlockContent]) {
+    let misplaced = ebt
+        .iter()
+        .filter(|x| matches!(x, BlockContent::EcData(_, None)))
+        .count();
+    if misplaced > 0 {
+        rpt.add_info(format!(
+            "{misplaced} block(s) have no VID header at the offset claimed by their EC header"
+        ));
+    }
+}
+
+/// Blocks that couldn't be read, which would otherwise go unnoticed
+fn report_issues(rpt: &howudoin::Tx, issues: &ScanIssues) {
+    if !issues.is_empty() {
+        rpt.add_info(issues.to_string());
+    }
+}
+
+/// A short description of a block's content, e.g. `EcData(ec 4, vol 1, lnum 7)`
+impl fmt::Display for BlockContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bad => write!(f, "Bad"),
+            Self::Erased => write!(f, "Erased"),
+            Self::EcErased(ec) => write!(f, "EcErased(ec {})", ec.ec),
+            Self::EcData(ec, Some(vid)) => write!(
+                f,
+                "EcData(ec {}, vol {}, lnum {})",
+                ec.ec, vid.vol_id, vid.lnum
+            ),
+            Self::EcData(ec, None) => write!(f, "EcData(ec {}, no VID)", ec.ec),
+            Self::RawVid(vid) => write!(f, "RawVid(vol {}, lnum {})", vid.vol_id, vid.lnum),
+            Self::VidOnly(vid) => write!(f, "VidOnly(vol {}, lnum {})", vid.vol_id, vid.lnum),
+            Self::Garbage => write!(f, "Garbage"),
+        }
+    }
+}
+
+/// How many buckets [EcStats::histogram] has
+pub const EC_HISTOGRAM_BUCKETS: usize = 8;
+
+/// The spread of erase counters across the blocks that have them
+#[derive(Debug, Clone, PartialEq)]
+pub struct EcStats {
+    /// How many blocks had an erase counter
+    pub count: u32,
+
+    pub min: u64,
+    pub mean: f64,
+    pub median: u64,
+    pub max: u64,
+
+    /// How many blocks fall into each of [EC_HISTOGRAM_BUCKETS] equal-width buckets, from `min` to
+    /// `max`; bucket `i` starts at `min + i * bucket_width`
+    pub histogram: [u32; EC_HISTOGRAM_BUCKETS],
+    pub bucket_width: u64,
+}
+
+impl EcStats {
+    /// Work out the spread of some erase counters, or None if there are none
+    pub fn from_ecs(ecs: impl IntoIterator<Item = u64>) -> Option<Self> {
+        let mut ecs: Vec<u64> = ecs.into_iter().collect();
+        ecs.sort_unstable();
+        let (&min, &max) = (ecs.first()?, ecs.last()?);
+
+        let total: u128 = ecs.iter().map(|&x| u128::from(x)).sum();
+        let bucket_width = (max - min) / EC_HISTOGRAM_BUCKETS as u64 + 1;
+        let mut histogram = [0; EC_HISTOGRAM_BUCKETS];
+        for ec in &ecs {
+            histogram[((ec - min) / bucket_width) as usize] += 1;
+        }
+
+        Some(Self {
+            count: ecs.len() as u32,
+            min,
+            mean: total as f64 / ecs.len() as f64,
+            median: ecs[(ecs.len() - 1) / 2],
+            max,
+            histogram,
+            bucket_width,
+        })
+    }
+}
+
+impl fmt::Display for EcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            min,
+            mean,
+            median,
+            max,
+            ..
+        } = self;
+        write!(
+            f,
+            "min {min}, median {median}, mean {mean:.1}, max {max} (over {} blocks)",
+            self.count
+        )
+    }
+}
+
+/// An overview of an [Ebt], as computed by [summarize]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct EbtSummary {
+    pub erased: u32,
+    pub ec_erased: u32,
+    pub ec_data: u32,
+    pub raw_vid: u32,
+    pub vid_only: u32,
+    pub garbage: u32,
+
+    /// The bad blocks, in order
+    pub bad_blocks: Vec<u32>,
+
+    /// The erase counters of the blocks with an EC header, or None if there are no such blocks
+    pub ec: Option<EcStats>,
+}
+
+/// Count up the blocks of an [Ebt] by content, and work out the spread of their erase counters
+pub fn summarize(ebt: &Ebt) -> EbtSummary {
+    let mut summary = EbtSummary::default();
+    let mut ecs = vec![];
+
+    for (n, content) in ebt.iter().enumerate() {
+        let ec = match content {
+            BlockContent::Bad => {
+                summary.bad_blocks.push(n as u32);
+                continue;
+            }
+            BlockContent::Erased => {
+                summary.erased += 1;
+                continue;
+            }
+            BlockContent::RawVid(_) => {
+                summary.raw_vid += 1;
+                continue;
+            }
+            BlockContent::VidOnly(_) => {
+                summary.vid_only += 1;
+                continue;
+            }
+            BlockContent::Garbage => {
+                summary.garbage += 1;
+                continue;
+            }
+            BlockContent::EcErased(ec) => {
+                summary.ec_erased += 1;
+                ec.ec
+            }
+            BlockContent::EcData(ec, _) => {
+                summary.ec_data += 1;
+                ec.ec
+            }
+        };
+
+        ecs.push(ec);
+    }
+
+    summary.ec = EcStats::from_ecs(ecs);
+    summary
+}
+
+impl fmt::Display for EbtSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (label, count) in [
+            ("Erased", self.erased),
+            ("EcErased", self.ec_erased),
+            ("EcData", self.ec_data),
+            ("RawVid", self.raw_vid),
+            ("VidOnly", self.vid_only),
+            ("Garbage", self.garbage),
+            ("Bad", self.bad_blocks.len() as u32),
+        ] {
+            writeln!(f, "{:<10}{count:>6}", format!("{label}:"))?;
+        }
+        if !self.bad_blocks.is_empty() {
+            let blocks: Vec<String> = self.bad_blocks.iter().map(u32::to_string).collect();
+            writeln!(f, "{:<10}{}", "Bad list:", blocks.join(", "))?;
+        }
+        match &self.ec {
+            Some(stats) => writeln!(f, "{:<10}{stats}", "EC:"),
+            None => writeln!(f, "{:<10}none", "EC:"),
+        }
+    }
+}
+
+/// How much of one volume in the volume table is on the NAND, as worked out by
+/// [summarize_volumes]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VolumeUsage {
+    pub vol_id: u32,
+    pub name: String,
+    pub vol_type: VolType,
+    pub reserved_pebs: u32,
+
+    /// How many distinct LEBs have a PEB holding them
+    pub lebs: u32,
+
+    /// The highest LEB number present, or None if there are none
+    pub highest_lnum: Option<u32>,
+
+    /// The total `data_size` of the LEBs present; only static volumes' VID headers give one
+    pub data_bytes: u64,
+
+    /// How many PEBs hold an older copy of a LEB that another PEB also holds
+    pub stale_copies: u32,
+}
+
+/// The volumes on the NAND, as worked out by [summarize_volumes]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VolumesSummary {
+    /// Each volume in the table, by ID
+    pub volumes: Vec<VolumeUsage>,
+
+    /// The PEBs of volumes that aren't in the table, by volume ID
+    pub orphans: BTreeMap<u32, Vec<u32>>,
+}
+
+/// Tally up the PEBs of each volume in `table` (as [read_volume_table](super::read_volume_table)
+/// gives it) from the VID headers in an [Ebt].
+///
+/// UBI's internal volumes (the layout volume and fastmap) are left out.
+pub fn summarize_volumes(ebt: &Ebt, table: &[Option<VolTableRecord>]) -> VolumesSummary {
+    // The VID header of the newest copy of each LEB, and how many older ones there are, by volume
+    let mut lebs: BTreeMap<u32, BTreeMap<u32, Vid>> = BTreeMap::new();
+    let mut stale: BTreeMap<u32, u32> = BTreeMap::new();
+    let mut orphans: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+    for (block, content) in ebt.iter().enumerate() {
+        // A block that lost its EC header still holds its LEB
+        let (BlockContent::EcData(_, Some(vid)) | BlockContent::VidOnly(vid)) = content else {
+            continue;
+        };
+        if vid.vol_id >= UBI_LAYOUT_VOLUME_ID {
+            continue;
+        }
+        if !matches!(table.get(vid.vol_id as usize), Some(Some(_))) {
+            orphans.entry(vid.vol_id).or_default().push(block as u32);
+            continue;
+        }
+
+        let volume = lebs.entry(vid.vol_id).or_default();
+        match volume.get(&vid.lnum) {
+            None => {
+                volume.insert(vid.lnum, *vid);
+            }
+            Some(other) => {
+                // Only the newest copy counts; whichever this isn't is stale
+                if vid.sqnum > other.sqnum {
+                    volume.insert(vid.lnum, *vid);
+                }
+                *stale.entry(vid.vol_id).or_default() += 1;
+            }
+        }
+    }
+
+    let volumes = table
+        .iter()
+        .enumerate()
+        .filter_map(|(vol_id, record)| Some((vol_id as u32, record.as_ref()?)))
+        .map(|(vol_id, record)| {
+            let present = lebs.remove(&vol_id).unwrap_or_default();
+            VolumeUsage {
+                vol_id,
+                name: record.name.clone(),
+                vol_type: record.vol_type,
+                reserved_pebs: record.reserved_pebs,
+                lebs: present.len() as u32,
+                highest_lnum: present.keys().next_back().copied(),
+                data_bytes: present
+                    .values()
+                    .filter(|vid| vid.vol_type == VolType::Static)
+                    .map(|vid| u64::from(vid.data_size))
+                    .sum(),
+                stale_copies: stale.get(&vol_id).copied().unwrap_or(0),
+            }
+        })
+        .collect();
+
+    VolumesSummary { volumes, orphans }
+}
+
+impl fmt::Display for VolumesSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for volume in &self.volumes {
+            let highest = volume
+                .highest_lnum
+                .map_or("-".to_string(), |x| x.to_string());
+            write!(
+                f,
+                "{:>3} {:<16} {:?}, {} of {} PEBs, highest LEB {highest}, {} data bytes",
+                volume.vol_id,
+                volume.name,
+                volume.vol_type,
+                volume.lebs,
+                volume.reserved_pebs,
+                volume.data_bytes,
+            )?;
+            if volume.stale_copies > 0 {
+                write!(f, ", {} stale copies", volume.stale_copies)?;
+            }
+            if volume.lebs > volume.reserved_pebs {
+                write!(f, " (more LEBs than reserved!)")?;
+            }
+            writeln!(f)?;
+        }
+        for (vol_id, blocks) in &self.orphans {
+            let blocks: Vec<String> = blocks.iter().map(u32::to_string).collect();
+            writeln!(
+                f,
+                "Orphan volume {vol_id}, not in the table: blocks {}",
+                blocks.join(", ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What happened to a block between two [Ebt]s, going by its content before and after
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Transition {
+    /// The block was erased, with or without getting an EC header
+    Erased,
+
+    /// The block was erased again, or had its erase counter changed, and is otherwise as it was
+    Recounted,
+
+    /// Data was written to the block
+    Written,
+
+    /// The block went bad
+    WentBad,
+
+    /// Anything else, such as the block turning to garbage
+    Other,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Erased => "Erased",
+            Self::Recounted => "Recounted",
+            Self::Written => "Written",
+            Self::WentBad => "Went bad",
+            Self::Other => "Other",
+        })
+    }
+}
+
+/// One block whose content differs between two [Ebt]s
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlockChange {
+    pub block: u32,
+    pub before: BlockContent,
+    pub after: BlockContent,
+}
+
+impl BlockChange {
+    /// Classify the change
+    pub fn transition(&self) -> Transition {
+        use BlockContent::*;
+
+        match (self.before, self.after) {
+            (_, Bad) => Transition::WentBad,
+            (Erased | EcErased(_), Erased | EcErased(_)) => Transition::Recounted,
+            (_, Erased | EcErased(_)) => Transition::Erased,
+            (EcData(_, Some(old)), EcData(_, Some(new))) if old == new => Transition::Recounted,
+            (_, EcData(_, Some(_))) => Transition::Written,
+            _ => Transition::Other,
+        }
+    }
+}
+
+/// The blocks that differ between two [Ebt]s, as computed by [diff]
+///
+/// Displays as a tally of the [Transition]s; the alternate form (`{:#}`) lists every changed
+/// block before that.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct EbtDiff {
+    pub changes: Vec<BlockChange>,
+}
+
+impl EbtDiff {
+    /// How many blocks went through each kind of [Transition]
+    pub fn tally(&self) -> BTreeMap<Transition, u32> {
+        let mut tally = BTreeMap::new();
+        for change in &self.changes {
+            *tally.entry(change.transition()).or_default() += 1;
+        }
+
+        tally
+    }
+}
+
+/// Find the blocks whose content differs between two [Ebt]s of the same NAND
+pub fn diff(before: &Ebt, after: &Ebt) -> EbtDiff {
+    let changes = before
+        .iter()
+        .zip(after.iter())
+        .enumerate()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(n, (&before, &after))| BlockChange {
+            block: n as u32,
+            before,
+            after,
+        })
+        .collect();
+
+    EbtDiff { changes }
+}
+
+impl fmt::Display for EbtDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return writeln!(f, "No blocks changed");
+        }
+
+        if f.alternate() {
+            let before: Vec<String> = self.changes.iter().map(|x| x.before.to_string()).collect();
+            let width = before.iter().map(String::len).max().unwrap_or_default();
+            for (change, before) in self.changes.iter().zip(before) {
+                writeln!(
+                    f,
+                    "{:4} => {before:<width$} -> {}",
+                    change.block, change.after
+                )?;
+            }
+        }
+
+        for (transition, count) in self.tally() {
+            writeln!(f, "{:<10}{count:>6}", format!("{transition}:"))?;
+        }
+
+        Ok(())
+    }
 }
 
 #[test]
@@ -125,24 +1052,39 @@ fn test_scan() -> anyhow::Result<()> {
     // Confirm that, on a fresh NAND, every block scans as "erased"
     let blocks = scan_blocks(&mut nand)?;
     assert_eq!(blocks.len(), nand.get_layout().blocks as usize);
-    assert!(blocks.into_iter().all(|&x| x == BlockContent::Erased));
+    assert!(blocks.iter().all(|&x| x == BlockContent::Erased));
 
     // Now modify several blocks for various states:
     use BlockContent::*;
+    let ec = Ec {
+        vid_hdr_offset: 128,
+        data_offset: 256,
+        ..Default::default()
+    };
+    let foreign_ec = Ec {
+        vid_hdr_offset: 256,
+        data_offset: 512,
+        ..Default::default()
+    };
     let desired_content = [
         Bad,
         Erased,
-        EcErased(Default::default()),
-        EcData(Default::default(), None),
+        EcErased(ec),
+        EcData(ec, None),
         RawVid(Default::default()),
         Garbage,
-        EcErased(Default::default()),
+        EcErased(ec),
         Erased,
         Garbage,
-        EcData(Default::default(), Some(Default::default())),
+        EcData(ec, Some(Default::default())),
         Erased,
         Bad,
         RawVid(Default::default()),
+        EcData(foreign_ec, Some(Default::default())),
+        VidOnly(Vid {
+            lnum: 3,
+            ..Default::default()
+        }),
     ];
 
     let mut buf = vec![0; nand.get_layout().bytes_per_page];
@@ -160,7 +1102,7 @@ fn test_scan() -> anyhow::Result<()> {
                 block.program(0, &buf)?;
                 if let Some(vid) = vid {
                     vid.encode(&mut buf)?;
-                    block.program(1, &buf)?;
+                    block.program(ec.vid_hdr_page(buf.len())?, &buf)?;
                 }
                 buf.fill(0xAA);
                 block.program(i as u32, &buf)?;
@@ -169,6 +1111,14 @@ fn test_scan() -> anyhow::Result<()> {
                 vid.encode(&mut buf)?;
                 block.program(0, &buf)?;
             }
+            VidOnly(vid) => {
+                // An EC header with a bit flipped in it
+                ec.encode(&mut buf)?;
+                buf[20] ^= 0x10;
+                block.program(0, &buf)?;
+                vid.encode(&mut buf)?;
+                block.program(1, &buf)?;
+            }
             Garbage => {
                 buf.fill(0xAA);
                 block.program(i as u32, &buf)?;
@@ -176,9 +1126,571 @@ fn test_scan() -> anyhow::Result<()> {
         }
     }
 
+    // A VID header somewhere other than where the EC header claims must not be trusted
+    let mut block = nand.block(desired_content.len() as u32)?.unwrap();
+    ec.encode(&mut buf)?;
+    block.program(0, &buf)?;
+    Vid::default().encode(&mut buf)?;
+    block.program(2, &buf)?;
+    drop(block);
+
     // Now scan it again
     let blocks = scan_blocks(&mut nand)?;
     assert_eq!(blocks[..desired_content.len()], desired_content);
+    assert_eq!(blocks[desired_content.len()], EcData(ec, None));
 
     Ok(())
 }
+
+#[test]
+fn test_scan_read_failures() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 256,
+    };
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    super::format(&mut nand, &mut ebt)?;
+
+    // Blocks whose reads fail, once or every time, are left for erasing to decide on
+    nand.set_read_failures(3, 1)?;
+    nand.set_read_failures(9, u32::MAX)?;
+    let stats = nand.stats();
+    let (scanned, issues) = scan_blocks_with_issues(&mut nand)?;
+    assert_eq!(
+        issues,
+        ScanIssues {
+            flaky: vec![3],
+            unreadable: vec![9],
+        }
+    );
+    assert_eq!(issues.count(), 2);
+    assert_eq!(scanned[3], BlockContent::Garbage);
+    assert_eq!(scanned[9], BlockContent::Garbage);
+    for n in (0..16).filter(|x| ![3, 9].contains(x)) {
+        assert_eq!(scanned[n], ebt[n], "block {n}");
+    }
+
+    // ...without writing anything: the unreadable block isn't marked bad by the scan
+    let ops = nand.stats().since(&stats);
+    assert_eq!((ops.programs, ops.erases, ops.mark_bads), (0, 0, 0));
+    assert!(nand.block(9)?.is_some());
+
+    // Formatting erases it, which is what decides whether it's bad
+    let mut formatted = scanned.clone();
+    super::format(&mut nand.clone(), &mut formatted)?;
+    assert!(matches!(formatted[9], BlockContent::EcErased(_)));
+
+    // The flaky block reads again, and the parallel scan sees the same as the other
+    nand.set_read_failures(3, 1)?;
+    let (parallel, parallel_issues) = scan_blocks_parallel_with_issues(&mut nand, 4)?;
+    assert_eq!((parallel, parallel_issues), (scanned, issues));
+    assert_eq!(scan_blocks(&mut nand)?[3], ebt[3]);
+
+    // Once readable again, a rescan puts it right
+    nand.set_read_failures(9, 0)?;
+    let mut rescanned = scan_blocks(&mut nand)?;
+    assert_eq!(rescanned[9], ebt[9]);
+    rescanned[9] = BlockContent::Garbage;
+    rescan_range(&mut nand, &mut rescanned, 9..10)?;
+    assert_eq!(rescanned, ebt);
+
+    Ok(())
+}
+
+#[test]
+fn test_scan_ec_backup() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 256,
+    };
+
+    // Formatting backs every EC header up into the OOB area, where there's room
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.set_oob_size(32)?;
+    let mut ebt = scan_blocks(&mut nand)?;
+    super::format(&mut nand, &mut ebt)?;
+    let proto = ebt[0].ec().unwrap();
+    let mut oob = [0; EC_BACKUP_SIZE];
+    nand.block(7)?.unwrap().read_oob(0, &mut oob)?;
+    assert_eq!(Ec::decode_backup(&oob), Some(proto));
+
+    // Blocks whose EC headers have rotted, with and without a VID header after them, and one
+    // with no backup
+    let worn = proto.ec(57);
+    let vid = Vid {
+        lnum: 2,
+        ..Default::default()
+    };
+    let mut page = vec![0; TEST_LAYOUT.bytes_per_page];
+    let mut rotted = vec![0; TEST_LAYOUT.bytes_per_page];
+    worn.encode(&mut rotted)?;
+    rotted[9] ^= 0x20;
+    for index in [3, 4, 5] {
+        let mut block = nand.block(index)?.unwrap();
+        block.erase()?;
+        match index {
+            5 => block.program(0, &rotted)?,
+            _ => block.program_oob(0, &rotted, &worn.encode_backup())?,
+        }
+        if index == 3 {
+            vid.encode(&mut page)?;
+            block.program(1, &page)?;
+        }
+    }
+
+    // The erase counters are recovered, and the blocks still have to be erased
+    let scanned = scan_blocks(&mut nand)?;
+    assert_eq!(scanned[3], BlockContent::EcData(worn, Some(vid)));
+    assert_eq!(scanned[4], BlockContent::EcData(worn, None));
+    assert_eq!(scanned[5], BlockContent::Garbage);
+    let mut ebt = scanned;
+    super::format(&mut nand, &mut ebt)?;
+    assert_eq!(ebt[3], BlockContent::EcErased(worn.inc_ec()));
+    assert_eq!(ebt[4], BlockContent::EcErased(worn.inc_ec()));
+    assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+    // Without room for the backup, none is written, and a NAND that doesn't let its OOB area be
+    // used has none to read
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    nand.set_oob_size(EC_BACKUP_SIZE - 1)?;
+    let mut ebt = scan_blocks(&mut nand)?;
+    super::format(&mut nand, &mut ebt)?;
+    let mut short = [0; EC_BACKUP_SIZE - 1];
+    nand.block(0)?.unwrap().read_oob(0, &mut short)?;
+    assert!(short.is_erased());
+    nand.set_oob_size(0)?;
+    assert!(nand.block(0)?.unwrap().read_oob(0, &mut oob).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_scan_parallel() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 64,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+
+    // A simple xorshift PRNG, so the "random" NAND is the same on every run
+    let mut state = 0x2545F491u32;
+    let mut random = move |n: u32| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state % n
+    };
+
+    let mut nand = SimNand::new(TEST_LAYOUT);
+    let mut buf = vec![0; TEST_LAYOUT.bytes_per_page];
+    for i in 0..TEST_LAYOUT.blocks {
+        let mut block = nand.block(i)?.unwrap();
+        let ec = Ec {
+            ec: random(1000).into(),
+            vid_hdr_offset: 128,
+            data_offset: 256,
+            ..Default::default()
+        };
+        let vid = Vid {
+            lnum: random(100),
+            ..Default::default()
+        };
+
+        match random(6) {
+            0 => block.mark_bad()?,
+            1 => (),
+            2 => {
+                ec.encode(&mut buf)?;
+                block.program(0, &buf)?;
+            }
+            3 | 4 => {
+                ec.encode(&mut buf)?;
+                block.program(0, &buf)?;
+                vid.encode(&mut buf)?;
+                block.program(1, &buf)?;
+            }
+            _ => {
+                buf.fill(0xAA);
+                block.program(random(TEST_LAYOUT.pages_per_block), &buf)?;
+            }
+        }
+    }
+
+    let expected = scan_blocks(&mut nand)?;
+    for threads in [0, 1, 3, 8] {
+        assert_eq!(scan_blocks_parallel(&mut nand, threads)?, expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_scan_op_counts() -> anyhow::Result<()> {
+    use crate::fixtures::{dirty_nand, CANARY_LAYOUT};
+    use crate::nand::{CountingNand, OpCounts};
+
+    let mut nand = CountingNand::new(dirty_nand(CANARY_LAYOUT)?);
+    let ebt = scan_blocks(&mut nand)?;
+
+    // One read per block that starts with a UBI header or garbage; erased blocks are read through
+    // in 4-page chunks. Reading page-by-page, or past the first chunk, would show up here.
+    assert_eq!(
+        nand.counts.take(),
+        OpCounts {
+            reads: 150,
+            ..Default::default()
+        }
+    );
+
+    // A NAND preferring whole blocks reads those blocks with a UBI header no further than before,
+    // and the rest of an erased block in one more read, with the same result
+    let block_bytes = CANARY_LAYOUT.block_bytes();
+    let count = |f: fn(&BlockContent) -> bool| ebt.iter().filter(|x| f(x)).count();
+    let (erased, headers) = (
+        count(|x| *x == BlockContent::Erased),
+        count(|x| x.ec().is_some()),
+    );
+    nand.inner.set_preferred_io_size(Some(block_bytes * 2));
+    let before = nand.stats();
+    assert_eq!(scan_blocks(&mut nand)?, ebt);
+    assert_eq!(
+        nand.counts.take(),
+        OpCounts {
+            reads: 126 + erased as u32,
+            ..Default::default()
+        }
+    );
+    let most = (ebt.len() - headers) * block_bytes + headers * 4 * CANARY_LAYOUT.bytes_per_page;
+    assert!(nand.stats().since(&before).bytes_read <= most as u64);
+
+    // ...and asking for less than a page still reads a page at a time
+    nand.inner.set_preferred_io_size(Some(1));
+    assert_eq!(crate::nand::io_pages(&nand, PAGE_CHUNKS), 1);
+    assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+    Ok(())
+}
+
+#[test]
+fn test_header_cache() {
+    let ec = |ec| {
+        BlockContent::EcErased(Ec {
+            ec,
+            ..Default::default()
+        })
+    };
+    let mut cache = HeaderCache::new(2);
+    cache.insert(0, ec(1), &[0]);
+    cache.insert(1, ec(1), &[1]);
+    assert_eq!(cache.get(0, &ec(1)), Some(&[0][..]));
+
+    // Block 1 is the least recently used, so it makes way for block 2
+    cache.insert(2, ec(1), &[2]);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(1, &ec(1)), None);
+    assert_eq!(cache.get(2, &ec(1)), Some(&[2][..]));
+
+    // A block whose content has changed since is dropped
+    assert_eq!(cache.get(0, &ec(2)), None);
+    assert_eq!(cache.get(0, &ec(1)), None);
+    assert_eq!((cache.len(), cache.hits()), (1, 2));
+
+    // A cache with no room keeps nothing
+    let mut cache = HeaderCache::new(0);
+    cache.insert(0, ec(1), &[0]);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_verify_ebt_cached() -> anyhow::Result<()> {
+    use crate::nand::{NandLayout, SimNand};
+    use crate::ubi::{format, ubinize::BasicVolume, ubinize::Volume, write_volumes, VolType};
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 32,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+
+    // Install a volume over a formatted NAND, then verify it, returning the NAND, its Ebt, the
+    // blocks that failed to verify and how many reads verifying took
+    let mut formatted = SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut formatted)?;
+    format(&mut formatted, &mut ebt)?;
+    let data = vec![0x33; 8 * 14 * 128];
+    let install = |mut cache: Option<&mut HeaderCache>| -> anyhow::Result<_> {
+        let mut nand = formatted.clone();
+        let mut ebt = match cache.as_deref_mut() {
+            Some(cache) => scan_blocks_cached(&mut nand, cache)?.0,
+            None => scan_blocks(&mut nand)?,
+        };
+        format(&mut nand, &mut ebt)?;
+        let volumes: Vec<Box<dyn Volume>> = vec![Box::new(
+            BasicVolume::new(VolType::Static)
+                .name("rootfs")
+                .size(data.len() as u64)
+                .image(&data[..]),
+        )];
+        write_volumes(&mut nand, &mut ebt, volumes)?;
+
+        let reads = nand.stats().reads;
+        let mismatched = verify_ebt(&mut nand, &ebt, cache)?;
+        let reads = nand.stats().reads - reads;
+        Ok((nand, ebt, mismatched, reads))
+    };
+    let image = |nand: &mut SimNand| -> anyhow::Result<Vec<u8>> {
+        let mut image = vec![];
+        nand.save(&mut image)?;
+        Ok(image)
+    };
+
+    // The cache changes nothing but how much is read back
+    let (mut uncached, ebt, mismatched, uncached_reads) = install(None)?;
+    assert_eq!(mismatched, []);
+    let mut cache = HeaderCache::new(TEST_LAYOUT.blocks as usize);
+    let (mut cached, cached_ebt, mismatched, reads) = install(Some(&mut cache))?;
+    assert_eq!(cached_ebt, ebt);
+    assert_eq!(mismatched, []);
+    assert_eq!(image(&mut cached)?, image(&mut uncached)?);
+    assert!(cache.hits() > 0);
+    assert!(
+        reads + cache.hits() <= uncached_reads,
+        "{reads} reads, {uncached_reads} uncached"
+    );
+
+    // A cache too small for every block still saves the reads of those it holds
+    let mut cache = HeaderCache::new(8);
+    let (_, _, mismatched, reads) = install(Some(&mut cache))?;
+    assert_eq!(mismatched, []);
+    assert_eq!(cache.len(), 8);
+    assert!(
+        reads < uncached_reads,
+        "{reads} reads, {uncached_reads} uncached"
+    );
+
+    // Without a cache, a block changed behind the Ebt's back is noticed
+    let (changed, _) = (0..)
+        .zip(ebt.iter())
+        .find(|(_, x)| matches!(x, BlockContent::EcData(..)))
+        .unwrap();
+    uncached.block(changed)?.unwrap().erase()?;
+    assert_eq!(verify_ebt(&mut uncached, &ebt, None)?, [changed]);
+
+    Ok(())
+}
+
+#[test]
+fn test_ebt_diff() {
+    use BlockContent::*;
+
+    let ec = |ec| Ec {
+        ec,
+        vid_hdr_offset: 128,
+        data_offset: 256,
+        ..Default::default()
+    };
+    let vid = |lnum| Vid {
+        vol_id: 1,
+        lnum,
+        ..Default::default()
+    };
+
+    let before: Ebt = [
+        Garbage,
+        Erased,
+        EcErased(ec(3)),
+        EcData(ec(5), Some(vid(0))),
+        EcErased(ec(2)),
+        RawVid(vid(1)),
+        EcData(ec(1), Some(vid(2))),
+        EcData(ec(7), Some(vid(3))),
+        Bad,
+        EcErased(ec(4)),
+    ]
+    .into();
+    let after: Ebt = [
+        EcErased(ec(1)),
+        EcErased(ec(0)),
+        EcErased(ec(4)),
+        EcData(ec(6), Some(vid(0))),
+        EcData(ec(3), Some(vid(7))),
+        EcErased(ec(1)),
+        Bad,
+        Garbage,
+        Bad,
+        EcErased(ec(4)),
+    ]
+    .into();
+
+    let diff = diff(&before, &after);
+    let transitions: Vec<_> = diff
+        .changes
+        .iter()
+        .map(|x| (x.block, x.transition()))
+        .collect();
+    assert_eq!(
+        transitions,
+        [
+            (0, Transition::Erased),
+            (1, Transition::Recounted),
+            (2, Transition::Recounted),
+            (3, Transition::Recounted),
+            (4, Transition::Written),
+            (5, Transition::Erased),
+            (6, Transition::WentBad),
+            (7, Transition::Other),
+        ]
+    );
+    assert_eq!(
+        diff.tally().into_iter().collect::<Vec<_>>(),
+        [
+            (Transition::Erased, 2),
+            (Transition::Recounted, 3),
+            (Transition::Written, 1),
+            (Transition::WentBad, 1),
+            (Transition::Other, 1),
+        ]
+    );
+
+    let listing = format!("{diff:#}");
+    assert!(
+        listing.contains("   4 => EcErased(ec 2)              -> EcData(ec 3, vol 1, lnum 7)\n")
+    );
+    assert!(listing.ends_with("Other:         1\n"));
+    assert!(!format!("{diff}").contains("=>"));
+
+    let summary = summarize(&after);
+    assert_eq!(
+        (summary.erased, summary.ec_erased, summary.ec_data),
+        (0, 5, 2)
+    );
+    assert_eq!((summary.raw_vid, summary.garbage), (0, 1));
+    assert_eq!(summary.bad_blocks, [6, 8]);
+    assert_eq!(
+        summary.ec,
+        Some(EcStats {
+            count: 7,
+            min: 0,
+            mean: 19.0 / 7.0,
+            median: 3,
+            max: 6,
+            histogram: [1, 2, 0, 1, 2, 0, 1, 0],
+            bucket_width: 1,
+        })
+    );
+    assert!(summary.to_string().contains("Bad list: 6, 8\n"));
+    assert_eq!(summarize(&Ebt::from([Bad])).ec, None);
+}
+
+#[test]
+fn test_summarize_volumes() {
+    use BlockContent::*;
+
+    let ec = Ec::default();
+    let vid = |vol_id, lnum, sqnum, data_size| Vid {
+        vol_type: VolType::Static,
+        vol_id,
+        lnum,
+        sqnum,
+        data_size,
+        ..Default::default()
+    };
+    let record = |name: &str, vol_type, reserved_pebs| {
+        Some(VolTableRecord {
+            reserved_pebs,
+            vol_type,
+            name: name.into(),
+            ..Default::default()
+        })
+    };
+
+    let ebt: Ebt = vec![
+        EcData(ec, Some(vid(0, 0, 1, 100))),
+        EcData(ec, Some(vid(0, 1, 2, 50))),
+        // A stale copy of LEB 1, found after the newer one, and another of LEB 0, found before
+        EcData(ec, Some(vid(0, 1, 1, 70))),
+        EcData(ec, Some(vid(0, 0, 5, 100))),
+        EcData(ec, Some(vid(UBI_LAYOUT_VOLUME_ID, 0, 6, 0))),
+        // Dynamic volumes have no data_size to go by, and this one has more LEBs than reserved
+        EcData(
+            ec,
+            Some(Vid {
+                vol_type: VolType::Dynamic,
+                ..vid(1, 4, 3, 0)
+            }),
+        ),
+        EcData(
+            ec,
+            Some(Vid {
+                vol_type: VolType::Dynamic,
+                ..vid(1, 2, 4, 0)
+            }),
+        ),
+        // Volume 5 isn't in the table at all
+        EcData(ec, Some(vid(5, 0, 7, 10))),
+        EcErased(ec),
+        EcData(ec, Some(vid(5, 1, 8, 10))),
+        EcData(ec, None),
+        Bad,
+    ]
+    .into();
+    let table = [
+        record("rootfs", VolType::Static, 4),
+        record("env", VolType::Dynamic, 1),
+        None,
+        record("empty", VolType::Dynamic, 3),
+    ];
+
+    let summary = summarize_volumes(&ebt, &table);
+    assert_eq!(
+        summary.volumes,
+        [
+            VolumeUsage {
+                vol_id: 0,
+                name: "rootfs".into(),
+                vol_type: VolType::Static,
+                reserved_pebs: 4,
+                lebs: 2,
+                highest_lnum: Some(1),
+                data_bytes: 150,
+                stale_copies: 2,
+            },
+            VolumeUsage {
+                vol_id: 1,
+                name: "env".into(),
+                vol_type: VolType::Dynamic,
+                reserved_pebs: 1,
+                lebs: 2,
+                highest_lnum: Some(4),
+                data_bytes: 0,
+                stale_copies: 0,
+            },
+            VolumeUsage {
+                vol_id: 3,
+                name: "empty".into(),
+                vol_type: VolType::Dynamic,
+                reserved_pebs: 3,
+                lebs: 0,
+                highest_lnum: None,
+                data_bytes: 0,
+                stale_copies: 0,
+            },
+        ]
+    );
+    assert_eq!(summary.orphans, BTreeMap::from([(5, vec![7, 9])]));
+
+    let shown = summary.to_string();
+    assert!(shown.contains("2 stale copies"));
+    assert!(shown.contains("(more LEBs than reserved!)"));
+    assert!(shown.contains("Orphan volume 5, not in the table: blocks 7, 9"));
+}
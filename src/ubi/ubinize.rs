@@ -2,14 +2,23 @@
 //!
 //! The user specifies a series of volumes as any iterable (i.e. implementing IntoIterator) type.
 //! The code here will, when told the LEB size by the consumer, iterate over the volumes and
-//! yield `(Vid, Vec<u8>)` pairs for each LEB. This also takes care of synthesizing the layout
-//! volume.
-
-use super::headers::{OptionIntoBytes, Vid, VolTableRecord, VolType, UBI_CRC};
+//! yield a `Vid` for each LEB, filling in the consumer's buffer with the LEB's contents. This also
+//! takes care of synthesizing the layout volume.
+
+use super::headers::{
+    OptionIntoBytes, Vid, VolTableRecord, VolType, UBI_CRC, UBI_VTBL_AUTORESIZE_FLG,
+    UBI_VTBL_SKIP_CRC_CHECK_FLG,
+};
+use crate::nand::NandLayout;
 use crate::util::ReadExt;
 
-use std::io::Read;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::num::NonZeroU32;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 /// Represents a UBI volume to be written to flash or an image file
 pub trait Volume {
@@ -25,23 +34,35 @@ pub trait Volume {
     /// especially if there is a conflict.
     fn get_vol_id(&self) -> Option<u32>;
 
-    /// Estimate how many blocks this `Volume` will occupy at the given `eb_size`.
+    /// Get the name that this `Volume` will be given in the volume table, if any.
+    fn get_name(&self) -> Option<&str>;
+
+    /// Get the flags (e.g. [UBI_VTBL_AUTORESIZE_FLG]) that this `Volume` will be given in the
+    /// volume table.
+    fn get_flags(&self) -> u8;
+
+    /// Estimate how many blocks this `Volume` will occupy at the given `eb_size`, or
+    /// [UNKNOWN_BLOCKS] if there's no telling until its data has been read.
     ///
     /// This is an estimate only; its accuracy is not enforced.
     fn estimate_blocks(&self, eb_size: NonZeroU32) -> u32;
 }
 
+/// What [Volume::estimate_blocks] gives for a volume whose size can't be known in advance (e.g. one
+/// read from a stream, with no size given)
+pub const UNKNOWN_BLOCKS: u32 = u32::MAX;
+
 /// A provider of data for a single volume of an image
 pub trait VolumeData {
     /// Try to determine the next block that should be written as part of this volume.
     ///
-    /// The block's data will be *appended* to `data`, so space for headers may be pre-reserved by
-    /// the caller if desired.
+    /// The block's data is written to the start of `data`, which is exactly one LEB long; the rest
+    /// of `data` is left as it was.
     ///
-    /// On success, the result will be `Some(Vid)`, or `None` if there are no further blocks.
-    /// The `Vid` will not have the sqnum set to anything in particular; the caller must
-    /// override this.
-    fn next_block(&mut self, data: &mut Vec<u8>) -> anyhow::Result<Option<Vid>>;
+    /// On success, the result will be `Some((Vid, filled))`, where `filled` is how many bytes of
+    /// `data` the block takes up, or `None` if there are no further blocks. The `Vid` will not
+    /// have the sqnum set to anything in particular; the caller must override this.
+    fn next_block(&mut self, data: &mut [u8]) -> anyhow::Result<Option<(Vid, usize)>>;
 
     /// Generate a volume table record for this `VolumeData`.
     ///
@@ -50,13 +71,118 @@ pub trait VolumeData {
     fn into_vtbl_record(self: Box<Self>) -> VolTableRecord;
 }
 
-const UBI_LAYOUT_VOLUME_ID: u32 = 0x7FFFEFFF;
+pub const UBI_LAYOUT_VOLUME_ID: u32 = 0x7FFFEFFF;
+
+/// The volumes holding UBI's fastmap: its anchor block, and the blocks the anchor points to
+pub(super) const UBI_FM_SB_VOLUME_ID: u32 = 0x7FFFF000;
+pub(super) const UBI_FM_DATA_VOLUME_ID: u32 = 0x7FFFF001;
 const UBI_LAYOUT_VOLUME_TYPE: VolType = VolType::Dynamic;
-const UBI_LAYOUT_VOLUME_EBS: u32 = 2;
+pub(super) const UBI_LAYOUT_VOLUME_EBS: u32 = 2;
 const UBI_LAYOUT_VOLUME_COMPAT: u8 = 5u8;
 
-const UBI_VTBL_RECORD_SIZE: usize = 0xAC;
-const UBI_MAX_VOLUMES: usize = 128;
+pub(super) const UBI_VTBL_RECORD_SIZE: usize = 0xAC;
+pub const UBI_MAX_VOLUMES: usize = 128;
+
+/// The longest a volume name may be, in bytes
+pub const UBI_VOL_NAME_MAX: usize = 127;
+
+/// The size of an EC or VID header
+const UBI_HDR_SIZE: usize = 64;
+
+impl NandLayout {
+    /// Make sure UBI can be laid out on this NAND the way this crate lays it out: an EC header and
+    /// a VID header in a page each, and room after them for at least one volume table record, in
+    /// enough blocks for the layout volume and some data.
+    pub fn validate_for_ubi(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.pages_per_block >= 3,
+            "pages_per_block is {}, but UBI needs at least 3 (EC header, VID header and data)",
+            self.pages_per_block
+        );
+        anyhow::ensure!(
+            self.bytes_per_page >= UBI_HDR_SIZE,
+            "bytes_per_page is {}, too small for a {UBI_HDR_SIZE}-byte UBI header",
+            self.bytes_per_page
+        );
+        let leb_size = (self.pages_per_block as usize - 2) * self.bytes_per_page;
+        anyhow::ensure!(
+            leb_size >= UBI_VTBL_RECORD_SIZE,
+            "pages_per_block and bytes_per_page leave a LEB size of {leb_size} bytes, too small \
+             for a {UBI_VTBL_RECORD_SIZE}-byte volume table record"
+        );
+        let min_blocks = UBI_LAYOUT_VOLUME_EBS + 1;
+        anyhow::ensure!(
+            self.blocks >= min_blocks,
+            "blocks is {}, but UBI needs at least {min_blocks} (the layout volume, and data)",
+            self.blocks
+        );
+
+        Ok(())
+    }
+}
+
+/// Compute how many volume table records fit in the layout volume, for a given EB size
+pub(super) fn vtbl_record_count(eb_size: NonZeroU32) -> usize {
+    std::cmp::min(
+        (u32::from(eb_size) as usize) / UBI_VTBL_RECORD_SIZE,
+        UBI_MAX_VOLUMES,
+    )
+}
+
+/// Work out the ID of each of `volumes`, for a volume table with `record_count` records; see
+/// [Ubinizer::new]
+fn assign_ids(volumes: &[Box<dyn Volume + '_>], record_count: usize) -> anyhow::Result<Vec<u32>> {
+    let mut taken = vec![false; record_count];
+    for id in volumes.iter().filter_map(|x| x.get_vol_id()) {
+        anyhow::ensure!(
+            (id as usize) < UBI_MAX_VOLUMES,
+            "volume ID {id} is too large; UBI volume IDs go up to {}",
+            UBI_MAX_VOLUMES - 1
+        );
+        let slot = taken.get_mut(id as usize).ok_or(anyhow::anyhow!(
+            "volume ID {id} doesn't fit in a volume table of {record_count} records"
+        ))?;
+        anyhow::ensure!(!*slot, "volume ID {id} is given to more than one volume");
+        *slot = true;
+    }
+
+    let mut names = HashSet::new();
+    for name in volumes.iter().filter_map(|x| x.get_name()) {
+        anyhow::ensure!(
+            name.len() <= UBI_VOL_NAME_MAX,
+            "volume name {name:?} is longer than {UBI_VOL_NAME_MAX} bytes"
+        );
+        anyhow::ensure!(
+            names.insert(name),
+            "volume name {name:?} is given to more than one volume"
+        );
+    }
+
+    // UBI refuses to attach with more than one volume to grow
+    let autoresize: Vec<_> = volumes
+        .iter()
+        .filter(|x| x.get_flags() & UBI_VTBL_AUTORESIZE_FLG != 0)
+        .map(|x| x.get_name().unwrap_or_default())
+        .collect();
+    anyhow::ensure!(
+        autoresize.len() <= 1,
+        "only one volume may be set to autoresize, not {autoresize:?}"
+    );
+
+    let mut free = (0..record_count as u32).filter(|&id| !taken[id as usize]);
+    volumes
+        .iter()
+        .map(|volume| {
+            volume
+                .get_vol_id()
+                .or_else(|| free.next())
+                .ok_or(anyhow::anyhow!(
+                    "{} volumes don't fit in a volume table of {record_count} records",
+                    volumes.len()
+                ))
+        })
+        .collect()
+}
 
 /// An internal volume, describing the layout of volumes on flash.
 struct LayoutVolume {
@@ -64,30 +190,18 @@ struct LayoutVolume {
 }
 
 impl LayoutVolume {
-    /// Begin building a new layout volume. The EB size must be known ahead of time.
-    fn new(eb_size: NonZeroU32) -> Self {
-        let eb_size: u32 = eb_size.into();
-        let record_count =
-            std::cmp::min((eb_size as usize) / UBI_VTBL_RECORD_SIZE, UBI_MAX_VOLUMES);
-
+    /// Begin building a new layout volume. The EB size must be known ahead of time, and must have
+    /// room for at least one record.
+    fn new(eb_size: NonZeroU32) -> anyhow::Result<Self> {
+        let record_count = vtbl_record_count(eb_size);
+        anyhow::ensure!(
+            record_count > 0,
+            "EB size of {eb_size} bytes is too small for a {UBI_VTBL_RECORD_SIZE}-byte volume \
+             table record"
+        );
         let records = vec![Default::default(); record_count];
 
-        Self { records }
-    }
-
-    /// Attempt to allocate some unused volume ID, from the (still-available) record slots
-    ///
-    /// The ID is not considered unavailable until [store_record] is called
-    fn allocate_id(&self) -> Option<u32> {
-        self.records
-            .iter()
-            .position(|x| x.is_none())
-            .map(|x| x as u32)
-    }
-
-    /// Confirm that a volume ID is available
-    fn is_id_available(&self, id: u32) -> bool {
-        self.records.get(id as usize) == Some(&None)
+        Ok(Self { records })
     }
 
     /// Store a volume table record
@@ -128,6 +242,14 @@ impl Volume for LayoutVolume {
         Some(UBI_LAYOUT_VOLUME_ID)
     }
 
+    fn get_name(&self) -> Option<&str> {
+        None
+    }
+
+    fn get_flags(&self) -> u8 {
+        0
+    }
+
     fn estimate_blocks(&self, _: NonZeroU32) -> u32 {
         UBI_LAYOUT_VOLUME_EBS
     }
@@ -139,7 +261,7 @@ struct LayoutVolumeData {
 }
 
 impl VolumeData for LayoutVolumeData {
-    fn next_block(&mut self, data: &mut Vec<u8>) -> anyhow::Result<Option<Vid>> {
+    fn next_block(&mut self, data: &mut [u8]) -> anyhow::Result<Option<(Vid, usize)>> {
         if self.vid.lnum >= UBI_LAYOUT_VOLUME_EBS {
             return Ok(None);
         }
@@ -147,8 +269,8 @@ impl VolumeData for LayoutVolumeData {
         let vid = self.vid;
         self.vid.lnum += 1;
 
-        data.extend(&self.data);
-        Ok(Some(vid))
+        data[..self.data.len()].copy_from_slice(&self.data);
+        Ok(Some((vid, self.data.len())))
     }
 
     fn into_vtbl_record(self: Box<Self>) -> VolTableRecord {
@@ -158,7 +280,7 @@ impl VolumeData for LayoutVolumeData {
 
 /// A non-internal volume, the contents of which come from an image or are initially blank
 pub struct BasicVolume<'a> {
-    image: Option<&'a mut dyn Read>,
+    image: Option<Box<dyn Read + 'a>>,
     vtype: VolType,
     id: Option<u32>,
     size: Option<u64>,
@@ -191,8 +313,8 @@ impl<'a> BasicVolume<'a> {
     }
 
     /// Change the source of the volume's contents.
-    pub fn image(mut self, image: &'a mut dyn Read) -> Self {
-        self.image = Some(image);
+    pub fn image<R: Read + 'a>(mut self, image: R) -> Self {
+        self.image = Some(Box::new(image));
         self
     }
 
@@ -225,20 +347,22 @@ impl<'a> BasicVolume<'a> {
 
     /// Set the UBI "autoresize" flag.
     pub fn autoresize(mut self) -> Self {
-        self.flags |= 0x01;
+        self.flags |= UBI_VTBL_AUTORESIZE_FLG;
         self
     }
 
     /// Set the UBI "skip CRC check" flag.
     pub fn skipcheck(mut self) -> Self {
-        self.flags |= 0x02;
+        self.flags |= UBI_VTBL_SKIP_CRC_CHECK_FLG;
         self
     }
 
     /// Set the alignment of the volume. All LEBs will be a multiple of this size, and will
-    /// therefore begin at offsets that are multiples of this size.
+    /// therefore begin at offsets that are multiples of this size. The EB size left over is
+    /// recorded as `data_pad`, in the volume table and every VID header.
     ///
-    /// The default alignment is 1.
+    /// The default alignment is 1. An alignment larger than the EB size leaves no room for data,
+    /// and fails the write.
     pub fn align(mut self, alignment: NonZeroU32) -> Self {
         self.alignment = alignment;
         self
@@ -260,7 +384,7 @@ impl Volume for BasicVolume<'_> {
         // Compute this volume's layout, now that eb_size is known:
         let used_ebs = match self.vtype {
             VolType::Dynamic => 0,
-            VolType::Static => self.estimate_blocks(eb_size), // Guaranteed correct for `Static`
+            VolType::Static => self.size_blocks(eb_size), // Guaranteed correct for `Static`
         };
         let data_pad = u32::from(eb_size) % self.alignment;
         let leb_size = u32::from(eb_size) - data_pad;
@@ -275,7 +399,7 @@ impl Volume for BasicVolume<'_> {
         };
 
         let record = VolTableRecord {
-            reserved_pebs: self.estimate_blocks(eb_size),
+            reserved_pebs: self.size_blocks(eb_size),
             alignment: self.alignment.into(),
             data_pad,
             vol_type: self.vtype,
@@ -302,31 +426,59 @@ impl Volume for BasicVolume<'_> {
         self.id
     }
 
+    fn get_name(&self) -> Option<&str> {
+        Some(self.name.as_str()).filter(|x| !x.is_empty())
+    }
+
+    fn get_flags(&self) -> u8 {
+        self.flags
+    }
+
     fn estimate_blocks(&self, eb_size: NonZeroU32) -> u32 {
+        // Without an image, nothing is written, whatever the size; with one, there's no knowing
+        // how long it is without a size
+        match (&self.image, self.size) {
+            (None, _) => 0,
+            (Some(_), None) => UNKNOWN_BLOCKS,
+            (Some(_), Some(_)) => self.size_blocks(eb_size),
+        }
+    }
+}
+
+impl BasicVolume<'_> {
+    /// How many LEBs of the given `eb_size` the volume's size takes up, or 0 if it has none
+    fn size_blocks(&self, eb_size: NonZeroU32) -> u32 {
         let eb_size: u32 = eb_size.into();
         let data_pad = eb_size % self.alignment;
         let leb_size = eb_size - data_pad;
-        ((self.size.unwrap_or(0) + (leb_size - 1) as u64) / leb_size as u64) as u32
+        if leb_size == 0 {
+            return 0; // Can't be written at all, as `next_block` will say
+        }
+        self.size.unwrap_or(0).div_ceil(leb_size.into()) as u32
     }
 }
 
 struct BasicVolumeData<'a> {
-    image: Option<std::io::Take<&'a mut dyn Read>>,
+    image: Option<io::Take<Box<dyn Read + 'a>>>,
     leb_size: u32,
     vid: Vid,
     record: VolTableRecord,
 }
 
 impl VolumeData for BasicVolumeData<'_> {
-    fn next_block(&mut self, data: &mut Vec<u8>) -> anyhow::Result<Option<Vid>> {
+    fn next_block(&mut self, data: &mut [u8]) -> anyhow::Result<Option<(Vid, usize)>> {
+        anyhow::ensure!(
+            self.leb_size > 0,
+            "volume alignment {} is larger than the EB size",
+            self.record.alignment
+        );
         let image = match &mut self.image {
             Some(image) => image,
             None => return Ok(None),
         };
 
-        let data_len = data.len();
-        image.read_to_vec(data, self.leb_size as usize)?;
-        let new_data = &data[data_len..];
+        let filled = image.read_fill(&mut data[..self.leb_size as usize])?;
+        let new_data = &data[..filled];
 
         if new_data.is_empty() {
             return Ok(None);
@@ -340,7 +492,7 @@ impl VolumeData for BasicVolumeData<'_> {
             vid.data_crc = UBI_CRC.checksum(new_data);
         }
 
-        Ok(Some(vid))
+        Ok(Some((vid, filled)))
     }
 
     fn into_vtbl_record(self: Box<Self>) -> VolTableRecord {
@@ -352,10 +504,364 @@ impl VolumeData for BasicVolumeData<'_> {
     }
 }
 
+/// A source of data that can also seek
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+/// Opens an image again from the start, e.g. by decompressing it again, for
+/// [DuplicateImage::reopened]
+pub type ReopenImage<'a> = Box<dyn FnMut() -> io::Result<Box<dyn Read + 'a>> + 'a>;
+
+/// One image, to be the contents of more than one volume (e.g. both halves of an A/B pair) without
+/// having to be supplied more than once.
+///
+/// Each volume gets a [copy](DuplicateImage::copy) as its image. The copies must be read in the
+/// order they were made, one after another, as [Ubinizer] does with the volumes; the first reads
+/// the source, and the others read it again from where the first started.
+pub struct DuplicateImage<'a> {
+    shared: Rc<RefCell<DuplicateSource<'a>>>,
+}
+
+enum DuplicateSource<'a> {
+    /// A source that can be read again by seeking back to `start`
+    Seekable {
+        image: &'a mut dyn ReadSeek,
+        start: u64,
+        copies: usize,
+    },
+
+    /// A source that can be opened again, for each copy after the first to read afresh
+    Reopened {
+        image: &'a mut dyn Read,
+        reopen: ReopenImage<'a>,
+        again: Option<Box<dyn Read + 'a>>,
+        copies: usize,
+    },
+
+    /// A source that can't, so that the first copy spills what it reads into a temporary file,
+    /// for the others to replay
+    Spilled {
+        image: &'a mut dyn Read,
+        spill: Option<File>,
+        copies: usize,
+    },
+}
+
+impl<'a> DuplicateImage<'a> {
+    /// Duplicate an image that can't seek, e.g. one being decompressed.
+    ///
+    /// The first copy spills the whole image into a file in [std::env::temp_dir] as it goes, so
+    /// there must be room there for it.
+    pub fn new(image: &'a mut dyn Read) -> Self {
+        Self::from_source(DuplicateSource::Spilled {
+            image,
+            spill: None,
+            copies: 0,
+        })
+    }
+
+    /// Duplicate an image that can seek, which is read again for each copy after the first,
+    /// starting where it is now
+    pub fn seekable(image: &'a mut dyn ReadSeek) -> io::Result<Self> {
+        let start = image.stream_position()?;
+        Ok(Self::from_source(DuplicateSource::Seekable {
+            image,
+            start,
+            copies: 0,
+        }))
+    }
+
+    /// Duplicate an image that can't seek, but can be opened again from the start with `reopen`
+    /// (e.g. a decompressed stream, with the compressed file being seekable), which is done for
+    /// each copy after the first. Nothing is spilled.
+    pub fn reopened(image: &'a mut dyn Read, reopen: ReopenImage<'a>) -> Self {
+        Self::from_source(DuplicateSource::Reopened {
+            image,
+            reopen,
+            again: None,
+            copies: 0,
+        })
+    }
+
+    fn from_source(source: DuplicateSource<'a>) -> Self {
+        Self {
+            shared: Rc::new(RefCell::new(source)),
+        }
+    }
+
+    /// Make another copy of the image, to give to [BasicVolume::image]
+    pub fn copy(&self) -> ImageCopy<'a> {
+        let (DuplicateSource::Seekable { copies, .. }
+        | DuplicateSource::Reopened { copies, .. }
+        | DuplicateSource::Spilled { copies, .. }) = &mut *self.shared.borrow_mut();
+        *copies += 1;
+        ImageCopy {
+            shared: self.shared.clone(),
+            first: *copies == 1,
+            started: false,
+        }
+    }
+}
+
+/// One copy of a [DuplicateImage]
+pub struct ImageCopy<'a> {
+    shared: Rc<RefCell<DuplicateSource<'a>>>,
+    first: bool,
+    started: bool,
+}
+
+impl Read for ImageCopy<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = std::mem::replace(&mut self.started, true);
+        match (&mut *self.shared.borrow_mut(), self.first) {
+            (DuplicateSource::Seekable { image, .. }, true) => image.read(buf),
+            (DuplicateSource::Seekable { image, start, .. }, false) => {
+                if !started {
+                    image.seek(SeekFrom::Start(*start))?;
+                }
+                image.read(buf)
+            }
+            (DuplicateSource::Reopened { image, .. }, true) => image.read(buf),
+            (DuplicateSource::Reopened { reopen, again, .. }, false) => {
+                let again = match again {
+                    Some(again) if started => again,
+                    _ => again.insert(reopen()?),
+                };
+                again.read(buf)
+            }
+            (DuplicateSource::Spilled { image, spill, .. }, true) => {
+                let spill = match spill {
+                    Some(spill) => spill,
+                    None => spill.insert(spill_file()?),
+                };
+                let n = image.read(buf)?;
+                spill.write_all(&buf[..n])?;
+                Ok(n)
+            }
+            (DuplicateSource::Spilled { spill, .. }, false) => {
+                // Nothing spilled means the first copy read nothing, so there's nothing to replay
+                let Some(spill) = spill else {
+                    return Ok(0);
+                };
+                if !started {
+                    spill.seek(SeekFrom::Start(0))?;
+                }
+                spill.read(buf)
+            }
+        }
+    }
+}
+
+/// Create a file to spill an image into. It's removed again straight away, so that it's gone
+/// however the installer exits; it lasts only as long as it's open.
+fn spill_file() -> io::Result<File> {
+    static SPILLS: AtomicU32 = AtomicU32::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "bmc-installer-spill-{}-{}",
+        std::process::id(),
+        SPILLS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// A non-internal volume, the contents of which are given LEB-by-LEB, e.g. from a dump of a volume
+/// on another device.
+///
+/// Unlike [BasicVolume], LEBs of a dynamic volume may be skipped: any `lnum` not provided is simply
+/// left unmapped. A static volume has to be given all of its LEBs, from 0 on, as UBI only reads one
+/// back whole.
+pub struct LebStreamVolume<'a> {
+    lebs: Box<dyn ExactSizeIterator<Item = (u32, Vec<u8>)> + 'a>,
+    vtype: VolType,
+    id: Option<u32>,
+    reserved_lebs: Option<u32>,
+    name: String,
+    flags: u8,
+}
+
+impl<'a> LebStreamVolume<'a> {
+    /// Begin creating a new `LebStreamVolume`, of a given type, from `(lnum, data)` pairs.
+    ///
+    /// The `lnum`s must be strictly increasing (and, for a static volume, contiguous from 0), and
+    /// no LEB may hold more than the LEB size.
+    pub fn new<I>(vtype: VolType, lebs: I) -> Self
+    where
+        I: IntoIterator<Item = (u32, Vec<u8>)>,
+        I::IntoIter: ExactSizeIterator + 'a,
+    {
+        Self {
+            lebs: Box::new(lebs.into_iter()),
+            vtype,
+            id: None,
+            reserved_lebs: None,
+            name: Default::default(),
+            flags: Default::default(),
+        }
+    }
+
+    /// Change the ID assigned to the volume from a default of auto-assigned.
+    ///
+    /// Note that this may be ignored if the ID is invalid.
+    pub fn id(mut self, id: u32) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Set the number of LEBs reserved for the volume.
+    ///
+    /// The default is just enough to hold the highest `lnum` provided.
+    pub fn reserve(mut self, lebs: u32) -> Self {
+        self.reserved_lebs = Some(lebs);
+        self
+    }
+
+    /// Set the name of the volume.
+    ///
+    /// The default is `""`
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set the UBI "skip CRC check" flag.
+    pub fn skipcheck(mut self) -> Self {
+        self.flags |= UBI_VTBL_SKIP_CRC_CHECK_FLG;
+        self
+    }
+}
+
+impl Volume for LebStreamVolume<'_> {
+    fn into_data<'a>(self: Box<Self>, eb_size: NonZeroU32, vol_id: u32) -> Box<dyn VolumeData + 'a>
+    where
+        Self: 'a,
+    {
+        let used_ebs = match self.vtype {
+            VolType::Dynamic => 0,
+            VolType::Static => self.estimate_blocks(eb_size),
+        };
+
+        let vid = Vid {
+            vol_type: self.vtype,
+            copy_flag: false,
+            vol_id,
+            used_ebs,
+            ..Default::default()
+        };
+
+        let record = VolTableRecord {
+            reserved_pebs: self.reserved_lebs.unwrap_or(0),
+            alignment: 1,
+            data_pad: 0,
+            vol_type: self.vtype,
+            upd_marker: false,
+            name: self.name,
+            flags: self.flags,
+        };
+
+        Box::new(LebStreamVolumeData {
+            lebs: self.lebs,
+            leb_size: eb_size.into(),
+            last_lnum: None,
+            vid,
+            record,
+        })
+    }
+
+    fn get_vol_id(&self) -> Option<u32> {
+        self.id
+    }
+
+    fn get_name(&self) -> Option<&str> {
+        Some(self.name.as_str()).filter(|x| !x.is_empty())
+    }
+
+    fn get_flags(&self) -> u8 {
+        self.flags
+    }
+
+    fn estimate_blocks(&self, _: NonZeroU32) -> u32 {
+        self.lebs.len() as u32
+    }
+}
+
+struct LebStreamVolumeData<'a> {
+    lebs: Box<dyn ExactSizeIterator<Item = (u32, Vec<u8>)> + 'a>,
+    leb_size: u32,
+    last_lnum: Option<u32>,
+    vid: Vid,
+    record: VolTableRecord,
+}
+
+impl VolumeData for LebStreamVolumeData<'_> {
+    fn next_block(&mut self, data: &mut [u8]) -> anyhow::Result<Option<(Vid, usize)>> {
+        let (lnum, leb) = match self.lebs.next() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        anyhow::ensure!(
+            !matches!(self.last_lnum, Some(last) if lnum <= last),
+            "LEB {lnum} of volume {} out of order",
+            self.vid.vol_id
+        );
+        anyhow::ensure!(
+            self.vid.vol_type != VolType::Static || lnum == self.last_lnum.map_or(0, |x| x + 1),
+            "LEB {lnum} of static volume {} leaves a gap",
+            self.vid.vol_id
+        );
+        anyhow::ensure!(
+            leb.len() <= self.leb_size as usize,
+            "LEB {lnum} of volume {} is larger than the LEB size",
+            self.vid.vol_id
+        );
+        self.last_lnum = Some(lnum);
+
+        let mut vid = self.vid;
+        vid.lnum = lnum;
+
+        if vid.vol_type == VolType::Static {
+            vid.data_size = leb.len() as u32;
+            vid.data_crc = UBI_CRC.checksum(&leb);
+        }
+
+        data[..leb.len()].copy_from_slice(&leb);
+        Ok(Some((vid, leb.len())))
+    }
+
+    fn into_vtbl_record(self: Box<Self>) -> VolTableRecord {
+        let mut record = self.record;
+        if record.reserved_pebs == 0 {
+            record.reserved_pebs = self.last_lnum.map_or(0, |x| x + 1);
+        }
+        record
+    }
+}
+
+/// One of the volumes a [Ubinizer] yields the blocks of, as [Ubinizer::plan] tells it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PlannedVolume {
+    /// The ID the volume has been given
+    pub vol_id: u32,
+
+    /// The volume's name, if it has one (the layout volume doesn't)
+    pub name: Option<String>,
+
+    /// How many blocks the volume is expected to take, as [Volume::estimate_blocks] says, or None
+    /// if it can't say
+    pub blocks: Option<u32>,
+}
+
 /// Given a sequence of volumes, and the EB size (i.e. PEB size minus EC/VID HDR pages), allows
 /// iterating over the individual PEBs that must be written in order to image the flash.
-pub struct Ubinizer<'a, I> {
-    volumes: I,
+pub struct Ubinizer<'a> {
+    /// The volumes still to come, each with the ID it's been given
+    volumes: std::vec::IntoIter<(u32, Box<dyn Volume + 'a>)>,
     eb_size: NonZeroU32,
     layout: Option<Box<LayoutVolume>>,
     sqnum: u64,
@@ -363,8 +869,9 @@ pub struct Ubinizer<'a, I> {
     current_data: Option<Box<dyn VolumeData + 'a>>,
 }
 
-impl<'a> Ubinizer<'a, ()> {
-    /// Estimate how many blocks the [Ubinizer] will yield, for a given [Volume] collection.
+impl<'a> Ubinizer<'a> {
+    /// Estimate how many blocks the [Ubinizer] will yield, for a given [Volume] collection, or
+    /// [UNKNOWN_BLOCKS] if any of the volumes can't say.
     pub fn estimate_blocks<'x, V>(volumes: V, eb_size: NonZeroU32) -> u32
     where
         V: IntoIterator<Item = &'x dyn Volume> + 'x,
@@ -373,23 +880,53 @@ impl<'a> Ubinizer<'a, ()> {
             .into_iter()
             .map(|x| x.estimate_blocks(eb_size))
             .chain(std::iter::once(UBI_LAYOUT_VOLUME_EBS))
-            .sum()
+            .try_fold(0u32, |sum, x| (x != UNKNOWN_BLOCKS).then(|| sum + x))
+            .unwrap_or(UNKNOWN_BLOCKS)
+    }
+
+    /// List the volumes still to come, in the order their blocks will be yielded (the layout
+    /// volume last), with the IDs they've been given and the blocks they're expected to take.
+    ///
+    /// Before the first [Ubinizer::next_block], that's all of them.
+    pub fn plan(&self) -> Vec<PlannedVolume> {
+        let volumes = self.volumes.as_slice().iter().map(|(id, x)| (*id, &**x));
+        let layout = self
+            .layout
+            .as_deref()
+            .map(|x| (UBI_LAYOUT_VOLUME_ID, x as &dyn Volume));
+        volumes
+            .chain(layout)
+            .map(|(vol_id, volume)| PlannedVolume {
+                vol_id,
+                name: volume.get_name().map(String::from),
+                blocks: Some(volume.estimate_blocks(self.eb_size)).filter(|&x| x != UNKNOWN_BLOCKS),
+            })
+            .collect()
     }
-}
 
-impl<'a, I: Iterator<Item = Box<dyn Volume + 'a>>> Ubinizer<'a, I> {
     /// Create a new [Ubinizer], which will build an image with the given volumes that fits in
     /// flash with a given EB size.
-    pub fn new<V: IntoIterator<IntoIter = I>>(volumes: V, eb_size: NonZeroU32) -> Self {
-        let volumes = volumes.into_iter();
-        Self {
-            volumes,
+    ///
+    /// Each volume gets the ID it asks for; the rest get the lowest IDs left over, in the order
+    /// they come, so the same volumes always make the same image. Fails if the EB size is too
+    /// small to hold the volume table, or the volumes can't all go in it: two asking for the same
+    /// ID or name, an ID or name too large for the table, or more volumes than it has room for.
+    pub fn new<V>(volumes: V, eb_size: NonZeroU32) -> anyhow::Result<Self>
+    where
+        V: IntoIterator<Item = Box<dyn Volume + 'a>>,
+    {
+        let layout = LayoutVolume::new(eb_size)?;
+        let volumes: Vec<_> = volumes.into_iter().collect();
+        let ids = assign_ids(&volumes, layout.records.len())?;
+
+        Ok(Self {
+            volumes: ids.into_iter().zip(volumes).collect::<Vec<_>>().into_iter(),
             eb_size,
-            layout: Some(Box::new(LayoutVolume::new(eb_size))),
+            layout: Some(Box::new(layout)),
             sqnum: 0,
             current_id: 0,
             current_data: None,
-        }
+        })
     }
 
     /// Pull the next volume from `self.volumes`, turn it into [VolumeData], and put it in
@@ -397,34 +934,37 @@ impl<'a, I: Iterator<Item = Box<dyn Volume + 'a>>> Ubinizer<'a, I> {
     ///
     /// This is an internal function.
     fn next_volume(&mut self) {
-        let volume = match self
-            .volumes
-            .next()
-            .or_else(|| self.layout.take().map(|x| x as _)) // `self.volumes` exhausted
-                                                            // => take layout volume
-        {
-            None => { self.current_data = None; return } // End of all volumes
+        let (vol_id, volume) = match self.volumes.next().or_else(|| {
+            // `self.volumes` exhausted => take layout volume
+            self.layout
+                .take()
+                .map(|x| (UBI_LAYOUT_VOLUME_ID, x as Box<dyn Volume>))
+        }) {
+            None => {
+                // End of all volumes
+                self.current_data = None;
+                return;
+            }
             Some(x) => x,
         };
 
-        // Allocate a volume ID
-        let mut vol_id = volume.get_vol_id();
-        if let Some(ref layout) = self.layout {
-            vol_id = vol_id
-                .filter(|&id| layout.is_id_available(id))
-                .or_else(|| layout.allocate_id());
-        }
-        self.current_id = vol_id.expect("failed to allocate volume ID");
-
-        let boxed_data = volume.into_data(self.eb_size, self.current_id);
-        self.current_data = Some(boxed_data);
+        self.current_id = vol_id;
+        self.current_data = Some(volume.into_data(self.eb_size, vol_id));
     }
 
-    /// Yield the next block of the image, or None if this is the end of the image.
+    /// Yield the next block of the image, with how many bytes of its data were written to the
+    /// start of `data`, or None if this is the end of the image.
     ///
-    /// The block's data will be *appended* to `data`, so space for headers may be pre-reserved by
-    /// the caller if desired.
-    pub fn next_block(&mut self, data: &mut Vec<u8>) -> anyhow::Result<Option<Vid>> {
+    /// `data` must be exactly one LEB long, so that the caller can reuse one buffer (with room for
+    /// headers around it) for every block.
+    pub fn next_block(&mut self, data: &mut [u8]) -> anyhow::Result<Option<(Vid, usize)>> {
+        anyhow::ensure!(
+            data.len() == u32::from(self.eb_size) as usize,
+            "LEB buffer is {} bytes, but the LEB size is {}",
+            data.len(),
+            self.eb_size
+        );
+
         loop {
             if self.current_data.is_none() {
                 self.next_volume();
@@ -436,10 +976,10 @@ impl<'a, I: Iterator<Item = Box<dyn Volume + 'a>>> Ubinizer<'a, I> {
             };
 
             // As long as `current_data` is providing blocks, just keep consuming it:
-            if let Some(vid) = current_data.next_block(data)? {
+            if let Some((vid, filled)) = current_data.next_block(data)? {
                 assert_eq!(vid.vol_id, self.current_id);
                 self.sqnum += 1;
-                return Ok(Some(vid.sqnum(self.sqnum)));
+                return Ok(Some((vid.sqnum(self.sqnum), filled)));
             }
 
             // Upon getting here, the `current_data` is empty; need to cycle it; drop the reference
@@ -448,7 +988,12 @@ impl<'a, I: Iterator<Item = Box<dyn Volume + 'a>>> Ubinizer<'a, I> {
 
             // If we still have the layout volume, tell it about the vtbl record.
             if let Some(ref mut layout) = self.layout {
-                let record = current_data.into_vtbl_record();
+                let mut record = current_data.into_vtbl_record();
+
+                // A record reserving no PEBs is taken for an empty one, and UBI won't attach if
+                // anything else is in it; an empty volume (to be grown by autoresize, say) still
+                // has to reserve a PEB
+                record.reserved_pebs = record.reserved_pebs.max(1);
                 layout.store_record(self.current_id, record);
             }
 
@@ -467,9 +1012,10 @@ fn test_basic_volume() -> anyhow::Result<()> {
     );
     let mut d = x.into_data(1024.try_into().unwrap(), 7);
 
-    let mut data = Vec::with_capacity(1024);
+    let mut data = vec![0; 1024];
     for i in 0..4 {
-        let vid = d.next_block(&mut data)?.unwrap();
+        data.fill(0);
+        let (vid, filled) = d.next_block(&mut data)?.unwrap();
 
         assert_eq!(
             vid,
@@ -487,7 +1033,7 @@ fn test_basic_volume() -> anyhow::Result<()> {
             }
         );
 
-        assert_eq!(data.len(), 1024 * (i + 1) as usize);
+        assert_eq!(filled, 1024);
         assert!(data.iter().all(|&b| b == 0x11));
     }
     assert_eq!(d.next_block(&mut data)?, None);
@@ -505,3 +1051,359 @@ fn test_basic_volume() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_leb_stream_volume() -> anyhow::Result<()> {
+    let lebs = vec![(0, vec![0x22; 100]), (1, vec![0x33; 1024]), (3, vec![])];
+    let x = Box::new(LebStreamVolume::new(VolType::Static, lebs));
+    assert_eq!(x.estimate_blocks(1024.try_into().unwrap()), 3);
+    let mut d = x.into_data(1024.try_into().unwrap(), 2);
+
+    let mut data = vec![0; 1024];
+    let (vid, filled) = d.next_block(&mut data)?.unwrap();
+    assert_eq!((vid.lnum, vid.used_ebs, vid.data_size), (0, 3, 100));
+    assert_eq!(filled, 100);
+    assert!(data[..100].iter().all(|&b| b == 0x22) && data[100..].iter().all(|&b| b == 0));
+    let (vid, filled) = d.next_block(&mut data)?.unwrap();
+    assert_eq!((vid.lnum, vid.used_ebs, vid.data_size), (1, 3, 1024));
+    assert_eq!(filled, 1024);
+
+    // A static volume can't skip LEBs, not even the first
+    assert!(d.next_block(&mut data).is_err());
+    let lebs = vec![(1, vec![0x22; 100])];
+    let mut d = Box::new(LebStreamVolume::new(VolType::Static, lebs))
+        .into_data(1024.try_into().unwrap(), 2);
+    assert!(d.next_block(&mut data).is_err());
+
+    // A dynamic one can, but its LEBs must still come in order
+    let lebs = vec![(1, vec![0x22; 100]), (4, vec![0x33; 1024]), (3, vec![])];
+    let mut d = Box::new(LebStreamVolume::new(VolType::Dynamic, lebs))
+        .into_data(1024.try_into().unwrap(), 2);
+    assert_eq!(d.next_block(&mut data)?.unwrap().0.lnum, 1);
+    assert_eq!(d.next_block(&mut data)?.unwrap().0.lnum, 4);
+    assert!(d.next_block(&mut data).is_err());
+
+    // Oversized LEBs are rejected
+    let lebs = vec![(0, vec![0x44; 1025])];
+    let mut d = Box::new(LebStreamVolume::new(VolType::Dynamic, lebs))
+        .into_data(1024.try_into().unwrap(), 2);
+    assert!(d.next_block(&mut [0; 1024]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_ubinizer_ids() -> anyhow::Result<()> {
+    // Room for 5 volume table records
+    let eb_size = NonZeroU32::new(1024).unwrap();
+    let volume = |id: Option<u32>, name: &str| -> Box<dyn Volume> {
+        let volume = BasicVolume::new(VolType::Dynamic)
+            .name(name)
+            .size(1024)
+            .image(&[0x5A; 10][..]);
+        Box::new(match id {
+            Some(id) => volume.id(id),
+            None => volume,
+        })
+    };
+    let ids = |volumes| -> anyhow::Result<Vec<u32>> {
+        let mut ubinizer = Ubinizer::new(volumes, eb_size)?;
+        let mut data = vec![0; 1024];
+        let mut ids = vec![];
+        while let Some((vid, _)) = ubinizer.next_block(&mut data)? {
+            if vid.lnum == 0 {
+                ids.push(vid.vol_id);
+            }
+        }
+        Ok(ids)
+    };
+
+    // Volumes without an ID of their own take the lowest left over, in order, and unnamed ones
+    // don't clash
+    let volumes = vec![
+        volume(None, "a"),
+        volume(Some(0), "b"),
+        volume(None, ""),
+        volume(Some(2), ""),
+    ];
+    assert_eq!(ids(volumes)?, [1, 0, 3, 2, UBI_LAYOUT_VOLUME_ID]);
+
+    // Anything that can't go in the volume table as it is is refused
+    let long_name = "x".repeat(UBI_VOL_NAME_MAX + 1);
+    for (volumes, mentions) in [
+        (
+            vec![volume(Some(1), "a"), volume(Some(1), "b")],
+            "ID 1 is given",
+        ),
+        (
+            vec![volume(None, "a"), volume(Some(1), "a")],
+            "\"a\" is given",
+        ),
+        (vec![volume(None, &long_name)], "longer than 127 bytes"),
+        (vec![volume(Some(128), "a")], "ID 128 is too large"),
+        (vec![volume(Some(5), "a")], "ID 5 doesn't fit"),
+        (
+            (0..6).map(|_| volume(None, "")).collect(),
+            "6 volumes don't fit",
+        ),
+    ] {
+        let error = ids(volumes).unwrap_err().to_string();
+        assert!(
+            error.contains(mentions),
+            "{error:?} doesn't mention {mentions:?}"
+        );
+    }
+
+    // The same volumes make the same image, every time
+    let image = || -> anyhow::Result<Vec<u8>> {
+        let mut nand = crate::nand::SimNand::new(NandLayout {
+            blocks: 16,
+            pages_per_block: 8,
+            bytes_per_page: 256,
+        });
+        let mut ebt = super::scan_blocks(&mut nand)?;
+        super::format(&mut nand, &mut ebt)?;
+        let volumes = vec![volume(None, "a"), volume(Some(0), "b"), volume(None, "c")];
+        super::write_volumes(&mut nand, &mut ebt, volumes)?;
+
+        let mut image = vec![];
+        nand.save(&mut image)?;
+        Ok(image)
+    };
+    assert!(image()? == image()?);
+
+    Ok(())
+}
+
+#[test]
+fn test_estimate_blocks() -> anyhow::Result<()> {
+    use std::collections::BTreeMap;
+
+    let eb_size = NonZeroU32::new(1024).unwrap();
+    let data = [0x5A; 5000];
+    let volume = |vtype, size: Option<u64>, image: Option<usize>| -> Box<dyn Volume> {
+        let mut volume = BasicVolume::new(vtype);
+        if let Some(size) = size {
+            volume = volume.size(size);
+        }
+        if let Some(len) = image {
+            volume = volume.image(&data[..len]);
+        }
+        Box::new(volume)
+    };
+    let lebs = |lnums: &[u32]| -> Box<dyn Volume> {
+        let lebs: Vec<_> = lnums.iter().map(|&x| (x, vec![0x22; 100])).collect();
+        Box::new(LebStreamVolume::new(VolType::Dynamic, lebs))
+    };
+
+    // Each set of volumes, with the blocks each is expected to take and the blocks it does take
+    let dynamic = VolType::Dynamic;
+    let sets = [
+        // A static volume takes just what its size says
+        (
+            vec![volume(VolType::Static, Some(4096), Some(4096))],
+            vec![Some(4)],
+            vec![4],
+        ),
+        // A volume without an image takes nothing, whatever its size
+        (
+            vec![
+                volume(dynamic, Some(65536), None),
+                volume(dynamic, Some(0), None),
+            ],
+            vec![Some(0), Some(0)],
+            vec![0, 0],
+        ),
+        // A dynamic image takes what its size says, down to its last partial LEB
+        (
+            vec![volume(dynamic, Some(3000), Some(3000)), lebs(&[0, 2, 5])],
+            vec![Some(3), Some(3)],
+            vec![3, 3],
+        ),
+        // An image of no given size can't be told in advance
+        (
+            vec![
+                volume(VolType::Static, Some(1024), Some(1024)),
+                volume(dynamic, None, Some(5000)),
+            ],
+            vec![Some(1), None],
+            vec![1, 5],
+        ),
+    ];
+
+    for (volumes, estimates, actual) in sets {
+        let estimate = Ubinizer::estimate_blocks(volumes.iter().map(|x| &**x), eb_size);
+        let mut ubinizer = Ubinizer::new(volumes, eb_size)?;
+        let plan = ubinizer.plan();
+        let planned: Vec<_> = plan.iter().map(|x| x.blocks).collect();
+        let layout = Some(UBI_LAYOUT_VOLUME_EBS);
+        assert_eq!(planned, [&estimates[..], &[layout]].concat());
+        assert_eq!(plan.last().unwrap().vol_id, UBI_LAYOUT_VOLUME_ID);
+        let total: Option<u32> = planned.iter().copied().sum();
+        assert_eq!(estimate, total.unwrap_or(UNKNOWN_BLOCKS));
+
+        let mut counts = BTreeMap::<u32, u32>::new();
+        let mut leb = vec![0; 1024];
+        while let Some((vid, _)) = ubinizer.next_block(&mut leb)? {
+            *counts.entry(vid.vol_id).or_default() += 1;
+        }
+        let counted: Vec<_> = plan
+            .iter()
+            .map(|x| counts.get(&x.vol_id).copied().unwrap_or(0))
+            .collect();
+        assert_eq!(counted, [&actual[..], &[UBI_LAYOUT_VOLUME_EBS]].concat());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_for_ubi() {
+    let good = NandLayout {
+        blocks: 16,
+        pages_per_block: 16,
+        bytes_per_page: 128,
+    };
+    assert!(good.validate_for_ubi().is_ok());
+
+    // Each degenerate layout is refused, naming what's wrong with it
+    for (layout, mentions) in [
+        (
+            NandLayout {
+                pages_per_block: 2,
+                ..good
+            },
+            "pages_per_block is 2",
+        ),
+        (
+            NandLayout {
+                bytes_per_page: 32,
+                ..good
+            },
+            "bytes_per_page is 32",
+        ),
+        (
+            NandLayout {
+                pages_per_block: 3,
+                bytes_per_page: 64,
+                ..good
+            },
+            "LEB size of 64 bytes",
+        ),
+        (NandLayout { blocks: 2, ..good }, "blocks is 2"),
+    ] {
+        let error = layout.validate_for_ubi().unwrap_err().to_string();
+        assert!(
+            error.contains(mentions),
+            "{error:?} doesn't mention {mentions:?}"
+        );
+    }
+
+    // Every entry point checks before touching the NAND
+    let mut nand = crate::nand::SimNand::new(NandLayout {
+        blocks: 8,
+        pages_per_block: 2,
+        bytes_per_page: 64,
+    });
+    let error = super::scan_blocks(&mut nand).unwrap_err();
+    assert!(error.to_string().contains("pages_per_block"));
+    let mut ebt: super::Ebt = vec![super::BlockContent::Erased; 8].into();
+    assert!(super::format(&mut nand, &mut ebt).is_err());
+    assert!(super::write_volumes(&mut nand, &mut ebt, Vec::<Box<dyn Volume>>::new()).is_err());
+
+    // The layout volume needs room for a record, too
+    let volumes: Vec<Box<dyn Volume>> = vec![];
+    assert!(Ubinizer::new(volumes, 64.try_into().unwrap()).is_err());
+}
+
+#[test]
+fn test_duplicate_image() -> anyhow::Result<()> {
+    use super::read::find_lebs;
+    use super::{format, read_volume, read_volume_table, scan_blocks, write_volumes};
+    use crate::nand::SimNand;
+
+    const TEST_LAYOUT: NandLayout = NandLayout {
+        blocks: 32,
+        pages_per_block: 8,
+        bytes_per_page: 256,
+    };
+    const LEB_SIZE: usize = 6 * 256;
+
+    let data: Vec<u8> = (0..LEB_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect();
+    fn ab_volumes<'a>(image: &DuplicateImage<'a>, size: usize) -> Vec<BasicVolume<'a>> {
+        ["rootfs_a", "rootfs_b"]
+            .into_iter()
+            .map(|name| {
+                BasicVolume::new(VolType::Static)
+                    .name(name)
+                    .size(size as u64)
+                    .image(image.copy())
+            })
+            .collect()
+    }
+
+    // A source that can't seek is spilled, one that can is read again (from where it was), and
+    // one that can be opened again is
+    let mut stream = &data[..];
+    let mut prefixed = vec![0xEE; 10];
+    prefixed.extend(&data);
+    let mut cursor = io::Cursor::new(prefixed);
+    cursor.set_position(10);
+    let mut first = &data[..];
+    let reopens = Rc::new(RefCell::new(0));
+    let reopen: ReopenImage = Box::new({
+        let (data, reopens) = (&data, reopens.clone());
+        move || {
+            *reopens.borrow_mut() += 1;
+            Ok(Box::new(&data[..]))
+        }
+    });
+    let sources = [
+        DuplicateImage::new(&mut stream),
+        DuplicateImage::seekable(&mut cursor)?,
+        DuplicateImage::reopened(&mut first, reopen),
+    ];
+
+    for image in sources {
+        let mut nand = SimNand::new(TEST_LAYOUT);
+        let mut ebt = scan_blocks(&mut nand)?;
+        format(&mut nand, &mut ebt)?;
+
+        let volumes = ab_volumes(&image, data.len());
+        let eb_size = NonZeroU32::new(LEB_SIZE as u32).unwrap();
+        let estimate = Ubinizer::estimate_blocks(volumes.iter().map(|x| x as &dyn Volume), eb_size);
+        assert_eq!(estimate, 2 * 4 + UBI_LAYOUT_VOLUME_EBS);
+        let volumes: Vec<_> = volumes
+            .into_iter()
+            .map(|x| Box::new(x) as Box<dyn Volume>)
+            .collect();
+        write_volumes(&mut nand, &mut ebt, volumes)?;
+
+        // Each volume has its own ID and name, and the whole image, with its own headers
+        let ebt = scan_blocks(&mut nand)?;
+        let table = read_volume_table(&mut nand, &ebt)?;
+        for (vol_id, name) in [(0, "rootfs_a"), (1, "rootfs_b")] {
+            assert_eq!(table[vol_id].as_ref().unwrap().name, name);
+
+            let lebs = find_lebs(&ebt, vol_id as u32);
+            assert_eq!(lebs.len(), 4);
+            for (lnum, leb) in &lebs {
+                let chunk = data.chunks(LEB_SIZE).nth(*lnum as usize).unwrap();
+                assert_eq!(leb.vid.used_ebs, 4);
+                assert_eq!(leb.vid.data_size as usize, chunk.len());
+                assert_eq!(leb.vid.data_crc, UBI_CRC.checksum(chunk));
+            }
+
+            let mut out = Vec::new();
+            read_volume(&mut nand, &ebt, &name.parse()?, &mut out)?;
+            assert!(out == data, "{name}");
+        }
+    }
+
+    // The source was only read through once, or opened again once for the second copy
+    assert!(stream.is_empty());
+    assert!(first.is_empty());
+    assert_eq!(*reopens.borrow(), 1);
+
+    Ok(())
+}
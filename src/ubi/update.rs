@@ -0,0 +1,1036 @@
+//! This module implements updating volumes on a UBI partition that stays in service, the way UBI
+//! itself does: the volume's record in the volume table has its `upd_marker` set while its data is
+//! being rewritten, so that an update cut short (e.g. by a power cut) is detected rather than the
+//! volume being silently corrupt.
+//!
+//! ```text
+//! begin_volume_update(nand, &mut ebt, vol_id)?;
+//! // ...rewrite the volume's LEBs...
+//! finish_volume_update(nand, &mut ebt, vol_id)?;
+//! ```
+//!
+//! [update_volume] does all of this to replace a dynamic volume's contents, and is what
+//! `test_flashing ubi-update` runs; the steps are public too, for updates that write the volume's
+//! LEBs some other way.
+//!
+//! A full installation writes every volume and the volume table from scratch, so it has no need
+//! for any of this, bar [write_leb] for filling in a small volume after the fact. A factory reset
+//! uses [reset_volumes] to empty the volumes holding the BMC's settings, without reinstalling,
+//! marking the small ones as being updated all at once to spare the layout volume's PEBs. And
+//! [migrate_volume_table] brings the names and IDs of an older layout's volumes up to date.
+//!
+//! Each of these erases any fastmap before it changes anything, as `format` does, so that a kernel
+//! attaching afterwards scans the partition rather than trusting a stale one.
+
+use super::format::{compute_prototype, FormatAction};
+use super::headers::{Ec, OptionIntoBytes, Vid, VolTableRecord, VolType};
+use super::read::{
+    eb_size, find_lebs, find_live_lebs, find_volume, read_leb, read_table, Leb, VolumeSelector,
+};
+use super::scan::{BlockContent, Ebt};
+use super::ubinize::{vtbl_record_count, UBI_LAYOUT_VOLUME_ID, UBI_VOL_NAME_MAX};
+
+use crate::nand::{Nand, NandBlock, PageUtil};
+
+/// The highest sqnum of any VID header in the EBT, including those of blocks that lost their EC
+/// header, so that nothing written now looks older than what they hold
+fn max_sqnum(ebt: &Ebt) -> u64 {
+    ebt.iter()
+        .filter_map(|content| match content {
+            BlockContent::EcData(_, Some(vid)) | BlockContent::VidOnly(vid) => Some(vid.sqnum),
+            _ => None,
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+/// Erase any fastmap, before anything else on the partition changes. A kernel attaching through a
+/// fastmap takes the PEBs it lists to hold the LEBs they did when it was written, rather than
+/// scanning them, so it would go on mapping LEBs that have since been erased or moved. Without one,
+/// the kernel scans, and writes a new fastmap. The EBT is kept up to date.
+fn invalidate_fastmap<N: Nand>(nand: &mut N, ebt: &mut Ebt) -> anyhow::Result<()> {
+    for (block, content) in ebt.iter_mut().enumerate() {
+        let BlockContent::EcData(ec, _) = *content else {
+            continue;
+        };
+        if !content.is_fastmap() {
+            continue;
+        }
+        let nand_block = nand
+            .block(block as u32)?
+            .ok_or(anyhow::anyhow!("block {block} unexpectedly marked bad"))?;
+        FormatAction::Erase(ec.inc_ec()).execute(nand_block, content)?;
+    }
+
+    Ok(())
+}
+
+/// Rewrite both copies of the layout volume in place, with `records` as the volume table (padded
+/// out with empty records as needed).
+///
+/// Each copy's PEB is erased and reprogrammed in turn, with its EC incremented and an sqnum newer
+/// than any other in the EBT, so that one intact copy remains should the rewrite be interrupted.
+/// The EBT is kept up to date.
+pub fn rewrite_layout<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    records: &[Option<VolTableRecord>],
+) -> anyhow::Result<()> {
+    nand.ensure_writeable()?;
+
+    let lebs = find_lebs(ebt, UBI_LAYOUT_VOLUME_ID);
+    anyhow::ensure!(!lebs.is_empty(), "no layout volume found");
+    invalidate_fastmap(nand, ebt)?;
+
+    let mut sqnum = max_sqnum(ebt);
+
+    let page_size = nand.get_layout().bytes_per_page;
+    for leb in lebs.into_values() {
+        let record_count = vtbl_record_count(eb_size(nand, &leb.ec)?.try_into()?);
+        anyhow::ensure!(
+            records.len() <= record_count,
+            "{} volume table records don't fit in a LEB (at most {record_count})",
+            records.len()
+        );
+        let mut data: Vec<u8> = (0..record_count)
+            .flat_map(|i| records.get(i).cloned().flatten().into_bytes())
+            .collect();
+        data.resize(data.len().div_ceil(page_size) * page_size, 0xFF);
+
+        sqnum += 1;
+        let vid = leb.vid.sqnum(sqnum);
+        let ec = leb.ec.inc_ec();
+        let mut vid_page = vec![0u8; page_size];
+        vid.encode(&mut vid_page)?;
+
+        let content = &mut ebt[leb.block as usize];
+        let block = nand.block(leb.block)?.ok_or(anyhow::anyhow!(
+            "block {} unexpectedly marked bad",
+            leb.block
+        ))?;
+        FormatAction::Erase(ec).execute(block, content)?;
+        anyhow::ensure!(
+            *content == BlockContent::EcErased(ec),
+            "block {} of the layout volume went bad",
+            leb.block
+        );
+
+        let mut block = nand.block(leb.block)?.ok_or(anyhow::anyhow!(
+            "block {} unexpectedly marked bad",
+            leb.block
+        ))?;
+        program_leb(&mut block, &ec, &vid_page, &data)?;
+        *content = BlockContent::EcData(ec, Some(vid));
+    }
+
+    Ok(())
+}
+
+/// Program a LEB's VID header page and its (page-aligned) data into a block with the EC header
+/// `ec`. Where the data follows straight on from the VID header, as it does in the layout
+/// [compute_prototype] gives, the two go in one program, as [write_volumes](super::write_volumes)
+/// writes them.
+fn program_leb<B: NandBlock>(
+    block: &mut B,
+    ec: &Ec,
+    vid_page: &[u8],
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let page_size = vid_page.len();
+    let (vid_at, data_at) = (ec.vid_hdr_page(page_size)?, ec.data_page(page_size)?);
+    if data.is_empty() {
+        return block.program(vid_at, vid_page);
+    }
+    if data_at == vid_at + 1 {
+        return block.program(vid_at, &[vid_page, data].concat());
+    }
+    block.program(vid_at, vid_page)?;
+    block.program(data_at, data)
+}
+
+/// Set or clear the `upd_marker` of the records of several volumes, rewriting the layout volume
+/// just once for all of them
+fn set_upd_marker<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    vol_ids: &[u32],
+    upd_marker: bool,
+) -> anyhow::Result<()> {
+    let (mut table, _) = read_table(nand, ebt)?;
+    for &vol_id in vol_ids {
+        let record = table
+            .get_mut(vol_id as usize)
+            .and_then(Option::as_mut)
+            .ok_or(anyhow::anyhow!("volume {vol_id} not found"))?;
+        record.upd_marker = upd_marker;
+    }
+
+    rewrite_layout(nand, ebt, &table)
+}
+
+/// Mark a volume as being updated, before any of its LEBs are rewritten
+pub fn begin_volume_update<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    vol_id: u32,
+) -> anyhow::Result<()> {
+    set_upd_marker(nand, ebt, &[vol_id], true)
+}
+
+/// Mark a volume as updated, once all of its LEBs have been rewritten
+pub fn finish_volume_update<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    vol_id: u32,
+) -> anyhow::Result<()> {
+    set_upd_marker(nand, ebt, &[vol_id], false)
+}
+
+/// The erased PEB with the lowest EC, for a LEB to be written into; only blocks laid out the way
+/// `write_volumes` would have them are used
+fn lowest_erased_block(ebt: &Ebt, page_size: usize) -> anyhow::Result<(u32, Ec)> {
+    ebt.iter()
+        .enumerate()
+        .filter_map(|(i, content)| match content {
+            BlockContent::EcErased(ec)
+                if ec.vid_hdr_offset as usize == page_size
+                    && ec.data_offset as usize == page_size * 2 =>
+            {
+                Some((i as u32, *ec))
+            }
+            _ => None,
+        })
+        .min_by_key(|(_, ec)| ec.ec)
+        .ok_or(anyhow::anyhow!("Flash is full"))
+}
+
+/// Write one LEB of a dynamic volume in place, into the erased PEB with the lowest EC, with an
+/// sqnum newer than any other in the EBT. `data` is padded out to a whole number of pages.
+///
+/// Whatever PEB held the LEB before is erased once the new one is written, so that only one copy
+/// is ever left. The EBT is kept up to date.
+pub fn write_leb<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    volume: &VolumeSelector,
+    lnum: u32,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    nand.ensure_writeable()?;
+
+    let (table, _) = read_table(nand, ebt)?;
+    let (vol_id, record) = find_volume(table, volume)?;
+    anyhow::ensure!(
+        record.vol_type == VolType::Dynamic,
+        "volume {volume} is static, so its LEBs can't be written one at a time"
+    );
+    anyhow::ensure!(
+        lnum < record.reserved_pebs,
+        "volume {volume} has no LEB {lnum}"
+    );
+    invalidate_fastmap(nand, ebt)?;
+
+    let page_size = nand.get_layout().bytes_per_page;
+    let (block, ec) = lowest_erased_block(ebt, page_size)?;
+
+    let leb_size = eb_size(nand, &ec)?
+        .checked_sub(record.data_pad)
+        .ok_or(anyhow::anyhow!("volume {volume} has invalid data_pad"))?;
+    anyhow::ensure!(
+        data.len() <= leb_size as usize,
+        "{} bytes don't fit in a LEB of {leb_size}",
+        data.len()
+    );
+
+    let old = find_lebs(ebt, vol_id).remove(&lnum);
+    let vid = Vid {
+        vol_type: VolType::Dynamic,
+        vol_id,
+        lnum,
+        data_pad: record.data_pad,
+        sqnum: max_sqnum(ebt) + 1,
+        ..Default::default()
+    };
+    let mut vid_page = vec![0u8; page_size];
+    vid.encode(&mut vid_page)?;
+    let mut data = data.to_vec();
+    data.resize(data.len().div_ceil(page_size) * page_size, 0xFF);
+
+    {
+        let mut nand_block = nand
+            .block(block)?
+            .ok_or(anyhow::anyhow!("block {block} unexpectedly marked bad"))?;
+        program_leb(&mut nand_block, &ec, &vid_page, &data)?;
+    }
+    ebt[block as usize] = BlockContent::EcData(ec, Some(vid));
+
+    if let Some(old) = old {
+        let content = &mut ebt[old.block as usize];
+        let block = nand.block(old.block)?.ok_or(anyhow::anyhow!(
+            "block {} unexpectedly marked bad",
+            old.block
+        ))?;
+        FormatAction::Erase(old.ec.inc_ec()).execute(block, content)?;
+    }
+
+    Ok(())
+}
+
+/// What [reset_volumes] did
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResetStats {
+    /// How many times the layout volume was rewritten, each time erasing and programming both of
+    /// its PEBs
+    pub layout_rewrites: u32,
+}
+
+/// How much data the volumes [reset_volumes] batches together may hold between them
+pub const RESET_BATCH_BYTES: u64 = 1 << 20;
+
+/// Wipe the named volumes back to empty, as though newly created, leaving every other block alone.
+/// Volumes holding no more than [RESET_BATCH_BYTES] between them are reset together.
+///
+/// See [reset_volumes_batched].
+pub fn reset_volumes<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    vol_names: &[&str],
+) -> anyhow::Result<ResetStats> {
+    reset_volumes_batched(nand, ebt, vol_names, RESET_BATCH_BYTES)
+}
+
+/// Wipe the named volumes back to empty, as though newly created, leaving every other block alone.
+///
+/// Each volume is marked as being updated ([begin_volume_update]), has every PEB holding any of
+/// its LEBs (stale copies included) erased, counting the erase, and is then marked as updated
+/// again ([finish_volume_update]), so the volumes are all still there, and one whose reset was cut
+/// short is refused by UBI rather than attached half-erased. Only dynamic volumes can be reset: a
+/// static volume reads back as exactly the data written to it, so an empty one would no longer be
+/// what its users expect. The EBT is kept up to date.
+///
+/// Consecutive volumes whose PEBs hold no more than `batch_bytes` between them share the layout
+/// volume rewrites setting and clearing their markers, rather than wearing its two PEBs twice
+/// each; a larger volume is reset on its own. A reset cut short partway through a batch leaves
+/// every volume in it marked as being updated, which is no worse: each one is being emptied
+/// anyway, and running the reset again finishes the job.
+pub fn reset_volumes_batched<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    vol_names: &[&str],
+    batch_bytes: u64,
+) -> anyhow::Result<ResetStats> {
+    nand.ensure_writeable()?;
+
+    let (table, _) = read_table(nand, ebt)?;
+    let mut vol_ids = Vec::with_capacity(vol_names.len());
+    for &name in vol_names {
+        let volume = VolumeSelector::Name(name.into());
+        let (vol_id, record) = find_volume(table.clone(), &volume)?;
+        anyhow::ensure!(
+            record.vol_type == VolType::Dynamic,
+            "volume {volume} is static, so it can't be reset"
+        );
+        vol_ids.push(vol_id);
+    }
+
+    // How much each volume holds, stale copies and all, going by whole PEBs
+    let block_bytes = nand.get_layout().block_bytes() as u64;
+    let volume_bytes = |vol_id: u32| {
+        let blocks = ebt.iter().filter(|x| match x {
+            BlockContent::EcData(_, Some(vid)) | BlockContent::VidOnly(vid) => vid.vol_id == vol_id,
+            _ => false,
+        });
+        blocks.count() as u64 * block_bytes
+    };
+    let mut batches: Vec<Vec<u32>> = vec![];
+    let mut batch_total = 0;
+    for vol_id in vol_ids {
+        let bytes = volume_bytes(vol_id);
+        match batches.last_mut() {
+            Some(batch) if batch_total + bytes <= batch_bytes => {
+                batch.push(vol_id);
+                batch_total += bytes;
+            }
+            _ => {
+                batches.push(vec![vol_id]);
+                batch_total = bytes;
+            }
+        }
+    }
+
+    let mut stats = ResetStats::default();
+    for batch in batches {
+        set_upd_marker(nand, ebt, &batch, true)?;
+        unmap_volumes(nand, ebt, &batch)?;
+        set_upd_marker(nand, ebt, &batch, false)?;
+        stats.layout_rewrites += 2;
+    }
+
+    Ok(stats)
+}
+
+/// Erase every PEB holding any LEB of the given volumes, stale copies included, counting the erase.
+/// The EBT is kept up to date.
+fn unmap_volumes<N: Nand>(nand: &mut N, ebt: &mut Ebt, vol_ids: &[u32]) -> anyhow::Result<()> {
+    // A block that lost its EC header gets the mean, as a format would give it
+    let (proto, _) = compute_prototype(nand.get_layout(), ebt.iter().copied())?;
+    for (block, content) in ebt.iter_mut().enumerate() {
+        let ec = match *content {
+            BlockContent::EcData(ec, Some(vid)) if vol_ids.contains(&vid.vol_id) => ec.inc_ec(),
+            BlockContent::VidOnly(vid) if vol_ids.contains(&vid.vol_id) => proto,
+            _ => continue,
+        };
+        let nand_block = nand
+            .block(block as u32)?
+            .ok_or(anyhow::anyhow!("block {block} unexpectedly marked bad"))?;
+        FormatAction::Erase(ec).execute(nand_block, content)?;
+    }
+
+    Ok(())
+}
+
+/// Replace the whole contents of a dynamic volume with `data`, in place, as `ubiupdatevol` does.
+///
+/// The volume is marked as being updated ([begin_volume_update]) before anything else, then
+/// emptied, then written LEB by LEB with [write_leb], and only marked as updated again
+/// ([finish_volume_update]) once all of `data` is on flash; UBI refuses to attach a volume whose
+/// update was cut short, rather than passing it off as good. The EBT is kept up to date.
+pub fn update_volume<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    volume: &VolumeSelector,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    nand.ensure_writeable()?;
+
+    let (table, _) = read_table(nand, ebt)?;
+    let (vol_id, record) = find_volume(table, volume)?;
+    anyhow::ensure!(
+        record.vol_type == VolType::Dynamic,
+        "volume {volume} is static, so it can't be updated in place"
+    );
+    let (proto, _) = compute_prototype(nand.get_layout(), ebt.iter().copied())?;
+    let leb_size = eb_size(nand, &proto)?
+        .checked_sub(record.data_pad)
+        .ok_or(anyhow::anyhow!("volume {volume} has invalid data_pad"))?;
+    let capacity = leb_size as usize * record.reserved_pebs as usize;
+    anyhow::ensure!(
+        data.len() <= capacity,
+        "{} bytes don't fit in volume {volume} ({capacity} bytes)",
+        data.len()
+    );
+
+    begin_volume_update(nand, ebt, vol_id)?;
+    unmap_volumes(nand, ebt, &[vol_id])?;
+    let volume = VolumeSelector::Id(vol_id);
+    for (lnum, chunk) in data.chunks(leb_size as usize).enumerate() {
+        write_leb(nand, ebt, &volume, lnum as u32, chunk)?;
+    }
+    finish_volume_update(nand, ebt, vol_id)
+}
+
+/// Rename volumes, and move them to new IDs, to bring the volume table of an older layout up to
+/// date. Each of `renames` is (old name, new name, new ID), with `None` for the ID to stay as it
+/// is. A volume that isn't there (e.g. because it was migrated already) is skipped. Returns
+/// whether anything changed.
+///
+/// A volume can only move to an ID that's free to begin with, not one another gives up in the same
+/// migration, and no two volumes can end up with the same name.
+///
+/// Every LEB of a volume that moves is copied into the erased PEB with the lowest EC, with its VID
+/// header saying the new ID and an sqnum newer than any other, before the PEB it came from is
+/// erased (stale copies too). The volume table is only rewritten after that, so a migration cut
+/// short (e.g. by a power cut) loses nothing, and is finished by running it again. The EBT is
+/// kept up to date.
+pub fn migrate_volume_table<N: Nand>(
+    nand: &mut N,
+    ebt: &mut Ebt,
+    renames: &[(&str, &str, Option<u32>)],
+) -> anyhow::Result<bool> {
+    let (table, _) = read_table(nand, ebt)?;
+    let mut migrated = table.clone();
+    let mut moves = vec![];
+    for &(old_name, new_name, new_id) in renames {
+        let Some(old_id) = migrated
+            .iter()
+            .position(|x| x.as_ref().is_some_and(|x| x.name == old_name))
+        else {
+            continue;
+        };
+        let new_id = new_id.map_or(old_id, |x| x as usize);
+
+        anyhow::ensure!(
+            !new_name.is_empty() && new_name.len() <= UBI_VOL_NAME_MAX,
+            "volume name {new_name:?} must be 1 to {UBI_VOL_NAME_MAX} bytes long"
+        );
+        anyhow::ensure!(
+            migrated
+                .iter()
+                .enumerate()
+                .all(|(id, x)| id == old_id || x.as_ref().is_none_or(|x| x.name != new_name)),
+            "volume name {new_name:?} is taken"
+        );
+        if new_id != old_id {
+            let free = |table: &[Option<VolTableRecord>]| table.get(new_id) == Some(&None);
+            anyhow::ensure!(
+                free(&table) && free(&migrated),
+                "volume ID {new_id} is taken, or doesn't fit in the volume table"
+            );
+        }
+
+        let mut record = migrated[old_id].take().unwrap();
+        record.name = new_name.into();
+        migrated[new_id] = Some(record);
+        if new_id != old_id {
+            moves.push((old_id as u32, new_id as u32));
+        }
+    }
+    if migrated == table {
+        return Ok(false);
+    }
+
+    nand.ensure_writeable()?;
+    invalidate_fastmap(nand, ebt)?;
+    for (old_id, new_id) in moves {
+        for leb in find_live_lebs(nand, ebt, old_id)?.into_values() {
+            move_leb(nand, ebt, &leb, new_id)?;
+        }
+
+        // Anything left of the volume is a stale copy
+        let (proto, _) = compute_prototype(nand.get_layout(), ebt.iter().copied())?;
+        for (block, content) in ebt.iter_mut().enumerate() {
+            let ec = match *content {
+                BlockContent::EcData(ec, Some(vid)) if vid.vol_id == old_id => ec.inc_ec(),
+                BlockContent::VidOnly(vid) if vid.vol_id == old_id => proto,
+                _ => continue,
+            };
+            let nand_block = nand
+                .block(block as u32)?
+                .ok_or(anyhow::anyhow!("block {block} unexpectedly marked bad"))?;
+            FormatAction::Erase(ec).execute(nand_block, content)?;
+        }
+    }
+
+    rewrite_layout(nand, ebt, &migrated)?;
+    Ok(true)
+}
+
+/// Copy a LEB into the erased PEB with the lowest EC, as LEB `leb.vid.lnum` of volume `vol_id`,
+/// then erase the PEB it came from. The EBT is kept up to date.
+fn move_leb<N: Nand>(nand: &mut N, ebt: &mut Ebt, leb: &Leb, vol_id: u32) -> anyhow::Result<()> {
+    let page_size = nand.get_layout().bytes_per_page;
+
+    // Pages never written are left that way, for UBI to go on appending to a dynamic LEB
+    let mut data = read_leb(nand, leb, eb_size(nand, &leb.ec)? as usize)?;
+    let written = data
+        .chunks(page_size)
+        .rposition(|x| !x.is_erased())
+        .map_or(0, |x| x + 1);
+    data.truncate(written * page_size);
+
+    let (block, ec) = lowest_erased_block(ebt, page_size)?;
+    anyhow::ensure!(
+        data.len() <= eb_size(nand, &ec)? as usize,
+        "LEB {} of volume {} doesn't fit in block {block}",
+        leb.vid.lnum,
+        leb.vid.vol_id
+    );
+    let vid = Vid {
+        vol_id,
+        sqnum: max_sqnum(ebt) + 1,
+        ..leb.vid
+    };
+    let mut vid_page = vec![0u8; page_size];
+    vid.encode(&mut vid_page)?;
+    {
+        let mut nand_block = nand
+            .block(block)?
+            .ok_or(anyhow::anyhow!("block {block} unexpectedly marked bad"))?;
+        program_leb(&mut nand_block, &ec, &vid_page, &data)?;
+    }
+    ebt[block as usize] = BlockContent::EcData(ec, Some(vid));
+
+    // A block that lost its EC header gets the mean, as a format would give it
+    let ec = match ebt[leb.block as usize] {
+        BlockContent::EcData(ec, _) => ec.inc_ec(),
+        _ => compute_prototype(nand.get_layout(), ebt.iter().copied())?.0,
+    };
+    let content = &mut ebt[leb.block as usize];
+    let old = nand.block(leb.block)?.ok_or(anyhow::anyhow!(
+        "block {} unexpectedly marked bad",
+        leb.block
+    ))?;
+    FormatAction::Erase(ec).execute(old, content)
+}
+
+/// A NAND small enough for the tests to lay out by hand, with LEBs of [TEST_LEB_SIZE]
+#[cfg(test)]
+const TEST_LAYOUT: crate::nand::NandLayout = crate::nand::NandLayout {
+    blocks: 32,
+    pages_per_block: 16,
+    bytes_per_page: 128,
+};
+
+#[cfg(test)]
+const TEST_LEB_SIZE: usize = 14 * 128;
+
+/// A freshly formatted [TEST_LAYOUT] NAND holding `volumes`, and its EBT
+#[cfg(test)]
+fn test_partition(
+    volumes: Vec<Box<dyn super::ubinize::Volume + '_>>,
+) -> anyhow::Result<(crate::nand::SimNand, Ebt)> {
+    use super::{format, scan_blocks, write_volumes};
+
+    let mut nand = crate::nand::SimNand::new(TEST_LAYOUT);
+    let mut ebt = scan_blocks(&mut nand)?;
+    format(&mut nand, &mut ebt)?;
+    write_volumes(&mut nand, &mut ebt, volumes)?;
+    Ok((nand, ebt))
+}
+
+#[test]
+fn test_volume_update() -> anyhow::Result<()> {
+    use super::read::read_volume;
+    use super::ubinize::BasicVolume;
+    use super::{read_volume_table, scan_blocks};
+
+    let data = vec![0x5A; TEST_LEB_SIZE + 100];
+    let (mut nand, mut ebt) = test_partition(vec![Box::new(
+        BasicVolume::new(VolType::Static)
+            .name("rootfs")
+            .size(data.len() as u64)
+            .image(&data[..]),
+    )])?;
+    let layout_blocks = |ebt: &Ebt| find_lebs(ebt, UBI_LAYOUT_VOLUME_ID).into_values();
+    let before: Vec<_> = layout_blocks(&ebt).collect();
+
+    // Stopping after the first phase leaves the marker set on flash, for a rescan to find
+    begin_volume_update(&mut nand, &mut ebt, 0)?;
+    let mut ebt = scan_blocks(&mut nand)?;
+    let table = read_volume_table(&mut nand, &ebt)?;
+    assert!(table[0].as_ref().unwrap().upd_marker);
+    assert!(table[1..].iter().all(Option::is_none));
+
+    // Both copies were rewritten in place, erased once more and newer than anything else
+    let max_data_sqnum = ebt
+        .iter()
+        .filter_map(|x| match x {
+            BlockContent::EcData(_, Some(vid)) if vid.vol_id == 0 => Some(vid.sqnum),
+            _ => None,
+        })
+        .max()
+        .unwrap();
+    let after: Vec<_> = layout_blocks(&ebt).collect();
+    assert_eq!(after.len(), 2);
+    for (before, after) in before.iter().zip(&after) {
+        assert_eq!(after.block, before.block);
+        assert_eq!(after.ec.ec, before.ec.ec + 1);
+        assert!(after.vid.sqnum > max_data_sqnum);
+    }
+
+    finish_volume_update(&mut nand, &mut ebt, 0)?;
+    let ebt = scan_blocks(&mut nand)?;
+    let table = read_volume_table(&mut nand, &ebt)?;
+    assert!(!table[0].as_ref().unwrap().upd_marker);
+
+    // The volume's data was left alone
+    let mut out = vec![];
+    read_volume(&mut nand, &ebt, &VolumeSelector::Id(0), &mut out)?;
+    assert!(out == data);
+
+    assert!(begin_volume_update(&mut nand, &mut ebt.clone(), 5).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_update_volume() -> anyhow::Result<()> {
+    use super::read::read_volume;
+    use super::ubinize::BasicVolume;
+    use super::{read_volume_table, scan_blocks};
+
+    let settings = vec![0x11; 3 * TEST_LEB_SIZE];
+    let (mut nand, mut ebt) = test_partition(vec![
+        Box::new(
+            BasicVolume::new(VolType::Static)
+                .name("rootfs")
+                .size(6)
+                .image(&b"rootfs"[..]),
+        ),
+        Box::new(
+            BasicVolume::new(VolType::Dynamic)
+                .name("env")
+                .size(4 * TEST_LEB_SIZE as u64)
+                .image(&settings[..]),
+        ),
+    ])?;
+    let env = VolumeSelector::Name("env".into());
+
+    // The old contents are gone, even past the end of the new ones, and the marker is clear again
+    let update = vec![0x22; TEST_LEB_SIZE + 100];
+    update_volume(&mut nand, &mut ebt, &env, &update)?;
+    assert_eq!(scan_blocks(&mut nand)?, ebt);
+    assert_eq!(find_lebs(&ebt, 1).len(), 2);
+    assert!(
+        !read_volume_table(&mut nand, &ebt)?[1]
+            .as_ref()
+            .unwrap()
+            .upd_marker
+    );
+    let mut out = vec![];
+    read_volume(&mut nand, &ebt, &env, &mut out)?;
+    assert!(out.starts_with(&update) && out[update.len()..].iter().all(|&x| x == 0xFF));
+
+    // Static volumes aren't updated in place, and data too big for the volume isn't written at all
+    let before = ebt.clone();
+    assert!(update_volume(&mut nand, &mut ebt, &VolumeSelector::Id(0), b"x").is_err());
+    assert!(update_volume(&mut nand, &mut ebt, &env, &vec![0; 4 * TEST_LEB_SIZE + 1]).is_err());
+    assert_eq!(ebt, before);
+
+    Ok(())
+}
+
+#[test]
+fn test_write_leb() -> anyhow::Result<()> {
+    use super::read::read_volume_leb;
+    use super::scan_blocks;
+    use super::ubinize::BasicVolume;
+
+    let (mut nand, mut ebt) = test_partition(vec![
+        Box::new(
+            BasicVolume::new(VolType::Static)
+                .name("rootfs")
+                .size(6)
+                .image(&b"rootfs"[..]),
+        ),
+        Box::new(BasicVolume::new(VolType::Dynamic).name("stamp").size(1)),
+    ])?;
+    let stamp = VolumeSelector::Name("stamp".into());
+    assert_eq!(read_volume_leb(&mut nand, &ebt, &stamp, 0)?, None);
+
+    // Writing the LEB again leaves only the newer copy behind; the VID header and data go in one
+    // program, besides the EC header of the block the older copy was erased from
+    for (erased, contents) in [&b"first"[..], b"second"].into_iter().enumerate() {
+        let before = nand.stats();
+        write_leb(&mut nand, &mut ebt, &stamp, 0, contents)?;
+        assert_eq!(nand.stats().since(&before).programs, 1 + erased as u64);
+        let ebt = scan_blocks(&mut nand)?;
+        assert_eq!(find_lebs(&ebt, 1).len(), 1);
+        let leb = read_volume_leb(&mut nand, &ebt, &stamp, 0)?.unwrap();
+        assert_eq!(leb.len(), TEST_LEB_SIZE);
+        assert!(leb.starts_with(contents) && leb[contents.len()..].iter().all(|&x| x == 0xFF));
+    }
+
+    // Static volumes have to be written whole, and LEBs past the end of a volume don't exist
+    let rootfs = VolumeSelector::Id(0);
+    assert!(write_leb(&mut nand, &mut ebt, &rootfs, 0, b"x").is_err());
+    assert!(write_leb(&mut nand, &mut ebt, &stamp, 1, b"x").is_err());
+    let leb = read_volume_leb(&mut nand, &ebt, &rootfs, 0)?.unwrap();
+    assert_eq!(leb, b"rootfs");
+
+    Ok(())
+}
+
+#[test]
+fn test_reset_volumes() -> anyhow::Result<()> {
+    use super::scan::summarize_volumes;
+    use super::ubinize::BasicVolume;
+    use super::{read_volume_table, scan_blocks};
+
+    let (data, settings) = (vec![0x33; 2 * TEST_LEB_SIZE], vec![0x44; 2 * TEST_LEB_SIZE]);
+    let (mut nand, mut ebt) = test_partition(vec![
+        Box::new(
+            BasicVolume::new(VolType::Static)
+                .name("rootfs")
+                .size(data.len() as u64)
+                .image(&data[..]),
+        ),
+        Box::new(
+            BasicVolume::new(VolType::Dynamic)
+                .name("env")
+                .size(4 * TEST_LEB_SIZE as u64)
+                .image(&settings[..]),
+        ),
+    ])?;
+    // A LEB written in place later goes too
+    write_leb(&mut nand, &mut ebt, &"env".parse()?, 3, b"settings")?;
+
+    let read_block = |nand: &mut crate::nand::SimNand, block: u32| -> anyhow::Result<Vec<u8>> {
+        let mut contents = vec![0; TEST_LAYOUT.block_bytes()];
+        nand.block(block)?.unwrap().read(0, &mut contents)?;
+        Ok(contents)
+    };
+    let rootfs_blocks: Vec<_> = find_lebs(&ebt, 0).into_values().map(|x| x.block).collect();
+    let rootfs_before = rootfs_blocks
+        .iter()
+        .map(|&x| read_block(&mut nand, x))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let table_before = read_volume_table(&mut nand, &ebt)?;
+    let env_blocks: Vec<_> = find_lebs(&ebt, 1).into_values().collect();
+
+    reset_volumes(&mut nand, &mut ebt, &["env"])?;
+    let ebt_after = scan_blocks(&mut nand)?;
+    assert_eq!(ebt_after, ebt);
+
+    // The rootfs is untouched, and the volumes are all still there, but `env` has no LEBs left
+    for (&block, before) in rootfs_blocks.iter().zip(&rootfs_before) {
+        assert!(read_block(&mut nand, block)? == *before, "block {block}");
+    }
+    let table = read_volume_table(&mut nand, &ebt)?;
+    assert_eq!(table, table_before);
+    let summary = summarize_volumes(&ebt, &table);
+    assert_eq!(summary.volumes[0].lebs, 2);
+    assert_eq!(summary.volumes[1].lebs, 0);
+    for leb in env_blocks {
+        assert_eq!(
+            ebt[leb.block as usize],
+            BlockContent::EcErased(leb.ec.inc_ec())
+        );
+    }
+
+    // A reset cut short once the volume's blocks are being erased leaves it marked as being
+    // updated, until the reset is run again
+    write_leb(&mut nand, &mut ebt, &"env".parse()?, 0, b"settings")?;
+    let mut interrupted = crate::nand::CountingNand::new(nand.clone());
+    interrupted.fail_after_mutations = Some(5);
+    assert!(reset_volumes(&mut interrupted, &mut ebt.clone(), &["env"]).is_err());
+    let mut interrupted = interrupted.inner;
+    let mut ebt_interrupted = scan_blocks(&mut interrupted)?;
+    let table = read_volume_table(&mut interrupted, &ebt_interrupted)?;
+    assert!(table[1].as_ref().unwrap().upd_marker);
+    reset_volumes(&mut interrupted, &mut ebt_interrupted, &["env"])?;
+    assert_eq!(
+        read_volume_table(&mut interrupted, &ebt_interrupted)?,
+        table_before
+    );
+    assert_eq!(find_lebs(&ebt_interrupted, 1).len(), 0);
+
+    // Static volumes, and volumes that don't exist, aren't reset
+    assert!(reset_volumes(&mut nand, &mut ebt, &["rootfs"]).is_err());
+    assert!(reset_volumes(&mut nand, &mut ebt, &["data"]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_reset_volumes_batched() -> anyhow::Result<()> {
+    use super::ubinize::{BasicVolume, Volume};
+    use super::{read_volume_table, scan_blocks};
+
+    // Six small volumes of a LEB each, and one of four
+    const SMALL: [&str; 6] = ["s0", "s1", "s2", "s3", "s4", "s5"];
+    let (small, big) = (vec![0x44; TEST_LEB_SIZE], vec![0x33; 4 * TEST_LEB_SIZE]);
+    let mut volumes: Vec<Box<dyn Volume>> = SMALL
+        .iter()
+        .map(|&name| -> Box<dyn Volume> {
+            Box::new(
+                BasicVolume::new(VolType::Dynamic)
+                    .name(name)
+                    .size(small.len() as u64)
+                    .image(&small[..]),
+            )
+        })
+        .collect();
+    volumes.push(Box::new(
+        BasicVolume::new(VolType::Dynamic)
+            .name("big")
+            .size(big.len() as u64)
+            .image(&big[..]),
+    ));
+    let (nand, ebt) = test_partition(volumes)?;
+    let block_bytes = TEST_LAYOUT.block_bytes() as u64;
+
+    // Reset some volumes of a copy of the partition, checking that every rewrite of the layout
+    // volume erased both of its PEBs, and that the volumes were all emptied and their markers
+    // cleared
+    let reset = |names: &[&str], batch_bytes| -> anyhow::Result<u32> {
+        let (mut nand, mut ebt) = (nand.clone(), ebt.clone());
+        let before = find_lebs(&ebt, UBI_LAYOUT_VOLUME_ID);
+        let stats = reset_volumes_batched(&mut nand, &mut ebt, names, batch_bytes)?;
+        assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+        let after = find_lebs(&ebt, UBI_LAYOUT_VOLUME_ID);
+        for (lnum, leb) in before {
+            let erases = after[&lnum].ec.ec - leb.ec.ec;
+            assert_eq!(erases, u64::from(stats.layout_rewrites));
+        }
+        let table = read_volume_table(&mut nand, &ebt)?;
+        for (vol_id, record) in table.iter().enumerate() {
+            let Some(record) = record else { continue };
+            assert!(!record.upd_marker);
+            let emptied = names.contains(&record.name.as_str());
+            assert_eq!(find_lebs(&ebt, vol_id as u32).is_empty(), emptied);
+        }
+        Ok(stats.layout_rewrites)
+    };
+
+    // The small volumes share one pair of rewrites, where each would otherwise take its own...
+    assert_eq!(reset(&SMALL, RESET_BATCH_BYTES)?, 2);
+    assert_eq!(reset(&SMALL, 0)?, 12);
+
+    // ...while a volume over the limit still gets a pair to itself, and the small volumes are
+    // split into batches under it
+    assert_eq!(reset(&["big"], RESET_BATCH_BYTES)?, 2);
+    assert_eq!(reset(&["big"], block_bytes)?, 2);
+    let all: Vec<&str> = ["big"].iter().chain(&SMALL).copied().collect();
+    assert_eq!(reset(&all, 3 * block_bytes)?, 6);
+
+    Ok(())
+}
+
+#[test]
+fn test_migrate_volume_table() -> anyhow::Result<()> {
+    use super::read::read_volume;
+    use super::ubinize::BasicVolume;
+    use super::{read_volume_table, scan_blocks};
+
+    const RENAMES: &[(&str, &str, Option<u32>)] =
+        &[("env", "uboot-env", None), ("rootfs", "rootfs", Some(1))];
+
+    // An older layout: the environment under another name, and the rootfs at ID 2
+    let data: Vec<u8> = (0..2 * TEST_LEB_SIZE + 100).map(|x| x as u8).collect();
+    let settings = vec![0x44; TEST_LEB_SIZE + 100];
+    let (mut nand, mut ebt) = test_partition(vec![
+        Box::new(
+            BasicVolume::new(VolType::Dynamic)
+                .name("env")
+                .size(2 * TEST_LEB_SIZE as u64)
+                .image(&settings[..]),
+        ),
+        Box::new(
+            BasicVolume::new(VolType::Static)
+                .name("rootfs")
+                .id(2)
+                .size(data.len() as u64)
+                .image(&data[..]),
+        ),
+    ])?;
+    let sqnum_before = max_sqnum(&ebt);
+    let rootfs_before: Vec<_> = find_lebs(&ebt, 2).into_values().collect();
+
+    assert!(migrate_volume_table(&mut nand, &mut ebt, RENAMES)?);
+    assert_eq!(scan_blocks(&mut nand)?, ebt);
+
+    // The volume table has the new names and IDs...
+    let table = read_volume_table(&mut nand, &ebt)?;
+    let names: Vec<_> = table
+        .iter()
+        .map(|x| x.as_ref().map(|x| x.name.as_str()))
+        .collect();
+    assert_eq!(names[..3], [Some("uboot-env"), Some("rootfs"), None]);
+    assert_eq!(table[1].as_ref().unwrap().vol_type, VolType::Static);
+
+    // ...and so does every data block: each LEB was copied with a newer sqnum, and its old block
+    // erased
+    assert!(ebt.iter().all(|x| match x {
+        BlockContent::EcData(_, Some(vid)) => vid.vol_id != 2,
+        _ => true,
+    }));
+    let rootfs_after: Vec<_> = find_lebs(&ebt, 1).into_values().collect();
+    assert_eq!(rootfs_after.len(), rootfs_before.len());
+    for (after, before) in rootfs_after.iter().zip(&rootfs_before) {
+        assert_eq!(
+            after.vid,
+            Vid {
+                vol_id: 1,
+                ..before.vid
+            }
+            .sqnum(after.vid.sqnum)
+        );
+        assert!(after.vid.sqnum > sqnum_before);
+        assert_eq!(
+            ebt[before.block as usize],
+            BlockContent::EcErased(before.ec.inc_ec())
+        );
+    }
+    let mut out = vec![];
+    read_volume(&mut nand, &ebt, &"rootfs".parse()?, &mut out)?;
+    assert!(out == data);
+
+    // A dynamic volume moves too, partly written LEB and all
+    let renames = [("uboot-env", "uboot-env", Some(4))];
+    assert!(migrate_volume_table(&mut nand, &mut ebt, &renames)?);
+    let mut out = vec![];
+    read_volume(&mut nand, &ebt, &VolumeSelector::Id(4), &mut out)?;
+    assert!(out.starts_with(&settings) && out[settings.len()..].iter().all(|&x| x == 0xFF));
+
+    // Migrating again changes nothing
+    let before = ebt.clone();
+    assert!(!migrate_volume_table(&mut nand, &mut ebt, RENAMES)?);
+    assert_eq!(ebt, before);
+
+    // Nor do migrations that would leave two volumes with the same name or ID, or take an ID that
+    // another volume gives up
+    let bad: [&[(&str, &str, Option<u32>)]; 4] = [
+        &[("uboot-env", "rootfs", None)],
+        &[("uboot-env", "env", Some(1))],
+        &[("uboot-env", "env", Some(200))],
+        &[
+            ("rootfs", "rootfs", Some(2)),
+            ("uboot-env", "uboot-env", Some(1)),
+        ],
+    ];
+    for renames in bad {
+        let result = migrate_volume_table(&mut nand, &mut ebt, renames);
+        assert!(result.is_err(), "{renames:?}");
+    }
+    assert_eq!(ebt, before);
+
+    Ok(())
+}
+
+#[test]
+fn test_updates_invalidate_fastmap() -> anyhow::Result<()> {
+    use super::scan_blocks;
+    use super::ubinize::{BasicVolume, UBI_FM_SB_VOLUME_ID};
+    use crate::nand::SimNand;
+
+    let (mut nand, ebt) = test_partition(vec![Box::new(
+        BasicVolume::new(VolType::Dynamic)
+            .name("env")
+            .size(2 * TEST_LEB_SIZE as u64)
+            .image(&b"settings"[..]),
+    )])?;
+
+    // A fastmap anchor, as a kernel that attached the partition would have left behind
+    let page_size = TEST_LAYOUT.bytes_per_page;
+    let (anchor, ec) = lowest_erased_block(&ebt, page_size)?;
+    let mut vid_page = vec![0xFF; page_size];
+    Vid {
+        vol_id: UBI_FM_SB_VOLUME_ID,
+        ..Default::default()
+    }
+    .encode(&mut vid_page)?;
+    let vid_hdr_page = ec.vid_hdr_page(page_size)?;
+    nand.block(anchor)?
+        .unwrap()
+        .program(vid_hdr_page, &vid_page)?;
+    let ebt = scan_blocks(&mut nand)?;
+    assert!(ebt[anchor as usize].is_fastmap());
+
+    type Update = fn(&mut SimNand, &mut Ebt) -> anyhow::Result<()>;
+    let updates: [(&str, Update); 4] = [
+        ("write_leb", |nand, ebt| {
+            write_leb(nand, ebt, &"env".parse()?, 1, b"settings")
+        }),
+        ("reset_volumes", |nand, ebt| {
+            reset_volumes(nand, ebt, &["env"]).map(drop)
+        }),
+        ("rewrite_layout", |nand, ebt| {
+            let (table, _) = read_table(nand, ebt)?;
+            rewrite_layout(nand, ebt, &table)
+        }),
+        ("migrate_volume_table", |nand, ebt| {
+            migrate_volume_table(nand, ebt, &[("env", "settings", Some(5))]).map(drop)
+        }),
+    ];
+    for (name, update) in updates {
+        let (mut nand, mut ebt) = (nand.clone(), ebt.clone());
+        update(&mut nand, &mut ebt)?;
+        assert!(!ebt.iter().any(BlockContent::is_fastmap), "{name}");
+        assert_eq!(ebt[anchor as usize].ec(), Some(ec.inc_ec()), "{name}");
+        assert_eq!(scan_blocks(&mut nand)?, ebt, "{name}");
+    }
+
+    Ok(())
+}
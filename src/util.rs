@@ -1,5 +1,9 @@
 //! Useful traits and other utilities that don't really belong anywhere else.
-use std::io::{self, Read};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub trait ReadExt {
     /// Tries to read exactly `read_len` bytes, like `read_exact`, but unlike `read_exact`, is
@@ -8,6 +12,12 @@ pub trait ReadExt {
     /// The returned vector will have exactly `read_len` bytes appended, unless an EOF was
     /// encountered, in which case it will have strictly shorter than `read_len` new bytes added.
     fn read_to_vec(&mut self, vec: &mut Vec<u8>, read_len: usize) -> io::Result<()>;
+
+    /// Fills `buf` as far as possible, like `read_exact`, but stops short at EOF rather than
+    /// failing.
+    ///
+    /// Returns how many bytes were read, which is less than `buf.len()` only if EOF was reached.
+    fn read_fill(&mut self, buf: &mut [u8]) -> io::Result<usize>;
 }
 
 impl<T: Read> ReadExt for T {
@@ -41,6 +51,120 @@ impl<T: Read> ReadExt for T {
             };
         }
     }
+
+    fn read_fill(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut cursor = 0;
+        while cursor < buf.len() {
+            cursor += match self.read(&mut buf[cursor..]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => 0,
+                Err(x) => return Err(x),
+            };
+        }
+
+        Ok(cursor)
+    }
+}
+
+/// A reader that can be cloned, every clone reading and seeking the one underlying reader; for
+/// users that take turns with it, each seeking to where it needs to be first
+#[derive(Debug)]
+pub struct SharedReader<R>(Rc<RefCell<R>>);
+
+impl<R> SharedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self(Rc::new(RefCell::new(inner)))
+    }
+}
+
+impl<R> Clone for SharedReader<R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl<R: Seek> Seek for SharedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.borrow_mut().seek(pos)
+    }
+}
+
+/// Shows bytes the way `hexdump -C` does: 16 to a line, after their offset and before their
+/// printable ASCII
+#[derive(Debug, Copy, Clone)]
+pub struct HexDump<'a> {
+    data: &'a [u8],
+    offset: u64,
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    /// Number the lines from `offset`, rather than from 0
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (line, chunk) in self.data.chunks(16).enumerate() {
+            write!(f, "{:08x} ", self.offset + line as u64 * 16)?;
+            for i in 0..16 {
+                if i % 8 == 0 {
+                    f.write_str(" ")?;
+                }
+                match chunk.get(i) {
+                    Some(byte) => write!(f, "{byte:02x} ")?,
+                    None => f.write_str("   ")?,
+                }
+            }
+
+            let ascii: String = chunk
+                .iter()
+                .map(|&x| match x {
+                    0x20..=0x7E => x as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(f, " |{ascii}|")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The error returned by a long-running operation that was cancelled through its abort flag
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "aborted by user")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Fail with [Aborted] if the (optional) abort flag has been raised.
+///
+/// Long-running operations call this between blocks, so that they stop at a point where the NAND
+/// is in no worse a state than a power loss would leave it.
+pub fn check_abort(abort: Option<&AtomicBool>) -> Result<(), Aborted> {
+    match abort {
+        Some(flag) if flag.load(Ordering::Relaxed) => Err(Aborted),
+        _ => Ok(()),
+    }
 }
 
 #[test]
@@ -54,3 +178,30 @@ fn test_read_to_vec() -> io::Result<()> {
     assert_eq!(vec, [0xAA, 0xAA, 0xAA, 0xAA, 0xBB, 0xBB, 1, 2, 3]);
     Ok(())
 }
+
+#[test]
+fn test_read_fill() -> io::Result<()> {
+    let mut buf = [0; 4];
+    assert_eq!(io::repeat(0xAA).read_fill(&mut buf)?, 4);
+    assert_eq!(buf, [0xAA; 4]);
+
+    // A reader that hands out one byte at a time still fills the buffer
+    let mut reader = io::Read::chain(&[1][..], &[2, 3][..]);
+    assert_eq!(reader.read_fill(&mut buf)?, 3);
+    assert_eq!(buf, [1, 2, 3, 0xAA]);
+    assert_eq!(reader.read_fill(&mut buf)?, 0);
+    Ok(())
+}
+
+#[test]
+fn test_hexdump() {
+    let data: Vec<u8> = b"UBI#".iter().copied().chain(0..20).collect();
+    assert_eq!(
+        HexDump::new(&data).offset(0x800).to_string(),
+        "\
+00000800  55 42 49 23 00 01 02 03  04 05 06 07 08 09 0a 0b  |UBI#............|
+00000810  0c 0d 0e 0f 10 11 12 13                           |........|
+"
+    );
+    assert_eq!(HexDump::new(&[]).to_string(), "");
+}